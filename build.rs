@@ -2,7 +2,9 @@
 extern crate gcc;
 
 fn main() {
+    // The `gcc` crate has since been renamed to `cc`, which deprecates all of its items.
     #[cfg(feature = "builtin-lua")]
+    #[allow(deprecated)]
     {
         use std::env;
 
@@ -10,6 +12,7 @@ fn main() {
 
         let target_os = env::var("CARGO_CFG_TARGET_OS");
         let target_family = env::var("CARGO_CFG_TARGET_FAMILY");
+        let wasm = env::var("CARGO_CFG_TARGET_ARCH") == Ok("wasm32".to_string());
 
         if target_os == Ok("linux".to_string()) {
             config.define("LUA_USE_LINUX", None);
@@ -26,6 +29,27 @@ fn main() {
         // confidence in the soundness of the API.
         config.define("LUA_USE_APICHECK", None);
 
+        // Lua 5.3 only honors the `__ipairs` metamethod in compatibility mode, which is needed for
+        // `MetaMethod::IPairs`. `LUA_COMPAT_5_2` already defines it.
+        if cfg!(feature = "lua52-compat") {
+            config.define("LUA_COMPAT_5_2", None);
+        } else {
+            config.define("LUA_COMPAT_IPAIRS", None);
+        }
+
+        // WebAssembly has no files or processes, so the io and os libraries are left out, along
+        // with `linit.c` which opens them. Lua raises errors with `setjmp` and `longjmp`, which
+        // clang only supports on WebAssembly through exception handling. The C compiler must
+        // still be given a C library to build against, such as the sysroot of wasi-sdk.
+        if wasm {
+            config.flag("-mllvm").flag("-wasm-enable-sjlj");
+        } else {
+            config
+                .file("lua/linit.c")
+                .file("lua/liolib.c")
+                .file("lua/loslib.c");
+        }
+
         config
             .include("lua")
             .file("lua/lapi.c")
@@ -41,15 +65,12 @@ fn main() {
             .file("lua/ldump.c")
             .file("lua/lfunc.c")
             .file("lua/lgc.c")
-            .file("lua/linit.c")
-            .file("lua/liolib.c")
             .file("lua/llex.c")
             .file("lua/lmathlib.c")
             .file("lua/lmem.c")
             .file("lua/loadlib.c")
             .file("lua/lobject.c")
             .file("lua/lopcodes.c")
-            .file("lua/loslib.c")
             .file("lua/lparser.c")
             .file("lua/lstate.c")
             .file("lua/lstring.c")
@@ -61,6 +82,7 @@ fn main() {
             .file("lua/lutf8lib.c")
             .file("lua/lvm.c")
             .file("lua/lzio.c")
+            .file("src/internals.c")
             .compile("liblua5.3.a");
     }
 }
@@ -36,7 +36,7 @@ fn examples() -> Result<()> {
     assert_eq!(globals.get::<_, String>("global")?, "foobar");
 
     assert_eq!(lua.eval::<i32>("1 + 1", None)?, 2);
-    assert_eq!(lua.eval::<bool>("false == false", None)?, true);
+    assert!(lua.eval::<bool>("false == false", None)?);
     assert_eq!(lua.eval::<i32>("return 1 + 2", None)?, 3);
 
     // You can create and manage lua tables
@@ -102,14 +102,8 @@ fn examples() -> Result<()> {
     });
     globals.set("join", join)?;
 
-    assert_eq!(
-        lua.eval::<bool>(r#"check_equal({"a", "b", "c"}, {"a", "b", "c"})"#, None)?,
-        true
-    );
-    assert_eq!(
-        lua.eval::<bool>(r#"check_equal({"a", "b", "c"}, {"d", "e", "f"})"#, None)?,
-        false
-    );
+    assert!(lua.eval::<bool>(r#"check_equal({"a", "b", "c"}, {"a", "b", "c"})"#, None)?);
+    assert!(!lua.eval::<bool>(r#"check_equal({"a", "b", "c"}, {"d", "e", "f"})"#, None)?);
     assert_eq!(lua.eval::<String>(r#"join("a", "b", "c")"#, None)?, "abc");
 
     // You can create userdata with methods and metamethods defined on them.
@@ -151,6 +151,9 @@ typedef struct global_State {
   TString *tmname[TM_N];  /* array with tag-method names */
   struct Table *mt[LUA_NUMTAGS];  /* metatables for basic types */
   TString *strcache[STRCACHE_N][STRCACHE_M];  /* cache for strings in API */
+  /* rlua: counts the entries tables grow by, see src/internals.c */
+  int (*tablehook) (struct lua_State *L, void *ud, size_t slots);
+  void *tablehookud;  /* rlua: userdata of 'tablehook' */
 } global_State;
 
 
@@ -39,6 +39,11 @@
 #include "lvm.h"
 
 
+/* rlua: counts the entries tables grow by, defined in src/internals.c */
+LUAI_FUNC void rlua_tablegrowth (lua_State *L, const Table *t, unsigned int nasize,
+                                 unsigned int nhsize);
+
+
 /*
 ** Maximum size of array part (MAXASIZE) is 2^MAXABITS. MAXABITS is
 ** the largest integer such that MAXASIZE fits in an unsigned int.
@@ -337,6 +342,7 @@ void luaH_resize (lua_State *L, Table *t, unsigned int nasize,
   unsigned int oldasize = t->sizearray;
   int oldhsize = allocsizenode(t);
   Node *nold = t->node;  /* save old hash ... */
+  rlua_tablegrowth(L, t, nasize, nhsize);  /* rlua: count table entries */
   if (nasize > oldasize)  /* array part must grow? */
     setarrayvector(L, t, nasize);
   /* create new hash part with appropriate size */
@@ -0,0 +1,767 @@
+//! Derive macros for implementing `rlua::UserData`, `rlua::ToLua` and `rlua::FromLua`.
+//!
+//! `#[derive(UserData)]` implements `UserData` for a struct. Fields marked with `#[lua(get)]`
+//! and / or `#[lua(set)]` can be read and written from Lua as `obj.field`, which requires the
+//! field type to implement `Clone + ToLua` for reading and `FromLua` for writing.
+//!
+//! Methods are registered from an `impl` block marked with `#[lua_methods]`, which requires the
+//! struct to be marked with `#[lua(methods)]`. Inside the block, functions can be marked with:
+//!
+//! - `#[lua(method)]`: a method called as `obj:name(...)`, taking `&self` or `&mut self`.
+//! - `#[lua(function)]`: a function without receiver, reachable through
+//!   `Lua::userdata_methods`.
+//! - `#[lua(meta = "tostring")]`: a metamethod taking `&self` or `&mut self`, named after the
+//!   metamethod without the leading underscores.
+//!
+//! Methods may be renamed with `#[lua(method, name = "other")]`. Functions returning a
+//! `Result` must use `rlua::Error` as the error type, other return values are wrapped in `Ok`.
+//!
+//! # Examples
+//!
+//! ```
+//! extern crate rlua;
+//! #[macro_use]
+//! extern crate rlua_derive;
+//!
+//! use rlua::Lua;
+//! use rlua_derive::lua_methods;
+//!
+//! #[derive(UserData)]
+//! #[lua(methods)]
+//! struct Counter {
+//!     #[lua(get, set)]
+//!     step: i64,
+//!     #[lua(get)]
+//!     count: i64,
+//! }
+//!
+//! #[lua_methods]
+//! impl Counter {
+//!     #[lua(function)]
+//!     fn new(step: i64) -> Counter {
+//!         Counter { step, count: 0 }
+//!     }
+//!
+//!     #[lua(method)]
+//!     fn increment(&mut self) {
+//!         self.count += self.step;
+//!     }
+//!
+//!     #[lua(meta = "tostring")]
+//!     fn describe(&self) -> String {
+//!         format!("Counter({})", self.count)
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let lua = Lua::new();
+//!     lua.globals()
+//!         .set("Counter", lua.userdata_methods::<Counter>().unwrap())
+//!         .unwrap();
+//!     lua.exec::<()>(
+//!         r#"
+//!             local counter = Counter.new(2)
+//!             counter:increment()
+//!             counter.step = 5
+//!             counter:increment()
+//!             assert(counter.count == 7)
+//!             assert(tostring(counter) == "Counter(7)")
+//!         "#,
+//!         None,
+//!     ).unwrap();
+//! }
+//! ```
+//!
+//! `#[derive(ToLua)]` and `#[derive(FromLua)]` convert a struct with named fields to and from a
+//! plain table, with one key per field. Fields can be marked with:
+//!
+//! - `#[lua(name = "other")]`: use a different key for the field.
+//! - `#[lua(default)]`: use `Default::default()` if the key is missing or `nil`, or
+//!   `#[lua(default = "path::to::function")]` to call a function instead.
+//! - `#[lua(skip)]`: leave the field out of the table, and use `Default::default()` for it.
+//!
+//! Without `default`, a missing key is converted like `nil`, so `Option` fields are optional.
+//! Conversion errors name the field which is missing or could not be converted, which makes these
+//! structs suited to taking named arguments with `rlua::Kwargs`.
+//!
+//! ```
+//! extern crate rlua;
+//! #[macro_use]
+//! extern crate rlua_derive;
+//!
+//! use rlua::Lua;
+//!
+//! #[derive(ToLua, FromLua)]
+//! struct Config {
+//!     name: String,
+//!     #[lua(name = "max_size", default)]
+//!     size: u32,
+//!     comment: Option<String>,
+//!     #[lua(skip)]
+//!     loaded: bool,
+//! }
+//!
+//! fn main() {
+//!     let lua = Lua::new();
+//!     let config: Config = lua.eval(r#"{ name = "test" }"#, None).unwrap();
+//!     assert_eq!(config.name, "test");
+//!     assert_eq!(config.size, 0);
+//!     assert!(config.comment.is_none() && !config.loaded);
+//!
+//!     lua.globals().set("config", config).unwrap();
+//!     lua.exec::<()>("assert(config.max_size == 0)", None).unwrap();
+//! }
+//! ```
+//!
+//! For enums without fields, the derives convert each variant to and from a string holding the
+//! variant name, which can be changed with `#[lua(name = "other")]` on the variant. Marking the
+//! enum with `#[lua(case_insensitive)]` accepts strings regardless of ASCII case. Converting an
+//! unknown string fails with an error listing the expected strings.
+//!
+//! ```
+//! extern crate rlua;
+//! #[macro_use]
+//! extern crate rlua_derive;
+//!
+//! use rlua::Lua;
+//!
+//! #[derive(Debug, PartialEq, ToLua, FromLua)]
+//! #[lua(case_insensitive)]
+//! enum Mode {
+//!     Read,
+//!     Write,
+//!     #[lua(name = "rw")]
+//!     ReadWrite,
+//! }
+//!
+//! fn main() {
+//!     let lua = Lua::new();
+//!     assert_eq!(lua.eval::<Mode>("'read'", None).unwrap(), Mode::Read);
+//!     assert_eq!(lua.eval::<Mode>("'RW'", None).unwrap(), Mode::ReadWrite);
+//!     assert!(lua.eval::<Mode>("'append'", None).is_err());
+//!
+//!     lua.globals().set("mode", Mode::Write).unwrap();
+//!     lua.exec::<()>("assert(mode == 'Write')", None).unwrap();
+//! }
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use syn::spanned::Spanned;
+use syn::{Attribute, Data, DataEnum, DeriveInput, Error, ExprPath, Field, Fields, FnArg, Generics,
+          Ident, ImplItem, ItemImpl, LitStr, Pat, ReturnType, Token, Type};
+
+/// Implements `rlua::UserData` for a struct.
+///
+/// Refer to the crate documentation for the supported attributes.
+#[proc_macro_derive(UserData, attributes(lua))]
+pub fn derive_user_data(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match user_data_impl(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Implements `rlua::ToLua` for a struct, converting it to a table, or for an enum without fields,
+/// converting it to a string.
+///
+/// Refer to the crate documentation for the supported attributes.
+#[proc_macro_derive(ToLua, attributes(lua))]
+pub fn derive_to_lua(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match to_lua_impl(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Implements `rlua::FromLua` for a struct, converting it from a table, or for an enum without
+/// fields, converting it from a string.
+///
+/// Refer to the crate documentation for the supported attributes.
+#[proc_macro_derive(FromLua, attributes(lua))]
+pub fn derive_from_lua(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match from_lua_impl(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Registers the marked functions of an `impl` block as userdata methods.
+///
+/// Refer to the crate documentation for the supported attributes.
+#[proc_macro_attribute]
+pub fn lua_methods(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return Error::new(Span::call_site(), "lua_methods does not take arguments")
+            .to_compile_error()
+            .into();
+    }
+    let mut item = syn::parse_macro_input!(input as ItemImpl);
+    match lua_methods_impl(&mut item) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+// The parsed contents of all `#[lua(...)]` attributes on an item.
+#[derive(Default)]
+struct LuaAttrs {
+    get: bool,
+    set: bool,
+    methods: bool,
+    method: bool,
+    function: bool,
+    meta: Option<LitStr>,
+    name: Option<LitStr>,
+    skip: bool,
+    case_insensitive: bool,
+    // `Some(None)` for `default`, `Some(Some(path))` for `default = "path"`.
+    default: Option<Option<LitStr>>,
+}
+
+fn parse_lua_attrs(attrs: &[Attribute]) -> Result<LuaAttrs, Error> {
+    let mut parsed = LuaAttrs::default();
+    for attr in attrs {
+        if !attr.path().is_ident("lua") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("get") {
+                parsed.get = true;
+            } else if meta.path.is_ident("set") {
+                parsed.set = true;
+            } else if meta.path.is_ident("methods") {
+                parsed.methods = true;
+            } else if meta.path.is_ident("method") {
+                parsed.method = true;
+            } else if meta.path.is_ident("function") {
+                parsed.function = true;
+            } else if meta.path.is_ident("meta") {
+                parsed.meta = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("name") {
+                parsed.name = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("skip") {
+                parsed.skip = true;
+            } else if meta.path.is_ident("case_insensitive") {
+                parsed.case_insensitive = true;
+            } else if meta.path.is_ident("default") {
+                parsed.default = Some(if meta.input.peek(Token![=]) {
+                    Some(meta.value()?.parse()?)
+                } else {
+                    None
+                });
+            } else {
+                return Err(meta.error("unknown lua attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(parsed)
+}
+
+fn user_data_impl(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let attrs = parse_lua_attrs(&input.attrs)?;
+    let fields = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => fields.named.iter().collect(),
+            _ => Vec::new(),
+        },
+        _ => {
+            return Err(Error::new(
+                input.span(),
+                "UserData can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut getters = Vec::new();
+    let mut setters = Vec::new();
+    for field in fields {
+        let field_attrs = parse_lua_attrs(&field.attrs)?;
+        let ident = field.ident.as_ref().unwrap();
+        let name = match field_attrs.name {
+            Some(ref name) => name.value(),
+            None => ident.to_string(),
+        };
+        if field_attrs.get {
+            getters.push(quote! {
+                #name => ::rlua::ToLua::to_lua(::std::clone::Clone::clone(&this.#ident), lua),
+            });
+        }
+        if field_attrs.set {
+            setters.push(quote! {
+                #name => {
+                    this.#ident = ::rlua::FromLua::from_lua(value, lua)?;
+                    Ok(())
+                }
+            });
+        }
+    }
+
+    let mut body = Vec::new();
+    if !getters.is_empty() {
+        body.push(quote! {
+            methods.add_meta_method(
+                ::rlua::MetaMethod::Index,
+                |lua, this, key: ::rlua::String| match key.to_str()? {
+                    #(#getters)*
+                    key => Err(::rlua::Error::RuntimeError(
+                        format!("no readable field '{}'", key),
+                    )),
+                },
+            );
+        });
+    }
+    if !setters.is_empty() {
+        body.push(quote! {
+            methods.add_meta_method_mut(
+                ::rlua::MetaMethod::NewIndex,
+                |lua, this, (key, value): (::rlua::String, ::rlua::Value)| match key.to_str()? {
+                    #(#setters)*
+                    key => Err(::rlua::Error::RuntimeError(
+                        format!("no writable field '{}'", key),
+                    )),
+                },
+            );
+        });
+    }
+    if attrs.methods {
+        body.push(quote! {
+            Self::__rlua_add_methods(methods);
+        });
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rlua::UserData for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn add_methods(methods: &mut ::rlua::UserDataMethods<Self>) {
+                #(#body)*
+            }
+        }
+    })
+}
+
+// Returns the named fields of a struct, for the `ToLua` and `FromLua` derives.
+fn table_fields<'a>(input: &'a DeriveInput, derive: &str) -> Result<Vec<&'a Field>, Error> {
+    match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => Ok(fields.named.iter().collect()),
+            _ => Err(Error::new(
+                input.span(),
+                format!("{} can only be derived for structs with named fields", derive),
+            )),
+        },
+        _ => Err(Error::new(
+            input.span(),
+            format!("{} can only be derived for structs and enums", derive),
+        )),
+    }
+}
+
+// Adds the `'lua` lifetime to the generics of the input, and bounds each field type by the given
+// conversion trait.
+fn conversion_generics(input: &DeriveInput, fields: &[&Field], bound: TokenStream2) -> Generics {
+    let mut generics = input.generics.clone();
+    generics.params.insert(0, syn::parse_quote!('lua));
+    {
+        let where_clause = generics.make_where_clause();
+        for field in fields {
+            let ty = &field.ty;
+            where_clause.predicates.push(syn::parse_quote!(#ty: #bound));
+        }
+    }
+    generics
+}
+
+// Returns the variants of an enum without fields and the strings they are converted to, for the
+// `ToLua` and `FromLua` derives.
+fn string_variants(data: &DataEnum) -> Result<Vec<(&Ident, String)>, Error> {
+    let mut variants = Vec::new();
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new(
+                variant.span(),
+                "only enums without fields can be converted to strings",
+            ));
+        }
+        let name = match parse_lua_attrs(&variant.attrs)?.name {
+            Some(ref name) => name.value(),
+            None => variant.ident.to_string(),
+        };
+        variants.push((&variant.ident, name));
+    }
+    Ok(variants)
+}
+
+fn enum_to_lua_impl(input: &DeriveInput, data: &DataEnum) -> Result<TokenStream2, Error> {
+    let ident = &input.ident;
+    let arms = string_variants(data)?
+        .into_iter()
+        .map(|(variant, name)| quote! { #ident::#variant => #name, });
+
+    let mut generics = input.generics.clone();
+    generics.params.insert(0, syn::parse_quote!('lua));
+    let (impl_generics, _, _) = generics.split_for_impl();
+    let (_, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rlua::ToLua<'lua> for #ident #ty_generics #where_clause {
+            fn to_lua(self, lua: &'lua ::rlua::Lua) -> ::rlua::Result<::rlua::Value<'lua>> {
+                let name = match self {
+                    #(#arms)*
+                };
+                Ok(::rlua::Value::String(lua.create_string(name)))
+            }
+        }
+    })
+}
+
+fn enum_from_lua_impl(input: &DeriveInput, data: &DataEnum) -> Result<TokenStream2, Error> {
+    let ident = &input.ident;
+    let type_name = ident.to_string();
+    let case_insensitive = parse_lua_attrs(&input.attrs)?.case_insensitive;
+
+    let variants = string_variants(data)?;
+    let expected = variants
+        .iter()
+        .map(|(_, name)| format!("'{}'", name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let checks = variants.iter().map(|(variant, name)| {
+        if case_insensitive {
+            quote! {
+                if s.eq_ignore_ascii_case(#name) {
+                    return Ok(#ident::#variant);
+                }
+            }
+        } else {
+            quote! {
+                if s == #name {
+                    return Ok(#ident::#variant);
+                }
+            }
+        }
+    });
+
+    let mut generics = input.generics.clone();
+    generics.params.insert(0, syn::parse_quote!('lua));
+    let (impl_generics, _, _) = generics.split_for_impl();
+    let (_, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rlua::FromLua<'lua> for #ident #ty_generics #where_clause {
+            fn from_lua(
+                value: ::rlua::Value<'lua>,
+                lua: &'lua ::rlua::Lua,
+            ) -> ::rlua::Result<Self> {
+                let s: ::rlua::String = ::rlua::FromLua::from_lua(value, lua)?;
+                let s = s.to_str()?;
+                #(#checks)*
+                Err(::rlua::Error::FromLuaConversionError {
+                    from: "string",
+                    to: #type_name,
+                    message: Some(format!("expected one of {}, got '{}'", #expected, s)),
+                })
+            }
+        }
+    })
+}
+
+fn to_lua_impl(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    if let Data::Enum(ref data) = input.data {
+        return enum_to_lua_impl(input, data);
+    }
+
+    let mut fields = Vec::new();
+    for field in table_fields(input, "ToLua")? {
+        if !parse_lua_attrs(&field.attrs)?.skip {
+            fields.push(field);
+        }
+    }
+
+    let mut sets = Vec::new();
+    for field in &fields {
+        let attrs = parse_lua_attrs(&field.attrs)?;
+        let ident = field.ident.as_ref().unwrap();
+        let name = match attrs.name {
+            Some(ref name) => name.value(),
+            None => ident.to_string(),
+        };
+        sets.push(quote! {
+            table.raw_set(#name, self.#ident)?;
+        });
+    }
+
+    let ident = &input.ident;
+    let generics = conversion_generics(input, &fields, quote!(::rlua::ToLua<'lua>));
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rlua::ToLua<'lua> for #ident #ty_generics #where_clause {
+            fn to_lua(self, lua: &'lua ::rlua::Lua) -> ::rlua::Result<::rlua::Value<'lua>> {
+                let table = lua.create_table();
+                #(#sets)*
+                Ok(::rlua::Value::Table(table))
+            }
+        }
+    })
+}
+
+fn from_lua_impl(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    if let Data::Enum(ref data) = input.data {
+        return enum_from_lua_impl(input, data);
+    }
+
+    let ident = &input.ident;
+    let type_name = ident.to_string();
+
+    let mut converted = Vec::new();
+    let mut inits = Vec::new();
+    for field in table_fields(input, "FromLua")? {
+        let attrs = parse_lua_attrs(&field.attrs)?;
+        let field_ident = field.ident.as_ref().unwrap();
+        if attrs.skip {
+            inits.push(quote! {
+                #field_ident: ::std::default::Default::default(),
+            });
+            continue;
+        }
+        converted.push(field);
+
+        let name = match attrs.name {
+            Some(ref name) => name.value(),
+            None => field_ident.to_string(),
+        };
+        let convert = quote! {{
+            let missing = ::std::matches!(value, ::rlua::Value::Nil);
+            ::rlua::FromLua::from_lua(value, lua).map_err(|err| {
+                ::rlua::Error::FromLuaConversionError {
+                    from: "table",
+                    to: #type_name,
+                    message: Some(if missing {
+                        format!("missing field '{}'", #name)
+                    } else {
+                        format!("invalid field '{}': {}", #name, err)
+                    }),
+                }
+            })?
+        }};
+        let init = match attrs.default {
+            Some(ref default) => {
+                let default = match *default {
+                    Some(ref path) => {
+                        let path: ExprPath = path.parse()?;
+                        quote! { #path() }
+                    }
+                    None => quote! { ::std::default::Default::default() },
+                };
+                quote! {
+                    match table.get::<_, ::rlua::Value>(#name)? {
+                        ::rlua::Value::Nil => #default,
+                        value => #convert,
+                    }
+                }
+            }
+            None => quote! {{
+                let value = table.get::<_, ::rlua::Value>(#name)?;
+                #convert
+            }},
+        };
+        inits.push(quote! {
+            #field_ident: #init,
+        });
+    }
+
+    let generics = conversion_generics(input, &converted, quote!(::rlua::FromLua<'lua>));
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rlua::FromLua<'lua> for #ident #ty_generics #where_clause {
+            fn from_lua(
+                value: ::rlua::Value<'lua>,
+                lua: &'lua ::rlua::Lua,
+            ) -> ::rlua::Result<Self> {
+                let table: ::rlua::Table = ::rlua::FromLua::from_lua(value, lua)?;
+                Ok(#ident {
+                    #(#inits)*
+                })
+            }
+        }
+    })
+}
+
+fn lua_methods_impl(item: &mut ItemImpl) -> Result<TokenStream2, Error> {
+    let mut registrations = Vec::new();
+
+    for impl_item in &mut item.items {
+        let method = match *impl_item {
+            ImplItem::Fn(ref mut method) => method,
+            _ => continue,
+        };
+        let attrs = parse_lua_attrs(&method.attrs)?;
+        method.attrs.retain(|attr| !attr.path().is_ident("lua"));
+
+        let kinds = attrs.method as u8 + attrs.function as u8 + attrs.meta.is_some() as u8;
+        if kinds == 0 {
+            continue;
+        } else if kinds > 1 {
+            return Err(Error::new(
+                method.sig.span(),
+                "only one of `method`, `function` and `meta` may be given",
+            ));
+        }
+
+        let fn_ident = &method.sig.ident;
+        let name = match attrs.name {
+            Some(ref name) => name.value(),
+            None => fn_ident.to_string(),
+        };
+
+        let mut receiver = None;
+        let mut arg_idents = Vec::new();
+        let mut arg_types = Vec::new();
+        for (i, input) in method.sig.inputs.iter().enumerate() {
+            match *input {
+                FnArg::Receiver(ref r) => {
+                    if r.reference.is_none() {
+                        return Err(Error::new(
+                            r.span(),
+                            "methods must take `&self` or `&mut self`",
+                        ));
+                    }
+                    receiver = Some(r.mutability.is_some());
+                }
+                FnArg::Typed(ref arg) => {
+                    let ident = match *arg.pat {
+                        Pat::Ident(ref pat) => pat.ident.clone(),
+                        _ => Ident::new(&format!("arg{}", i), arg.pat.span()),
+                    };
+                    arg_idents.push(ident);
+                    arg_types.push((*arg.ty).clone());
+                }
+            }
+        }
+
+        let call_args = quote! { (#(#arg_idents,)*): (#(#arg_types,)*) };
+        let result = if returns_result(&method.sig.output) {
+            quote! { result }
+        } else {
+            quote! { Ok(result) }
+        };
+
+        let registration = if attrs.function {
+            if receiver.is_some() {
+                return Err(Error::new(
+                    method.sig.span(),
+                    "functions must not take `self`",
+                ));
+            }
+            quote! {
+                methods.add_function(#name, |_, #call_args| {
+                    let result = Self::#fn_ident(#(#arg_idents),*);
+                    #result
+                });
+            }
+        } else {
+            let is_mut = match receiver {
+                Some(is_mut) => is_mut,
+                None => {
+                    return Err(Error::new(
+                        method.sig.span(),
+                        "methods must take `&self` or `&mut self`",
+                    ))
+                }
+            };
+            let register = match (attrs.meta.is_some(), is_mut) {
+                (false, false) => quote! { add_method },
+                (false, true) => quote! { add_method_mut },
+                (true, false) => quote! { add_meta_method },
+                (true, true) => quote! { add_meta_method_mut },
+            };
+            let key = match attrs.meta {
+                Some(ref meta) => meta_method(meta),
+                None => quote! { #name },
+            };
+            quote! {
+                methods.#register(#key, |_, this, #call_args| {
+                    let result = this.#fn_ident(#(#arg_idents),*);
+                    #result
+                });
+            }
+        };
+        registrations.push(registration);
+    }
+
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        #item
+
+        impl #impl_generics #self_ty #where_clause {
+            #[doc(hidden)]
+            #[allow(unused_parens)]
+            pub fn __rlua_add_methods(methods: &mut ::rlua::UserDataMethods<Self>) {
+                #(#registrations)*
+            }
+        }
+    })
+}
+
+fn returns_result(output: &ReturnType) -> bool {
+    match *output {
+        ReturnType::Type(_, ref ty) => match **ty {
+            Type::Path(ref path) => path.path
+                .segments
+                .last()
+                .map(|segment| segment.ident == "Result")
+                .unwrap_or(false),
+            _ => false,
+        },
+        ReturnType::Default => false,
+    }
+}
+
+fn meta_method(meta: &LitStr) -> TokenStream2 {
+    let variant = match meta.value().trim_start_matches("__") {
+        "add" => "Add",
+        "sub" => "Sub",
+        "mul" => "Mul",
+        "div" => "Div",
+        "mod" => "Mod",
+        "pow" => "Pow",
+        "unm" => "Unm",
+        "idiv" => "IDiv",
+        "band" => "BAnd",
+        "bor" => "BOr",
+        "bxor" => "BXor",
+        "bnot" => "BNot",
+        "shl" => "Shl",
+        "shr" => "Shr",
+        "concat" => "Concat",
+        "len" => "Len",
+        "eq" => "Eq",
+        "lt" => "Lt",
+        "le" => "Le",
+        "index" => "Index",
+        "newindex" => "NewIndex",
+        "call" => "Call",
+        "tostring" => "ToString",
+        "pairs" => "Pairs",
+        "ipairs" => "IPairs",
+        other => {
+            let name = format!("__{}", other);
+            return quote! { ::rlua::MetaMethod::Custom(#name.to_owned()) };
+        }
+    };
+    let variant = Ident::new(variant, meta.span());
+    quote! { ::rlua::MetaMethod::#variant }
+}
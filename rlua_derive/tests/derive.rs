@@ -0,0 +1,256 @@
+extern crate rlua;
+#[macro_use]
+extern crate rlua_derive;
+
+use rlua::{Error, Lua, Result};
+use rlua_derive::lua_methods;
+
+#[derive(Clone, UserData)]
+struct Plain(i64);
+
+#[derive(UserData)]
+#[lua(methods)]
+struct Vec2 {
+    #[lua(get, set)]
+    x: f64,
+    #[lua(get, set)]
+    y: f64,
+    #[lua(get, name = "label")]
+    name: String,
+}
+
+#[lua_methods]
+impl Vec2 {
+    #[lua(function)]
+    fn new(x: f64, y: f64) -> Vec2 {
+        Vec2 {
+            x,
+            y,
+            name: "vec".to_owned(),
+        }
+    }
+
+    #[lua(method)]
+    fn length(&self) -> f64 {
+        (self.x * self.x + self.y * self.y).sqrt()
+    }
+
+    #[lua(method, name = "scale")]
+    fn scale_by(&mut self, factor: f64) {
+        self.x *= factor;
+        self.y *= factor;
+    }
+
+    #[lua(method)]
+    fn checked_div(&self, divisor: f64) -> Result<f64> {
+        if divisor == 0.0 {
+            Err(Error::RuntimeError("division by zero".to_owned()))
+        } else {
+            Ok(self.x / divisor)
+        }
+    }
+
+    #[lua(meta = "tostring")]
+    fn describe(&self) -> String {
+        format!("({}, {})", self.x, self.y)
+    }
+
+    #[lua(meta = "add")]
+    fn add(&self, other: f64) -> f64 {
+        self.x + self.y + other
+    }
+
+    #[allow(dead_code)]
+    fn not_exported(&self) {}
+}
+
+#[derive(Debug, PartialEq, ToLua, FromLua)]
+struct Settings<T> {
+    title: String,
+    #[lua(name = "w")]
+    width: u32,
+    #[lua(default = "default_height")]
+    height: u32,
+    #[lua(default)]
+    tags: Vec<String>,
+    extra: Option<T>,
+    #[lua(skip)]
+    dirty: bool,
+}
+
+#[derive(Debug, PartialEq, ToLua, FromLua)]
+enum Align {
+    Left,
+    #[lua(name = "middle")]
+    Center,
+    Right,
+}
+
+#[derive(Debug, PartialEq, FromLua)]
+#[lua(case_insensitive)]
+enum Level {
+    Low,
+    High,
+}
+
+fn default_height() -> u32 {
+    600
+}
+
+#[test]
+fn test_derive_plain() {
+    let lua = Lua::new();
+    lua.globals().set("plain", Plain(1)).unwrap();
+    assert!(lua.exec::<()>("return plain.field", None).is_err());
+    assert_eq!(lua.globals().get::<_, Plain>("plain").unwrap().0, 1);
+}
+
+#[test]
+fn test_derive_fields_and_methods() {
+    let lua = Lua::new();
+    lua.globals()
+        .set("Vec2", lua.userdata_methods::<Vec2>().unwrap())
+        .unwrap();
+
+    lua.exec::<()>(
+        r#"
+            local v = Vec2.new(3, 4)
+            assert(v.x == 3 and v.y == 4)
+            assert(v.label == "vec")
+            assert(v:length() == 5)
+            v:scale(2)
+            assert(v.x == 6 and v.y == 8)
+            v.x = 0
+            assert(v:length() == 8)
+            assert(tostring(v) == "(0.0, 8.0)" or tostring(v) == "(0, 8)")
+            assert(v + 1 == 9)
+            assert(v:checked_div(2) == 0)
+            assert(not pcall(v.checked_div, v, 0))
+            assert(not pcall(function() return v.missing end))
+            assert(not pcall(function() v.label = "other" end))
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn test_derive_table_conversions() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let settings: Settings<i64> = lua.eval(r#"{ title = "main", w = 800 }"#, None).unwrap();
+    assert_eq!(
+        settings,
+        Settings {
+            title: "main".to_owned(),
+            width: 800,
+            height: 600,
+            tags: Vec::new(),
+            extra: None,
+            dirty: false,
+        }
+    );
+
+    globals
+        .set(
+            "settings",
+            Settings {
+                title: "other".to_owned(),
+                width: 1,
+                height: 2,
+                tags: vec!["a".to_owned()],
+                extra: Some(3),
+                dirty: true,
+            },
+        )
+        .unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(settings.title == "other" and settings.w == 1 and settings.width == nil)
+            assert(settings.height == 2 and settings.tags[1] == "a" and settings.extra == 3)
+            assert(settings.dirty == nil)
+        "#,
+        None,
+    ).unwrap();
+    let settings = globals.get::<_, Settings<i64>>("settings").unwrap();
+    assert!(!settings.dirty);
+    assert_eq!(settings.extra, Some(3));
+
+    match lua.eval::<Settings<i64>>(r#"{ title = "main", w = "wide" }"#, None) {
+        Err(Error::FromLuaConversionError {
+            to: "Settings",
+            message: Some(message),
+            ..
+        }) => assert!(message.starts_with("invalid field 'w': ")),
+        r => panic!("wrong result for invalid field: {:?}", r.map(|_| ())),
+    }
+    match lua.eval::<Settings<i64>>("{ w = 1 }", None) {
+        Err(Error::FromLuaConversionError {
+            message: Some(message),
+            ..
+        }) => assert_eq!(message, "missing field 'title'"),
+        r => panic!("wrong result for missing field: {:?}", r.map(|_| ())),
+    }
+    assert!(lua.eval::<Settings<i64>>("1", None).is_err());
+}
+
+#[test]
+fn test_derive_kwargs() {
+    use rlua::Kwargs;
+
+    let lua = Lua::new();
+    let spawn = lua.create_function(|_, (kind, Kwargs(settings)): (String, Kwargs<Settings<i64>>)| {
+        Ok(format!("{} {} {}x{}", kind, settings.title, settings.width, settings.height))
+    });
+    lua.globals().set("spawn", spawn).unwrap();
+
+    assert_eq!(
+        lua.eval::<String>(r#"spawn("window", { title = "main", w = 80 })"#, None)
+            .unwrap(),
+        "window main 80x600"
+    );
+    for &(code, message) in &[
+        (r#"spawn("window", { w = 80 })"#, "missing field 'title'"),
+        (r#"spawn("window", { title = "main", w = -1 })"#, "invalid field 'w'"),
+        (r#"spawn("window", "main")"#, "expected a table of named arguments"),
+    ] {
+        match lua.exec::<()>(code, None) {
+            Err(Error::CallbackError { ref cause, .. }) => {
+                let cause = cause.to_string();
+                assert!(cause.starts_with("bad argument #2 to 'spawn'"), "{}", cause);
+                assert!(cause.contains(message), "{}", cause);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_derive_enum_conversions() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("center", Align::Center).unwrap();
+    globals.set("right", Align::Right).unwrap();
+    lua.exec::<()>("assert(center == 'middle' and right == 'Right')", None)
+        .unwrap();
+    assert_eq!(lua.eval::<Align>("'Left'", None).unwrap(), Align::Left);
+    assert_eq!(lua.eval::<Align>("'middle'", None).unwrap(), Align::Center);
+    assert!(lua.eval::<Align>("'left'", None).is_err());
+    assert!(lua.eval::<Align>("{}", None).is_err());
+
+    match lua.eval::<Align>("'Center'", None) {
+        Err(Error::FromLuaConversionError {
+            to: "Align",
+            message: Some(message),
+            ..
+        }) => assert_eq!(
+            message,
+            "expected one of 'Left', 'middle', 'Right', got 'Center'"
+        ),
+        r => panic!("wrong result for unknown variant: {:?}", r),
+    }
+
+    assert_eq!(lua.eval::<Level>("'HIGH'", None).unwrap(), Level::High);
+    assert_eq!(lua.eval::<Level>("'low'", None).unwrap(), Level::Low);
+}
@@ -0,0 +1,263 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use error::{Error, Result};
+use ffi;
+use lua::{FromLuaMulti, Lua, MultiValue, Resume, Thread, ToLuaMulti, Value};
+use userdata::UserData;
+use util::{check_stack, stack_guard};
+
+/// Wraps a future returned by a Rust callback, so that the script calling the callback waits for
+/// its output.
+///
+/// When the callback returns, the coroutine running the script is suspended, and resumed with
+/// the output of the future once it is ready, which the call returns. An error of the future is
+/// raised at the call. Scripts do not see any of this: they call asynchronous host functions like
+/// any other function, and the host drives them with [`Function::call_async`].
+///
+/// Futures can only be awaited by the code of the function run with `call_async`, which the
+/// futures suspend. Returning an `Awaitable` elsewhere, such as in a call made with
+/// [`Function::call`], in a coroutine created by the script, or inside of `pcall`, which cannot
+/// be suspended, raises an error.
+///
+/// Requires the `async` feature.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use std::future::Future;
+/// # use std::pin::Pin;
+/// # use std::sync::Arc;
+/// # use std::task::{Context, Poll, Wake, Waker};
+/// # use rlua::{Awaitable, Function, Lua, Result};
+/// // A future which is pending once before it is ready.
+/// struct Delayed(Option<i64>, bool);
+///
+/// impl Future for Delayed {
+///     type Output = Result<i64>;
+///
+///     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<i64>> {
+///         if self.1 {
+///             Poll::Ready(Ok(self.0.take().unwrap()))
+///         } else {
+///             self.1 = true;
+///             cx.waker().wake_by_ref();
+///             Poll::Pending
+///         }
+///     }
+/// }
+///
+/// # struct NoopWaker;
+/// # impl Wake for NoopWaker {
+/// #     fn wake(self: Arc<Self>) {}
+/// # }
+/// # fn block_on<F: Future>(future: F) -> F::Output {
+/// #     let waker = Waker::from(Arc::new(NoopWaker));
+/// #     let mut cx = Context::from_waker(&waker);
+/// #     let mut future = Box::pin(future);
+/// #     loop {
+/// #         if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+/// #             return output;
+/// #         }
+/// #     }
+/// # }
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let fetch = lua.create_function(|_, id: i64| Ok(Awaitable(Delayed(Some(id * 10), false))));
+/// lua.globals().set("fetch", fetch)?;
+///
+/// let script: Function = lua.eval(r#"
+///     function()
+///         return fetch(1) + fetch(2)
+///     end
+/// "#, None)?;
+/// // `block_on` is the executor of your choice.
+/// assert_eq!(block_on(script.call_async::<_, i64>(()))?, 30);
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Function::call_async`]: struct.Function.html#method.call_async
+/// [`Function::call`]: struct.Function.html#method.call
+#[derive(Debug)]
+pub struct Awaitable<F>(pub F);
+
+impl<'lua, F, R> ToLuaMulti<'lua> for Awaitable<F>
+where
+    F: 'static + Future<Output = Result<R>>,
+    R: 'static + for<'a> ToLuaMulti<'a>,
+{
+    fn to_lua_multi(self, lua: &'lua Lua) -> Result<MultiValue<'lua>> {
+        unsafe {
+            if (*lua.extra_data()).awaiting_thread != lua.state
+                || ffi::lua_isyieldable(lua.state) == 0
+            {
+                return Err(Error::RuntimeError(
+                    "a Rust future can only be awaited by a function run with call_async"
+                        .to_owned(),
+                ));
+            }
+        }
+
+        let mut future = Box::pin(self.0);
+        let poll: PollFn = Box::new(move |cx| match future.as_mut().poll(cx) {
+            Poll::Ready(output) => Poll::Ready(output.map(|output| Box::new(output) as _)),
+            Poll::Pending => Poll::Pending,
+        });
+        lua.request_yield(
+            lua.create_userdata(PendingFuture(Some(poll))),
+            Resume::Await,
+        )?;
+        Ok(lua.new_multi_value())
+    }
+}
+
+// The output of an awaited future, converted to Lua values once it is ready.
+trait FutureOutput {
+    fn into_lua_multi<'lua>(self: Box<Self>, lua: &'lua Lua) -> Result<MultiValue<'lua>>;
+}
+
+impl<R: for<'lua> ToLuaMulti<'lua>> FutureOutput for R {
+    fn into_lua_multi<'lua>(self: Box<Self>, lua: &'lua Lua) -> Result<MultiValue<'lua>> {
+        (*self).to_lua_multi(lua)
+    }
+}
+
+type PollFn = Box<dyn FnMut(&mut Context) -> Poll<Result<Box<dyn FutureOutput>>>>;
+
+// The future yielded by a callback returning an `Awaitable`, taken by the `AsyncCall` resuming
+// the thread.
+struct PendingFuture(Option<PollFn>);
+
+impl UserData for PendingFuture {}
+
+/// A future running a Lua function, which awaits the futures returned by the Rust callbacks it
+/// calls.
+///
+/// This struct is created by the [`Function::call_async`] method. Its output is the result of
+/// the function. Requires the `async` feature.
+///
+/// [`Function::call_async`]: struct.Function.html#method.call_async
+pub struct AsyncCall<'lua, R> {
+    thread: Thread<'lua>,
+    // The arguments of the next resume, if the thread is not waiting for a future.
+    args: Option<Result<MultiValue<'lua>>>,
+    future: Option<PollFn>,
+    _phantom: PhantomData<fn() -> R>,
+}
+
+impl<'lua, R> AsyncCall<'lua, R> {
+    pub(crate) fn new(thread: Thread<'lua>, args: Result<MultiValue<'lua>>) -> AsyncCall<'lua, R> {
+        AsyncCall {
+            thread,
+            args: Some(args),
+            future: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    // Resumes the thread, letting the callbacks it calls await futures.
+    fn resume(&self, args: MultiValue<'lua>) -> Result<MultiValue<'lua>> {
+        let lua = self.thread.0.lua;
+        unsafe {
+            let thread_state = stack_guard(lua.state, 0, || {
+                check_stack(lua.state, 1);
+                lua.push_ref(lua.state, &self.thread.0);
+                let thread_state = ffi::lua_tothread(lua.state, -1);
+                ffi::lua_pop(lua.state, 1);
+                thread_state
+            });
+            let _awaiting = AwaitingThread::enter(lua, thread_state);
+            self.thread.resume(args)
+        }
+    }
+}
+
+impl<'lua, R> Future for AsyncCall<'lua, R>
+where
+    R: FromLuaMulti<'lua>,
+{
+    type Output = Result<R>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<R>> {
+        let this = self.get_mut();
+        let lua = this.thread.0.lua;
+        loop {
+            let args = match this.future.as_mut().map(|poll| poll(cx)) {
+                Some(Poll::Pending) => return Poll::Pending,
+                Some(Poll::Ready(output)) => {
+                    this.future = None;
+                    let mut args = lua.new_multi_value();
+                    match output.and_then(|output| output.into_lua_multi(lua)) {
+                        Ok(values) => {
+                            args.push_back(Value::Boolean(true));
+                            args.extend(values);
+                        }
+                        Err(err) => {
+                            args.push_back(Value::Boolean(false));
+                            args.push_back(Value::Error(err));
+                        }
+                    }
+                    args
+                }
+                None => match this.args.take() {
+                    Some(Ok(args)) => args,
+                    Some(Err(err)) => return Poll::Ready(Err(err)),
+                    None => return Poll::Ready(Err(Error::CoroutineInactive)),
+                },
+            };
+
+            let results = match this.resume(args) {
+                Ok(results) => results,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+            if !this.thread.is_resumable() {
+                return Poll::Ready(R::from_lua_multi(results, lua));
+            }
+
+            let future = match results.get(0) {
+                Some(Value::UserData(ud)) if results.len() == 1 => ud
+                    .borrow_mut::<PendingFuture>()
+                    .ok()
+                    .and_then(|mut pending| pending.0.take()),
+                _ => None,
+            };
+            match future {
+                Some(future) => this.future = Some(future),
+                None => {
+                    // The script yielded by itself, which gives other tasks of the executor a
+                    // chance to run.
+                    this.args = Some(Ok(lua.new_multi_value()));
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+// Marks a thread as being resumed by an `AsyncCall`, until it is dropped.
+struct AwaitingThread<'lua>(&'lua Lua, *mut ffi::lua_State);
+
+impl<'lua> AwaitingThread<'lua> {
+    unsafe fn enter(lua: &'lua Lua, thread_state: *mut ffi::lua_State) -> AwaitingThread<'lua> {
+        let extra = lua.extra_data();
+        let previous = (*extra).awaiting_thread;
+        (*extra).awaiting_thread = thread_state;
+        AwaitingThread(lua, previous)
+    }
+}
+
+impl<'lua> Drop for AwaitingThread<'lua> {
+    fn drop(&mut self) {
+        unsafe {
+            (*self.0.extra_data()).awaiting_thread = self.1;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use error::Error;
+use types::Integer;
+use userdata::{MetaMethod, UserData, UserDataMethods};
+
+/// A mutable byte buffer, which scripts and Rust can both read and modify in place.
+///
+/// Lua strings are immutable, so passing binary data back and forth as strings copies it on every
+/// crossing. A buffer is a userdata created with [`Lua::create_userdata`], which Rust can access
+/// by borrowing it from the [`AnyUserData`] with `borrow::<Buffer>()` or `borrow_mut::<Buffer>()`.
+/// It has the following methods, which count bytes from 1 like Lua's string functions:
+///
+/// - `len()` returns the number of bytes, as does the `#` operator.
+/// - `get(i)` returns the byte at position `i`, or `nil` if `i` is out of range.
+/// - `set(i, byte)` replaces the byte at position `i`, which must be in range.
+/// - `slice(i, j)` returns a new buffer holding a copy of the bytes from `i` to `j`, where
+///   negative positions count from the end like in `string.sub`. `j` defaults to -1.
+/// - `tostring(i, j)` returns the same bytes as a Lua string, all of them by default.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Buffer, Lua, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let buffer = lua.create_userdata(Buffer::from(vec![1, 2, 3]));
+/// lua.globals().set("buffer", buffer.clone())?;
+///
+/// lua.exec::<()>("buffer:set(2, buffer:get(1) + buffer:get(3))", None)?;
+/// assert_eq!(buffer.borrow::<Buffer>()?.as_slice(), &[1, 4, 3]);
+///
+/// buffer.borrow_mut::<Buffer>()?.as_mut_slice()[0] = b'a';
+/// assert_eq!(lua.eval::<String>("buffer:tostring(1, 1)", None)?, "a");
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Lua::create_userdata`]: struct.Lua.html#method.create_userdata
+/// [`AnyUserData`]: struct.AnyUserData.html
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Buffer(Vec<u8>);
+
+impl Buffer {
+    /// Creates an empty buffer.
+    pub fn new() -> Buffer {
+        Buffer(Vec::new())
+    }
+
+    /// Returns the contents of the buffer.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the contents of the buffer for modification.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    /// Returns the vector holding the contents of the buffer, which can also change its size.
+    pub fn as_mut_vec(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+
+    /// Returns the contents of the buffer as a vector.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+
+    // Returns the range of bytes from `i` to `j` like `string.sub`.
+    fn range(&self, i: Option<Integer>, j: Option<Integer>) -> &[u8] {
+        let len = self.0.len() as Integer;
+        let position = |n: Integer| if n < 0 { len + n + 1 } else { n };
+        let start = position(i.unwrap_or(1)).max(1);
+        let end = position(j.unwrap_or(-1)).min(len);
+        if start > end {
+            &[]
+        } else {
+            &self.0[start as usize - 1..end as usize]
+        }
+    }
+
+    // Returns the index in the buffer of the position `i`, if it is in range.
+    fn index(&self, i: Integer) -> Option<usize> {
+        if i >= 1 && i <= self.0.len() as Integer {
+            Some(i as usize - 1)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<Vec<u8>> for Buffer {
+    fn from(bytes: Vec<u8>) -> Buffer {
+        Buffer(bytes)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Buffer {
+    fn from(bytes: &'a [u8]) -> Buffer {
+        Buffer(bytes.to_vec())
+    }
+}
+
+impl From<Buffer> for Vec<u8> {
+    fn from(buffer: Buffer) -> Vec<u8> {
+        buffer.0
+    }
+}
+
+impl UserData for Buffer {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("len", |_, buffer, ()| Ok(buffer.0.len() as Integer));
+
+        methods.add_meta_method(MetaMethod::Len, |_, buffer, ()| {
+            Ok(buffer.0.len() as Integer)
+        });
+
+        methods.add_method("get", |_, buffer, i: Integer| {
+            Ok(buffer.index(i).map(|i| buffer.0[i] as Integer))
+        });
+
+        methods.add_method_mut("set", |_, buffer, (i, byte): (Integer, Integer)| {
+            let i = buffer
+                .index(i)
+                .ok_or_else(|| Error::RuntimeError(format!("index {} out of range", i)))?;
+            if !(0..=255).contains(&byte) {
+                return Err(Error::RuntimeError(format!("value {} is not a byte", byte)));
+            }
+            buffer.0[i] = byte as u8;
+            Ok(())
+        });
+
+        methods.add_method(
+            "slice",
+            |_, buffer, (i, j): (Integer, Option<Integer>)| {
+                Ok(Buffer::from(buffer.range(Some(i), j)))
+            },
+        );
+
+        methods.add_method(
+            "tostring",
+            |lua, buffer, (i, j): (Option<Integer>, Option<Integer>)| {
+                Ok(lua.create_byte_string(buffer.range(i, j)))
+            },
+        );
+    }
+}
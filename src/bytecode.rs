@@ -0,0 +1,482 @@
+use std::fmt::Write;
+use std::string::String as StdString;
+
+use error::{Error, Result};
+
+// Lists the instructions of a precompiled chunk created by `lua_dump`, and of the functions it
+// defines, in the format of `luac -l`.
+pub(crate) fn listing(dump: &[u8]) -> Result<StdString> {
+    let mut reader = Reader {
+        bytes: dump,
+        pos: 0,
+        int_size: 0,
+        size_t_size: 0,
+    };
+    let proto = reader.chunk().ok_or_else(|| {
+        Error::RuntimeError("unable to read the bytecode of the function".to_owned())
+    })?;
+    let mut out = StdString::new();
+    print_function(&mut out, &proto, &[]);
+    Ok(out)
+}
+
+// Returns the lines with instructions of a precompiled chunk created by `lua_dump`, including
+// the lines of the functions it defines, or `None` if the chunk cannot be read.
+pub(crate) fn lines(dump: &[u8]) -> Option<Vec<u32>> {
+    fn collect(f: &Proto, lines: &mut Vec<u32>) {
+        lines.extend(f.line_info.iter().filter(|&&line| line > 0).map(|&line| line as u32));
+        for proto in &f.protos {
+            collect(proto, lines);
+        }
+    }
+
+    let mut reader = Reader {
+        bytes: dump,
+        pos: 0,
+        int_size: 0,
+        size_t_size: 0,
+    };
+    let mut lines = Vec::new();
+    collect(&reader.chunk()?, &mut lines);
+    Some(lines)
+}
+
+// A function prototype, as written by `DumpFunction` in `ldump.c`.
+struct Proto {
+    // The source of the function, or `None` if it is the same as the source of its parent.
+    source: Option<Vec<u8>>,
+    line_defined: i64,
+    last_line_defined: i64,
+    num_params: u8,
+    is_vararg: bool,
+    max_stack_size: u8,
+    code: Vec<u32>,
+    constants: Vec<Constant>,
+    num_upvalues: usize,
+    protos: Vec<Proto>,
+    // Debug information, which is empty if the chunk was stripped.
+    line_info: Vec<i64>,
+    num_locals: usize,
+    upvalue_names: Vec<Option<Vec<u8>>>,
+}
+
+enum Constant {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Integer(i64),
+    String(Vec<u8>),
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // Sizes of `int` and `size_t` on the platform the chunk was dumped on.
+    int_size: usize,
+    size_t_size: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn chunk(&mut self) -> Option<Proto> {
+        // Signature, version, format and the bytes used to detect conversion errors.
+        if self.take(4)? != b"\x1bLua" || self.byte()? != 0x53 {
+            return None;
+        }
+        self.take(7)?;
+        self.int_size = self.byte()? as usize;
+        self.size_t_size = self.byte()? as usize;
+        let sizes = self.take(3)?;
+        if sizes != [4, 8, 8] || self.int_size > 8 || self.size_t_size > 8 {
+            return None;
+        }
+        // Sample integer and number, and the number of upvalues of the main function.
+        self.take(8 + 8 + 1)?;
+        self.function()
+    }
+
+    fn function(&mut self) -> Option<Proto> {
+        let source = self.string()?;
+        let line_defined = self.int()?;
+        let last_line_defined = self.int()?;
+        let num_params = self.byte()?;
+        let is_vararg = self.byte()? != 0;
+        let max_stack_size = self.byte()?;
+
+        let code = (0..self.count()?)
+            .map(|_| self.take(4).map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]])))
+            .collect::<Option<Vec<_>>>()?;
+        let constants = (0..self.count()?)
+            .map(|_| self.constant())
+            .collect::<Option<Vec<_>>>()?;
+        let num_upvalues = self.count()?;
+        self.take(num_upvalues * 2)?;
+        let protos = (0..self.count()?)
+            .map(|_| self.function())
+            .collect::<Option<Vec<_>>>()?;
+
+        let line_info = (0..self.count()?)
+            .map(|_| self.int())
+            .collect::<Option<Vec<_>>>()?;
+        let num_locals = self.count()?;
+        for _ in 0..num_locals {
+            // Name, and the first and last instruction where the local is active.
+            self.string()?;
+            self.int()?;
+            self.int()?;
+        }
+        let upvalue_names = (0..self.count()?)
+            .map(|_| self.string())
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Proto {
+            source,
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            max_stack_size,
+            code,
+            constants,
+            num_upvalues,
+            protos,
+            line_info,
+            num_locals,
+            upvalue_names,
+        })
+    }
+
+    fn constant(&mut self) -> Option<Constant> {
+        // The type tags of `lobject.h`, including their variant bits.
+        Some(match self.byte()? {
+            0 => Constant::Nil,
+            1 => Constant::Boolean(self.byte()? != 0),
+            3 => Constant::Number(f64::from_bits(self.u64()?)),
+            19 => Constant::Integer(self.u64()? as i64),
+            4 | 20 => Constant::String(self.string()?.unwrap_or_default()),
+            _ => return None,
+        })
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let bytes = self.bytes.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    // Reads a native-endian unsigned integer of `len` bytes.
+    fn unsigned(&mut self, len: usize) -> Option<u64> {
+        let mut buf = [0; 8];
+        let bytes = self.take(len)?;
+        if cfg!(target_endian = "little") {
+            buf[..len].copy_from_slice(bytes);
+        } else {
+            buf[8 - len..].copy_from_slice(bytes);
+        }
+        Some(u64::from_ne_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.unsigned(8)
+    }
+
+    fn int(&mut self) -> Option<i64> {
+        let len = self.int_size;
+        let value = self.unsigned(len)?;
+        // Sign-extend from the size of `int`.
+        let shift = 64 - 8 * len as u32;
+        Some(((value << shift) as i64) >> shift)
+    }
+
+    // Reads the size of a vector, which must fit in the remaining bytes.
+    fn count(&mut self) -> Option<usize> {
+        let count = self.int()?;
+        if count < 0 || count as usize > self.bytes.len() - self.pos {
+            return None;
+        }
+        Some(count as usize)
+    }
+
+    fn string(&mut self) -> Option<Option<Vec<u8>>> {
+        let size = match self.byte()? {
+            0 => return Some(None),
+            0xFF => {
+                let len = self.size_t_size;
+                self.unsigned(len)? as usize
+            }
+            size => size as usize,
+        };
+        // The size includes the terminating zero, which is not written.
+        let bytes = self.take(size.checked_sub(1)?)?;
+        Some(Some(bytes.to_vec()))
+    }
+}
+
+// The format of the operands of an instruction, as in `lopcodes.h`.
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Abc,
+    Abx,
+    AsBx,
+    Ax,
+}
+
+// How an operand is used: not at all, as a number, as a register, or as a register or constant.
+#[derive(Copy, Clone, PartialEq)]
+enum Arg {
+    N,
+    U,
+    R,
+    K,
+}
+
+const OPCODES: [(&str, Mode, Arg, Arg); 47] = [
+    ("MOVE", Mode::Abc, Arg::R, Arg::N),
+    ("LOADK", Mode::Abx, Arg::K, Arg::N),
+    ("LOADKX", Mode::Abx, Arg::N, Arg::N),
+    ("LOADBOOL", Mode::Abc, Arg::U, Arg::U),
+    ("LOADNIL", Mode::Abc, Arg::U, Arg::N),
+    ("GETUPVAL", Mode::Abc, Arg::U, Arg::N),
+    ("GETTABUP", Mode::Abc, Arg::U, Arg::K),
+    ("GETTABLE", Mode::Abc, Arg::R, Arg::K),
+    ("SETTABUP", Mode::Abc, Arg::K, Arg::K),
+    ("SETUPVAL", Mode::Abc, Arg::U, Arg::N),
+    ("SETTABLE", Mode::Abc, Arg::K, Arg::K),
+    ("NEWTABLE", Mode::Abc, Arg::U, Arg::U),
+    ("SELF", Mode::Abc, Arg::R, Arg::K),
+    ("ADD", Mode::Abc, Arg::K, Arg::K),
+    ("SUB", Mode::Abc, Arg::K, Arg::K),
+    ("MUL", Mode::Abc, Arg::K, Arg::K),
+    ("MOD", Mode::Abc, Arg::K, Arg::K),
+    ("POW", Mode::Abc, Arg::K, Arg::K),
+    ("DIV", Mode::Abc, Arg::K, Arg::K),
+    ("IDIV", Mode::Abc, Arg::K, Arg::K),
+    ("BAND", Mode::Abc, Arg::K, Arg::K),
+    ("BOR", Mode::Abc, Arg::K, Arg::K),
+    ("BXOR", Mode::Abc, Arg::K, Arg::K),
+    ("SHL", Mode::Abc, Arg::K, Arg::K),
+    ("SHR", Mode::Abc, Arg::K, Arg::K),
+    ("UNM", Mode::Abc, Arg::R, Arg::N),
+    ("BNOT", Mode::Abc, Arg::R, Arg::N),
+    ("NOT", Mode::Abc, Arg::R, Arg::N),
+    ("LEN", Mode::Abc, Arg::R, Arg::N),
+    ("CONCAT", Mode::Abc, Arg::R, Arg::R),
+    ("JMP", Mode::AsBx, Arg::R, Arg::N),
+    ("EQ", Mode::Abc, Arg::K, Arg::K),
+    ("LT", Mode::Abc, Arg::K, Arg::K),
+    ("LE", Mode::Abc, Arg::K, Arg::K),
+    ("TEST", Mode::Abc, Arg::N, Arg::U),
+    ("TESTSET", Mode::Abc, Arg::R, Arg::U),
+    ("CALL", Mode::Abc, Arg::U, Arg::U),
+    ("TAILCALL", Mode::Abc, Arg::U, Arg::U),
+    ("RETURN", Mode::Abc, Arg::U, Arg::N),
+    ("FORLOOP", Mode::AsBx, Arg::R, Arg::N),
+    ("FORPREP", Mode::AsBx, Arg::R, Arg::N),
+    ("TFORCALL", Mode::Abc, Arg::N, Arg::U),
+    ("TFORLOOP", Mode::AsBx, Arg::R, Arg::N),
+    ("SETLIST", Mode::Abc, Arg::U, Arg::U),
+    ("CLOSURE", Mode::Abx, Arg::U, Arg::N),
+    ("VARARG", Mode::Abc, Arg::U, Arg::N),
+    ("EXTRAARG", Mode::Ax, Arg::U, Arg::U),
+];
+
+// Operands with this bit set refer to a constant instead of a register.
+const BITRK: i64 = 1 << 8;
+const MAXARG_SBX: i64 = (1 << 18) / 2 - 1;
+
+// Prints a function and the functions it defines. `parent_source` is the source of the enclosing
+// function, which nested functions share unless they were loaded from elsewhere.
+fn print_function(out: &mut StdString, f: &Proto, parent_source: &[u8]) {
+    let source = f.source.as_ref().map_or(parent_source, |source| &source[..]);
+    print_header(out, f, source);
+    print_code(out, f);
+    for proto in &f.protos {
+        print_function(out, proto, source);
+    }
+}
+
+fn print_header(out: &mut StdString, f: &Proto, source: &[u8]) {
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    let _ = writeln!(
+        out,
+        "\n{} <{}:{},{}> ({} instruction{})",
+        if f.line_defined == 0 { "main" } else { "function" },
+        chunk_name(source),
+        f.line_defined,
+        f.last_line_defined,
+        f.code.len(),
+        plural(f.code.len())
+    );
+    let _ = writeln!(
+        out,
+        "{}{} param{}, {} slot{}, {} upvalue{}, {} local{}, {} constant{}, {} function{}",
+        f.num_params,
+        if f.is_vararg { "+" } else { "" },
+        plural(f.num_params as usize),
+        f.max_stack_size,
+        plural(f.max_stack_size as usize),
+        f.num_upvalues,
+        plural(f.num_upvalues),
+        f.num_locals,
+        plural(f.num_locals),
+        f.constants.len(),
+        plural(f.constants.len()),
+        f.protos.len(),
+        plural(f.protos.len())
+    );
+}
+
+fn print_code(out: &mut StdString, f: &Proto) {
+    let mut pc = 0;
+    while pc < f.code.len() {
+        let i = f.code[pc];
+        let op = (i & 0x3F) as usize;
+        let a = ((i >> 6) & 0xFF) as i64;
+        let b = ((i >> 23) & 0x1FF) as i64;
+        let c = ((i >> 14) & 0x1FF) as i64;
+        let ax = (i >> 6) as i64;
+        let bx = ((i >> 14) & 0x3FFFF) as i64;
+        let sbx = bx - MAXARG_SBX;
+        let (name, mode, b_mode, c_mode) = match OPCODES.get(op) {
+            Some(&opcode) => opcode,
+            None => ("?", Mode::Ax, Arg::N, Arg::N),
+        };
+
+        let _ = write!(out, "\t{}\t", pc + 1);
+        match f.line_info.get(pc) {
+            Some(line) => {
+                let _ = write!(out, "[{}]\t", line);
+            }
+            None => out.push_str("[-]\t"),
+        }
+        let _ = write!(out, "{:<9}\t", name);
+        // Constants are shown as negative numbers, starting at -1.
+        let rk = |x: i64| if x & BITRK != 0 { -1 - (x & !BITRK) } else { x };
+        match mode {
+            Mode::Abc => {
+                let _ = write!(out, "{}", a);
+                if b_mode != Arg::N {
+                    let _ = write!(out, " {}", rk(b));
+                }
+                if c_mode != Arg::N {
+                    let _ = write!(out, " {}", rk(c));
+                }
+            }
+            Mode::Abx => {
+                let _ = write!(out, "{}", a);
+                match b_mode {
+                    Arg::K => {
+                        let _ = write!(out, " {}", -1 - bx);
+                    }
+                    Arg::U => {
+                        let _ = write!(out, " {}", bx);
+                    }
+                    _ => {}
+                }
+            }
+            Mode::AsBx => {
+                let _ = write!(out, "{} {}", a, sbx);
+            }
+            Mode::Ax => {
+                let _ = write!(out, "{}", -1 - ax);
+            }
+        }
+
+        let constant = |x: i64| {
+            f.constants
+                .get((x & !BITRK) as usize)
+                .map_or_else(|| "?".to_owned(), format_constant)
+        };
+        let upvalue = |x: i64| match f.upvalue_names.get(x as usize) {
+            Some(Some(name)) => StdString::from_utf8_lossy(name).into_owned(),
+            _ => "-".to_owned(),
+        };
+        let comment = match name {
+            "LOADK" => Some(constant(bx)),
+            "GETUPVAL" | "SETUPVAL" => Some(upvalue(b)),
+            "GETTABUP" if c & BITRK != 0 => Some(format!("{} {}", upvalue(b), constant(c))),
+            "GETTABUP" => Some(upvalue(b)),
+            "SETTABUP" => {
+                let mut comment = upvalue(a);
+                for &x in &[b, c] {
+                    if x & BITRK != 0 {
+                        comment.push(' ');
+                        comment.push_str(&constant(x));
+                    }
+                }
+                Some(comment)
+            }
+            "GETTABLE" | "SELF" if c & BITRK != 0 => Some(constant(c)),
+            "SETTABLE" | "ADD" | "SUB" | "MUL" | "MOD" | "POW" | "DIV" | "IDIV" | "BAND"
+            | "BOR" | "BXOR" | "SHL" | "SHR" | "EQ" | "LT" | "LE"
+                if b & BITRK != 0 || c & BITRK != 0 =>
+            {
+                let operand = |x: i64| if x & BITRK != 0 { constant(x) } else { "-".to_owned() };
+                Some(format!("{} {}", operand(b), operand(c)))
+            }
+            "JMP" | "FORLOOP" | "FORPREP" | "TFORLOOP" => {
+                Some(format!("to {}", sbx + pc as i64 + 2))
+            }
+            "CLOSURE" => f.protos.get(bx as usize).map(|proto| {
+                format!("function <{},{}>", proto.line_defined, proto.last_line_defined)
+            }),
+            "SETLIST" if c == 0 => {
+                // The block number does not fit in C, and is stored in the next instruction.
+                pc += 1;
+                Some(f.code.get(pc).map_or(0, |&n| n >> 6).to_string())
+            }
+            "SETLIST" => Some(c.to_string()),
+            "EXTRAARG" => Some(constant(ax)),
+            _ => None,
+        };
+        if let Some(comment) = comment {
+            let _ = write!(out, "\t; {}", comment);
+        }
+        out.push('\n');
+        pc += 1;
+    }
+}
+
+// Returns the name of a chunk as it appears in Lua messages, like `luaO_chunkid`.
+fn chunk_name(source: &[u8]) -> StdString {
+    let source = StdString::from_utf8_lossy(source);
+    match source.chars().next() {
+        Some('=') | Some('@') => source[1..].to_owned(),
+        _ => format!("[string \"{}\"]", source.lines().next().unwrap_or("")),
+    }
+}
+
+fn format_constant(constant: &Constant) -> StdString {
+    match *constant {
+        Constant::Nil => "nil".to_owned(),
+        Constant::Boolean(b) => b.to_string(),
+        Constant::Number(n) => format!("{:?}", n),
+        Constant::Integer(i) => i.to_string(),
+        Constant::String(ref bytes) => {
+            let mut quoted = StdString::from("\"");
+            for &b in bytes {
+                match b {
+                    b'"' => quoted.push_str("\\\""),
+                    b'\\' => quoted.push_str("\\\\"),
+                    b'\x07' => quoted.push_str("\\a"),
+                    b'\x08' => quoted.push_str("\\b"),
+                    b'\x0C' => quoted.push_str("\\f"),
+                    b'\n' => quoted.push_str("\\n"),
+                    b'\r' => quoted.push_str("\\r"),
+                    b'\t' => quoted.push_str("\\t"),
+                    b'\x0B' => quoted.push_str("\\v"),
+                    0x20..=0x7E => quoted.push(b as char),
+                    _ => {
+                        let _ = write!(quoted, "\\{:03}", b);
+                    }
+                }
+            }
+            quoted.push('"');
+            quoted
+        }
+    }
+}
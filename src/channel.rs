@@ -0,0 +1,78 @@
+use std::sync::mpsc::{Receiver, RecvError, SyncSender, TryRecvError, TrySendError};
+
+use error::Result;
+use lua::{FromLua, ToLua, Value};
+use userdata::{UserData, UserDataMethods};
+
+/// The sending half of a channel, used by scripts to send values to Rust.
+///
+/// This userdata is created by [`Lua::create_channel`]. It has the following methods:
+///
+/// - `send(value)` converts the value to `T` and sends it, waiting while the channel is full.
+///   Returns `false` if the receiver has been dropped, and `true` otherwise.
+/// - `try_send(value)` sends the value if there is room in the channel, and returns whether it
+///   was sent.
+/// - `close()` drops the sender, so that the receiver sees the channel as disconnected once all
+///   values sent before have been received.
+///
+/// [`Lua::create_channel`]: struct.Lua.html#method.create_channel
+pub struct ChannelSender<T>(pub(crate) Option<SyncSender<T>>);
+
+impl<T> UserData for ChannelSender<T>
+where
+    T: 'static + Send + for<'lua> FromLua<'lua>,
+{
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("send", |_, sender, value: T| {
+            Ok(match sender.0 {
+                Some(ref sender) => sender.send(value).is_ok(),
+                None => false,
+            })
+        });
+
+        methods.add_method("try_send", |_, sender, value: T| {
+            Ok(match sender.0 {
+                Some(ref sender) => match sender.try_send(value) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+                },
+                None => false,
+            })
+        });
+
+        methods.add_method_mut("close", |_, sender, ()| {
+            sender.0 = None;
+            Ok(())
+        });
+    }
+}
+
+/// The receiving half of a channel, used by scripts to receive values from Rust.
+///
+/// This userdata is created by [`Lua::create_reverse_channel`]. It has the following methods:
+///
+/// - `recv()` waits for the next value and returns it. Returns `nil` once all senders have been
+///   dropped and every value has been received.
+/// - `try_recv()` returns the next value if one is available, and `nil` otherwise.
+///
+/// [`Lua::create_reverse_channel`]: struct.Lua.html#method.create_reverse_channel
+pub struct ChannelReceiver<T>(pub(crate) Receiver<T>);
+
+impl<T> UserData for ChannelReceiver<T>
+where
+    T: 'static + Send + for<'lua> ToLua<'lua>,
+{
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("recv", |lua, receiver, ()| match receiver.0.recv() {
+            Ok(value) => value.to_lua(lua),
+            Err(RecvError) => Ok(Value::Nil),
+        });
+
+        methods.add_method("try_recv", |lua, receiver, ()| -> Result<Value> {
+            match receiver.0.try_recv() {
+                Ok(value) => value.to_lua(lua),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => Ok(Value::Nil),
+            }
+        });
+    }
+}
@@ -0,0 +1,67 @@
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use error::Result;
+use lua::{Function, Lua, Value};
+use table::Table;
+use types::{Integer, Number};
+
+// Replaces `os.time`, `os.date` and `os.clock` with functions reading the time from `time` and
+// `clock`, for `Lua::set_clock`. The original `os.time` and `os.date` are bound as the first
+// argument of their replacements, which call them for anything but the current time.
+pub(crate) fn install_clock<T, C>(lua: &Lua, time: T, clock: C) -> Result<()>
+where
+    T: 'static + Fn() -> SystemTime,
+    C: 'static + Fn() -> Duration,
+{
+    let os = match lua.globals().get::<_, Option<Table>>("os")? {
+        Some(os) => os,
+        None => return Ok(()),
+    };
+    let time = Rc::new(time);
+
+    let os_time = os.get::<_, Function>("time")?;
+    let time_now = time.clone();
+    os.set(
+        "time",
+        lua.create_function(move |_, (os_time, table): (Function, Value)| match table {
+            Value::Nil => Ok(Value::Integer(timestamp(time_now()))),
+            table => os_time.call::<_, Value>(table),
+        }).bind(os_time)?,
+    )?;
+
+    let os_date = os.get::<_, Function>("date")?;
+    os.set(
+        "date",
+        lua.create_function(
+            move |_, (os_date, format, timestamp_value): (Function, Value, Value)| {
+                let timestamp_value = match timestamp_value {
+                    Value::Nil => Value::Integer(timestamp(time())),
+                    timestamp_value => timestamp_value,
+                };
+                os_date.call::<_, Value>((format, timestamp_value))
+            },
+        ).bind(os_date)?,
+    )?;
+
+    os.set(
+        "clock",
+        lua.create_function(move |_, ()| {
+            let clock = clock();
+            Ok(clock.as_secs() as Number + clock.subsec_nanos() as Number / 1e9)
+        }),
+    )?;
+
+    Ok(())
+}
+
+// Returns the whole number of seconds between the Unix epoch and `time`, rounded down.
+fn timestamp(time: SystemTime) -> Integer {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as Integer,
+        Err(err) => {
+            let before = err.duration();
+            -(before.as_secs() as Integer) - if before.subsec_nanos() > 0 { 1 } else { 0 }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+#[cfg(feature = "yaml")]
+use serde_yaml;
+#[cfg(feature = "toml")]
+use toml;
+
+use error::{Error, Result};
+use lua::{Lua, Value};
+use table::Table;
+
+// Parses a TOML document into a table, for `Lua::table_from_toml`.
+#[cfg(feature = "toml")]
+pub(crate) fn table_from_toml<'lua>(lua: &'lua Lua, s: &str) -> Result<Table<'lua>> {
+    let document = toml::from_str::<toml::Table>(s).map_err(Error::external)?;
+    toml_table(lua, document)
+}
+
+#[cfg(feature = "toml")]
+fn toml_table<'lua>(lua: &'lua Lua, map: toml::Table) -> Result<Table<'lua>> {
+    let table = lua.create_table_with_capacity(0, map.len());
+    for (key, value) in map {
+        table.raw_set(key, toml_value(lua, value)?)?;
+    }
+    Ok(table)
+}
+
+#[cfg(feature = "toml")]
+fn toml_value<'lua>(lua: &'lua Lua, value: toml::Value) -> Result<Value<'lua>> {
+    use toml::Value as Toml;
+
+    Ok(match value {
+        Toml::String(s) => Value::String(lua.create_string(&s)),
+        Toml::Integer(i) => Value::Integer(i),
+        Toml::Float(n) => Value::Number(n),
+        Toml::Boolean(b) => Value::Boolean(b),
+        Toml::Datetime(datetime) => Value::String(lua.create_string(&datetime.to_string())),
+        Toml::Array(values) => {
+            let table = lua.create_table_with_capacity(values.len(), 0);
+            for (i, value) in values.into_iter().enumerate() {
+                table.raw_set(i + 1, toml_value(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+        Toml::Table(map) => Value::Table(toml_table(lua, map)?),
+    })
+}
+
+// Parses a YAML document into a table, for `Lua::table_from_yaml`.
+#[cfg(feature = "yaml")]
+pub(crate) fn table_from_yaml<'lua>(lua: &'lua Lua, s: &str) -> Result<Table<'lua>> {
+    use serde_yaml::Value as Yaml;
+
+    match serde_yaml::from_str::<Yaml>(s).map_err(Error::external)? {
+        Yaml::Null => Ok(lua.create_table()),
+        Yaml::Mapping(map) => yaml_mapping(lua, map),
+        _ => Err(yaml_error("document is not a mapping")),
+    }
+}
+
+#[cfg(feature = "yaml")]
+fn yaml_mapping<'lua>(lua: &'lua Lua, map: serde_yaml::Mapping) -> Result<Table<'lua>> {
+    let table = lua.create_table_with_capacity(0, map.len());
+    for (key, value) in map {
+        let key = match yaml_value(lua, key)? {
+            Value::Nil => return Err(yaml_error("mapping key is null")),
+            Value::Number(n) if n.is_nan() => return Err(yaml_error("mapping key is NaN")),
+            key => key,
+        };
+        table.raw_set(key, yaml_value(lua, value)?)?;
+    }
+    Ok(table)
+}
+
+#[cfg(feature = "yaml")]
+fn yaml_value<'lua>(lua: &'lua Lua, value: serde_yaml::Value) -> Result<Value<'lua>> {
+    use serde_yaml::Value as Yaml;
+
+    Ok(match value {
+        Yaml::Null => Value::Nil,
+        Yaml::Bool(b) => Value::Boolean(b),
+        Yaml::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Yaml::String(s) => Value::String(lua.create_string(&s)),
+        Yaml::Sequence(values) => {
+            let table = lua.create_table_with_capacity(values.len(), 0);
+            for (i, value) in values.into_iter().enumerate() {
+                table.raw_set(i + 1, yaml_value(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+        Yaml::Mapping(map) => Value::Table(yaml_mapping(lua, map)?),
+        Yaml::Tagged(tagged) => yaml_value(lua, tagged.value)?,
+    })
+}
+
+#[cfg(feature = "yaml")]
+fn yaml_error(message: &str) -> Error {
+    Error::ToLuaConversionError {
+        from: "YAML",
+        to: "table",
+        message: Some(message.to_owned()),
+    }
+}
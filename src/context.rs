@@ -0,0 +1,40 @@
+use std::ops::Deref;
+
+use lua::Lua;
+
+/// The Lua state, as seen by the closure passed to [`Lua::context`].
+///
+/// A context gives access to every method of [`Lua`], and [`lua`] returns the state itself, for
+/// functions which return handles created from it. The handles created in a context cannot
+/// leave the closure, so values which must outlive it are stored in the registry with
+/// [`Lua::create_registry_value`], which returns a key that does not borrow the state.
+///
+/// [`Lua::context`]: struct.Lua.html#method.context
+/// [`Lua`]: struct.Lua.html
+/// [`lua`]: #method.lua
+/// [`Lua::create_registry_value`]: struct.Lua.html#method.create_registry_value
+#[derive(Copy, Clone)]
+pub struct Context<'lua>(&'lua Lua);
+
+impl<'lua> Context<'lua> {
+    pub(crate) fn new(lua: &'lua Lua) -> Context<'lua> {
+        Context(lua)
+    }
+
+    /// Returns the Lua state, borrowed for as long as the context.
+    ///
+    /// The handles created through the returned reference can be returned from functions taking
+    /// the context by value, unlike those created through the context itself, which borrow the
+    /// local variable holding it.
+    pub fn lua(self) -> &'lua Lua {
+        self.0
+    }
+}
+
+impl<'lua> Deref for Context<'lua> {
+    type Target = Lua;
+
+    fn deref(&self) -> &Lua {
+        self.0
+    }
+}
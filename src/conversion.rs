@@ -1,13 +1,30 @@
-use std::collections::{BTreeMap, HashMap};
+use std::any::{type_name, TypeId};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::hash::{BuildHasher, Hash};
+use std::os::raw::c_void;
+use std::num::{NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize,
+               NonZeroU128, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize};
 use std::string::String as StdString;
 
+#[cfg(feature = "chrono")]
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
 use error::*;
-use types::{Integer, LightUserData, Number};
+use types::{DurationFormat, Integer, LightUserData, Number, TypedLightUserData};
 use lua::*;
 use string::String;
 use table::Table;
 use userdata::{AnyUserData, UserData};
+#[cfg(feature = "chrono")]
+use datetime;
+
+// The number of values read with a single call into Lua when converting a sequence.
+const SEQUENCE_BATCH_SIZE: usize = 32;
 
 impl<'lua> ToLua<'lua> for Value<'lua> {
     fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
@@ -34,7 +51,7 @@ impl<'lua> FromLua<'lua> for String<'lua> {
 }
 
 impl<'lua> ToLua<'lua> for Table<'lua> {
-    fn to_lua(self, _: &'lua Lua) -> Result<Value> {
+    fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
         Ok(Value::Table(self))
     }
 }
@@ -71,6 +88,37 @@ impl<'lua> FromLua<'lua> for Function<'lua> {
     }
 }
 
+impl<'lua, A, R> ToLua<'lua> for TypedFunction<'lua, A, R>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+{
+    fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Function(self.into_function()))
+    }
+}
+
+impl<'lua, A, R> FromLua<'lua> for TypedFunction<'lua, A, R>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+{
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<TypedFunction<'lua, A, R>> {
+        Ok(TypedFunction::new(Function::from_lua(value, lua)?))
+    }
+}
+
+impl<'lua, A, R, F> ToLua<'lua> for WrappedFunction<A, R, F>
+where
+    A: FromLuaMulti<'lua>,
+    R: ToLuaMulti<'lua>,
+    F: 'static + FnMut(&'lua Lua, A) -> Result<R>,
+{
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Function(lua.create_function(self.func)))
+    }
+}
+
 impl<'lua> ToLua<'lua> for Thread<'lua> {
     fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
         Ok(Value::Thread(self))
@@ -138,11 +186,7 @@ impl<'lua> FromLua<'lua> for Error {
     fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Error> {
         match value {
             Value::Error(err) => Ok(err),
-            val => Ok(Error::RuntimeError(
-                lua.coerce_string(val)
-                    .and_then(|s| Ok(s.to_str()?.to_owned()))
-                    .unwrap_or_else(|_| "<unprintable error>".to_owned()),
-            )),
+            val => Ok(lua.runtime_error(val)),
         }
     }
 }
@@ -182,6 +226,34 @@ impl<'lua> FromLua<'lua> for LightUserData {
     }
 }
 
+impl<'lua, T: 'static> ToLua<'lua> for TypedLightUserData<T> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        let ud = LightUserData(self.0 as *mut c_void);
+        lua.tag_light_userdata(ud.0, TypeId::of::<T>());
+        Ok(Value::LightUserData(ud))
+    }
+}
+
+impl<'lua, T: 'static> FromLua<'lua> for TypedLightUserData<T> {
+    fn from_lua(value: Value, lua: &'lua Lua) -> Result<Self> {
+        let message = match value {
+            Value::LightUserData(ud) => match lua.light_userdata_type(ud.0) {
+                Some(type_id) if type_id == TypeId::of::<T>() => {
+                    return Ok(TypedLightUserData(ud.0 as *mut T))
+                }
+                Some(_) => "light userdata points to a value of another type",
+                None => "light userdata was not created from a TypedLightUserData",
+            },
+            _ => "expected light userdata",
+        };
+        Err(Error::FromLuaConversionError {
+            from: value.type_name(),
+            to: type_name::<TypedLightUserData<T>>(),
+            message: Some(message.to_owned()),
+        })
+    }
+}
+
 impl<'lua> ToLua<'lua> for StdString {
     fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
         Ok(Value::String(lua.create_string(&self)))
@@ -194,23 +266,196 @@ impl<'lua> FromLua<'lua> for StdString {
     }
 }
 
-impl<'lua, 'a> ToLua<'lua> for &'a str {
+impl<'lua> ToLua<'lua> for &str {
     fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
         Ok(Value::String(lua.create_string(self)))
     }
 }
 
+impl<'lua, 'a> ToLua<'lua> for Cow<'a, str> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::String(lua.create_string(&self)))
+    }
+}
+
+/// Always produces an owned value, as the string cannot borrow from the Lua value.
+impl<'lua> FromLua<'lua> for Cow<'static, str> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        Ok(Cow::Owned(StdString::from_lua(value, lua)?))
+    }
+}
+
+impl<'lua> ToLua<'lua> for Box<str> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::String(lua.create_string(&self)))
+    }
+}
+
+impl<'lua> FromLua<'lua> for Box<str> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        Ok(StdString::from_lua(value, lua)?.into_boxed_str())
+    }
+}
+
+impl<'lua> ToLua<'lua> for char {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        let mut buf = [0; 4];
+        Ok(Value::String(lua.create_string(self.encode_utf8(&mut buf))))
+    }
+}
+
+impl<'lua> FromLua<'lua> for char {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        let type_name = value.type_name();
+        let s = lua.coerce_string(value)?;
+        let mut chars = s.to_str()?.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => Err(Error::FromLuaConversionError {
+                from: type_name,
+                to: "char",
+                message: Some(format!(
+                    "expected a string of one character, got {} characters",
+                    s.to_str()?.chars().count()
+                )),
+            }),
+        }
+    }
+}
+
+impl<'lua> ToLua<'lua> for OsString {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        os_str_to_lua(&self, lua)
+    }
+}
+
+impl<'lua> FromLua<'lua> for OsString {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        os_string_from_lua(value, lua, "OsString")
+    }
+}
+
+impl<'lua> ToLua<'lua> for &OsStr {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        os_str_to_lua(self, lua)
+    }
+}
+
+impl<'lua> ToLua<'lua> for PathBuf {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        os_str_to_lua(self.as_os_str(), lua)
+    }
+}
+
+impl<'lua> FromLua<'lua> for PathBuf {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        Ok(PathBuf::from(os_string_from_lua(value, lua, "PathBuf")?))
+    }
+}
+
+impl<'lua> ToLua<'lua> for &Path {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        os_str_to_lua(self.as_os_str(), lua)
+    }
+}
+
+// On Unix, OS strings are passed to Lua as their raw bytes, which need not be valid UTF-8. Other
+// platforms have no byte representation of OS strings, so only valid Unicode can be converted.
+#[cfg(unix)]
+fn os_str_to_lua<'lua>(s: &OsStr, lua: &'lua Lua) -> Result<Value<'lua>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(Value::String(lua.create_byte_string(s.as_bytes())))
+}
+
+#[cfg(not(unix))]
+fn os_str_to_lua<'lua>(s: &OsStr, lua: &'lua Lua) -> Result<Value<'lua>> {
+    match s.to_str() {
+        Some(s) => Ok(Value::String(lua.create_string(s))),
+        None => Err(Error::ToLuaConversionError {
+            from: "OsStr",
+            to: "string",
+            message: Some("OS string is not valid unicode".to_string()),
+        }),
+    }
+}
+
+#[cfg(unix)]
+fn os_string_from_lua<'lua>(
+    value: Value<'lua>,
+    lua: &'lua Lua,
+    _to: &'static str,
+) -> Result<OsString> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(OsStr::from_bytes(lua.coerce_string(value)?.as_bytes()).to_owned())
+}
+
+#[cfg(not(unix))]
+fn os_string_from_lua<'lua>(
+    value: Value<'lua>,
+    lua: &'lua Lua,
+    to: &'static str,
+) -> Result<OsString> {
+    let s = lua.coerce_string(value)?;
+    match s.to_str() {
+        Ok(s) => Ok(OsString::from(s)),
+        Err(_) => Err(Error::FromLuaConversionError {
+            from: "string",
+            to: to,
+            message: Some("string is not valid UTF-8".to_string()),
+        }),
+    }
+}
+
 macro_rules! lua_convert_int {
     ($x: ty) => {
+        /// Converts the integer to a Lua integer, or to a float if it is out of range of Lua
+        /// integers but can be represented exactly as a float.
         impl<'lua> ToLua<'lua> for $x {
             fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
-                Ok(Value::Integer(self as Integer))
+                Integer::try_from(self).map(Value::Integer).or_else(|_| {
+                    let n = self as Number;
+                    if n < <$x>::MAX as Number && n as $x == self {
+                        Ok(Value::Number(n))
+                    } else {
+                        Err(Error::ToLuaConversionError {
+                            from: stringify!($x),
+                            to: "number",
+                            message: Some(format!(
+                                "{} cannot be represented exactly as a Lua number",
+                                self
+                            )),
+                        })
+                    }
+                })
             }
         }
 
+        /// Converts integers and floats without a fractional part, failing instead of truncating
+        /// if the number is out of range. Strings are coerced unless strict numeric conversions
+        /// are enabled.
         impl<'lua> FromLua<'lua> for $x {
             fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
-                Ok(lua.coerce_integer(value)? as $x)
+                let type_name = value.type_name();
+                let error = |message| Error::FromLuaConversionError {
+                    from: type_name,
+                    to: stringify!($x),
+                    message: Some(message),
+                };
+                match value {
+                    Value::Integer(i) => <$x>::try_from(i)
+                        .map_err(|_| error(format!("number {} is out of range", i))),
+                    Value::Number(n) if n.fract() != 0.0 && n.is_finite() => {
+                        Err(error(format!("number {} has a fractional part", n)))
+                    }
+                    Value::Number(n) => number_to_int(n)
+                        .ok_or_else(|| error(format!("number {} is out of range", n))),
+                    value => {
+                        check_strict_number(&value, stringify!($x), lua)?;
+                        let i = lua.coerce_integer(value)?;
+                        <$x>::try_from(i)
+                            .map_err(|_| error(format!("number {} is out of range", i)))
+                    }
+                }
             }
         }
     }
@@ -224,9 +469,60 @@ lua_convert_int!(i32);
 lua_convert_int!(u32);
 lua_convert_int!(i64);
 lua_convert_int!(u64);
+lua_convert_int!(i128);
+lua_convert_int!(u128);
 lua_convert_int!(isize);
 lua_convert_int!(usize);
 
+macro_rules! lua_convert_non_zero {
+    ($x: ty, $prim: ty) => {
+        impl<'lua> ToLua<'lua> for $x {
+            fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+                self.get().to_lua(lua)
+            }
+        }
+
+        impl<'lua> FromLua<'lua> for $x {
+            fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+                let type_name = value.type_name();
+                <$x>::new(<$prim>::from_lua(value, lua)?).ok_or_else(|| {
+                    Error::FromLuaConversionError {
+                        from: type_name,
+                        to: stringify!($x),
+                        message: Some("expected a non-zero integer, got 0".to_string()),
+                    }
+                })
+            }
+        }
+    }
+}
+
+lua_convert_non_zero!(NonZeroI8, i8);
+lua_convert_non_zero!(NonZeroU8, u8);
+lua_convert_non_zero!(NonZeroI16, i16);
+lua_convert_non_zero!(NonZeroU16, u16);
+lua_convert_non_zero!(NonZeroI32, i32);
+lua_convert_non_zero!(NonZeroU32, u32);
+lua_convert_non_zero!(NonZeroI64, i64);
+lua_convert_non_zero!(NonZeroU64, u64);
+lua_convert_non_zero!(NonZeroI128, i128);
+lua_convert_non_zero!(NonZeroU128, u128);
+lua_convert_non_zero!(NonZeroIsize, isize);
+lua_convert_non_zero!(NonZeroUsize, usize);
+
+// Converts a float without a fractional part to an integer, if it is in range.
+fn number_to_int<T: TryFrom<i128> + TryFrom<u128>>(n: Number) -> Option<T> {
+    if n.fract() != 0.0 {
+        None
+    } else if n >= 0.0 && n < u128::MAX as Number {
+        T::try_from(n as u128).ok()
+    } else if n < 0.0 && n >= i128::MIN as Number {
+        T::try_from(n as i128).ok()
+    } else {
+        None
+    }
+}
+
 macro_rules! lua_convert_float {
     ($x: ty) => {
         impl<'lua> ToLua<'lua> for $x {
@@ -235,17 +531,247 @@ macro_rules! lua_convert_float {
             }
         }
 
+        /// Converts numbers, and strings unless strict numeric conversions are enabled. In
+        /// strict mode, integers and numbers must be exactly representable.
         impl<'lua> FromLua<'lua> for $x {
             fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
-                Ok(lua.coerce_number(value)? as $x)
+                let type_name = value.type_name();
+                let (n, exact) = match value {
+                    Value::Number(n) => (n, true),
+                    Value::Integer(i) => {
+                        let n = i as Number;
+                        (n, n as i128 == i as i128)
+                    }
+                    value => {
+                        check_strict_number(&value, stringify!($x), lua)?;
+                        (lua.coerce_number(value)?, true)
+                    }
+                };
+
+                // Converting back finds numbers which are out of range or lose precision.
+                let x = n as $x;
+                let exact = exact && (x as Number == n || n.is_nan());
+                if !exact && lua.strict_numbers() {
+                    return Err(Error::FromLuaConversionError {
+                        from: type_name,
+                        to: stringify!($x),
+                        message: Some(format!(
+                            "number {} cannot be represented exactly",
+                            n
+                        )),
+                    });
+                }
+                Ok(x)
             }
         }
     }
 }
 
+// Fails if strict numeric conversions are enabled, for a value which is not a number.
+fn check_strict_number<'lua>(value: &Value<'lua>, to: &'static str, lua: &'lua Lua) -> Result<()> {
+    if !lua.strict_numbers() {
+        return Ok(());
+    }
+    let shown = match *value {
+        Value::String(ref s) => format!("string {:?}", StdString::from_utf8_lossy(s.as_bytes())),
+        ref value => value.type_name().to_string(),
+    };
+    Err(Error::FromLuaConversionError {
+        from: value.type_name(),
+        to,
+        message: Some(format!(
+            "{} is not a number, and strict numeric conversions are enabled",
+            shown
+        )),
+    })
+}
+
 lua_convert_float!(f32);
 lua_convert_float!(f64);
 
+/// Converts the duration to fractional seconds, or to whole milliseconds if set with
+/// [`Lua::set_duration_format`].
+///
+/// [`Lua::set_duration_format`]: struct.Lua.html#method.set_duration_format
+impl<'lua> ToLua<'lua> for Duration {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        match lua.duration_format() {
+            DurationFormat::Seconds => Ok(Value::Number(self.as_secs_f64())),
+            DurationFormat::Milliseconds => match Integer::try_from(self.as_millis()) {
+                Ok(millis) => Ok(Value::Integer(millis)),
+                Err(_) => Err(Error::ToLuaConversionError {
+                    from: "Duration",
+                    to: "integer",
+                    message: Some("duration is too long".to_string()),
+                }),
+            },
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for Duration {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        let type_name = value.type_name();
+        let secs = match lua.duration_format() {
+            DurationFormat::Seconds => lua.coerce_number(value)?,
+            DurationFormat::Milliseconds => lua.coerce_number(value)? / 1000.0,
+        };
+        duration_from_secs(secs).ok_or_else(|| Error::FromLuaConversionError {
+            from: type_name,
+            to: "Duration",
+            message: Some("duration must be a non-negative finite number".to_string()),
+        })
+    }
+}
+
+/// Converts the time to the number of seconds since the Unix epoch, which is negative for times
+/// before the epoch.
+impl<'lua> ToLua<'lua> for SystemTime {
+    fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Number(match self.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs_f64(),
+            Err(err) => -err.duration().as_secs_f64(),
+        }))
+    }
+}
+
+impl<'lua> FromLua<'lua> for SystemTime {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        let type_name = value.type_name();
+        let secs = lua.coerce_number(value)?;
+        let time = if secs >= 0.0 {
+            duration_from_secs(secs).and_then(|d| UNIX_EPOCH.checked_add(d))
+        } else {
+            duration_from_secs(-secs).and_then(|d| UNIX_EPOCH.checked_sub(d))
+        };
+        time.ok_or_else(|| Error::FromLuaConversionError {
+            from: type_name,
+            to: "SystemTime",
+            message: Some("time is out of range".to_string()),
+        })
+    }
+}
+
+fn duration_from_secs(secs: Number) -> Option<Duration> {
+    if secs >= 0.0 && secs <= u64::MAX as Number {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+/// Converts the date and time to the number of seconds since the Unix epoch, like `SystemTime`.
+///
+/// Requires the `chrono` feature.
+#[cfg(feature = "chrono")]
+impl<'lua, Tz: TimeZone> ToLua<'lua> for DateTime<Tz> {
+    fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Number(datetime::to_timestamp(&self)))
+    }
+}
+
+/// Converts a number of seconds since the Unix epoch, an RFC 3339 string, or a table with the
+/// fields of the table returned by `os.date("!*t")`.
+///
+/// Requires the `chrono` feature.
+#[cfg(feature = "chrono")]
+impl<'lua> FromLua<'lua> for DateTime<Utc> {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
+        Ok(datetime::datetime_from_value(value)?.with_timezone(&Utc))
+    }
+}
+
+/// Converts a number of seconds since the Unix epoch, an RFC 3339 string, or a table with the
+/// fields of the table returned by `os.date("!*t")`. Only RFC 3339 strings have an offset other
+/// than UTC.
+///
+/// Requires the `chrono` feature.
+#[cfg(feature = "chrono")]
+impl<'lua> FromLua<'lua> for DateTime<FixedOffset> {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
+        datetime::datetime_from_value(value)
+    }
+}
+
+/// Converts the date and time to a table with the fields of the table returned by
+/// `os.date("*t")`, except `isdst`. `sec` has a fractional part if the time does.
+///
+/// Requires the `chrono` feature.
+#[cfg(feature = "chrono")]
+impl<'lua> ToLua<'lua> for NaiveDateTime {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Table(datetime::datetime_table(lua, &self)?))
+    }
+}
+
+/// Converts a table with the fields `year`, `month`, `day`, and optionally `hour`, `min` and
+/// `sec`, or a string such as `"2018-03-21T12:30:00"`.
+///
+/// Requires the `chrono` feature.
+#[cfg(feature = "chrono")]
+impl<'lua> FromLua<'lua> for NaiveDateTime {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
+        match value {
+            Value::Table(table) => datetime::naive_from_table(&table),
+            Value::String(s) => s.to_str()?.parse().map_err(|err: ::chrono::ParseError| {
+                Error::FromLuaConversionError {
+                    from: "string",
+                    to: "NaiveDateTime",
+                    message: Some(err.to_string()),
+                }
+            }),
+            value => Err(Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "NaiveDateTime",
+                message: Some("expected a table or a string".to_owned()),
+            }),
+        }
+    }
+}
+
+/// Converts the date to a table with the fields `year`, `month`, `day`, `wday` and `yday`.
+///
+/// Requires the `chrono` feature.
+#[cfg(feature = "chrono")]
+impl<'lua> ToLua<'lua> for NaiveDate {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Table(datetime::date_table(lua, &self)?))
+    }
+}
+
+/// Converts a table with the fields `year`, `month` and `day`, or a string such as
+/// `"2018-03-21"`.
+///
+/// Requires the `chrono` feature.
+#[cfg(feature = "chrono")]
+impl<'lua> FromLua<'lua> for NaiveDate {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
+        match value {
+            Value::Table(table) => NaiveDate::from_ymd_opt(
+                table.get("year")?,
+                table.get("month")?,
+                table.get("day")?,
+            ).ok_or_else(|| Error::FromLuaConversionError {
+                from: "table",
+                to: "NaiveDate",
+                message: Some("invalid date".to_owned()),
+            }),
+            Value::String(s) => s.to_str()?.parse().map_err(|err: ::chrono::ParseError| {
+                Error::FromLuaConversionError {
+                    from: "string",
+                    to: "NaiveDate",
+                    message: Some(err.to_string()),
+                }
+            }),
+            value => Err(Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "NaiveDate",
+                message: Some("expected a table or a string".to_owned()),
+            }),
+        }
+    }
+}
+
 impl<'lua, T: ToLua<'lua>> ToLua<'lua> for Vec<T> {
     fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
         Ok(Value::Table(lua.create_sequence_from(self)?))
@@ -255,7 +781,7 @@ impl<'lua, T: ToLua<'lua>> ToLua<'lua> for Vec<T> {
 impl<'lua, T: FromLua<'lua>> FromLua<'lua> for Vec<T> {
     fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
         if let Value::Table(table) = value {
-            table.sequence_values().collect()
+            table.sequence_values_batched(SEQUENCE_BATCH_SIZE).collect()
         } else {
             Err(Error::FromLuaConversionError {
                 from: value.type_name(),
@@ -266,6 +792,59 @@ impl<'lua, T: FromLua<'lua>> FromLua<'lua> for Vec<T> {
     }
 }
 
+impl<'lua, T: ToLua<'lua>, const N: usize> ToLua<'lua> for [T; N] {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Table(lua.create_sequence_from(self)?))
+    }
+}
+
+impl<'lua, T: FromLua<'lua>, const N: usize> FromLua<'lua> for [T; N] {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        let type_name = value.type_name();
+        let values = Vec::<T>::from_lua(value, lua)?;
+        let len = values.len();
+        <[T; N] as TryFrom<Vec<T>>>::try_from(values).map_err(|_| {
+            Error::FromLuaConversionError {
+                from: type_name,
+                to: "array",
+                message: Some(format!("expected table of length {}, got {}", N, len)),
+            }
+        })
+    }
+}
+
+impl<'lua, T: ToLua<'lua>> ToLua<'lua> for Box<[T]> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Table(lua.create_sequence_from(self.into_vec())?))
+    }
+}
+
+impl<'lua, T: FromLua<'lua>> FromLua<'lua> for Box<[T]> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        Ok(Vec::<T>::from_lua(value, lua)?.into_boxed_slice())
+    }
+}
+
+impl<'lua, T: ToLua<'lua>> ToLua<'lua> for VecDeque<T> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Table(lua.create_sequence_from(self)?))
+    }
+}
+
+impl<'lua, T: FromLua<'lua>> FromLua<'lua> for VecDeque<T> {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
+        if let Value::Table(table) = value {
+            table.sequence_values_batched(SEQUENCE_BATCH_SIZE).collect()
+        } else {
+            Err(Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "VecDeque",
+                message: Some("expected table".to_string()),
+            })
+        }
+    }
+}
+
 impl<'lua, K: Eq + Hash + ToLua<'lua>, V: ToLua<'lua>, S: BuildHasher> ToLua<'lua>
     for HashMap<K, V, S> {
     fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
@@ -308,6 +887,51 @@ impl<'lua, K: Ord + FromLua<'lua>, V: FromLua<'lua>> FromLua<'lua> for BTreeMap<
     }
 }
 
+/// Converts the set to a table with the elements as keys and `true` as values.
+impl<'lua, T: Eq + Hash + ToLua<'lua>, S: BuildHasher> ToLua<'lua> for HashSet<T, S> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Table(lua.create_table_from(self.into_iter().map(|v| (v, true)))?))
+    }
+}
+
+/// Converts a table to a set of its keys, the values of the table are ignored.
+impl<'lua, T: Eq + Hash + FromLua<'lua>, S: BuildHasher + Default> FromLua<'lua>
+    for HashSet<T, S> {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
+        if let Value::Table(table) = value {
+            table.pairs::<T, Value>().map(|pair| Ok(pair?.0)).collect()
+        } else {
+            Err(Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "HashSet",
+                message: Some("expected table".to_string()),
+            })
+        }
+    }
+}
+
+/// Converts the set to a table with the elements as keys and `true` as values.
+impl<'lua, T: Ord + ToLua<'lua>> ToLua<'lua> for BTreeSet<T> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::Table(lua.create_table_from(self.into_iter().map(|v| (v, true)))?))
+    }
+}
+
+/// Converts a table to a set of its keys, the values of the table are ignored.
+impl<'lua, T: Ord + FromLua<'lua>> FromLua<'lua> for BTreeSet<T> {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<Self> {
+        if let Value::Table(table) = value {
+            table.pairs::<T, Value>().map(|pair| Ok(pair?.0)).collect()
+        } else {
+            Err(Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "BTreeSet",
+                message: Some("expected table".to_string()),
+            })
+        }
+    }
+}
+
 impl<'lua, T: ToLua<'lua>> ToLua<'lua> for Option<T> {
     fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
         match self {
@@ -325,3 +949,125 @@ impl<'lua, T: FromLua<'lua>> FromLua<'lua> for Option<T> {
         }
     }
 }
+
+/// Converts JSON values to Lua values.
+///
+/// `null` becomes `nil`, numbers which fit into an integer become integers and all other numbers
+/// become floats, arrays become tables with the keys `1..n` and objects become tables with string
+/// keys. Note that `null` array elements and object values leave holes in the resulting table.
+///
+/// Requires the `serde_json` feature.
+#[cfg(feature = "serde_json")]
+impl<'lua> ToLua<'lua> for ::serde_json::Value {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        use serde_json::Value as Json;
+
+        Ok(match self {
+            Json::Null => Nil,
+            Json::Bool(b) => Value::Boolean(b),
+            Json::Number(n) => match n.as_i64() {
+                Some(i) => Value::Integer(i),
+                None => Value::Number(n.as_f64().unwrap_or(Number::NAN)),
+            },
+            Json::String(s) => Value::String(lua.create_string(&s)),
+            Json::Array(values) => Value::Table(lua.create_sequence_from(values)?),
+            Json::Object(map) => Value::Table(lua.create_table_from(map)?),
+        })
+    }
+}
+
+/// Converts Lua values to JSON values.
+///
+/// `nil` and [`Lua::null`] become `null`, integers and floats become numbers, and strings must be
+/// valid UTF-8. A table with only the keys `1..n` or the [`Lua::array_metatable`] becomes an
+/// array, and any other table, including an empty one, becomes an object. Object keys must be
+/// strings or numbers, numbers are converted to their string representation. Functions, threads,
+/// userdata, recursive tables, tables nested more than 128 levels deep and floats which are not
+/// finite cannot be converted.
+///
+/// Requires the `serde_json` feature.
+///
+/// [`Lua::null`]: struct.Lua.html#method.null
+/// [`Lua::array_metatable`]: struct.Lua.html#method.array_metatable
+#[cfg(feature = "serde_json")]
+impl<'lua> FromLua<'lua> for ::serde_json::Value {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        json_from_lua(value, lua, &mut Vec::new())
+    }
+}
+
+// Deepest nesting of tables converted to JSON, the same as the limit of the parser of
+// `serde_json`. Deeper tables would overflow the stack of the conversion.
+#[cfg(feature = "serde_json")]
+const MAX_JSON_DEPTH: usize = 128;
+
+// Converts a Lua value to JSON, `visited` holds the tables currently being converted.
+#[cfg(feature = "serde_json")]
+fn json_from_lua<'lua>(
+    value: Value<'lua>,
+    lua: &'lua Lua,
+    visited: &mut Vec<*const ::std::os::raw::c_void>,
+) -> Result<::serde_json::Value> {
+    use serde_json::{Map, Value as Json};
+
+    let error = |value: &Value, message: &str| Error::FromLuaConversionError {
+        from: value.type_name(),
+        to: "JSON value",
+        message: Some(message.to_owned()),
+    };
+
+    Ok(match value {
+        Nil => Json::Null,
+        Value::LightUserData(ud) if ud.0.is_null() => Json::Null,
+        Value::Boolean(b) => Json::Bool(b),
+        Value::Integer(i) => Json::from(i),
+        Value::Number(n) => match ::serde_json::Number::from_f64(n) {
+            Some(n) => Json::Number(n),
+            None => return Err(error(&value, "number is not finite")),
+        },
+        Value::String(ref s) => Json::String(s.to_str()?.to_owned()),
+        Value::Table(ref table) => {
+            let ptr = table.to_pointer();
+            if visited.contains(&ptr) {
+                return Err(error(&value, "recursive table"));
+            }
+            if visited.len() == MAX_JSON_DEPTH {
+                return Err(error(&value, "tables are nested too deeply"));
+            }
+            visited.push(ptr);
+
+            let len = table.raw_len();
+            let pairs = table
+                .clone()
+                .pairs::<Value, Value>()
+                .collect::<Result<Vec<_>>>()?;
+
+            let is_sequence = len > 0 && pairs.len() as Integer == len;
+            let json = if is_sequence || lua.is_array_table(table) {
+                let values = table
+                    .clone()
+                    .sequence_values::<Value>()
+                    .map(|v| json_from_lua(v?, lua, visited))
+                    .collect::<Result<Vec<_>>>()?;
+                Json::Array(values)
+            } else {
+                let mut map = Map::new();
+                for (k, v) in pairs {
+                    let key = match k {
+                        Value::String(s) => s.to_str()?.to_owned(),
+                        Value::Integer(_) | Value::Number(_) => {
+                            lua.coerce_string(k)?.to_str()?.to_owned()
+                        }
+                        k => return Err(error(&k, "table key is not a string or number")),
+                    };
+                    map.insert(key, json_from_lua(v, lua, visited)?);
+                }
+                Json::Object(map)
+            };
+
+            visited.pop();
+            json
+        }
+        _ => return Err(error(&value, "cannot be represented as JSON")),
+    })
+}
@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::os::raw::{c_char, c_int, c_void};
+use std::slice;
+use std::string::String as StdString;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytecode;
+use ffi;
+
+/// The lines of Lua code which ran, and how often, recorded after [`Lua::start_coverage`].
+///
+/// Lines are keyed by chunk, named like the breakpoints of a [`Debugger`]: the name a chunk was
+/// loaded with, or the path of a file loaded by `loadfile` or `require`. The coverage can be
+/// exported as LCOV or Cobertura XML, to be merged with the coverage of the host in CI.
+///
+/// [`Lua::start_coverage`]: struct.Lua.html#method.start_coverage
+/// [`Debugger`]: struct.Debugger.html
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    chunks: BTreeMap<StdString, BTreeMap<u32, u64>>,
+}
+
+impl Coverage {
+    /// Returns the lines with code of each chunk, and the number of times each of them ran.
+    ///
+    /// Lines of a chunk which never ran are only included once any function of the chunk ran,
+    /// and only for the functions that chunk defines.
+    pub fn chunks(&self) -> &BTreeMap<StdString, BTreeMap<u32, u64>> {
+        &self.chunks
+    }
+
+    /// Returns the number of times a line ran, or `None` if the line has no code or the chunk did
+    /// not run.
+    pub fn hits(&self, chunk: &str, line: u32) -> Option<u64> {
+        self.chunks.get(chunk)?.get(&line).cloned()
+    }
+
+    /// Exports the coverage in the LCOV tracefile format, with a record for each chunk.
+    pub fn to_lcov(&self) -> StdString {
+        let mut out = StdString::new();
+        for (chunk, lines) in &self.chunks {
+            let _ = writeln!(out, "TN:\nSF:{}", chunk);
+            for (line, hits) in lines {
+                let _ = writeln!(out, "DA:{},{}", line, hits);
+            }
+            let _ = writeln!(out, "LF:{}\nLH:{}\nend_of_record", lines.len(), covered(lines));
+        }
+        out
+    }
+
+    /// Exports the coverage as Cobertura XML, with a class for each chunk.
+    pub fn to_cobertura(&self) -> StdString {
+        let valid = self.chunks.values().map(BTreeMap::len).sum::<usize>();
+        let hit = self.chunks.values().map(covered).sum::<usize>();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+
+        let mut out = StdString::new();
+        out.push_str("<?xml version=\"1.0\" ?>\n");
+        out.push_str(
+            "<!DOCTYPE coverage SYSTEM \
+             \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n",
+        );
+        let _ = writeln!(
+            out,
+            "<coverage line-rate=\"{}\" branch-rate=\"0\" lines-covered=\"{}\" \
+             lines-valid=\"{}\" branches-covered=\"0\" branches-valid=\"0\" complexity=\"0\" \
+             version=\"rlua\" timestamp=\"{}\">",
+            rate(hit, valid),
+            hit,
+            valid,
+            timestamp
+        );
+        out.push_str("  <sources>\n    <source>.</source>\n  </sources>\n  <packages>\n");
+        let _ = writeln!(
+            out,
+            "    <package name=\"lua\" line-rate=\"{}\" branch-rate=\"0\" complexity=\"0\">\n      \
+             <classes>",
+            rate(hit, valid)
+        );
+        for (chunk, lines) in &self.chunks {
+            let _ = writeln!(
+                out,
+                "        <class name=\"{0}\" filename=\"{0}\" line-rate=\"{1}\" branch-rate=\"0\" \
+                 complexity=\"0\">\n          <methods/>\n          <lines>",
+                escape_xml(chunk),
+                rate(covered(lines), lines.len())
+            );
+            for (line, hits) in lines {
+                let _ = writeln!(
+                    out,
+                    "            <line number=\"{}\" hits=\"{}\"/>",
+                    line, hits
+                );
+            }
+            out.push_str("          </lines>\n        </class>\n");
+        }
+        out.push_str("      </classes>\n    </package>\n  </packages>\n</coverage>\n");
+        out
+    }
+}
+
+fn covered(lines: &BTreeMap<u32, u64>) -> usize {
+    lines.values().filter(|&&hits| hits > 0).count()
+}
+
+fn rate(hit: usize, valid: usize) -> f64 {
+    if valid == 0 {
+        1.0
+    } else {
+        hit as f64 / valid as f64
+    }
+}
+
+fn escape_xml(s: &str) -> StdString {
+    let mut escaped = StdString::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Lines recorded since `Lua::start_coverage`, from the line events of `limit_hook`.
+pub(crate) struct CoverageState {
+    chunks: HashMap<StdString, BTreeMap<u32, u64>>,
+    // Functions whose lines have been added to `chunks`.
+    functions: HashSet<*const c_void>,
+}
+
+impl CoverageState {
+    pub(crate) fn new() -> CoverageState {
+        CoverageState {
+            chunks: HashMap::new(),
+            functions: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn to_coverage(&self) -> Coverage {
+        Coverage {
+            chunks: self.chunks
+                .iter()
+                .map(|(chunk, lines)| (chunk.clone(), lines.clone()))
+                .collect(),
+        }
+    }
+
+    pub(crate) unsafe fn track_line(
+        &mut self,
+        state: *mut ffi::lua_State,
+        ar: *mut ffi::lua_Debug,
+    ) {
+        let line = (*ar).currentline;
+        if line <= 0 {
+            return;
+        }
+        ffi::lua_getinfo(state, cstr!("Sf"), ar);
+        let source = CStr::from_ptr((*ar).source).to_string_lossy();
+        let chunk = if source.starts_with('@') || source.starts_with('=') {
+            &source[1..]
+        } else {
+            &source[..]
+        };
+        if !self.chunks.contains_key(chunk) {
+            self.chunks.insert(chunk.to_owned(), BTreeMap::new());
+        }
+        let lines = self.chunks.get_mut(chunk).unwrap();
+
+        // The first time a function runs, the lines of code it and the functions it defines
+        // have are listed from its bytecode, so that lines which never run are reported. A
+        // function created at the address of a collected one is not listed again, which can
+        // only leave out lines which never ran.
+        if self.functions.insert(ffi::lua_topointer(state, -1)) {
+            let mut dump = Vec::new();
+            if ffi::lua_dump(state, write_dump, &mut dump as *mut Vec<u8> as *mut c_void, 0) == 0 {
+                for line in bytecode::lines(&dump).unwrap_or_default() {
+                    lines.entry(line).or_insert(0);
+                }
+            }
+        }
+        ffi::lua_pop(state, 1);
+        *lines.entry(line as u32).or_insert(0) += 1;
+    }
+}
+
+unsafe extern "C" fn write_dump(
+    _: *mut ffi::lua_State,
+    p: *const c_void,
+    sz: usize,
+    ud: *mut c_void,
+) -> c_int {
+    (*(ud as *mut Vec<u8>)).extend_from_slice(slice::from_raw_parts(p as *const u8, sz));
+    0
+}
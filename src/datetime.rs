@@ -0,0 +1,243 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, Datelike, FixedOffset, Local, LocalResult, NaiveDate, NaiveDateTime,
+             NaiveTime, TimeZone, Timelike, Utc};
+
+use error::{Error, Result};
+use lua::{Lua, Value};
+use string::String;
+use table::Table;
+use types::{Integer, Number};
+
+// The time zone argument of the functions of the module.
+enum Zone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    // Parses "UTC", "local" or an offset such as "+02:00", defaulting to UTC.
+    fn parse(zone: Option<String>) -> Result<Zone> {
+        let zone = match zone {
+            Some(ref zone) => zone.to_str()?,
+            None => return Ok(Zone::Utc),
+        };
+        match zone {
+            "UTC" | "utc" | "Z" => Ok(Zone::Utc),
+            "local" => Ok(Zone::Local),
+            offset => parse_offset(offset).map(Zone::Fixed).ok_or_else(|| {
+                Error::RuntimeError(format!("invalid time zone '{}'", offset))
+            }),
+        }
+    }
+
+    // Returns the time in the time zone.
+    fn convert(&self, datetime: &DateTime<Utc>) -> DateTime<FixedOffset> {
+        match *self {
+            Zone::Utc => datetime.fixed_offset(),
+            Zone::Local => datetime.with_timezone(&Local).fixed_offset(),
+            Zone::Fixed(offset) => datetime.with_timezone(&offset),
+        }
+    }
+
+    // Returns the time of a local date and time in the time zone, or the earliest one if the local
+    // time is ambiguous.
+    fn resolve(&self, datetime: &NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+        fn earliest<Tz: TimeZone>(result: LocalResult<DateTime<Tz>>) -> Option<DateTime<Tz>> {
+            result.earliest()
+        }
+
+        match *self {
+            Zone::Utc => earliest(Utc.from_local_datetime(datetime)).map(|d| d.fixed_offset()),
+            Zone::Local => earliest(Local.from_local_datetime(datetime)).map(|d| d.fixed_offset()),
+            Zone::Fixed(offset) => earliest(offset.from_local_datetime(datetime)),
+        }
+    }
+}
+
+// Parses an offset from UTC of the form "+HH:MM", "+HHMM" or "+HH".
+fn parse_offset(offset: &str) -> Option<FixedOffset> {
+    let sign = match offset.as_bytes().first() {
+        Some(&b'+') => 1,
+        Some(&b'-') => -1,
+        _ => return None,
+    };
+    let digits = offset[1..].replace(':', "");
+    if digits.len() != 2 && digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours = digits[..2].parse::<i32>().ok()?;
+    let minutes = digits[2..].parse::<i32>().unwrap_or(0);
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+// Creates the table of functions installed by `Lua::load_datetime_module`.
+pub(crate) fn create_datetime_module(lua: &Lua) -> Result<Table<'_>> {
+    let module = lua.create_table();
+
+    module.raw_set(
+        "now",
+        lua.create_function(|_, ()| Ok(to_timestamp(&Utc::now()))),
+    )?;
+
+    module.raw_set(
+        "format",
+        lua.create_function(|_, (timestamp, format, zone): (Number, String, Option<String>)| {
+            let datetime = Zone::parse(zone)?.convert(&from_timestamp(timestamp)?);
+            let format = format.to_str()?;
+            // Formatting fails instead of panicking if the format string is invalid.
+            let mut formatted = ::std::string::String::new();
+            write!(formatted, "{}", datetime.format(format)).map_err(|_| {
+                Error::RuntimeError(format!("invalid format string '{}'", format))
+            })?;
+            Ok(formatted)
+        }),
+    )?;
+
+    module.raw_set(
+        "parse",
+        lua.create_function(|_, (s, format): (String, Option<String>)| {
+            let s = s.to_str()?;
+            let datetime = match format {
+                None => DateTime::parse_from_rfc3339(s),
+                Some(ref format) => {
+                    let format = format.to_str()?;
+                    DateTime::parse_from_str(s, format).or_else(|err| {
+                        NaiveDateTime::parse_from_str(s, format)
+                            .map(|datetime| datetime.and_utc().fixed_offset())
+                            .map_err(|_| err)
+                    })
+                }
+            };
+            datetime
+                .map(|datetime| to_timestamp(&datetime))
+                .map_err(|err| Error::RuntimeError(format!("cannot parse '{}': {}", s, err)))
+        }),
+    )?;
+
+    module.raw_set(
+        "date",
+        lua.create_function(|lua, (timestamp, zone): (Number, Option<String>)| {
+            let datetime = Zone::parse(zone)?.convert(&from_timestamp(timestamp)?);
+            let table = datetime_table(lua, &datetime.naive_local())?;
+            table.raw_set("offset", datetime.offset().local_minus_utc())?;
+            Ok(table)
+        }),
+    )?;
+
+    module.raw_set(
+        "time",
+        lua.create_function(|_, (table, zone): (Table, Option<String>)| {
+            let datetime = naive_from_table(&table)?;
+            let datetime = Zone::parse(zone)?.resolve(&datetime).ok_or_else(|| {
+                Error::RuntimeError(format!("{} does not exist in the time zone", datetime))
+            })?;
+            Ok(to_timestamp(&datetime))
+        }),
+    )?;
+
+    Ok(module)
+}
+
+// Returns the number of seconds since the Unix epoch, including fractions of a second.
+pub(crate) fn to_timestamp<Tz: TimeZone>(datetime: &DateTime<Tz>) -> Number {
+    datetime.timestamp() as Number + datetime.timestamp_subsec_nanos() as Number / 1e9
+}
+
+pub(crate) fn from_timestamp(timestamp: Number) -> Result<DateTime<Utc>> {
+    let secs = timestamp.floor();
+    let nanos = ((timestamp - secs) * 1e9).round().min(999_999_999.0);
+    if secs.is_finite() && secs.abs() < Integer::MAX as Number {
+        if let Some(datetime) = DateTime::from_timestamp(secs as i64, nanos as u32) {
+            return Ok(datetime);
+        }
+    }
+    Err(Error::FromLuaConversionError {
+        from: "number",
+        to: "DateTime",
+        message: Some("timestamp is out of range".to_owned()),
+    })
+}
+
+// Returns a table with the date fields of the table returned by `os.date("*t")`.
+pub(crate) fn date_table<'lua>(lua: &'lua Lua, date: &NaiveDate) -> Result<Table<'lua>> {
+    let table = lua.create_table_with_capacity(0, 9);
+    table.raw_set("year", date.year())?;
+    table.raw_set("month", date.month())?;
+    table.raw_set("day", date.day())?;
+    table.raw_set("wday", date.weekday().number_from_sunday())?;
+    table.raw_set("yday", date.ordinal())?;
+    Ok(table)
+}
+
+// Returns a table with the fields of the table returned by `os.date("*t")`, except `isdst`.
+pub(crate) fn datetime_table<'lua>(
+    lua: &'lua Lua,
+    datetime: &NaiveDateTime,
+) -> Result<Table<'lua>> {
+    let table = date_table(lua, &datetime.date())?;
+    table.raw_set("hour", datetime.hour())?;
+    table.raw_set("min", datetime.minute())?;
+    if datetime.nanosecond() == 0 {
+        table.raw_set("sec", datetime.second())?;
+    } else {
+        table.raw_set(
+            "sec",
+            datetime.second() as Number + datetime.nanosecond() as Number / 1e9,
+        )?;
+    }
+    Ok(table)
+}
+
+// Reads a date and time from the fields `year`, `month`, `day`, `hour`, `min` and `sec` of a
+// table. The time fields default to 0, and `sec` may have a fractional part.
+pub(crate) fn naive_from_table(table: &Table) -> Result<NaiveDateTime> {
+    let error = |message: &str| Error::FromLuaConversionError {
+        from: "table",
+        to: "NaiveDateTime",
+        message: Some(message.to_owned()),
+    };
+
+    let date = NaiveDate::from_ymd_opt(
+        table.get("year")?,
+        table.get("month")?,
+        table.get("day")?,
+    ).ok_or_else(|| error("invalid date"))?;
+
+    let sec = table.get::<_, Option<Number>>("sec")?.unwrap_or(0.0);
+    if !(0.0..61.0).contains(&sec) {
+        return Err(error("invalid time"));
+    }
+    let time = NaiveTime::from_hms_nano_opt(
+        table.get::<_, Option<u32>>("hour")?.unwrap_or(0),
+        table.get::<_, Option<u32>>("min")?.unwrap_or(0),
+        sec.trunc() as u32,
+        (sec.fract() * 1e9).round().min(999_999_999.0) as u32,
+    ).ok_or_else(|| error("invalid time"))?;
+
+    Ok(date.and_time(time))
+}
+
+// Converts a timestamp, an RFC 3339 string or a table holding a date and time in UTC, for the
+// `FromLua` implementations of `DateTime`.
+pub(crate) fn datetime_from_value(value: Value) -> Result<DateTime<FixedOffset>> {
+    let type_name = value.type_name();
+    match value {
+        Value::Integer(i) => from_timestamp(i as Number).map(|d| d.fixed_offset()),
+        Value::Number(n) => from_timestamp(n).map(|d| d.fixed_offset()),
+        Value::String(s) => DateTime::parse_from_rfc3339(s.to_str()?).map_err(|err| {
+            Error::FromLuaConversionError {
+                from: type_name,
+                to: "DateTime",
+                message: Some(err.to_string()),
+            }
+        }),
+        Value::Table(t) => Ok(naive_from_table(&t)?.and_utc().fixed_offset()),
+        _ => Err(Error::FromLuaConversionError {
+            from: type_name,
+            to: "DateTime",
+            message: Some("expected a timestamp, a string or a table".to_owned()),
+        }),
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::{c_char, c_int};
+use std::string::String as StdString;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use ffi;
+use lua::{stack_frame, Frame, Lua};
+use util::check_stack;
+
+/// A debugger for scripts, controlled from another thread.
+///
+/// Attach a debugger to a state with [`Lua::set_debugger`], and keep a clone of it to control
+/// scripts from another thread. A script stops at a breakpoint, or at its next line once
+/// [`pause`] has been requested, and stays stopped until it is resumed or stepped with the
+/// methods of the debugger. While a script is stopped, [`paused`] describes its call stack and
+/// local variables.
+///
+/// Scripts are stopped by blocking the thread running them, so the debugger must never be
+/// controlled from that thread.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use std::thread;
+/// # use std::time::Duration;
+/// # use rlua::{Debugger, Lua, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let debugger = Debugger::new();
+/// debugger.set_breakpoint("script.lua", 3);
+/// lua.set_debugger(debugger.clone());
+///
+/// let control = thread::spawn(move || {
+///     let pause = debugger.wait_for_pause(Duration::from_secs(10)).unwrap();
+///     let frame = &pause.frames[0];
+///     assert_eq!(frame.frame.line, Some(3));
+///     assert!(frame.locals.contains(&("x".to_owned(), "42".to_owned())));
+///     debugger.resume();
+/// });
+///
+/// lua.load(r#"
+///     local x = 42
+///     print(x)
+/// "#, Some("script.lua"))?.call::<_, ()>(())?;
+/// control.join().unwrap();
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Lua::set_debugger`]: struct.Lua.html#method.set_debugger
+/// [`pause`]: #method.pause
+/// [`paused`]: #method.paused
+#[derive(Clone, Debug, Default)]
+pub struct Debugger(Arc<Shared>);
+
+/// Why a script stopped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PauseReason {
+    /// The script reached a breakpoint.
+    Breakpoint,
+    /// The script finished a step requested with [`Debugger::step_into`], [`Debugger::step_over`]
+    /// or [`Debugger::step_out`].
+    ///
+    /// [`Debugger::step_into`]: struct.Debugger.html#method.step_into
+    /// [`Debugger::step_over`]: struct.Debugger.html#method.step_over
+    /// [`Debugger::step_out`]: struct.Debugger.html#method.step_out
+    Step,
+    /// The script was paused with [`Debugger::pause`].
+    ///
+    /// [`Debugger::pause`]: struct.Debugger.html#method.pause
+    Pause,
+}
+
+/// The state of a script stopped by a [`Debugger`].
+///
+/// [`Debugger`]: struct.Debugger.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugPause {
+    /// Why the script stopped.
+    pub reason: PauseReason,
+    /// The call stack of the script, innermost frame first.
+    pub frames: Vec<DebugFrame>,
+}
+
+/// A frame of the call stack of a script stopped by a [`Debugger`].
+///
+/// [`Debugger`]: struct.Debugger.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugFrame {
+    /// The function and line of the frame.
+    pub frame: Frame,
+    /// The names of the local variables of the frame which are in scope, and their values
+    /// rendered by [`Value::to_debug_string`].
+    ///
+    /// [`Value::to_debug_string`]: enum.Value.html#method.to_debug_string
+    pub locals: Vec<(StdString, StdString)>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Chunk names of the breakpoints, keyed by their line.
+    breakpoints: HashMap<u32, HashSet<StdString>>,
+    command: Command,
+    paused: Option<DebugPause>,
+    // Number of frames on the stack of the paused thread.
+    paused_depth: usize,
+}
+
+// What the debugger does on the next line of a script.
+#[derive(Copy, Clone, Debug, Default)]
+enum Command {
+    #[default]
+    Run,
+    Pause,
+    StepInto,
+    // Stop on a line with at most, or fewer than, the given number of frames on the stack.
+    StepOver(usize),
+    StepOut(usize),
+}
+
+// Levels of locals which are rendered while paused.
+const LOCAL_DEPTH: usize = 2;
+
+impl Debugger {
+    /// Creates a debugger without breakpoints.
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Sets a breakpoint on a line of a chunk.
+    ///
+    /// `chunk` is the name the chunk was loaded with, such as `script.lua` for
+    /// `lua.load(source, Some("script.lua"))`, or the path of a file loaded by `loadfile` or
+    /// `require`, without the `@` prefix Lua adds to it.
+    pub fn set_breakpoint(&self, chunk: &str, line: u32) {
+        self.lock()
+            .breakpoints
+            .entry(line)
+            .or_default()
+            .insert(chunk.to_owned());
+    }
+
+    /// Removes a breakpoint set with [`set_breakpoint`], returning whether it was set.
+    ///
+    /// [`set_breakpoint`]: #method.set_breakpoint
+    pub fn remove_breakpoint(&self, chunk: &str, line: u32) -> bool {
+        let mut state = self.lock();
+        let removed = match state.breakpoints.get_mut(&line) {
+            Some(chunks) => chunks.remove(chunk),
+            None => false,
+        };
+        if state.breakpoints.get(&line).is_some_and(HashSet::is_empty) {
+            state.breakpoints.remove(&line);
+        }
+        removed
+    }
+
+    /// Stops the running script at its next line.
+    pub fn pause(&self) {
+        let mut state = self.lock();
+        if state.paused.is_none() {
+            state.command = Command::Pause;
+        }
+    }
+
+    /// Resumes the stopped script, which runs until the next breakpoint.
+    pub fn resume(&self) {
+        let mut state = self.lock();
+        state.command = Command::Run;
+        state.paused = None;
+        self.0.changed.notify_all();
+    }
+
+    /// Resumes the stopped script until the next line it runs, also in called functions.
+    ///
+    /// Does nothing unless the script is stopped.
+    pub fn step_into(&self) {
+        self.step(|_| Command::StepInto);
+    }
+
+    /// Resumes the stopped script until the next line of the current function, or of the
+    /// function it returns to.
+    ///
+    /// Does nothing unless the script is stopped.
+    pub fn step_over(&self) {
+        self.step(Command::StepOver);
+    }
+
+    /// Resumes the stopped script until the current function returns.
+    ///
+    /// Does nothing unless the script is stopped.
+    pub fn step_out(&self) {
+        self.step(Command::StepOut);
+    }
+
+    /// Returns the state of the stopped script, or `None` if no script is stopped.
+    pub fn paused(&self) -> Option<DebugPause> {
+        self.lock().paused.clone()
+    }
+
+    /// Waits until a script stops, for at most `timeout`.
+    ///
+    /// Returns the state of the stopped script, or `None` if no script stopped in time.
+    pub fn wait_for_pause(&self, timeout: Duration) -> Option<DebugPause> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        while state.paused.is_none() {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self.0
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        state.paused.clone()
+    }
+
+    // Resumes the stopped script with the command built from the depth of its stack.
+    fn step<F: FnOnce(usize) -> Command>(&self, command: F) {
+        let mut state = self.lock();
+        if state.paused.is_some() {
+            state.command = command(state.paused_depth);
+            state.paused = None;
+            self.0.changed.notify_all();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Called from the line hook of a state the debugger is attached to. Stops the thread if it
+    // reached a breakpoint or finished a step, until the debugger resumes it.
+    pub(crate) unsafe fn line_hook(
+        &self,
+        lua: &Lua,
+        state: *mut ffi::lua_State,
+        ar: *mut ffi::lua_Debug,
+    ) {
+        let reason = {
+            let debugger = self.lock();
+            let reason = match debugger.command {
+                Command::Run => None,
+                Command::Pause => Some(PauseReason::Pause),
+                Command::StepInto => Some(PauseReason::Step),
+                Command::StepOver(depth) if stack_depth(state) <= depth => Some(PauseReason::Step),
+                Command::StepOut(depth) if stack_depth(state) < depth => Some(PauseReason::Step),
+                Command::StepOver(_) | Command::StepOut(_) => None,
+            };
+            match reason {
+                Some(reason) => reason,
+                None => match debugger.breakpoints.get(&((*ar).currentline as u32)) {
+                    Some(chunks) => {
+                        ffi::lua_getinfo(state, cstr!("S"), ar);
+                        let source = CStr::from_ptr((*ar).source).to_string_lossy();
+                        let chunk = if source.starts_with('@') || source.starts_with('=') {
+                            &source[1..]
+                        } else {
+                            &source[..]
+                        };
+                        if chunks.contains(chunk) {
+                            PauseReason::Breakpoint
+                        } else {
+                            return;
+                        }
+                    }
+                    None => return,
+                },
+            }
+        };
+
+        let frames = capture_frames(lua, state);
+        let mut debugger = self.lock();
+        debugger.paused_depth = frames.len();
+        debugger.paused = Some(DebugPause {
+            reason,
+            frames,
+        });
+        self.0.changed.notify_all();
+        while debugger.paused.is_some() {
+            debugger = self.0
+                .changed
+                .wait(debugger)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+// Returns the number of frames on the stack of a thread.
+unsafe fn stack_depth(state: *mut ffi::lua_State) -> usize {
+    let mut ar: ffi::lua_Debug = mem::zeroed();
+    let mut depth = 0;
+    while ffi::lua_getstack(state, depth as c_int, &mut ar) != 0 {
+        depth += 1;
+    }
+    depth
+}
+
+// Describes the frames of a thread and their local variables.
+unsafe fn capture_frames(lua: &Lua, state: *mut ffi::lua_State) -> Vec<DebugFrame> {
+    check_stack(state, 1);
+    let mut frames = Vec::new();
+    let mut ar: ffi::lua_Debug = mem::zeroed();
+    let mut level = 0;
+    while ffi::lua_getstack(state, level, &mut ar) != 0 {
+        let frame = stack_frame(state, &mut ar);
+        let mut locals = Vec::new();
+        let mut n = 1;
+        loop {
+            let name = ffi::lua_getlocal(state, &ar, n);
+            if name.is_null() {
+                break;
+            }
+            let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+            let value = lua.pop_value(state);
+            // Names starting with a parenthesis are internal to Lua, such as `(for index)`.
+            if !name.starts_with('(') {
+                locals.push((name, value.to_debug_string(LOCAL_DEPTH)));
+            }
+            n += 1;
+        }
+        frames.push(DebugFrame {
+            frame,
+            locals,
+        });
+        level += 1;
+    }
+    frames
+}
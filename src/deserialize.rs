@@ -0,0 +1,358 @@
+use std::fmt::Display;
+use std::vec;
+
+use serde::de::{self, DeserializeSeed, Visitor};
+
+use error::{Error, Result};
+use types::Integer;
+use table::Table;
+use lua::Value;
+use serialize::VisitGuard;
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::DeserializeError(msg.to_string())
+    }
+}
+
+// Deserializes Rust values from Lua values.
+pub(crate) struct Deserializer<'lua> {
+    pub(crate) value: Value<'lua>,
+}
+
+impl<'lua> Deserializer<'lua> {
+    // Lua code freely produces floats such as `10 / 2`, so floats without a fractional part are
+    // accepted wherever an integer is expected.
+    fn deserialize_integer<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Number(n) if n.fract() == 0.0 && n >= Integer::MIN as f64
+                && n < Integer::MAX as f64 =>
+            {
+                visitor.visit_i64(n as Integer)
+            }
+            _ => de::Deserializer::deserialize_any(self, visitor),
+        }
+    }
+}
+
+// Marks a table as being deserialized until the guard is dropped, failing for recursive tables
+// and tables nested too deeply.
+fn enter_table(table: &Table) -> Result<VisitGuard> {
+    VisitGuard::enter(table.to_pointer())
+        .map_err(|reason| de::Error::custom(format!("cannot deserialize {}", reason)))
+}
+
+impl<'de, 'lua> de::Deserializer<'de> for Deserializer<'lua> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Nil => visitor.visit_unit(),
+            Value::LightUserData(ud) if ud.0.is_null() => visitor.visit_unit(),
+            Value::Boolean(b) => visitor.visit_bool(b),
+            Value::Integer(i) => visitor.visit_i64(i),
+            Value::Number(n) => visitor.visit_f64(n),
+            Value::String(s) => match s.to_str() {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_bytes(s.as_bytes()),
+            },
+            Value::Table(t) => {
+                let _guard = enter_table(&t)?;
+                let len = t.raw_len();
+                let pairs = t.clone()
+                    .pairs::<Value, Value>()
+                    .collect::<Result<Vec<_>>>()?;
+
+                // Tables with only the keys `1..n` or the array metatable are sequences, any other
+                // table is a map.
+                if (len > 0 && pairs.len() as Integer == len) || t.0.lua.is_array_table(&t) {
+                    let values = t.sequence_values::<Value>().collect::<Result<Vec<_>>>()?;
+                    visit_seq(values, visitor)
+                } else {
+                    visit_map(pairs, visitor)
+                }
+            }
+            value => Err(de::Error::custom(format!(
+                "cannot deserialize {}",
+                value.type_name()
+            ))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Nil => visitor.visit_none(),
+            Value::LightUserData(ud) if ud.0.is_null() => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.value {
+            Value::String(_) => visitor.visit_enum(EnumDeserializer {
+                variant: self.value,
+                value: None,
+            }),
+            Value::Table(t) => {
+                let _guard = enter_table(&t)?;
+                let mut pairs = t.pairs::<Value, Value>();
+                match (pairs.next(), pairs.next()) {
+                    (Some(pair), None) => {
+                        let (variant, value) = pair?;
+                        visitor.visit_enum(EnumDeserializer {
+                            variant,
+                            value: Some(value),
+                        })
+                    }
+                    _ => Err(de::Error::custom(
+                        "expected a table with a single key for an enum variant",
+                    )),
+                }
+            }
+            value => Err(de::Error::invalid_type(
+                de::Unexpected::Other(value.type_name()),
+                &"string or table",
+            )),
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Table(t) => {
+                let _guard = enter_table(&t)?;
+                let values = t.sequence_values::<Value>().collect::<Result<Vec<_>>>()?;
+                visit_seq(values, visitor)
+            }
+            value => Err(de::Error::invalid_type(
+                de::Unexpected::Other(value.type_name()),
+                &"table",
+            )),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Table(t) => {
+                let _guard = enter_table(&t)?;
+                let pairs = t.pairs::<Value, Value>().collect::<Result<Vec<_>>>()?;
+                visit_map(pairs, visitor)
+            }
+            value => Err(de::Error::invalid_type(
+                de::Unexpected::Other(value.type_name()),
+                &"table",
+            )),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf unit unit_struct identifier ignored_any
+    }
+}
+
+fn visit_seq<'de, 'lua, V: Visitor<'de>>(values: Vec<Value<'lua>>, visitor: V) -> Result<V::Value> {
+    let len = values.len();
+    let mut seq = SeqDeserializer(values.into_iter());
+    let value = visitor.visit_seq(&mut seq)?;
+    if seq.0.len() == 0 {
+        Ok(value)
+    } else {
+        Err(de::Error::invalid_length(len, &"fewer elements in table"))
+    }
+}
+
+fn visit_map<'de, 'lua, V: Visitor<'de>>(
+    pairs: Vec<(Value<'lua>, Value<'lua>)>,
+    visitor: V,
+) -> Result<V::Value> {
+    let mut map = MapDeserializer {
+        pairs: pairs.into_iter(),
+        value: None,
+    };
+    visitor.visit_map(&mut map)
+}
+
+struct SeqDeserializer<'lua>(vec::IntoIter<Value<'lua>>);
+
+impl<'de, 'lua> de::SeqAccess<'de> for SeqDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        match self.0.next() {
+            Some(value) => seed.deserialize(Deserializer { value }).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct MapDeserializer<'lua> {
+    pairs: vec::IntoIter<(Value<'lua>, Value<'lua>)>,
+    value: Option<Value<'lua>>,
+}
+
+impl<'de, 'lua> de::MapAccess<'de> for MapDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.pairs.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(Deserializer { value: key }).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(Deserializer { value }),
+            None => Err(de::Error::custom("map value requested before its key")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.pairs.len())
+    }
+}
+
+struct EnumDeserializer<'lua> {
+    variant: Value<'lua>,
+    value: Option<Value<'lua>>,
+}
+
+impl<'de, 'lua> de::EnumAccess<'de> for EnumDeserializer<'lua> {
+    type Error = Error;
+    type Variant = VariantDeserializer<'lua>;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<(T::Value, VariantDeserializer<'lua>)> {
+        let variant = seed.deserialize(Deserializer {
+            value: self.variant,
+        })?;
+        Ok((variant, VariantDeserializer(self.value)))
+    }
+}
+
+struct VariantDeserializer<'lua>(Option<Value<'lua>>);
+
+impl<'de, 'lua> de::VariantAccess<'de> for VariantDeserializer<'lua> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.0 {
+            None | Some(Value::Nil) => Ok(()),
+            Some(Value::LightUserData(ref ud)) if ud.0.is_null() => Ok(()),
+            Some(value) => de::Deserialize::deserialize(Deserializer { value }),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        match self.0 {
+            Some(value) => seed.deserialize(Deserializer { value }),
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"newtype variant",
+            )),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            Some(value) => {
+                de::Deserializer::deserialize_seq(Deserializer { value }, visitor)
+            }
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"tuple variant",
+            )),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.0 {
+            Some(value) => {
+                de::Deserializer::deserialize_map(Deserializer { value }, visitor)
+            }
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"struct variant",
+            )),
+        }
+    }
+}
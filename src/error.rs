@@ -3,7 +3,44 @@ use std::sync::Arc;
 use std::error::Error as StdError;
 use std::result::Result as StdResult;
 
+use lua::Frame;
+use types::{Integer, Number};
+
 /// Error type returned by rlua methods.
+///
+/// When a Rust callback returns an error, it is raised in Lua as a userdata value. Besides
+/// converting it to a string with `tostring`, scripts can read the following fields of it:
+///
+/// * `message`: the error message, as displayed by Rust.
+/// * `kind`: the kind of error, one of `"syntax"`, `"runtime"`, `"to_lua_conversion"`,
+///   `"from_lua_conversion"`, `"serialize"`, `"deserialize"`, `"coroutine_inactive"`,
+///   `"userdata_type_mismatch"`, `"userdata_borrow"`, `"userdata_borrow_mut"`,
+///   `"mismatched_registry_key"`, `"callback"`, `"callback_panic"`, `"memory"`, `"external"`,
+///   `"bad_argument"` or `"context"`.
+/// * `incomplete_input` for syntax errors, `value` for runtime errors raised with a value that is
+///   not a string, `from` and `to` for conversion errors, `traceback` for callback errors,
+///   `function_name` and `arg_index` for bad arguments, `context` for errors with context, and
+///   `cause` for the last three.
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+///
+/// let parse = lua.create_function(|_, n: u32| Ok(n));
+/// lua.globals().set("parse", parse)?;
+/// let kind = lua.eval::<String>(r#"
+///     local ok, err = pcall(parse, "abc")
+///     return err.kind .. " #" .. err.arg_index .. ": " .. err.cause.from .. " to " .. err.cause.to
+/// "#, None)?;
+/// assert_eq!(kind, "bad_argument #1: string to integer");
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
 #[derive(Debug, Clone)]
 pub enum Error {
     /// Syntax error while parsing Lua source code.
@@ -22,6 +59,18 @@ pub enum Error {
     /// Among other things, this includes invoking operators on wrong types (such as calling or
     /// indexing a `nil` value).
     RuntimeError(String),
+    /// Lua runtime error whose error object is not a UTF-8 string.
+    ///
+    /// Lua code may raise any value as an error, such as a table holding details about the error
+    /// or a string containing binary data. Such errors keep a copy of the raised value instead of
+    /// being converted to a `RuntimeError` with a lossy message.
+    RuntimeErrorValue {
+        /// A description of the error value followed by a traceback, as it would be the message
+        /// of a `RuntimeError`.
+        message: String,
+        /// A copy of the raised value.
+        value: ErrorValue,
+    },
     /// A Rust value could not be converted to a Lua value.
     ToLuaConversionError {
         /// Name of the Rust type that could not be converted.
@@ -40,6 +89,20 @@ pub enum Error {
         /// A string containing more detailed error information.
         message: Option<String>,
     },
+    /// A Rust value could not be serialized into a Lua value.
+    ///
+    /// Returned by the `serde` integration, see [`Lua::to_value`], and by [`Lua::persist`].
+    ///
+    /// [`Lua::to_value`]: struct.Lua.html#method.to_value
+    /// [`Lua::persist`]: struct.Lua.html#method.persist
+    SerializeError(String),
+    /// A Lua value could not be deserialized into a Rust value.
+    ///
+    /// Returned by the `serde` integration, see [`Lua::from_value`], and by [`Lua::unpersist`].
+    ///
+    /// [`Lua::from_value`]: struct.Lua.html#method.from_value
+    /// [`Lua::unpersist`]: struct.Lua.html#method.unpersist
+    DeserializeError(String),
     /// [`Thread::resume`] was called on an inactive coroutine.
     ///
     /// A coroutine is inactive if its main function has returned or if an error has occured inside
@@ -78,6 +141,10 @@ pub enum Error {
     /// [`AnyUserData`]: struct.AnyUserData.html
     /// [`UserData`]: trait.UserData.html
     UserDataBorrowMutError,
+    /// A [`RegistryKey`] was used with a different Lua state than the one which created it.
+    ///
+    /// [`RegistryKey`]: struct.RegistryKey.html
+    MismatchedRegistryKey,
     /// A Rust callback returned `Err`, raising the contained `Error` as a Lua error.
     CallbackError {
         /// Lua call stack backtrace.
@@ -85,6 +152,19 @@ pub enum Error {
         /// Original error returned by the Rust code.
         cause: Arc<Error>,
     },
+    /// A Rust callback panicked while panics were caught, see [`PanicMode`].
+    ///
+    /// Holds the panic message, if the panic was raised with one.
+    ///
+    /// [`PanicMode`]: enum.PanicMode.html
+    CallbackPanic(String),
+    /// Lua memory error, aka `LUA_ERRMEM`.
+    ///
+    /// Raised when Lua code exceeds the memory limit set with [`Lua::set_resource_limits`]. Other
+    /// allocation failures abort the process.
+    ///
+    /// [`Lua::set_resource_limits`]: struct.Lua.html#method.set_resource_limits
+    MemoryError(String),
     /// A custom error.
     ///
     /// This can be used for returning user-defined errors from callbacks.
@@ -92,7 +172,83 @@ pub enum Error {
     /// Returning `Err(ExternalError(...))` from a Rust callback will raise the error as a Lua
     /// error. The Rust code that originally invoked the Lua code then receives a `CallbackError`,
     /// from which the original error (and a stack traceback) can be recovered.
-    ExternalError(Arc<StdError + Send + Sync>),
+    ExternalError(Arc<dyn StdError + Send + Sync>),
+    /// An argument of a Rust callback could not be converted to the expected type.
+    ///
+    /// This is displayed like the errors of Lua's own functions, such as
+    /// `bad argument #2 to 'spawn' (error converting Lua string to integer)`.
+    BadArgument {
+        /// The name of the function as seen by its caller, if a name could be found.
+        function_name: Option<String>,
+        /// The position of the argument, counting from 1.
+        arg_index: usize,
+        /// The error which occurred while converting the argument.
+        cause: Arc<Error>,
+    },
+    /// An error annotated with additional context, created by [`Error::context`].
+    ///
+    /// [`Error::context`]: #method.context
+    WithContext {
+        /// A description of what was being done when the error occurred.
+        context: String,
+        /// The original error.
+        cause: Arc<Error>,
+    },
+}
+
+/// An owned copy of a value raised as an error by Lua code.
+///
+/// Tables are copied along with their contents, but without their metatables. Values which
+/// cannot be copied are represented by `Other`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorValue {
+    Nil,
+    Boolean(bool),
+    Integer(Integer),
+    Number(Number),
+    /// The bytes of a Lua string, which do not have to be valid UTF-8.
+    String(Vec<u8>),
+    /// The key-value pairs of a table, in no particular order.
+    Table(Vec<(ErrorValue, ErrorValue)>),
+    /// A function, thread, userdata or light userdata, or a table which contains itself or is
+    /// nested too deeply. Holds the name of the Lua type of the value.
+    Other(&'static str),
+}
+
+impl ErrorValue {
+    /// Returns the value of the field `key` if this is a table, or `None` otherwise.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Error, ErrorValue, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// match lua.exec::<()>("error({ code = 404 })", None) {
+    ///     Err(Error::RuntimeErrorValue { value, .. }) => {
+    ///         assert_eq!(value.get("code"), Some(&ErrorValue::Integer(404)));
+    ///     }
+    ///     _ => unreachable!(),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn get(&self, key: &str) -> Option<&ErrorValue> {
+        match *self {
+            ErrorValue::Table(ref entries) => entries
+                .iter()
+                .find(|entry| {
+                    matches!(entry.0, ErrorValue::String(ref k) if k[..] == *key.as_bytes())
+                })
+                .map(|entry| &entry.1),
+            _ => None,
+        }
+    }
 }
 
 /// A specialized `Result` type used by rlua's API.
@@ -102,7 +258,9 @@ impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::SyntaxError { ref message, .. } => write!(fmt, "syntax error: {}", message),
-            Error::RuntimeError(ref msg) => write!(fmt, "runtime error: {}", msg),
+            Error::RuntimeError(ref msg) | Error::RuntimeErrorValue { message: ref msg, .. } => {
+                write!(fmt, "runtime error: {}", msg)
+            }
             Error::ToLuaConversionError {
                 from,
                 to,
@@ -125,14 +283,36 @@ impl fmt::Display for Error {
                     Some(ref message) => write!(fmt, " ({})", message),
                 }
             }
+            Error::SerializeError(ref msg) => write!(fmt, "serialize error: {}", msg),
+            Error::DeserializeError(ref msg) => write!(fmt, "deserialize error: {}", msg),
             Error::CoroutineInactive => write!(fmt, "cannot resume inactive coroutine"),
             Error::UserDataTypeMismatch => write!(fmt, "userdata is not expected type"),
             Error::UserDataBorrowError => write!(fmt, "userdata already mutably borrowed"),
             Error::UserDataBorrowMutError => write!(fmt, "userdata already borrowed"),
+            Error::MismatchedRegistryKey => {
+                write!(fmt, "RegistryKey used with a different Lua state")
+            }
             Error::CallbackError { ref traceback, .. } => {
                 write!(fmt, "callback error: {}", traceback)
             }
+            Error::CallbackPanic(ref msg) => write!(fmt, "callback panicked: {}", msg),
+            Error::MemoryError(ref msg) => write!(fmt, "memory error: {}", msg),
             Error::ExternalError(ref err) => err.fmt(fmt),
+            Error::BadArgument {
+                ref function_name,
+                arg_index,
+                ref cause,
+            } => {
+                write!(fmt, "bad argument #{}", arg_index)?;
+                if let Some(ref name) = *function_name {
+                    write!(fmt, " to '{}'", name)?;
+                }
+                write!(fmt, " ({})", cause)
+            }
+            Error::WithContext {
+                ref context,
+                ref cause,
+            } => write!(fmt, "{}: {}", context, cause),
         }
     }
 }
@@ -141,22 +321,37 @@ impl StdError for Error {
     fn description(&self) -> &str {
         match *self {
             Error::SyntaxError { .. } => "syntax error",
-            Error::RuntimeError(_) => "runtime error",
+            Error::RuntimeError(_) | Error::RuntimeErrorValue { .. } => "runtime error",
             Error::ToLuaConversionError { .. } => "conversion error to lua",
             Error::FromLuaConversionError { .. } => "conversion error from lua",
+            Error::SerializeError(_) => "serialize error",
+            Error::DeserializeError(_) => "deserialize error",
             Error::CoroutineInactive => "attempt to resume inactive coroutine",
             Error::UserDataTypeMismatch => "userdata type mismatch",
             Error::UserDataBorrowError => "userdata already mutably borrowed",
             Error::UserDataBorrowMutError => "userdata already borrowed",
+            Error::MismatchedRegistryKey => "registry key used with a different Lua state",
             Error::CallbackError { .. } => "callback error",
+            Error::CallbackPanic(_) => "callback panicked",
+            Error::MemoryError(_) => "memory error",
+            // `description` is deprecated, but is still what this method has to forward to.
+            #[allow(deprecated)]
             Error::ExternalError(ref err) => err.description(),
+            Error::BadArgument { .. } => "bad argument",
+            #[allow(deprecated)]
+            Error::WithContext { ref cause, .. } => cause.description(),
         }
     }
 
-    fn cause(&self) -> Option<&StdError> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match *self {
             Error::CallbackError { ref cause, .. } => Some(cause.as_ref()),
-            Error::ExternalError(ref err) => err.cause(),
+            // The external error is displayed as itself, so its own source comes next.
+            Error::ExternalError(ref err) => err.source(),
+            // The cause is displayed as part of these errors, so its source comes next.
+            Error::BadArgument { ref cause, .. } | Error::WithContext { ref cause, .. } => {
+                cause.source()
+            }
             _ => None,
         }
     }
@@ -166,6 +361,245 @@ impl Error {
     pub fn external<T: 'static + StdError + Send + Sync>(err: T) -> Error {
         Error::ExternalError(Arc::new(err))
     }
+
+    /// Annotates the error with a description of what was being done when it occurred.
+    ///
+    /// The resulting error is displayed as `"{context}: {error}"`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{ErrorContext, Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let config = lua.eval::<Table>(r#"{ timeout = "soon" }"#, None)?;
+    /// let err = config
+    ///     .get::<_, u32>("timeout")
+    ///     .context("while reading field 'timeout' of config table")
+    ///     .unwrap_err();
+    /// assert!(err
+    ///     .to_string()
+    ///     .starts_with("while reading field 'timeout' of config table: error converting"));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn context<C: fmt::Display>(self, context: C) -> Error {
+        Error::WithContext {
+            context: context.to_string(),
+            cause: Arc::new(self),
+        }
+    }
+
+    /// Like [`context`], but only evaluates the context when it is needed.
+    ///
+    /// [`context`]: #method.context
+    pub fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Error {
+        self.context(f())
+    }
+
+    /// Returns a reference to the original error of type `T`, if this error was created from one.
+    ///
+    /// This looks through `CallbackError`s to the error returned by the Rust callback, and into
+    /// errors created with [`Error::external`], [`ExternalError::to_lua_err`] or, with the
+    /// `anyhow` and `eyre` features, converted from `anyhow::Error` and `eyre::Report`.
+    ///
+    /// [`Error::external`]: #method.external
+    /// [`ExternalError::to_lua_err`]: trait.ExternalError.html#tymethod.to_lua_err
+    pub fn downcast_ref<T: 'static + StdError + Send + Sync>(&self) -> Option<&T> {
+        match *self {
+            Error::CallbackError { ref cause, .. }
+            | Error::BadArgument { ref cause, .. }
+            | Error::WithContext { ref cause, .. } => cause.downcast_ref(),
+            Error::ExternalError(ref err) => {
+                if let Some(err) = err.downcast_ref::<T>() {
+                    return Some(err);
+                }
+                if let Some(err) = err.downcast_ref::<WrapError>() {
+                    return err.0.downcast_ref();
+                }
+                #[cfg(feature = "anyhow")]
+                {
+                    if let Some(err) = err.downcast_ref::<AnyhowError>() {
+                        return err.0.downcast_ref();
+                    }
+                }
+                #[cfg(feature = "eyre")]
+                {
+                    if let Some(err) = err.downcast_ref::<EyreError>() {
+                        return err.0.downcast_ref();
+                    }
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if this error was created from an error of type `T`.
+    ///
+    /// See [`downcast_ref`] for the errors which are looked through.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::fmt;
+    /// # use rlua::{Error, Function, Lua, Result};
+    /// #[derive(Debug)]
+    /// struct DbError;
+    ///
+    /// impl fmt::Display for DbError {
+    ///     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    ///         write!(f, "database error")
+    ///     }
+    /// }
+    ///
+    /// impl std::error::Error for DbError {}
+    ///
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let query = lua.create_function(|_, ()| Err::<(), _>(Error::external(DbError)));
+    /// lua.globals().set("query", query)?;
+    ///
+    /// // The error is caught and raised again by the script.
+    /// let script = lua.eval::<Function>(r#"
+    ///     function()
+    ///         local ok, err = pcall(query)
+    ///         error(err)
+    ///     end
+    /// "#, None)?;
+    /// let err = script.call::<_, ()>(()).unwrap_err();
+    /// assert!(err.is::<DbError>());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`downcast_ref`]: #method.downcast_ref
+    pub fn is<T: 'static + StdError + Send + Sync>(&self) -> bool {
+        self.downcast_ref::<T>().is_some()
+    }
+
+    /// Returns the Lua call stack at the point where the error was raised, innermost frame first.
+    ///
+    /// Errors raised while running Lua code, such as those returned by [`Function::call`], carry a
+    /// traceback in their message (for a `RuntimeError`) or in the `traceback` field (for a
+    /// `CallbackError`). This returns that traceback as structured data, so it can be displayed
+    /// differently or mapped back to the original source files. Returns `None` if the error has no
+    /// traceback.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let f = lua.load(r#"
+    ///     local function fail()
+    ///         error("oops")
+    ///     end
+    ///     fail()
+    /// "#, Some("script.lua"))?;
+    /// let frames = f.call::<_, ()>(()).unwrap_err().frames().unwrap();
+    ///
+    /// let fail = frames.iter().find(|f| f.name == Some("fail".to_owned())).unwrap();
+    /// assert_eq!(fail.source, r#"[string "script.lua"]"#);
+    /// assert_eq!(fail.line, Some(3));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Function::call`]: struct.Function.html#method.call
+    pub fn frames(&self) -> Option<Vec<Frame>> {
+        let traceback = match *self {
+            Error::RuntimeError(ref message) | Error::RuntimeErrorValue { ref message, .. } => {
+                message
+            }
+            Error::CallbackError { ref traceback, .. } => traceback,
+            Error::WithContext { ref cause, .. } => return cause.frames(),
+            _ => return None,
+        };
+
+        let start = traceback.find("stack traceback:")?;
+        Some(
+            traceback[start..]
+                .lines()
+                .skip(1)
+                .take_while(|line| line.starts_with('\t'))
+                .filter_map(|line| parse_frame(&line[1..]))
+                .collect(),
+        )
+    }
+}
+
+// Parses a line of a traceback produced by `luaL_traceback`, such as
+// `[string "chunk"]:3: in function 'f'`. Returns `None` for lines which don't describe a frame,
+// such as the marker for skipped levels.
+fn parse_frame(line: &str) -> Option<Frame> {
+    // Chunk names are quoted and may contain anything, so the location is searched for after them.
+    let search_start = if line.starts_with("[string \"") {
+        line.find("\"]").map(|i| i + 2).unwrap_or(0)
+    } else {
+        0
+    };
+    let split = search_start + line[search_start..].find(": in ")?;
+    let (location, function) = (&line[..split], &line[split + 5..]);
+
+    let (source, line) = match location.rfind(':') {
+        Some(i) if i >= search_start => match location[i + 1..].parse() {
+            Ok(line) => (&location[..i], Some(line)),
+            Err(_) => (location, None),
+        },
+        _ => (location, None),
+    };
+
+    // Named functions are described as `function 'name'`, `local 'name'`, `method 'name'` and so
+    // on, while others are described as `main chunk`, `function <source:line>` or `?`.
+    let name = match (function.find('\''), function.rfind('\'')) {
+        (Some(start), Some(end)) if start < end => Some(function[start + 1..end].to_owned()),
+        _ => None,
+    };
+
+    Some(Frame {
+        source: source.to_owned(),
+        line,
+        name,
+    })
+}
+
+/// Adds [`Error::context`] and [`Error::with_context`] to `Result`s.
+///
+/// [`Error::context`]: enum.Error.html#method.context
+/// [`Error::with_context`]: enum.Error.html#method.with_context
+pub trait ErrorContext<T> {
+    /// Annotates the error, if any, with a description of what was being done.
+    fn context<C: fmt::Display>(self, context: C) -> Result<T>;
+
+    /// Annotates the error, if any, with a lazily evaluated description of what was being done.
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T> ErrorContext<T> for Result<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.map_err(|err| err.context(context))
+    }
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|err| err.with_context(f))
+    }
 }
 
 pub trait ExternalError {
@@ -174,34 +608,108 @@ pub trait ExternalError {
 
 impl<E> ExternalError for E
 where
-    E: Into<Box<StdError + Send + Sync>>,
+    E: Into<Box<dyn StdError + Send + Sync>>,
 {
     fn to_lua_err(self) -> Error {
-        struct WrapError(Box<StdError + Send + Sync>);
+        Error::external(WrapError(self.into()))
+    }
+}
 
-        impl fmt::Debug for WrapError {
-            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                fmt::Debug::fmt(&self.0, f)
-            }
-        }
+// Wraps a boxed error, which does not implement `Error` itself.
+struct WrapError(Box<dyn StdError + Send + Sync>);
 
-        impl fmt::Display for WrapError {
-            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                fmt::Display::fmt(&self.0, f)
-            }
+impl fmt::Debug for WrapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for WrapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl StdError for WrapError {
+    fn description(&self) -> &str {
+        #[allow(deprecated)]
+        self.0.description()
+    }
+
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Converts an `anyhow::Error` into an `ExternalError`, unless it wraps an `rlua::Error`, in which
+/// case that error is returned unchanged.
+#[cfg(feature = "anyhow")]
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Error {
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => Error::external(AnyhowError(err)),
         }
+    }
+}
 
-        impl StdError for WrapError {
-            fn description(&self) -> &str {
-                self.0.description()
-            }
+#[cfg(feature = "anyhow")]
+struct AnyhowError(anyhow::Error);
 
-            fn cause(&self) -> Option<&StdError> {
-                self.0.cause()
-            }
+#[cfg(feature = "anyhow")]
+impl fmt::Debug for AnyhowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+#[cfg(feature = "anyhow")]
+impl fmt::Display for AnyhowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[cfg(feature = "anyhow")]
+impl StdError for AnyhowError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Converts an `eyre::Report` into an `ExternalError`, unless it wraps an `rlua::Error`, in which
+/// case that error is returned unchanged.
+#[cfg(feature = "eyre")]
+impl From<eyre::Report> for Error {
+    fn from(err: eyre::Report) -> Error {
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => Error::external(EyreError(err)),
         }
+    }
+}
 
-        Error::external(WrapError(self.into()))
+#[cfg(feature = "eyre")]
+struct EyreError(eyre::Report);
+
+#[cfg(feature = "eyre")]
+impl fmt::Debug for EyreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+#[cfg(feature = "eyre")]
+impl fmt::Display for EyreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[cfg(feature = "eyre")]
+impl StdError for EyreError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.0.source()
     }
 }
 
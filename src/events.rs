@@ -0,0 +1,193 @@
+use std::any::{type_name, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use error::{Error, Result};
+use lua::{Function, Lua, ToLuaMulti};
+use table::Table;
+use userdata::{UserData, UserDataMethods};
+
+static NEXT_EVENTS_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A registry of named events, which scripts listen to and Rust emits.
+///
+/// Rust defines each event with [`define`], along with the type of its payload. Passed to Lua,
+/// the registry is a userdata with the following methods:
+///
+/// - `on(name, listener)` adds a function to call when the event `name` is emitted.
+/// - `off(name, listener)` removes a function added with `on`, and returns whether it was found.
+///
+/// Listening to an event which has not been defined raises an error. [`emit`] calls every
+/// listener of an event with the payload, in the order they were added. A listener which fails
+/// does not stop the others from being called: its error is returned along with the errors of
+/// the other listeners.
+///
+/// Clones of a registry share its events and listeners. The listeners are kept in the Lua state
+/// they were added in, until they are removed or the state is dropped, so the same registry can be
+/// used with several states.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Events, Lua, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let events = Events::new();
+/// events.define::<(String, i64)>("player_died");
+/// lua.globals().set("events", events.clone())?;
+///
+/// lua.exec::<()>(r#"
+///     deaths = 0
+///     events:on("player_died", function(name, score)
+///         deaths = deaths + 1
+///     end)
+///     events:on("player_died", function(name, score)
+///         error("broken plugin")
+///     end)
+/// "#, None)?;
+///
+/// let errors = events.emit(&lua, "player_died", ("alice".to_owned(), 42i64))?;
+/// assert_eq!(errors.len(), 1);
+/// assert_eq!(lua.globals().get::<_, i64>("deaths")?, 1);
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`define`]: #method.define
+/// [`emit`]: #method.emit
+#[derive(Clone, Debug)]
+pub struct Events(Rc<EventsInner>);
+
+#[derive(Debug)]
+struct EventsInner {
+    // Identifies the listeners of the registry in the states it is used with. Ids are never
+    // reused, so that a new registry does not see the listeners of a dropped one.
+    id: usize,
+    // The type of the payload of each defined event.
+    payloads: RefCell<HashMap<String, (TypeId, &'static str)>>,
+}
+
+impl Events {
+    /// Creates a registry without any events.
+    pub fn new() -> Events {
+        Events(Rc::new(EventsInner {
+            id: NEXT_EVENTS_ID.fetch_add(1, Ordering::Relaxed),
+            payloads: RefCell::new(HashMap::new()),
+        }))
+    }
+
+    /// Defines an event which scripts can listen to, with `T` as the type of its payload.
+    ///
+    /// Events with a payload of several values use a tuple, and events without a payload use
+    /// `()`. Defining an event again replaces the type of its payload.
+    pub fn define<T: 'static>(&self, name: &str) {
+        self.0
+            .payloads
+            .borrow_mut()
+            .insert(name.to_owned(), (TypeId::of::<T>(), type_name::<T>()));
+    }
+
+    /// Returns whether an event has been defined.
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.0.payloads.borrow().contains_key(name)
+    }
+
+    /// Calls the listeners of an event with `payload`, and returns the errors of the listeners
+    /// which failed.
+    ///
+    /// Returns an error, without calling any listener, if the event has not been defined, if `T`
+    /// is not the type of its payload, or if the payload cannot be converted to Lua values. As the
+    /// type must match exactly, integer literals in the payload may need a suffix such as `i64`.
+    pub fn emit<'lua, T>(&self, lua: &'lua Lua, name: &str, payload: T) -> Result<Vec<Error>>
+    where
+        T: 'static + ToLuaMulti<'lua>,
+    {
+        self.check_payload::<T>(name)?;
+        let payload = payload.to_lua_multi(lua)?;
+        let listeners = self.listeners(lua, name)?
+            .sequence_values::<Function>()
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut errors = Vec::new();
+        for listener in listeners {
+            if let Err(err) = listener.call::<_, ()>(payload.clone()) {
+                errors.push(err);
+            }
+        }
+        Ok(errors)
+    }
+
+    /// Returns the number of listeners of an event in a Lua state.
+    pub fn listener_count(&self, lua: &Lua, name: &str) -> Result<usize> {
+        Ok(self.listeners(lua, name)?.raw_len() as usize)
+    }
+
+    fn check_payload<T: 'static>(&self, name: &str) -> Result<()> {
+        match self.0.payloads.borrow().get(name) {
+            Some(&(type_id, _)) if type_id == TypeId::of::<T>() => Ok(()),
+            Some(&(_, payload)) => Err(Error::RuntimeError(format!(
+                "the payload of event '{}' is {}, not {}",
+                name,
+                payload,
+                type_name::<T>()
+            ))),
+            None => Err(unknown_event(name)),
+        }
+    }
+
+    // Returns the sequence of listeners of a defined event, creating it on first use.
+    fn listeners<'lua>(&self, lua: &'lua Lua, name: &str) -> Result<Table<'lua>> {
+        if !self.is_defined(name) {
+            return Err(unknown_event(name));
+        }
+        let events = lua.event_listeners(self.0.id);
+        match events.raw_get::<_, Option<Table>>(name)? {
+            Some(listeners) => Ok(listeners),
+            None => {
+                let listeners = lua.create_table();
+                events.raw_set(name, listeners.clone())?;
+                Ok(listeners)
+            }
+        }
+    }
+}
+
+impl Default for Events {
+    fn default() -> Events {
+        Events::new()
+    }
+}
+
+impl UserData for Events {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("on", |lua, events, (name, listener): (String, Function)| {
+            let listeners = events.listeners(lua, &name)?;
+            listeners.raw_set(listeners.raw_len() + 1, listener)
+        });
+
+        methods.add_method("off", |lua, events, (name, listener): (String, Function)| {
+            let listeners = events.listeners(lua, &name)?;
+            let len = listeners.raw_len();
+            for i in 1..=len {
+                if listeners.raw_get::<_, Function>(i)?.to_pointer() == listener.to_pointer() {
+                    for j in i..len {
+                        listeners.raw_set(j, listeners.raw_get::<_, Function>(j + 1)?)?;
+                    }
+                    listeners.raw_set(len, None::<Function>)?;
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        });
+    }
+}
+
+fn unknown_event(name: &str) -> Error {
+    Error::RuntimeError(format!("unknown event '{}'", name))
+}
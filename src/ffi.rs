@@ -2,8 +2,9 @@
 
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
+#![allow(clippy::missing_safety_doc)]
 
-use std::ptr;
+use std::{mem, ptr};
 use std::os::raw::{c_char, c_double, c_int, c_longlong, c_void};
 
 pub type lua_Integer = c_longlong;
@@ -23,6 +24,33 @@ pub type lua_KFunction = unsafe extern "C" fn(
     ctx: lua_KContext,
 ) -> c_int;
 pub type lua_CFunction = unsafe extern "C" fn(state: *mut lua_State) -> c_int;
+pub type lua_Writer = unsafe extern "C" fn(
+    state: *mut lua_State,
+    p: *const c_void,
+    sz: usize,
+    ud: *mut c_void,
+) -> c_int;
+pub type lua_Hook = unsafe extern "C" fn(state: *mut lua_State, ar: *mut lua_Debug);
+
+pub const LUA_IDSIZE: usize = 60;
+
+#[repr(C)]
+pub struct lua_Debug {
+    pub event: c_int,
+    pub name: *const c_char,
+    pub namewhat: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub currentline: c_int,
+    pub linedefined: c_int,
+    pub lastlinedefined: c_int,
+    pub nups: u8,
+    pub nparams: u8,
+    pub isvararg: c_char,
+    pub istailcall: c_char,
+    pub short_src: [c_char; LUA_IDSIZE],
+    i_ci: *mut c_void,
+}
 
 pub const LUA_OK: c_int = 0;
 pub const LUA_YIELD: c_int = 1;
@@ -36,6 +64,26 @@ pub const LUA_NOREF: c_int = -2;
 pub const LUA_REFNIL: c_int = -1;
 
 pub const LUA_MULTRET: c_int = -1;
+pub const LUA_MINSTACK: c_int = 20;
+
+pub const LUA_OPEQ: c_int = 0;
+
+pub const LUA_HOOKCALL: c_int = 0;
+pub const LUA_HOOKRET: c_int = 1;
+pub const LUA_HOOKLINE: c_int = 2;
+pub const LUA_HOOKCOUNT: c_int = 3;
+pub const LUA_HOOKTAILCALL: c_int = 4;
+
+pub const LUA_MASKCALL: c_int = 1 << 0;
+pub const LUA_MASKRET: c_int = 1 << 1;
+pub const LUA_MASKLINE: c_int = 1 << 2;
+pub const LUA_MASKCOUNT: c_int = 1 << 3;
+
+pub const LUA_GCCOLLECT: c_int = 2;
+pub const LUA_GCCOUNT: c_int = 3;
+pub const LUA_GCCOUNTB: c_int = 4;
+pub const LUA_GCSETPAUSE: c_int = 6;
+
 pub const LUAI_MAXSTACK: c_int = 1_000_000;
 pub const LUA_REGISTRYINDEX: c_int = -LUAI_MAXSTACK - 1000;
 pub const LUA_RIDX_MAINTHREAD: lua_Integer = 1;
@@ -57,6 +105,8 @@ extern "C" {
     pub fn lua_newstate(alloc: lua_Alloc, ud: *mut c_void) -> *mut lua_State;
 
     pub fn lua_close(state: *mut lua_State);
+    pub fn lua_getallocf(state: *mut lua_State, ud: *mut *mut c_void) -> lua_Alloc;
+    pub fn lua_setallocf(state: *mut lua_State, f: lua_Alloc, ud: *mut c_void);
     pub fn lua_callk(
         state: *mut lua_State,
         nargs: c_int,
@@ -72,8 +122,21 @@ extern "C" {
         ctx: lua_KContext,
         k: Option<lua_KFunction>,
     ) -> c_int;
+    pub fn lua_yieldk(
+        state: *mut lua_State,
+        nresults: c_int,
+        ctx: lua_KContext,
+        k: Option<lua_KFunction>,
+    ) -> c_int;
     pub fn lua_resume(state: *mut lua_State, from: *mut lua_State, nargs: c_int) -> c_int;
+    pub fn lua_isyieldable(state: *mut lua_State) -> c_int;
     pub fn lua_status(state: *mut lua_State) -> c_int;
+    pub fn lua_dump(
+        state: *mut lua_State,
+        writer: lua_Writer,
+        data: *mut c_void,
+        strip: c_int,
+    ) -> c_int;
 
     pub fn lua_pushnil(state: *mut lua_State);
     pub fn lua_pushvalue(state: *mut lua_State, index: c_int);
@@ -83,6 +146,7 @@ extern "C" {
     pub fn lua_pushlstring(state: *mut lua_State, s: *const c_char, len: usize) -> *const c_char;
     pub fn lua_pushlightuserdata(state: *mut lua_State, data: *mut c_void);
     pub fn lua_pushcclosure(state: *mut lua_State, function: lua_CFunction, n: c_int);
+    pub fn lua_pushthread(state: *mut lua_State) -> c_int;
 
     pub fn lua_tointegerx(state: *mut lua_State, index: c_int, isnum: *mut c_int) -> lua_Integer;
     pub fn lua_tolstring(state: *mut lua_State, index: c_int, len: *mut usize) -> *const c_char;
@@ -90,12 +154,15 @@ extern "C" {
     pub fn lua_tonumberx(state: *mut lua_State, index: c_int, isnum: *mut c_int) -> lua_Number;
     pub fn lua_touserdata(state: *mut lua_State, index: c_int) -> *mut c_void;
     pub fn lua_tothread(state: *mut lua_State, index: c_int) -> *mut lua_State;
+    pub fn lua_topointer(state: *mut lua_State, index: c_int) -> *const c_void;
+    pub fn lua_tocfunction(state: *mut lua_State, index: c_int) -> Option<lua_CFunction>;
 
     pub fn lua_gettop(state: *const lua_State) -> c_int;
     pub fn lua_settop(state: *mut lua_State, n: c_int);
     pub fn lua_checkstack(state: *mut lua_State, n: c_int) -> c_int;
     pub fn lua_rotate(state: *mut lua_State, index: c_int, n: c_int);
     pub fn lua_copy(state: *mut lua_State, from: c_int, to: c_int);
+    pub fn lua_xmove(from: *mut lua_State, to: *mut lua_State, n: c_int);
     pub fn lua_absindex(state: *mut lua_State, index: c_int) -> c_int;
 
     pub fn lua_isinteger(state: *mut lua_State, index: c_int) -> c_int;
@@ -106,23 +173,41 @@ extern "C" {
     pub fn lua_type(state: *mut lua_State, index: c_int) -> c_int;
 
     pub fn lua_gettable(state: *mut lua_State, index: c_int) -> c_int;
+    pub fn lua_getfield(state: *mut lua_State, index: c_int, k: *const c_char) -> c_int;
     pub fn lua_geti(state: *mut lua_State, index: c_int, i: lua_Integer) -> c_int;
     pub fn lua_rawget(state: *mut lua_State, index: c_int) -> c_int;
     pub fn lua_rawgeti(state: *mut lua_State, index: c_int, n: lua_Integer) -> c_int;
     pub fn lua_getmetatable(state: *mut lua_State, index: c_int) -> c_int;
+    pub fn lua_getuservalue(state: *mut lua_State, index: c_int) -> c_int;
 
     pub fn lua_createtable(state: *mut lua_State, narr: c_int, nrec: c_int);
     pub fn lua_newuserdata(state: *mut lua_State, size: usize) -> *mut c_void;
     pub fn lua_newthread(state: *mut lua_State) -> *mut lua_State;
 
     pub fn lua_settable(state: *mut lua_State, index: c_int);
+    pub fn lua_setfield(state: *mut lua_State, index: c_int, k: *const c_char);
     pub fn lua_rawset(state: *mut lua_State, index: c_int);
+    pub fn lua_rawseti(state: *mut lua_State, index: c_int, n: lua_Integer);
     pub fn lua_setmetatable(state: *mut lua_State, index: c_int);
+    pub fn lua_setuservalue(state: *mut lua_State, index: c_int);
 
     pub fn lua_len(state: *mut lua_State, index: c_int);
     pub fn lua_rawlen(state: *mut lua_State, index: c_int) -> usize;
     pub fn lua_next(state: *mut lua_State, index: c_int) -> c_int;
     pub fn lua_rawequal(state: *mut lua_State, index1: c_int, index2: c_int) -> c_int;
+    pub fn lua_compare(state: *mut lua_State, index1: c_int, index2: c_int, op: c_int) -> c_int;
+    pub fn lua_concat(state: *mut lua_State, n: c_int);
+
+    pub fn lua_getstack(state: *mut lua_State, level: c_int, ar: *mut lua_Debug) -> c_int;
+    pub fn lua_getinfo(state: *mut lua_State, what: *const c_char, ar: *mut lua_Debug) -> c_int;
+    pub fn lua_getlocal(state: *mut lua_State, ar: *const lua_Debug, n: c_int) -> *const c_char;
+    pub fn lua_getupvalue(state: *mut lua_State, funcindex: c_int, n: c_int) -> *const c_char;
+    pub fn lua_setupvalue(state: *mut lua_State, funcindex: c_int, n: c_int) -> *const c_char;
+    pub fn lua_upvalueid(state: *mut lua_State, funcindex: c_int, n: c_int) -> *mut c_void;
+    pub fn lua_upvaluejoin(state: *mut lua_State, f1: c_int, n1: c_int, f2: c_int, n2: c_int);
+    pub fn lua_sethook(state: *mut lua_State, f: Option<lua_Hook>, mask: c_int, count: c_int);
+
+    pub fn lua_gc(state: *mut lua_State, what: c_int, data: c_int) -> c_int;
 
     pub fn lua_error(state: *mut lua_State) -> !;
     pub fn lua_atpanic(state: *mut lua_State, panic: lua_CFunction) -> lua_CFunction;
@@ -137,6 +222,7 @@ extern "C" {
     pub fn luaopen_math(state: *mut lua_State) -> c_int;
     pub fn luaopen_debug(state: *mut lua_State) -> c_int;
     pub fn luaopen_package(state: *mut lua_State) -> c_int;
+    pub fn luaopen_bit32(state: *mut lua_State) -> c_int;
 
     pub fn luaL_openlibs(state: *mut lua_State);
     pub fn luaL_requiref(
@@ -156,6 +242,9 @@ extern "C" {
     pub fn luaL_ref(state: *mut lua_State, table: c_int) -> c_int;
     pub fn luaL_unref(state: *mut lua_State, table: c_int, lref: c_int);
     pub fn luaL_checkstack(state: *mut lua_State, size: c_int, msg: *const c_char);
+    pub fn luaL_checklstring(state: *mut lua_State, arg: c_int, l: *mut usize) -> *const c_char;
+    pub fn luaL_getsubtable(state: *mut lua_State, index: c_int, fname: *const c_char) -> c_int;
+    pub fn luaL_where(state: *mut lua_State, level: c_int);
     pub fn luaL_traceback(
         push_state: *mut lua_State,
         state: *mut lua_State,
@@ -163,6 +252,51 @@ extern "C" {
         level: c_int,
     );
     pub fn luaL_len(push_state: *mut lua_State, index: c_int) -> lua_Integer;
+    pub fn luaL_tolstring(state: *mut lua_State, index: c_int, len: *mut usize) -> *const c_char;
+}
+
+// A call of a suspended coroutine, see `src/internals.c`.
+#[cfg(feature = "builtin-lua")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct rlua_Frame {
+    pub func: lua_Integer,
+    pub top: lua_Integer,
+    pub base: lua_Integer,
+    pub pc: lua_Integer,
+    pub extra: lua_Integer,
+    pub nresults: c_int,
+    pub callstatus: c_int,
+}
+
+// Helpers reaching into the internals of the bundled Lua, from `src/internals.c`.
+#[cfg(feature = "builtin-lua")]
+#[link(name = "lua5.3")]
+extern "C" {
+    pub(crate) fn rlua_stacktop(co: *mut lua_State) -> lua_Integer;
+    pub(crate) fn rlua_pushslot(state: *mut lua_State, co: *mut lua_State, i: lua_Integer);
+    pub(crate) fn rlua_getframes(co: *mut lua_State, frames: *mut rlua_Frame, n: c_int) -> c_int;
+    pub(crate) fn rlua_setframes(
+        co: *mut lua_State,
+        frames: *const rlua_Frame,
+        n: c_int,
+    ) -> *const c_char;
+    pub(crate) fn rlua_openupvalue(state: *mut lua_State, n: c_int) -> lua_Integer;
+    pub(crate) fn rlua_joinopenupvalue(
+        state: *mut lua_State,
+        n: c_int,
+        co: *mut lua_State,
+        level: lua_Integer,
+    ) -> *const c_char;
+    pub(crate) fn rlua_resetthread(co: *mut lua_State);
+    pub(crate) fn rlua_setextraspace(state: *mut lua_State, p: *mut c_void);
+    pub(crate) fn rlua_settablehook(
+        state: *mut lua_State,
+        hook: Option<
+            unsafe extern "C" fn(state: *mut lua_State, ud: *mut c_void, slots: usize) -> c_int,
+        >,
+        ud: *mut c_void,
+    );
 }
 
 pub unsafe fn lua_pop(state: *mut lua_State, n: c_int) {
@@ -181,6 +315,10 @@ pub unsafe fn lua_pushcfunction(state: *mut lua_State, function: lua_CFunction)
     lua_pushcclosure(state, function, 0);
 }
 
+pub unsafe fn lua_getextraspace(state: *mut lua_State) -> *mut c_void {
+    (state as *mut u8).offset(-(mem::size_of::<*mut c_void>() as isize)) as *mut c_void
+}
+
 pub unsafe fn lua_tonumber(state: *mut lua_State, index: c_int) -> lua_Number {
     lua_tonumberx(state, index, ptr::null_mut())
 }
@@ -271,6 +409,10 @@ pub unsafe fn lua_pcall(
     lua_pcallk(state, nargs, nresults, msgh, ptr::null_mut(), None)
 }
 
+pub unsafe fn lua_yield(state: *mut lua_State, nresults: c_int) -> c_int {
+    lua_yieldk(state, nresults, ptr::null_mut(), None)
+}
+
 pub unsafe fn lua_replace(state: *mut lua_State, index: c_int) {
     lua_copy(state, -1, index);
     lua_pop(state, 1);
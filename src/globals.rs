@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+use std::os::raw::c_void;
+use std::string::String as StdString;
+
+use error::Result;
+use lua::{is_identifier, Value};
+use table::Table;
+use types::{Integer, Number};
+
+/// An owned copy of the global variables of a Lua state, taken with [`Lua::globals_snapshot`].
+///
+/// Snapshots are meant to be compared with [`diff`], for example to find out which globals a
+/// script created or modified. With the `serde` feature, they can also be serialized.
+///
+/// [`Lua::globals_snapshot`]: struct.Lua.html#method.globals_snapshot
+/// [`diff`]: #method.diff
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalsSnapshot {
+    pub(crate) globals: BTreeMap<StdString, SnapshotValue>,
+}
+
+/// A value copied into a [`GlobalsSnapshot`].
+///
+/// [`GlobalsSnapshot`]: struct.GlobalsSnapshot.html
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotValue {
+    Boolean(bool),
+    Integer(Integer),
+    Number(Number),
+    /// The bytes of a Lua string, which do not have to be valid UTF-8.
+    String(Vec<u8>),
+    /// The fields of a table, keyed like in [`GlobalsSnapshot::get`].
+    ///
+    /// [`GlobalsSnapshot::get`]: struct.GlobalsSnapshot.html#method.get
+    Table(BTreeMap<StdString, SnapshotValue>),
+    /// A table which contains itself, or is nested deeper than the snapshot goes. Holds the
+    /// address of the table.
+    Truncated(usize),
+    /// A function, thread, userdata or light userdata, which is not copied. Holds the name of the
+    /// Lua type of the value and its address.
+    Other(&'static str, usize),
+}
+
+/// The differences between two [`GlobalsSnapshot`]s, as returned by [`GlobalsSnapshot::diff`].
+///
+/// Each difference is described by the path of the variable, such as `config.debug` or
+/// `handlers[1]`, and the paths are sorted.
+///
+/// [`GlobalsSnapshot`]: struct.GlobalsSnapshot.html
+/// [`GlobalsSnapshot::diff`]: struct.GlobalsSnapshot.html#method.diff
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GlobalsDiff {
+    /// Variables which only exist in the newer snapshot.
+    pub added: Vec<StdString>,
+    /// Variables which only exist in the older snapshot.
+    pub removed: Vec<StdString>,
+    /// Variables which exist in both snapshots with different values.
+    pub changed: Vec<StdString>,
+}
+
+impl GlobalsDiff {
+    /// Returns `true` if the snapshots are equal.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl GlobalsSnapshot {
+    /// Returns the global variables in the snapshot.
+    ///
+    /// Fields whose key is an identifier are keyed by their name, and other fields by their key
+    /// in brackets, such as `[1]` or `["a b"]`.
+    pub fn globals(&self) -> &BTreeMap<StdString, SnapshotValue> {
+        &self.globals
+    }
+
+    /// Returns the value of a global variable, or of a field of one, by its path.
+    ///
+    /// Paths are written like in Lua, such as `config.debug` or `handlers[1]`, but only refer to
+    /// keys as they are shown by [`globals`].
+    ///
+    /// [`globals`]: #method.globals
+    pub fn get(&self, path: &str) -> Option<&SnapshotValue> {
+        let mut keys = split_path(path).into_iter();
+        let mut value = self.globals.get(keys.next()?)?;
+        for key in keys {
+            value = match *value {
+                SnapshotValue::Table(ref fields) => fields.get(key)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    /// Compares this snapshot with a newer one.
+    ///
+    /// Tables are compared field by field, other values by their contents, or by their addresses
+    /// for functions, threads and userdata.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.exec::<()>("config = { debug = false }", None)?;
+    ///
+    /// let before = lua.globals_snapshot()?;
+    /// lua.exec::<()>("config.debug = true; count = 1; print = nil", None)?;
+    /// let diff = before.diff(&lua.globals_snapshot()?);
+    ///
+    /// assert_eq!(diff.added, vec!["count"]);
+    /// assert_eq!(diff.removed, vec!["print"]);
+    /// assert_eq!(diff.changed, vec!["config.debug"]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn diff(&self, newer: &GlobalsSnapshot) -> GlobalsDiff {
+        let mut diff = GlobalsDiff::default();
+        diff_fields("", &self.globals, &newer.globals, &mut diff);
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+}
+
+// Copies the fields of the globals table.
+pub(crate) fn snapshot<'lua>(globals: Table<'lua>, max_depth: usize) -> Result<GlobalsSnapshot> {
+    Ok(GlobalsSnapshot {
+        globals: copy_fields(globals, max_depth, &mut Vec::new())?,
+    })
+}
+
+// Copies the fields of a table, `visited` holds the addresses of the tables which are currently
+// being copied.
+fn copy_fields<'lua>(
+    table: Table<'lua>,
+    depth: usize,
+    visited: &mut Vec<*const c_void>,
+) -> Result<BTreeMap<StdString, SnapshotValue>> {
+    visited.push(table.to_pointer());
+    let mut fields = BTreeMap::new();
+    for pair in table.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let value = match value {
+            Value::Nil => continue,
+            Value::Boolean(b) => SnapshotValue::Boolean(b),
+            Value::Integer(i) => SnapshotValue::Integer(i),
+            Value::Number(n) => SnapshotValue::Number(n),
+            Value::String(s) => SnapshotValue::String(s.as_bytes().to_vec()),
+            Value::Table(t) => {
+                let pointer = t.to_pointer();
+                if depth == 0 || visited.contains(&pointer) {
+                    SnapshotValue::Truncated(pointer as usize)
+                } else {
+                    SnapshotValue::Table(copy_fields(t, depth - 1, visited)?)
+                }
+            }
+            Value::Function(f) => SnapshotValue::Other("function", f.to_pointer() as usize),
+            Value::Thread(t) => SnapshotValue::Other("thread", t.to_pointer() as usize),
+            Value::UserData(ud) => SnapshotValue::Other("userdata", ud.to_pointer() as usize),
+            Value::LightUserData(ud) => SnapshotValue::Other("userdata", ud.0 as usize),
+            Value::Error(_) => continue,
+        };
+        let key = match key.as_str() {
+            Some(name) if is_identifier(name) => name.to_owned(),
+            _ => format!("[{}]", key.to_debug_string(0)),
+        };
+        fields.insert(key, value);
+    }
+    visited.pop();
+    Ok(fields)
+}
+
+// Splits a path like `a.b[1].c` into the keys `a`, `b`, `[1]` and `c`.
+fn split_path(path: &str) -> Vec<&str> {
+    let mut keys = Vec::new();
+    let mut rest = path;
+    while !rest.is_empty() {
+        if rest.starts_with('[') {
+            // Keys in brackets may contain dots and brackets within strings.
+            let end = bracket_end(rest);
+            keys.push(&rest[..end]);
+            rest = &rest[end..];
+        } else {
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            keys.push(&rest[..end]);
+            rest = &rest[end..];
+        }
+        if let Some(next) = rest.strip_prefix('.') {
+            rest = next;
+        }
+    }
+    keys
+}
+
+// Returns the length of the key in brackets at the start of `path`.
+fn bracket_end(path: &str) -> usize {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in path.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ']' if !in_string => return i + 1,
+            _ => {}
+        }
+    }
+    path.len()
+}
+
+// Appends a key to a path.
+fn join_path(path: &str, key: &str) -> StdString {
+    if path.is_empty() || key.starts_with('[') {
+        format!("{}{}", path, key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn diff_fields(
+    path: &str,
+    older: &BTreeMap<StdString, SnapshotValue>,
+    newer: &BTreeMap<StdString, SnapshotValue>,
+    diff: &mut GlobalsDiff,
+) {
+    for (key, old) in older {
+        let path = join_path(path, key);
+        match (old, newer.get(key)) {
+            (_, None) => diff.removed.push(path),
+            (SnapshotValue::Table(old), Some(SnapshotValue::Table(new))) => {
+                diff_fields(&path, old, new, diff)
+            }
+            (old, Some(new)) => if !same_value(old, new) {
+                diff.changed.push(path)
+            },
+        }
+    }
+    for key in newer.keys() {
+        if !older.contains_key(key) {
+            diff.added.push(join_path(path, key));
+        }
+    }
+}
+
+// Compares two values, where NaN is equal to itself.
+fn same_value(a: &SnapshotValue, b: &SnapshotValue) -> bool {
+    match (a, b) {
+        (&SnapshotValue::Number(a), &SnapshotValue::Number(b)) => a.to_bits() == b.to_bits(),
+        (a, b) => a == b,
+    }
+}
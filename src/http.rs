@@ -0,0 +1,165 @@
+use std::io::Read;
+use std::result::Result as StdResult;
+use std::string::String as StdString;
+use std::time::Duration;
+
+use ureq;
+
+use error::{Error, Result};
+use lua::Lua;
+use multi::LuaConvention;
+use string::String;
+use table::Table;
+
+/// Options of the module installed by [`Lua::load_http_module`].
+///
+/// [`Lua::load_http_module`]: struct.Lua.html#method.load_http_module
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpOptions {
+    /// Hosts which scripts may send requests to.
+    ///
+    /// A host of the form `*.example.com` allows every subdomain of `example.com`, but not
+    /// `example.com` itself. Defaults to no hosts, so that scripts cannot send any requests.
+    pub allowed_hosts: Vec<StdString>,
+    /// Time limit for a whole request, including connecting and reading the response.
+    ///
+    /// Defaults to 30 seconds.
+    pub timeout: Duration,
+    /// Largest response body, in bytes, which is read. A request fails if the body is larger.
+    ///
+    /// Defaults to 16 MiB.
+    pub max_body_size: usize,
+}
+
+impl Default for HttpOptions {
+    fn default() -> HttpOptions {
+        HttpOptions {
+            allowed_hosts: Vec::new(),
+            timeout: Duration::from_secs(30),
+            max_body_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl HttpOptions {
+    fn allows_host(&self, host: &str) -> bool {
+        let host = host.as_bytes();
+        self.allowed_hosts.iter().any(|allowed| match allowed.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => {
+                host.len() > suffix.len()
+                    && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix.as_bytes())
+            }
+            _ => host.eq_ignore_ascii_case(allowed.as_bytes()),
+        })
+    }
+}
+
+type Response<'lua> = LuaConvention<(u16, Table<'lua>, String<'lua>), StdString>;
+
+// Creates the table of functions installed by `Lua::load_http_module`.
+pub(crate) fn create_http_module(lua: &Lua, options: HttpOptions) -> Result<Table<'_>> {
+    // Redirects are returned to scripts rather than followed, so that they cannot lead to hosts
+    // which are not allowed.
+    let agent = ureq::AgentBuilder::new()
+        .timeout(options.timeout)
+        .redirects(0)
+        .build();
+    let module = lua.create_table();
+
+    let get_agent = agent.clone();
+    let get_options = options.clone();
+    module.raw_set(
+        "get",
+        lua.create_function(move |lua, (url, headers): (String, Option<Table>)| {
+            let request = prepare(&get_agent, &get_options, "GET", &url, headers)?;
+            response(lua, &get_options, request.call())
+        }),
+    )?;
+
+    module.raw_set(
+        "post",
+        lua.create_function(
+            move |lua, (url, body, headers): (String, String, Option<Table>)| {
+                let request = prepare(&agent, &options, "POST", &url, headers)?;
+                response(lua, &options, request.send_bytes(body.as_bytes()))
+            },
+        ),
+    )?;
+
+    Ok(module)
+}
+
+// Creates a request, after checking that its host is allowed.
+fn prepare(
+    agent: &ureq::Agent,
+    options: &HttpOptions,
+    method: &str,
+    url: &String,
+    headers: Option<Table>,
+) -> Result<ureq::Request> {
+    let url = url.to_str()?;
+    let mut request = agent.request(method, url);
+
+    let request_url = request
+        .request_url()
+        .map_err(|err| Error::RuntimeError(format!("invalid URL '{}': {}", url, err)))?;
+    if request_url.scheme() != "http" && request_url.scheme() != "https" {
+        return Err(Error::RuntimeError(format!(
+            "unsupported URL scheme '{}'",
+            request_url.scheme()
+        )));
+    }
+    if !options.allows_host(request_url.host()) {
+        return Err(Error::RuntimeError(format!(
+            "requests to '{}' are not allowed",
+            request_url.host()
+        )));
+    }
+
+    if let Some(headers) = headers {
+        for pair in headers.pairs::<String, String>() {
+            let (name, value) = pair?;
+            let name = name.to_str()?;
+            // Another `Host` would reach other sites served from the address of an allowed host.
+            if name.trim().eq_ignore_ascii_case("host") {
+                return Err(Error::RuntimeError("the Host header cannot be set".to_owned()));
+            }
+            request = request.set(name, value.to_str()?);
+        }
+    }
+    Ok(request)
+}
+
+// Returns the status, headers and body of a response, or `nil` and a message if the request
+// failed. Responses with an error status are returned like any other response.
+fn response<'lua>(
+    lua: &'lua Lua,
+    options: &HttpOptions,
+    result: StdResult<ureq::Response, ureq::Error>,
+) -> Result<Response<'lua>> {
+    let response = match result {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(err) => return Ok(LuaConvention(Err(err.to_string()))),
+    };
+
+    let status = response.status();
+    let headers = lua.create_table();
+    for name in response.headers_names() {
+        let value = response.all(&name).join(", ");
+        headers.raw_set(name.to_ascii_lowercase(), value)?;
+    }
+
+    let mut body = Vec::new();
+    let limit = options.max_body_size as u64 + 1;
+    if let Err(err) = response.into_reader().take(limit).read_to_end(&mut body) {
+        return Ok(LuaConvention(Err(err.to_string())));
+    }
+    if body.len() > options.max_body_size {
+        return Ok(LuaConvention(Err(format!(
+            "response body is larger than {} bytes",
+            options.max_body_size
+        ))));
+    }
+
+    Ok(LuaConvention(Ok((status, headers, lua.create_byte_string(&body)))))
+}
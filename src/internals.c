@@ -0,0 +1,325 @@
+/*
+** Helpers reaching into the internals of the bundled Lua, for what the C API does not give access
+** to. Only built along with the bundled Lua, against which they must be kept in sync.
+*/
+
+#include <string.h>
+
+#include "lua.h"
+
+#include "ldebug.h"
+#include "ldo.h"
+#include "lfunc.h"
+#include "lgc.h"
+#include "lobject.h"
+#include "lstate.h"
+#include "ltable.h"
+
+
+/*
+** A call of a suspended coroutine. Positions are indices in the stack of the coroutine, 'base'
+** and 'pc' are only used by calls of Lua functions, and 'extra' only by the innermost call, where
+** it holds the position of the function which yielded.
+*/
+typedef struct rlua_Frame {
+  lua_Integer func;
+  lua_Integer top;
+  lua_Integer base;
+  lua_Integer pc;
+  lua_Integer extra;
+  int nresults;
+  int callstatus;
+} rlua_Frame;
+
+
+/*
+** Returns the number of values on the stack of 'co', including the unused slot below its first
+** function.
+*/
+lua_Integer rlua_stacktop (lua_State *co) {
+  return co->top - co->stack;
+}
+
+
+/*
+** Pushes the value at position 'i' of the stack of 'co' onto the stack of 'L'. There must be room
+** for it.
+*/
+void rlua_pushslot (lua_State *L, lua_State *co, lua_Integer i) {
+  setobj2s(L, L->top, co->stack + i);
+  L->top++;
+}
+
+
+/*
+** Writes the calls of the suspended coroutine 'co' to 'frames', outermost first, if they fit in
+** 'n' frames, and returns their number. Returns -1 if 'co' is suspended inside a C function
+** other than the one which yielded, or if that function has a continuation, as C functions
+** cannot be resumed elsewhere.
+*/
+int rlua_getframes (lua_State *co, rlua_Frame *frames, int n) {
+  CallInfo *ci;
+  int count = 0;
+  for (ci = co->ci; ci != &co->base_ci; ci = ci->previous) {
+    if (!isLua(ci) && (ci != co->ci || ci->u.c.k != NULL))
+      return -1;
+    count++;
+  }
+  if (count > n)
+    return count;
+  n = count;
+  for (ci = co->ci; ci != &co->base_ci; ci = ci->previous) {
+    rlua_Frame *f = &frames[--n];
+    /* the call which yielded has moved its 'func' above its results */
+    StkId func = (ci == co->ci) ? restorestack(co, ci->extra) : ci->func;
+    f->func = ci->func - co->stack;
+    f->top = ci->top - co->stack;
+    f->nresults = ci->nresults;
+    f->callstatus = ci->callstatus;
+    f->extra = (ci == co->ci) ? func - co->stack : 0;
+    if (isLua(ci)) {
+      f->base = ci->u.l.base - co->stack;
+      f->pc = ci->u.l.savedpc - clLvalue(func)->p->code;
+    }
+    else {
+      f->base = 0;
+      f->pc = 0;
+    }
+  }
+  return count;
+}
+
+
+static void extendci (lua_State *co, void *ud) {
+  int n = *(int *)ud;
+  CallInfo *ci = &co->base_ci;
+  while (n-- > 0) {
+    if (ci->next == NULL) {
+      co->ci = ci;
+      luaE_extendCI(co);
+    }
+    ci = ci->next;
+  }
+}
+
+
+/*
+** Returns the position of the function of a call, which the call which yielded has saved in
+** 'extra'.
+*/
+static StkId framefunc (lua_State *co, const rlua_Frame *f, int innermost) {
+  return co->stack + (innermost ? f->extra : f->func);
+}
+
+
+/*
+** Checks a call read back from persisted data, against the values already on the stack of 'co'.
+*/
+static const char *checkframe (lua_State *co, const rlua_Frame *f, int innermost) {
+  lua_Integer size = co->top - co->stack;
+  lua_Integer limit = co->stack_last - co->stack;
+  StkId func;
+  if (f->func < 1 || f->func >= size || f->top < f->func || f->top > limit)
+    return "call outside of the stack";
+  if (innermost && (f->extra < 1 || f->extra >= size))
+    return "coroutine which did not yield";
+  if (f->nresults < LUA_MULTRET || f->nresults > SHRT_MAX)
+    return "invalid number of results";
+  if (f->callstatus & ~(CIST_OAH | CIST_LUA | CIST_FRESH | CIST_TAIL | CIST_HOOKYIELD | CIST_LEQ))
+    return "unsupported call";
+  func = framefunc(co, f, innermost);
+  if (f->callstatus & CIST_LUA) {
+    Proto *p;
+    if (!ttisLclosure(func))
+      return "call of a Lua function which is not a Lua function";
+    p = clLvalue(func)->p;
+    if (f->base <= func - co->stack || f->base + p->maxstacksize != f->top)
+      return "call outside of the stack";
+    if (f->pc < 0 || f->pc > p->sizecode)
+      return "call outside of its function";
+    if (innermost && !(f->callstatus & CIST_HOOKYIELD))
+      return "coroutine which did not yield";
+  }
+  else if (!innermost)
+    return "call of a C function which did not yield";
+  else if (!ttisfunction(func) || ttisLclosure(func))
+    return "call of a C function which is not a C function";
+  return NULL;
+}
+
+
+/*
+** Suspends the coroutine 'co', which has not been started, in the calls 'frames', outermost
+** first, as written by 'rlua_getframes'. The stack of 'co' must already hold its values. Returns
+** NULL, or a description of why the calls are not valid, or "not enough memory".
+*/
+const char *rlua_setframes (lua_State *co, const rlua_Frame *frames, int n) {
+  CallInfo *ci;
+  int i;
+  if (co->status != LUA_OK || co->ci != &co->base_ci || n < 1)
+    return "coroutine without calls";
+  for (i = 0; i < n; i++) {
+    const char *msg = checkframe(co, &frames[i], i == n - 1);
+    if (msg != NULL)
+      return msg;
+  }
+  if (luaD_rawrunprotected(co, extendci, &n) != LUA_OK) {
+    co->ci = &co->base_ci;
+    return "not enough memory";
+  }
+  ci = &co->base_ci;
+  for (i = 0; i < n; i++) {
+    const rlua_Frame *f = &frames[i];
+    ci = ci->next;
+    ci->func = co->stack + f->func;
+    ci->top = co->stack + f->top;
+    ci->nresults = cast(short, f->nresults);
+    ci->callstatus = cast(unsigned short, f->callstatus);
+    ci->extra = savestack(co, co->stack + f->extra);
+    if (isLua(ci)) {
+      ci->u.l.base = co->stack + f->base;
+      ci->u.l.savedpc = clLvalue(framefunc(co, f, i == n - 1))->p->code + f->pc;
+    }
+    else {
+      ci->u.c.k = NULL;
+      ci->u.c.old_errfunc = 0;
+      ci->u.c.ctx = 0;
+    }
+  }
+  co->ci = ci;
+  /* line hooks compare the resumed instruction with the last one they ran on */
+  if (isLua(ci))
+    co->oldpc = ci->u.l.savedpc;
+  co->status = LUA_YIELD;
+  return NULL;
+}
+
+
+/*
+** If upvalue 'n' of the Lua function on top of the stack of 'L' is open, pushes the thread whose
+** stack holds it and returns its position in that stack, or returns -1 otherwise. There must be
+** room for the thread.
+*/
+lua_Integer rlua_openupvalue (lua_State *L, int n) {
+  LClosure *f = clLvalue(L->top - 1);
+  UpVal *uv;
+  lua_State *th;
+  if (n < 1 || n > f->nupvalues || f->upvals[n - 1] == NULL)
+    return -1;
+  uv = f->upvals[n - 1];
+  if (!upisopen(uv))
+    return -1;
+  for (th = G(L)->twups; th != NULL; th = th->twups) {
+    if (uv->v >= th->stack && uv->v < th->stack + th->stacksize) {
+      setthvalue(L, L->top, th);
+      L->top++;
+      return uv->v - th->stack;
+    }
+  }
+  return -1;
+}
+
+
+typedef struct FindUpval {
+  StkId level;
+  UpVal *uv;
+} FindUpval;
+
+
+static void findupval (lua_State *co, void *ud) {
+  FindUpval *find = (FindUpval *)ud;
+  find->uv = luaF_findupval(co, find->level);
+}
+
+
+/*
+** Makes upvalue 'n' of the Lua function on top of the stack of 'L' the open upvalue of the value
+** at position 'level' of the stack of 'co'. Returns NULL, or a description of why it cannot.
+*/
+const char *rlua_joinopenupvalue (lua_State *L, int n, lua_State *co, lua_Integer level) {
+  LClosure *f = clLvalue(L->top - 1);
+  UpVal **up;
+  FindUpval find;
+  if (n < 1 || n > f->nupvalues)
+    return "function has fewer upvalues";
+  if (level < 1 || level >= co->top - co->stack)
+    return "upvalue outside of the stack";
+  find.level = co->stack + level;
+  if (luaD_rawrunprotected(co, findupval, &find) != LUA_OK)
+    return "not enough memory";
+  up = &f->upvals[n - 1];
+  luaC_upvdeccount(L, *up);
+  *up = find.uv;
+  find.uv->refcount++;
+  find.uv->u.open.touched = 1;
+  luaC_upvalbarrier(L, find.uv);
+  return NULL;
+}
+
+
+/*
+** Abandons the calls of 'co', which is suspended or has raised an error, so that it can be
+** started again. Closes its open upvalues and leaves its stack empty. Backported from
+** 'luaE_resetthread' of Lua 5.4, without the '__close' metamethods 5.3 does not have.
+*/
+void rlua_resetthread (lua_State *co) {
+  CallInfo *ci = co->ci = &co->base_ci;
+  luaF_close(co, co->stack);
+  setnilvalue(co->stack);
+  ci->func = co->stack;
+  ci->callstatus = 0;
+  co->status = LUA_OK;
+  co->errfunc = 0;
+  /* an error raised in a hook skips turning hooks back on */
+  co->allowhook = 1;
+  co->top = co->stack + 1;
+  ci->top = co->top + LUA_MINSTACK;
+}
+
+
+/*
+** Stores 'p' in the extra space of every thread of the state of 'L'. Threads copy the extra space
+** of the main thread when they are created, so those created before the state was attached by
+** rlua hold whatever the main thread had then.
+*/
+void rlua_setextraspace (lua_State *L, void *p) {
+  global_State *g = G(L);
+  GCObject *o;
+  memcpy(lua_getextraspace(g->mainthread), &p, sizeof(p));
+  for (o = g->allgc; o != NULL; o = o->next) {
+    if (o->tt == LUA_TTHREAD)
+      memcpy(lua_getextraspace(gco2th(o)), &p, sizeof(p));
+  }
+}
+
+
+typedef int (*rlua_TableHook) (lua_State *L, void *ud, size_t slots);
+
+
+/*
+** Sets the function which 'rlua_tablegrowth' reports the growth of the tables of the state of
+** 'L' to, and its userdata, or removes it if 'hook' is NULL.
+*/
+void rlua_settablehook (lua_State *L, rlua_TableHook hook, void *ud) {
+  global_State *g = G(L);
+  g->tablehook = hook;
+  g->tablehookud = ud;
+}
+
+
+/*
+** Called by 'luaH_resize' before table 't' is resized to 'nasize' slots in its array part and
+** room for 'nhsize' entries in its hash part, which is rounded up to a power of 2. Reports the
+** number of slots the table grows by, if it grows, and raises an error instead of letting it
+** grow if the hook returns 0.
+*/
+void rlua_tablegrowth (lua_State *L, const Table *t, unsigned int nasize, unsigned int nhsize) {
+  global_State *g = G(L);
+  size_t oldsize = t->sizearray + allocsizenode(t);
+  size_t newsize = nasize;
+  if (nhsize > 0)
+    newsize += (size_t)1 << luaO_ceillog2(nhsize);
+  if (g->tablehook != NULL && newsize > oldsize &&
+      !g->tablehook(L, g->tablehookud, newsize - oldsize))
+    luaG_runerror(L, "table entry limit exceeded");
+}
@@ -0,0 +1,77 @@
+use serde_json::{self, Value as Json};
+
+use error::{Error, Result};
+use lua::{FromLua, Lua, Nil, Value};
+use string::String;
+use table::Table;
+
+// Creates the table of functions installed by `Lua::load_json_module`.
+pub(crate) fn create_json_module(lua: &Lua) -> Result<Table<'_>> {
+    let module = lua.create_table();
+    module.raw_set("null", lua.null())?;
+
+    module.raw_set(
+        "encode",
+        lua.create_function(|lua, (value, options): (Value, Option<Table>)| {
+            let pretty = match options {
+                Some(options) => options.get::<_, Option<bool>>("pretty")?.unwrap_or(false),
+                None => false,
+            };
+            let json = Json::from_lua(value, lua)?;
+            let encoded = if pretty {
+                serde_json::to_string_pretty(&json)
+            } else {
+                serde_json::to_string(&json)
+            };
+            encoded.map_err(|err| Error::RuntimeError(err.to_string()))
+        }),
+    )?;
+
+    module.raw_set(
+        "decode",
+        lua.create_function(|lua, (s, options): (String, Option<Table>)| {
+            let null = match options {
+                Some(options) => match options.raw_get::<_, Value>("null")? {
+                    Nil => lua.null(),
+                    null => null,
+                },
+                None => lua.null(),
+            };
+            let json = serde_json::from_slice::<Json>(s.as_bytes())
+                .map_err(|err| Error::RuntimeError(format!("invalid JSON: {}", err)))?;
+            decode(lua, json, &null, &lua.array_metatable())
+        }),
+    )?;
+
+    Ok(module)
+}
+
+// Converts a decoded JSON value to Lua, replacing nulls with `null`. Unlike the `ToLua`
+// implementation, this keeps nulls inside of arrays and objects, and marks arrays with the array
+// metatable so that empty arrays are encoded as arrays again.
+fn decode<'lua>(
+    lua: &'lua Lua,
+    json: Json,
+    null: &Value<'lua>,
+    array_metatable: &Table<'lua>,
+) -> Result<Value<'lua>> {
+    Ok(match json {
+        Json::Null => null.clone(),
+        Json::Array(values) => {
+            let table = lua.create_table_with_capacity(values.len(), 0);
+            for (i, value) in values.into_iter().enumerate() {
+                table.raw_set(i + 1, decode(lua, value, null, array_metatable)?)?;
+            }
+            table.set_metatable(Some(array_metatable.clone()));
+            Value::Table(table)
+        }
+        Json::Object(map) => {
+            let table = lua.create_table_with_capacity(0, map.len());
+            for (key, value) in map {
+                table.raw_set(key, decode(lua, value, null, array_metatable)?)?;
+            }
+            Value::Table(table)
+        }
+        json => lua.pack(json)?,
+    })
+}
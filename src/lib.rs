@@ -41,6 +41,40 @@
 #![doc(test(attr(deny(warnings))))]
 
 extern crate libc;
+#[cfg(feature = "anyhow")]
+extern crate anyhow;
+#[cfg(feature = "eyre")]
+extern crate eyre;
+#[cfg(feature = "derive")]
+extern crate rlua_derive;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "msgpack")]
+extern crate rmpv;
+#[cfg(feature = "toml")]
+extern crate toml;
+#[cfg(feature = "yaml")]
+extern crate serde_yaml;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "rand")]
+extern crate rand_core;
+#[cfg(all(test, feature = "rand"))]
+extern crate rand_chacha;
+#[cfg(feature = "http")]
+extern crate ureq;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate erased_serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(any(feature = "serde_json", all(test, feature = "serde")))]
+extern crate serde_json;
 
 pub mod ffi;
 #[macro_use]
@@ -49,21 +83,96 @@ mod error;
 mod types;
 mod lua;
 mod conversion;
+#[macro_use]
 mod multi;
 mod string;
 mod table;
 mod userdata;
+#[cfg(feature = "async")]
+mod async_call;
+mod buffer;
+mod bytecode;
+mod channel;
+mod clock;
+mod context;
+mod coverage;
+mod debugger;
+mod events;
+mod globals;
+#[macro_use]
+mod lua_trait;
+mod precompile;
+mod pattern;
+mod persist;
+mod pool;
+mod proxy;
+#[cfg(not(target_arch = "wasm32"))]
+mod safe_os;
+mod source_map;
+mod vfs;
+mod watchdog;
+#[cfg(feature = "log")]
+mod logging;
+#[cfg(feature = "regex")]
+mod regexp;
+#[cfg(feature = "serde_json")]
+mod json;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+#[cfg(any(feature = "toml", feature = "yaml"))]
+mod config;
+#[cfg(feature = "chrono")]
+mod datetime;
+#[cfg(feature = "rand")]
+mod random;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "serde")]
+mod deserialize;
 
 #[cfg(test)]
 mod tests;
 
-pub use error::{Error, ExternalError, ExternalResult, Result};
-pub use types::{Integer, LightUserData, Number};
-pub use multi::Variadic;
-pub use string::String;
-pub use table::{Table, TablePairs, TableSequence};
-pub use userdata::{AnyUserData, MetaMethod, UserData, UserDataMethods};
-pub use lua::{FromLua, FromLuaMulti, Function, Lua, MultiValue, Nil, Thread, ThreadStatus, ToLua,
-              ToLuaMulti, Value};
+pub use error::{Error, ErrorContext, ErrorValue, ExternalError, ExternalResult, Result};
+pub use types::{AccessKind, AllocationSite, Budget, DurationFormat, GcEvent, GlobalAccess, Integer,
+                LightUserData, MemoryProfile, Number, PanicMode, RefStats, RegistryKey,
+                RequireResolver, RequireTrace, ResourceLimits, ResourceReport, TypedLightUserData,
+                WeakLuaRef};
+pub use multi::{Either, IterValues, Kwargs, LuaConvention, Opt, Variadic};
+pub use buffer::Buffer;
+pub use context::Context;
+pub use channel::{ChannelReceiver, ChannelSender};
+pub use coverage::Coverage;
+pub use debugger::{DebugFrame, Debugger, DebugPause, PauseReason};
+pub use events::Events;
+pub use globals::{GlobalsDiff, GlobalsSnapshot, SnapshotValue};
+#[doc(hidden)]
+pub use lua_trait::call_lua_trait_method;
+pub use precompile::{IncludedChunk, precompile_script, PrecompileOptions};
+pub use pool::LuaPool;
+pub use proxy::TableBackend;
+pub use source_map::SourceMap;
+pub use vfs::{DirectoryVfs, MemoryVfs, OpenMode, ReadOnlyVfs, Vfs, VfsFile};
+pub use util::StackGuard;
+pub use watchdog::Watchdog;
+pub use string::{BorrowedBytes, BorrowedStr, String};
+pub use table::{Table, TableIPairs, TablePairs, TableSequence};
+pub use userdata::{AnyUserData, MetaFieldValue, MetaMethod, UserData, UserDataMetatable,
+                   UserDataMethods, UserDataRef, UserDataRefMut};
+pub use lua::{Execution, ExecutionStep, Frame, FromLua, FromLuaMulti, Function, Lua, MultiValue,
+              Nil, SequenceItem, Snapshot, Thread, ThreadStatus, ToLua, ToLuaMulti, TypedFunction,
+              Value, WrappedFunction, Yielder};
+
+#[cfg(feature = "serde")]
+pub use serialize::SerializeOptions;
+#[cfg(feature = "http")]
+pub use http::HttpOptions;
+#[cfg(feature = "async")]
+pub use async_call::{AsyncCall, Awaitable};
+
+#[cfg(feature = "derive")]
+pub use rlua_derive::{lua_methods, FromLua, ToLua, UserData};
 
 pub mod prelude;
@@ -0,0 +1,94 @@
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::c_char;
+
+use log::{self, Level, Record};
+
+use ffi;
+use error::Result;
+use lua::{Function, Lua, MultiValue};
+use table::Table;
+
+// Creates the table of logging functions installed by `Lua::load_log_module`.
+pub(crate) fn create_log_module(lua: &Lua) -> Result<Table<'_>> {
+    let format = lua.globals()
+        .get::<_, Table>("string")?
+        .get::<_, Function>("format")?;
+
+    let module = lua.create_table();
+    for &(name, level) in &[
+        ("trace", Level::Trace),
+        ("debug", Level::Debug),
+        ("info", Level::Info),
+        ("warn", Level::Warn),
+        ("error", Level::Error),
+    ] {
+        let function = lua.create_function(move |lua, (format, mut args): (Function, MultiValue)| {
+            if level > log::max_level() {
+                return Ok(());
+            }
+
+            let message = match args.len() {
+                0 => String::new(),
+                1 => format.call(("%s", args.pop_front().unwrap()))?,
+                _ => format.call(args)?,
+            };
+            let (source, line) = caller_location(lua);
+            let target = match line {
+                Some(line) => format!("{}:{}", source, line),
+                None => source.clone(),
+            };
+
+            log::logger().log(
+                &Record::builder()
+                    .level(level)
+                    .target(&target)
+                    .file(Some(&source))
+                    .line(line)
+                    .args(format_args!("{}", message))
+                    .build(),
+            );
+            Ok(())
+        });
+        module.raw_set(name, function.bind(format.clone())?)?;
+    }
+    Ok(module)
+}
+
+// Returns the name of the chunk a function was loaded from. Unlike the `short_src` field, this
+// does not quote the names given to chunks loaded from strings.
+unsafe fn chunk_name(ar: &ffi::lua_Debug) -> String {
+    let source = CStr::from_ptr(ar.source).to_bytes();
+    match source.first() {
+        Some(&b'=') | Some(&b'@') => String::from_utf8_lossy(&source[1..]).into_owned(),
+        _ if source.len() < ar.short_src.len() && !source.contains(&b'\n') => {
+            String::from_utf8_lossy(source).into_owned()
+        }
+        _ => CStr::from_ptr(ar.short_src.as_ptr())
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+// Returns the source and current line of the innermost Lua function on the call stack of the
+// running callback, skipping C functions such as the callback itself.
+fn caller_location(lua: &Lua) -> (String, Option<u32>) {
+    unsafe {
+        let mut ar: ffi::lua_Debug = mem::zeroed();
+        let mut level = 0;
+        while ffi::lua_getstack(lua.state, level, &mut ar) != 0 {
+            ffi::lua_getinfo(lua.state, cstr!("Sl"), &mut ar);
+            if CStr::from_ptr(ar.what).to_bytes() != b"C" {
+                let source = chunk_name(&ar);
+                let line = if ar.currentline > 0 {
+                    Some(ar.currentline as u32)
+                } else {
+                    None
+                };
+                return (source, line);
+            }
+            level += 1;
+        }
+    }
+    ("lua".to_owned(), None)
+}
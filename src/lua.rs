@@ -1,26 +1,86 @@
-use std::{ptr, str};
+use std::{cmp, fmt, mem, ptr, slice, str};
 use std::ops::{Deref, DerefMut};
 use std::iter::FromIterator;
 use std::cell::RefCell;
-use std::ffi::CString;
-use std::any::TypeId;
+use std::ffi::{CStr, CString};
+use std::any::{type_name, Any, TypeId};
+#[cfg(debug_assertions)]
+use std::backtrace::Backtrace;
 use std::marker::PhantomData;
 use std::collections::{HashMap, VecDeque};
 use std::os::raw::{c_char, c_int, c_void};
+use std::io::{self, Read, Write};
+use std::panic;
+use std::path::Path;
 use std::process;
+use std::rc::Rc;
+use std::result::Result as StdResult;
+use std::string::String as StdString;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::{Duration, Instant, SystemTime};
 
+#[cfg(not(target_arch = "wasm32"))]
 use libc;
 
 use ffi;
 use error::*;
 use util::*;
-use types::{Callback, Integer, LightUserData, LuaRef, Number};
+use context::Context;
+use types::{AccessKind, AllocationSite, Budget, Callback, DurationFormat, ErrorFormatter, GcEvent,
+            GlobalAccess, Integer, LightUserData, LuaRef, MemoryProfile, MethodCallback,
+            MethodSelf, MethodTable, Number, PanicMode, RefIndex, RefStats, RegistryKey,
+            RequireResolver, RequireTrace, ResourceLimits, ResourceReport, WeakLuaRef};
 use string::String;
+use bytecode;
+use channel::{ChannelReceiver, ChannelSender};
+use clock;
+use coverage::{Coverage, CoverageState};
+use debugger::Debugger;
+use globals::{self, GlobalsSnapshot};
+use precompile::{self, IncludedChunk};
+use proxy::{self, TableBackend};
+#[cfg(feature = "async")]
+use async_call::AsyncCall;
+#[cfg(not(target_arch = "wasm32"))]
+use safe_os;
+use pattern;
+use persist;
+use source_map::{self, SourceMap};
 use table::Table;
-use userdata::{AnyUserData, MetaMethod, UserData, UserDataMethods};
+use userdata::{AnyUserData, MetaFieldValue, MetaMethod, UserData, UserDataCallback,
+               UserDataMethods};
+use vfs::{self, Vfs};
+use watchdog::{Watchdog, WatchedState};
+#[cfg(feature = "log")]
+use logging;
+#[cfg(feature = "regex")]
+use regexp;
+#[cfg(feature = "serde_json")]
+use json;
+#[cfg(feature = "msgpack")]
+use msgpack;
+#[cfg(any(feature = "toml", feature = "yaml"))]
+use config;
+#[cfg(feature = "chrono")]
+use datetime;
+#[cfg(feature = "rand")]
+use random;
+#[cfg(feature = "http")]
+use http::{self, HttpOptions};
+#[cfg(feature = "rand")]
+use rand_core::{RngCore, SeedableRng};
+#[cfg(feature = "serde")]
+use serialize::{self, SerializeOptions, SerializeUserData};
+#[cfg(feature = "serde")]
+use deserialize;
 
 /// A dynamically typed Lua value.
-#[derive(Debug, Clone)]
+///
+/// The `Debug` output of tables shows their contents up to a depth of 3, see
+/// [`Value::to_debug_string`].
+///
+/// [`Value::to_debug_string`]: #method.to_debug_string
+#[derive(Clone)]
 pub enum Value<'lua> {
     /// The Lua value `nil`.
     Nil,
@@ -53,7 +113,30 @@ pub enum Value<'lua> {
 pub use self::Value::Nil;
 
 impl<'lua> Value<'lua> {
-    pub(crate) fn type_name(&self) -> &'static str {
+    /// Returns the name of the value's type, as used in the crate's conversion errors.
+    ///
+    /// The names match those returned by Lua's `type` function, except that integers and floats
+    /// are named `"integer"` and `"number"`, and light userdata is named `"light userdata"`. Error
+    /// values are named `"userdata"`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// assert_eq!(lua.eval::<Value>("{}", None)?.type_name(), "table");
+    /// assert_eq!(lua.eval::<Value>("1", None)?.type_name(), "integer");
+    /// assert_eq!(lua.eval::<Value>("1.5", None)?.type_name(), "number");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn type_name(&self) -> &'static str {
         match *self {
             Value::Nil => "nil",
             Value::Boolean(_) => "boolean",
@@ -67,6 +150,474 @@ impl<'lua> Value<'lua> {
             Value::UserData(_) | Value::Error(_) => "userdata",
         }
     }
+
+    /// Returns a pointer identifying the value, if it is a table, function, thread, userdata or
+    /// light userdata, and a null pointer otherwise.
+    ///
+    /// See [`Table::to_pointer`] for details. Pointers can be used as the keys of maps and sets
+    /// which identify Lua objects, for example to visit each object of a structure once.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::collections::HashSet;
+    /// # use rlua::{Lua, Result, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let values = lua.eval::<Vec<Value>>(
+    ///     "local shared = {} return { shared, shared, {}, print, print, 1, 1 }",
+    ///     None,
+    /// )?;
+    ///
+    /// let mut seen = HashSet::new();
+    /// let objects = values
+    ///     .iter()
+    ///     .filter(|value| !value.to_pointer().is_null())
+    ///     .filter(|value| seen.insert(value.to_pointer()))
+    ///     .count();
+    /// assert_eq!(objects, 3);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Table::to_pointer`]: struct.Table.html#method.to_pointer
+    pub fn to_pointer(&self) -> *const c_void {
+        match *self {
+            Value::LightUserData(ud) => ud.0,
+            Value::Table(ref t) => t.to_pointer(),
+            Value::Function(ref f) => f.to_pointer(),
+            Value::Thread(ref t) => t.to_pointer(),
+            Value::UserData(ref ud) => ud.to_pointer(),
+            _ => ptr::null(),
+        }
+    }
+
+    /// Compares the value with `other` for equality, following the semantics of Lua's `==`
+    /// operator.
+    ///
+    /// Integers and floats are equal if they represent the same number, and tables and userdata
+    /// are compared using their `__eq` metamethod, if they are not the same object. Any error
+    /// raised by the metamethod is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let one = Value::Integer(1);
+    /// assert!(one.equals(&lua.eval::<Value>("1.0", None)?)?);
+    ///
+    /// let (a, b) = lua.eval::<(Value, Value)>(r#"
+    ///     local mt = { __eq = function(a, b) return a.id == b.id end }
+    ///     return setmetatable({ id = 1 }, mt), setmetatable({ id = 1 }, mt)
+    /// "#, None)?;
+    /// assert!(a.equals(&b)?);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn equals(&self, other: &Value<'lua>) -> Result<bool> {
+        let lua = match (self.lua(), other.lua()) {
+            (Some(lua), _) | (None, Some(lua)) => lua,
+            (None, None) => {
+                return Ok(match (self, other) {
+                    (&Value::Nil, &Value::Nil) => true,
+                    (&Value::Boolean(a), &Value::Boolean(b)) => a == b,
+                    (&Value::LightUserData(a), &Value::LightUserData(b)) => a == b,
+                    (&Value::Integer(a), &Value::Integer(b)) => a == b,
+                    (&Value::Number(a), &Value::Number(b)) => a == b,
+                    (&Value::Integer(i), &Value::Number(n))
+                    | (&Value::Number(n), &Value::Integer(i)) => {
+                        // Lua compares mixed numbers exactly, as integers.
+                        n.fract() == 0.0 && n >= Integer::MIN as Number
+                            && n < Integer::MAX as Number && n as Integer == i
+                    }
+                    _ => false,
+                })
+            }
+        };
+
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                check_stack(lua.state, 5);
+                lua.push_value(lua.state, self.clone());
+                lua.push_value(lua.state, other.clone());
+                pequal(lua.state)
+            })
+        }
+    }
+
+    /// Makes the value read-only for scripts, if it is a table, along with every table reachable
+    /// from it.
+    ///
+    /// Tables are frozen in place, so that references to them held by scripts or by Rust see the
+    /// change: scripts can still read them, take their length and iterate over them with `pairs`
+    /// and `ipairs`, but assigning to a field, even with `rawset`, raises an error, and their
+    /// metatable cannot be read or replaced. This lets a host share a structure such as a
+    /// configuration between plugins which must not change it. The keys and values of frozen
+    /// tables are frozen as well, as is the table in the `__index` field of their metatable. Other
+    /// fields of their metatable keep working, but are copied, so that later changes to the
+    /// metatable are not seen. Functions, userdata and threads are not changed. Rust can still
+    /// modify frozen tables, but should not, as their contents are no longer stored in the table
+    /// itself: the raw functions `rawget`, `rawlen` and `next` see an empty table.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config = lua.eval::<Value>("{ name = 'server', ports = { 80, 443 } }", None)?;
+    /// config.freeze_deep();
+    /// lua.globals().set("config", config)?;
+    ///
+    /// assert_eq!(lua.eval::<i64>("config.ports[2]", None)?, 443);
+    /// assert!(lua.exec::<()>("config.ports[1] = 8080", None).is_err());
+    /// assert!(lua.exec::<()>("rawset(config, 'name', 'client')", None).is_err());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn freeze_deep(&self) {
+        if let Value::Table(ref table) = *self {
+            let lua = table.0.lua;
+            unsafe {
+                stack_guard(lua.state, 0, || {
+                    check_stack(lua.state, 14);
+                    lua.push_ref(lua.state, &table.0);
+                    freeze_deep(lua.state);
+                })
+            }
+        }
+    }
+
+    /// Converts the value to a string exactly like Lua's `tostring` function, including calling
+    /// the `__tostring` metamethod.
+    ///
+    /// Values which are not references to Lua objects, such as numbers, do not know which Lua
+    /// state they belong to, so the state to use for the conversion is passed as `lua`. Any bytes
+    /// which are not valid UTF-8 are replaced with `U+FFFD`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// assert_eq!(Value::Number(2.0).to_string_lua(&lua)?, "2.0");
+    ///
+    /// let point = lua.eval::<Value>(r#"
+    ///     setmetatable({ x = 1, y = 2 }, {
+    ///         __tostring = function(p) return "(" .. p.x .. ", " .. p.y .. ")" end
+    ///     })
+    /// "#, None)?;
+    /// assert_eq!(point.to_string_lua(&lua)?, "(1, 2)");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn to_string_lua(&self, lua: &'lua Lua) -> Result<StdString> {
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                check_stack(lua.state, 3);
+                lua.push_value(lua.state, self.clone());
+                ptostring(lua.state)?;
+
+                let mut size = 0;
+                let data = ffi::lua_tolstring(lua.state, -1, &mut size);
+                let bytes = slice::from_raw_parts(data as *const u8, size);
+                let string = StdString::from_utf8_lossy(bytes).into_owned();
+                ffi::lua_pop(lua.state, 1);
+                Ok(string)
+            })
+        }
+    }
+
+    /// Renders the value for debugging, expanding the contents of tables up to `max_depth` levels.
+    ///
+    /// Tables nested deeper than `max_depth` are shown as `{...}`, and a table which contains
+    /// itself is shown as `<cycle>` where it recurs. Tables are read with raw accesses, so no
+    /// metamethods are called. The array part of a table is shown first, followed by the other
+    /// entries ordered by their rendering. Functions, threads and userdata are shown by their type
+    /// and address.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let value = lua.eval::<Value>(r#"{ 1, 2, name = "a", nested = { { true } } }"#, None)?;
+    /// assert_eq!(
+    ///     value.to_debug_string(2),
+    ///     r#"{1, 2, name = "a", nested = {{...}}}"#
+    /// );
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn to_debug_string(&self, max_depth: usize) -> StdString {
+        let mut out = StdString::new();
+        self.write_debug(&mut out, max_depth, &mut Vec::new());
+        out
+    }
+
+    // Writes the debug rendering of the value to `out`, `visited` holds the pointers of the tables
+    // which are currently being rendered.
+    fn write_debug(&self, out: &mut StdString, depth: usize, visited: &mut Vec<*const c_void>) {
+        use std::fmt::Write;
+
+        let _ = match *self {
+            Value::Nil => write!(out, "nil"),
+            Value::Boolean(b) => write!(out, "{}", b),
+            Value::LightUserData(ud) => write!(out, "light userdata: {:p}", ud.0),
+            Value::Integer(i) => write!(out, "{}", i),
+            Value::Number(n) => write!(out, "{:?}", n),
+            Value::String(ref s) => write!(out, "{:?}", StdString::from_utf8_lossy(s.as_bytes())),
+            Value::Table(ref t) => {
+                let ptr = t.to_pointer();
+                if visited.contains(&ptr) {
+                    out.push_str("<cycle>");
+                    return;
+                }
+                if depth == 0 {
+                    out.push_str("{...}");
+                    return;
+                }
+                visited.push(ptr);
+
+                let len = t.raw_len();
+                let mut entries = Vec::new();
+                for i in 1..=len {
+                    let mut entry = StdString::new();
+                    match t.raw_get::<_, Value>(i) {
+                        Ok(v) => v.write_debug(&mut entry, depth - 1, visited),
+                        Err(_) => entry.push_str("<error>"),
+                    }
+                    entries.push(entry);
+                }
+
+                let mut fields = Vec::new();
+                for pair in t.clone().pairs::<Value, Value>() {
+                    let (k, v) = match pair {
+                        Ok(pair) => pair,
+                        Err(_) => {
+                            fields.push("<error>".to_owned());
+                            break;
+                        }
+                    };
+                    if let Value::Integer(i) = k {
+                        if i >= 1 && i <= len {
+                            continue;
+                        }
+                    }
+
+                    let mut field = StdString::new();
+                    match k.as_str() {
+                        Some(name) if is_identifier(name) => field.push_str(name),
+                        _ => {
+                            field.push('[');
+                            k.write_debug(&mut field, depth - 1, visited);
+                            field.push(']');
+                        }
+                    }
+                    field.push_str(" = ");
+                    v.write_debug(&mut field, depth - 1, visited);
+                    fields.push(field);
+                }
+                fields.sort();
+                entries.extend(fields);
+
+                visited.pop();
+                write!(out, "{{{}}}", entries.join(", "))
+            }
+            Value::Function(ref f) => write!(out, "function: {:p}", f.to_pointer()),
+            Value::Thread(ref t) => write!(out, "thread: {:p}", t.to_pointer()),
+            Value::UserData(ref ud) => write!(out, "userdata: {:p}", ud.to_pointer()),
+            Value::Error(ref e) => write!(out, "error: {}", e),
+        };
+    }
+
+    // Returns the Lua state the value belongs to, if it is a reference to a Lua object.
+    fn lua(&self) -> Option<&'lua Lua> {
+        match *self {
+            Value::String(ref s) => Some(s.0.lua),
+            Value::Table(ref t) => Some(t.0.lua),
+            Value::Function(ref f) => Some(f.0.lua),
+            Value::Thread(ref t) => Some(t.0.lua),
+            Value::UserData(ref ud) => Some(ud.0.lua),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the value is `nil`.
+    pub fn is_nil(&self) -> bool {
+        matches!(*self, Value::Nil)
+    }
+
+    /// Returns the value as a `bool` if it is a boolean.
+    ///
+    /// Unlike Lua's truthiness rules, this returns `None` for any other value, including `nil`.
+    pub fn as_boolean(&self) -> Option<bool> {
+        match *self {
+            Value::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as an `Integer` if it is an integer.
+    ///
+    /// Floats are not converted, even if they have no fractional part.
+    pub fn as_integer(&self) -> Option<Integer> {
+        match *self {
+            Value::Integer(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a `Number` if it is a float or an integer.
+    pub fn as_number(&self) -> Option<Number> {
+        match *self {
+            Value::Integer(i) => Some(i as Number),
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a `&str` if it is a string which is valid UTF-8.
+    ///
+    /// Numbers are not coerced to strings.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => s.to_str().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns a reference to the string handle if the value is a string.
+    pub fn as_string(&self) -> Option<&String<'lua>> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns a reference to the table handle if the value is a table.
+    pub fn as_table(&self) -> Option<&Table<'lua>> {
+        match *self {
+            Value::Table(ref t) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// Returns a reference to the function handle if the value is a function.
+    pub fn as_function(&self) -> Option<&Function<'lua>> {
+        match *self {
+            Value::Function(ref f) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// Returns a reference to the thread handle if the value is a thread.
+    pub fn as_thread(&self) -> Option<&Thread<'lua>> {
+        match *self {
+            Value::Thread(ref t) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// Returns a reference to the userdata handle if the value is a userdata object.
+    pub fn as_userdata(&self) -> Option<&AnyUserData<'lua>> {
+        match *self {
+            Value::UserData(ref ud) => Some(ud),
+            _ => None,
+        }
+    }
+
+    /// Encodes the value as MessagePack, which can be decoded with [`Lua::value_from_msgpack`].
+    ///
+    /// `nil` and the null light userdata become nil, and strings become MessagePack strings if
+    /// they are valid UTF-8 and binary data otherwise. A table with only the keys `1..n` becomes an
+    /// array, and any other table, including an empty one, becomes a map. Functions, threads,
+    /// userdata, recursive tables and tables nested more than 128 levels deep cannot be encoded.
+    ///
+    /// Requires the `msgpack` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table, Value};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let value = lua.eval::<Value>("{ name = 'probe', readings = { 1, 2.5 } }", None)?;
+    /// let bytes = value.to_msgpack()?;
+    ///
+    /// let decoded = lua.value_from_msgpack(&bytes)?;
+    /// let table = decoded.as_table().unwrap();
+    /// assert_eq!(table.get::<_, String>("name")?, "probe");
+    /// assert_eq!(table.get::<_, Table>("readings")?.get::<_, f64>(2)?, 2.5);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Lua::value_from_msgpack`]: struct.Lua.html#method.value_from_msgpack
+    #[cfg(feature = "msgpack")]
+    pub fn to_msgpack(&self) -> Result<Vec<u8>> {
+        msgpack::encode(self)
+    }
+}
+
+impl<'lua> fmt::Debug for Value<'lua> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Nil => write!(f, "Nil"),
+            Value::Boolean(b) => write!(f, "Boolean({})", b),
+            Value::LightUserData(ud) => write!(f, "LightUserData({:p})", ud.0),
+            Value::Integer(i) => write!(f, "Integer({})", i),
+            Value::Number(n) => write!(f, "Number({:?})", n),
+            Value::String(_) => write!(f, "String({})", self.to_debug_string(0)),
+            Value::Table(_) => write!(f, "Table({})", self.to_debug_string(DEBUG_DEPTH)),
+            Value::Function(_) => write!(f, "Function({})", self.to_debug_string(0)),
+            Value::Thread(_) => write!(f, "Thread({})", self.to_debug_string(0)),
+            Value::UserData(_) => write!(f, "UserData({})", self.to_debug_string(0)),
+            Value::Error(ref e) => write!(f, "Error({:?})", e),
+        }
+    }
+}
+
+// Returns whether `name` can be written as a table key without brackets.
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
 
 /// Trait for types convertible to `Value`.
@@ -79,9 +630,22 @@ pub trait ToLua<'lua> {
 pub trait FromLua<'lua>: Sized {
     /// Performs the conversion.
     fn from_lua(lua_value: Value<'lua>, lua: &'lua Lua) -> Result<Self>;
+
+    /// Performs the conversion for a missing value, such as an omitted trailing argument.
+    ///
+    /// By default, missing values are converted like `nil`. See [`Opt`] for a type which tells
+    /// them apart.
+    ///
+    /// [`Opt`]: struct.Opt.html
+    fn from_lua_missing(lua: &'lua Lua) -> Result<Self> {
+        Self::from_lua(Nil, lua)
+    }
 }
 
 /// Multiple Lua values used for both argument passing and also for multiple return values.
+///
+/// The buffers of the `MultiValue`s used to pass arguments to and results from Rust callbacks are
+/// kept in a small pool and reused, so calling a callback does not allocate for them.
 #[derive(Debug, Clone)]
 pub struct MultiValue<'lua>(VecDeque<Value<'lua>>);
 
@@ -90,6 +654,33 @@ impl<'lua> MultiValue<'lua> {
     pub fn new() -> MultiValue<'lua> {
         MultiValue(VecDeque::new())
     }
+
+    /// Creates an empty `MultiValue` with space for at least `capacity` values.
+    pub fn with_capacity(capacity: usize) -> MultiValue<'lua> {
+        MultiValue(VecDeque::with_capacity(capacity))
+    }
+
+    /// Creates a `MultiValue` from a vector of values.
+    pub fn from_vec(values: Vec<Value<'lua>>) -> MultiValue<'lua> {
+        MultiValue(VecDeque::from(values))
+    }
+
+    /// Converts this `MultiValue` into a vector of its values.
+    pub fn into_vec(self) -> Vec<Value<'lua>> {
+        Vec::from(self.0)
+    }
+
+    /// Returns a reference to the value at position `index`, or `None` if there are not enough
+    /// values.
+    pub fn get(&self, index: usize) -> Option<&Value<'lua>> {
+        self.0.get(index)
+    }
+}
+
+impl<'lua> Default for MultiValue<'lua> {
+    fn default() -> MultiValue<'lua> {
+        MultiValue::new()
+    }
 }
 
 impl<'lua> FromIterator<Value<'lua>> for MultiValue<'lua> {
@@ -142,11 +733,55 @@ pub trait FromLuaMulti<'lua>: Sized {
     /// assigning values. Similarly, if not enough values are given, conversions should assume that
     /// any missing values are nil.
     fn from_lua_multi(values: MultiValue<'lua>, lua: &'lua Lua) -> Result<Self>;
+
+    /// Performs the conversion for the arguments of a Rust callback, where the first value is
+    /// argument number `first_arg`, counting from 1.
+    ///
+    /// Conversion errors of individual arguments should be reported as [`Error::BadArgument`], so
+    /// that scripts are told which argument was wrong. By default, this is the same as
+    /// `from_lua_multi`.
+    ///
+    /// [`Error::BadArgument`]: enum.Error.html#variant.BadArgument
+    fn from_lua_args(values: MultiValue<'lua>, first_arg: usize, lua: &'lua Lua) -> Result<Self> {
+        let _ = first_arg;
+        Self::from_lua_multi(values, lua)
+    }
+}
+
+/// Trait for the types of slices which [`Lua::create_sequence_from_slice`] copies into a table
+/// directly, without converting each element to a [`Value`] first.
+///
+/// Implemented for `f64`, `i64` and `&str`.
+///
+/// [`Lua::create_sequence_from_slice`]: struct.Lua.html#method.create_sequence_from_slice
+/// [`Value`]: enum.Value.html
+pub trait SequenceItem {
+    // Pushes the element onto the stack of `state`, which must have space for it.
+    #[doc(hidden)]
+    unsafe fn push_item(&self, state: *mut ffi::lua_State);
+}
+
+impl SequenceItem for Number {
+    unsafe fn push_item(&self, state: *mut ffi::lua_State) {
+        ffi::lua_pushnumber(state, *self);
+    }
+}
+
+impl SequenceItem for Integer {
+    unsafe fn push_item(&self, state: *mut ffi::lua_State) {
+        ffi::lua_pushinteger(state, *self);
+    }
+}
+
+impl SequenceItem for &str {
+    unsafe fn push_item(&self, state: *mut ffi::lua_State) {
+        ffi::lua_pushlstring(state, self.as_ptr() as *const c_char, self.len());
+    }
 }
 
 /// Handle to an internal Lua function.
 #[derive(Clone, Debug)]
-pub struct Function<'lua>(LuaRef<'lua>);
+pub struct Function<'lua>(pub(crate) LuaRef<'lua>);
 
 impl<'lua> Function<'lua> {
     /// Calls the function, passing `args` as function arguments.
@@ -198,24 +833,287 @@ impl<'lua> Function<'lua> {
     /// # }
     /// ```
     pub fn call<A: ToLuaMulti<'lua>, R: FromLuaMulti<'lua>>(&self, args: A) -> Result<R> {
-        let lua = self.0.lua;
-        unsafe {
-            stack_err_guard(lua.state, 0, || {
-                let args = args.to_lua_multi(lua)?;
-                let nargs = args.len() as c_int;
-                check_stack(lua.state, nargs + 3);
+        self.call_impl(args, true)
+    }
 
-                let stack_start = ffi::lua_gettop(lua.state);
-                lua.push_ref(lua.state, &self.0);
-                for arg in args {
-                    lua.push_value(lua.state, arg);
-                }
+    /// Calls the function like [`call`], but without collecting a traceback on error.
+    ///
+    /// Every call made through [`call`] installs a message handler which builds a Lua traceback
+    /// when an error occurs. This method skips that work, which lowers the overhead of calls made
+    /// in hot loops. Errors are still caught: a Lua error becomes an [`Error::RuntimeError`]
+    /// holding only the error message, and an error returned by a Rust callback is passed through
+    /// as-is instead of being wrapped in an [`Error::CallbackError`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Function, Error, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let check: Function = lua.eval(r#"
+    ///     function(n)
+    ///         if n < 0 then error("negative", 0) end
+    ///         return n * 2
+    ///     end
+    /// "#, None)?;
+    ///
+    /// let mut total = 0;
+    /// for i in 0..100 {
+    ///     total += check.call_without_traceback::<_, i64>(i)?;
+    /// }
+    /// assert_eq!(total, 9900);
+    ///
+    /// match check.call_without_traceback::<_, i64>(-1) {
+    ///     Err(Error::RuntimeError(msg)) => assert_eq!(msg, "negative"),
+    ///     r => panic!("unexpected result {:?}", r),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`call`]: #method.call
+    /// [`Error::RuntimeError`]: enum.Error.html#variant.RuntimeError
+    /// [`Error::CallbackError`]: enum.Error.html#variant.CallbackError
+    pub fn call_without_traceback<A, R>(&self, args: A) -> Result<R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        self.call_impl(args, false)
+    }
+
+    /// Calls the function like [`call`], and measures the resources it uses.
+    ///
+    /// The [`ResourceReport`] is returned alongside the result, also when the call fails, so that
+    /// hosts can account for every invocation of a script. Instructions and calls are measured on
+    /// the main thread and on coroutines created during the call.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Function, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let fib: Function = lua.eval(r#"
+    ///     function fib(n)
+    ///         if n < 2 then return n end
+    ///         return fib(n - 1) + fib(n - 2)
+    ///     end
+    ///     return fib
+    /// "#, None)?;
+    ///
+    /// let (result, report) = fib.call_with_report::<_, u32>(20);
+    /// assert_eq!(result?, 6765);
+    /// assert!(report.instructions > 10_000);
+    /// assert_eq!(report.peak_stack_depth, 20);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`call`]: #method.call
+    /// [`ResourceReport`]: struct.ResourceReport.html
+    pub fn call_with_report<A, R>(&self, args: A) -> (Result<R>, ResourceReport)
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let scope = ReportScope::begin(self.0.lua);
+        let result = self.call(args);
+        (result, scope.finish())
+    }
+
+    fn call_impl<A, R>(&self, args: A, traceback: bool) -> Result<R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let lua = self.0.lua;
+        let mut args = args.to_lua_multi(lua)?;
+        let nargs = args.len() as c_int;
+        let _execution = ExecutionScope::begin(lua);
+        unsafe {
+            let sg = StackGuard::with_reserved(lua.state, nargs + 3);
+            lua.push_ref(lua.state, &self.0);
+            for arg in args.drain(..) {
+                lua.push_value(lua.state, arg);
+            }
+            lua.cache_multi_value(args);
+            let ret = if traceback {
+                pcall_with_traceback(lua.state, nargs, ffi::LUA_MULTRET)
+            } else {
+                limited_pcall(lua.state, nargs, ffi::LUA_MULTRET, 0)
+            };
+            handle_error(lua.state, ret)?;
+            let nresults = ffi::lua_gettop(lua.state) - sg.top();
+            let mut results = lua.new_multi_value();
+            check_stack(lua.state, 1);
+            for _ in 0..nresults {
+                results.push_front(lua.pop_value(lua.state));
+            }
+            R::from_lua_multi(results, lua)
+        }
+    }
+
+    /// Calls the function in protected mode, returning the raw Lua error value on failure.
+    ///
+    /// This is similar to Lua's `pcall`. Where [`call`] turns any error raised by the function
+    /// into an [`Error`], this method returns the value passed to `error` as-is, so that scripts
+    /// raising structured errors such as `error({ code = 404 })` can be inspected from Rust.
+    ///
+    /// The outer `Result` reports errors that happen outside of the called function, such as a
+    /// failure to convert the arguments or return values. An error raised by a Rust callback is
+    /// returned as a `Value::Error`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Function, Value, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let fetch: Function = lua.eval(r#"
+    ///     function()
+    ///         error({ code = 404, msg = "not found" })
+    ///     end
+    /// "#, None)?;
+    ///
+    /// match fetch.pcall::<_, ()>(())? {
+    ///     Err(Value::Table(err)) => assert_eq!(err.get::<_, i64>("code")?, 404),
+    ///     r => panic!("unexpected result {:?}", r),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`call`]: #method.call
+    /// [`Error`]: enum.Error.html
+    pub fn pcall<A, R>(&self, args: A) -> Result<StdResult<R, Value<'lua>>>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let lua = self.0.lua;
+        let _execution = ExecutionScope::begin(lua);
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                let mut args = args.to_lua_multi(lua)?;
+                let nargs = args.len() as c_int;
+                check_stack(lua.state, nargs + 2);
+
+                let stack_start = ffi::lua_gettop(lua.state);
+                lua.push_ref(lua.state, &self.0);
+                for arg in args.drain(..) {
+                    lua.push_value(lua.state, arg);
+                }
+                lua.cache_multi_value(args);
+                let ret = limited_pcall(lua.state, nargs, ffi::LUA_MULTRET, 0);
+                if ret != ffi::LUA_OK {
+                    if (ret != ffi::LUA_ERRRUN && ret != ffi::LUA_ERRERR && ret != ffi::LUA_ERRMEM)
+                        || is_wrapped_panic(lua.state, -1)
+                    {
+                        // Panics must be resumed.
+                        handle_error(lua.state, ret)?;
+                    }
+                    return Ok(Err(lua.pop_value(lua.state)));
+                }
+                let nresults = ffi::lua_gettop(lua.state) - stack_start;
+                let mut results = lua.new_multi_value();
+                check_stack(lua.state, 1);
+                for _ in 0..nresults {
+                    results.push_front(lua.pop_value(lua.state));
+                }
+                R::from_lua_multi(results, lua).map(Ok)
+            })
+        }
+    }
+
+    /// Calls the function with a custom message handler, like Lua's `xpcall`.
+    ///
+    /// If an error occurs, `handler` is called with the error value before the stack is unwound,
+    /// so it can still inspect the failing call stack (for example to build a custom traceback).
+    /// Whatever the handler returns becomes the error value, which is then converted into an
+    /// [`Error`] as usual: a returned `Error` value is passed through unchanged, and any other
+    /// value results in a `RuntimeError`.
+    ///
+    /// Unlike [`call`], errors are not automatically augmented with a traceback, and errors
+    /// returned by Rust callbacks are not wrapped in `CallbackError`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Function, Error, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let fail: Function = lua.eval(r#"function() error("oops", 0) end"#, None)?;
+    /// let handler: Function = lua.eval(r#"function(e) return "handled: " .. e end"#, None)?;
+    ///
+    /// match fail.call_with_handler::<_, ()>(handler, ()) {
+    ///     Err(Error::RuntimeError(msg)) => assert_eq!(msg, "handled: oops"),
+    ///     r => panic!("unexpected result {:?}", r),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Error`]: enum.Error.html
+    /// [`call`]: #method.call
+    pub fn call_with_handler<A, R>(&self, handler: Function<'lua>, args: A) -> Result<R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        // Calls the user provided handler (upvalue 1), but never lets it see a wrapped panic.
+        unsafe extern "C" fn message_handler(state: *mut ffi::lua_State) -> c_int {
+            if !is_wrapped_panic(state, -1) {
+                ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+                ffi::lua_insert(state, -2);
+                ffi::lua_call(state, 1, 1);
+            }
+            1
+        }
+
+        let lua = self.0.lua;
+        let _execution = ExecutionScope::begin(lua);
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                let mut args = args.to_lua_multi(lua)?;
+                let nargs = args.len() as c_int;
+                check_stack(lua.state, nargs + 3);
+
+                let stack_start = ffi::lua_gettop(lua.state);
+                lua.push_ref(lua.state, &handler.0);
+                ffi::lua_pushcclosure(lua.state, message_handler, 1);
+                lua.push_ref(lua.state, &self.0);
+                for arg in args.drain(..) {
+                    lua.push_value(lua.state, arg);
+                }
+                lua.cache_multi_value(args);
                 handle_error(
                     lua.state,
-                    pcall_with_traceback(lua.state, nargs, ffi::LUA_MULTRET),
+                    limited_pcall(lua.state, nargs, ffi::LUA_MULTRET, stack_start + 1),
                 )?;
+                ffi::lua_remove(lua.state, stack_start + 1);
                 let nresults = ffi::lua_gettop(lua.state) - stack_start;
-                let mut results = MultiValue::new();
+                let mut results = lua.new_multi_value();
                 check_stack(lua.state, 1);
                 for _ in 0..nresults {
                     results.push_front(lua.pop_value(lua.state));
@@ -225,6 +1123,27 @@ impl<'lua> Function<'lua> {
         }
     }
 
+    /// Returns a future which calls the function in a new thread, awaiting the [`Awaitable`]
+    /// futures returned by the Rust callbacks it calls.
+    ///
+    /// Each time a callback returns an `Awaitable`, the thread is suspended until the future is
+    /// ready, and polling the returned future polls the future of the callback. A script which
+    /// yields by itself with `coroutine.yield` also suspends the call, which is resumed the next
+    /// time it is polled. The future outputs the results of the function, or the error it raised.
+    ///
+    /// Requires the `async` feature.
+    ///
+    /// [`Awaitable`]: struct.Awaitable.html
+    #[cfg(feature = "async")]
+    pub fn call_async<A, R>(&self, args: A) -> AsyncCall<'lua, R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let lua = self.0.lua;
+        AsyncCall::new(lua.create_thread(self.clone()), args.to_lua_multi(lua))
+    }
+
     /// Returns a function that, when called, calls `self`, passing `args` as the first set of
     /// arguments.
     ///
@@ -280,15 +1199,16 @@ impl<'lua> Function<'lua> {
         let lua = self.0.lua;
         unsafe {
             stack_err_guard(lua.state, 0, || {
-                let args = args.to_lua_multi(lua)?;
+                let mut args = args.to_lua_multi(lua)?;
                 let nargs = args.len() as c_int;
 
                 check_stack(lua.state, nargs + 3);
                 lua.push_ref(lua.state, &self.0);
                 ffi::lua_pushinteger(lua.state, nargs as ffi::lua_Integer);
-                for arg in args {
+                for arg in args.drain(..) {
                     lua.push_value(lua.state, arg);
                 }
+                lua.cache_multi_value(args);
 
                 ffi::lua_pushcclosure(lua.state, bind_call_impl, nargs + 2);
 
@@ -296,108 +1216,466 @@ impl<'lua> Function<'lua> {
             })
         }
     }
-}
 
-/// Status of a Lua thread (or coroutine).
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum ThreadStatus {
-    /// The thread was just created, or is suspended because it has called `coroutine.yield`.
+    /// Wraps a Rust function or closure so that it can be passed to Lua directly.
     ///
-    /// If a thread is in this state, it can be resumed by calling [`Thread::resume`].
+    /// The returned [`WrappedFunction`] implements [`ToLua`], and is turned into a Lua function
+    /// (as if by [`Lua::create_function`]) when it is converted. This removes the need to create a
+    /// function handle before storing a callback in a table or passing it as an argument.
     ///
-    /// [`Thread::resume`]: struct.Thread.html#method.resume
-    Resumable,
-    /// Either the thread has finished executing, or the thread is currently running.
-    Unresumable,
-    /// The thread has raised a Lua error during execution.
-    Error,
-}
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Function, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let api = lua.create_table();
+    ///
+    /// api.set("double", Function::wrap(|_, x: i64| Ok(x * 2)))?;
+    /// lua.globals().set("api", api)?;
+    ///
+    /// assert_eq!(lua.eval::<i64>("api.double(21)", None)?, 42);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`WrappedFunction`]: struct.WrappedFunction.html
+    /// [`ToLua`]: trait.ToLua.html
+    /// [`Lua::create_function`]: struct.Lua.html#method.create_function
+    pub fn wrap<A, R, F>(func: F) -> WrappedFunction<A, R, F>
+    where
+        A: FromLuaMulti<'lua>,
+        R: ToLuaMulti<'lua>,
+        F: 'static + FnMut(&'lua Lua, A) -> Result<R>,
+    {
+        WrappedFunction {
+            func,
+            _phantom: PhantomData,
+        }
+    }
 
-/// Handle to an internal Lua thread (or coroutine).
-#[derive(Clone, Debug)]
-pub struct Thread<'lua>(LuaRef<'lua>);
+    /// Creates a Lua function from a C function written against the Lua C API.
+    ///
+    /// This lets functions from existing C modules, or written by hand with the [`ffi`] bindings,
+    /// be used like any other function. The C function is called in protected mode, so it may
+    /// raise errors with `lua_error` as usual.
+    ///
+    /// # Safety
+    ///
+    /// `func` must follow the rules of the Lua C API. In particular, it must check that the stack
+    /// has room before pushing more than `LUA_MINSTACK` values, it must not unwind, and it must
+    /// not remove values from the registry that it did not create.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::os::raw::c_int;
+    /// # use rlua::{ffi, Function, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// unsafe extern "C" fn double(state: *mut ffi::lua_State) -> c_int {
+    ///     let n = ffi::lua_tointeger(state, 1);
+    ///     ffi::lua_pushinteger(state, n * 2);
+    ///     1
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let double = unsafe { Function::from_c_function(&lua, double) };
+    /// assert_eq!(double.call::<_, i64>(21)?, 42);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`ffi`]: ffi/index.html
+    pub unsafe fn from_c_function(lua: &'lua Lua, func: ffi::lua_CFunction) -> Function<'lua> {
+        let _sg = StackGuard::with_reserved(lua.state, 1);
+        ffi::lua_pushcfunction(lua.state, func);
+        Function(lua.pop_ref(lua.state))
+    }
 
-impl<'lua> Thread<'lua> {
-    /// Resumes execution of this thread.
+    /// Returns the precompiled bytecode of the function, like `string.dump`.
     ///
-    /// Equivalent to `coroutine.resume`.
+    /// If `strip` is true, debug information such as line numbers and the names of local
+    /// variables is left out. See [`dump_to`] for details.
     ///
-    /// Passes `args` as arguments to the thread. If the coroutine has called `coroutine.yield`, it
-    /// will return these arguments. Otherwise, the coroutine wasn't yet started, so the arguments
-    /// are passed to its main function.
+    /// [`dump_to`]: #method.dump_to
+    pub fn dump(&self, strip: bool) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.dump_to(&mut bytes, strip)?;
+        Ok(bytes)
+    }
+
+    /// Writes the precompiled bytecode of the function to `writer`, like `string.dump`.
     ///
-    /// If the thread is no longer in `Active` state (meaning it has finished execution or
-    /// encountered an error), this will return `Err(CoroutineInactive)`, otherwise will return `Ok`
-    /// as follows:
+    /// Lua writes the bytecode in many small pieces, so `writer` should be buffered if writes are
+    /// expensive. If `strip` is true, debug information such as line numbers and the names of
+    /// local variables is left out. Only functions written in Lua can be dumped, and the upvalues
+    /// of the function are not saved.
     ///
-    /// If the thread calls `coroutine.yield`, returns the values passed to `yield`. If the thread
-    /// `return`s values from its main function, returns those.
+    /// The bytecode can be loaded again with Lua's `load` function. Lua does not verify
+    /// bytecode, so loading bytecode which was not created by this version of Lua is unsafe.
     ///
     /// # Examples
     ///
     /// ```
     /// # extern crate rlua;
-    /// # use rlua::{Lua, Thread, Error, Result};
+    /// # use std::env;
+    /// # use std::fs::File;
+    /// # use std::io::{BufWriter, Write};
+    /// # use rlua::{Error, Function, Lua, Result};
     /// # fn try_main() -> Result<()> {
     /// let lua = Lua::new();
-    /// let thread: Thread = lua.eval(r#"
-    ///     coroutine.create(function(arg)
-    ///         assert(arg == 42)
-    ///         local yieldarg = coroutine.yield(123)
-    ///         assert(yieldarg == 43)
-    ///         return 987
-    ///     end)
-    /// "#, None).unwrap();
+    /// let add = lua.eval::<Function>("function(a, b) return a + b end", None)?;
     ///
-    /// assert_eq!(thread.resume::<_, u32>(42).unwrap(), 123);
-    /// assert_eq!(thread.resume::<_, u32>(43).unwrap(), 987);
+    /// let path = env::temp_dir().join("rlua_dump_to_example.luac");
+    /// let mut writer = BufWriter::new(File::create(&path).map_err(Error::external)?);
+    /// add.dump_to(&mut writer, true)?;
+    /// writer.flush().map_err(Error::external)?;
     ///
-    /// // The coroutine has now returned, so `resume` will fail
-    /// match thread.resume::<_, u32>(()) {
-    ///     Err(Error::CoroutineInactive) => {},
-    ///     unexpected => panic!("unexpected result {:?}", unexpected),
-    /// }
+    /// let loadfile = lua.globals().get::<_, Function>("loadfile")?;
+    /// let loaded = loadfile.call::<_, Function>(path.to_str().unwrap())?;
+    /// assert_eq!(loaded.call::<_, i64>((1, 2))?, 3);
     /// # Ok(())
     /// # }
     /// # fn main() {
     /// #     try_main().unwrap();
     /// # }
     /// ```
-    pub fn resume<A, R>(&self, args: A) -> Result<R>
-    where
-        A: ToLuaMulti<'lua>,
-        R: FromLuaMulti<'lua>,
-    {
-        let lua = self.0.lua;
-        unsafe {
-            stack_err_guard(lua.state, 0, || {
-                check_stack(lua.state, 1);
-
-                lua.push_ref(lua.state, &self.0);
-                let thread_state = ffi::lua_tothread(lua.state, -1);
+    pub fn dump_to<W: Write>(&self, writer: &mut W, strip: bool) -> Result<()> {
+        struct DumpState<'a> {
+            writer: &'a mut dyn Write,
+            error: Option<io::Error>,
+            panic: Option<Box<dyn Any + Send>>,
+        }
+
+        unsafe extern "C" fn write_chunk(
+            _: *mut ffi::lua_State,
+            p: *const c_void,
+            sz: usize,
+            ud: *mut c_void,
+        ) -> c_int {
+            let state = &mut *(ud as *mut DumpState);
+            let bytes = slice::from_raw_parts(p as *const u8, sz);
+            // Panics must not unwind through `lua_dump`, they are resumed once it returns.
+            match panic::catch_unwind(panic::AssertUnwindSafe(|| state.writer.write_all(bytes))) {
+                Ok(Ok(())) => 0,
+                Ok(Err(err)) => {
+                    state.error = Some(err);
+                    1
+                }
+                Err(payload) => {
+                    state.panic = Some(payload);
+                    1
+                }
+            }
+        }
+
+        let lua = self.0.lua;
+        let mut state = DumpState {
+            writer,
+            error: None,
+            panic: None,
+        };
+        let status = unsafe {
+            let _sg = StackGuard::with_reserved(lua.state, 1);
+            lua.push_ref(lua.state, &self.0);
+            ffi::lua_dump(
+                lua.state,
+                write_chunk,
+                &mut state as *mut DumpState as *mut c_void,
+                strip as c_int,
+            )
+        };
+
+        if let Some(payload) = state.panic {
+            panic::resume_unwind(payload);
+        }
+        if let Some(err) = state.error {
+            return Err(Error::external(err));
+        }
+        if status != 0 {
+            return Err(Error::RuntimeError("unable to dump given function".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Returns a listing of the bytecode instructions of the function and of the functions it
+    /// defines, in the format of `luac -l`.
+    ///
+    /// This is meant for tooling which shows users what their functions compile to. Each
+    /// instruction is listed with its line, its operands and a comment describing the constants,
+    /// upvalues or jump targets it refers to. Only functions written in Lua can be listed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Function, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let greet = lua.eval::<Function>(r#"function(name) print("hello " .. name) end"#, None)?;
+    ///
+    /// let listing = greet.bytecode_listing()?;
+    /// assert!(listing.contains("GETTABUP \t1 0 -1\t; _ENV \"print\""));
+    /// assert!(listing.contains("CONCAT"));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn bytecode_listing(&self) -> Result<StdString> {
+        bytecode::listing(&self.dump(false)?)
+    }
+
+    /// Returns a pointer identifying the function.
+    ///
+    /// The pointer is the same for every handle to the same Lua function, so it can be used as a
+    /// key to recognize the function without holding a reference to it. It cannot be dereferenced,
+    /// and may be reused for a different value once the function has been garbage collected.
+    pub fn to_pointer(&self) -> *const c_void {
+        self.0.to_pointer()
+    }
+
+    /// Creates a weak reference to the function, which does not keep it from being garbage
+    /// collected.
+    pub fn downgrade(&self) -> WeakLuaRef<'lua, Function<'lua>> {
+        WeakLuaRef::new(&self.0)
+    }
+}
+
+/// A Rust function or closure that becomes a Lua function when converted with [`ToLua`].
+///
+/// This struct is created by the [`Function::wrap`] method.
+///
+/// [`ToLua`]: trait.ToLua.html
+/// [`Function::wrap`]: struct.Function.html#method.wrap
+pub struct WrappedFunction<A, R, F> {
+    pub(crate) func: F,
+    _phantom: PhantomData<(A, R)>,
+}
+
+/// Handle to a Lua function with fixed argument and return types.
+///
+/// Converting a Lua value into a `TypedFunction` checks that the value is a function, and
+/// subsequent calls always convert `A` to Lua and the results back to `R`. This is convenient for
+/// functions that are stored and called repeatedly from Rust, as the types only have to be named
+/// once.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, TypedFunction, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// lua.exec::<()>("function add(a, b) return a + b end", None)?;
+///
+/// let add: TypedFunction<(i64, i64), i64> = lua.globals().get("add")?;
+/// assert_eq!(add.call((1, 2))?, 3);
+/// assert_eq!(add.call((3, 4))?, 7);
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+pub struct TypedFunction<'lua, A, R> {
+    function: Function<'lua>,
+    _phantom: PhantomData<(A, R)>,
+}
+
+impl<'lua, A, R> TypedFunction<'lua, A, R>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+{
+    /// Wraps a `Function`, fixing its argument and return types.
+    pub fn new(function: Function<'lua>) -> TypedFunction<'lua, A, R> {
+        TypedFunction {
+            function,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Calls the function, passing `args` as function arguments.
+    ///
+    /// Refer to [`Function::call`] for details.
+    ///
+    /// [`Function::call`]: struct.Function.html#method.call
+    pub fn call(&self, args: A) -> Result<R> {
+        self.function.call(args)
+    }
+
+    /// Returns the underlying untyped function handle.
+    pub fn into_function(self) -> Function<'lua> {
+        self.function
+    }
+}
+
+impl<'lua, A, R> Clone for TypedFunction<'lua, A, R> {
+    fn clone(&self) -> Self {
+        TypedFunction {
+            function: self.function.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'lua, A, R> fmt::Debug for TypedFunction<'lua, A, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("TypedFunction").field(&self.function).finish()
+    }
+}
+
+/// Status of a Lua thread (or coroutine).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ThreadStatus {
+    /// The thread was just created, or is suspended because it has called `coroutine.yield`.
+    ///
+    /// If a thread is in this state, it can be resumed by calling [`Thread::resume`].
+    ///
+    /// [`Thread::resume`]: struct.Thread.html#method.resume
+    Resumable,
+    /// The thread is currently running, either executing itself or resuming another thread.
+    Running,
+    /// The thread has finished executing.
+    Finished,
+    /// The thread has raised a Lua error during execution.
+    Error,
+}
+
+/// A single entry of the call stack of a thread, as returned by [`Thread::traceback`] and
+/// [`Error::frames`].
+///
+/// [`Thread::traceback`]: struct.Thread.html#method.traceback
+/// [`Error::frames`]: enum.Error.html#method.frames
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// A printable version of the source of the function, such as a chunk name or `[C]`.
+    pub source: StdString,
+    /// The line currently executing, if known.
+    pub line: Option<u32>,
+    /// The name of the function, if a reasonable name could be found.
+    pub name: Option<StdString>,
+}
+
+// Describes the function of an activation record returned by `lua_getstack`.
+pub(crate) unsafe fn stack_frame(state: *mut ffi::lua_State, ar: &mut ffi::lua_Debug) -> Frame {
+    ffi::lua_getinfo(state, cstr!("Sln"), ar);
+    Frame {
+        source: CStr::from_ptr(ar.short_src.as_ptr())
+            .to_string_lossy()
+            .into_owned(),
+        line: if ar.currentline > 0 {
+            Some(ar.currentline as u32)
+        } else {
+            None
+        },
+        name: if ar.name.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(ar.name).to_string_lossy().into_owned())
+        },
+    }
+}
+
+/// Handle to an internal Lua thread (or coroutine).
+#[derive(Clone, Debug)]
+pub struct Thread<'lua>(pub(crate) LuaRef<'lua>);
+
+impl<'lua> Thread<'lua> {
+    /// Resumes execution of this thread.
+    ///
+    /// Equivalent to `coroutine.resume`.
+    ///
+    /// Passes `args` as arguments to the thread. If the coroutine has called `coroutine.yield`, it
+    /// will return these arguments. Otherwise, the coroutine wasn't yet started, so the arguments
+    /// are passed to its main function.
+    ///
+    /// If the thread is no longer in `Active` state (meaning it has finished execution or
+    /// encountered an error), this will return `Err(CoroutineInactive)`, otherwise will return `Ok`
+    /// as follows:
+    ///
+    /// If the thread calls `coroutine.yield`, returns the values passed to `yield`. If the thread
+    /// `return`s values from its main function, returns those.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Thread, Error, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let thread: Thread = lua.eval(r#"
+    ///     coroutine.create(function(arg)
+    ///         assert(arg == 42)
+    ///         local yieldarg = coroutine.yield(123)
+    ///         assert(yieldarg == 43)
+    ///         return 987
+    ///     end)
+    /// "#, None).unwrap();
+    ///
+    /// assert_eq!(thread.resume::<_, u32>(42).unwrap(), 123);
+    /// assert_eq!(thread.resume::<_, u32>(43).unwrap(), 987);
+    ///
+    /// // The coroutine has now returned, so `resume` will fail
+    /// match thread.resume::<_, u32>(()) {
+    ///     Err(Error::CoroutineInactive) => {},
+    ///     unexpected => panic!("unexpected result {:?}", unexpected),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn resume<A, R>(&self, args: A) -> Result<R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        let lua = self.0.lua;
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                check_stack(lua.state, 1);
+
+                lua.push_ref(lua.state, &self.0);
+                let thread_state = ffi::lua_tothread(lua.state, -1);
 
                 let status = ffi::lua_status(thread_state);
-                if status != ffi::LUA_YIELD && ffi::lua_gettop(thread_state) == 0 {
+                if (status != ffi::LUA_YIELD && ffi::lua_gettop(thread_state) == 0)
+                    || thread_state == main_state(thread_state)
+                {
                     return Err(Error::CoroutineInactive);
                 }
 
                 ffi::lua_pop(lua.state, 1);
 
-                let args = args.to_lua_multi(lua)?;
+                let mut args = args.to_lua_multi(lua)?;
                 let nargs = args.len() as c_int;
                 check_stack(thread_state, nargs);
 
-                for arg in args {
+                for arg in args.drain(..) {
                     lua.push_value(thread_state, arg);
                 }
+                lua.cache_multi_value(args);
 
+                let _execution = ExecutionScope::begin(lua);
                 handle_error(
                     thread_state,
                     resume_with_traceback(thread_state, lua.state, nargs),
                 )?;
 
                 let nresults = ffi::lua_gettop(thread_state);
-                let mut results = MultiValue::new();
+                let mut results = lua.new_multi_value();
                 check_stack(thread_state, 1);
                 for _ in 0..nresults {
                     results.push_front(lua.pop_value(thread_state));
@@ -407,6 +1685,97 @@ impl<'lua> Thread<'lua> {
         }
     }
 
+    /// Resets a thread so that it runs `func` the next time it is resumed.
+    ///
+    /// This allows reusing thread objects instead of creating a new thread for every run of a
+    /// short-lived coroutine.
+    ///
+    /// Threads that have not started yet, that have returned from their main function, that raised
+    /// an error or that are suspended can be reset. The calls of an errored or suspended thread are
+    /// abandoned, and the locals of those calls which are captured by closures keep their current
+    /// values. Threads that are currently running cannot be reset, and this returns an error for
+    /// them.
+    ///
+    /// Without the bundled Lua, the `builtin-lua` feature, Lua 5.3 offers no way to recover a
+    /// thread that raised an error or to abandon one that is suspended, so this also returns an
+    /// error for such threads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Function, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let double: Function = lua.eval("function(x) return x * 2 end", None)?;
+    ///
+    /// let thread = lua.create_thread(double.clone());
+    /// assert_eq!(thread.resume::<_, i64>(1)?, 2);
+    ///
+    /// thread.reset(double)?;
+    /// assert_eq!(thread.resume::<_, i64>(2)?, 4);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn reset(&self, func: Function<'lua>) -> Result<()> {
+        let lua = self.0.lua;
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                check_stack(lua.state, 1);
+
+                lua.push_ref(lua.state, &self.0);
+                let thread_state = ffi::lua_tothread(lua.state, -1);
+                ffi::lua_pop(lua.state, 1);
+
+                let status = ffi::lua_status(thread_state);
+                if status != ffi::LUA_OK {
+                    abandon_thread(thread_state, status)?;
+                }
+
+                let mut ar: ffi::lua_Debug = mem::zeroed();
+                if ffi::lua_getstack(thread_state, 0, &mut ar) != 0 {
+                    return Err(Error::RuntimeError(
+                        "cannot reset a running coroutine".to_owned(),
+                    ));
+                }
+
+                check_stack(thread_state, 1);
+                ffi::lua_settop(thread_state, 0);
+                lua.push_ref(thread_state, &func.0);
+                Ok(())
+            })
+        }
+    }
+
+    /// Returns the call stack of this thread, innermost frame first.
+    ///
+    /// For a suspended thread this is the stack at the point where it yielded, and for a thread
+    /// which raised an error it is the stack at the point of the error. The thread is not resumed.
+    pub fn traceback(&self) -> Vec<Frame> {
+        let lua = self.0.lua;
+        unsafe {
+            stack_guard(lua.state, 0, || {
+                check_stack(lua.state, 1);
+
+                lua.push_ref(lua.state, &self.0);
+                let thread_state = ffi::lua_tothread(lua.state, -1);
+                ffi::lua_pop(lua.state, 1);
+
+                let mut frames = Vec::new();
+                let mut ar: ffi::lua_Debug = mem::zeroed();
+                let mut level = 0;
+                while ffi::lua_getstack(thread_state, level, &mut ar) != 0 {
+                    frames.push(stack_frame(thread_state, &mut ar));
+                    level += 1;
+                }
+                frames
+            })
+        }
+    }
+
     /// Gets the status of the thread.
     pub fn status(&self) -> ThreadStatus {
         let lua = self.0.lua;
@@ -419,22 +1788,186 @@ impl<'lua> Thread<'lua> {
                 ffi::lua_pop(lua.state, 1);
 
                 let status = ffi::lua_status(thread_state);
-                if status != ffi::LUA_OK && status != ffi::LUA_YIELD {
-                    ThreadStatus::Error
-                } else if status == ffi::LUA_YIELD || ffi::lua_gettop(thread_state) > 0 {
+                if thread_state == main_state(thread_state) {
+                    // The main thread cannot be resumed, and it is never finished.
+                    ThreadStatus::Running
+                } else if status == ffi::LUA_YIELD {
                     ThreadStatus::Resumable
+                } else if status != ffi::LUA_OK {
+                    ThreadStatus::Error
                 } else {
-                    ThreadStatus::Unresumable
+                    let mut ar: ffi::lua_Debug = mem::zeroed();
+                    if ffi::lua_getstack(thread_state, 0, &mut ar) != 0 {
+                        ThreadStatus::Running
+                    } else if ffi::lua_gettop(thread_state) > 0 {
+                        ThreadStatus::Resumable
+                    } else {
+                        ThreadStatus::Finished
+                    }
                 }
             })
         }
     }
+
+    /// Returns true if the thread can be resumed, i.e. its status is `ThreadStatus::Resumable`.
+    pub fn is_resumable(&self) -> bool {
+        self.status() == ThreadStatus::Resumable
+    }
+
+    /// Returns a pointer identifying the thread.
+    ///
+    /// The pointer is the same for every handle to the same Lua thread, so it can be used as a
+    /// key to recognize the thread without holding a reference to it. It cannot be dereferenced,
+    /// and may be reused for a different value once the thread has been garbage collected.
+    pub fn to_pointer(&self) -> *const c_void {
+        self.0.to_pointer()
+    }
+
+    /// Creates a weak reference to the thread, which does not keep it from being garbage collected.
+    pub fn downgrade(&self) -> WeakLuaRef<'lua, Thread<'lua>> {
+        WeakLuaRef::new(&self.0)
+    }
+}
+
+// Abandons the calls of a thread which is suspended or has raised an error, given its status.
+#[cfg(feature = "builtin-lua")]
+unsafe fn abandon_thread(thread_state: *mut ffi::lua_State, _status: c_int) -> Result<()> {
+    ffi::rlua_resetthread(thread_state);
+    Ok(())
+}
+
+#[cfg(not(feature = "builtin-lua"))]
+unsafe fn abandon_thread(_thread_state: *mut ffi::lua_State, status: c_int) -> Result<()> {
+    if status == ffi::LUA_YIELD {
+        Err(Error::RuntimeError(
+            "cannot reset a suspended coroutine".to_owned(),
+        ))
+    } else {
+        Err(Error::RuntimeError(
+            "cannot reset a coroutine that raised an error".to_owned(),
+        ))
+    }
+}
+
+/// A chunk of Lua code run a slice at a time, created with [`Lua::exec_incremental`].
+///
+/// The chunk runs in a coroutine, which [`step`] resumes for a number of instructions before
+/// making it yield, so that scripts can be spread over several frames of a game loop without
+/// calling `coroutine.yield` themselves.
+///
+/// [`Lua::exec_incremental`]: struct.Lua.html#method.exec_incremental
+/// [`step`]: #method.step
+#[derive(Clone, Debug)]
+pub struct Execution<'lua> {
+    thread: Thread<'lua>,
+}
+
+/// The result of running a slice of an [`Execution`].
+///
+/// [`Execution`]: struct.Execution.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExecutionStep<R> {
+    /// The chunk has not finished yet.
+    Pending,
+    /// The chunk has finished, and returned the given values.
+    Done(R),
+}
+
+impl<'lua> Execution<'lua> {
+    /// Runs the chunk for up to `instructions` more instructions.
+    ///
+    /// Returns [`ExecutionStep::Done`] with the values returned by the chunk once it finishes, and
+    /// [`ExecutionStep::Pending`] if it has to be stepped again. Instructions are counted in Lua
+    /// code running in the coroutine of the chunk and in the coroutines it resumes, and the chunk
+    /// only yields where it can, so that a step lasts longer than `instructions` while Rust
+    /// callbacks run or while the chunk runs inside another coroutine. A chunk which calls
+    /// `coroutine.yield` itself is also left pending, and the values it yields are discarded.
+    ///
+    /// Returns an error if the chunk raised one, or if it has already finished.
+    ///
+    /// [`ExecutionStep::Done`]: enum.ExecutionStep.html#variant.Done
+    /// [`ExecutionStep::Pending`]: enum.ExecutionStep.html#variant.Pending
+    pub fn step<R: FromLuaMulti<'lua>>(&self, instructions: u64) -> Result<ExecutionStep<R>> {
+        let lua = self.thread.0.lua;
+        unsafe {
+            let thread_state = stack_guard(lua.state, 0, || {
+                check_stack(lua.state, 1);
+                lua.push_ref(lua.state, &self.thread.0);
+                let thread_state = ffi::lua_tothread(lua.state, -1);
+                ffi::lua_pop(lua.state, 1);
+                thread_state
+            });
+
+            // A step made from a callback of another step resumes that step once it is over.
+            let extra = lua.extra_data();
+            let outer = (*extra).time_slice.replace((thread_state, instructions));
+            set_limit_hook(thread_state);
+            let results = self.thread.resume::<_, MultiValue>(());
+            (*extra).time_slice = outer;
+            set_limit_hook(thread_state);
+
+            let results = results?;
+            if self.thread.status() == ThreadStatus::Resumable {
+                Ok(ExecutionStep::Pending)
+            } else {
+                R::from_lua_multi(results, lua).map(ExecutionStep::Done)
+            }
+        }
+    }
+
+    /// Returns whether the chunk has finished, either by returning or by raising an error.
+    pub fn is_finished(&self) -> bool {
+        !self.thread.is_resumable()
+    }
+}
+
+/// Handle passed to the closure of [`Lua::create_thread_from`], used to yield values from the
+/// thread.
+///
+/// [`Lua::create_thread_from`]: struct.Lua.html#method.create_thread_from
+pub struct Yielder<'lua> {
+    lua: &'lua Lua,
+}
+
+impl<'lua> Yielder<'lua> {
+    /// Returns the `Lua` instance the thread belongs to.
+    pub fn lua(&self) -> &'lua Lua {
+        self.lua
+    }
+
+    /// Yields the given values from the thread once the closure returns.
+    ///
+    /// The values returned by the closure are discarded, and the closure is called again with the
+    /// arguments of the next resume. Calling this method again replaces the values to yield.
+    pub fn yield_values<A: ToLuaMulti<'lua>>(&self, args: A) -> Result<()> {
+        self.lua.request_yield(args, Resume::Callback)
+    }
+}
+
+// How a callback which requested to yield continues when its thread is resumed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Resume {
+    // The resume arguments are returned to the caller of the callback.
+    Return,
+    // The callback is called again with the resume arguments.
+    Callback,
+    // The resume arguments are the outcome of a future, see `await_resume`.
+    #[cfg(feature = "async")]
+    Await,
 }
 
+/// A copy of the global state of Lua code, taken with [`Lua::snapshot`] and restored with
+/// [`Lua::restore`].
+///
+/// [`Lua::snapshot`]: struct.Lua.html#method.snapshot
+/// [`Lua::restore`]: struct.Lua.html#method.restore
+#[derive(Clone, Debug)]
+pub struct Snapshot<'lua>(Table<'lua>);
+
 /// Top level Lua struct which holds the Lua state itself.
 pub struct Lua {
     pub(crate) state: *mut ffi::lua_State,
-    main_state: *mut ffi::lua_State,
+    pub(crate) main_state: *mut ffi::lua_State,
     ephemeral: bool,
 }
 
@@ -442,7 +1975,12 @@ impl Drop for Lua {
     fn drop(&mut self) {
         unsafe {
             if !self.ephemeral {
+                // The allocator is still used while the state is closed.
+                let allocator = (*self.extra_data()).allocator;
+                // Finalizers run while the state is closed, which must not be reported.
+                (*allocator).gc = None;
                 ffi::lua_close(self.state);
+                drop(Box::from_raw(allocator));
             }
         }
     }
@@ -452,108 +1990,184 @@ impl Lua {
     /// Creates a new Lua state.
     ///
     /// Also loads the standard library.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Lua {
-        unsafe extern "C" fn allocator(
-            _: *mut c_void,
-            ptr: *mut c_void,
-            _: usize,
-            nsize: usize,
-        ) -> *mut c_void {
-            if nsize == 0 {
-                libc::free(ptr as *mut libc::c_void);
-                ptr::null_mut()
-            } else {
-                let p = libc::realloc(ptr as *mut libc::c_void, nsize);
-                if p.is_null() {
-                    // We must abort on OOM, because otherwise this will result in an unsafe
-                    // longjmp.
-                    eprintln!("Out of memory in Lua allocation, aborting!");
-                    process::abort()
-                } else {
-                    p as *mut c_void
-                }
-            }
-        }
-
-        unsafe {
-            let state = ffi::lua_newstate(allocator, ptr::null_mut());
-
-            stack_guard(state, 0, || {
-                // Do not open the debug library, currently it can be used to cause unsafety.
-                ffi::luaL_requiref(state, cstr!("_G"), ffi::luaopen_base, 1);
-                ffi::luaL_requiref(state, cstr!("coroutine"), ffi::luaopen_coroutine, 1);
-                ffi::luaL_requiref(state, cstr!("table"), ffi::luaopen_table, 1);
-                ffi::luaL_requiref(state, cstr!("io"), ffi::luaopen_io, 1);
-                ffi::luaL_requiref(state, cstr!("os"), ffi::luaopen_os, 1);
-                ffi::luaL_requiref(state, cstr!("string"), ffi::luaopen_string, 1);
-                ffi::luaL_requiref(state, cstr!("utf8"), ffi::luaopen_utf8, 1);
-                ffi::luaL_requiref(state, cstr!("math"), ffi::luaopen_math, 1);
-                ffi::luaL_requiref(state, cstr!("package"), ffi::luaopen_package, 1);
-                ffi::lua_pop(state, 9);
+        unsafe { Lua::create(false) }
+    }
 
-                // Create the userdata registry table
+    /// Creates a new Lua state which opens most of the standard library on first use.
+    ///
+    /// The base, `coroutine` and `string` libraries are loaded right away. The `table`, `io`,
+    /// `os`, `utf8`, `math` and `package` libraries are only opened when their global variable
+    /// is first read, or when they are loaded with `require`. This makes creating the state
+    /// considerably faster for workloads which run many short-lived states that rarely use these
+    /// libraries.
+    ///
+    /// Until a library is opened, it does not show up when iterating over the globals table or
+    /// when reading the globals table without invoking metamethods. The lazy loading is
+    /// implemented with a metatable on the globals table, so libraries which have not been opened
+    /// yet can no longer be reached through global variables once that metatable is replaced.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new_lazy();
+    /// assert_eq!(lua.eval::<f64>("math.sqrt(16)", None)?, 4.0);
+    /// assert_eq!(lua.eval::<String>("require('table').concat({1, 2}, ',')", None)?, "1,2");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn new_lazy() -> Lua {
+        unsafe { Lua::create(true) }
+    }
 
-                ffi::lua_pushlightuserdata(
-                    state,
-                    &LUA_USERDATA_REGISTRY_KEY as *const u8 as *mut c_void,
-                );
+    /// Attaches to a Lua state created outside of rlua, such as by the host application.
+    ///
+    /// Installs the registry entries rlua relies on, unless they have already been installed by a
+    /// previous call, and replaces `pcall`, `xpcall`, `setmetatable` and `coroutine.resume` with
+    /// the versions rlua uses, if they exist. No libraries are opened. The allocator of the state
+    /// is wrapped so that failing allocations abort the process, as they do in states created by
+    /// rlua, and to enforce the memory limit.
+    ///
+    /// The returned `Lua` owns the state and closes it when dropped, use [`into_raw`] to hand it
+    /// back to the host instead. `state` may be any thread of the state, rlua always uses the main
+    /// thread.
+    ///
+    /// # Safety
+    ///
+    /// `state` must be a valid Lua 5.3 state, using the same Lua library as rlua. It must not be
+    /// attached more than once at the same time, and must not be closed or used from C code while
+    /// it is attached. rlua stores a pointer in the extra space of every thread, which the host
+    /// must not use. Without the bundled Lua, the `builtin-lua` feature, coroutines created before
+    /// the state was first attached have a stale copy of the extra space, and must not be used
+    /// through rlua.
+    ///
+    /// [`into_raw`]: #method.into_raw
+    pub unsafe fn init_from_ptr(state: *mut ffi::lua_State) -> Lua {
+        if ffi::lua_checkstack(state, ffi::LUA_MINSTACK) == 0 {
+            panic!("rlua: out of stack space");
+        }
+        let state = main_state(state);
+
+        let mut ud = ptr::null_mut();
+        let alloc = ffi::lua_getallocf(state, &mut ud);
+        let allocator = Box::into_raw(Box::new(AllocatorState {
+            host: Some((alloc, ud)),
+            used: used_memory(state),
+            max_memory: None,
+            max_table_entries: None,
+            table_entries: 0,
+            limits_enforced: false,
+            allocated: 0,
+            freed: 0,
+            profile: None,
+            gc: None,
+            gc_generation: 0,
+            main_state: state,
+        }));
+        ffi::lua_setallocf(state, allocator_function, allocator as *mut c_void);
+
+        init_registry(state, allocator);
+        #[cfg(feature = "builtin-lua")]
+        ffi::rlua_setextraspace(state, *(ffi::lua_getextraspace(state) as *mut *mut c_void));
+
+        Lua {
+            state,
+            main_state: state,
+            ephemeral: false,
+        }
+    }
 
-                push_userdata::<HashMap<TypeId, c_int>>(state, HashMap::new());
+    /// Returns the Lua state without closing it, for the host application to use.
+    ///
+    /// The allocator of a state attached with [`init_from_ptr`] is restored, the memory and table
+    /// entry limits set with [`set_resource_limits`] and the budget set with [`set_budget`] no
+    /// longer apply, and coverage, debugging and other measurements made through hooks are
+    /// stopped. The registry entries of rlua are kept, so that the state can be attached again
+    /// later, but values created through rlua that are still referenced from Lua, such as Rust
+    /// callbacks, must not be used until then. The state must eventually be closed with
+    /// `lua_close`.
+    ///
+    /// [`init_from_ptr`]: #method.init_from_ptr
+    /// [`set_resource_limits`]: #method.set_resource_limits
+    /// [`set_budget`]: #method.set_budget
+    pub fn into_raw(self) -> *mut ffi::lua_State {
+        unsafe {
+            let state = self.main_state;
+            let extra = self.extra_data();
+            let allocator = Box::from_raw((*extra).allocator);
+            let (alloc, ud) = allocator.host.unwrap_or((system_allocator, ptr::null_mut()));
+            ffi::lua_setallocf(state, alloc, ud);
+            #[cfg(feature = "builtin-lua")]
+            ffi::rlua_settablehook(state, None, ptr::null_mut());
+            // Coroutines created while the hook was set keep it, and remove it themselves the
+            // next time it runs.
+            ffi::lua_sethook(state, None, 0, 0);
+            (*extra).budget = None;
+            (*extra).watchdog = None;
+            (*extra).report = None;
+            (*extra).debugger = None;
+            (*extra).coverage = None;
+            (*extra).time_slice = None;
+            (*extra).allocator = ptr::null_mut();
+            mem::forget(self);
+            state
+        }
+    }
 
+    unsafe fn create(lazy_libraries: bool) -> Lua {
+        let allocator = Box::into_raw(Box::new(AllocatorState {
+            host: None,
+            used: 0,
+            max_memory: None,
+            max_table_entries: None,
+            table_entries: 0,
+            limits_enforced: false,
+            allocated: 0,
+            freed: 0,
+            profile: None,
+            gc: None,
+            gc_generation: 0,
+            main_state: ptr::null_mut(),
+        }));
+        let state = ffi::lua_newstate(allocator_function, allocator as *mut c_void);
+        (*allocator).main_state = state;
+
+        stack_guard(state, 0, || {
+            // Do not open the debug library, currently it can be used to cause unsafety.
+            ffi::luaL_requiref(state, cstr!("_G"), ffi::luaopen_base, 1);
+            ffi::luaL_requiref(state, cstr!("coroutine"), ffi::luaopen_coroutine, 1);
+            ffi::luaL_requiref(state, cstr!("string"), ffi::luaopen_string, 1);
+            ffi::lua_pop(state, 3);
+
+            if lazy_libraries {
+                ffi::lua_rawgeti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
                 ffi::lua_newtable(state);
-
-                push_string(state, "__gc");
-                ffi::lua_pushcfunction(state, userdata_destructor::<HashMap<TypeId, c_int>>);
+                push_string(state, "__index");
+                ffi::lua_pushcfunction(state, lazy_library_index);
                 ffi::lua_rawset(state, -3);
-
                 ffi::lua_setmetatable(state, -2);
-
-                ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
-
-                // Create the function metatable
-
-                ffi::lua_pushlightuserdata(
-                    state,
-                    &FUNCTION_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
-                );
-
-                ffi::lua_newtable(state);
-
-                push_string(state, "__gc");
-                ffi::lua_pushcfunction(state, userdata_destructor::<RefCell<Callback>>);
-                ffi::lua_rawset(state, -3);
-
-                push_string(state, "__metatable");
-                ffi::lua_pushboolean(state, 0);
-                ffi::lua_rawset(state, -3);
-
-                ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
-
-                // Override pcall, xpcall, and setmetatable with versions that cannot be used to
-                // cause unsafety.
-
-                ffi::lua_rawgeti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
-
-                push_string(state, "pcall");
-                ffi::lua_pushcfunction(state, safe_pcall);
-                ffi::lua_rawset(state, -3);
-
-                push_string(state, "xpcall");
-                ffi::lua_pushcfunction(state, safe_xpcall);
-                ffi::lua_rawset(state, -3);
-
-                push_string(state, "setmetatable");
-                ffi::lua_pushcfunction(state, safe_setmetatable);
-                ffi::lua_rawset(state, -3);
-
                 ffi::lua_pop(state, 1);
-            });
-
-            Lua {
-                state,
-                main_state: state,
-                ephemeral: false,
+            } else {
+                for &(name, open) in LAZY_LIBRARIES {
+                    ffi::luaL_requiref(state, name.as_ptr() as *const c_char, open, 1);
+                    ffi::lua_pop(state, 1);
+                }
             }
+        });
+
+        init_registry(state, allocator);
+
+        Lua {
+            state,
+            main_state: state,
+            ephemeral: false,
         }
     }
 
@@ -561,665 +2175,6841 @@ impl Lua {
     ///
     /// The debug library is very unsound, loading it and using it breaks all
     /// the guarantees of rlua.
+    ///
+    /// # Safety
+    ///
+    /// Scripts can use the debug library to read and modify values rlua relies on, such as the
+    /// upvalues of Rust callbacks and the metatables of userdata.
     pub unsafe fn load_debug(&self) {
         check_stack(self.state, 1);
         ffi::luaL_requiref(self.state, cstr!("debug"), ffi::luaopen_debug, 1);
         ffi::lua_pop(self.state, 1);
     }
 
-    /// Loads a chunk of Lua code and returns it as a function.
+    /// Returns true if the linked Lua was built with `LUA_COMPAT_5_2`, which keeps the functions
+    /// deprecated by Lua 5.3, such as `math.pow` and the `bit32` library.
     ///
-    /// The source can be named by setting the `name` parameter. This is generally recommended as it
-    /// results in better error traces.
-    ///
-    /// Equivalent to Lua's `load` function.
-    pub fn load(&self, source: &str, name: Option<&str>) -> Result<Function> {
-        unsafe {
-            stack_err_guard(self.state, 0, || {
+    /// The bundled Lua is built this way with the `lua52-compat` feature, which also opens
+    /// `bit32` along with the other standard libraries. A Lua linked without the `builtin-lua`
+    /// feature may have been built either way, which this checks by looking for `math.pow` in a
+    /// fresh copy of the math library, so that scripts written for Lua 5.2 can be run or refused
+    /// consistently on every platform.
+    pub fn lua52_compat(&self) -> bool {
+        let open_math = unsafe {
+            stack_guard(self.state, 0, || {
                 check_stack(self.state, 1);
-
-                handle_error(
-                    self.state,
-                    if let Some(name) = name {
-                        let name = CString::new(name.to_owned()).map_err(|e| {
-                            Error::ToLuaConversionError {
-                                from: "&str",
-                                to: "string",
-                                message: Some(e.to_string()),
-                            }
-                        })?;
-                        ffi::luaL_loadbuffer(
-                            self.state,
-                            source.as_ptr() as *const c_char,
-                            source.len(),
-                            name.as_ptr(),
-                        )
-                    } else {
-                        ffi::luaL_loadbuffer(
-                            self.state,
-                            source.as_ptr() as *const c_char,
-                            source.len(),
-                            ptr::null(),
-                        )
-                    },
-                )?;
-
-                Ok(Function(self.pop_ref(self.state)))
+                ffi::lua_pushcfunction(self.state, ffi::luaopen_math);
+                Function(self.pop_ref(self.state))
             })
-        }
+        };
+        open_math
+            .call::<_, Table>(())
+            .and_then(|math| math.raw_get::<_, Option<Function>>("pow"))
+            .map(|pow| pow.is_some())
+            .unwrap_or(false)
     }
 
-    /// Execute a chunk of Lua code.
+    /// Calls `f` with the raw Lua state, so that code using the [`ffi`] bindings can work with the
+    /// same state as the safe API.
     ///
-    /// This is equivalent to simply loading the source with `load` and then calling the resulting
-    /// function with no arguments.
+    /// `f` must leave the stack as it found it, this function panics if `f` left values on the
+    /// stack or removed values it did not push. Only `LUA_MINSTACK` stack slots are guaranteed to
+    /// be free, `f` must call `lua_checkstack` before pushing more values.
     ///
-    /// Returns the values returned by the chunk.
-    pub fn exec<'lua, R: FromLuaMulti<'lua>>(
-        &'lua self,
-        source: &str,
-        name: Option<&str>,
-    ) -> Result<R> {
-        self.load(source, name)?.call(())
-    }
-
-    /// Evaluate the given expression or chunk inside this Lua state.
+    /// Using the state is unsafe: `f` must not raise Lua errors, since they would unwind through
+    /// Rust frames, so functions which may raise errors must be called with `lua_pcall`.
     ///
-    /// If `source` is an expression, returns the value it evaluates to. Otherwise, returns the
-    /// values returned by the chunk (if any).
-    pub fn eval<'lua, R: FromLuaMulti<'lua>>(
-        &'lua self,
-        source: &str,
-        name: Option<&str>,
-    ) -> Result<R> {
-        // First, try interpreting the lua as an expression by adding
-        // "return", then as a statement.  This is the same thing the
-        // actual lua repl does.
-        self.load(&format!("return {}", source), name)
-            .or_else(|_| self.load(source, name))?
-            .call(())
-    }
-
-    /// Pass a `&str` slice to Lua, creating and returning an interned Lua string.
-    pub fn create_string(&self, s: &str) -> String {
-        unsafe {
-            stack_guard(self.state, 0, || {
-                check_stack(self.state, 2);
-                ffi::lua_pushlstring(self.state, s.as_ptr() as *const c_char, s.len());
-                String(self.pop_ref(self.state))
-            })
-        }
-    }
-
-    /// Creates and returns a new table.
-    pub fn create_table(&self) -> Table {
-        unsafe {
-            stack_guard(self.state, 0, || {
-                check_stack(self.state, 2);
-                ffi::lua_newtable(self.state);
-                Table(self.pop_ref(self.state))
-            })
-        }
-    }
-
-    /// Creates a table and fills it with values from an iterator.
-    pub fn create_table_from<'lua, K, V, I>(&'lua self, cont: I) -> Result<Table<'lua>>
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{ffi, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let globals = lua.globals();
+    /// globals.set("answer", 42)?;
+    ///
+    /// let answer = lua.with_raw_state(|state| unsafe {
+    ///     ffi::lua_rawgeti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+    ///     ffi::lua_pushlstring(state, "answer".as_ptr() as *const _, 6);
+    ///     ffi::lua_rawget(state, -2);
+    ///     let answer = ffi::lua_tointeger(state, -1);
+    ///     ffi::lua_pop(state, 2);
+    ///     answer
+    /// });
+    /// assert_eq!(answer, 42);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`ffi`]: ffi/index.html
+    pub fn with_raw_state<R, F>(&self, f: F) -> R
     where
-        K: ToLua<'lua>,
-        V: ToLua<'lua>,
-        I: IntoIterator<Item = (K, V)>,
+        F: FnOnce(*mut ffi::lua_State) -> R,
     {
         unsafe {
-            stack_err_guard(self.state, 0, || {
-                check_stack(self.state, 3);
-                ffi::lua_newtable(self.state);
-
-                for (k, v) in cont {
-                    self.push_value(self.state, k.to_lua(self)?);
-                    self.push_value(self.state, v.to_lua(self)?);
-                    ffi::lua_rawset(self.state, -3);
-                }
-                Ok(Table(self.pop_ref(self.state)))
-            })
+            let guard = StackGuard::with_reserved(self.state, ffi::LUA_MINSTACK);
+            let result = f(self.state);
+            let top = ffi::lua_gettop(self.state);
+            if top != guard.top() {
+                panic!(
+                    "stack imbalance in with_raw_state, the height of the stack changed by {}",
+                    top - guard.top()
+                );
+            }
+            result
         }
     }
 
-    /// Creates a table from an iterator of values, using `1..` as the keys.
-    pub fn create_sequence_from<'lua, T, I>(&'lua self, cont: I) -> Result<Table<'lua>>
-    where
-        T: ToLua<'lua>,
-        I: IntoIterator<Item = T>,
-    {
-        self.create_table_from(cont.into_iter().enumerate().map(|(k, v)| (k + 1, v)))
+    /// Loads a chunk of Lua code and returns it as a function.
+    ///
+    /// The source can be named by setting the `name` parameter. This is generally recommended as it
+    /// results in better error traces.
+    ///
+    /// Equivalent to Lua's `load` function.
+    pub fn load(&self, source: &str, name: Option<&str>) -> Result<Function<'_>> {
+        // Lua bytecode always starts with a byte sequence which is not valid UTF-8, so `source`
+        // cannot be bytecode.
+        unsafe { self.load_buffer(source.as_bytes(), name) }
     }
 
-    /// Wraps a Rust function or closure, creating a callable Lua function handle to it.
+    /// Loads a script included with [`include_lua!`], which may have been compiled to bytecode.
     ///
-    /// # Examples
+    /// Messages refer to the script by its path, like `scripts/init.lua:3: message`. See
+    /// [`precompile_script`].
     ///
-    /// Create a function which prints its argument:
+    /// [`include_lua!`]: macro.include_lua.html
+    /// [`precompile_script`]: fn.precompile_script.html
+    pub fn load_included(&self, chunk: IncludedChunk) -> Result<Function<'_>> {
+        let name = precompile::chunk_name(Path::new(chunk.name));
+        // `IncludedChunk` can only hold bytecode written by `precompile_script`.
+        unsafe { self.load_buffer(chunk.chunk, Some(&name)) }
+    }
+
+    /// Loads a chunk of Lua code generated from other sources, such as templates, and returns it
+    /// as a function.
+    ///
+    /// Error messages and tracebacks which refer to lines of the chunk are rewritten with `map`
+    /// to refer to the sources it was generated from, once they are returned to Rust. Messages
+    /// caught by `pcall` within Lua are not rewritten. The map applies to all chunks loaded with
+    /// the same `name`, and replaces the map of any chunk previously loaded with that name.
+    ///
+    /// # Examples
     ///
     /// ```
     /// # extern crate rlua;
-    /// # use rlua::{Lua, Result};
+    /// # use rlua::{Lua, Result, SourceMap};
     /// # fn try_main() -> Result<()> {
     /// let lua = Lua::new();
     ///
-    /// let greet = lua.create_function(|_, name: String| {
-    ///     println!("Hello, {}!", name);
-    ///     Ok(())
-    /// });
-    /// # let _ = greet;    // used
+    /// // Generated from `page.tmpl`, where the expression is on line 12.
+    /// let generated = "local out = {}\nout[#out + 1] = tostring(user.name)\nreturn out";
+    /// let mut map = SourceMap::new();
+    /// map.insert(2, "page.tmpl", 12);
+    ///
+    /// let template = lua.load_with_source_map(generated, "page", map)?;
+    /// let err = template.call::<_, ()>(()).unwrap_err();
+    /// assert!(err.to_string().contains("page.tmpl:12: attempt to index"));
     /// # Ok(())
     /// # }
     /// # fn main() {
     /// #     try_main().unwrap();
     /// # }
     /// ```
+    pub fn load_with_source_map(
+        &self,
+        source: &str,
+        name: &str,
+        map: SourceMap,
+    ) -> Result<Function<'_>> {
+        unsafe {
+            // The name of a chunk in messages depends only on the name it was loaded with.
+            let chunk = self.load_buffer(b"", Some(name))?;
+            let chunk = stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+                let mut ar: ffi::lua_Debug = mem::zeroed();
+                self.push_ref(self.state, &chunk.0);
+                ffi::lua_getinfo(self.state, cstr!(">S"), &mut ar);
+                CStr::from_ptr(ar.short_src.as_ptr())
+                    .to_string_lossy()
+                    .into_owned()
+            });
+
+            let extra = self.extra_data();
+            (*extra).source_maps.retain(|map| map.0 != chunk);
+            (*extra).source_maps.push((chunk, map));
+            self.load(source, Some(name))
+        }
+    }
+
+    // Loads a chunk of source or bytecode. Lua does not verify bytecode, and loading invalid
+    // bytecode is undefined behavior.
+    pub(crate) unsafe fn load_buffer(
+        &self,
+        chunk: &[u8],
+        name: Option<&str>,
+    ) -> Result<Function<'_>> {
+        stack_err_guard(self.state, 0, || {
+            check_stack(self.state, 1);
+
+            handle_error(
+                self.state,
+                if let Some(name) = name {
+                    let name = CString::new(name.to_owned()).map_err(|e| {
+                        Error::ToLuaConversionError {
+                            from: "&str",
+                            to: "string",
+                            message: Some(e.to_string()),
+                        }
+                    })?;
+                    ffi::luaL_loadbuffer(
+                        self.state,
+                        chunk.as_ptr() as *const c_char,
+                        chunk.len(),
+                        name.as_ptr(),
+                    )
+                } else {
+                    ffi::luaL_loadbuffer(
+                        self.state,
+                        chunk.as_ptr() as *const c_char,
+                        chunk.len(),
+                        ptr::null(),
+                    )
+                },
+            )?;
+
+            Ok(Function(self.pop_ref(self.state)))
+        })
+    }
+
+    // Loads a chunk of source code read by `loadfile` or `require`, which must not be bytecode,
+    // and sets its environment to `env` if it is given.
+    pub(crate) fn load_source<'lua>(
+        &'lua self,
+        source: &[u8],
+        name: &str,
+        env: Option<Value<'lua>>,
+    ) -> Result<Function<'lua>> {
+        // Bytecode starts with the escape character of the Lua signature.
+        if source.first() == Some(&0x1b) {
+            return Err(Error::SyntaxError {
+                message: format!("{}: attempt to load a binary chunk", name),
+                incomplete_input: false,
+            });
+        }
+        let function = unsafe { self.load_buffer(source, Some(name))? };
+        if let Some(env) = env {
+            unsafe {
+                stack_guard(self.state, 0, || {
+                    check_stack(self.state, 2);
+                    self.push_ref(self.state, &function.0);
+                    self.push_value(self.state, env);
+                    if ffi::lua_setupvalue(self.state, -2, 1).is_null() {
+                        ffi::lua_pop(self.state, 1);
+                    }
+                    ffi::lua_pop(self.state, 1);
+                })
+            }
+        }
+        Ok(function)
+    }
+
+    /// Loads a native Lua module from a shared library, like `package.loadlib` followed by
+    /// `require`.
     ///
-    /// Use tuples to accept multiple arguments:
+    /// `symbol` is the name of the function opening the module, of the form `luaopen_<name>`,
+    /// where dots in the module name are written as underscores. The function is called with the
+    /// module name and `path`, and its result is stored in `package.loaded` under the module name
+    /// and returned. If it returns nothing, `true` is stored instead, unless the module stored a
+    /// value itself. The library stays loaded until the process exits.
     ///
-    /// ```
+    /// # Safety
+    ///
+    /// The module runs arbitrary native code, and must have been built for Lua 5.3. It must use
+    /// the same Lua library as rlua: with the `builtin-lua` feature, Lua is linked statically, so
+    /// the executable must export the Lua API for the module to link against it, for example by
+    /// linking with `-rdynamic`. Like the debug library, the module must not be able to break the
+    /// guarantees of rlua, for example by calling Rust callbacks in unexpected ways.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
     /// # extern crate rlua;
     /// # use rlua::{Lua, Result};
     /// # fn try_main() -> Result<()> {
     /// let lua = Lua::new();
-    ///
-    /// let print_person = lua.create_function(|_, (name, age): (String, u8)| {
-    ///     println!("{} is {} years old!", name, age);
-    ///     Ok(())
-    /// });
-    /// # let _ = print_person;    // used
+    /// let lpeg = unsafe { lua.load_c_module("/usr/lib/lua/5.3/lpeg.so", "luaopen_lpeg")? };
+    /// lua.globals().set("lpeg", lpeg)?;
+    /// lua.exec::<()>("assert(lpeg.match(lpeg.P('a'), 'abc') == 2)", None)?;
     /// # Ok(())
     /// # }
     /// # fn main() {
     /// #     try_main().unwrap();
     /// # }
     /// ```
-    pub fn create_function<'lua, A, R, F>(&'lua self, mut func: F) -> Function<'lua>
-    where
-        A: FromLuaMulti<'lua>,
-        R: ToLuaMulti<'lua>,
-        F: 'static + FnMut(&'lua Lua, A) -> Result<R>,
-    {
-        self.create_callback_function(Box::new(move |lua, args| {
-            func(lua, A::from_lua_multi(args, lua)?)?.to_lua_multi(lua)
-        }))
-    }
+    #[cfg(unix)]
+    pub unsafe fn load_c_module<'lua, P: AsRef<Path>>(
+        &'lua self,
+        path: P,
+        symbol: &str,
+    ) -> Result<Value<'lua>> {
+        use std::os::unix::ffi::OsStrExt;
+
+        unsafe fn dl_error() -> StdString {
+            let message = libc::dlerror();
+            if message.is_null() {
+                "unknown error".to_owned()
+            } else {
+                CStr::from_ptr(message).to_string_lossy().into_owned()
+            }
+        }
 
-    /// Wraps a Lua function into a new thread (or coroutine).
+        let path = path.as_ref();
+        let name = match symbol.find("luaopen_") {
+            Some(0) if symbol.len() > 8 => symbol[8..].replace('_', "."),
+            _ => {
+                return Err(Error::RuntimeError(format!(
+                    "'{}' is not of the form luaopen_<name>",
+                    symbol
+                )))
+            }
+        };
+        let to_c_string = |s: &[u8]| {
+            CString::new(s).map_err(|e| Error::ToLuaConversionError {
+                from: "&str",
+                to: "string",
+                message: Some(e.to_string()),
+            })
+        };
+        let c_path = to_c_string(path.as_os_str().as_bytes())?;
+        let c_symbol = to_c_string(symbol.as_bytes())?;
+
+        let handle = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if handle.is_null() {
+            return Err(Error::RuntimeError(format!(
+                "cannot load C module '{}': {}",
+                path.display(),
+                dl_error()
+            )));
+        }
+        let open = libc::dlsym(handle, c_symbol.as_ptr());
+        if open.is_null() {
+            let err = dl_error();
+            libc::dlclose(handle);
+            return Err(Error::RuntimeError(format!(
+                "cannot find '{}' in '{}': {}",
+                symbol,
+                path.display(),
+                err
+            )));
+        }
+        let open = mem::transmute::<*mut libc::c_void, ffi::lua_CFunction>(open);
+
+        let module = Function::from_c_function(self, open)
+            .call::<_, Value>((name.as_str(), path.to_string_lossy().as_ref()))?;
+
+        let loaded = stack_guard(self.state, 0, || {
+            check_stack(self.state, 2);
+            ffi::luaL_getsubtable(self.state, ffi::LUA_REGISTRYINDEX, cstr!("_LOADED"));
+            Table(self.pop_ref(self.state))
+        });
+        if !module.is_nil() {
+            loaded.raw_set(name.as_str(), module)?;
+        }
+        if loaded.raw_get::<_, Value>(name.as_str())?.is_nil() {
+            loaded.raw_set(name.as_str(), true)?;
+        }
+        loaded.raw_get(name.as_str())
+    }
+
+    /// Execute a chunk of Lua code.
     ///
-    /// Equivalent to `coroutine.create`.
-    pub fn create_thread<'lua>(&'lua self, func: Function<'lua>) -> Thread<'lua> {
+    /// This is equivalent to simply loading the source with `load` and then calling the resulting
+    /// function with no arguments.
+    ///
+    /// Returns the values returned by the chunk.
+    pub fn exec<'lua, R: FromLuaMulti<'lua>>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+    ) -> Result<R> {
+        self.load(source, name)?.call(())
+    }
+
+    /// Executes a chunk of Lua code like [`exec`], passing `args` to it.
+    ///
+    /// The chunk receives the arguments as its varargs, like the standalone `lua` interpreter
+    /// passes the command line to scripts, so inputs do not have to go through globals. Calling
+    /// the function returned by [`load`] with arguments does the same, and can run the chunk
+    /// several times.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let greeting = lua.exec_with_args::<_, String>(r#"
+    ///     local name, times = ...
+    ///     return ("hello " .. name .. "! "):rep(times)
+    /// "#, None, ("world", 2))?;
+    /// assert_eq!(greeting, "hello world! hello world! ");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`exec`]: #method.exec
+    /// [`load`]: #method.load
+    pub fn exec_with_args<'lua, A, R>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+        args: A,
+    ) -> Result<R>
+    where
+        A: ToLuaMulti<'lua>,
+        R: FromLuaMulti<'lua>,
+    {
+        self.load(source, name)?.call(args)
+    }
+
+    /// Prepares a chunk of Lua code to be run a slice at a time with [`Execution::step`].
+    ///
+    /// Returns a syntax error if the chunk cannot be loaded. Nothing runs until the first step.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{ExecutionStep, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let execution = lua.exec_incremental(r#"
+    ///     local sum = 0
+    ///     for i = 1, 100000 do sum = sum + i end
+    ///     return sum
+    /// "#, None)?;
+    ///
+    /// let mut frames = 1;
+    /// let sum = loop {
+    ///     match execution.step::<i64>(10_000)? {
+    ///         ExecutionStep::Pending => frames += 1,
+    ///         ExecutionStep::Done(sum) => break sum,
+    ///     }
+    /// };
+    /// assert_eq!(sum, 5000050000);
+    /// assert!(frames > 10);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Execution::step`]: struct.Execution.html#method.step
+    pub fn exec_incremental<'lua>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+    ) -> Result<Execution<'lua>> {
+        Ok(Execution {
+            thread: self.create_thread(self.load(source, name)?),
+        })
+    }
+
+    /// Executes a chunk of Lua code like [`exec`], and measures the resources it uses.
+    ///
+    /// The measurements include loading the chunk. See [`Function::call_with_report`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let (result, report) = lua.exec_with_report::<()>(r#"
+    ///     local t = {}
+    ///     for i = 1, 10000 do t[i] = tostring(i) end
+    /// "#, None);
+    /// result?;
+    /// assert!(report.instructions > 10_000);
+    /// assert!(report.lua_allocated_bytes > 10_000);
+    ///
+    /// let (result, _) = lua.exec_with_report::<()>("error('failed')", None);
+    /// assert!(result.is_err());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`exec`]: #method.exec
+    /// [`Function::call_with_report`]: struct.Function.html#method.call_with_report
+    pub fn exec_with_report<'lua, R: FromLuaMulti<'lua>>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+    ) -> (Result<R>, ResourceReport) {
+        let scope = ReportScope::begin(self);
+        let result = self.load(source, name).and_then(|function| function.call(()));
+        (result, scope.finish())
+    }
+
+    /// Evaluate the given expression or chunk inside this Lua state.
+    ///
+    /// If `source` is an expression, returns the value it evaluates to. Otherwise, returns the
+    /// values returned by the chunk (if any).
+    pub fn eval<'lua, R: FromLuaMulti<'lua>>(
+        &'lua self,
+        source: &str,
+        name: Option<&str>,
+    ) -> Result<R> {
+        // First, try interpreting the lua as an expression by adding
+        // "return", then as a statement.  This is the same thing the
+        // actual lua repl does.
+        self.load(&format!("return {}", source), name)
+            .or_else(|_| self.load(source, name))?
+            .call(())
+    }
+
+    /// Pass a `&str` slice to Lua, creating and returning an interned Lua string.
+    pub fn create_string(&self, s: &str) -> String<'_> {
+        self.create_byte_string(s.as_bytes())
+    }
+
+    // Creates a Lua string from arbitrary bytes, which need not be valid UTF-8.
+    pub(crate) fn create_byte_string(&self, s: &[u8]) -> String<'_> {
         unsafe {
-            stack_guard(self.state, 0, move || {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 2);
+                ffi::lua_pushlstring(self.state, s.as_ptr() as *const c_char, s.len());
+                String(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Creates and returns a new table.
+    pub fn create_table(&self) -> Table<'_> {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 2);
+                ffi::lua_newtable(self.state);
+                Table(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Creates and returns a new table, with space preallocated for `narr` elements in its
+    /// sequence part and `nrec` other elements.
+    ///
+    /// Filling a table this size does not need to grow it, which saves repeated reallocations when
+    /// the number of elements is known in advance.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let squares = lua.create_table_with_capacity(1000, 0);
+    /// for i in 1..1001 {
+    ///     squares.raw_set(i, i * i)?;
+    /// }
+    /// assert_eq!(squares.raw_len(), 1000);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn create_table_with_capacity(&self, narr: usize, nrec: usize) -> Table<'_> {
+        unsafe {
+            stack_guard(self.state, 0, || {
                 check_stack(self.state, 2);
+                ffi::lua_createtable(self.state, table_size(narr), table_size(nrec));
+                Table(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Creates a table and fills it with values from an iterator.
+    ///
+    /// Space for the number of pairs given by the lower bound of the iterator's `size_hint` is
+    /// allocated up front.
+    pub fn create_table_from<'lua, K, V, I>(&'lua self, cont: I) -> Result<Table<'lua>>
+    where
+        K: ToLua<'lua>,
+        V: ToLua<'lua>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let cont = cont.into_iter();
+        unsafe {
+            stack_err_guard(self.state, 0, || {
+                check_stack(self.state, 3);
+                ffi::lua_createtable(self.state, 0, table_size(cont.size_hint().0));
+
+                for (k, v) in cont {
+                    self.push_value(self.state, k.to_lua(self)?);
+                    self.push_value(self.state, v.to_lua(self)?);
+                    ffi::lua_rawset(self.state, -3);
+                }
+                Ok(Table(self.pop_ref(self.state)))
+            })
+        }
+    }
+
+    /// Creates a table from an iterator of values, using `1..` as the keys.
+    ///
+    /// Space for the number of values given by the lower bound of the iterator's `size_hint` is
+    /// allocated up front.
+    pub fn create_sequence_from<'lua, T, I>(&'lua self, cont: I) -> Result<Table<'lua>>
+    where
+        T: ToLua<'lua>,
+        I: IntoIterator<Item = T>,
+    {
+        let cont = cont.into_iter();
+        unsafe {
+            stack_err_guard(self.state, 0, || {
+                check_stack(self.state, 3);
+                ffi::lua_createtable(self.state, table_size(cont.size_hint().0), 0);
+
+                for (i, v) in cont.enumerate() {
+                    self.push_value(self.state, v.to_lua(self)?);
+                    ffi::lua_rawseti(self.state, -2, i as Integer + 1);
+                }
+                Ok(Table(self.pop_ref(self.state)))
+            })
+        }
+    }
+
+    /// Creates a table holding a copy of a slice of numbers or strings, using `1..` as the keys.
+    ///
+    /// This is the same as [`create_sequence_from`], but much faster for large slices, as the
+    /// elements are stored into the table directly.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let samples = (0..1000).map(|i| i as f64 / 10.0).collect::<Vec<_>>();
+    /// lua.globals().set("samples", lua.create_sequence_from_slice(&samples))?;
+    ///
+    /// assert_eq!(lua.eval::<f64>("samples[#samples]", None)?, 99.9);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_sequence_from`]: #method.create_sequence_from
+    pub fn create_sequence_from_slice<T: SequenceItem>(&self, slice: &[T]) -> Table<'_> {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 3);
+                ffi::lua_createtable(self.state, table_size(slice.len()), 0);
+                for (i, item) in slice.iter().enumerate() {
+                    item.push_item(self.state);
+                    ffi::lua_rawseti(self.state, -2, i as Integer + 1);
+                }
+                Table(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Wraps a Rust function or closure, creating a callable Lua function handle to it.
+    ///
+    /// # Examples
+    ///
+    /// Create a function which prints its argument:
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let greet = lua.create_function(|_, name: String| {
+    ///     println!("Hello, {}!", name);
+    ///     Ok(())
+    /// });
+    /// # let _ = greet;    // used
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// Use tuples to accept multiple arguments:
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let print_person = lua.create_function(|_, (name, age): (String, u8)| {
+    ///     println!("{} is {} years old!", name, age);
+    ///     Ok(())
+    /// });
+    /// # let _ = print_person;    // used
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn create_function<'lua, A, R, F>(&'lua self, mut func: F) -> Function<'lua>
+    where
+        A: FromLuaMulti<'lua>,
+        R: ToLuaMulti<'lua>,
+        F: 'static + FnMut(&'lua Lua, A) -> Result<R>,
+    {
+        self.create_callback_function(
+            Box::new(move |lua, args| {
+                let args = A::from_lua_args(args, 1, lua)
+                    .map_err(|err| lua.name_bad_argument(err, false))?;
+                func(lua, args)?.to_lua_multi(lua)
+            }),
+            None,
+        )
+    }
+
+    /// Like [`create_function`], but panics in `func` are handled according to `mode` instead of
+    /// the mode set with [`set_panic_mode`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, PanicMode, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let plugin = lua.create_function_with_panic_mode(PanicMode::Catch, |_, ()| -> Result<()> {
+    ///     panic!("plugin bug")
+    /// });
+    /// lua.globals().set("plugin", plugin)?;
+    /// let message = lua.eval::<String>("select(2, pcall(plugin)).message", None)?;
+    /// assert_eq!(message, "callback panicked: plugin bug");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_function`]: #method.create_function
+    /// [`set_panic_mode`]: #method.set_panic_mode
+    pub fn create_function_with_panic_mode<'lua, A, R, F>(
+        &'lua self,
+        mode: PanicMode,
+        mut func: F,
+    ) -> Function<'lua>
+    where
+        A: FromLuaMulti<'lua>,
+        R: ToLuaMulti<'lua>,
+        F: 'static + FnMut(&'lua Lua, A) -> Result<R>,
+    {
+        self.create_callback_function(
+            Box::new(move |lua, args| {
+                let args = A::from_lua_args(args, 1, lua)
+                    .map_err(|err| lua.name_bad_argument(err, false))?;
+                func(lua, args)?.to_lua_multi(lua)
+            }),
+            Some(mode),
+        )
+    }
+
+    /// Wraps a plain Rust function pointer to be callable from Lua.
+    ///
+    /// This behaves like [`create_function`], but is considerably cheaper: as a function pointer
+    /// has no state, it is stored in the Lua function directly instead of in a heap allocated
+    /// callback and userdata. Prefer it for registering large numbers of API functions. Closures
+    /// which do not capture anything can be passed as well.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// fn add(_: &Lua, (a, b): (i64, i64)) -> Result<i64> {
+    ///     Ok(a + b)
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let globals = lua.globals();
+    /// globals.set("add", lua.create_function_ptr(add))?;
+    /// globals.set("neg", lua.create_function_ptr(|_, n: i64| Ok(-n)))?;
+    /// assert_eq!(lua.eval::<i64>("neg(add(1, 2))", None)?, -3);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_function`]: #method.create_function
+    pub fn create_function_ptr<'lua, A, R>(
+        &'lua self,
+        func: fn(&'lua Lua, A) -> Result<R>,
+    ) -> Function<'lua>
+    where
+        A: FromLuaMulti<'lua>,
+        R: ToLuaMulti<'lua>,
+    {
+        unsafe extern "C" fn fn_call_impl<'lua, A, R>(state: *mut ffi::lua_State) -> c_int
+        where
+            A: FromLuaMulti<'lua>,
+            R: ToLuaMulti<'lua>,
+        {
+            let catch_panic = || catches_panics(state, None);
+            let (nresults, yielding) = callback_error_catching(state, catch_panic, || {
+                let lua = Lua {
+                    state,
+                    main_state: main_state(state),
+                    ephemeral: true,
+                };
+                // Like for other callbacks, values created during the call are tied to the
+                // lifetime of the outer `Lua`, which outlives the call.
+                let lua = &*(&lua as *const Lua);
+
+                let func = mem::transmute::<*mut c_void, fn(&'lua Lua, A) -> Result<R>>(
+                    ffi::lua_touserdata(state, ffi::lua_upvalueindex(1)),
+                );
+
+                let nargs = ffi::lua_gettop(state);
+                call_callback(lua, nargs, |args| {
+                    let args = A::from_lua_args(args, 1, lua)
+                        .map_err(|err| lua.name_bad_argument(err, false))?;
+                    func(lua, args)?.to_lua_multi(lua)
+                })
+            });
+
+            // Rust frames have all been left at this point, so it is safe for lua_yield to longjmp
+            // out of this function.
+            callback_return(state, nresults, yielding, fn_resume::<A, R>)
+        }
+
+        unsafe extern "C" fn fn_resume<'lua, A, R>(
+            state: *mut ffi::lua_State,
+            _status: c_int,
+            _ctx: ffi::lua_KContext,
+        ) -> c_int
+        where
+            A: FromLuaMulti<'lua>,
+            R: ToLuaMulti<'lua>,
+        {
+            fn_call_impl::<A, R>(state)
+        }
+
+        unsafe {
+            stack_guard(self.state, 0, move || {
+                check_stack(self.state, 2);
+                ffi::lua_pushlightuserdata(self.state, func as *mut c_void);
+                ffi::lua_pushcclosure(self.state, fn_call_impl::<A, R>, 1);
+                Function(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Wraps a Lua function into a new thread (or coroutine).
+    ///
+    /// Equivalent to `coroutine.create`.
+    pub fn create_thread<'lua>(&'lua self, func: Function<'lua>) -> Thread<'lua> {
+        unsafe {
+            stack_guard(self.state, 0, move || {
+                check_stack(self.state, 2);
+
+                let extra = self.extra_data();
+                if let Some(index) = (*extra).thread_pool.pop() {
+                    let thread = LuaRef {
+                        lua: self,
+                        index,
+                    };
+                    self.push_ref(self.state, &thread);
+                    let thread_state = ffi::lua_tothread(self.state, -1);
+                    ffi::lua_pop(self.state, 1);
+                    // The thread may have been created before the current limits were set.
+                    set_limit_hook(thread_state);
+
+                    check_stack(thread_state, 1);
+                    self.push_ref(thread_state, &func.0);
+
+                    return Thread(thread);
+                }
+
+                let thread_state = ffi::lua_newthread(self.state);
+                self.push_ref(thread_state, &func.0);
+
+                Thread(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Sets how `Duration` values are converted to and from Lua numbers.
+    ///
+    /// By default, durations are represented as fractional seconds. With
+    /// [`DurationFormat::Milliseconds`], they are converted to whole milliseconds instead, which
+    /// suits scripts that work with integer timer APIs. This does not affect `SystemTime`, which is
+    /// always represented as seconds since the Unix epoch.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::time::Duration;
+    /// # use rlua::{DurationFormat, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.globals().set("timeout", Duration::from_millis(1500))?;
+    /// assert_eq!(lua.eval::<f64>("timeout", None)?, 1.5);
+    ///
+    /// lua.set_duration_format(DurationFormat::Milliseconds);
+    /// lua.globals().set("timeout", Duration::from_millis(1500))?;
+    /// assert_eq!(lua.eval::<i64>("timeout", None)?, 1500);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`DurationFormat::Milliseconds`]: enum.DurationFormat.html#variant.Milliseconds
+    pub fn set_duration_format(&self, format: DurationFormat) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+                (*self.extra_data()).duration_format = format;
+            })
+        }
+    }
+
+    // Returns the format set with `set_duration_format`.
+    pub(crate) fn duration_format(&self) -> DurationFormat {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+                (*self.extra_data()).duration_format
+            })
+        }
+    }
+
+    /// Forgets the type of the value a light userdata created from a [`TypedLightUserData`]
+    /// points to, so that converting a light userdata with this address back to a
+    /// `TypedLightUserData` fails.
+    ///
+    /// This should be called once the value is freed, so that scripts which kept the light
+    /// userdata cannot pass a dangling pointer back to Rust through a `TypedLightUserData`.
+    ///
+    /// [`TypedLightUserData`]: struct.TypedLightUserData.html
+    pub fn forget_light_userdata<T>(&self, ptr: *const T) {
+        unsafe {
+            (*self.extra_data())
+                .light_userdata_types
+                .remove(&(ptr as usize));
+        }
+    }
+
+    // Records the type of the value a light userdata points to, see `TypedLightUserData`.
+    pub(crate) fn tag_light_userdata(&self, ptr: *mut c_void, type_id: TypeId) {
+        unsafe {
+            (*self.extra_data())
+                .light_userdata_types
+                .insert(ptr as usize, type_id);
+        }
+    }
+
+    pub(crate) fn light_userdata_type(&self, ptr: *mut c_void) -> Option<TypeId> {
+        unsafe {
+            (*self.extra_data())
+                .light_userdata_types
+                .get(&(ptr as usize))
+                .cloned()
+        }
+    }
+
+    /// Sets what happens when a Rust callback called from Lua panics.
+    ///
+    /// By default ([`PanicMode::Resume`]), the panic unwinds through the Lua code without being
+    /// caught by it, and is resumed once it reaches the Rust code which called into Lua. This
+    /// suits programs which should stop on bugs, such as command line tools. With
+    /// [`PanicMode::Catch`], the panic is instead raised as an [`Error::CallbackPanic`], which Lua
+    /// code may catch, and which is otherwise returned to the caller like any other error. This
+    /// suits long-running programs which should keep running after a failing script.
+    ///
+    /// This applies to all functions and userdata methods created from Rust, except those created
+    /// with [`create_function_with_panic_mode`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Error, Lua, PanicMode, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.set_panic_mode(PanicMode::Catch);
+    ///
+    /// let divide = lua.create_function(|_, (a, b): (i64, i64)| Ok(a / b));
+    /// lua.globals().set("divide", divide)?;
+    /// match lua.exec::<()>("divide(1, 0)", None) {
+    ///     Err(Error::CallbackError { cause, .. }) => match *cause {
+    ///         Error::CallbackPanic(ref message) => assert!(message.contains("divide by zero")),
+    ///         ref err => panic!("unexpected error {:?}", err),
+    ///     },
+    ///     r => panic!("unexpected result {:?}", r),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`PanicMode::Resume`]: enum.PanicMode.html#variant.Resume
+    /// [`PanicMode::Catch`]: enum.PanicMode.html#variant.Catch
+    /// [`Error::CallbackPanic`]: enum.Error.html#variant.CallbackPanic
+    /// [`create_function_with_panic_mode`]: #method.create_function_with_panic_mode
+    pub fn set_panic_mode(&self, mode: PanicMode) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+                (*self.extra_data()).panic_mode = mode;
+            })
+        }
+    }
+
+    /// Sets how errors returned by Rust functions are shown to scripts, by `tostring` and the
+    /// `message` field of the error, replacing any formatter set before.
+    ///
+    /// By default, errors are shown as they are displayed in Rust. A formatter lets the host
+    /// translate messages, or hide details such as paths and the tracebacks held by
+    /// [`Error::CallbackError`] before script authors see them. Errors returned to Rust are not
+    /// affected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Error, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.set_error_formatter(|err| match *err {
+    ///     Error::RuntimeError(ref message) => format!("erreur : {}", message),
+    ///     _ => "erreur interne".to_owned(),
+    /// });
+    ///
+    /// let open = lua.create_function(|_, path: String| -> Result<()> {
+    ///     Err(Error::RuntimeError(format!("cannot open {}", path)))
+    /// });
+    /// lua.globals().set("open", open)?;
+    /// let message = lua.eval::<String>("tostring(select(2, pcall(open, 'save.dat')))", None)?;
+    /// assert_eq!(message, "erreur : cannot open save.dat");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Error::CallbackError`]: enum.Error.html#variant.CallbackError
+    pub fn set_error_formatter<F>(&self, formatter: F)
+    where
+        F: 'static + Fn(&Error) -> StdString,
+    {
+        unsafe {
+            (*self.extra_data()).error_formatter = Some(Box::new(formatter));
+        }
+    }
+
+    /// Removes the formatter set with [`set_error_formatter`], so that errors are shown to scripts
+    /// as they are displayed in Rust.
+    ///
+    /// [`set_error_formatter`]: #method.set_error_formatter
+    pub fn remove_error_formatter(&self) {
+        unsafe {
+            (*self.extra_data()).error_formatter = None;
+        }
+    }
+
+    /// Enables or disables strict numeric conversions.
+    ///
+    /// Converting Lua values to Rust integers always fails for numbers with a fractional part and
+    /// numbers out of range of the integer type. By default, strings are coerced to numbers as in
+    /// Lua, and conversions to floats may lose precision. With strict numeric conversions, numeric
+    /// types only accept Lua numbers, and conversions to floats fail if the number cannot be
+    /// represented exactly. Errors include the offending value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// assert_eq!(lua.eval::<i32>("'42'", None)?, 42);
+    /// assert!(lua.eval::<i32>("3.7", None).is_err());
+    ///
+    /// lua.set_strict_numbers(true);
+    /// assert!(lua.eval::<i32>("'42'", None).is_err());
+    /// assert!(lua.eval::<f32>("1e300", None).is_err());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn set_strict_numbers(&self, enabled: bool) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+                (*self.extra_data()).strict_numbers = enabled;
+            })
+        }
+    }
+
+    // Returns whether strict numeric conversions are enabled.
+    pub(crate) fn strict_numbers(&self) -> bool {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+                (*self.extra_data()).strict_numbers
+            })
+        }
+    }
+
+    /// Sets whether scripts may only use global variables which have been declared.
+    ///
+    /// When enabled, reading a global variable which is `nil` raises an error, unless the
+    /// variable has been declared, to catch misspelled variable names early. A global variable is
+    /// declared by assigning to it in the main chunk of a script, from Rust, or with
+    /// [`declare_global`]. Assigning to an undeclared global variable inside of a function raises
+    /// an error. Functions written in C or Rust can read any global variable. Disabled by default.
+    ///
+    /// Strict mode is implemented with the `__index` and `__newindex` metamethods of the globals
+    /// table, which call the metamethods that were set before it was enabled, such as those of
+    /// [`new_lazy`]. It stops working if scripts replace the metatable of the globals table.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.set_strict_globals(true);
+    /// lua.declare_global("config");
+    ///
+    /// lua.exec::<()>(r#"
+    ///     count = 0
+    ///     function increment()
+    ///         count = count + 1
+    ///     end
+    ///     increment()
+    ///     assert(config == nil)
+    /// "#, None)?;
+    ///
+    /// assert!(lua.exec::<()>("print(cuont)", None).is_err());
+    /// assert!(lua.exec::<()>("(function() total = 1 end)()", None).is_err());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`declare_global`]: #method.declare_global
+    /// [`new_lazy`]: #method.new_lazy
+    pub fn set_strict_globals(&self, enabled: bool) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 5);
+                if enabled {
+                    install_strict_globals(self.state);
+                }
+                (*self.extra_data()).strict_globals = enabled;
+            })
+        }
+    }
+
+    /// Sets limits on the resources scripts can use, replacing the previous limits.
+    ///
+    /// Setting a string length limit replaces `string.rep` and `table.concat` with versions which
+    /// raise an error instead of creating a string longer than the limit. Other ways of creating
+    /// strings, such as the `..` operator, can at most double the length of a string, and are
+    /// covered by the memory limit. The memory limit is enforced by the allocator of the state,
+    /// which refuses allocations exceeding it while Lua code runs, on any thread. Setting a pattern
+    /// step limit replaces `string.find`, `string.match`, `string.gmatch` and `string.gsub` with
+    /// versions which count the steps taken by a single call, since the instruction count does not
+    /// advance while a pattern is matched. The table entry limit counts the growth of tables
+    /// during each call into Lua made by the host, and is checked by the bundled Lua whenever a
+    /// table grows. Exceeding a limit raises a Lua error, which scripts can catch with `pcall`.
+    /// Rust callbacks are not limited, so that these errors cannot unwind through them, but the
+    /// memory and table entries they use are counted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, ResourceLimits, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.set_resource_limits(ResourceLimits {
+    ///     max_string_length: Some(1024 * 1024),
+    ///     max_memory: Some(16 * 1024 * 1024),
+    ///     max_pattern_steps: Some(1_000_000),
+    ///     max_table_entries: Some(100_000),
+    /// });
+    ///
+    /// assert!(lua.exec::<()>("local s = ('x'):rep(2^30)", None).is_err());
+    /// assert!(lua.exec::<()>("local t = {} for i = 1, 1e8 do t[i] = i end", None).is_err());
+    /// assert!(lua.exec::<()>("(('a'):rep(30000)):find('.-.-.-b')", None).is_err());
+    /// assert!(lua.exec::<()>("local t = {} for i = 1, 1e6 do t[i] = i end", None).is_err());
+    /// assert_eq!(lua.eval::<usize>("#('x'):rep(1000)", None)?, 1000);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn set_resource_limits(&self, limits: ResourceLimits) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 5);
+                let extra = self.extra_data();
+                (*extra).resource_limits = limits;
+                (*(*extra).allocator).max_memory = limits.max_memory;
+                (*(*extra).allocator).max_table_entries = limits.max_table_entries;
+                #[cfg(feature = "builtin-lua")]
+                {
+                    if limits.max_table_entries.is_some() {
+                        ffi::rlua_settablehook(
+                            self.main_state,
+                            Some(table_growth_hook),
+                            (*extra).allocator as *mut c_void,
+                        );
+                    } else {
+                        ffi::rlua_settablehook(self.main_state, None, ptr::null_mut());
+                    }
+                }
+                if limits.max_string_length.is_some() {
+                    install_string_limits(self.state);
+                }
+                if limits.max_pattern_steps.is_some() {
+                    self.install_pattern_limits();
+                }
+                set_limit_hook(self.main_state);
+            })
+        }
+    }
+
+    // Replaces the pattern matching functions of the `string` library with versions which check
+    // the number of steps they take against `ResourceLimits::max_pattern_steps`.
+    unsafe fn install_pattern_limits(&self) {
+        let functions = pattern::create_pattern_functions(self);
+        stack_guard(self.state, 0, || {
+            check_stack(self.state, 8);
+            // The library is reached through `package.loaded`, the `string` global and the
+            // `__index` field of the metatable of strings, which are usually the same table.
+            ffi::luaL_getsubtable(self.state, ffi::LUA_REGISTRYINDEX, cstr!("_LOADED"));
+            push_string(self.state, "string");
+            ffi::lua_rawget(self.state, -2);
+            ffi::lua_remove(self.state, -2);
+            ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+            push_string(self.state, "string");
+            ffi::lua_rawget(self.state, -2);
+            ffi::lua_remove(self.state, -2);
+            push_string(self.state, "");
+            if ffi::lua_getmetatable(self.state, -1) != 0 {
+                push_string(self.state, "__index");
+                ffi::lua_rawget(self.state, -2);
+                ffi::lua_remove(self.state, -2);
+            } else {
+                ffi::lua_pushnil(self.state);
+            }
+            ffi::lua_remove(self.state, -2);
+
+            let libraries = ffi::lua_gettop(self.state) - 2;
+            for (name, function) in functions {
+                self.push_ref(self.state, &function.0);
+                for library in libraries..libraries + 3 {
+                    if ffi::lua_istable(self.state, library) != 0 {
+                        push_string(self.state, name);
+                        ffi::lua_pushvalue(self.state, -2);
+                        ffi::lua_rawset(self.state, library);
+                    }
+                }
+                ffi::lua_pop(self.state, 1);
+            }
+            ffi::lua_pop(self.state, 3);
+        })
+    }
+
+    // Wraps a Rust function replacing one of the string library, so that it raises its errors as
+    // strings like the original.
+    pub(crate) fn raise_string_errors<'lua>(&'lua self, function: Function<'lua>) -> Function<'lua> {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+                self.push_ref(self.state, &function.0);
+                ffi::lua_pushcclosure(self.state, raise_string_errors, 1);
+                Function(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    // Returns `ResourceLimits::max_pattern_steps`, for the pattern matching functions.
+    pub(crate) fn pattern_step_limit(&self) -> Option<usize> {
+        unsafe { (*self.extra_data()).resource_limits.max_pattern_steps }
+    }
+
+    /// Attaches an instruction budget to the state, replacing the previous one.
+    ///
+    /// The budget is consumed by Lua code running on the main thread, or on coroutines created
+    /// after the budget has been set, across all calls into Lua. Once it is exhausted, scripts are
+    /// stopped with an error, which is raised again on every instruction so that `pcall` cannot be
+    /// used to keep running. The host can refill the budget through a clone of it to let scripts
+    /// run again.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Budget, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let budget = Budget::instructions(100_000);
+    /// lua.set_budget(budget.clone());
+    ///
+    /// lua.exec::<()>("for i = 1, 10000 do end", None)?;
+    /// assert!(budget.remaining() < 100_000);
+    /// assert!(lua.exec::<()>("while true do end", None).is_err());
+    /// assert_eq!(budget.remaining(), 0);
+    ///
+    /// budget.refill(100_000);
+    /// lua.exec::<()>("for i = 1, 100 do end", None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn set_budget(&self, budget: Budget) {
+        unsafe {
+            (*self.extra_data()).budget = Some(budget);
+            set_limit_hook(self.main_state);
+        }
+    }
+
+    /// Removes the budget attached with [`set_budget`], so that scripts can run without limit.
+    ///
+    /// [`set_budget`]: #method.set_budget
+    pub fn remove_budget(&self) {
+        unsafe {
+            (*self.extra_data()).budget = None;
+            set_limit_hook(self.main_state);
+        }
+    }
+
+    /// Attaches a watchdog to the state, replacing the previous one, so that the state is
+    /// interrupted once the deadline set with [`set_deadline`] has passed.
+    ///
+    /// The deadline applies to Lua code running on the main thread, or on coroutines created after
+    /// the watchdog has been attached. Once it has passed, scripts are stopped with an error, which
+    /// is raised again on every instruction so that `pcall` cannot be used to keep running, until
+    /// the state is given a new deadline or its deadline is cleared. See [`Watchdog`] for an
+    /// example.
+    ///
+    /// [`set_deadline`]: #method.set_deadline
+    /// [`Watchdog`]: struct.Watchdog.html
+    pub fn set_watchdog(&self, watchdog: Watchdog) {
+        unsafe {
+            let extra = self.extra_data();
+            (*extra).watchdog = Some(watchdog.watch((*extra).deadline));
+            set_limit_hook(self.main_state);
+        }
+    }
+
+    /// Detaches the watchdog attached with [`set_watchdog`], so that scripts can run without a
+    /// deadline.
+    ///
+    /// [`set_watchdog`]: #method.set_watchdog
+    pub fn remove_watchdog(&self) {
+        unsafe {
+            (*self.extra_data()).watchdog = None;
+            set_limit_hook(self.main_state);
+        }
+    }
+
+    /// Sets the time after which the watchdog attached with [`set_watchdog`] interrupts the
+    /// state, replacing the previous deadline.
+    ///
+    /// This also lets scripts run again once the state has been interrupted.
+    ///
+    /// [`set_watchdog`]: #method.set_watchdog
+    pub fn set_deadline(&self, deadline: Instant) {
+        self.replace_deadline(Some(deadline));
+    }
+
+    /// Clears the deadline set with [`set_deadline`], so that scripts can run without a time
+    /// limit, also once the state has been interrupted.
+    ///
+    /// [`set_deadline`]: #method.set_deadline
+    pub fn clear_deadline(&self) {
+        self.replace_deadline(None);
+    }
+
+    fn replace_deadline(&self, deadline: Option<Instant>) {
+        unsafe {
+            let extra = self.extra_data();
+            (*extra).deadline = deadline;
+            if let Some(ref watchdog) = (*extra).watchdog {
+                watchdog.set_deadline(deadline);
+                set_limit_hook(self.main_state);
+            }
+        }
+    }
+
+    /// Attaches a debugger to the state, replacing the previous one.
+    ///
+    /// The debugger stops Lua code running on the main thread, or on coroutines created after the
+    /// debugger has been attached. See [`Debugger`].
+    ///
+    /// [`Debugger`]: struct.Debugger.html
+    pub fn set_debugger(&self, debugger: Debugger) {
+        unsafe {
+            (*self.extra_data()).debugger = Some(debugger);
+            set_limit_hook(self.main_state);
+        }
+    }
+
+    /// Detaches the debugger attached with [`set_debugger`].
+    ///
+    /// [`set_debugger`]: #method.set_debugger
+    pub fn remove_debugger(&self) {
+        unsafe {
+            (*self.extra_data()).debugger = None;
+            set_limit_hook(self.main_state);
+        }
+    }
+
+    /// Sets a callback which is called when the garbage collector starts and finishes a cycle,
+    /// replacing any callback set before.
+    ///
+    /// This can be used to log collections, for example to correlate them with slow frames. The
+    /// callback runs while Lua allocates memory or collects garbage, so it must not use the Lua
+    /// state, and a panic in the callback aborts the process.
+    ///
+    /// Lua does not report collections itself, so the start of a cycle is detected when the memory
+    /// used by the state grows past the threshold the collector sets, and its end when the cycle
+    /// runs the finalizer of an object rlua keeps for this purpose. Both are approximations: the
+    /// collector works in small steps, and keeps freeing memory after the end of a cycle.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::cell::RefCell;
+    /// # use std::rc::Rc;
+    /// # use rlua::{GcEvent, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let reclaimed = Rc::new(RefCell::new(0));
+    /// let total = reclaimed.clone();
+    /// lua.on_gc(move |event| {
+    ///     if let GcEvent::End { reclaimed_bytes, .. } = event {
+    ///         *total.borrow_mut() += reclaimed_bytes;
+    ///     }
+    /// });
+    ///
+    /// lua.exec::<()>("for i = 1, 100000 do local t = { i } end", None)?;
+    /// assert!(*reclaimed.borrow() > 0);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn on_gc<F: 'static + FnMut(GcEvent)>(&self, callback: F) {
+        unsafe {
+            let allocator = &mut *(*self.extra_data()).allocator;
+            allocator.gc_generation += 1;
+            let mut gc = GcState {
+                callback: Box::new(callback),
+                generation: allocator.gc_generation,
+                pause: 0,
+                used_at_end: 0,
+                freed_at_end: 0,
+                started: None,
+            };
+            gc.end_cycle(self.state, allocator.used, allocator.freed);
+            allocator.gc = Some(gc);
+            push_gc_sentinel(self.state, allocator.gc_generation);
+            ffi::lua_pop(self.state, 1);
+        }
+    }
+
+    /// Removes the callback set with [`on_gc`].
+    ///
+    /// [`on_gc`]: #method.on_gc
+    pub fn remove_gc_callback(&self) {
+        unsafe {
+            (*(*self.extra_data()).allocator).gc = None;
+        }
+    }
+
+    /// Starts recording which lines of Lua code run, discarding what was recorded before.
+    ///
+    /// Lines are recorded on the main thread, and on coroutines created after recording has
+    /// started. Recording coverage makes Lua code run noticeably slower.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.start_coverage();
+    ///
+    /// lua.exec::<()>(r#"
+    ///     local function check(n)
+    ///         if n > 0 then
+    ///             return "positive"
+    ///         end
+    ///         return "negative"
+    ///     end
+    ///     check(1)
+    /// "#, Some("plugin.lua"))?;
+    ///
+    /// let coverage = lua.stop_coverage();
+    /// assert_eq!(coverage.hits("plugin.lua", 4), Some(1));
+    /// assert_eq!(coverage.hits("plugin.lua", 6), Some(0));
+    /// assert!(coverage.to_lcov().contains("SF:plugin.lua\nDA:3,1\n"));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn start_coverage(&self) {
+        unsafe {
+            (*self.extra_data()).coverage = Some(CoverageState::new());
+            set_limit_hook(self.main_state);
+        }
+    }
+
+    /// Returns the coverage recorded since [`start_coverage`], and stops recording.
+    ///
+    /// Returns an empty coverage if coverage was not being recorded.
+    ///
+    /// [`start_coverage`]: #method.start_coverage
+    pub fn stop_coverage(&self) -> Coverage {
+        unsafe {
+            let coverage = (*self.extra_data()).coverage.take();
+            set_limit_hook(self.main_state);
+            coverage.map_or_else(Coverage::default, |coverage| coverage.to_coverage())
+        }
+    }
+
+    /// Returns the coverage recorded since [`start_coverage`], and keeps recording.
+    ///
+    /// Returns an empty coverage if coverage is not being recorded.
+    ///
+    /// [`start_coverage`]: #method.start_coverage
+    pub fn coverage(&self) -> Coverage {
+        unsafe {
+            match (*self.extra_data()).coverage {
+                Some(ref coverage) => coverage.to_coverage(),
+                None => Coverage::default(),
+            }
+        }
+    }
+
+    /// Starts recording which lines of Lua code allocate memory, discarding what was recorded
+    /// before.
+    ///
+    /// Memory is charged to the line of Lua code which is running on the main thread, or on a
+    /// coroutine created after profiling has started, including the memory allocated by the
+    /// functions it calls until they run lines of their own. Profiling makes Lua code run
+    /// noticeably slower.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.start_memory_profile();
+    ///
+    /// lua.exec::<()>(r#"
+    ///     local small = {}
+    ///     local large = {}
+    ///     for i = 1, 1000 do large[i] = ("x"):rep(100) .. i end
+    /// "#, Some("script"))?;
+    ///
+    /// let profile = lua.stop_memory_profile();
+    /// assert_eq!(profile.sites[0].chunk, r#"[string "script"]"#);
+    /// assert_eq!(profile.sites[0].line, 4);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn start_memory_profile(&self) {
+        unsafe {
+            (*(*self.extra_data()).allocator).profile = Some(ProfileState::new());
+            set_limit_hook(self.main_state);
+        }
+    }
+
+    /// Returns what has been recorded since [`start_memory_profile`], and stops recording.
+    ///
+    /// Returns an empty profile if memory was not being profiled.
+    ///
+    /// [`start_memory_profile`]: #method.start_memory_profile
+    pub fn stop_memory_profile(&self) -> MemoryProfile {
+        unsafe {
+            let profile = (*(*self.extra_data()).allocator).profile.take();
+            set_limit_hook(self.main_state);
+            profile.map_or_else(MemoryProfile::default, |profile| profile.to_profile())
+        }
+    }
+
+    /// Returns what has been recorded since [`start_memory_profile`], and keeps recording.
+    ///
+    /// Returns an empty profile if memory is not being profiled.
+    ///
+    /// [`start_memory_profile`]: #method.start_memory_profile
+    pub fn memory_profile(&self) -> MemoryProfile {
+        unsafe {
+            match (*(*self.extra_data()).allocator).profile {
+                Some(ref profile) => profile.to_profile(),
+                None => MemoryProfile::default(),
+            }
+        }
+    }
+
+    /// Returns the number of bytes used by the Lua state.
+    pub fn used_memory(&self) -> usize {
+        unsafe { used_memory(self.state) }
+    }
+
+    /// Returns the number of references to Lua values held from Rust, and of other entries which
+    /// keep values alive.
+    ///
+    /// Each handle to a table, function, string, userdata or thread holds a reference, which keeps
+    /// the value from being collected until the handle is dropped. A count which keeps growing
+    /// while a long-running host is idle means handles are being leaked, such as by storing them
+    /// in a collection which is never cleared. In debug builds, [`set_ref_tracking`] helps to
+    /// find where the leaked handles were created.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let before = lua.ref_stats();
+    ///
+    /// let tables = (0..10).map(|_| lua.create_table()).collect::<Vec<_>>();
+    /// assert_eq!(lua.ref_stats().live_refs, before.live_refs + 10);
+    ///
+    /// drop(tables);
+    /// assert_eq!(lua.ref_stats().live_refs, before.live_refs);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set_ref_tracking`]: #method.set_ref_tracking
+    pub fn ref_stats(&self) -> RefStats {
+        unsafe {
+            let extra = self.extra_data();
+            let ref_thread = (*extra).ref_thread;
+            // The first slot of the reference thread always holds nil.
+            let stack_refs = ffi::lua_gettop(ref_thread) as usize - 1 - (*extra).ref_free.len();
+
+            let registry_len = stack_guard(self.state, 0, || {
+                check_stack(self.state, 3);
+                ffi::lua_pushvalue(self.state, ffi::LUA_REGISTRYINDEX);
+                ffi::lua_pushnil(self.state);
+                let mut len = 0;
+                while ffi::lua_next(self.state, -2) != 0 {
+                    ffi::lua_pop(self.state, 1);
+                    len += 1;
+                }
+                ffi::lua_pop(self.state, 1);
+                len
+            });
+
+            RefStats {
+                live_refs: stack_refs + (*extra).registry_refs,
+                live_callbacks: (*extra).live_callbacks,
+                registry_len,
+            }
+        }
+    }
+
+    /// Starts or stops recording where references to Lua values are created, so that
+    /// [`tracked_refs`] can tell where the handles which are still alive come from.
+    ///
+    /// Recording takes a backtrace for every handle created, which makes creating handles much
+    /// slower, so this is meant to be enabled while looking for a leak. Stopping discards what was
+    /// recorded. Only available in debug builds.
+    ///
+    /// [`tracked_refs`]: #method.tracked_refs
+    #[cfg(debug_assertions)]
+    pub fn set_ref_tracking(&self, enabled: bool) {
+        unsafe {
+            let extra = self.extra_data();
+            if !enabled {
+                (*extra).ref_origins = None;
+            } else if (*extra).ref_origins.is_none() {
+                (*extra).ref_origins = Some(HashMap::new());
+            }
+        }
+    }
+
+    /// Returns the backtraces of the places which created the references that are still alive,
+    /// among those created since [`set_ref_tracking`] was enabled.
+    ///
+    /// References created and dropped by rlua itself during a call do not appear, as they are
+    /// dropped by the time this can be called. Only available in debug builds.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.set_ref_tracking(true);
+    ///
+    /// let leaked = lua.create_table();
+    /// let origins = lua.tracked_refs();
+    /// assert_eq!(origins.len(), 1);
+    ///
+    /// drop(leaked);
+    /// assert!(lua.tracked_refs().is_empty());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set_ref_tracking`]: #method.set_ref_tracking
+    #[cfg(debug_assertions)]
+    pub fn tracked_refs(&self) -> Vec<StdString> {
+        unsafe {
+            match (*self.extra_data()).ref_origins {
+                Some(ref origins) => origins.values().map(|origin| origin.to_string()).collect(),
+                None => Vec::new(),
+            }
+        }
+    }
+
+    /// Declares a global variable for strict mode, so that scripts can read it while it is `nil`,
+    /// and assign to it from any function.
+    ///
+    /// See [`set_strict_globals`].
+    ///
+    /// [`set_strict_globals`]: #method.set_strict_globals
+    pub fn declare_global(&self, name: &str) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 3);
+                push_declared_globals(self.state);
+                push_string(self.state, name);
+                ffi::lua_pushboolean(self.state, 1);
+                ffi::lua_rawset(self.state, -3);
+                ffi::lua_pop(self.state, 1);
+            })
+        }
+    }
+
+    /// Sets a callback which is called for every read and assignment of a global variable by Lua
+    /// code, replacing any callback set before.
+    ///
+    /// This can be used to log which globals and host functions the scripts of a plugin use. The
+    /// callback is passed the name of the variable, whether it was read or assigned, and the
+    /// source and line of the Lua function which accessed it. Accesses made by functions written
+    /// in C or Rust, and through [`globals`], are not reported. A panic in the callback is
+    /// propagated through the Lua code which accessed the variable.
+    ///
+    /// Accesses are intercepted by an empty proxy of the globals table, which takes its place as
+    /// the environment of chunks loaded afterwards, and as the value of `_G`. Chunks loaded before
+    /// keep using the globals table itself. `pairs` iterates over the globals through the proxy,
+    /// but the raw functions `rawget`, `rawset`, `rawlen` and `next` see an empty table, and the
+    /// metatable of the proxy cannot be replaced.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::cell::RefCell;
+    /// # use std::rc::Rc;
+    /// # use rlua::{AccessKind, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let accesses = Rc::new(RefCell::new(Vec::new()));
+    /// let log = accesses.clone();
+    /// lua.audit_globals(move |access| log.borrow_mut().push(access));
+    ///
+    /// lua.exec::<()>("greeting = string.upper('hi')", Some("plugin"))?;
+    ///
+    /// let accesses = accesses.borrow();
+    /// assert_eq!(accesses[0].name, "string");
+    /// assert_eq!(accesses[0].kind, AccessKind::Read);
+    /// assert_eq!(accesses[1].name, "greeting");
+    /// assert_eq!(accesses[1].kind, AccessKind::Write);
+    /// assert_eq!(accesses[1].line, Some(1));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`globals`]: #method.globals
+    pub fn audit_globals<F: 'static + FnMut(GlobalAccess)>(&self, callback: F) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 7);
+                install_globals_proxy(self.state);
+                (*self.extra_data()).global_audit = Some(Box::new(callback));
+            })
+        }
+    }
+
+    /// Removes the callback set with [`audit_globals`], and puts the globals table back in place
+    /// of its proxy.
+    ///
+    /// [`audit_globals`]: #method.audit_globals
+    pub fn remove_global_audit(&self) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 2);
+                remove_globals_proxy(self.state);
+                (*self.extra_data()).global_audit = None;
+            })
+        }
+    }
+
+    /// Sets a callback which is called for every call to `require`, replacing any callback set
+    /// before.
+    ///
+    /// This can be used to log the modules each plugin depends on, or to find out why a module
+    /// was not found. The callback is passed the name of the module, how it was resolved, the
+    /// path of the file it is loaded from, and the source and line of the call to `require`.
+    /// It is called before the module is loaded, so that the modules a module requires are
+    /// reported after it. A panic in the callback is propagated through the call to `require`.
+    ///
+    /// `require` is replaced with a version which searches `package.searchers` and fills in
+    /// `package.loaded` like the original. The package library is opened first in states
+    /// created by [`new_lazy`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::cell::RefCell;
+    /// # use std::rc::Rc;
+    /// # use rlua::{Lua, MemoryVfs, RequireResolver, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let files = MemoryVfs::new();
+    /// files.insert("util.lua", b"return {}".to_vec());
+    /// lua.set_vfs(files)?;
+    ///
+    /// let traces = Rc::new(RefCell::new(Vec::new()));
+    /// let log = traces.clone();
+    /// lua.trace_require(move |trace| log.borrow_mut().push(trace));
+    ///
+    /// lua.exec::<()>("require('util'); require('util')", None)?;
+    /// assert!(lua.exec::<()>("require('missing')", None).is_err());
+    ///
+    /// let traces = traces.borrow();
+    /// assert_eq!(traces[0].resolver, RequireResolver::Searcher(2));
+    /// assert_eq!(traces[0].path, Some("./util.lua".to_owned()));
+    /// assert_eq!(traces[1].resolver, RequireResolver::Loaded);
+    /// match traces[2].resolver {
+    ///     RequireResolver::NotFound(ref reasons) => assert!(reasons.contains("./missing.lua")),
+    ///     ref resolver => panic!("unexpected resolver {:?}", resolver),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`new_lazy`]: #method.new_lazy
+    pub fn trace_require<F: 'static + FnMut(RequireTrace)>(&self, callback: F) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 6);
+                install_traced_require(self.state);
+                (*self.extra_data()).require_trace = Some(Box::new(callback));
+            })
+        }
+    }
+
+    /// Removes the callback set with [`trace_require`].
+    ///
+    /// [`trace_require`]: #method.trace_require
+    pub fn remove_require_trace(&self) {
+        unsafe {
+            (*self.extra_data()).require_trace = None;
+        }
+    }
+
+    /// Sets the maximum number of finished threads that are kept around for reuse.
+    ///
+    /// Threads are placed into the pool with [`recycle_thread`], as long as it holds fewer than
+    /// `size` threads. [`create_thread`] takes threads from this pool before allocating new ones,
+    /// which makes thread creation cheap for workloads that run many short-lived coroutines.
+    ///
+    /// The pool is disabled by default (a size of 0).
+    ///
+    /// [`recycle_thread`]: #method.recycle_thread
+    /// [`create_thread`]: #method.create_thread
+    pub fn set_thread_pool_size(&self, size: usize) {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+
+                let extra = self.extra_data();
+                (*extra).thread_pool_size = size;
+                while (*extra).thread_pool.len() > size {
+                    let index = (*extra).thread_pool.pop().unwrap();
+                    self.drop_ref(index);
+                }
+            })
+        }
+    }
+
+    /// Places a thread which has finished executing into the pool set up with
+    /// [`set_thread_pool_size`], for [`create_thread`] to restart it with another function.
+    ///
+    /// Returns `false` and releases the thread as usual if it has not returned from its function,
+    /// or if the pool is full.
+    ///
+    /// # Safety
+    ///
+    /// `thread` must be the only reference to the thread. There must be no other [`Thread`]
+    /// handles to it, and it must not be reachable from Lua, such as through a value of
+    /// `coroutine.running()` kept by the coroutine itself. Those references would observe the
+    /// thread being restarted with an unrelated function.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Function, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.set_thread_pool_size(16);
+    ///
+    /// let double: Function = lua.eval("function(n) return n * 2 end", None)?;
+    /// for i in 0..100 {
+    ///     let thread = lua.create_thread(double.clone());
+    ///     assert_eq!(thread.resume::<_, i64>(i)?, i * 2);
+    ///     assert!(unsafe { lua.recycle_thread(thread) });
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set_thread_pool_size`]: #method.set_thread_pool_size
+    /// [`create_thread`]: #method.create_thread
+    /// [`Thread`]: struct.Thread.html
+    pub unsafe fn recycle_thread<'lua>(&'lua self, thread: Thread<'lua>) -> bool {
+        let mut thread = thread.0;
+        stack_guard(self.state, 0, || {
+            check_stack(self.state, 1);
+
+            let extra = self.extra_data();
+            if (*extra).thread_pool.len() >= (*extra).thread_pool_size {
+                return false;
+            }
+
+            self.push_ref(self.state, &thread);
+            let thread_state = ffi::lua_tothread(self.state, -1);
+            ffi::lua_pop(self.state, 1);
+
+            let mut ar: ffi::lua_Debug = mem::zeroed();
+            if ffi::lua_status(thread_state) == ffi::LUA_OK
+                && ffi::lua_gettop(thread_state) == 0
+                && ffi::lua_getstack(thread_state, 0, &mut ar) == 0
+            {
+                (*extra).thread_pool.push(thread.index);
+                thread.index = RefIndex::Nil;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Create a Lua userdata object from a custom userdata type.
+    pub fn create_userdata<T>(&self, data: T) -> AnyUserData<'_>
+    where
+        T: UserData,
+    {
+        unsafe {
+            stack_guard(self.state, 0, move || {
+                check_stack(self.state, 3);
+
+                push_userdata::<RefCell<T>>(self.state, RefCell::new(data));
+
+                ffi::lua_rawgeti(
+                    self.state,
+                    ffi::LUA_REGISTRYINDEX,
+                    self.userdata_metatable::<T>() as ffi::lua_Integer,
+                );
+
+                ffi::lua_setmetatable(self.state, -2);
+
+                AnyUserData(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Creates a channel through which scripts send values to Rust.
+    ///
+    /// Returns a [`ChannelSender`] userdata to pass to Lua, and the receiving half of the channel.
+    /// Scripts call `sender:send(value)` to convert a value to `T` and send it. The receiver is
+    /// `Send`, so values can be consumed on another thread than the one running the script.
+    ///
+    /// The channel holds up to `capacity` values, after which `send` waits until a value has been
+    /// received. With a `capacity` of 0, every `send` waits for a matching receive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # use std::thread;
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let (sender, receiver) = lua.create_channel::<String>(16);
+    ///
+    /// let consumer = thread::spawn(move || receiver.iter().collect::<Vec<_>>());
+    ///
+    /// lua.globals().set("results", sender)?;
+    /// lua.exec::<()>(r#"
+    ///     for i = 1, 3 do
+    ///         results:send("item " .. i)
+    ///     end
+    ///     results:close()
+    /// "#, None)?;
+    ///
+    /// assert_eq!(consumer.join().unwrap(), vec!["item 1", "item 2", "item 3"]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`ChannelSender`]: struct.ChannelSender.html
+    pub fn create_channel<T>(&self, capacity: usize) -> (AnyUserData<'_>, Receiver<T>)
+    where
+        T: 'static + Send + for<'lua> FromLua<'lua>,
+    {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        (self.create_userdata(ChannelSender(Some(sender))), receiver)
+    }
+
+    /// Creates a channel through which Rust sends values to scripts.
+    ///
+    /// This is the reverse of [`create_channel`]: it returns the sending half of the channel, and
+    /// a [`ChannelReceiver`] userdata to pass to Lua. Scripts call `receiver:recv()` to wait for
+    /// the next value, which is converted to a Lua value, or `receiver:try_recv()` to poll for
+    /// one. Both return `nil` when no more values can be received.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # use std::thread;
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let (sender, receiver) = lua.create_reverse_channel::<i64>(4);
+    ///
+    /// thread::spawn(move || {
+    ///     for i in 1..11 {
+    ///         sender.send(i).unwrap();
+    ///     }
+    /// });
+    ///
+    /// lua.globals().set("jobs", receiver)?;
+    /// let sum = lua.eval::<i64>(r#"
+    ///     local sum = 0
+    ///     for job in jobs.recv, jobs do
+    ///         sum = sum + job
+    ///     end
+    ///     return sum
+    /// "#, None)?;
+    /// assert_eq!(sum, 55);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`create_channel`]: #method.create_channel
+    /// [`ChannelReceiver`]: struct.ChannelReceiver.html
+    pub fn create_reverse_channel<T>(&self, capacity: usize) -> (SyncSender<T>, AnyUserData<'_>)
+    where
+        T: 'static + Send + for<'lua> ToLua<'lua>,
+    {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        (sender, self.create_userdata(ChannelReceiver(receiver)))
+    }
+
+    /// Creates a Lua userdata object from a custom userdata type that can be serialized.
+    ///
+    /// Userdata created this way is serialized as the wrapped value when serializing an
+    /// [`AnyUserData`] with `serde`, instead of failing. Once a value of type `T` has been created
+    /// with this method, all userdata of type `T` can be serialized.
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// [`AnyUserData`]: struct.AnyUserData.html
+    #[cfg(feature = "serde")]
+    pub fn create_ser_userdata<T>(&self, data: T) -> AnyUserData<'_>
+    where
+        T: UserData + ::serde::Serialize,
+    {
+        unsafe {
+            stack_guard(self.state, 0, move || {
+                check_stack(self.state, 1);
+
+                ffi::lua_rawgeti(
+                    self.state,
+                    ffi::LUA_REGISTRYINDEX,
+                    self.userdata_metatable::<T>() as ffi::lua_Integer,
+                );
+                let metatable = ffi::lua_topointer(self.state, -1);
+                ffi::lua_pop(self.state, 1);
+
+                let extra = self.extra_data();
+                (*extra)
+                    .userdata_serializers
+                    .insert(metatable as usize, serialize::serialize_userdata::<T>);
+            });
+        }
+
+        self.create_userdata(data)
+    }
+
+    /// Creates the metatable for userdata of type `T` ahead of time.
+    ///
+    /// The metatable of a [`UserData`] type, including a function for each of its methods, is
+    /// otherwise created when the first userdata of that type is passed to Lua. Registering types
+    /// up front moves this cost out of the code which first uses them, for example into the setup
+    /// of a pool of `Lua` states. Does nothing if the metatable has already been created.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, UserData, UserDataMethods};
+    /// # fn try_main() -> Result<()> {
+    /// struct Vec2(f64, f64);
+    ///
+    /// impl UserData for Vec2 {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.add_method("length", |_, v, ()| Ok((v.0 * v.0 + v.1 * v.1).sqrt()));
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// lua.register_userdata::<Vec2>();
+    ///
+    /// lua.globals().set("v", Vec2(3.0, 4.0))?;
+    /// assert_eq!(lua.eval::<f64>("v:length()", None)?, 5.0);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`UserData`]: trait.UserData.html
+    pub fn register_userdata<T: UserData>(&self) {
+        unsafe {
+            self.userdata_metatable::<T>();
+        }
+    }
+
+    /// Registers methods for userdata of type `T`, which does not need to implement [`UserData`].
+    ///
+    /// This allows binding types from other crates, which cannot implement [`UserData`] due to the
+    /// orphan rule. Values of the type can then be passed to Lua with [`create_any_userdata`] and
+    /// accessed through [`AnyUserData`] as usual.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RuntimeError` if methods for `T` have already been registered, or if `T`
+    /// implements [`UserData`] and a userdata of that type has already been created.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # use std::time::Duration;
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// lua.register_userdata_type::<Duration, _>(|methods| {
+    ///     methods.add_method("seconds", |_, duration, ()| Ok(duration.as_secs()));
+    /// })?;
+    ///
+    /// let timeout = lua.create_any_userdata(Duration::from_secs(30))?;
+    /// lua.globals().set("timeout", timeout)?;
+    /// assert_eq!(lua.eval::<u64>("timeout:seconds()", None)?, 30);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`UserData`]: trait.UserData.html
+    /// [`AnyUserData`]: struct.AnyUserData.html
+    /// [`create_any_userdata`]: #method.create_any_userdata
+    pub fn register_userdata_type<'lua, T, F>(&'lua self, f: F) -> Result<()>
+    where
+        T: 'static,
+        F: FnOnce(&mut UserDataMethods<'lua, T>),
+    {
+        unsafe {
+            if self.registered_userdata_metatable::<T>().is_some() {
+                return Err(Error::RuntimeError(
+                    "userdata type is already registered".to_owned(),
+                ));
+            }
+
+            let mut methods = UserDataMethods::new();
+            f(&mut methods);
+            self.register_userdata_metatable(methods);
+            Ok(())
+        }
+    }
+
+    /// Create a Lua userdata object from a value of a type registered with
+    /// [`register_userdata_type`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RuntimeError` if no methods have been registered for `T`.
+    ///
+    /// [`register_userdata_type`]: #method.register_userdata_type
+    pub fn create_any_userdata<T: 'static>(&self, data: T) -> Result<AnyUserData<'_>> {
+        unsafe {
+            let table_id = match self.registered_userdata_metatable::<T>() {
+                Some(table_id) => table_id,
+                None => {
+                    return Err(Error::RuntimeError(
+                        "userdata type is not registered".to_owned(),
+                    ))
+                }
+            };
+
+            stack_guard(self.state, 0, move || {
+                check_stack(self.state, 3);
+
+                push_userdata::<RefCell<T>>(self.state, RefCell::new(data));
+                ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, table_id as ffi::lua_Integer);
+                ffi::lua_setmetatable(self.state, -2);
+
+                Ok(AnyUserData(self.pop_ref(self.state)))
+            })
+        }
+    }
+
+    /// Returns the table holding the regular methods of the userdata type `T`.
+    ///
+    /// This is the table that method lookups on userdata of type `T` go through, so it can be
+    /// exposed to Lua to make functions registered with [`UserDataMethods::add_function`] callable
+    /// without an instance, for example as constructors. Functions added to the table from Lua also
+    /// become available as methods.
+    ///
+    /// Returns `None` if `T` does not define any regular methods.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, UserData, UserDataMethods, Result};
+    /// # fn try_main() -> Result<()> {
+    /// struct Vec2(f64, f64);
+    ///
+    /// impl UserData for Vec2 {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.add_function("new", |_, (x, y)| Ok(Vec2(x, y)));
+    ///         methods.add_method("length", |_, v, ()| Ok((v.0 * v.0 + v.1 * v.1).sqrt()));
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// lua.globals().set("Vec2", lua.userdata_methods::<Vec2>().unwrap())?;
+    ///
+    /// assert_eq!(lua.eval::<f64>("Vec2.new(3, 4):length()", None)?, 5.0);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`UserDataMethods::add_function`]: struct.UserDataMethods.html#method.add_function
+    pub fn userdata_methods<T: UserData>(&self) -> Option<Table<'_>> {
+        unsafe {
+            stack_guard(self.state, 0, move || {
+                check_stack(self.state, 3);
+
+                ffi::lua_rawgeti(
+                    self.state,
+                    ffi::LUA_REGISTRYINDEX,
+                    self.userdata_metatable::<T>() as ffi::lua_Integer,
+                );
+                replace_with_methods_table(self.state);
+
+                if ffi::lua_istable(self.state, -1) != 0 {
+                    Some(Table(self.pop_ref(self.state)))
+                } else {
+                    ffi::lua_pop(self.state, 1);
+                    None
+                }
+            })
+        }
+    }
+
+    /// Creates a class table for the userdata type `T`.
+    ///
+    /// The returned table gives access to the regular methods of `T`, including the functions
+    /// registered with [`UserDataMethods::add_function`], so that it can be set as a global and
+    /// used by scripts to call constructors and other static functions. Unlike the table returned
+    /// by [`userdata_methods`], assigning fields on the proxy does not affect the methods of `T`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, UserData, UserDataMethods, Result};
+    /// # fn try_main() -> Result<()> {
+    /// struct Player {
+    ///     name: String,
+    /// }
+    ///
+    /// impl UserData for Player {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.add_function("new", |_, name| Ok(Player { name }));
+    ///         methods.add_method("name", |_, player, ()| Ok(player.name.clone()));
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// lua.globals().set("Player", lua.create_proxy::<Player>())?;
+    ///
+    /// let name = lua.eval::<String>(r#"
+    ///     local p = Player.new("bob")
+    ///     return p:name()
+    /// "#, None)?;
+    /// assert_eq!(name, "bob");
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`UserDataMethods::add_function`]: struct.UserDataMethods.html#method.add_function
+    /// [`userdata_methods`]: #method.userdata_methods
+    pub fn create_proxy<T: UserData>(&self) -> Table<'_> {
+        let proxy = self.create_table();
+        if let Some(methods) = self.userdata_methods::<T>() {
+            let metatable = self.create_table();
+            metatable
+                .raw_set("__index", methods)
+                .expect("could not set proxy __index");
+            proxy.set_metatable(Some(metatable));
+        }
+        proxy
+    }
+
+    /// Creates a table whose contents are kept in Rust by `backend`.
+    ///
+    /// Indexing the table, assigning to it, taking its length with `#` and iterating over it with
+    /// `pairs` or `ipairs` call the [`TableBackend`], so that collections such as component
+    /// stores or database rows can be exposed to scripts without copying them into Lua. Keys
+    /// which cannot be converted to the key type of the backend have no value, and assigning to
+    /// them raises an error. The table itself stays empty: `rawget`, `rawset` and `next` do not
+    /// see the contents of the backend, and its metatable cannot be replaced.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::cell::RefCell;
+    /// # use std::rc::Rc;
+    /// # use rlua::{Error, Lua, Result, TableBackend};
+    /// # fn try_main() -> Result<()> {
+    /// struct Positions(Rc<RefCell<Vec<f64>>>);
+    ///
+    /// impl TableBackend for Positions {
+    ///     type Key = usize;
+    ///     type Value = f64;
+    ///
+    ///     fn get(&self, key: usize) -> Result<Option<f64>> {
+    ///         Ok(key.checked_sub(1).and_then(|i| self.0.borrow().get(i).cloned()))
+    ///     }
+    ///
+    ///     fn set(&mut self, key: usize, value: Option<f64>) -> Result<()> {
+    ///         let mut positions = self.0.borrow_mut();
+    ///         match (key.checked_sub(1).and_then(|i| positions.get_mut(i)), value) {
+    ///             (Some(position), Some(value)) => {
+    ///                 *position = value;
+    ///                 Ok(())
+    ///             }
+    ///             _ => Err(Error::RuntimeError("no such entity".to_owned())),
+    ///         }
+    ///     }
+    ///
+    ///     fn len(&self) -> usize {
+    ///         self.0.borrow().len()
+    ///     }
+    ///
+    ///     fn iter(&self) -> Box<dyn Iterator<Item = (usize, f64)>> {
+    ///         let positions = self.0.borrow().clone();
+    ///         Box::new(positions.into_iter().enumerate().map(|(i, x)| (i + 1, x)))
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let positions = Rc::new(RefCell::new(vec![0.0, 1.5, 3.0]));
+    /// let table = lua.create_proxy_table(Positions(positions.clone()))?;
+    /// lua.globals().set("positions", table)?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     for i, x in ipairs(positions) do
+    ///         positions[i] = x + 1
+    ///     end
+    ///     assert(#positions == 3)
+    /// "#, None)?;
+    /// assert_eq!(*positions.borrow(), vec![1.0, 2.5, 4.0]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`TableBackend`]: trait.TableBackend.html
+    pub fn create_proxy_table<B: 'static + TableBackend>(&self, backend: B) -> Result<Table<'_>> {
+        proxy::create_proxy_table(self, backend)
+    }
+
+    /// Creates a table whose contents are loaded by `loader` the first time it is used.
+    ///
+    /// The table starts out empty. The first time a script indexes it, assigns to it, takes its
+    /// length or iterates over it with `pairs`, `loader` is called, and the contents and the
+    /// metatable of the table it returns are copied into the lazy table, which behaves like it
+    /// from then on. This avoids the cost of building large parts of an API which scripts may not
+    /// use. Errors returned by `loader` are raised in the script which used the table, and using
+    /// the table again raises an error as well. `rawget`, `rawset` and `next` do not load the
+    /// table.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::cell::Cell;
+    /// # use std::rc::Rc;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let loaded = Rc::new(Cell::new(false));
+    /// let set_loaded = loaded.clone();
+    /// let locale = lua.create_lazy_table(move |lua| {
+    ///     set_loaded.set(true);
+    ///     lua.create_table_from(vec![("hello", "bonjour"), ("bye", "au revoir")])
+    /// })?;
+    /// lua.globals().set("locale", locale)?;
+    ///
+    /// assert!(!loaded.get());
+    /// assert_eq!(lua.eval::<String>("locale.hello", None)?, "bonjour");
+    /// assert!(loaded.get());
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn create_lazy_table<F>(&self, loader: F) -> Result<Table<'_>>
+    where
+        F: 'static + FnOnce(&Lua) -> Result<Table>,
+    {
+        proxy::create_lazy_table(self, loader)
+    }
+
+    /// Returns a handle to the global environment.
+    pub fn globals(&self) -> Table<'_> {
+        unsafe {
+            stack_guard(self.state, 0, move || {
+                check_stack(self.state, 2);
+                push_globals(self.state);
+                Table(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Returns the thread running the code which calls this method.
+    ///
+    /// In a Rust callback, this is the coroutine which called the callback, or the main thread if
+    /// it was not called from a coroutine. Outside of callbacks, this is the main thread. Like
+    /// `coroutine.running`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Thread};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let where_am_i = lua.create_function(|lua, ()| {
+    ///     let current = lua.current_thread();
+    ///     Ok((lua.is_main_thread(), current))
+    /// });
+    /// lua.globals().set("where_am_i", where_am_i)?;
+    ///
+    /// let (main, _): (bool, Thread) = lua.eval("where_am_i()", None)?;
+    /// assert!(main);
+    ///
+    /// let (main, current): (bool, bool) = lua.eval(r#"
+    ///     coroutine.wrap(function()
+    ///         local main, current = where_am_i()
+    ///         return main, current == coroutine.running()
+    ///     end)()
+    /// "#, None)?;
+    /// assert!(!main);
+    /// assert!(current);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn current_thread(&self) -> Thread<'_> {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+                ffi::lua_pushthread(self.state);
+                Thread(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Returns the main thread of the state, which runs the code not running in a coroutine.
+    ///
+    /// The status of the main thread is always [`ThreadStatus::Running`], and it cannot be
+    /// resumed.
+    ///
+    /// [`ThreadStatus::Running`]: enum.ThreadStatus.html#variant.Running
+    pub fn main_thread(&self) -> Thread<'_> {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 1);
+                ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_MAINTHREAD);
+                Thread(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Returns true if the code calling this method runs in the main thread rather than in a
+    /// coroutine.
+    ///
+    /// See [`current_thread`].
+    ///
+    /// [`current_thread`]: #method.current_thread
+    pub fn is_main_thread(&self) -> bool {
+        self.state == self.main_state
+    }
+
+    /// Returns true if the thread running the code calling this method can yield, such as with
+    /// [`Yielder`].
+    ///
+    /// The main thread cannot yield, and neither can a coroutine while it runs a function called
+    /// by Rust code, such as with [`Function::call`], or a function called by `pcall`. Like
+    /// `coroutine.isyieldable`.
+    ///
+    /// [`Yielder`]: struct.Yielder.html
+    /// [`Function::call`]: struct.Function.html#method.call
+    pub fn is_yieldable(&self) -> bool {
+        unsafe { ffi::lua_isyieldable(self.state) != 0 }
+    }
+
+    /// Calls `f` with a [`Context`] to use the state with.
+    ///
+    /// The handles created in the closure cannot be returned from it, as their lifetime is only
+    /// known inside of the closure. This keeps code which stores the `Lua` state in a struct, such
+    /// as a game or a server owning its scripts, from also storing handles borrowing the state in
+    /// the same struct, which the borrow checker rejects. Such a struct stores [`RegistryKey`]s
+    /// instead, which the closure turns into handles with [`registry_value`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Function, Lua, RegistryKey, Result};
+    /// struct Plugin {
+    ///     lua: Lua,
+    ///     on_tick: RegistryKey,
+    /// }
+    ///
+    /// impl Plugin {
+    ///     fn load(source: &str) -> Result<Plugin> {
+    ///         let lua = Lua::new();
+    ///         let on_tick = lua.context(|ctx| {
+    ///             let on_tick: Function = ctx.eval(source, None)?;
+    ///             ctx.create_registry_value(on_tick)
+    ///         })?;
+    ///         Ok(Plugin { lua: lua, on_tick: on_tick })
+    ///     }
+    ///
+    ///     fn tick(&self, n: i64) -> Result<i64> {
+    ///         self.lua.context(|ctx| {
+    ///             ctx.registry_value::<Function>(&self.on_tick)?.call(n)
+    ///         })
+    ///     }
+    /// }
+    ///
+    /// # fn try_main() -> Result<()> {
+    /// let plugin = Plugin::load("function(n) return n * 2 end")?;
+    /// assert_eq!(plugin.tick(21)?, 42);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Context`]: struct.Context.html
+    /// [`RegistryKey`]: struct.RegistryKey.html
+    /// [`registry_value`]: #method.registry_value
+    pub fn context<F, R>(&self, f: F) -> R
+    where
+        F: for<'lua> FnOnce(Context<'lua>) -> R,
+    {
+        f(Context::new(self))
+    }
+
+    /// Stores a value in the Lua registry, and returns the key to get it back with
+    /// [`registry_value`].
+    ///
+    /// The key keeps the value alive until it is passed to [`remove_registry_value`] or dropped,
+    /// but it does not borrow the state. See [`context`].
+    ///
+    /// [`registry_value`]: #method.registry_value
+    /// [`remove_registry_value`]: #method.remove_registry_value
+    /// [`context`]: #method.context
+    pub fn create_registry_value<'lua, T: ToLua<'lua>>(&'lua self, t: T) -> Result<RegistryKey> {
+        let value = t.to_lua(self)?;
+        unsafe {
+            let unref_list = (*self.extra_data()).registry_unref_list.clone();
+            self.expire_registry_values();
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 2);
+                self.push_value(self.state, value);
+                let registry_id = ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX);
+                Ok(RegistryKey {
+                    registry_id,
+                    unref_list,
+                })
+            })
+        }
+    }
+
+    /// Returns the value stored in the registry under `key`, converted to `T`.
+    ///
+    /// Returns `Error::MismatchedRegistryKey` if the key was created by another state.
+    pub fn registry_value<'lua, T: FromLua<'lua>>(&'lua self, key: &RegistryKey) -> Result<T> {
+        if !self.owns_registry_value(key) {
+            return Err(Error::MismatchedRegistryKey);
+        }
+        let value = unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 2);
+                ffi::lua_rawgeti(
+                    self.state,
+                    ffi::LUA_REGISTRYINDEX,
+                    key.registry_id as ffi::lua_Integer,
+                );
+                self.pop_value(self.state)
+            })
+        };
+        T::from_lua(value, self)
+    }
+
+    /// Removes the value stored in the registry under `key`, so that it can be garbage collected.
+    ///
+    /// Dropping the key also removes the value, but only the next time a value is stored in the
+    /// registry. Returns `Error::MismatchedRegistryKey` if the key was created by another state.
+    pub fn remove_registry_value(&self, mut key: RegistryKey) -> Result<()> {
+        if !self.owns_registry_value(&key) {
+            return Err(Error::MismatchedRegistryKey);
+        }
+        unsafe {
+            ffi::luaL_unref(self.state, ffi::LUA_REGISTRYINDEX, key.registry_id);
+        }
+        key.registry_id = ffi::LUA_NOREF;
+        Ok(())
+    }
+
+    /// Returns true if `key` was created by this state, or by another `Lua` referring to it such
+    /// as the one passed to callbacks.
+    pub fn owns_registry_value(&self, key: &RegistryKey) -> bool {
+        unsafe { Rc::ptr_eq(&key.unref_list, &(*self.extra_data()).registry_unref_list) }
+    }
+
+    // Removes the values of the dropped `RegistryKey`s from the registry.
+    unsafe fn expire_registry_values(&self) {
+        let unref_list = (*self.extra_data()).registry_unref_list.clone();
+        for registry_id in unref_list.borrow_mut().drain(..) {
+            ffi::luaL_unref(self.state, ffi::LUA_REGISTRYINDEX, registry_id);
+        }
+    }
+
+    /// Takes a snapshot of the globals and of the modules loaded with `require`, which can be
+    /// restored with [`restore`].
+    ///
+    /// The snapshot holds a copy of the contents and the metatable of every table reachable from
+    /// the globals table and `package.loaded`, through the keys, values and metatables of tables.
+    /// Other values are not copied: functions, userdata and threads are kept as references, so the
+    /// upvalues of functions, the data of userdata and tables only reachable from them are not
+    /// part of the snapshot. The snapshot keeps every table it refers to alive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.exec::<()>("config = { retries = 3 }", None)?;
+    /// let snapshot = lua.snapshot();
+    ///
+    /// lua.exec::<()>("config.retries = nil; leaked = true", None)?;
+    /// lua.restore(&snapshot)?;
+    /// assert_eq!(lua.eval::<i64>("config.retries", None)?, 3);
+    /// assert_eq!(lua.eval::<Option<bool>>("leaked", None)?, None);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`restore`]: #method.restore
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 12);
+                let state = self.state;
+
+                // Each entry of the snapshot is a table holding an original table, a copy of its
+                // contents and its metatable. Entries are added as tables are found, and filled in
+                // order, so that the entries themselves serve as the queue of tables to copy.
+                ffi::lua_newtable(state);
+                let entries = ffi::lua_gettop(state);
+                ffi::lua_newtable(state);
+                let seen = ffi::lua_gettop(state);
+                let mut count = 0;
+
+                push_globals(state);
+                snapshot_add(state, entries, seen, &mut count);
+                ffi::luaL_getsubtable(state, ffi::LUA_REGISTRYINDEX, cstr!("_LOADED"));
+                snapshot_add(state, entries, seen, &mut count);
+
+                let mut i = 1;
+                while i <= count {
+                    ffi::lua_rawgeti(state, entries, i);
+                    ffi::lua_rawgeti(state, -1, 1);
+                    ffi::lua_newtable(state);
+                    ffi::lua_pushnil(state);
+                    while ffi::lua_next(state, -3) != 0 {
+                        ffi::lua_pushvalue(state, -2);
+                        snapshot_add(state, entries, seen, &mut count);
+                        ffi::lua_pushvalue(state, -1);
+                        snapshot_add(state, entries, seen, &mut count);
+                        ffi::lua_pushvalue(state, -2);
+                        ffi::lua_insert(state, -2);
+                        ffi::lua_rawset(state, -4);
+                    }
+                    ffi::lua_rawseti(state, -3, 2);
+                    if ffi::lua_getmetatable(state, -1) != 0 {
+                        ffi::lua_pushvalue(state, -1);
+                        snapshot_add(state, entries, seen, &mut count);
+                        ffi::lua_rawseti(state, -3, 3);
+                    }
+                    ffi::lua_pop(state, 2);
+                    i += 1;
+                }
+
+                ffi::lua_pop(state, 1);
+                Snapshot(Table(self.pop_ref(state)))
+            })
+        }
+    }
+
+    /// Restores the globals and the modules loaded with `require` to their state when `snapshot`
+    /// was taken.
+    ///
+    /// Every table in the snapshot is restored in place, so that references to it held by
+    /// functions or by Rust stay valid. Tables created after the snapshot was taken are no longer
+    /// reachable from the restored tables, and modules loaded since are loaded again by the next
+    /// `require`. A snapshot can be restored any number of times. See [`snapshot`] for what a
+    /// snapshot holds.
+    ///
+    /// The prelude set with [`set_prelude`] then runs again, so that its definitions are present
+    /// even if the snapshot was taken before it was set. Returns the error it raises, if any.
+    ///
+    /// [`snapshot`]: #method.snapshot
+    /// [`set_prelude`]: #method.set_prelude
+    pub fn restore(&self, snapshot: &Snapshot) -> Result<()> {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 7);
+                let state = self.state;
+
+                self.push_ref(state, &(snapshot.0).0);
+                let count = ffi::lua_rawlen(state, -1) as ffi::lua_Integer;
+                for i in 1..count + 1 {
+                    ffi::lua_rawgeti(state, -1, i);
+                    ffi::lua_rawgeti(state, -1, 1);
+                    ffi::lua_rawgeti(state, -2, 2);
+
+                    // Fields may be cleared while traversing a table.
+                    ffi::lua_pushnil(state);
+                    while ffi::lua_next(state, -3) != 0 {
+                        ffi::lua_pop(state, 1);
+                        ffi::lua_pushvalue(state, -1);
+                        ffi::lua_pushnil(state);
+                        ffi::lua_rawset(state, -5);
+                    }
+                    ffi::lua_pushnil(state);
+                    while ffi::lua_next(state, -2) != 0 {
+                        ffi::lua_pushvalue(state, -2);
+                        ffi::lua_insert(state, -2);
+                        ffi::lua_rawset(state, -5);
+                    }
+
+                    ffi::lua_rawgeti(state, -3, 3);
+                    ffi::lua_setmetatable(state, -3);
+                    ffi::lua_pop(state, 3);
+                }
+                ffi::lua_pop(state, 1);
+            })
+        }
+        self.run_prelude()
+    }
+
+    /// Runs a chunk of Lua code now, and again whenever the globals are reset by [`restore`], so
+    /// that the helpers it defines, such as a class system or utility functions, are always
+    /// present. Replaces the previous prelude.
+    ///
+    /// Coroutines share the globals of the state, so they see the definitions of the prelude
+    /// without running it again. To install a prelude in every state of a pool, see
+    /// [`LuaPool::set_prelude`]. Returns the error loading or running the prelude raised, in
+    /// which case the previous prelude is kept.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let snapshot = lua.snapshot();
+    /// lua.set_prelude("function clamp(x, min, max) return math.max(min, math.min(x, max)) end")?;
+    /// assert_eq!(lua.eval::<i64>("clamp(15, 0, 10)", None)?, 10);
+    ///
+    /// lua.restore(&snapshot)?;
+    /// assert_eq!(lua.eval::<i64>("clamp(-5, 0, 10)", None)?, 0);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`restore`]: #method.restore
+    /// [`LuaPool::set_prelude`]: struct.LuaPool.html#method.set_prelude
+    pub fn set_prelude(&self, source: &str) -> Result<()> {
+        self.exec::<()>(source, Some("prelude"))?;
+        unsafe {
+            (*self.extra_data()).prelude = Some(source.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Removes the prelude set with [`set_prelude`], so that it no longer runs on [`restore`].
+    /// The definitions it made are kept.
+    ///
+    /// [`set_prelude`]: #method.set_prelude
+    /// [`restore`]: #method.restore
+    pub fn remove_prelude(&self) {
+        unsafe {
+            (*self.extra_data()).prelude = None;
+        }
+    }
+
+    fn run_prelude(&self) -> Result<()> {
+        let prelude = unsafe { (*self.extra_data()).prelude.clone() };
+        match prelude {
+            Some(prelude) => self.exec(&prelude, Some("prelude")),
+            None => Ok(()),
+        }
+    }
+
+    /// Serializes a value and everything reachable from it to bytes, which can be turned back into
+    /// an equivalent value with [`unpersist`], for example to save the state of a game written in
+    /// Lua.
+    ///
+    /// Tables are saved with their contents and metatables, and functions written in Lua with
+    /// their bytecode and upvalues, so that closures keep sharing the upvalues they share. Values
+    /// reachable more than once, including through cycles, are saved once. Coroutines are saved
+    /// before they start, after they finish, and while suspended, with their stack and the calls
+    /// they are suspended in, so that they continue where they left off when resumed. Saving a
+    /// suspended coroutine needs the bundled Lua (the `builtin-lua` feature), and only works when
+    /// it is suspended in calls of Lua functions, apart from the function which yielded, which
+    /// must be one of the permanents: `coroutine.yield` or a callback using [`yield_with`]. A
+    /// thread created with [`create_thread_from`], which is suspended inside its Rust closure,
+    /// cannot be saved.
+    ///
+    /// Other values, such as userdata, Rust and C functions, and tables the host owns like the
+    /// globals table, can only be saved by name: `permanents` maps names to values, and each of
+    /// these values is saved as its name, to be replaced by the value with the same name in the
+    /// permanents table passed to [`unpersist`]. Functions defined by a chunk usually have the
+    /// globals table as their first upvalue, so it should be one of the permanents. Persisting a
+    /// value which is not a permanent and cannot be saved, or which nests tables, functions and
+    /// coroutines more than 200 levels deep, returns a [`SerializeError`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Function, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let counter = lua.eval::<Function>(
+    ///     "local count = 0 return function() count = count + 1 return count end",
+    ///     None,
+    /// )?;
+    /// counter.call::<_, ()>(())?;
+    ///
+    /// let permanents = lua.create_table();
+    /// permanents.set("globals", lua.globals())?;
+    /// let saved = lua.persist(counter, permanents.clone())?;
+    ///
+    /// let restored = unsafe { lua.unpersist::<Function>(&saved, permanents)? };
+    /// assert_eq!(restored.call::<_, i64>(())?, 2);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`unpersist`]: #method.unpersist
+    /// [`yield_with`]: #method.yield_with
+    /// [`create_thread_from`]: #method.create_thread_from
+    /// [`SerializeError`]: enum.Error.html#variant.SerializeError
+    pub fn persist<'lua, V: ToLua<'lua>>(
+        &'lua self,
+        value: V,
+        permanents: Table<'lua>,
+    ) -> Result<Vec<u8>> {
+        persist::persist(value.to_lua(self)?, &permanents)
+    }
+
+    /// Restores a value saved with [`persist`].
+    ///
+    /// Every table, function and coroutine of the value is created anew, and values saved as
+    /// permanents are looked up by name in `permanents`. Data which is not valid or nests values
+    /// too deeply returns a [`DeserializeError`], as does a permanent which is missing.
+    ///
+    /// # Safety
+    ///
+    /// The data holds the bytecode of functions and the calls of suspended coroutines, which Lua
+    /// does not verify, so it must have been written by [`persist`] with this version of Lua, and
+    /// not be modified. The permanents must also be the same functions as when it was written,
+    /// since a suspended coroutine resumes into them.
+    ///
+    /// [`persist`]: #method.persist
+    /// [`DeserializeError`]: enum.Error.html#variant.DeserializeError
+    pub unsafe fn unpersist<'lua, V: FromLua<'lua>>(
+        &'lua self,
+        data: &[u8],
+        permanents: Table<'lua>,
+    ) -> Result<V> {
+        V::from_lua(persist::unpersist(self, data, &permanents)?, self)
+    }
+
+    /// Takes an owned copy of the global variables, to be compared with [`GlobalsSnapshot::diff`].
+    ///
+    /// Tables are copied up to 8 levels deep. Functions, threads and userdata are not copied, but
+    /// recorded by their address. See [`globals_snapshot_with_depth`].
+    ///
+    /// [`GlobalsSnapshot::diff`]: struct.GlobalsSnapshot.html#method.diff
+    /// [`globals_snapshot_with_depth`]: #method.globals_snapshot_with_depth
+    pub fn globals_snapshot(&self) -> Result<GlobalsSnapshot> {
+        self.globals_snapshot_with_depth(GLOBALS_SNAPSHOT_DEPTH)
+    }
+
+    /// Takes an owned copy of the global variables like [`globals_snapshot`], copying tables up to
+    /// `max_depth` levels deep.
+    ///
+    /// Tables nested deeper, as well as tables which contain themselves, are recorded by their
+    /// address as [`SnapshotValue::Truncated`]. With a depth of 0, only the values of the global
+    /// variables themselves are copied.
+    ///
+    /// [`globals_snapshot`]: #method.globals_snapshot
+    /// [`SnapshotValue::Truncated`]: enum.SnapshotValue.html#variant.Truncated
+    pub fn globals_snapshot_with_depth(&self, max_depth: usize) -> Result<GlobalsSnapshot> {
+        globals::snapshot(self.globals(), max_depth)
+    }
+
+    /// Coerces a Lua value to a string.
+    ///
+    /// The value must be a string (in which case this is a no-op) or a number.
+    pub fn coerce_string<'lua>(&'lua self, v: Value<'lua>) -> Result<String<'lua>> {
+        match v {
+            Value::String(s) => Ok(s),
+            v => unsafe {
+                stack_guard(self.state, 0, || {
+                    check_stack(self.state, 2);
+                    let ty = v.type_name();
+                    self.push_value(self.state, v);
+                    if ffi::lua_tostring(self.state, -1).is_null() {
+                        ffi::lua_pop(self.state, 1);
+                        Err(Error::FromLuaConversionError {
+                            from: ty,
+                            to: "String",
+                            message: Some("expected string or number".to_string()),
+                        })
+                    } else {
+                        Ok(String(self.pop_ref(self.state)))
+                    }
+                })
+            },
+        }
+    }
+
+    /// Coerces a Lua value to an integer.
+    ///
+    /// The value must be an integer, or a floating point number or a string that can be converted
+    /// to an integer. Refer to the Lua manual for details.
+    pub fn coerce_integer(&self, v: Value) -> Result<Integer> {
+        match v {
+            Value::Integer(i) => Ok(i),
+            v => unsafe {
+                stack_guard(self.state, 0, || {
+                    check_stack(self.state, 1);
+                    let ty = v.type_name();
+                    self.push_value(self.state, v);
+                    let mut isint = 0;
+                    let i = ffi::lua_tointegerx(self.state, -1, &mut isint);
+                    ffi::lua_pop(self.state, 1);
+                    if isint == 0 {
+                        Err(Error::FromLuaConversionError {
+                            from: ty,
+                            to: "integer",
+                            message: None,
+                        })
+                    } else {
+                        Ok(i)
+                    }
+                })
+            },
+        }
+    }
+
+    /// Coerce a Lua value to a number.
+    ///
+    /// The value must be a number or a string that can be converted to a number. Refer to the Lua
+    /// manual for details.
+    pub fn coerce_number(&self, v: Value) -> Result<Number> {
+        match v {
+            Value::Number(n) => Ok(n),
+            v => unsafe {
+                stack_guard(self.state, 0, || {
+                    check_stack(self.state, 1);
+                    let ty = v.type_name();
+                    self.push_value(self.state, v);
+                    let mut isnum = 0;
+                    let n = ffi::lua_tonumberx(self.state, -1, &mut isnum);
+                    ffi::lua_pop(self.state, 1);
+                    if isnum == 0 {
+                        Err(Error::FromLuaConversionError {
+                            from: ty,
+                            to: "number",
+                            message: Some("number or string coercible to number".to_string()),
+                        })
+                    } else {
+                        Ok(n)
+                    }
+                })
+            },
+        }
+    }
+
+    /// Converts a value that implements `ToLua` into a `Value` instance.
+    pub fn pack<'lua, T: ToLua<'lua>>(&'lua self, t: T) -> Result<Value<'lua>> {
+        t.to_lua(self)
+    }
+
+    /// Converts a `Value` instance into a value that implements `FromLua`.
+    pub fn unpack<'lua, T: FromLua<'lua>>(&'lua self, value: Value<'lua>) -> Result<T> {
+        T::from_lua(value, self)
+    }
+
+    /// Converts a value that implements `Serialize` into a `Value` instance.
+    ///
+    /// Structs and maps become tables with the field names or map keys as keys, sequences and
+    /// tuples become tables with the keys `1..n`, and `None` and units become `nil`. Unit enum
+    /// variants become the variant name, other variants a table with the variant name as its only
+    /// key. Use [`to_value_with`] to change how `None`, units and sequences are converted.
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # #[macro_use] extern crate serde_derive;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// #[derive(Serialize)]
+    /// struct Point {
+    ///     x: i32,
+    ///     y: i32,
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let point = lua.to_value(&Point { x: 1, y: 2 })?;
+    /// lua.globals().set("point", point)?;
+    /// assert_eq!(lua.eval::<i32>("point.x + point.y", None)?, 3);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn to_value<'lua, T>(&'lua self, t: &T) -> Result<Value<'lua>>
+    where
+        T: ?Sized + ::serde::Serialize,
+    {
+        self.to_value_with(t, SerializeOptions::default())
+    }
+
+    /// Converts a value that implements `Serialize` into a `Value` instance, using the given
+    /// options.
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// [`to_value`]: #method.to_value
+    #[cfg(feature = "serde")]
+    pub fn to_value_with<'lua, T>(
+        &'lua self,
+        t: &T,
+        options: SerializeOptions,
+    ) -> Result<Value<'lua>>
+    where
+        T: ?Sized + ::serde::Serialize,
+    {
+        let serializer = serialize::Serializer {
+            lua: self,
+            options,
+        };
+        ::serde::Serialize::serialize(t, serializer)
+    }
+
+    /// Installs a module which lets scripts log through the [`log`] crate.
+    ///
+    /// The module is stored in the global variable `name`, and can also be loaded with
+    /// `require(name)`. It contains the functions `trace`, `debug`, `info`, `warn` and `error`,
+    /// which format their arguments like `string.format` and emit a log record of the
+    /// corresponding level. The target of the record is the chunk name and line of the calling
+    /// script, such as `main.lua:12`, so script messages can be filtered with the configuration
+    /// of the host's logger.
+    ///
+    /// Requires the `log` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.load_log_module("log")?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     log.info("loaded %d items in %.1f ms", 42, 1.5)
+    ///     require("log").warn("disk usage high")
+    /// "#, Some("startup.lua"))?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`log`]: https://docs.rs/log
+    #[cfg(feature = "log")]
+    pub fn load_log_module(&self, name: &str) -> Result<()> {
+        let module = logging::create_log_module(self)?;
+        self.install_module(name, module)
+    }
+
+    /// Installs a module which lets scripts use the regular expressions of the [`regex`] crate.
+    ///
+    /// The module is stored in the global variable `name`, and can also be loaded with
+    /// `require(name)`. Patterns use the [syntax] of the `regex` crate, and match against the
+    /// bytes of Lua strings. Positions are 1-based byte offsets, like those of the `string`
+    /// library, and negative start positions count from the end of the string.
+    ///
+    /// `re.compile(pattern)` returns a compiled regular expression, which has the following
+    /// methods. The module also has functions of the same names that take a pattern as their first
+    /// argument, such as `re.find(pattern, s)`, and keep recently used patterns compiled.
+    ///
+    /// - `is_match(s [, init])` returns whether the pattern matches `s`.
+    /// - `find(s [, init])` returns the start and end of the first match, or `nil`.
+    /// - `match(s [, init])` returns the capture groups of the first match, or the whole match if
+    ///   the pattern has no groups, like `string.match`.
+    /// - `captures(s [, init])` returns a table holding the whole match at index 0, the capture
+    ///   groups from index 1 and the named groups under their names, or `nil`.
+    /// - `replace(s, replacement [, limit])` replaces matches like `string.gsub`, and returns the
+    ///   new string and the number of replacements. In a replacement string, `$1` and `${name}`
+    ///   refer to capture groups. A replacement function receives the values `match` would
+    ///   return, and the match is kept if it returns `false` or `nil`.
+    /// - `split(s [, limit])` returns a sequence of the pieces of `s` between matches.
+    ///
+    /// Capture groups which did not take part in a match are returned as `false`.
+    ///
+    /// Requires the `regex` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.load_regex_module("re")?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     local date = re.compile([[(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})]])
+    ///     local caps = date:captures("released on 2018-03-21")
+    ///     assert(caps.year == "2018" and caps[2] == "03" and caps[0] == "2018-03-21")
+    ///
+    ///     assert(re.replace([[\d+]], "a1b22c333", "<$0>") == "a<1>b<22>c<333>")
+    ///     assert(#re.split([[,\s*]], "a, b,c") == 3)
+    /// "#, None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`regex`]: https://docs.rs/regex
+    /// [syntax]: https://docs.rs/regex/1/regex/#syntax
+    #[cfg(feature = "regex")]
+    pub fn load_regex_module(&self, name: &str) -> Result<()> {
+        let module = regexp::create_regex_module(self)?;
+        self.install_module(name, module)
+    }
+
+    /// Installs a module which lets scripts encode and decode JSON with [`serde_json`].
+    ///
+    /// The module is stored in the global variable `name`, and can also be loaded with
+    /// `require(name)`. It has the following fields:
+    ///
+    /// - `encode(value [, options])` returns `value` encoded as JSON, converted as described for
+    ///   the `FromLua` implementation of `serde_json::Value`, which refuses tables nested more
+    ///   than 128 levels deep. If `options.pretty` is `true`, the output is indented over several
+    ///   lines.
+    /// - `decode(s [, options])` returns the value encoded in the JSON string `s`. JSON `null`
+    ///   becomes `options.null` if it is given, and `null` otherwise, so that nulls don't leave
+    ///   holes in arrays. Decoded arrays have the [`array_metatable`], so that empty arrays are
+    ///   encoded as arrays again.
+    /// - `null` is the value returned by [`Lua::null`], which encodes as `null`.
+    ///
+    /// Errors in encoding or decoding are raised as Lua errors.
+    ///
+    /// Requires the `serde_json` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.load_json_module("json")?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     local value = json.decode('{"tags": [], "parent": null}')
+    ///     assert(value.parent == json.null and #value.tags == 0)
+    ///     assert(json.encode(value) == '{"parent":null,"tags":[]}')
+    ///
+    ///     value = json.decode('[1, null, 3]', {null = false})
+    ///     assert(value[2] == false and value[3] == 3)
+    /// "#, None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`serde_json`]: https://docs.rs/serde_json
+    /// [`array_metatable`]: #method.array_metatable
+    /// [`Lua::null`]: #method.null
+    #[cfg(feature = "serde_json")]
+    pub fn load_json_module(&self, name: &str) -> Result<()> {
+        let module = json::create_json_module(self)?;
+        self.install_module(name, module)
+    }
+
+    /// Installs a module which lets scripts work with dates and times in any time zone, using
+    /// [`chrono`] instead of the `os` library.
+    ///
+    /// The module is stored in the global variable `name`, and can also be loaded with
+    /// `require(name)`. Times are represented by the number of seconds since the Unix epoch, which
+    /// may have a fractional part. Time zones are given as `"UTC"`, which is the default,
+    /// `"local"` for the time zone of the system, or an offset such as `"+02:00"`.
+    ///
+    /// - `now()` returns the current time.
+    /// - `format(time, format [, zone])` formats the time in the time zone, using the [specifiers]
+    ///   of `chrono`, which are similar to those of `strftime`.
+    /// - `parse(s [, format])` parses an RFC 3339 string, or a string in the given format. If the
+    ///   format has no offset, the time is taken to be in UTC.
+    /// - `date(time [, zone])` returns a table with the fields of the table returned by
+    ///   `os.date("*t")`, except `isdst`, and the offset of the time zone in seconds as `offset`.
+    /// - `time(table [, zone])` returns the time of the date and time in the table, like
+    ///   `os.time`. The time fields default to 0.
+    ///
+    /// Requires the `chrono` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.load_datetime_module("datetime")?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     local time = datetime.parse("2018-03-21T22:30:00Z")
+    ///     assert(datetime.format(time, "%Y-%m-%d %H:%M", "+02:00") == "2018-03-22 00:30")
+    ///     assert(datetime.date(time, "-01:00").hour == 21)
+    ///     assert(datetime.time({ year = 2018, month = 3, day = 22 }, "+02:00") == time - 1800)
+    /// "#, None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`chrono`]: https://docs.rs/chrono
+    /// [specifiers]: https://docs.rs/chrono/0.4/chrono/format/strftime/index.html
+    #[cfg(feature = "chrono")]
+    pub fn load_datetime_module(&self, name: &str) -> Result<()> {
+        let module = datetime::create_datetime_module(self)?;
+        self.install_module(name, module)
+    }
+
+    /// Installs a module which lets scripts send HTTP requests with [`ureq`].
+    ///
+    /// The module is stored in the global variable `name`, and can also be loaded with
+    /// `require(name)`. Requests may only be sent to the hosts allowed by `options`, and block
+    /// until the response has been read or the timeout has passed.
+    ///
+    /// - `get(url [, headers])` sends a GET request, with the headers in the table `headers`.
+    /// - `post(url, body [, headers])` sends a POST request with the string `body`.
+    ///
+    /// Both return the status code, a table of the response headers with lowercase names and the
+    /// body of the response. If the request fails, for example because the host cannot be
+    /// reached, they return `nil` and an error message instead. An invalid URL, a host which is
+    /// not allowed or a `Host` header, which would reach other sites served from the same
+    /// address, raises an error. Redirects are not followed, but returned like any other response.
+    ///
+    /// Requires the `http` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # extern crate rlua;
+    /// # use rlua::{HttpOptions, Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let options = HttpOptions {
+    ///     allowed_hosts: vec!["api.example.com".to_owned()],
+    ///     ..HttpOptions::default()
+    /// };
+    /// lua.load_http_module("http", options)?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     local status, headers, body = http.get("https://api.example.com/status", {
+    ///         ["Accept"] = "application/json",
+    ///     })
+    ///     if not status then
+    ///         error("request failed: " .. headers)
+    ///     end
+    ///     print(status, headers["content-type"], body)
+    /// "#, None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`ureq`]: https://docs.rs/ureq
+    #[cfg(feature = "http")]
+    pub fn load_http_module(&self, name: &str, options: HttpOptions) -> Result<()> {
+        let module = http::create_http_module(self, options)?;
+        self.install_module(name, module)
+    }
+
+    /// Replaces `math.random` and `math.randomseed` with functions using `rng`.
+    ///
+    /// The functions behave like those of the standard library, except that they use `rng`
+    /// instead of the C library generator, which is neither secure nor the same on every
+    /// platform. `math.randomseed(x)` replaces the generator with `R::seed_from_u64(x)`, so a
+    /// script which seeds the generator gets the same numbers on every run. Hosts which need
+    /// unpredictable numbers can install a cryptographically secure generator, and replace
+    /// `math.randomseed` afterwards if scripts should not be able to seed it.
+    ///
+    /// Requires the `rand` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rand_chacha;
+    /// # extern crate rlua;
+    /// # use rand_chacha::ChaCha20Rng;
+    /// # use rand_chacha::rand_core::SeedableRng;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.install_rng(ChaCha20Rng::seed_from_u64(0))?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     math.randomseed(42)
+    ///     local first = { math.random(6), math.random(6), math.random(6) }
+    ///     math.randomseed(42)
+    ///     assert(math.random(6) == first[1] and math.random(6) == first[2])
+    /// "#, None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    #[cfg(feature = "rand")]
+    pub fn install_rng<R>(&self, rng: R) -> Result<()>
+    where
+        R: 'static + RngCore + SeedableRng,
+    {
+        random::install_rng(self, rng)
+    }
+
+    /// Replaces `os.time`, `os.date` and `os.clock` with functions reading the current time from
+    /// `time` and the processor time from `clock`.
+    ///
+    /// This lets simulations and tests control the time scripts see through the standard
+    /// library. `os.time()` returns the whole seconds of `time()` since the Unix epoch, and
+    /// `os.date` formats it when it is not given a time. `os.clock()` returns `clock()` in seconds.
+    /// Converting a date table with `os.time(table)` and formatting a given time with `os.date` are
+    /// done by the functions of the standard library, in the time zone of the host. States without
+    /// the `os` library are left unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::cell::Cell;
+    /// # use std::rc::Rc;
+    /// # use std::time::{Duration, UNIX_EPOCH};
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let elapsed = Rc::new(Cell::new(Duration::from_secs(0)));
+    /// let time_elapsed = elapsed.clone();
+    /// let clock_elapsed = elapsed.clone();
+    /// lua.set_clock(
+    ///     move || UNIX_EPOCH + Duration::from_secs(1_500_000_000) + time_elapsed.get(),
+    ///     move || clock_elapsed.get(),
+    /// )?;
+    ///
+    /// lua.exec::<()>("start = os.time()", None)?;
+    /// elapsed.set(Duration::from_secs(90));
+    /// lua.exec::<()>(r#"
+    ///     assert(os.time() - start == 90)
+    ///     assert(os.clock() == 90)
+    ///     assert(os.date("!%H:%M", 0) == "00:00")
+    ///     assert(os.date("!%Y-%m-%d %H:%M") == "2017-07-14 02:41")
+    /// "#, None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn set_clock<T, C>(&self, time: T, clock: C) -> Result<()>
+    where
+        T: 'static + Fn() -> SystemTime,
+        C: 'static + Fn() -> Duration,
+    {
+        clock::install_clock(self, time, clock)
+    }
+
+    /// Replaces the `os` library with a hardened subset, which sandboxes can offer to untrusted
+    /// scripts.
+    ///
+    /// The new `os` table only has `time`, `clock` and `date`, which are the functions of the
+    /// standard library, and `getenv`, which returns `nil` for every environment variable not
+    /// named in `allowed_env`. Functions which reach outside of the state, such as `execute`,
+    /// `remove`, `rename`, `tmpname`, `setlocale` and `exit`, are not available. The table is
+    /// stored in the global variable `os`, and returned by `require("os")`.
+    ///
+    /// This works whether or not the standard `os` library has been opened. To also control the
+    /// time seen by scripts, call [`set_clock`] after this method.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.use_safe_os(&["LANG"])?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     assert(type(os.time()) == "number")
+    ///     assert(os.date("!%Y", 0) == "1970")
+    ///     assert(os.getenv("PATH") == nil)
+    ///     assert(os.execute == nil and os.remove == nil and os.exit == nil)
+    ///     assert(require("os") == os)
+    /// "#, None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set_clock`]: #method.set_clock
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn use_safe_os(&self, allowed_env: &[&str]) -> Result<()> {
+        safe_os::install_safe_os(self, allowed_env)
+    }
+
+    /// Makes the `io` library and module loading use `vfs` instead of the file system of the host.
+    ///
+    /// `io.open`, `io.lines`, `io.input`, `io.output`, `io.tmpfile`, `os.remove`, `os.rename`,
+    /// `loadfile`, `dofile` and `require` are replaced with versions which only access files
+    /// through `vfs`, and `io.list(path)` is added, returning the names of the entries of a
+    /// directory. `io.stdin`, `io.stdout` and `io.stderr` still refer to the standard streams of
+    /// the process. `io.popen` and `os.tmpname` raise an error, since they would reach outside of
+    /// `vfs`. Modules are searched in `package.path`, which is set to `./?.lua;./?/init.lua`, and
+    /// native modules cannot be loaded. Files containing bytecode are never loaded.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, MemoryVfs, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let files = MemoryVfs::new();
+    /// files.insert("lib/greet.lua", b"return function(name) return 'hi ' .. name end".to_vec());
+    /// lua.set_vfs(files.clone())?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     local greet = require("lib.greet")
+    ///     local file = io.open("/out/greeting.txt", "w")
+    ///     file:write(greet("lua"))
+    ///     file:close()
+    ///     assert(io.open("../../etc/passwd") == nil)
+    /// "#, None)?;
+    /// assert_eq!(files.get("out/greeting.txt"), Some(b"hi lua".to_vec()));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn set_vfs<V: 'static + Vfs>(&self, vfs: V) -> Result<()> {
+        vfs::install_vfs(self, Rc::new(vfs))
+    }
+
+    /// Creates a file handle which scripts use like the files of the `io` library, but which
+    /// reads from and writes to `handle`.
+    ///
+    /// This lets the host grant scripts access to a log, a socket or a pipe without letting them
+    /// open files themselves: only the host can create such handles, and the `io` library can
+    /// be removed, or restricted with [`set_vfs`]. The handle has the methods `read`, `write`,
+    /// `lines`, `flush`, `close` and `setvbuf` of Lua files, and is accepted by the functions
+    /// of the `io` library installed by [`set_vfs`], such as `io.input` and `io.lines`. As
+    /// `handle` cannot seek, `seek` returns an error, and data read ahead from it is kept for the
+    /// next read even after a write. Closing the handle drops `handle`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use std::io::Cursor;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// lua.globals().set("io", rlua::Nil)?;
+    ///
+    /// let log = Cursor::new(b"started\nfailed to connect\nstopped\n".to_vec());
+    /// lua.globals().set("log", lua.create_file_handle(log))?;
+    ///
+    /// let failures = lua.eval::<i64>(r#"
+    ///     local failures = 0
+    ///     for line in log:lines() do
+    ///         if line:find("failed") then failures = failures + 1 end
+    ///     end
+    ///     return failures
+    /// "#, None)?;
+    /// assert_eq!(failures, 1);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set_vfs`]: #method.set_vfs
+    pub fn create_file_handle<H>(&self, handle: H) -> AnyUserData<'_>
+    where
+        H: 'static + Read + Write,
+    {
+        vfs::create_file_handle(self, handle)
+    }
+
+    /// Returns the value used to represent a null value when converting with `serde`.
+    ///
+    /// Unlike `nil`, this value can be stored in tables, so that `None` elements don't leave holes
+    /// in sequences. It is a light userdata holding a null pointer, and is converted to a unit or
+    /// `None` when deserializing. See [`SerializeOptions`].
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// [`SerializeOptions`]: struct.SerializeOptions.html
+    #[cfg(feature = "serde")]
+    pub fn null(&self) -> Value<'_> {
+        Value::LightUserData(LightUserData(ptr::null_mut()))
+    }
+
+    /// Returns the metatable used to mark tables as arrays when converting with `serde`.
+    ///
+    /// Tables with this metatable are always serialized and deserialized as sequences, so empty
+    /// arrays are not mistaken for maps. See [`SerializeOptions`].
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// [`SerializeOptions`]: struct.SerializeOptions.html
+    #[cfg(feature = "serde")]
+    pub fn array_metatable(&self) -> Table<'_> {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 3);
+
+                ffi::lua_pushlightuserdata(
+                    self.state,
+                    &ARRAY_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+                );
+                if ffi::lua_rawget(self.state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TNIL {
+                    ffi::lua_pop(self.state, 1);
+                    ffi::lua_newtable(self.state);
+                    ffi::lua_pushlightuserdata(
+                        self.state,
+                        &ARRAY_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+                    );
+                    ffi::lua_pushvalue(self.state, -2);
+                    ffi::lua_rawset(self.state, ffi::LUA_REGISTRYINDEX);
+                }
+                Table(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    /// Converts a `Value` instance into a value that implements `Deserialize`.
+    ///
+    /// This is the inverse of [`to_value`]. Tables with only the keys `1..n` can be deserialized
+    /// as sequences, and any table as a map or struct. Floats without a fractional part are
+    /// accepted where an integer is expected. Recursive tables and tables nested more than 128
+    /// levels deep cannot be deserialized.
+    ///
+    /// Requires the `serde` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # #[macro_use] extern crate serde_derive;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// #[derive(Deserialize)]
+    /// struct Config {
+    ///     name: String,
+    ///     sizes: Vec<u32>,
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let value = lua.eval(r#"{ name = "test", sizes = { 1, 2, 3 } }"#, None)?;
+    /// let config: Config = lua.from_value(value)?;
+    /// assert_eq!(config.name, "test");
+    /// assert_eq!(config.sizes, vec![1, 2, 3]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`to_value`]: #method.to_value
+    #[cfg(feature = "serde")]
+    pub fn from_value<'lua, T>(&'lua self, value: Value<'lua>) -> Result<T>
+    where
+        T: ::serde::de::DeserializeOwned,
+    {
+        T::deserialize(deserialize::Deserializer { value })
+    }
+
+    /// Decodes a value encoded as MessagePack, such as by [`Value::to_msgpack`].
+    ///
+    /// `bytes` must hold exactly one value. Both strings and binary data become Lua strings, and
+    /// unsigned integers which don't fit in an `Integer` become floats. Arrays become tables with
+    /// the keys `1..n`, and maps become tables with the same keys, which must not be `nil` or NaN.
+    /// Extension types are not supported.
+    ///
+    /// Requires the `msgpack` feature.
+    ///
+    /// [`Value::to_msgpack`]: enum.Value.html#method.to_msgpack
+    #[cfg(feature = "msgpack")]
+    pub fn value_from_msgpack<'lua>(&'lua self, bytes: &[u8]) -> Result<Value<'lua>> {
+        msgpack::decode(self, bytes)
+    }
+
+    /// Parses a TOML document into a table, so that configuration can be shared with scripts.
+    ///
+    /// TOML tables become Lua tables with string keys, and arrays become tables with the keys
+    /// `1..n`. Dates and times become strings in their TOML representation.
+    ///
+    /// Requires the `toml` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config = lua.table_from_toml(r#"
+    ///     [server]
+    ///     host = "localhost"
+    ///     ports = [8080, 8081]
+    /// "#)?;
+    /// lua.globals().set("config", config)?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     assert(config.server.host == "localhost" and config.server.ports[2] == 8081)
+    /// "#, None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    #[cfg(feature = "toml")]
+    pub fn table_from_toml(&self, s: &str) -> Result<Table<'_>> {
+        config::table_from_toml(self, s)
+    }
+
+    /// Parses a YAML document into a table, so that configuration can be shared with scripts.
+    ///
+    /// The document must be a mapping, or empty. Mappings become tables with the same keys, which
+    /// must not be null, and sequences become tables with the keys `1..n`. Null values become
+    /// `nil`, so they are missing from mappings and leave holes in sequences. Tags are ignored.
+    ///
+    /// Requires the `yaml` feature.
+    #[cfg(feature = "yaml")]
+    pub fn table_from_yaml(&self, s: &str) -> Result<Table<'_>> {
+        config::table_from_yaml(self, s)
+    }
+
+    /// Converts a value that implements `ToLuaMulti` into a `MultiValue` instance.
+    pub fn pack_multi<'lua, T: ToLuaMulti<'lua>>(&'lua self, t: T) -> Result<MultiValue<'lua>> {
+        t.to_lua_multi(self)
+    }
+
+    /// Converts a `MultiValue` instance into a value that implements `FromLuaMulti`.
+    pub fn unpack_multi<'lua, T: FromLuaMulti<'lua>>(
+        &'lua self,
+        value: MultiValue<'lua>,
+    ) -> Result<T> {
+        T::from_lua_multi(value, self)
+    }
+
+    /// Requests that the running Rust callback yields the current coroutine.
+    ///
+    /// The given values are passed to the code which resumed the coroutine, as if the calling Lua
+    /// code had called `coroutine.yield(...)`. The yield happens when the callback returns, so the
+    /// callback should return right after calling this method, and its return values are ignored.
+    /// Once the coroutine is resumed again, the values passed to the resume become the results of
+    /// the callback.
+    ///
+    /// Returns an error if called outside of a Rust callback. If the callback was not called from
+    /// inside a coroutine (or the coroutine cannot yield, for example because the callback was
+    /// called through `pcall`), a Lua error is raised when the callback returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let wait = lua.create_function(|lua, id: i64| {
+    ///     lua.yield_with(id)?;
+    ///     Ok(())
+    /// });
+    /// lua.globals().set("wait", wait)?;
+    ///
+    /// let thread = lua.create_thread(lua.eval(r#"
+    ///     function()
+    ///         local result = wait(7)
+    ///         return result * 2
+    ///     end
+    /// "#, None)?);
+    ///
+    /// assert_eq!(thread.resume::<_, i64>(())?, 7);
+    /// assert_eq!(thread.resume::<_, i64>(21)?, 42);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn yield_with<'lua, A: ToLuaMulti<'lua>>(&'lua self, args: A) -> Result<()> {
+        self.request_yield(args, Resume::Return)
+    }
+
+    /// Creates a thread whose body is implemented by a Rust closure.
+    ///
+    /// Rust closures cannot be suspended in the middle of their execution, so the closure acts as
+    /// a step function instead: it is called with the arguments of the first resume, and if it
+    /// calls [`Yielder::yield_values`] before returning, the thread yields those values and the
+    /// closure is called again with the arguments of the next resume. Once the closure returns
+    /// without yielding, its return values finish the thread.
+    ///
+    /// Any state which should survive between resumes (such as an iterator) can be kept in the
+    /// closure itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    ///
+    /// let mut lines = vec!["first", "second"].into_iter();
+    /// let thread = lua.create_thread_from(move |yielder, ()| {
+    ///     if let Some(line) = lines.next() {
+    ///         yielder.yield_values(line)?;
+    ///     }
+    ///     Ok(())
+    /// });
+    /// lua.globals().set("lines", thread)?;
+    ///
+    /// lua.exec::<()>(r#"
+    ///     local seen = {}
+    ///     while true do
+    ///         local ok, line = coroutine.resume(lines)
+    ///         if not line then break end
+    ///         seen[#seen + 1] = line
+    ///     end
+    ///     assert(seen[1] == "first" and seen[2] == "second" and #seen == 2)
+    /// "#, None)?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`Yielder::yield_values`]: struct.Yielder.html#method.yield_values
+    pub fn create_thread_from<'lua, A, R, F>(&'lua self, mut func: F) -> Thread<'lua>
+    where
+        A: FromLuaMulti<'lua>,
+        R: ToLuaMulti<'lua>,
+        F: 'static + FnMut(&Yielder<'lua>, A) -> Result<R>,
+    {
+        let step = self.create_callback_function(
+            Box::new(move |lua, args| {
+                let yielder = Yielder { lua };
+                let args = A::from_lua_args(args, 1, lua)
+                    .map_err(|err| lua.name_bad_argument(err, false))?;
+                func(&yielder, args)?.to_lua_multi(lua)
+            }),
+            None,
+        );
+        self.create_thread(step)
+    }
+
+    // Pushes the values to yield when the running callback returns, and `resume` tells how the
+    // callback continues when its thread is resumed.
+    pub(crate) fn request_yield<'lua, A: ToLuaMulti<'lua>>(
+        &'lua self,
+        args: A,
+        resume: Resume,
+    ) -> Result<()> {
+        let mut args = args.to_lua_multi(self)?;
+        unsafe {
+            check_stack(self.state, 1);
+            let extra = self.extra_data();
+            let depth = (*extra).callback_depth;
+            if depth == 0 {
+                return Err(Error::RuntimeError(
+                    "cannot yield outside of a Rust callback".to_owned(),
+                ));
+            }
+
+            // Values of a previous request are still on top of the stack, replace them.
+            if let Some((yield_depth, nyield, _)) = (*extra).pending_yield.take() {
+                if yield_depth == depth {
+                    ffi::lua_pop(self.state, nyield);
+                }
+            }
+
+            let nargs = args.len() as c_int;
+            check_stack(self.state, nargs);
+            for arg in args.drain(..) {
+                self.push_value(self.state, arg);
+            }
+            self.cache_multi_value(args);
+            (*extra).pending_yield = Some((depth, nargs, resume));
+            Ok(())
+        }
+    }
+
+    // Creates a function calling the given callback. Panics in the callback are handled according
+    // to `panic_mode`, or to the mode set with `set_panic_mode` if it is `None`.
+    fn create_callback_function<'lua>(
+        &'lua self,
+        func: Callback<'lua>,
+        panic_mode: Option<PanicMode>,
+    ) -> Function<'lua> {
+        unsafe extern "C" fn callback_call_impl(state: *mut ffi::lua_State) -> c_int {
+            let catch_panic = || catches_panics(state, Some(ffi::lua_upvalueindex(2)));
+            let (nresults, yielding) = callback_error_catching(state, catch_panic, || {
+                let lua = Lua {
+                    state,
+                    main_state: main_state(state),
+                    ephemeral: true,
+                };
+
+                let func = get_userdata::<RefCell<Callback>>(state, ffi::lua_upvalueindex(1));
+                let mut func = if let Ok(func) = (*func).try_borrow_mut() {
+                    func
+                } else {
+                    lua_panic!(
+                        state,
+                        "recursive callback function call would mutably borrow function twice"
+                    );
+                };
+
+                let nargs = ffi::lua_gettop(state);
+                call_callback(&lua, nargs, |args| func.deref_mut()(&lua, args))
+            });
+
+            // Rust frames have all been left at this point, so it is safe for lua_yield to longjmp
+            // out of this function.
+            callback_return(state, nresults, yielding, callback_resume)
+        }
+
+        // Continuation of a callback which yielded through a `Yielder`, the resume arguments are
+        // the only values on the stack and become the arguments of the next call.
+        unsafe extern "C" fn callback_resume(
+            state: *mut ffi::lua_State,
+            _status: c_int,
+            _ctx: ffi::lua_KContext,
+        ) -> c_int {
+            callback_call_impl(state)
+        }
+
+        unsafe {
+            stack_guard(self.state, 0, move || {
+                check_stack(self.state, 3);
+
+                push_userdata::<RefCell<Callback>>(self.state, RefCell::new(func));
+                (*self.extra_data()).live_callbacks += 1;
+
+                ffi::lua_pushlightuserdata(
+                    self.state,
+                    &FUNCTION_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+                );
+                ffi::lua_gettable(self.state, ffi::LUA_REGISTRYINDEX);
+                ffi::lua_setmetatable(self.state, -2);
+
+                if let Some(mode) = panic_mode {
+                    ffi::lua_pushboolean(self.state, (mode == PanicMode::Catch) as c_int);
+                    ffi::lua_pushcclosure(self.state, callback_call_impl, 2);
+                } else {
+                    ffi::lua_pushcclosure(self.state, callback_call_impl, 1);
+                }
+
+                Function(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    // Creates a function calling a userdata method. `metatable` is the absolute stack index of the
+    // metatable of the type the method is registered for, and `methods` the absolute stack index of
+    // the method table of that type, which the method is added to.
+    //
+    // Calls with a `self` argument of that type pass a pointer to the userdata to the method
+    // directly, skipping the conversion to an `AnyUserData` and the lookup of its type.
+    unsafe fn create_method_function<'lua>(
+        &'lua self,
+        func: MethodCallback<'lua>,
+        metatable: c_int,
+        methods: c_int,
+    ) -> Function<'lua> {
+        // Puts a running method back into its slot of the method table when dropped, also when the
+        // method panics.
+        struct RunningMethod<'lua> {
+            slot: *mut Option<MethodCallback<'lua>>,
+            func: Option<MethodCallback<'lua>>,
+        }
+
+        impl<'lua> Drop for RunningMethod<'lua> {
+            fn drop(&mut self) {
+                unsafe {
+                    *self.slot = self.func.take();
+                }
+            }
+        }
+
+        unsafe extern "C" fn method_call_impl(state: *mut ffi::lua_State) -> c_int {
+            let catch_panic = || catches_panics(state, None);
+            let (nresults, yielding) = callback_error_catching(state, catch_panic, || {
+                let lua = Lua {
+                    state,
+                    main_state: main_state(state),
+                    ephemeral: true,
+                };
+
+                // Other methods of the type may run while this one does, so only a pointer to the
+                // slot is kept.
+                let slot = {
+                    let methods = &mut *get_userdata::<MethodTable>(state, ffi::lua_upvalueindex(1));
+                    let id = ffi::lua_tointeger(state, ffi::lua_upvalueindex(3)) as usize;
+                    lua_assert!(state, id < methods.len());
+                    methods.as_mut_ptr().add(id)
+                };
+                let mut running = if let Some(func) = (*slot).take() {
+                    RunningMethod {
+                        slot,
+                        func: Some(func),
+                    }
+                } else {
+                    lua_panic!(
+                        state,
+                        "recursive callback function call would mutably borrow function twice"
+                    );
+                };
+                let func = running.func.as_mut().unwrap();
+
+                check_stack(state, 1);
+                let nargs = ffi::lua_gettop(state);
+                let checked = nargs > 0 && ffi::lua_type(state, 1) == ffi::LUA_TUSERDATA
+                    && ffi::lua_getmetatable(state, 1) != 0 && {
+                    let matches = ffi::lua_rawequal(state, -1, ffi::lua_upvalueindex(2)) != 0;
+                    ffi::lua_pop(state, 1);
+                    matches
+                };
+
+                if checked {
+                    // The userdata stays at the bottom of the stack until the method returns, so
+                    // that it cannot be collected while the method borrows it.
+                    let ud = ffi::lua_touserdata(state, 1);
+                    call_callback(&lua, nargs - 1, |args| {
+                        func(&lua, MethodSelf::Checked(ud), args)
+                    })
+                } else {
+                    call_callback(&lua, nargs, |mut args| {
+                        let this = args.pop_front();
+                        func(&lua, MethodSelf::Unchecked(this), args)
+                    })
+                }
+            });
+
+            // Rust frames have all been left at this point, so it is safe for lua_yield to longjmp
+            // out of this function.
+            callback_return(state, nresults, yielding, method_resume)
+        }
+
+        unsafe extern "C" fn method_resume(
+            state: *mut ffi::lua_State,
+            _status: c_int,
+            _ctx: ffi::lua_KContext,
+        ) -> c_int {
+            method_call_impl(state)
+        }
+
+        stack_guard(self.state, 0, move || {
+            check_stack(self.state, 3);
+
+            // No method runs while the type is being registered, so the table may grow here.
+            let table = get_userdata::<MethodTable>(self.state, methods);
+            (*table).push(Some(func));
+            (*self.extra_data()).live_callbacks += 1;
+
+            ffi::lua_pushvalue(self.state, methods);
+            ffi::lua_pushvalue(self.state, metatable);
+            ffi::lua_pushinteger(self.state, ((*table).len() - 1) as ffi::lua_Integer);
+            ffi::lua_pushcclosure(self.state, method_call_impl, 3);
+
+            Function(self.pop_ref(self.state))
+        })
+    }
+
+    // Creates the method table of a userdata type, which is left on the stack.
+    unsafe fn push_method_table(&self) {
+        check_stack(self.state, 2);
+
+        push_userdata::<MethodTable>(self.state, Vec::new());
+        ffi::lua_pushlightuserdata(
+            self.state,
+            &METHOD_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+        );
+        ffi::lua_gettable(self.state, ffi::LUA_REGISTRYINDEX);
+        ffi::lua_setmetatable(self.state, -2);
+    }
+
+    // Creates a function for a callback registered in `UserDataMethods`, `metatable` and `methods`
+    // are the absolute stack indices of the metatable and the method table of the userdata type.
+    unsafe fn create_userdata_callback<'lua>(
+        &'lua self,
+        callback: UserDataCallback<'lua>,
+        metatable: c_int,
+        methods: c_int,
+    ) -> Function<'lua> {
+        match callback {
+            UserDataCallback::Function(func) => self.create_callback_function(func, None),
+            UserDataCallback::Method(func) => self.create_method_function(func, metatable, methods),
+        }
+    }
+
+    // Used 1 stack space, does not call checkstack
+    pub(crate) unsafe fn push_value(&self, state: *mut ffi::lua_State, value: Value) {
+        match value {
+            Value::Nil => {
+                ffi::lua_pushnil(state);
+            }
+
+            Value::Boolean(b) => {
+                ffi::lua_pushboolean(state, if b { 1 } else { 0 });
+            }
+
+            Value::LightUserData(ud) => {
+                ffi::lua_pushlightuserdata(state, ud.0);
+            }
+
+            Value::Integer(i) => {
+                ffi::lua_pushinteger(state, i);
+            }
+
+            Value::Number(n) => {
+                ffi::lua_pushnumber(state, n);
+            }
+
+            Value::String(s) => {
+                self.push_ref(state, &s.0);
+            }
+
+            Value::Table(t) => {
+                self.push_ref(state, &t.0);
+            }
+
+            Value::Function(f) => {
+                self.push_ref(state, &f.0);
+            }
+
+            Value::Thread(t) => {
+                self.push_ref(state, &t.0);
+            }
+
+            Value::UserData(ud) => {
+                self.push_ref(state, &ud.0);
+            }
+
+            Value::Error(e) => {
+                push_wrapped_error(state, e);
+            }
+        }
+    }
+
+    // Used 1 stack space, does not call checkstack
+    pub(crate) unsafe fn pop_value(&self, state: *mut ffi::lua_State) -> Value<'_> {
+        match ffi::lua_type(state, -1) {
+            ffi::LUA_TNIL => {
+                ffi::lua_pop(state, 1);
+                Nil
+            }
+
+            ffi::LUA_TBOOLEAN => {
+                let b = Value::Boolean(ffi::lua_toboolean(state, -1) != 0);
+                ffi::lua_pop(state, 1);
+                b
+            }
+
+            ffi::LUA_TLIGHTUSERDATA => {
+                let ud = Value::LightUserData(LightUserData(ffi::lua_touserdata(state, -1)));
+                ffi::lua_pop(state, 1);
+                ud
+            }
+
+            ffi::LUA_TNUMBER => if ffi::lua_isinteger(state, -1) != 0 {
+                let i = Value::Integer(ffi::lua_tointeger(state, -1));
+                ffi::lua_pop(state, 1);
+                i
+            } else {
+                let n = Value::Number(ffi::lua_tonumber(state, -1));
+                ffi::lua_pop(state, 1);
+                n
+            },
+
+            ffi::LUA_TSTRING => Value::String(String(self.pop_ref(state))),
+
+            ffi::LUA_TTABLE => Value::Table(Table(self.pop_ref(state))),
+
+            ffi::LUA_TFUNCTION => Value::Function(Function(self.pop_ref(state))),
+
+            ffi::LUA_TUSERDATA => {
+                // It should not be possible to interact with userdata types
+                // other than custom UserData types OR a WrappedError.
+                // WrappedPanic should never be able to be caught in lua, so it
+                // should never be here.
+                if let Some(err) = pop_wrapped_error(state) {
+                    Value::Error(err)
+                } else {
+                    Value::UserData(AnyUserData(self.pop_ref(state)))
+                }
+            }
+
+            ffi::LUA_TTHREAD => Value::Thread(Thread(self.pop_ref(state))),
+
+            _ => unreachable!("internal error: LUA_TNONE in pop_value"),
+        }
+    }
+
+    // Used 1 stack space, does not call checkstack
+    pub(crate) unsafe fn push_ref(&self, state: *mut ffi::lua_State, lref: &LuaRef) {
+        assert_eq!(
+            lref.lua.main_state,
+            self.main_state,
+            "Lua instance passed Value created from a different Lua"
+        );
+
+        match lref.index {
+            RefIndex::Nil => ffi::lua_pushnil(state),
+            RefIndex::Stack(index) => {
+                let ref_thread = (*self.extra_data()).ref_thread;
+                ffi::lua_pushvalue(ref_thread, index);
+                ffi::lua_xmove(ref_thread, state, 1);
+            }
+            RefIndex::Registry(id) => {
+                ffi::lua_rawgeti(state, ffi::LUA_REGISTRYINDEX, id as ffi::lua_Integer);
+            }
+        }
+    }
+
+    // Pops the topmost element of the stack and stores a reference to it.
+    //
+    // This pins the object, preventing garbage collection until the returned
+    // `LuaRef` is dropped.
+    //
+    // References are kept in slots on the stack of a dedicated thread, which are reused once their
+    // `LuaRef` is dropped. This is much cheaper than creating registry references, which are only
+    // used once that stack cannot grow any further. The thread always has a free slot above its
+    // top, which `push_ref` and `pop_ref` use to move values between it and other threads.
+    //
+    // pop_ref uses 1 extra stack space and does not call checkstack
+    pub(crate) unsafe fn pop_ref(&self, state: *mut ffi::lua_State) -> LuaRef<'_> {
+        let extra = self.extra_data();
+        let ref_thread = (*extra).ref_thread;
+
+        let index = if let Some(index) = (*extra).ref_free.pop() {
+            ffi::lua_xmove(state, ref_thread, 1);
+            ffi::lua_replace(ref_thread, index);
+            RefIndex::Stack(index)
+        } else if ffi::lua_checkstack(ref_thread, 2) != 0 {
+            ffi::lua_xmove(state, ref_thread, 1);
+            RefIndex::Stack(ffi::lua_gettop(ref_thread))
+        } else {
+            (*extra).registry_refs += 1;
+            RefIndex::Registry(ffi::luaL_ref(state, ffi::LUA_REGISTRYINDEX))
+        };
+
+        #[cfg(debug_assertions)]
+        {
+            if let Some(ref mut origins) = (*extra).ref_origins {
+                origins.insert(index, Backtrace::force_capture());
+            }
+        }
+
+        LuaRef {
+            lua: self,
+            index,
+        }
+    }
+
+    // Releases the value stored by a `LuaRef`, does not use any stack space.
+    pub(crate) unsafe fn drop_ref(&self, index: RefIndex) {
+        let extra = self.extra_data();
+        #[cfg(debug_assertions)]
+        {
+            if let Some(ref mut origins) = (*extra).ref_origins {
+                origins.remove(&index);
+            }
+        }
+        match index {
+            RefIndex::Nil => {}
+            RefIndex::Stack(index) => {
+                ffi::lua_copy((*extra).ref_thread, 1, index);
+                (*extra).ref_free.push(index);
+            }
+            RefIndex::Registry(id) => {
+                (*extra).registry_refs -= 1;
+                ffi::luaL_unref(self.state, ffi::LUA_REGISTRYINDEX, id)
+            }
+        }
+    }
+
+    // Pops the topmost element of the stack and stores a weak reference to it, returning its key
+    // in the table of weak references. Keys are never reused, so that a collected value cannot be
+    // replaced by another.
+    //
+    // Uses 4 stack spaces, does not call checkstack
+    pub(crate) unsafe fn create_weak_ref(&self, state: *mut ffi::lua_State) -> Integer {
+        let extra = self.extra_data();
+        (*extra).weak_ref_count += 1;
+        let key = (*extra).weak_ref_count;
+        push_weak_refs(state);
+        ffi::lua_insert(state, -2);
+        ffi::lua_rawseti(state, -2, key);
+        ffi::lua_pop(state, 1);
+        key
+    }
+
+    // Pushes the value of a weak reference, or nil if it has been collected.
+    //
+    // Uses 4 stack spaces, does not call checkstack
+    pub(crate) unsafe fn push_weak_ref(&self, state: *mut ffi::lua_State, key: Integer) {
+        push_weak_refs(state);
+        ffi::lua_rawgeti(state, -1, key);
+        ffi::lua_remove(state, -2);
+    }
+
+    // Releases a weak reference.
+    //
+    // Uses 4 stack spaces, does not call checkstack
+    pub(crate) unsafe fn drop_weak_ref(&self, key: Integer) {
+        push_weak_refs(self.state);
+        ffi::lua_pushnil(self.state);
+        ffi::lua_rawseti(self.state, -2, key);
+        ffi::lua_pop(self.state, 1);
+    }
+
+    // Returns the table holding the listeners added in this state to the `Events` of id `id`,
+    // creating it on first use.
+    pub(crate) fn event_listeners(&self, id: usize) -> Table<'_> {
+        unsafe {
+            stack_guard(self.state, 0, move || {
+                check_stack(self.state, 4);
+                let key = &EVENTS_REGISTRY_KEY as *const u8 as *mut c_void;
+                ffi::lua_pushlightuserdata(self.state, key);
+                if ffi::lua_rawget(self.state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TNIL {
+                    ffi::lua_pop(self.state, 1);
+                    ffi::lua_newtable(self.state);
+                    ffi::lua_pushlightuserdata(self.state, key);
+                    ffi::lua_pushvalue(self.state, -2);
+                    ffi::lua_rawset(self.state, ffi::LUA_REGISTRYINDEX);
+                }
+                if ffi::lua_rawgeti(self.state, -1, id as Integer) == ffi::LUA_TNIL {
+                    ffi::lua_pop(self.state, 1);
+                    ffi::lua_newtable(self.state);
+                    ffi::lua_pushvalue(self.state, -1);
+                    ffi::lua_rawseti(self.state, -3, id as Integer);
+                }
+                ffi::lua_remove(self.state, -2);
+                Table(self.pop_ref(self.state))
+            })
+        }
+    }
+
+    // Does not use any stack space
+    pub(crate) unsafe fn extra_data(&self) -> *mut ExtraData {
+        *(ffi::lua_getextraspace(self.state) as *mut *mut ExtraData)
+    }
+
+    // Converts a value raised as a Lua error to a `RuntimeError`, or to a `RuntimeErrorValue` if it
+    // is not a number or a UTF-8 string.
+    pub(crate) fn runtime_error(&self, value: Value) -> Error {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 3);
+                self.push_value(self.state, value);
+                let err = if is_plain_error(self.state, -1) {
+                    let s = CStr::from_ptr(ffi::lua_tostring(self.state, -1));
+                    Error::RuntimeError(s.to_string_lossy().into_owned())
+                } else {
+                    runtime_error_value(self.state, -1, false)
+                };
+                ffi::lua_pop(self.state, 1);
+                err
+            })
+        }
+    }
+
+    // Makes `module` available to scripts as the global variable `name`, and as the result of
+    // `require(name)`.
+    #[cfg(any(
+        not(target_arch = "wasm32"),
+        feature = "log",
+        feature = "regex",
+        feature = "serde_json",
+        feature = "chrono",
+        feature = "http"
+    ))]
+    pub(crate) fn install_module(&self, name: &str, module: Table) -> Result<()> {
+        let loaded = unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 2);
+                ffi::luaL_getsubtable(self.state, ffi::LUA_REGISTRYINDEX, cstr!("_LOADED"));
+                Table(self.pop_ref(self.state))
+            })
+        };
+        loaded.raw_set(name, module.clone())?;
+        self.globals().raw_set(name, module)
+    }
+
+    // Fills in the name of the running callback in a `BadArgument` error, as seen by its caller.
+    // Like `luaL_argerror`, the `self` argument of a method is not counted if it was called with
+    // method syntax.
+    pub(crate) fn name_bad_argument(&self, err: Error, method: bool) -> Error {
+        match err {
+            Error::BadArgument {
+                function_name: None,
+                mut arg_index,
+                cause,
+            } => {
+                let mut function_name = None;
+                unsafe {
+                    let mut ar: ffi::lua_Debug = mem::zeroed();
+                    if ffi::lua_getstack(self.state, 0, &mut ar) != 0 {
+                        ffi::lua_getinfo(self.state, cstr!("n"), &mut ar);
+                        if !ar.name.is_null() {
+                            function_name =
+                                Some(CStr::from_ptr(ar.name).to_string_lossy().into_owned());
+                        }
+                        if method && arg_index > 1 && !ar.namewhat.is_null()
+                            && CStr::from_ptr(ar.namewhat).to_bytes() == b"method"
+                        {
+                            arg_index -= 1;
+                        }
+                    }
+                }
+                Error::BadArgument {
+                    function_name,
+                    arg_index,
+                    cause,
+                }
+            }
+            err => err,
+        }
+    }
+
+    // Returns an empty `MultiValue`, reusing a buffer from the pool if there is one.
+    pub(crate) fn new_multi_value(&self) -> MultiValue<'_> {
+        unsafe {
+            match (*self.extra_data()).multi_value_pool.pop() {
+                Some(buffer) => {
+                    MultiValue(mem::transmute::<VecDeque<Value<'static>>, VecDeque<Value>>(buffer))
+                }
+                None => MultiValue::new(),
+            }
+        }
+    }
+
+    // Places the buffer of a `MultiValue` which is no longer needed into the pool, if it has
+    // allocated storage and there is room left.
+    pub(crate) fn cache_multi_value(&self, mut multi_value: MultiValue) {
+        if multi_value.0.capacity() == 0 {
+            return;
+        }
+        multi_value.0.clear();
+
+        unsafe {
+            let extra = self.extra_data();
+            if (*extra).multi_value_pool.len() < MULTI_VALUE_POOL_SIZE {
+                // The buffer is empty, so it holds no references with the `'lua` lifetime.
+                (*extra)
+                    .multi_value_pool
+                    .push(mem::transmute::<VecDeque<Value>, VecDeque<Value<'static>>>(
+                        multi_value.0,
+                    ));
+            }
+        }
+    }
+
+    pub(crate) unsafe fn userdata_metatable<T: UserData>(&self) -> c_int {
+        if let Some(table_id) = self.registered_userdata_metatable::<T>() {
+            return table_id;
+        }
+
+        let mut methods = UserDataMethods::new();
+        T::add_methods(&mut methods);
+        self.register_userdata_metatable(methods)
+    }
+
+    // Returns the registry id of the metatable for userdata of type `T`, if one has been created.
+    pub(crate) unsafe fn registered_userdata_metatable<T: 'static>(&self) -> Option<c_int> {
+        (*self.extra_data())
+            .registered_userdata
+            .get(&TypeId::of::<T>())
+            .cloned()
+    }
+
+    unsafe fn register_userdata_metatable<'lua, T: 'static>(
+        &'lua self,
+        methods: UserDataMethods<'lua, T>,
+    ) -> c_int {
+        stack_guard(self.state, 0, move || {
+            check_stack(self.state, 6);
+
+            let bases = methods
+                .bases
+                .into_iter()
+                .map(|base| ((base.metatable)(self), base.type_id, base.coercion))
+                .collect::<Vec<_>>();
+
+            self.push_method_table();
+            let method_table = ffi::lua_gettop(self.state);
+
+            ffi::lua_newtable(self.state);
+            let metatable = ffi::lua_gettop(self.state);
+
+            let has_methods = !methods.methods.is_empty() || !bases.is_empty();
+
+            if has_methods {
+                push_string(self.state, "__index");
+                ffi::lua_newtable(self.state);
+
+                for (k, m) in methods.methods {
+                    push_string(self.state, &k);
+                    self.push_value(
+                        self.state,
+                        Value::Function(self.create_userdata_callback(m, metatable, method_table)),
+                    );
+                    ffi::lua_rawset(self.state, -3);
+                }
+
+                if !bases.is_empty() {
+                    check_stack(self.state, bases.len() as c_int + 2);
+                    ffi::lua_newtable(self.state);
+                    push_string(self.state, "__index");
+                    for &(base_id, _, _) in &bases {
+                        ffi::lua_rawgeti(
+                            self.state,
+                            ffi::LUA_REGISTRYINDEX,
+                            base_id as ffi::lua_Integer,
+                        );
+                        replace_with_methods_table(self.state);
+                    }
+                    ffi::lua_pushcclosure(self.state, base_index_impl, bases.len() as c_int);
+                    ffi::lua_rawset(self.state, -3);
+                    ffi::lua_setmetatable(self.state, -2);
+                }
+
+                ffi::lua_rawset(self.state, -3);
+            }
+
+            for (k, m) in methods.meta_methods {
+                if k == MetaMethod::Index && has_methods {
+                    push_string(self.state, "__index");
+                    ffi::lua_pushvalue(self.state, -1);
+                    ffi::lua_gettable(self.state, -3);
+                    self.push_value(
+                        self.state,
+                        Value::Function(self.create_userdata_callback(m, metatable, method_table)),
+                    );
+                    ffi::lua_pushcclosure(self.state, meta_index_impl, 2);
+                    ffi::lua_rawset(self.state, -3);
+                } else {
+                    push_string(self.state, k.name());
+                    self.push_value(
+                        self.state,
+                        Value::Function(self.create_userdata_callback(m, metatable, method_table)),
+                    );
+                    ffi::lua_rawset(self.state, -3);
+                }
+            }
+
+            // Used by Lua in error messages and the default `tostring`, unless overridden by a
+            // meta field.
+            push_string(self.state, "__name");
+            push_string(self.state, type_name::<T>());
+            ffi::lua_rawset(self.state, -3);
+
+            for (k, v) in methods.meta_fields {
+                push_string(self.state, k.name());
+                match v {
+                    MetaFieldValue::Boolean(b) => ffi::lua_pushboolean(self.state, b as c_int),
+                    MetaFieldValue::Integer(i) => ffi::lua_pushinteger(self.state, i),
+                    MetaFieldValue::Number(n) => ffi::lua_pushnumber(self.state, n),
+                    MetaFieldValue::String(s) => push_string(self.state, &s),
+                }
+                ffi::lua_rawset(self.state, -3);
+            }
+
+            push_string(self.state, "__gc");
+            if let Some(destructor) = methods.destructor {
+                self.push_value(
+                    self.state,
+                    Value::Function(self.create_callback_function(destructor, None)),
+                );
+            } else {
+                ffi::lua_pushcfunction(self.state, userdata_destructor::<RefCell<T>>);
+            }
+            ffi::lua_rawset(self.state, -3);
+
+            push_string(self.state, "__metatable");
+            ffi::lua_pushboolean(self.state, 0);
+            ffi::lua_rawset(self.state, -3);
+
+            let metatable = ffi::lua_topointer(self.state, -1);
+            let extra = self.extra_data();
+            for (_, type_id, coercion) in bases {
+                (*extra)
+                    .userdata_coercions
+                    .insert((metatable as usize, type_id), coercion);
+            }
+
+            let id = ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX);
+            (*extra).registered_userdata.insert(TypeId::of::<T>(), id);
+            // The method table is kept alive by the methods of the type.
+            ffi::lua_pop(self.state, 1);
+            id
+        })
+    }
+
+    // Returns whether the table has the array metatable, without creating the array metatable.
+    #[cfg(feature = "serde")]
+    pub(crate) fn is_array_table(&self, table: &Table) -> bool {
+        unsafe {
+            stack_guard(self.state, 0, || {
+                check_stack(self.state, 3);
+
+                self.push_ref(self.state, &table.0);
+                if ffi::lua_getmetatable(self.state, -1) == 0 {
+                    ffi::lua_pop(self.state, 1);
+                    return false;
+                }
+                ffi::lua_pushlightuserdata(
+                    self.state,
+                    &ARRAY_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+                );
+                ffi::lua_rawget(self.state, ffi::LUA_REGISTRYINDEX);
+                let is_array = ffi::lua_rawequal(self.state, -1, -2) != 0;
+                ffi::lua_pop(self.state, 3);
+                is_array
+            })
+        }
+    }
+
+    // Returns the serializer of the userdata type with the given metatable.
+    #[cfg(feature = "serde")]
+    pub(crate) unsafe fn userdata_serializer(
+        &self,
+        metatable: *const c_void,
+    ) -> Option<SerializeUserData> {
+        let extra = self.extra_data();
+        (*extra)
+            .userdata_serializers
+            .get(&(metatable as usize))
+            .cloned()
+    }
+
+    // Returns the `Coercion` to the base type `type_id`, declared by the type with the given
+    // metatable.
+    pub(crate) unsafe fn userdata_coercion(
+        &self,
+        metatable: *const c_void,
+        type_id: TypeId,
+    ) -> Option<&dyn Any> {
+        let extra = self.extra_data();
+        (*extra)
+            .userdata_coercions
+            .get(&(metatable as usize, type_id))
+            .map(|coercion| &**coercion)
+    }
+}
+
+// Used if both an __index metamethod is set and regular methods, checks methods table first, then
+// __index metamethod.
+unsafe extern "C" fn meta_index_impl(state: *mut ffi::lua_State) -> c_int {
+    check_stack(state, 2);
+
+    ffi::lua_pushvalue(state, -1);
+    ffi::lua_gettable(state, ffi::lua_upvalueindex(1));
+    if ffi::lua_isnil(state, -1) == 0 {
+        ffi::lua_insert(state, -3);
+        ffi::lua_pop(state, 2);
+        1
+    } else {
+        ffi::lua_pop(state, 1);
+        ffi::lua_pushvalue(state, ffi::lua_upvalueindex(2));
+        ffi::lua_insert(state, -3);
+        ffi::lua_call(state, 2, 1);
+        1
+    }
+}
+
+// Returns whether a panic in the running callback should be caught, according to the panic mode
+// given as a boolean at `index` if there is one, or to the mode set with `set_panic_mode`.
+unsafe fn catches_panics(state: *mut ffi::lua_State, index: Option<c_int>) -> bool {
+    if let Some(index) = index {
+        if ffi::lua_type(state, index) == ffi::LUA_TBOOLEAN {
+            return ffi::lua_toboolean(state, index) != 0;
+        }
+    }
+
+    let lua = Lua {
+        state,
+        main_state: main_state(state),
+        ephemeral: true,
+    };
+    check_stack(state, 1);
+    (*lua.extra_data()).panic_mode == PanicMode::Catch
+}
+
+// Pops the `nargs` arguments of a callback from the stack, calls it and pushes its results.
+// Returns the number of values to return, and whether the callback has requested to yield them
+// instead, and to be called again on resume.
+unsafe fn call_callback<'lua, F>(
+    lua: &'lua Lua,
+    nargs: c_int,
+    f: F,
+) -> Result<(c_int, Option<Resume>)>
+where
+    F: FnOnce(MultiValue<'lua>) -> Result<MultiValue<'lua>>,
+{
+    let mut args = lua.new_multi_value();
+    check_stack(lua.state, 1);
+    for _ in 0..nargs {
+        args.push_front(lua.pop_value(lua.state));
+    }
+
+    let extra = lua.extra_data();
+    let depth = CallbackDepth::enter(extra);
+
+    let mut results = f(args)?;
+
+    // If the callback has requested to yield, the values to yield are already on top of the stack
+    // and its return values are discarded.
+    if let Some((yield_depth, nyield, resume)) = (*extra).pending_yield {
+        if yield_depth == depth.0 {
+            (*extra).pending_yield = None;
+            return Ok((nyield, Some(resume)));
+        }
+    }
+
+    let nresults = results.len() as c_int;
+
+    check_stack(lua.state, nresults);
+
+    for r in results.drain(..) {
+        lua.push_value(lua.state, r);
+    }
+    lua.cache_multi_value(results);
+
+    Ok((nresults, None))
+}
+
+// Finalizer of the userdata holding a callback, which also counts the callbacks left for
+// `Lua::ref_stats`. The extra data is finalized after every callback, as it was created first.
+unsafe extern "C" fn callback_destructor<T>(state: *mut ffi::lua_State) -> c_int {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    (*extra).live_callbacks = (*extra).live_callbacks.saturating_sub(1);
+    userdata_destructor::<T>(state)
+}
+
+// Drops the method table of a userdata type, along with all of its methods.
+unsafe extern "C" fn method_table_destructor(state: *mut ffi::lua_State) -> c_int {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    let methods = ffi::lua_touserdata(state, 1) as *mut Option<MethodTable>;
+    if let Some(ref methods) = *methods {
+        (*extra).live_callbacks = (*extra).live_callbacks.saturating_sub(methods.len());
+    }
+    userdata_destructor::<MethodTable>(state)
+}
+
+// Returns the results of a callback, or yields them if it requested to yield, in which case
+// `resume` is the continuation calling the callback again.
+unsafe fn callback_return(
+    state: *mut ffi::lua_State,
+    nresults: c_int,
+    yielding: Option<Resume>,
+    resume: ffi::lua_KFunction,
+) -> c_int {
+    match yielding {
+        Some(Resume::Callback) => ffi::lua_yieldk(state, nresults, ptr::null_mut(), Some(resume)),
+        Some(Resume::Return) => ffi::lua_yield(state, nresults),
+        #[cfg(feature = "async")]
+        Some(Resume::Await) => {
+            ffi::lua_yieldk(state, nresults, ptr::null_mut(), Some(await_resume))
+        }
+        None => nresults,
+    }
+}
+
+// Continuation of a callback which awaited a future. `AsyncCall` resumes the thread with `true`
+// and the output of the future, or with `false` and its error, which is raised.
+#[cfg(feature = "async")]
+unsafe extern "C" fn await_resume(
+    state: *mut ffi::lua_State,
+    _status: c_int,
+    _ctx: ffi::lua_KContext,
+) -> c_int {
+    if ffi::lua_type(state, 1) != ffi::LUA_TBOOLEAN {
+        push_string(state, "attempt to resume a thread awaiting a Rust future");
+        ffi::lua_error(state)
+    } else if ffi::lua_toboolean(state, 1) != 0 {
+        ffi::lua_remove(state, 1);
+        ffi::lua_gettop(state)
+    } else {
+        ffi::lua_settop(state, 2);
+        ffi::lua_error(state)
+    }
+}
+
+// Used as the `__index` metamethod of the methods table of a type with base types, looks up the
+// key in the methods tables of the base types, given as upvalues.
+unsafe extern "C" fn base_index_impl(state: *mut ffi::lua_State) -> c_int {
+    check_stack(state, 2);
+
+    let mut i = 1;
+    while ffi::lua_type(state, ffi::lua_upvalueindex(i)) != ffi::LUA_TNONE {
+        if ffi::lua_istable(state, ffi::lua_upvalueindex(i)) != 0 {
+            ffi::lua_pushvalue(state, 2);
+            ffi::lua_gettable(state, ffi::lua_upvalueindex(i));
+            if ffi::lua_isnil(state, -1) == 0 {
+                return 1;
+            }
+            ffi::lua_pop(state, 1);
+        }
+        i += 1;
+    }
+
+    ffi::lua_pushnil(state);
+    1
+}
+
+// Replaces the userdata metatable on top of the stack with its table of regular methods, or nil if
+// there is none.
+unsafe fn replace_with_methods_table(state: *mut ffi::lua_State) {
+    push_string(state, "__index");
+    ffi::lua_rawget(state, -2);
+
+    // With a custom `__index` metamethod, the methods table is the first upvalue of the combined
+    // index function.
+    if ffi::lua_type(state, -1) == ffi::LUA_TFUNCTION {
+        if ffi::lua_getupvalue(state, -1, 1).is_null() {
+            ffi::lua_pushnil(state);
+        }
+        ffi::lua_replace(state, -2);
+    }
+
+    if ffi::lua_istable(state, -1) == 0 {
+        ffi::lua_pop(state, 1);
+        ffi::lua_pushnil(state);
+    }
+    ffi::lua_replace(state, -2);
+}
+
+// Installs the registry entries and safe replacements of standard functions which rlua relies on,
+// unless they have already been installed in the state. `allocator` is the userdata of the
+// allocator of the state.
+unsafe fn init_registry(state: *mut ffi::lua_State, allocator: *mut AllocatorState) {
+    stack_guard(state, 0, || {
+        ffi::lua_pushlightuserdata(state, &EXTRA_DATA_REGISTRY_KEY as *const u8 as *mut c_void);
+        if ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TUSERDATA {
+            let extra = get_userdata::<ExtraData>(state, -1);
+            (*extra).allocator = allocator;
+            (*extra).resource_limits.max_memory = None;
+            (*extra).resource_limits.max_table_entries = None;
+            *(ffi::lua_getextraspace(state) as *mut *mut ExtraData) = extra;
+            ffi::lua_pop(state, 1);
+            return;
+        }
+        ffi::lua_pop(state, 1);
+
+        // Create the function metatable
+
+        ffi::lua_pushlightuserdata(
+            state,
+            &FUNCTION_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+        );
+
+        ffi::lua_newtable(state);
+
+        push_string(state, "__gc");
+        ffi::lua_pushcfunction(state, callback_destructor::<RefCell<Callback>>);
+        ffi::lua_rawset(state, -3);
+
+        push_string(state, "__metatable");
+        ffi::lua_pushboolean(state, 0);
+        ffi::lua_rawset(state, -3);
+
+        ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+
+        // Create the method metatable
+
+        ffi::lua_pushlightuserdata(
+            state,
+            &METHOD_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
+        );
+
+        ffi::lua_newtable(state);
+
+        push_string(state, "__gc");
+        ffi::lua_pushcfunction(state, method_table_destructor);
+        ffi::lua_rawset(state, -3);
+
+        push_string(state, "__metatable");
+        ffi::lua_pushboolean(state, 0);
+        ffi::lua_rawset(state, -3);
+
+        ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+
+        // Create the extra data registry entry
+
+        ffi::lua_pushlightuserdata(
+            state,
+            &EXTRA_DATA_REGISTRY_KEY as *const u8 as *mut c_void,
+        );
+
+        push_userdata::<ExtraData>(
+            state,
+            ExtraData {
+                ref_thread: ptr::null_mut(),
+                ref_free: Vec::new(),
+                registry_refs: 0,
+                registry_unref_list: Rc::new(RefCell::new(Vec::new())),
+                #[cfg(debug_assertions)]
+                ref_origins: None,
+                live_callbacks: 0,
+                registered_userdata: HashMap::new(),
+                thread_pool: Vec::new(),
+                thread_pool_size: 0,
+                callback_depth: 0,
+                pending_yield: None,
+                #[cfg(feature = "async")]
+                awaiting_thread: ptr::null_mut(),
+                userdata_coercions: HashMap::new(),
+                duration_format: DurationFormat::Seconds,
+                strict_numbers: false,
+                strict_globals: false,
+                global_audit: None,
+                require_trace: None,
+                prelude: None,
+                weak_ref_count: 0,
+                light_userdata_types: HashMap::new(),
+                resource_limits: ResourceLimits::default(),
+                executions: 0,
+                budget: None,
+                hook_interval: 0,
+                time_slice: None,
+                report: None,
+                source_maps: Vec::new(),
+                debugger: None,
+                coverage: None,
+                watchdog: None,
+                deadline: None,
+                panic_mode: PanicMode::Resume,
+                error_formatter: None,
+                multi_value_pool: Vec::new(),
+                #[cfg(feature = "serde")]
+                userdata_serializers: HashMap::new(),
+                allocator,
+            },
+        );
+
+        ffi::lua_newtable(state);
+
+        push_string(state, "__gc");
+        ffi::lua_pushcfunction(state, userdata_destructor::<ExtraData>);
+        ffi::lua_rawset(state, -3);
+
+        ffi::lua_setmetatable(state, -2);
+
+        // Every thread copies the extra space of the main thread when it is created, so
+        // this makes the extra data reachable from all threads without a registry lookup.
+        let extra = get_userdata::<ExtraData>(state, -1);
+        *(ffi::lua_getextraspace(state) as *mut *mut ExtraData) = extra;
+
+        ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+
+        // Create the thread holding the values of `LuaRef`s on its stack, whose first slot
+        // always holds nil.
+
+        (*extra).ref_thread = ffi::lua_newthread(state);
+        ffi::luaL_ref(state, ffi::LUA_REGISTRYINDEX);
+        ffi::lua_pushnil((*extra).ref_thread);
+
+        // Override pcall, xpcall, setmetatable and coroutine.resume with versions that
+        // cannot be used to cause unsafety, and rawset with a version that refuses to modify
+        // frozen tables. States created outside of rlua may not have opened every library, so
+        // only functions which exist are replaced.
+
+        ffi::lua_rawgeti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+
+        let safe_functions: [(&str, ffi::lua_CFunction); 4] = [
+            ("pcall", safe_pcall),
+            ("xpcall", safe_xpcall),
+            ("setmetatable", safe_setmetatable),
+            ("rawset", frozen_rawset),
+        ];
+        for &(name, function) in &safe_functions {
+            push_string(state, name);
+            let exists = ffi::lua_rawget(state, -2) != ffi::LUA_TNIL;
+            ffi::lua_pop(state, 1);
+            if exists {
+                push_string(state, name);
+                ffi::lua_pushcfunction(state, function);
+                ffi::lua_rawset(state, -3);
+            }
+        }
+
+        push_string(state, "coroutine");
+        if ffi::lua_rawget(state, -2) == ffi::LUA_TTABLE {
+            push_string(state, "resume");
+            push_string(state, "resume");
+            if ffi::lua_rawget(state, -3) == ffi::LUA_TNIL {
+                ffi::lua_pop(state, 2);
+            } else {
+                ffi::lua_pushcclosure(state, safe_resume, 1);
+                ffi::lua_rawset(state, -3);
+            }
+        }
+
+        ffi::lua_pop(state, 2);
+    });
+}
+
+// Used as the `__index` metamethod of the globals table of states created by `Lua::new_lazy`,
+// opens standard libraries the first time their global variable (or `require`) is read.
+unsafe extern "C" fn lazy_library_index(state: *mut ffi::lua_State) -> c_int {
+    if ffi::lua_type(state, 2) == ffi::LUA_TSTRING {
+        let mut len = 0;
+        let data = ffi::lua_tolstring(state, 2, &mut len);
+        let key = slice::from_raw_parts(data as *const u8, len);
+
+        if key == b"require" {
+            ffi::luaL_requiref(state, cstr!("package"), open_package, 1);
+            ffi::lua_pop(state, 1);
+            ffi::lua_pushvalue(state, 2);
+            ffi::lua_rawget(state, 1);
+            return 1;
+        }
+
+        for &(name, open) in LAZY_LIBRARIES {
+            if &name.as_bytes()[..name.len() - 1] == key {
+                ffi::luaL_requiref(state, name.as_ptr() as *const c_char, open, 1);
+                return 1;
+            }
+        }
+    }
+
+    ffi::lua_pushnil(state);
+    1
+}
+
+// Opens the package library, and makes the standard libraries which have not been opened yet
+// available to `require`.
+unsafe extern "C" fn open_package(state: *mut ffi::lua_State) -> c_int {
+    ffi::luaopen_package(state);
+    ffi::luaL_getsubtable(state, ffi::LUA_REGISTRYINDEX, cstr!("_LOADED"));
+    ffi::luaL_getsubtable(state, ffi::LUA_REGISTRYINDEX, cstr!("_PRELOAD"));
+    for &(name, open) in LAZY_LIBRARIES {
+        let name = name.as_ptr() as *const c_char;
+        let loaded = ffi::lua_getfield(state, -2, name) != ffi::LUA_TNIL;
+        ffi::lua_pop(state, 1);
+        if !loaded {
+            ffi::lua_pushcfunction(state, open);
+            ffi::lua_setfield(state, -2, name);
+        }
+    }
+    ffi::lua_pop(state, 2);
+    1
+}
+
+// Pops a value, and adds an entry for it to a snapshot if it is a table which has not been seen
+// yet.
+unsafe fn snapshot_add(
+    state: *mut ffi::lua_State,
+    entries: c_int,
+    seen: c_int,
+    count: &mut ffi::lua_Integer,
+) {
+    if ffi::lua_type(state, -1) != ffi::LUA_TTABLE {
+        ffi::lua_pop(state, 1);
+        return;
+    }
+    ffi::lua_pushvalue(state, -1);
+    if ffi::lua_rawget(state, seen) != ffi::LUA_TNIL {
+        ffi::lua_pop(state, 2);
+        return;
+    }
+    ffi::lua_pop(state, 1);
+    ffi::lua_pushvalue(state, -1);
+    ffi::lua_pushboolean(state, 1);
+    ffi::lua_rawset(state, seen);
+
+    ffi::lua_createtable(state, 3, 0);
+    ffi::lua_insert(state, -2);
+    ffi::lua_rawseti(state, -2, 1);
+    *count += 1;
+    ffi::lua_rawseti(state, entries, *count);
+}
+
+// Sets the `__index` and `__newindex` metamethods of the globals table used by strict mode,
+// unless they are already set, keeping the previous metamethods as their upvalues.
+unsafe fn install_strict_globals(state: *mut ffi::lua_State) {
+    push_globals(state);
+    if ffi::lua_getmetatable(state, -1) == 0 {
+        ffi::lua_newtable(state);
+        ffi::lua_pushvalue(state, -1);
+        ffi::lua_setmetatable(state, -3);
+    }
+
+    let metamethods: [(&str, ffi::lua_CFunction); 2] = [
+        ("__index", strict_global_index),
+        ("__newindex", strict_global_newindex),
+    ];
+    for &(name, metamethod) in &metamethods {
+        push_string(state, name);
+        ffi::lua_rawget(state, -2);
+        match ffi::lua_tocfunction(state, -1) {
+            Some(f) if f as usize == metamethod as usize => ffi::lua_pop(state, 1),
+            _ => {
+                ffi::lua_pushcclosure(state, metamethod, 1);
+                push_string(state, name);
+                ffi::lua_insert(state, -2);
+                ffi::lua_rawset(state, -3);
+            }
+        }
+    }
+    ffi::lua_pop(state, 2);
+}
+
+// Pushes the table of the names of declared global variables, creating it if necessary.
+unsafe fn push_declared_globals(state: *mut ffi::lua_State) {
+    let key = &DECLARED_GLOBALS_REGISTRY_KEY as *const u8 as *mut c_void;
+    ffi::lua_pushlightuserdata(state, key);
+    if ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) != ffi::LUA_TTABLE {
+        ffi::lua_pop(state, 1);
+        ffi::lua_newtable(state);
+        ffi::lua_pushlightuserdata(state, key);
+        ffi::lua_pushvalue(state, -2);
+        ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+    }
+}
+
+// Returns whether strict mode is enabled, and the key at `index` names a global variable which
+// has not been declared.
+unsafe fn is_undeclared_global(state: *mut ffi::lua_State, index: c_int) -> bool {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    if !(*extra).strict_globals || ffi::lua_type(state, index) != ffi::LUA_TSTRING {
+        return false;
+    }
+    push_declared_globals(state);
+    ffi::lua_pushvalue(state, index);
+    let declared = ffi::lua_rawget(state, -2) != ffi::LUA_TNIL;
+    ffi::lua_pop(state, 2);
+    !declared
+}
+
+// Returns the level of the function which accessed the global variable whose metamethod is
+// running. This is the caller of the metamethod, unless the access was forwarded by the proxy
+// installed by `Lua::audit_globals`.
+unsafe fn global_accessor_level(state: *mut ffi::lua_State) -> c_int {
+    let mut ar: ffi::lua_Debug = mem::zeroed();
+    if ffi::lua_getstack(state, 1, &mut ar) != 0 {
+        ffi::lua_getinfo(state, cstr!("f"), &mut ar);
+        let function = ffi::lua_tocfunction(state, -1).map(|f| f as usize);
+        ffi::lua_pop(state, 1);
+        if function == Some(audited_global_index as ffi::lua_CFunction as usize)
+            || function == Some(audited_global_newindex as ffi::lua_CFunction as usize)
+        {
+            return 2;
+        }
+    }
+    1
+}
+
+// Returns whether the function which accessed a global variable in the running metamethod is one
+// of the given kinds, as reported in the `what` field of `lua_Debug`.
+unsafe fn caller_is(state: *mut ffi::lua_State, kinds: &[&[u8]]) -> bool {
+    let mut ar: ffi::lua_Debug = mem::zeroed();
+    if ffi::lua_getstack(state, global_accessor_level(state), &mut ar) == 0 {
+        return true;
+    }
+    ffi::lua_getinfo(state, cstr!("S"), &mut ar);
+    let what = CStr::from_ptr(ar.what).to_bytes();
+    kinds.contains(&what)
+}
+
+// Raises an error about the global variable whose name is at index 2, pointing at the line of
+// the function which accessed it.
+unsafe fn strict_global_error(state: *mut ffi::lua_State, prefix: &str, suffix: &str) -> ! {
+    ffi::luaL_where(state, global_accessor_level(state));
+    push_string(state, prefix);
+    ffi::lua_pushvalue(state, 2);
+    push_string(state, suffix);
+    ffi::lua_concat(state, 4);
+    ffi::lua_error(state)
+}
+
+// The `__index` metamethod of the globals table in strict mode, its upvalue is the previous
+// `__index` metamethod.
+unsafe extern "C" fn strict_global_index(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_settop(state, 2);
+    match ffi::lua_type(state, ffi::lua_upvalueindex(1)) {
+        ffi::LUA_TNIL => ffi::lua_pushnil(state),
+        ffi::LUA_TFUNCTION => {
+            ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+            ffi::lua_pushvalue(state, 1);
+            ffi::lua_pushvalue(state, 2);
+            ffi::lua_call(state, 2, 1);
+        }
+        _ => {
+            ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+            ffi::lua_pushvalue(state, 2);
+            ffi::lua_gettable(state, -2);
+        }
+    }
 
-                let thread_state = ffi::lua_newthread(self.state);
-                self.push_ref(thread_state, &func.0);
+    let is_nil = ffi::lua_isnil(state, -1) != 0;
+    if is_nil && is_undeclared_global(state, 2) && !caller_is(state, &[b"C"]) {
+        strict_global_error(state, "variable '", "' is not declared");
+    }
+    1
+}
 
-                Thread(self.pop_ref(self.state))
-            })
+// The `__newindex` metamethod of the globals table in strict mode, its upvalue is the previous
+// `__newindex` metamethod.
+unsafe extern "C" fn strict_global_newindex(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_settop(state, 3);
+    if is_undeclared_global(state, 2) {
+        if !caller_is(state, &[b"main", b"C"]) {
+            strict_global_error(state, "assignment to undeclared variable '", "'");
         }
+        push_declared_globals(state);
+        ffi::lua_pushvalue(state, 2);
+        ffi::lua_pushboolean(state, 1);
+        ffi::lua_rawset(state, -3);
+        ffi::lua_pop(state, 1);
     }
 
-    /// Create a Lua userdata object from a custom userdata type.
-    pub fn create_userdata<T>(&self, data: T) -> AnyUserData
-    where
-        T: UserData,
-    {
-        unsafe {
-            stack_guard(self.state, 0, move || {
-                check_stack(self.state, 3);
+    match ffi::lua_type(state, ffi::lua_upvalueindex(1)) {
+        ffi::LUA_TNIL => ffi::lua_rawset(state, 1),
+        ffi::LUA_TFUNCTION => {
+            ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+            ffi::lua_insert(state, 1);
+            ffi::lua_call(state, 3, 0);
+        }
+        _ => {
+            ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+            ffi::lua_insert(state, 2);
+            ffi::lua_settable(state, 2);
+        }
+    }
+    0
+}
 
-                push_userdata::<RefCell<T>>(self.state, RefCell::new(data));
+// Pushes the globals table. While globals are audited, the registry holds the proxy installed by
+// `Lua::audit_globals` in its place, and the table itself is kept under
+// `AUDITED_GLOBALS_REGISTRY_KEY`.
+unsafe fn push_globals(state: *mut ffi::lua_State) {
+    ffi::lua_pushlightuserdata(
+        state,
+        &AUDITED_GLOBALS_REGISTRY_KEY as *const u8 as *mut c_void,
+    );
+    if ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TNIL {
+        ffi::lua_pop(state, 1);
+        ffi::lua_rawgeti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+    }
+}
 
-                ffi::lua_rawgeti(
-                    self.state,
-                    ffi::LUA_REGISTRYINDEX,
-                    self.userdata_metatable::<T>() as ffi::lua_Integer,
-                );
+// Replaces the globals table in the registry with an empty proxy, whose metamethods report every
+// access to the callback set with `Lua::audit_globals` and forward it to the globals table, unless
+// the proxy is already installed. The metamethods have the globals table and the proxy as their
+// upvalues.
+unsafe fn install_globals_proxy(state: *mut ffi::lua_State) {
+    let key = &AUDITED_GLOBALS_REGISTRY_KEY as *const u8 as *mut c_void;
+    ffi::lua_pushlightuserdata(state, key);
+    if ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) != ffi::LUA_TNIL {
+        ffi::lua_pop(state, 1);
+        return;
+    }
+    ffi::lua_pop(state, 1);
+
+    ffi::lua_rawgeti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+    ffi::lua_pushlightuserdata(state, key);
+    ffi::lua_pushvalue(state, -2);
+    ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+
+    ffi::lua_newtable(state);
+    ffi::lua_newtable(state);
+    let metamethods: [(&str, ffi::lua_CFunction); 3] = [
+        ("__index", audited_global_index),
+        ("__newindex", audited_global_newindex),
+        ("__pairs", audited_globals_pairs),
+    ];
+    for &(name, metamethod) in &metamethods {
+        push_string(state, name);
+        ffi::lua_pushvalue(state, -4);
+        ffi::lua_pushvalue(state, -4);
+        ffi::lua_pushcclosure(state, metamethod, 2);
+        ffi::lua_rawset(state, -3);
+    }
+    push_string(state, "__metatable");
+    ffi::lua_pushboolean(state, 0);
+    ffi::lua_rawset(state, -3);
+    ffi::lua_setmetatable(state, -2);
 
-                ffi::lua_setmetatable(self.state, -2);
+    ffi::lua_rawseti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+    ffi::lua_pop(state, 1);
+}
 
-                AnyUserData(self.pop_ref(self.state))
-            })
+// Puts the globals table back into the registry in place of the proxy installed by
+// `install_globals_proxy`. Functions which still refer to the proxy keep working through it.
+unsafe fn remove_globals_proxy(state: *mut ffi::lua_State) {
+    let key = &AUDITED_GLOBALS_REGISTRY_KEY as *const u8 as *mut c_void;
+    ffi::lua_pushlightuserdata(state, key);
+    if ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TNIL {
+        ffi::lua_pop(state, 1);
+        return;
+    }
+    ffi::lua_rawseti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+    ffi::lua_pushlightuserdata(state, key);
+    ffi::lua_pushnil(state);
+    ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
+}
+
+// Calls the callback set with `Lua::audit_globals` for an access to the global variable whose
+// name is at index 2, if it was made by a Lua function.
+unsafe fn report_global_access(state: *mut ffi::lua_State, kind: AccessKind) {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    if (*extra).global_audit.is_none() || ffi::lua_type(state, 2) != ffi::LUA_TSTRING {
+        return;
+    }
+    let mut ar: ffi::lua_Debug = mem::zeroed();
+    if ffi::lua_getstack(state, 1, &mut ar) == 0 {
+        return;
+    }
+    ffi::lua_getinfo(state, cstr!("Sl"), &mut ar);
+    if CStr::from_ptr(ar.what).to_bytes() == b"C" {
+        return;
+    }
+
+    let mut len = 0;
+    let name = ffi::lua_tolstring(state, 2, &mut len);
+    let access = GlobalAccess {
+        name: StdString::from_utf8_lossy(slice::from_raw_parts(name as *const u8, len))
+            .into_owned(),
+        kind,
+        source: CStr::from_ptr(ar.short_src.as_ptr())
+            .to_string_lossy()
+            .into_owned(),
+        line: if ar.currentline > 0 {
+            Some(ar.currentline as u32)
+        } else {
+            None
+        },
+    };
+    let callback = panic::AssertUnwindSafe(&mut (*extra).global_audit);
+    callback_error(state, move || {
+        if let Some(ref mut callback) = *callback.0 {
+            callback(access);
         }
+        Ok(())
+    });
+}
+
+// The `__index` metamethod of the proxy of the globals table, which replaces the globals table
+// itself by the proxy so that it is also audited when reached through `_G`.
+unsafe extern "C" fn audited_global_index(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_settop(state, 2);
+    report_global_access(state, AccessKind::Read);
+    ffi::lua_pushvalue(state, 2);
+    ffi::lua_gettable(state, ffi::lua_upvalueindex(1));
+    if ffi::lua_rawequal(state, -1, ffi::lua_upvalueindex(1)) != 0 {
+        ffi::lua_pushvalue(state, ffi::lua_upvalueindex(2));
     }
+    1
+}
 
-    /// Returns a handle to the global environment.
-    pub fn globals(&self) -> Table {
-        unsafe {
-            stack_guard(self.state, 0, move || {
-                check_stack(self.state, 2);
-                ffi::lua_rawgeti(self.state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
-                Table(self.pop_ref(self.state))
-            })
+// The `__newindex` metamethod of the proxy of the globals table.
+unsafe extern "C" fn audited_global_newindex(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_settop(state, 3);
+    report_global_access(state, AccessKind::Write);
+    ffi::lua_settable(state, ffi::lua_upvalueindex(1));
+    0
+}
+
+// The `__pairs` metamethod of the proxy of the globals table, which iterates over the globals
+// table without reporting accesses.
+unsafe extern "C" fn audited_globals_pairs(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+    ffi::lua_pushvalue(state, ffi::lua_upvalueindex(2));
+    ffi::lua_pushcclosure(state, audited_globals_next, 2);
+    ffi::lua_pushvalue(state, ffi::lua_upvalueindex(2));
+    ffi::lua_pushnil(state);
+    3
+}
+
+// The iterator returned by the `__pairs` metamethod of the proxy of the globals table.
+unsafe extern "C" fn audited_globals_next(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_settop(state, 2);
+    if ffi::lua_next(state, ffi::lua_upvalueindex(1)) == 0 {
+        ffi::lua_pushnil(state);
+        return 1;
+    }
+    if ffi::lua_rawequal(state, -1, ffi::lua_upvalueindex(1)) != 0 {
+        ffi::lua_pop(state, 1);
+        ffi::lua_pushvalue(state, ffi::lua_upvalueindex(2));
+    }
+    2
+}
+
+// Returns whether the standard libraries are opened the first time they are used, as in states
+// created by `Lua::new_lazy`.
+unsafe fn has_lazy_libraries(state: *mut ffi::lua_State) -> bool {
+    push_globals(state);
+    let lazy = ffi::lua_getmetatable(state, -1) != 0 && {
+        push_string(state, "__index");
+        ffi::lua_rawget(state, -2);
+        let index = ffi::lua_tocfunction(state, -1);
+        ffi::lua_pop(state, 2);
+        index.map(|f| f as usize) == Some(lazy_library_index as ffi::lua_CFunction as usize)
+    };
+    ffi::lua_pop(state, 1);
+    lazy
+}
+
+// Replaces `require` with `traced_require`, unless it has already been replaced. The package
+// library is opened first in states created by `Lua::new_lazy`, and states without it are left
+// unchanged.
+unsafe fn install_traced_require(state: *mut ffi::lua_State) {
+    ffi::luaL_getsubtable(state, ffi::LUA_REGISTRYINDEX, cstr!("_LOADED"));
+    let opened = ffi::lua_getfield(state, -1, cstr!("package")) == ffi::LUA_TTABLE;
+    ffi::lua_remove(state, -2);
+    if !opened {
+        ffi::lua_pop(state, 1);
+        if !has_lazy_libraries(state) {
+            return;
         }
+        ffi::luaL_requiref(state, cstr!("package"), open_package, 1);
     }
 
-    /// Coerces a Lua value to a string.
-    ///
-    /// The value must be a string (in which case this is a no-op) or a number.
-    pub fn coerce_string<'lua>(&'lua self, v: Value<'lua>) -> Result<String<'lua>> {
-        match v {
-            Value::String(s) => Ok(s),
-            v => unsafe {
-                stack_guard(self.state, 0, || {
-                    check_stack(self.state, 2);
-                    let ty = v.type_name();
-                    self.push_value(self.state, v);
-                    if ffi::lua_tostring(self.state, -1).is_null() {
-                        ffi::lua_pop(self.state, 1);
-                        Err(Error::FromLuaConversionError {
-                            from: ty,
-                            to: "String",
-                            message: Some("expected string or number".to_string()),
-                        })
-                    } else {
-                        Ok(String(self.pop_ref(self.state)))
-                    }
-                })
-            },
+    push_globals(state);
+    push_string(state, "require");
+    ffi::lua_rawget(state, -2);
+    let installed = ffi::lua_tocfunction(state, -1).map(|f| f as usize)
+        == Some(traced_require as ffi::lua_CFunction as usize);
+    ffi::lua_pop(state, 1);
+    if !installed {
+        push_string(state, "require");
+        ffi::lua_pushvalue(state, -3);
+        ffi::lua_pushcclosure(state, traced_require, 1);
+        ffi::lua_rawset(state, -3);
+    }
+    ffi::lua_pop(state, 2);
+}
+
+// The version of `require` installed by `Lua::trace_require`, which loads modules like the
+// original, and reports how each module was resolved before loading it. Its upvalue is the
+// package table.
+unsafe extern "C" fn traced_require(state: *mut ffi::lua_State) -> c_int {
+    ffi::luaL_checklstring(state, 1, ptr::null_mut());
+    ffi::lua_settop(state, 1);
+    ffi::luaL_getsubtable(state, ffi::LUA_REGISTRYINDEX, cstr!("_LOADED"));
+    ffi::lua_pushvalue(state, 1);
+    ffi::lua_gettable(state, 2);
+    if ffi::lua_toboolean(state, -1) != 0 {
+        report_require(state, RequireResolver::Loaded, None);
+        return 1;
+    }
+    ffi::lua_pop(state, 1);
+
+    if ffi::lua_getfield(state, ffi::lua_upvalueindex(1), cstr!("searchers")) != ffi::LUA_TTABLE {
+        ffi::luaL_where(state, 1);
+        push_string(state, "'package.searchers' must be a table");
+        ffi::lua_concat(state, 2);
+        ffi::lua_error(state);
+    }
+    // The reasons given by the searchers which did not find the module.
+    push_string(state, "");
+    let mut index = 1;
+    loop {
+        if ffi::lua_rawgeti(state, 3, index) == ffi::LUA_TNIL {
+            let mut len = 0;
+            let reasons = ffi::lua_tolstring(state, 4, &mut len);
+            let reasons = slice::from_raw_parts(reasons as *const u8, len);
+            let resolver =
+                RequireResolver::NotFound(StdString::from_utf8_lossy(reasons).into_owned());
+            report_require(state, resolver, None);
+            ffi::luaL_where(state, 1);
+            push_string(state, "module '");
+            ffi::lua_pushvalue(state, 1);
+            push_string(state, "' not found:");
+            ffi::lua_pushvalue(state, 4);
+            ffi::lua_concat(state, 5);
+            ffi::lua_error(state);
         }
+        ffi::lua_pushvalue(state, 1);
+        ffi::lua_call(state, 1, 2);
+        if ffi::lua_type(state, 5) == ffi::LUA_TFUNCTION {
+            break;
+        } else if ffi::lua_isstring(state, 5) != 0 {
+            ffi::lua_pop(state, 1);
+            ffi::lua_concat(state, 2);
+        } else {
+            ffi::lua_pop(state, 2);
+        }
+        index += 1;
     }
 
-    /// Coerces a Lua value to an integer.
-    ///
-    /// The value must be an integer, or a floating point number or a string that can be converted
-    /// to an integer. Refer to the Lua manual for details.
-    pub fn coerce_integer(&self, v: Value) -> Result<Integer> {
-        match v {
-            Value::Integer(i) => Ok(i),
-            v => unsafe {
-                stack_guard(self.state, 0, || {
-                    check_stack(self.state, 1);
-                    let ty = v.type_name();
-                    self.push_value(self.state, v);
-                    let mut isint = 0;
-                    let i = ffi::lua_tointegerx(self.state, -1, &mut isint);
-                    ffi::lua_pop(self.state, 1);
-                    if isint == 0 {
-                        Err(Error::FromLuaConversionError {
-                            from: ty,
-                            to: "integer",
-                            message: None,
-                        })
-                    } else {
-                        Ok(i)
-                    }
-                })
-            },
+    let preload = ffi::lua_getfield(state, ffi::lua_upvalueindex(1), cstr!("preload"))
+        == ffi::LUA_TTABLE && {
+        ffi::lua_pushvalue(state, 1);
+        ffi::lua_rawget(state, -2);
+        let preload = ffi::lua_rawequal(state, -1, 5) != 0;
+        ffi::lua_pop(state, 1);
+        preload
+    };
+    ffi::lua_pop(state, 1);
+    let resolver = if preload {
+        RequireResolver::Preload
+    } else {
+        RequireResolver::Searcher(index as usize)
+    };
+    report_require(state, resolver, Some(6));
+
+    // Call the loader with the name of the module and the extra value returned by the searcher,
+    // and store its result in `package.loaded`, or `true` if it returned nothing and did not store
+    // a value itself.
+    ffi::lua_pushvalue(state, 1);
+    ffi::lua_insert(state, -2);
+    ffi::lua_call(state, 2, 1);
+    if ffi::lua_isnil(state, -1) == 0 {
+        ffi::lua_pushvalue(state, 1);
+        ffi::lua_insert(state, -2);
+        ffi::lua_settable(state, 2);
+    }
+    ffi::lua_pushvalue(state, 1);
+    if ffi::lua_gettable(state, 2) == ffi::LUA_TNIL {
+        ffi::lua_pushvalue(state, 1);
+        ffi::lua_pushboolean(state, 1);
+        ffi::lua_settable(state, 2);
+        ffi::lua_pushboolean(state, 1);
+    }
+    1
+}
+
+// Calls the callback set with `Lua::trace_require` for the module whose name is at index 1,
+// resolved to the string at `path`, if any.
+unsafe fn report_require(
+    state: *mut ffi::lua_State,
+    resolver: RequireResolver,
+    path: Option<c_int>,
+) {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    if (*extra).require_trace.is_none() {
+        return;
+    }
+    let to_string = |index| {
+        let mut len = 0;
+        let data = ffi::lua_tolstring(state, index, &mut len);
+        StdString::from_utf8_lossy(slice::from_raw_parts(data as *const u8, len)).into_owned()
+    };
+    let mut ar: ffi::lua_Debug = mem::zeroed();
+    let caller = if ffi::lua_getstack(state, 1, &mut ar) != 0 {
+        stack_frame(state, &mut ar)
+    } else {
+        Frame {
+            source: "[C]".to_owned(),
+            line: None,
+            name: None,
         }
+    };
+    let trace = RequireTrace {
+        module: to_string(1),
+        resolver,
+        path: path.filter(|&index| ffi::lua_type(state, index) == ffi::LUA_TSTRING)
+            .map(to_string),
+        source: caller.source,
+        line: caller.line,
+    };
+    let callback = panic::AssertUnwindSafe(&mut (*extra).require_trace);
+    callback_error(state, move || {
+        if let Some(ref mut callback) = *callback.0 {
+            callback(trace);
+        }
+        Ok(())
+    });
+}
+
+// Pushes the table holding the values of weak references, creating it on first use.
+//
+// Uses 4 stack spaces, does not call checkstack
+unsafe fn push_weak_refs(state: *mut ffi::lua_State) {
+    let key = &WEAK_REFS_REGISTRY_KEY as *const u8 as *mut c_void;
+    ffi::lua_pushlightuserdata(state, key);
+    if ffi::lua_rawget(state, ffi::LUA_REGISTRYINDEX) == ffi::LUA_TNIL {
+        ffi::lua_pop(state, 1);
+        ffi::lua_newtable(state);
+        ffi::lua_newtable(state);
+        push_string(state, "__mode");
+        push_string(state, "v");
+        ffi::lua_rawset(state, -3);
+        ffi::lua_setmetatable(state, -2);
+        ffi::lua_pushlightuserdata(state, key);
+        ffi::lua_pushvalue(state, -2);
+        ffi::lua_rawset(state, ffi::LUA_REGISTRYINDEX);
     }
+}
 
-    /// Coerce a Lua value to a number.
-    ///
-    /// The value must be a number or a string that can be converted to a number. Refer to the Lua
-    /// manual for details.
-    pub fn coerce_number(&self, v: Value) -> Result<Number> {
-        match v {
-            Value::Number(n) => Ok(n),
-            v => unsafe {
-                stack_guard(self.state, 0, || {
-                    check_stack(self.state, 1);
-                    let ty = v.type_name();
-                    self.push_value(self.state, v);
-                    let mut isnum = 0;
-                    let n = ffi::lua_tonumberx(self.state, -1, &mut isnum);
-                    ffi::lua_pop(self.state, 1);
-                    if isnum == 0 {
-                        Err(Error::FromLuaConversionError {
-                            from: ty,
-                            to: "number",
-                            message: Some("number or string coercible to number".to_string()),
-                        })
-                    } else {
-                        Ok(n)
-                    }
-                })
-            },
+// Freezes the table at the top of the stack and the tables reachable from it, for
+// `Value::freeze_deep`, and pops it. Tables are added to a queue as they are found, so that the
+// queue also records which tables have been found.
+unsafe fn freeze_deep(state: *mut ffi::lua_State) {
+    ffi::lua_newtable(state);
+    let queue = ffi::lua_gettop(state);
+    ffi::lua_newtable(state);
+    let queued = ffi::lua_gettop(state);
+    let mut count = 0;
+    ffi::lua_pushvalue(state, queue - 1);
+    freeze_enqueue(state, queue, queued, &mut count);
+
+    let mut i = 1;
+    while i <= count {
+        ffi::lua_rawgeti(state, queue, i);
+        freeze_table(state, queue, queued, &mut count);
+        ffi::lua_pop(state, 1);
+        i += 1;
+    }
+    ffi::lua_pop(state, 3);
+}
+
+// Adds the value at the top of the stack to the queue of tables to freeze if it is a table which
+// is neither frozen nor queued, and pops it.
+unsafe fn freeze_enqueue(
+    state: *mut ffi::lua_State,
+    queue: c_int,
+    queued: c_int,
+    count: &mut ffi::lua_Integer,
+) {
+    if ffi::lua_type(state, -1) == ffi::LUA_TTABLE && !is_frozen(state, -1) {
+        ffi::lua_pushvalue(state, -1);
+        if ffi::lua_rawget(state, queued) == ffi::LUA_TNIL {
+            ffi::lua_pushvalue(state, -2);
+            ffi::lua_pushboolean(state, 1);
+            ffi::lua_rawset(state, queued);
+            *count += 1;
+            ffi::lua_pushvalue(state, -2);
+            ffi::lua_rawseti(state, queue, *count);
         }
+        ffi::lua_pop(state, 1);
     }
+    ffi::lua_pop(state, 1);
+}
 
-    /// Converts a value that implements `ToLua` into a `Value` instance.
-    pub fn pack<'lua, T: ToLua<'lua>>(&'lua self, t: T) -> Result<Value<'lua>> {
-        t.to_lua(self)
+// Freezes the table at the top of the stack, by moving its contents to a new table which only
+// its metamethods can reach, and queues the tables it refers to.
+unsafe fn freeze_table(
+    state: *mut ffi::lua_State,
+    queue: c_int,
+    queued: c_int,
+    count: &mut ffi::lua_Integer,
+) {
+    let table = ffi::lua_gettop(state);
+    ffi::lua_newtable(state);
+    let contents = ffi::lua_gettop(state);
+    ffi::lua_pushnil(state);
+    while ffi::lua_next(state, table) != 0 {
+        ffi::lua_pushvalue(state, -2);
+        freeze_enqueue(state, queue, queued, count);
+        ffi::lua_pushvalue(state, -1);
+        freeze_enqueue(state, queue, queued, count);
+        ffi::lua_pushvalue(state, -2);
+        ffi::lua_insert(state, -2);
+        ffi::lua_rawset(state, contents);
+    }
+    // Fields may be cleared while traversing a table.
+    ffi::lua_pushnil(state);
+    while ffi::lua_next(state, table) != 0 {
+        ffi::lua_pop(state, 1);
+        ffi::lua_pushvalue(state, -1);
+        ffi::lua_pushnil(state);
+        ffi::lua_rawset(state, table);
     }
 
-    /// Converts a `Value` instance into a value that implements `FromLua`.
-    pub fn unpack<'lua, T: FromLua<'lua>>(&'lua self, value: Value<'lua>) -> Result<T> {
-        T::from_lua(value, self)
+    // Copy the fields of the metatable, except for those replaced by the metamethods of frozen
+    // tables. Its `__index` field is used for the fields the table does not have.
+    ffi::lua_pushnil(state);
+    let index = ffi::lua_gettop(state);
+    ffi::lua_newtable(state);
+    let metatable = ffi::lua_gettop(state);
+    if ffi::lua_getmetatable(state, table) != 0 {
+        ffi::lua_pushnil(state);
+        while ffi::lua_next(state, -2) != 0 {
+            let name = if ffi::lua_type(state, -2) == ffi::LUA_TSTRING {
+                let mut len = 0;
+                let data = ffi::lua_tolstring(state, -2, &mut len);
+                slice::from_raw_parts(data as *const u8, len)
+            } else {
+                &[]
+            };
+            match name {
+                b"__index" => {
+                    ffi::lua_pushvalue(state, -1);
+                    freeze_enqueue(state, queue, queued, count);
+                    ffi::lua_replace(state, index);
+                }
+                b"__newindex" | b"__len" | b"__pairs" | b"__metatable" => ffi::lua_pop(state, 1),
+                _ => {
+                    ffi::lua_pushvalue(state, -2);
+                    ffi::lua_insert(state, -2);
+                    ffi::lua_rawset(state, metatable);
+                }
+            }
+        }
+        ffi::lua_pop(state, 1);
     }
 
-    /// Converts a value that implements `ToLuaMulti` into a `MultiValue` instance.
-    pub fn pack_multi<'lua, T: ToLuaMulti<'lua>>(&'lua self, t: T) -> Result<MultiValue<'lua>> {
-        t.to_lua_multi(self)
+    push_string(state, "__index");
+    ffi::lua_pushvalue(state, contents);
+    ffi::lua_pushvalue(state, index);
+    ffi::lua_pushcclosure(state, frozen_index, 2);
+    ffi::lua_rawset(state, metatable);
+    let metamethods: [(&str, ffi::lua_CFunction); 3] = [
+        ("__newindex", frozen_newindex),
+        ("__len", frozen_len),
+        ("__pairs", frozen_pairs),
+    ];
+    for &(name, metamethod) in &metamethods {
+        push_string(state, name);
+        ffi::lua_pushvalue(state, contents);
+        ffi::lua_pushcclosure(state, metamethod, 1);
+        ffi::lua_rawset(state, metatable);
     }
+    push_string(state, "__metatable");
+    ffi::lua_pushboolean(state, 0);
+    ffi::lua_rawset(state, metatable);
+    ffi::lua_pushlightuserdata(state, &FROZEN_METATABLE_KEY as *const u8 as *mut c_void);
+    ffi::lua_pushboolean(state, 1);
+    ffi::lua_rawset(state, metatable);
+
+    ffi::lua_setmetatable(state, table);
+    ffi::lua_pop(state, 2);
+}
 
-    /// Converts a `MultiValue` instance into a value that implements `FromLuaMulti`.
-    pub fn unpack_multi<'lua, T: FromLuaMulti<'lua>>(
-        &'lua self,
-        value: MultiValue<'lua>,
-    ) -> Result<T> {
-        T::from_lua_multi(value, self)
+// Returns whether the value at `index` is a table frozen by `Value::freeze_deep`.
+unsafe fn is_frozen(state: *mut ffi::lua_State, index: c_int) -> bool {
+    if ffi::lua_getmetatable(state, index) == 0 {
+        return false;
     }
+    ffi::lua_pushlightuserdata(state, &FROZEN_METATABLE_KEY as *const u8 as *mut c_void);
+    let frozen = ffi::lua_rawget(state, -2) != ffi::LUA_TNIL;
+    ffi::lua_pop(state, 2);
+    frozen
+}
 
-    fn create_callback_function<'lua>(&'lua self, func: Callback<'lua>) -> Function<'lua> {
-        unsafe extern "C" fn callback_call_impl(state: *mut ffi::lua_State) -> c_int {
-            callback_error(state, || {
-                let lua = Lua {
-                    state: state,
-                    main_state: main_state(state),
-                    ephemeral: true,
-                };
+unsafe fn frozen_error(state: *mut ffi::lua_State) -> ! {
+    ffi::luaL_where(state, 1);
+    push_string(state, "attempt to modify a frozen table");
+    ffi::lua_concat(state, 2);
+    ffi::lua_error(state)
+}
 
-                let func = get_userdata::<RefCell<Callback>>(state, ffi::lua_upvalueindex(1));
-                let mut func = if let Ok(func) = (*func).try_borrow_mut() {
-                    func
-                } else {
-                    lua_panic!(
-                        state,
-                        "recursive callback function call would mutably borrow function twice"
-                    );
-                };
+// The `__index` metamethod of frozen tables, its upvalues are the contents of the table and the
+// `__index` field of its original metatable.
+unsafe extern "C" fn frozen_index(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_settop(state, 2);
+    ffi::lua_pushvalue(state, 2);
+    if ffi::lua_rawget(state, ffi::lua_upvalueindex(1)) != ffi::LUA_TNIL {
+        return 1;
+    }
+    match ffi::lua_type(state, ffi::lua_upvalueindex(2)) {
+        ffi::LUA_TNIL => {}
+        ffi::LUA_TFUNCTION => {
+            ffi::lua_pushvalue(state, ffi::lua_upvalueindex(2));
+            ffi::lua_pushvalue(state, 1);
+            ffi::lua_pushvalue(state, 2);
+            ffi::lua_call(state, 2, 1);
+        }
+        _ => {
+            ffi::lua_pushvalue(state, ffi::lua_upvalueindex(2));
+            ffi::lua_pushvalue(state, 2);
+            ffi::lua_gettable(state, -2);
+        }
+    }
+    1
+}
 
-                let nargs = ffi::lua_gettop(state);
-                let mut args = MultiValue::new();
-                check_stack(state, 1);
-                for _ in 0..nargs {
-                    args.push_front(lua.pop_value(state));
-                }
+unsafe extern "C" fn frozen_newindex(state: *mut ffi::lua_State) -> c_int {
+    frozen_error(state)
+}
+
+unsafe extern "C" fn frozen_len(state: *mut ffi::lua_State) -> c_int {
+    let len = ffi::lua_rawlen(state, ffi::lua_upvalueindex(1));
+    ffi::lua_pushinteger(state, len as ffi::lua_Integer);
+    1
+}
+
+// The `__pairs` metamethod of frozen tables, which iterates over their contents without giving
+// scripts access to the table holding them.
+unsafe extern "C" fn frozen_pairs(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+    ffi::lua_pushcclosure(state, frozen_next, 1);
+    ffi::lua_pushvalue(state, 1);
+    ffi::lua_pushnil(state);
+    3
+}
+
+unsafe extern "C" fn frozen_next(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_settop(state, 2);
+    if ffi::lua_next(state, ffi::lua_upvalueindex(1)) != 0 {
+        2
+    } else {
+        ffi::lua_pushnil(state);
+        1
+    }
+}
 
-                let results = func.deref_mut()(&lua, args)?;
-                let nresults = results.len() as c_int;
+// Replaces the `rawset` function of the base library, refusing to modify frozen tables.
+unsafe extern "C" fn frozen_rawset(state: *mut ffi::lua_State) -> c_int {
+    if ffi::lua_type(state, 1) != ffi::LUA_TTABLE {
+        push_string(state, "bad argument #1 to 'rawset' (table expected)");
+        ffi::lua_error(state);
+    }
+    if ffi::lua_gettop(state) < 3 {
+        push_string(state, "bad argument #3 to 'rawset' (value expected)");
+        ffi::lua_error(state);
+    }
+    if is_frozen(state, 1) {
+        frozen_error(state);
+    }
+    ffi::lua_settop(state, 3);
+    ffi::lua_rawset(state, 1);
+    1
+}
 
-                check_stack(state, nresults);
+// Replaces `string.rep` and `table.concat` with versions which check the length of their result
+// against `ResourceLimits::max_string_length`, unless they have already been replaced. The `table`
+// library is opened first in states created by `Lua::new_lazy`.
+unsafe fn install_string_limits(state: *mut ffi::lua_State) {
+    if has_lazy_libraries(state) {
+        ffi::luaL_requiref(state, cstr!("table"), ffi::luaopen_table, 1);
+        ffi::lua_pop(state, 1);
+    }
 
-                for r in results {
-                    lua.push_value(state, r);
+    let limited: [(&str, &str, ffi::lua_CFunction); 2] = [
+        ("string", "rep", limited_rep),
+        ("table", "concat", limited_concat),
+    ];
+    ffi::luaL_getsubtable(state, ffi::LUA_REGISTRYINDEX, cstr!("_LOADED"));
+    for &(library, name, function) in &limited {
+        push_string(state, library);
+        if ffi::lua_rawget(state, -2) == ffi::LUA_TTABLE {
+            push_string(state, name);
+            ffi::lua_rawget(state, -2);
+            match ffi::lua_tocfunction(state, -1) {
+                Some(f) if f as usize != function as usize => {
+                    ffi::lua_pushcclosure(state, function, 1);
+                    push_string(state, name);
+                    ffi::lua_insert(state, -2);
+                    ffi::lua_rawset(state, -3);
                 }
+                _ => ffi::lua_pop(state, 1),
+            }
+        }
+        ffi::lua_pop(state, 1);
+    }
+    ffi::lua_pop(state, 1);
+}
 
-                Ok(nresults)
-            })
+// Raises an error if a string of `len` bytes is longer than the limit of the state.
+unsafe fn check_string_length(state: *mut ffi::lua_State, len: usize) {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    if let Some(max) = (*extra).resource_limits.max_string_length {
+        if len > max {
+            ffi::luaL_where(state, 1);
+            push_string(state, "resulting string is longer than the limit of the state");
+            ffi::lua_concat(state, 2);
+            ffi::lua_error(state);
+        }
+    }
+}
+
+// Calls the Rust function in the first upvalue with all arguments, and returns its results. Errors
+// it returns are raised as strings with the position of the caller, like those of the functions
+// of the string library it replaces.
+unsafe extern "C" fn raise_string_errors(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+    ffi::lua_insert(state, 1);
+    if ffi::lua_pcall(state, ffi::lua_gettop(state) - 1, ffi::LUA_MULTRET, 0) == ffi::LUA_OK {
+        return ffi::lua_gettop(state);
+    }
+    if is_wrapped_error(state, -1) {
+        let _limits = LimitScope::new(state, false);
+        let error = get_userdata::<WrappedError>(state, -1);
+        ffi::luaL_where(state, 1);
+        match (*error).0 {
+            Error::RuntimeError(ref message) => push_string(state, message),
+            ref error => push_string(state, &error.to_string()),
+        }
+        ffi::lua_concat(state, 2);
+    }
+    ffi::lua_error(state)
+}
+
+// Calls the function in the first upvalue with all arguments, and returns its results.
+unsafe fn call_replaced_function(state: *mut ffi::lua_State) -> c_int {
+    ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+    ffi::lua_insert(state, 1);
+    ffi::lua_call(state, ffi::lua_gettop(state) - 1, ffi::LUA_MULTRET);
+    ffi::lua_gettop(state)
+}
+
+// Replaces `string.rep(s, n [, sep])`, the original function is its upvalue.
+unsafe extern "C" fn limited_rep(state: *mut ffi::lua_State) -> c_int {
+    let mut len = 0;
+    let mut sep_len = 0;
+    let mut isnum = 0;
+    let n = ffi::lua_tointegerx(state, 2, &mut isnum);
+    if ffi::lua_isstring(state, 1) != 0 && isnum != 0 && n > 0 {
+        ffi::lua_tolstring(state, 1, &mut len);
+        if ffi::lua_isstring(state, 3) != 0 {
+            ffi::lua_tolstring(state, 3, &mut sep_len);
+        }
+        let n = n as u64;
+        let total = (len as u64)
+            .saturating_mul(n)
+            .saturating_add((sep_len as u64).saturating_mul(n - 1));
+        check_string_length(state, cmp::min(total, usize::MAX as u64) as usize);
+    }
+    call_replaced_function(state)
+}
+
+// Replaces `table.concat(list [, sep [, i [, j]]])`, the original function is its upvalue.
+// Elements which are not strings or numbers are left for the original function to report.
+unsafe extern "C" fn limited_concat(state: *mut ffi::lua_State) -> c_int {
+    if ffi::lua_istable(state, 1) != 0 {
+        let mut sep_len = 0;
+        if ffi::lua_isstring(state, 2) != 0 {
+            ffi::lua_tolstring(state, 2, &mut sep_len);
+        }
+        let mut isnum = 0;
+        let first = ffi::lua_tointegerx(state, 3, &mut isnum);
+        let first = if isnum != 0 { first } else { 1 };
+        let last = ffi::lua_tointegerx(state, 4, &mut isnum);
+        let last = if isnum != 0 { last } else { ffi::luaL_len(state, 1) };
+
+        let mut total = 0usize;
+        let mut i = first;
+        while i <= last {
+            ffi::lua_geti(state, 1, i);
+            if ffi::lua_isstring(state, -1) == 0 {
+                ffi::lua_pop(state, 1);
+                break;
+            }
+            let mut len = 0;
+            ffi::lua_tolstring(state, -1, &mut len);
+            ffi::lua_pop(state, 1);
+            total = total.saturating_add(len);
+            if i < last {
+                total = total.saturating_add(sep_len);
+            }
+            check_string_length(state, total);
+            if i == Integer::MAX {
+                break;
+            }
+            i += 1;
+        }
+    }
+    call_replaced_function(state)
+}
+
+// Sets `limit_hook` on a thread if the state has a budget, a watchdog or a debugger, or a call is
+// being measured, memory is being profiled or coverage is being recorded, or removes it otherwise.
+unsafe fn set_limit_hook(state: *mut ffi::lua_State) {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    if (*extra).budget.is_none() && (*extra).watchdog.is_none() && (*extra).report.is_none()
+        && (*extra).debugger.is_none() && (*(*extra).allocator).profile.is_none()
+        && (*extra).coverage.is_none() && (*extra).time_slice.is_none()
+    {
+        ffi::lua_sethook(state, None, 0, 0);
+        return;
+    }
+
+    // A budget is counted down exactly until it is exhausted, after which the hook runs on every
+    // instruction, like it does once the watchdog has interrupted the state.
+    let interval = match (*extra).budget {
+        Some(ref budget) => cmp::max(cmp::min(budget.remaining(), LIMIT_CHECK_INTERVAL as u64), 1),
+        None => LIMIT_CHECK_INTERVAL as u64,
+    } as c_int;
+    let interval = match (*extra).time_slice {
+        Some((_, remaining)) => cmp::min(interval as u64, cmp::max(remaining, 1)) as c_int,
+        None => interval,
+    };
+    let interval = match (*extra).watchdog {
+        Some(ref watchdog) if watchdog.interrupted() => 1,
+        _ => interval,
+    };
+    (*extra).hook_interval = interval;
+    ffi::lua_sethook(state, Some(limit_hook), hook_mask(state), interval);
+}
+
+// Returns the events `limit_hook` must run on. Calls and returns are only needed to measure the
+// depth of calls for a `ResourceReport`, lines only for a debugger or to record coverage, and
+// lines and returns only to profile memory.
+unsafe fn hook_mask(state: *mut ffi::lua_State) -> c_int {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    let mut mask = ffi::LUA_MASKCOUNT;
+    if (*extra).report.is_some() {
+        mask |= ffi::LUA_MASKCALL | ffi::LUA_MASKRET;
+    }
+    if (*extra).debugger.is_some() || (*extra).coverage.is_some() {
+        mask |= ffi::LUA_MASKLINE;
+    }
+    if (*(*extra).allocator).profile.is_some() {
+        mask |= ffi::LUA_MASKLINE | ffi::LUA_MASKRET;
+    }
+    mask
+}
+
+// Consumes the budget set with `Lua::set_budget`, stops scripts interrupted by the watchdog set
+// with `Lua::set_watchdog`, measures the call made with `Function::call_with_report`, profiles
+// memory, records coverage and runs the debugger set with `Lua::set_debugger`, installed as a
+// hook.
+unsafe extern "C" fn limit_hook(state: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    // The state has been detached with `Lua::into_raw`, but this thread inherited the hook.
+    if (*extra).allocator.is_null() {
+        ffi::lua_sethook(state, None, 0, 0);
+        return;
+    }
+    let limits = LimitScope::new(state, false);
+    if (*ar).event == ffi::LUA_HOOKLINE || (*ar).event == ffi::LUA_HOOKRET {
+        if let Some(ref mut profile) = (*(*extra).allocator).profile {
+            profile.track_line(state, ar);
+        }
+    }
+    if (*ar).event == ffi::LUA_HOOKLINE {
+        if let Some(ref mut coverage) = (*extra).coverage {
+            coverage.track_line(state, ar);
         }
+        if let Some(debugger) = (*extra).debugger.clone() {
+            let lua = Lua {
+                state,
+                main_state: main_state(state),
+                ephemeral: true,
+            };
+            debugger.line_hook(&lua, state, ar);
+        }
+        return;
+    }
+    if (*ar).event != ffi::LUA_HOOKCOUNT {
+        if let Some(ref mut report) = (*extra).report {
+            report.track_call(state, ar);
+        }
+        return;
+    }
 
-        unsafe {
-            stack_guard(self.state, 0, move || {
-                check_stack(self.state, 2);
-
-                push_userdata::<RefCell<Callback>>(self.state, RefCell::new(func));
+    let executed = (*extra).hook_interval;
+    let exhausted = match (*extra).budget {
+        Some(ref budget) => budget.consume(executed as u64) == 0,
+        None => false,
+    };
+    if let Some(ref mut report) = (*extra).report {
+        report.instructions += executed as u64;
+    }
+    // Once its time slice is over, the thread of an `Execution` yields as soon as it runs again
+    // where it can yield, rather than in a Rust callback or in another coroutine.
+    let slice_over = match (*extra).time_slice {
+        Some((thread, ref mut remaining)) => {
+            *remaining = remaining.saturating_sub(executed as u64);
+            *remaining == 0 && thread == state && ffi::lua_isyieldable(state) != 0
+        }
+        None => false,
+    };
+    set_limit_hook(state);
+    let interrupted = (*extra).watchdog.as_ref().is_some_and(WatchedState::interrupted);
+
+    drop(limits);
+    if exhausted {
+        limit_error(state, "instruction budget exhausted");
+    }
+    if interrupted {
+        limit_error(state, "deadline exceeded");
+    }
+    if slice_over {
+        ffi::lua_yield(state, 0);
+    }
+}
 
-                ffi::lua_pushlightuserdata(
-                    self.state,
-                    &FUNCTION_METATABLE_REGISTRY_KEY as *const u8 as *mut c_void,
-                );
-                ffi::lua_gettable(self.state, ffi::LUA_REGISTRYINDEX);
-                ffi::lua_setmetatable(self.state, -2);
+// Raises an error with the position of the running Lua code, from a hook.
+unsafe fn limit_error(state: *mut ffi::lua_State, message: &str) -> ! {
+    ffi::luaL_where(state, 0);
+    push_string(state, message);
+    ffi::lua_concat(state, 2);
+    ffi::lua_error(state);
+}
 
-                ffi::lua_pushcclosure(self.state, callback_call_impl, 1);
+// Measurements of the call being made with `Function::call_with_report`.
+struct ReportState {
+    instructions: u64,
+    peak_depth: usize,
+    // The functions of the calls entered on each thread, innermost last.
+    calls: HashMap<*mut ffi::lua_State, Vec<*const c_void>>,
+}
 
-                Function(self.pop_ref(self.state))
-            })
+impl ReportState {
+    fn new() -> ReportState {
+        ReportState {
+            instructions: 0,
+            peak_depth: 0,
+            calls: HashMap::new(),
         }
     }
 
-    // Used 1 stack space, does not call checkstack
-    pub(crate) unsafe fn push_value(&self, state: *mut ffi::lua_State, value: Value) {
-        match value {
-            Value::Nil => {
-                ffi::lua_pushnil(state);
-            }
-
-            Value::Boolean(b) => {
-                ffi::lua_pushboolean(state, if b { 1 } else { 0 });
-            }
-
-            Value::LightUserData(ud) => {
-                ffi::lua_pushlightuserdata(state, ud.0);
+    // Tracks the calls on a thread from the call and return events of `limit_hook`.
+    unsafe fn track_call(&mut self, state: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) {
+        let event = (*ar).event;
+        ffi::lua_getinfo(state, cstr!("f"), ar);
+        let function = ffi::lua_topointer(state, -1);
+        ffi::lua_pop(state, 1);
+
+        let calls = self.calls.entry(state).or_default();
+        match event {
+            ffi::LUA_HOOKCALL => {
+                // A call without a caller starts a new thread, or one whose address was reused.
+                let mut caller = mem::zeroed();
+                if ffi::lua_getstack(state, 1, &mut caller) == 0 {
+                    calls.clear();
+                }
+                calls.push(function);
+                self.peak_depth = cmp::max(self.peak_depth, calls.len());
             }
-
-            Value::Integer(i) => {
-                ffi::lua_pushinteger(state, i);
+            ffi::LUA_HOOKTAILCALL => {
+                calls.pop();
+                calls.push(function);
             }
-
-            Value::Number(n) => {
-                ffi::lua_pushnumber(state, n);
+            ffi::LUA_HOOKRET => {
+                // Calls left by an error have no return event, and are dropped once the call which
+                // caught the error returns.
+                if let Some(index) = calls.iter().rposition(|&f| f == function) {
+                    calls.truncate(index);
+                }
             }
+            _ => {}
+        }
+    }
+}
 
-            Value::String(s) => {
-                self.push_ref(state, &s.0);
-            }
+// Measures the resources used by Lua code from its creation until it is finished, for
+// `Function::call_with_report`. Measurements can be nested, in which case the outer one includes
+// the inner one.
+struct ReportScope<'lua> {
+    lua: &'lua Lua,
+    outer: Option<ReportState>,
+    start: Instant,
+    allocated: u64,
+}
 
-            Value::Table(t) => {
-                self.push_ref(state, &t.0);
+impl<'lua> ReportScope<'lua> {
+    fn begin(lua: &'lua Lua) -> ReportScope<'lua> {
+        unsafe {
+            let extra = lua.extra_data();
+            let outer = (*extra).report.replace(ReportState::new());
+            set_limit_hook(lua.main_state);
+            if lua.state != lua.main_state {
+                set_limit_hook(lua.state);
             }
-
-            Value::Function(f) => {
-                self.push_ref(state, &f.0);
+            ReportScope {
+                lua,
+                outer,
+                start: Instant::now(),
+                allocated: (*(*extra).allocator).allocated,
             }
+        }
+    }
 
-            Value::Thread(t) => {
-                self.push_ref(state, &t.0);
+    fn finish(self) -> ResourceReport {
+        unsafe {
+            let extra = self.lua.extra_data();
+            let report = (*extra).report.as_ref().expect("report in progress");
+            ResourceReport {
+                instructions: report.instructions,
+                lua_allocated_bytes: (*(*extra).allocator).allocated - self.allocated,
+                peak_stack_depth: report.peak_depth,
+                wall_time: self.start.elapsed(),
             }
+        }
+    }
+}
 
-            Value::UserData(ud) => {
-                self.push_ref(state, &ud.0);
+impl<'lua> Drop for ReportScope<'lua> {
+    fn drop(&mut self) {
+        unsafe {
+            let lua = self.lua;
+            let extra = lua.extra_data();
+            let inner = (*extra).report.take();
+            if let (Some(mut outer), Some(inner)) = (self.outer.take(), inner) {
+                outer.instructions += inner.instructions;
+                let depth = outer.calls.get(&lua.state).map_or(0, |calls| calls.len());
+                outer.peak_depth = cmp::max(outer.peak_depth, depth + inner.peak_depth);
+                (*extra).report = Some(outer);
             }
-
-            Value::Error(e) => {
-                push_wrapped_error(state, e);
+            set_limit_hook(lua.main_state);
+            if lua.state != lua.main_state {
+                set_limit_hook(lua.state);
             }
         }
     }
+}
 
-    // Used 1 stack space, does not call checkstack
-    pub(crate) unsafe fn pop_value(&self, state: *mut ffi::lua_State) -> Value {
-        match ffi::lua_type(state, -1) {
-            ffi::LUA_TNIL => {
-                ffi::lua_pop(state, 1);
-                Nil
-            }
+// Rewrites the locations in an error with the source maps of `Lua::load_with_source_map`.
+pub(crate) unsafe fn map_error_locations(state: *mut ffi::lua_State, err: Error) -> Error {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    if (*extra).source_maps.is_empty() {
+        err
+    } else {
+        source_map::map_error(&(*extra).source_maps, err)
+    }
+}
 
-            ffi::LUA_TBOOLEAN => {
-                let b = Value::Boolean(ffi::lua_toboolean(state, -1) != 0);
-                ffi::lua_pop(state, 1);
-                b
-            }
+// Renders an error for scripts with the formatter set with `Lua::set_error_formatter`.
+pub(crate) unsafe fn format_error(state: *mut ffi::lua_State, err: &Error) -> StdString {
+    let extra = *(ffi::lua_getextraspace(state) as *mut *mut ExtraData);
+    match (*extra).error_formatter {
+        Some(ref formatter) => formatter(err),
+        None => err.to_string(),
+    }
+}
 
-            ffi::LUA_TLIGHTUSERDATA => {
-                let ud = Value::LightUserData(LightUserData(ffi::lua_touserdata(state, -1)));
-                ffi::lua_pop(state, 1);
-                ud
-            }
+// Returns the number of bytes used by the state.
+unsafe fn used_memory(state: *mut ffi::lua_State) -> usize {
+    let kbytes = ffi::lua_gc(state, ffi::LUA_GCCOUNT, 0) as usize;
+    let bytes = ffi::lua_gc(state, ffi::LUA_GCCOUNTB, 0) as usize;
+    kbytes * 1024 + bytes
+}
 
-            ffi::LUA_TNUMBER => if ffi::lua_isinteger(state, -1) != 0 {
-                let i = Value::Integer(ffi::lua_tointeger(state, -1));
-                ffi::lua_pop(state, 1);
-                i
-            } else {
-                let n = Value::Number(ffi::lua_tonumber(state, -1));
-                ffi::lua_pop(state, 1);
-                n
-            },
+// Converts a number of elements to preallocate in a table to the type used by `lua_createtable`.
+fn table_size(size: usize) -> c_int {
+    cmp::min(size, c_int::MAX as usize) as c_int
+}
 
-            ffi::LUA_TSTRING => Value::String(String(self.pop_ref(state))),
+// Rust-side state shared by all `Lua` instances that refer to the same Lua state.
+pub(crate) struct ExtraData {
+    // Thread whose stack holds the values of `LuaRef`s, and its slots which are no longer used.
+    ref_thread: *mut ffi::lua_State,
+    ref_free: Vec<c_int>,
+    // Number of registry references made by `pop_ref` once the stack of `ref_thread` was full.
+    registry_refs: usize,
+    // The registry ids of the dropped `RegistryKey`s, removed when the next key is created.
+    registry_unref_list: Rc<RefCell<Vec<c_int>>>,
+    // Where each reference was created, while references are tracked in debug builds.
+    #[cfg(debug_assertions)]
+    ref_origins: Option<HashMap<RefIndex, Backtrace>>,
+    // Number of functions created for Rust callbacks which have not been finalized yet.
+    live_callbacks: usize,
+    // Registry ids of the metatables of userdata types.
+    registered_userdata: HashMap<TypeId, c_int>,
+    thread_pool: Vec<RefIndex>,
+    thread_pool_size: usize,
+    // Number of currently executing Rust callbacks.
+    callback_depth: usize,
+    // Callback depth, number of values and way of resuming the callback, of a pending yield.
+    pending_yield: Option<(usize, c_int, Resume)>,
+    // The thread being resumed by an `AsyncCall`, whose callbacks can await futures.
+    #[cfg(feature = "async")]
+    pub(crate) awaiting_thread: *mut ffi::lua_State,
+    // Coercions to base types, keyed by the metatable of the derived type and the base type.
+    userdata_coercions: HashMap<(usize, TypeId), Box<dyn Any>>,
+    duration_format: DurationFormat,
+    strict_numbers: bool,
+    // Whether the metamethods installed by `Lua::set_strict_globals` reject undeclared globals.
+    strict_globals: bool,
+    // The callback set with `Lua::audit_globals`.
+    global_audit: Option<Box<dyn FnMut(GlobalAccess)>>,
+    // The callback set with `Lua::trace_require`.
+    require_trace: Option<Box<dyn FnMut(RequireTrace)>>,
+    // The chunk set with `Lua::set_prelude`, which runs again after `Lua::restore`.
+    prelude: Option<StdString>,
+    // Number of weak references created, see `Lua::create_weak_ref`.
+    weak_ref_count: Integer,
+    // Types of the values light userdata created from a `TypedLightUserData` point to, keyed by
+    // their address.
+    light_userdata_types: HashMap<usize, TypeId>,
+    resource_limits: ResourceLimits,
+    // Number of calls into Lua made by the host which are running, see `ExecutionScope`.
+    executions: usize,
+    budget: Option<Budget>,
+    // Number of instructions after which `limit_hook` was last set to run.
+    hook_interval: c_int,
+    // The thread run by `Execution::step` and the number of instructions left before it yields,
+    // while a step is in progress.
+    time_slice: Option<(*mut ffi::lua_State, u64)>,
+    // Measurements of the call being made with `Function::call_with_report`, if any.
+    report: Option<ReportState>,
+    // Source maps of the chunks loaded with `Lua::load_with_source_map`, keyed by their names as
+    // they appear in Lua messages.
+    source_maps: Vec<(StdString, SourceMap)>,
+    debugger: Option<Debugger>,
+    // Lines recorded since `Lua::start_coverage`, if coverage is being recorded.
+    coverage: Option<CoverageState>,
+    watchdog: Option<WatchedState>,
+    // Deadline set with `Lua::set_deadline`, kept to attach the state to another watchdog.
+    deadline: Option<Instant>,
+    panic_mode: PanicMode,
+    // The formatter set with `Lua::set_error_formatter`.
+    error_formatter: Option<ErrorFormatter>,
+    // Empty buffers of `MultiValue`s for reuse.
+    multi_value_pool: Vec<VecDeque<Value<'static>>>,
+    // Serializers of userdata types, keyed by their metatable.
+    #[cfg(feature = "serde")]
+    userdata_serializers: HashMap<usize, SerializeUserData>,
+    // Userdata of the allocator, which is freed once the state has been closed.
+    allocator: *mut AllocatorState,
+}
 
-            ffi::LUA_TTABLE => Value::Table(Table(self.pop_ref(state))),
+// Userdata of `allocator_function`.
+struct AllocatorState {
+    // The allocator and its userdata a state attached with `Lua::init_from_ptr` was created with,
+    // or `None` if the state was created by rlua.
+    host: Option<(ffi::lua_Alloc, *mut c_void)>,
+    // Number of bytes allocated by the state.
+    used: usize,
+    // Memory limit set with `Lua::set_resource_limits`.
+    max_memory: Option<usize>,
+    // Table entry limit set with `Lua::set_resource_limits`, and the number of entries tables have
+    // grown by since the current execution started, see `table_growth_hook`.
+    max_table_entries: Option<usize>,
+    table_entries: usize,
+    // Whether the memory and table entry limits are enforced, see `LimitScope`.
+    limits_enforced: bool,
+    // Number of bytes allocated by the state since it was created, not counting memory which was
+    // freed.
+    allocated: u64,
+    // Number of bytes freed by the state since it was created.
+    freed: u64,
+    // Allocation sites recorded since `Lua::start_memory_profile`, if the state is being profiled.
+    profile: Option<ProfileState>,
+    // The callback set with `Lua::on_gc`, and the number of callbacks set so far.
+    gc: Option<GcState>,
+    gc_generation: u64,
+    main_state: *mut ffi::lua_State,
+}
 
-            ffi::LUA_TFUNCTION => Value::Function(Function(self.pop_ref(state))),
+// Allocates the memory of Lua states, and keeps track of the memory they use. While limits are
+// enforced, allocations which would exceed the memory limit of the state fail, which makes Lua
+// raise a memory error after a full collection.
+unsafe extern "C" fn allocator_function(
+    ud: *mut c_void,
+    ptr: *mut c_void,
+    osize: usize,
+    nsize: usize,
+) -> *mut c_void {
+    let allocator = &mut *(ud as *mut AllocatorState);
+    // Lua passes the type of a new object as its old size.
+    let old_size = if ptr.is_null() { 0 } else { osize };
+    if let Some(max_memory) = allocator.max_memory {
+        if allocator.limits_enforced && nsize > old_size
+            && allocator.used.saturating_sub(old_size) + nsize > max_memory
+        {
+            return ptr::null_mut();
+        }
+    }
 
-            ffi::LUA_TUSERDATA => {
-                // It should not be possible to interact with userdata types
-                // other than custom UserData types OR a WrappedError.
-                // WrappedPanic should never be able to be caught in lua, so it
-                // should never be here.
-                if let Some(err) = pop_wrapped_error(state) {
-                    Value::Error(err)
-                } else {
-                    Value::UserData(AnyUserData(self.pop_ref(state)))
-                }
-            }
+    let p = match allocator.host {
+        Some((alloc, host_ud)) => alloc(host_ud, ptr, osize, nsize),
+        None => system_allocator(ud, ptr, osize, nsize),
+    };
+    if p.is_null() && nsize != 0 {
+        // We must abort on OOM, because otherwise this will result in an unsafe longjmp.
+        eprintln!("Out of memory in Lua allocation, aborting!");
+        process::abort()
+    }
 
-            ffi::LUA_TTHREAD => Value::Thread(Thread(self.pop_ref(state))),
+    allocator.used = allocator.used.saturating_sub(old_size) + nsize;
+    if nsize > old_size {
+        allocator.allocated += (nsize - old_size) as u64;
+        if let Some(ref mut profile) = allocator.profile {
+            profile.record((nsize - old_size) as u64);
+        }
+    }
+    if old_size > nsize {
+        allocator.freed += (old_size - nsize) as u64;
+    }
+    if let Some(ref mut gc) = allocator.gc {
+        if gc.started.is_none() && allocator.used > gc.threshold(allocator.freed) {
+            gc.started = Some(Instant::now());
+            gc.emit(GcEvent::Start {
+                used_memory: allocator.used,
+            });
+        }
+    }
+    p
+}
 
-            _ => unreachable!("internal error: LUA_TNONE in pop_value"),
+// Counts the slots tables grow by against `ResourceLimits::max_table_entries`, called by the
+// bundled Lua whenever a table of a state with a table entry limit is resized, with the allocator
+// of the state as its userdata. Returns 0 to make Lua raise an error instead of growing the
+// table, which like a failing allocation is only done while limits are enforced.
+#[cfg(feature = "builtin-lua")]
+unsafe extern "C" fn table_growth_hook(
+    _state: *mut ffi::lua_State,
+    ud: *mut c_void,
+    slots: usize,
+) -> c_int {
+    let allocator = &mut *(ud as *mut AllocatorState);
+    let table_entries = allocator.table_entries.saturating_add(slots);
+    if let Some(max_table_entries) = allocator.max_table_entries {
+        if allocator.limits_enforced && table_entries > max_table_entries {
+            return 0;
         }
     }
+    allocator.table_entries = table_entries;
+    1
+}
 
-    // Used 1 stack space, does not call checkstack
-    pub(crate) unsafe fn push_ref(&self, state: *mut ffi::lua_State, lref: &LuaRef) {
-        assert_eq!(
-            lref.lua.main_state,
-            self.main_state,
-            "Lua instance passed Value created from a different Lua"
-        );
+// Enforces the memory and table entry limits of a state while Lua code called by rlua runs, or
+// lifts them while Rust code called by Lua runs, until dropped. Exceeding a limit raises a Lua
+// error from inside the allocation or resize which exceeded it, so this keeps those errors from
+// unwinding through Rust frames. Must be dropped before raising an error, since `lua_error` does
+// not run destructors. Does nothing on states which do not use `allocator_function`, because they
+// were not created or attached by rlua, or have been detached with `Lua::into_raw`.
+pub(crate) struct LimitScope {
+    allocator: *mut AllocatorState,
+    previous: bool,
+}
 
-        ffi::lua_rawgeti(
-            state,
-            ffi::LUA_REGISTRYINDEX,
-            lref.registry_id as ffi::lua_Integer,
-        );
+impl LimitScope {
+    pub(crate) unsafe fn new(state: *mut ffi::lua_State, enforced: bool) -> LimitScope {
+        let mut ud = ptr::null_mut();
+        let alloc = ffi::lua_getallocf(state, &mut ud);
+        if alloc as usize != allocator_function as ffi::lua_Alloc as usize {
+            return LimitScope {
+                allocator: ptr::null_mut(),
+                previous: false,
+            };
+        }
+        let allocator = ud as *mut AllocatorState;
+        let previous = mem::replace(&mut (*allocator).limits_enforced, enforced);
+        LimitScope {
+            allocator,
+            previous,
+        }
     }
+}
 
-    // Pops the topmost element of the stack and stores a reference to it in the
-    // registry.
-    //
-    // This pins the object, preventing garbage collection until the returned
-    // `LuaRef` is dropped.
-    //
-    // pop_ref uses 1 extra stack space and does not call checkstack
-    pub(crate) unsafe fn pop_ref(&self, state: *mut ffi::lua_State) -> LuaRef {
-        let registry_id = ffi::luaL_ref(state, ffi::LUA_REGISTRYINDEX);
-        LuaRef {
-            lua: self,
-            registry_id: registry_id,
+impl Drop for LimitScope {
+    fn drop(&mut self) {
+        if !self.allocator.is_null() {
+            unsafe {
+                (*self.allocator).limits_enforced = self.previous;
+            }
         }
     }
+}
 
-    pub(crate) unsafe fn userdata_metatable<T: UserData>(&self) -> c_int {
-        // Used if both an __index metamethod is set and regular methods, checks methods table
-        // first, then __index metamethod.
-        unsafe extern "C" fn meta_index_impl(state: *mut ffi::lua_State) -> c_int {
-            check_stack(state, 2);
+// A call into Lua made by the host, for the limits which apply to a single execution. Calls made
+// while another one is running, such as by Rust callbacks, are part of the same execution.
+struct ExecutionScope<'lua> {
+    lua: &'lua Lua,
+}
 
-            ffi::lua_pushvalue(state, -1);
-            ffi::lua_gettable(state, ffi::lua_upvalueindex(1));
-            if ffi::lua_isnil(state, -1) == 0 {
-                ffi::lua_insert(state, -3);
-                ffi::lua_pop(state, 2);
-                1
-            } else {
-                ffi::lua_pop(state, 1);
-                ffi::lua_pushvalue(state, ffi::lua_upvalueindex(2));
-                ffi::lua_insert(state, -3);
-                ffi::lua_call(state, 2, 1);
-                1
+impl<'lua> ExecutionScope<'lua> {
+    fn begin(lua: &'lua Lua) -> ExecutionScope<'lua> {
+        unsafe {
+            let extra = lua.extra_data();
+            if (*extra).executions == 0 {
+                (*(*extra).allocator).table_entries = 0;
             }
+            (*extra).executions += 1;
         }
+        ExecutionScope { lua }
+    }
+}
 
-        stack_guard(self.state, 0, move || {
-            check_stack(self.state, 5);
+impl<'lua> Drop for ExecutionScope<'lua> {
+    fn drop(&mut self) {
+        unsafe {
+            (*self.lua.extra_data()).executions -= 1;
+        }
+    }
+}
 
-            ffi::lua_pushlightuserdata(
-                self.state,
-                &LUA_USERDATA_REGISTRY_KEY as *const u8 as *mut c_void,
-            );
-            ffi::lua_gettable(self.state, ffi::LUA_REGISTRYINDEX);
-            let registered_userdata = get_userdata::<HashMap<TypeId, c_int>>(self.state, -1);
-            ffi::lua_pop(self.state, 1);
+// The callback set with `Lua::on_gc`. The end of a cycle is detected by the finalizer of a
+// sentinel userdata, which is replaced by a new one for the next cycle. Finalizers run once the
+// collector has marked the objects which are still reachable, and the unreachable ones are freed
+// in the following steps. Lua does not report the start of a cycle, so it is detected by the
+// allocator once the memory used grows past the threshold the collector sets after freeing them.
+struct GcState {
+    callback: Box<dyn FnMut(GcEvent)>,
+    // Sentinels created for earlier callbacks are ignored.
+    generation: u64,
+    // The pause of the collector, as set with `collectgarbage("setpause")`.
+    pause: usize,
+    // Memory used and value of `AllocatorState::freed` when the previous cycle ended.
+    used_at_end: usize,
+    freed_at_end: u64,
+    // When the running cycle started, if its start was seen.
+    started: Option<Instant>,
+}
 
-            if let Some(table_id) = (*registered_userdata).get(&TypeId::of::<T>()) {
-                return *table_id;
-            }
+impl GcState {
+    // The callback runs while Lua allocates memory or collects garbage, where a panic cannot be
+    // resumed.
+    fn emit(&mut self, event: GcEvent) {
+        let callback = &mut self.callback;
+        if panic::catch_unwind(panic::AssertUnwindSafe(|| callback(event))).is_err() {
+            eprintln!("Panic in Lua garbage collection callback, aborting!");
+            process::abort()
+        }
+    }
 
-            let mut methods = UserDataMethods {
-                methods: HashMap::new(),
-                meta_methods: HashMap::new(),
-                _type: PhantomData,
-            };
-            T::add_methods(&mut methods);
+    // Returns the memory use above which the next cycle starts. Like the collector, this
+    // estimates the memory still in use from the memory used when the previous cycle ended,
+    // minus the memory it has freed since.
+    fn threshold(&self, freed: u64) -> usize {
+        let live = self.used_at_end
+            .saturating_sub((freed - self.freed_at_end) as usize);
+        (live / 100).saturating_mul(self.pause)
+    }
 
-            ffi::lua_newtable(self.state);
+    unsafe fn end_cycle(&mut self, state: *mut ffi::lua_State, used: usize, freed: u64) {
+        let pause = ffi::lua_gc(state, ffi::LUA_GCSETPAUSE, 0);
+        ffi::lua_gc(state, ffi::LUA_GCSETPAUSE, pause);
+        self.pause = pause as usize;
+        self.used_at_end = used;
+        self.freed_at_end = freed;
+        self.started = None;
+    }
+}
 
-            let has_methods = !methods.methods.is_empty();
+// Pushes a userdata holding the generation of the callback set with `Lua::on_gc`, whose
+// finalizer reports the end of the garbage collection cycle which collected it.
+unsafe fn push_gc_sentinel(state: *mut ffi::lua_State, generation: u64) {
+    check_stack(state, 3);
+    let ud = ffi::lua_newuserdata(state, mem::size_of::<u64>()) as *mut u64;
+    ptr::write(ud, generation);
+    ffi::lua_newtable(state);
+    push_string(state, "__gc");
+    ffi::lua_pushcfunction(state, gc_sentinel_finalizer);
+    ffi::lua_rawset(state, -3);
+    ffi::lua_setmetatable(state, -2);
+}
 
-            if has_methods {
-                push_string(self.state, "__index");
-                ffi::lua_newtable(self.state);
+unsafe extern "C" fn gc_sentinel_finalizer(state: *mut ffi::lua_State) -> c_int {
+    // The state may have been detached with `Lua::into_raw`, which frees the allocator.
+    let mut ud = ptr::null_mut();
+    let alloc = ffi::lua_getallocf(state, &mut ud);
+    if alloc as usize != allocator_function as ffi::lua_Alloc as usize {
+        return 0;
+    }
+    let _limits = LimitScope::new(state, false);
+    let allocator = &mut *(ud as *mut AllocatorState);
+    let generation = *(ffi::lua_touserdata(state, 1) as *const u64);
+    let gc = match allocator.gc {
+        Some(ref mut gc) if gc.generation == generation => gc,
+        _ => return 0,
+    };
+
+    let duration = gc.started.map(|started| started.elapsed());
+    let reclaimed = allocator.freed - gc.freed_at_end;
+    gc.end_cycle(state, allocator.used, allocator.freed);
+    gc.emit(GcEvent::End {
+        reclaimed_bytes: reclaimed as usize,
+        used_memory: allocator.used,
+        duration,
+    });
+
+    push_gc_sentinel(state, generation);
+    ffi::lua_pop(state, 1);
+    0
+}
 
-                for (k, m) in methods.methods {
-                    push_string(self.state, &k);
-                    self.push_value(
-                        self.state,
-                        Value::Function(self.create_callback_function(m)),
-                    );
-                    ffi::lua_rawset(self.state, -3);
-                }
+// Allocation sites recorded by `Lua::start_memory_profile`. The line of Lua code which is running
+// is tracked by `limit_hook`, and charged with the memory allocated until another line runs.
+struct ProfileState {
+    // Names of the chunks which allocated memory, and their indices in `chunks`.
+    chunks: Vec<StdString>,
+    chunk_indices: HashMap<StdString, usize>,
+    // The chunk index and line of the running line, or `None` if no Lua code is running.
+    site: Option<(usize, u32)>,
+    // Bytes and number of allocations, by chunk index and line.
+    sites: HashMap<(usize, u32), (u64, u64)>,
+    unattributed: u64,
+}
 
-                ffi::lua_rawset(self.state, -3);
+impl ProfileState {
+    fn new() -> ProfileState {
+        ProfileState {
+            chunks: Vec::new(),
+            chunk_indices: HashMap::new(),
+            site: None,
+            sites: HashMap::new(),
+            unattributed: 0,
+        }
+    }
+
+    fn record(&mut self, bytes: u64) {
+        match self.site {
+            Some(site) => {
+                let entry = self.sites.entry(site).or_insert((0, 0));
+                entry.0 += bytes;
+                entry.1 += 1;
             }
+            None => self.unattributed += bytes,
+        }
+    }
 
-            for (k, m) in methods.meta_methods {
-                if k == MetaMethod::Index && has_methods {
-                    push_string(self.state, "__index");
-                    ffi::lua_pushvalue(self.state, -1);
-                    ffi::lua_gettable(self.state, -3);
-                    self.push_value(
-                        self.state,
-                        Value::Function(self.create_callback_function(m)),
-                    );
-                    ffi::lua_pushcclosure(self.state, meta_index_impl, 2);
-                    ffi::lua_rawset(self.state, -3);
-                } else {
-                    let name = match k {
-                        MetaMethod::Add => "__add",
-                        MetaMethod::Sub => "__sub",
-                        MetaMethod::Mul => "__mul",
-                        MetaMethod::Div => "__div",
-                        MetaMethod::Mod => "__mod",
-                        MetaMethod::Pow => "__pow",
-                        MetaMethod::Unm => "__unm",
-                        MetaMethod::IDiv => "__idiv",
-                        MetaMethod::BAnd => "__band",
-                        MetaMethod::BOr => "__bor",
-                        MetaMethod::BXor => "__bxor",
-                        MetaMethod::BNot => "__bnot",
-                        MetaMethod::Shl => "__shl",
-                        MetaMethod::Shr => "__shr",
-                        MetaMethod::Concat => "__concat",
-                        MetaMethod::Len => "__len",
-                        MetaMethod::Eq => "__eq",
-                        MetaMethod::Lt => "__lt",
-                        MetaMethod::Le => "__le",
-                        MetaMethod::Index => "__index",
-                        MetaMethod::NewIndex => "__newindex",
-                        MetaMethod::Call => "__call",
-                        MetaMethod::ToString => "__tostring",
-                    };
-                    push_string(self.state, name);
-                    self.push_value(
-                        self.state,
-                        Value::Function(self.create_callback_function(m)),
-                    );
-                    ffi::lua_rawset(self.state, -3);
-                }
+    // Updates the running line from the line and return events of `limit_hook`. On return, the
+    // line of the calling function runs again.
+    unsafe fn track_line(&mut self, state: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) {
+        let mut caller: ffi::lua_Debug = mem::zeroed();
+        let ar = if (*ar).event == ffi::LUA_HOOKRET {
+            if ffi::lua_getstack(state, 1, &mut caller) == 0 {
+                self.site = None;
+                return;
             }
+            &mut caller
+        } else {
+            &mut *ar
+        };
+        ffi::lua_getinfo(state, cstr!("Sl"), ar);
+        if ar.currentline <= 0 {
+            self.site = None;
+            return;
+        }
 
-            push_string(self.state, "__gc");
-            ffi::lua_pushcfunction(self.state, userdata_destructor::<RefCell<T>>);
-            ffi::lua_rawset(self.state, -3);
+        let chunk = CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy();
+        let index = match self.chunk_indices.get(&*chunk) {
+            Some(&index) => index,
+            None => {
+                let index = self.chunks.len();
+                self.chunks.push(chunk.clone().into_owned());
+                self.chunk_indices.insert(chunk.into_owned(), index);
+                index
+            }
+        };
+        self.site = Some((index, ar.currentline as u32));
+    }
 
-            push_string(self.state, "__metatable");
-            ffi::lua_pushboolean(self.state, 0);
-            ffi::lua_rawset(self.state, -3);
+    fn to_profile(&self) -> MemoryProfile {
+        let mut sites = self.sites
+            .iter()
+            .map(|(&(chunk, line), &(bytes, allocations))| AllocationSite {
+                chunk: self.chunks[chunk].clone(),
+                line,
+                bytes,
+                allocations,
+            })
+            .collect::<Vec<_>>();
+        sites.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.chunk.cmp(&b.chunk))
+                .then_with(|| a.line.cmp(&b.line))
+        });
+        MemoryProfile {
+            sites,
+            unattributed_bytes: self.unattributed,
+        }
+    }
+}
 
-            let id = ffi::luaL_ref(self.state, ffi::LUA_REGISTRYINDEX);
-            (*registered_userdata).insert(TypeId::of::<T>(), id);
-            id
-        })
+// Allocates memory with `realloc`, the allocator of states created by rlua once they have been
+// handed over with `Lua::into_raw`.
+#[cfg(not(target_arch = "wasm32"))]
+unsafe extern "C" fn system_allocator(
+    _: *mut c_void,
+    ptr: *mut c_void,
+    _: usize,
+    nsize: usize,
+) -> *mut c_void {
+    if nsize == 0 {
+        libc::free(ptr);
+        ptr::null_mut()
+    } else {
+        libc::realloc(ptr, nsize)
+    }
+}
+
+// WebAssembly targets may have no C library, so memory comes from the global allocator of Rust.
+// Unlike `free`, it needs the size of the blocks it frees, which Lua passes as their old size.
+#[cfg(target_arch = "wasm32")]
+unsafe extern "C" fn system_allocator(
+    _: *mut c_void,
+    ptr: *mut c_void,
+    osize: usize,
+    nsize: usize,
+) -> *mut c_void {
+    use std::alloc::{self, Layout};
+
+    // The alignment of `LUAI_MAXALIGN`, which Lua expects of every block.
+    const ALIGN: usize = 8;
+    if ptr.is_null() {
+        // Lua passes the type of a new object as its old size.
+        if nsize == 0 {
+            ptr::null_mut()
+        } else {
+            alloc::alloc(Layout::from_size_align_unchecked(nsize, ALIGN)) as *mut c_void
+        }
+    } else if nsize == 0 {
+        alloc::dealloc(ptr as *mut u8, Layout::from_size_align_unchecked(osize, ALIGN));
+        ptr::null_mut()
+    } else {
+        let layout = Layout::from_size_align_unchecked(osize, ALIGN);
+        alloc::realloc(ptr as *mut u8, layout, nsize) as *mut c_void
+    }
+}
+
+// Tracks the nesting of Rust callbacks, so that a pending yield is only ever performed by the
+// callback which requested it.
+struct CallbackDepth(usize, *mut ExtraData);
+
+impl CallbackDepth {
+    unsafe fn enter(extra: *mut ExtraData) -> CallbackDepth {
+        (*extra).callback_depth += 1;
+        CallbackDepth((*extra).callback_depth, extra)
+    }
+}
+
+impl Drop for CallbackDepth {
+    fn drop(&mut self) {
+        unsafe {
+            let extra = self.1;
+            (*extra).callback_depth -= 1;
+            if let Some((yield_depth, _, _)) = (*extra).pending_yield {
+                if yield_depth >= self.0 {
+                    (*extra).pending_yield = None;
+                }
+            }
+        }
     }
 }
 
-static LUA_USERDATA_REGISTRY_KEY: u8 = 0;
 static FUNCTION_METATABLE_REGISTRY_KEY: u8 = 0;
+static METHOD_METATABLE_REGISTRY_KEY: u8 = 0;
+static EXTRA_DATA_REGISTRY_KEY: u8 = 0;
+static DECLARED_GLOBALS_REGISTRY_KEY: u8 = 0;
+static AUDITED_GLOBALS_REGISTRY_KEY: u8 = 0;
+static WEAK_REFS_REGISTRY_KEY: u8 = 0;
+static EVENTS_REGISTRY_KEY: u8 = 0;
+static FROZEN_METATABLE_KEY: u8 = 0;
+#[cfg(feature = "serde")]
+static ARRAY_METATABLE_REGISTRY_KEY: u8 = 0;
+
+// Standard libraries which are opened on first use in states created by `Lua::new_lazy`, with
+// their names as C strings. WebAssembly has no files or processes, so the io and os libraries are
+// not built for it.
+const LAZY_LIBRARIES: &[(&str, ffi::lua_CFunction)] = &[
+    ("table\0", ffi::luaopen_table),
+    #[cfg(not(target_arch = "wasm32"))]
+    ("io\0", ffi::luaopen_io),
+    #[cfg(not(target_arch = "wasm32"))]
+    ("os\0", ffi::luaopen_os),
+    ("utf8\0", ffi::luaopen_utf8),
+    ("math\0", ffi::luaopen_math),
+    #[cfg(feature = "lua52-compat")]
+    ("bit32\0", ffi::luaopen_bit32),
+    ("package\0", open_package),
+];
+
+// Largest number of instructions between runs of `limit_hook`.
+const LIMIT_CHECK_INTERVAL: c_int = 1000;
+// Depth up to which `Lua::globals_snapshot` copies tables.
+const GLOBALS_SNAPSHOT_DEPTH: usize = 8;
+
+// Maximum number of `MultiValue` buffers kept for reuse.
+const MULTI_VALUE_POOL_SIZE: usize = 16;
+
+// Depth up to which the `Debug` output of `Value` shows the contents of tables.
+const DEBUG_DEPTH: usize = 3;
@@ -0,0 +1,133 @@
+use error::{Error, Result};
+use lua::{FromLuaMulti, ToLuaMulti, Value};
+use table::Table;
+
+/// Implements a Rust trait with a Lua table of functions.
+///
+/// The macro defines a struct wrapping a [`Table`], and implements the trait for it by calling
+/// the function in the field of the table with the name of each method, with the table itself as
+/// the first argument. This lets plugins implement the interfaces of the host entirely in Lua,
+/// with either `function plugin:greet(name)` or `function plugin.greet(self, name)`.
+///
+/// The methods of the trait must take `&self`, and return a `Result` whose error type can be
+/// converted from [`Error`], as calling into Lua can fail. A field which is not a function is
+/// reported as an [`Error::RuntimeError`] when the method is called. The struct also implements
+/// `FromLua`, so callbacks can take the implementation directly as an argument, and has a
+/// `boxed` constructor returning the implementation as a trait object.
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rlua;
+/// # use rlua::{Lua, Result, Table};
+/// trait Greeter {
+///     fn greet(&self, name: String) -> Result<String>;
+///     fn count(&self) -> Result<i64>;
+/// }
+///
+/// impl_lua_trait! {
+///     /// A `Greeter` implemented by a Lua plugin.
+///     pub struct LuaGreeter: Greeter {
+///         fn greet(&self, name: String) -> Result<String>;
+///         fn count(&self) -> Result<i64>;
+///     }
+/// }
+///
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let plugin: Table = lua.eval(r#"
+///     local plugin = { greeted = 0 }
+///     function plugin:greet(name)
+///         self.greeted = self.greeted + 1
+///         return "hello " .. name
+///     end
+///     function plugin:count()
+///         return self.greeted
+///     end
+///     return plugin
+/// "#, None)?;
+///
+/// let greeter: Box<dyn Greeter> = LuaGreeter::boxed(plugin);
+/// assert_eq!(greeter.greet("world".to_owned())?, "hello world");
+/// assert_eq!(greeter.count()?, 1);
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Table`]: struct.Table.html
+/// [`Error`]: enum.Error.html
+/// [`Error::RuntimeError`]: enum.Error.html#variant.RuntimeError
+#[macro_export]
+macro_rules! impl_lua_trait {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident : $trait:path {
+            $(fn $method:ident(&self $(, $arg:ident : $arg_ty:ty)*) -> $ret:ty;)*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Debug)]
+        $vis struct $name<'lua>(pub $crate::Table<'lua>);
+
+        impl<'lua> $name<'lua> {
+            /// Returns the implementation of the trait by the table as a trait object.
+            #[allow(dead_code)]
+            $vis fn boxed(table: $crate::Table<'lua>) -> ::std::boxed::Box<dyn $trait + 'lua> {
+                ::std::boxed::Box::new($name(table))
+            }
+        }
+
+        impl<'lua> $crate::FromLua<'lua> for $name<'lua> {
+            fn from_lua(
+                value: $crate::Value<'lua>,
+                lua: &'lua $crate::Lua,
+            ) -> $crate::Result<Self> {
+                $crate::FromLua::from_lua(value, lua).map($name)
+            }
+        }
+
+        impl<'lua> $trait for $name<'lua> {
+            $(
+                fn $method(&self $(, $arg: $arg_ty)*) -> $ret {
+                    $crate::call_lua_trait_method(
+                        &self.0,
+                        stringify!($trait),
+                        stringify!($method),
+                        ($($arg,)*),
+                    ).map_err(::std::convert::From::from)
+                }
+            )*
+        }
+    };
+}
+
+// Used by `impl_lua_trait!`. Calls the method `method` of the Lua implementation `table` of the
+// trait `trait_name`.
+#[doc(hidden)]
+pub fn call_lua_trait_method<'lua, A, R>(
+    table: &Table<'lua>,
+    trait_name: &str,
+    method: &str,
+    args: A,
+) -> Result<R>
+where
+    A: ToLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+{
+    let lua = table.0.lua;
+    let function = match table.get::<_, Value>(method)? {
+        Value::Function(function) => function,
+        _ => {
+            return Err(Error::RuntimeError(format!(
+                "the Lua implementation of {} has no method '{}'",
+                trait_name, method
+            )))
+        }
+    };
+    let mut args = args.to_lua_multi(lua)?;
+    args.push_front(Value::Table(table.clone()));
+    function.call(args)
+}
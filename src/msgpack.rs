@@ -0,0 +1,133 @@
+use std::os::raw::c_void;
+
+use rmpv::{self, Value as MsgPack};
+
+use error::{Error, Result};
+use lua::{Lua, Nil, Value};
+use types::Integer;
+
+// Deepest nesting of tables encoded as MessagePack. Deeper tables would overflow the stack of the
+// conversion.
+const MAX_MSGPACK_DEPTH: usize = 128;
+
+// Encodes a Lua value as MessagePack, for `Value::to_msgpack`.
+pub(crate) fn encode(value: &Value) -> Result<Vec<u8>> {
+    let msgpack = to_msgpack(value, &mut Vec::new())?;
+    let mut bytes = Vec::new();
+    rmpv::encode::write_value(&mut bytes, &msgpack).map_err(|err| {
+        Error::FromLuaConversionError {
+            from: value.type_name(),
+            to: "MessagePack",
+            message: Some(err.to_string()),
+        }
+    })?;
+    Ok(bytes)
+}
+
+// Decodes a single MessagePack value, for `Lua::value_from_msgpack`.
+pub(crate) fn decode<'lua>(lua: &'lua Lua, mut bytes: &[u8]) -> Result<Value<'lua>> {
+    let msgpack = rmpv::decode::read_value(&mut bytes).map_err(|err| error(&err.to_string()))?;
+    if !bytes.is_empty() {
+        return Err(error(&format!("{} trailing bytes", bytes.len())));
+    }
+    from_msgpack(lua, msgpack)
+}
+
+// Converts a Lua value to a MessagePack value, `visited` holds the tables currently being
+// converted.
+fn to_msgpack(value: &Value, visited: &mut Vec<*const c_void>) -> Result<MsgPack> {
+    let error = |message: &str| Error::FromLuaConversionError {
+        from: value.type_name(),
+        to: "MessagePack",
+        message: Some(message.to_owned()),
+    };
+
+    Ok(match *value {
+        Nil => MsgPack::Nil,
+        Value::LightUserData(ud) if ud.0.is_null() => MsgPack::Nil,
+        Value::Boolean(b) => MsgPack::Boolean(b),
+        Value::Integer(i) => MsgPack::from(i),
+        Value::Number(n) => MsgPack::F64(n),
+        Value::String(ref s) => match s.to_str() {
+            Ok(s) => MsgPack::from(s),
+            Err(_) => MsgPack::Binary(s.as_bytes().to_vec()),
+        },
+        Value::Table(ref table) => {
+            let ptr = table.to_pointer();
+            if visited.contains(&ptr) {
+                return Err(error("recursive table"));
+            }
+            if visited.len() == MAX_MSGPACK_DEPTH {
+                return Err(error("tables are nested too deeply"));
+            }
+            visited.push(ptr);
+
+            let len = table.raw_len();
+            let pairs = table
+                .clone()
+                .pairs::<Value, Value>()
+                .collect::<Result<Vec<_>>>()?;
+
+            let msgpack = if len > 0 && pairs.len() as Integer == len {
+                let values = (1..=len)
+                    .map(|i| to_msgpack(&table.raw_get::<_, Value>(i)?, visited))
+                    .collect::<Result<Vec<_>>>()?;
+                MsgPack::Array(values)
+            } else {
+                let entries = pairs
+                    .iter()
+                    .map(|(k, v)| Ok((to_msgpack(k, visited)?, to_msgpack(v, visited)?)))
+                    .collect::<Result<Vec<_>>>()?;
+                MsgPack::Map(entries)
+            };
+
+            visited.pop();
+            msgpack
+        }
+        _ => return Err(error("cannot be represented as MessagePack")),
+    })
+}
+
+// Converts a decoded MessagePack value to Lua. Strings and binary data both become Lua strings.
+fn from_msgpack<'lua>(lua: &'lua Lua, msgpack: MsgPack) -> Result<Value<'lua>> {
+    Ok(match msgpack {
+        MsgPack::Nil => Nil,
+        MsgPack::Boolean(b) => Value::Boolean(b),
+        MsgPack::Integer(i) => match i.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Number(i.as_f64().unwrap_or(f64::NAN)),
+        },
+        MsgPack::F32(n) => Value::Number(n.into()),
+        MsgPack::F64(n) => Value::Number(n),
+        MsgPack::String(s) => Value::String(lua.create_byte_string(s.as_bytes())),
+        MsgPack::Binary(bytes) => Value::String(lua.create_byte_string(&bytes)),
+        MsgPack::Array(values) => {
+            let table = lua.create_table_with_capacity(values.len(), 0);
+            for (i, value) in values.into_iter().enumerate() {
+                table.raw_set(i + 1, from_msgpack(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+        MsgPack::Map(entries) => {
+            let table = lua.create_table_with_capacity(0, entries.len());
+            for (key, value) in entries {
+                let key = match from_msgpack(lua, key)? {
+                    Nil => return Err(error("map key is nil")),
+                    Value::Number(n) if n.is_nan() => return Err(error("map key is NaN")),
+                    key => key,
+                };
+                table.raw_set(key, from_msgpack(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+        MsgPack::Ext(_, _) => return Err(error("extension types are not supported")),
+    })
+}
+
+fn error(message: &str) -> Error {
+    Error::ToLuaConversionError {
+        from: "MessagePack",
+        to: "value",
+        message: Some(message.to_owned()),
+    }
+}
@@ -1,6 +1,8 @@
+use std::fmt;
 use std::ops::{Deref, DerefMut};
 use std::iter::FromIterator;
 use std::result::Result as StdResult;
+use std::sync::Arc;
 
 use error::*;
 use lua::*;
@@ -9,7 +11,7 @@ use lua::*;
 /// on success, or in the case of an error, returning `nil` and an error message.
 impl<'lua, T: ToLua<'lua>, E: ToLua<'lua>> ToLuaMulti<'lua> for StdResult<T, E> {
     fn to_lua_multi(self, lua: &'lua Lua) -> Result<MultiValue<'lua>> {
-        let mut result = MultiValue::new();
+        let mut result = lua.new_multi_value();
 
         match self {
             Ok(v) => result.push_back(v.to_lua(lua)?),
@@ -25,7 +27,7 @@ impl<'lua, T: ToLua<'lua>, E: ToLua<'lua>> ToLuaMulti<'lua> for StdResult<T, E>
 
 impl<'lua, T: ToLua<'lua>> ToLuaMulti<'lua> for T {
     fn to_lua_multi(self, lua: &'lua Lua) -> Result<MultiValue<'lua>> {
-        let mut v = MultiValue::new();
+        let mut v = lua.new_multi_value();
         v.push_back(self.to_lua(lua)?);
         Ok(v)
     }
@@ -33,7 +35,25 @@ impl<'lua, T: ToLua<'lua>> ToLuaMulti<'lua> for T {
 
 impl<'lua, T: FromLua<'lua>> FromLuaMulti<'lua> for T {
     fn from_lua_multi(mut values: MultiValue<'lua>, lua: &'lua Lua) -> Result<Self> {
-        Ok(T::from_lua(values.pop_front().unwrap_or(Nil), lua)?)
+        let value = values.pop_front();
+        lua.cache_multi_value(values);
+        match value {
+            Some(value) => T::from_lua(value, lua),
+            None => T::from_lua_missing(lua),
+        }
+    }
+
+    fn from_lua_args(values: MultiValue<'lua>, first_arg: usize, lua: &'lua Lua) -> Result<Self> {
+        T::from_lua_multi(values, lua).map_err(|err| bad_argument(first_arg, err))
+    }
+}
+
+// Reports a conversion error of the argument at position `arg_index`.
+fn bad_argument(arg_index: usize, err: Error) -> Error {
+    Error::BadArgument {
+        function_name: None,
+        arg_index,
+        cause: Arc::new(err),
     }
 }
 
@@ -56,6 +76,10 @@ impl<'lua> FromLuaMulti<'lua> for MultiValue<'lua> {
 /// using [`FromLua`]. `Variadic<T>` can also be returned from a callback, returning a variable
 /// number of values to Lua.
 ///
+/// `Variadic<T>` may also be the last element of a tuple of arguments or return values, in which
+/// case it collects all values after the preceding tuple elements. It dereferences to `Vec<T>` and
+/// converts from and into one, so the values can be used without copying them.
+///
 /// The [`MultiValue`] type is equivalent to `Variadic<Value>`.
 ///
 /// # Examples
@@ -105,6 +129,42 @@ impl<T> IntoIterator for Variadic<T> {
     }
 }
 
+impl<'a, T> IntoIterator for &'a Variadic<T> {
+    type Item = &'a T;
+    type IntoIter = <&'a Vec<T> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut Variadic<T> {
+    type Item = &'a mut T;
+    type IntoIter = <&'a mut Vec<T> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter_mut()
+    }
+}
+
+impl<T> From<Vec<T>> for Variadic<T> {
+    fn from(vec: Vec<T>) -> Self {
+        Variadic(vec)
+    }
+}
+
+impl<T> From<Variadic<T>> for Vec<T> {
+    fn from(variadic: Variadic<T>) -> Self {
+        variadic.0
+    }
+}
+
+impl<T> Default for Variadic<T> {
+    fn default() -> Self {
+        Variadic::new()
+    }
+}
+
 impl<T> Deref for Variadic<T> {
     type Target = Vec<T>;
 
@@ -133,18 +193,303 @@ impl<'lua, T: FromLua<'lua>> FromLuaMulti<'lua> for Variadic<T> {
             .collect::<Result<Vec<T>>>()
             .map(Variadic)
     }
+
+    fn from_lua_args(values: MultiValue<'lua>, first_arg: usize, lua: &'lua Lua) -> Result<Self> {
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| T::from_lua(e, lua).map_err(|err| bad_argument(first_arg + i, err)))
+            .collect::<Result<Vec<T>>>()
+            .map(Variadic)
+    }
+}
+
+/// The values of an iterator, returned from a callback as multiple values.
+///
+/// The values are converted as the iterator produces them, into a buffer which the Lua state
+/// reuses between calls, so that returning many values does not allocate a `Vec` first as
+/// returning a [`Variadic`] does. To return the values as a single table instead, create it with
+/// [`Lua::create_sequence_from`].
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{IterValues, Lua, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+///
+/// let range = lua.create_function(|_, n: i64| Ok(IterValues(1..n + 1)));
+/// lua.globals().set("range", range)?;
+/// assert_eq!(lua.eval::<i64>("select('#', range(100))", None)?, 100);
+/// assert_eq!(lua.eval::<i64>("math.max(range(100))", None)?, 100);
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Variadic`]: struct.Variadic.html
+/// [`Lua::create_sequence_from`]: struct.Lua.html#method.create_sequence_from
+#[derive(Debug, Clone)]
+pub struct IterValues<I>(pub I);
+
+impl<'lua, I> ToLuaMulti<'lua> for IterValues<I>
+where
+    I: IntoIterator,
+    I::Item: ToLua<'lua>,
+{
+    fn to_lua_multi(self, lua: &'lua Lua) -> Result<MultiValue<'lua>> {
+        let mut values = lua.new_multi_value();
+        for value in self.0 {
+            values.push_back(value.to_lua(lua)?);
+        }
+        Ok(values)
+    }
+}
+
+/// A value which is converted from Lua as either an `A` or a `B`.
+///
+/// Converting from Lua first tries `A`, and then `B` if that fails. If both conversions fail, the
+/// error reports both failures. This is useful for callbacks which accept different kinds of
+/// arguments, such as a string or a table of options.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Either, Lua, Result, Table};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+///
+/// let greet = lua.create_function(|_, arg: Either<String, Table>| {
+///     let name = match arg {
+///         Either::Left(name) => name,
+///         Either::Right(options) => options.get("name")?,
+///     };
+///     Ok(format!("hello, {}", name))
+/// });
+/// lua.globals().set("greet", greet)?;
+/// assert_eq!(lua.eval::<String>(r#"greet("a")"#, None)?, "hello, a");
+/// assert_eq!(lua.eval::<String>(r#"greet({ name = "b" })"#, None)?, "hello, b");
+/// assert!(lua.eval::<String>("greet(true)", None).is_err());
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Either<A, B> {
+    /// A value converted as `A`.
+    Left(A),
+    /// A value converted as `B`, after conversion as `A` failed.
+    Right(B),
+}
+
+impl<'lua, A: ToLua<'lua>, B: ToLua<'lua>> ToLua<'lua> for Either<A, B> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        match self {
+            Either::Left(a) => a.to_lua(lua),
+            Either::Right(b) => b.to_lua(lua),
+        }
+    }
+}
+
+impl<'lua, A: FromLua<'lua>, B: FromLua<'lua>> FromLua<'lua> for Either<A, B> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        let type_name = value.type_name();
+        let left_err = match A::from_lua(value.clone(), lua) {
+            Ok(a) => return Ok(Either::Left(a)),
+            Err(err) => err,
+        };
+        let right_err = match B::from_lua(value, lua) {
+            Ok(b) => return Ok(Either::Right(b)),
+            Err(err) => err,
+        };
+        Err(Error::FromLuaConversionError {
+            from: type_name,
+            to: "Either",
+            message: Some(format!(
+                "both conversions failed: {}; {}",
+                left_err, right_err
+            )),
+        })
+    }
+}
+
+/// An optional argument which tells an omitted value apart from an explicit `nil`.
+///
+/// Converting a missing value, such as an omitted trailing argument of a callback, results in
+/// `Opt(None)`. Any value which is present, including `nil`, is converted to `T`. This allows
+/// callbacks to use a default when an argument is omitted, while still rejecting an explicit
+/// `nil`. In contrast, `Option<T>` converts both a missing value and `nil` to `None`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, Opt, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+///
+/// let repeat = lua.create_function(|_, (s, count): (String, Opt<usize>)| {
+///     Ok(s.repeat(count.0.unwrap_or(2)))
+/// });
+/// lua.globals().set("rep", repeat)?;
+/// assert_eq!(lua.eval::<String>(r#"rep("a")"#, None)?, "aa");
+/// assert_eq!(lua.eval::<String>(r#"rep("a", 3)"#, None)?, "aaa");
+/// assert!(lua.eval::<String>(r#"rep("a", nil)"#, None).is_err());
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opt<T>(pub Option<T>);
+
+impl<'lua, T: ToLua<'lua>> ToLua<'lua> for Opt<T> {
+    fn to_lua(self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        self.0.to_lua(lua)
+    }
+}
+
+impl<'lua, T: FromLua<'lua>> FromLua<'lua> for Opt<T> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        Ok(Opt(Some(T::from_lua(value, lua)?)))
+    }
+
+    fn from_lua_missing(_: &'lua Lua) -> Result<Self> {
+        Ok(Opt(None))
+    }
+}
+
+/// Named arguments, passed to a callback as a single table like `spawn{ name = "orc", hp = 10 }`.
+///
+/// The table is converted to `T`, usually a struct implementing `FromLua` with one field per
+/// argument, such as a struct deriving `FromLua` with the `derive` feature. The derived conversion
+/// reports which field is missing or invalid, so that scripts get errors like
+/// `bad argument #1 to 'spawn' (... missing field 'hp')`. A value which is not a table is rejected
+/// with an error saying named arguments were expected.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{FromLua, Kwargs, Lua, Result, Table, Value};
+/// # fn try_main() -> Result<()> {
+/// struct Spawn {
+///     name: String,
+///     hp: u32,
+/// }
+///
+/// impl<'lua> FromLua<'lua> for Spawn {
+///     fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Spawn> {
+///         let table = Table::from_lua(value, lua)?;
+///         Ok(Spawn {
+///             name: table.get("name")?,
+///             hp: table.get("hp")?,
+///         })
+///     }
+/// }
+///
+/// let lua = Lua::new();
+/// let spawn = lua.create_function(|_, Kwargs(spawn): Kwargs<Spawn>| {
+///     Ok(format!("{} ({} hp)", spawn.name, spawn.hp))
+/// });
+/// lua.globals().set("spawn", spawn)?;
+/// assert_eq!(lua.eval::<String>(r#"spawn{ name = "orc", hp = 10 }"#, None)?, "orc (10 hp)");
+/// assert!(lua.eval::<String>(r#"spawn("orc")"#, None).is_err());
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kwargs<T>(pub T);
+
+impl<'lua, T: FromLua<'lua>> FromLua<'lua> for Kwargs<T> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        match value {
+            Value::Table(_) => Ok(Kwargs(T::from_lua(value, lua)?)),
+            _ => Err(Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "named arguments",
+                message: Some("expected a table of named arguments".to_owned()),
+            }),
+        }
+    }
+}
+
+/// The result of a callback, converted following the Lua convention of returning `nil` and an
+/// error message on failure, instead of raising an error.
+///
+/// On success, the values of `T` are returned. On failure, `nil` is returned together with the
+/// error formatted with `Display`, so that scripts can check the result with
+/// `local value, err = f()`. Unlike the conversion of `Result<T, E>`, which requires the error
+/// to be convertible to a Lua value, any error type which implements `Display` can be used.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, LuaConvention, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+///
+/// let parse = lua.create_function(|_, s: String| Ok(LuaConvention(s.parse::<i64>())));
+/// lua.globals().set("parse", parse)?;
+/// lua.exec::<()>(
+///     r#"
+///         assert(parse("42") == 42)
+///         local value, err = parse("x")
+///         assert(value == nil and err == "invalid digit found in string")
+///     "#,
+///     None,
+/// )?;
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LuaConvention<T, E>(pub StdResult<T, E>);
+
+impl<T, E> From<StdResult<T, E>> for LuaConvention<T, E> {
+    fn from(result: StdResult<T, E>) -> Self {
+        LuaConvention(result)
+    }
+}
+
+impl<'lua, T: ToLuaMulti<'lua>, E: fmt::Display> ToLuaMulti<'lua> for LuaConvention<T, E> {
+    fn to_lua_multi(self, lua: &'lua Lua) -> Result<MultiValue<'lua>> {
+        match self.0 {
+            Ok(v) => v.to_lua_multi(lua),
+            Err(e) => {
+                let mut result = lua.new_multi_value();
+                result.push_back(Nil);
+                result.push_back(e.to_string().to_lua(lua)?);
+                Ok(result)
+            }
+        }
+    }
 }
 
 macro_rules! impl_tuple {
     () => (
         impl<'lua> ToLuaMulti<'lua> for () {
-            fn to_lua_multi(self, _: &'lua Lua) -> Result<MultiValue> {
-                Ok(MultiValue::new())
+            fn to_lua_multi(self, lua: &'lua Lua) -> Result<MultiValue<'lua>> {
+                Ok(lua.new_multi_value())
             }
         }
 
         impl<'lua> FromLuaMulti<'lua> for () {
-            fn from_lua_multi(_: MultiValue, _: &'lua Lua) -> Result<Self> {
+            fn from_lua_multi(values: MultiValue, lua: &'lua Lua) -> Result<Self> {
+                lua.cache_multi_value(values);
                 Ok(())
             }
         }
@@ -173,9 +518,31 @@ macro_rules! impl_tuple {
             #[allow(unused_mut)]
             #[allow(non_snake_case)]
             fn from_lua_multi(mut values: MultiValue<'lua>, lua: &'lua Lua) -> Result<Self> {
-                $(let $name = values.pop_front().unwrap_or(Nil);)*
+                $(let $name = values.pop_front();)*
                 let $last = FromLuaMulti::from_lua_multi(values, lua)?;
-                Ok(($(FromLua::from_lua($name, lua)?,)* $last,))
+                Ok(($(match $name {
+                    Some(value) => FromLua::from_lua(value, lua)?,
+                    None => FromLua::from_lua_missing(lua)?,
+                },)* $last,))
+            }
+
+            #[allow(unused_mut)]
+            #[allow(non_snake_case)]
+            fn from_lua_args(
+                mut values: MultiValue<'lua>,
+                first_arg: usize,
+                lua: &'lua Lua,
+            ) -> Result<Self> {
+                let mut arg = first_arg;
+                $(
+                    let $name = match values.pop_front() {
+                        Some(value) => FromLua::from_lua(value, lua),
+                        None => FromLua::from_lua_missing(lua),
+                    }.map_err(|err| bad_argument(arg, err))?;
+                    arg += 1;
+                )*
+                let $last = FromLuaMulti::from_lua_args(values, arg, lua)?;
+                Ok(($($name,)* $last,))
             }
         }
     );
@@ -207,3 +574,140 @@ impl_tuple!{A B C D E F G H I}
 impl_tuple!{A B C D E F G H I J}
 impl_tuple!{A B C D E F G H I J K}
 impl_tuple!{A B C D E F G H I J K L}
+impl_tuple!{A B C D E F G H I J K L M}
+impl_tuple!{A B C D E F G H I J K L M N}
+impl_tuple!{A B C D E F G H I J K L M N O}
+impl_tuple!{A B C D E F G H I J K L M N O P}
+
+/// Implements `ToLuaMulti` for a struct, converting its fields to multiple values in the given
+/// order.
+///
+/// Each field but the last must implement `ToLua`, and the last one `ToLuaMulti`, so that a
+/// `Variadic` or `MultiValue` field adds all of its values at the end. A struct can list up to 16
+/// fields, the same as the tuples implementing `ToLuaMulti`. If the struct has a lifetime
+/// parameter, it is used as the lifetime of the Lua state.
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rlua;
+/// # use rlua::{Lua, Result};
+/// struct Position {
+///     x: f64,
+///     y: f64,
+///     label: String,
+/// }
+///
+/// impl_to_lua_multi!(Position { x, y, label });
+///
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let describe = lua.eval::<rlua::Function>(
+///     "function(x, y, label) return label .. ' at ' .. x .. ', ' .. y end",
+///     None,
+/// )?;
+/// let position = Position { x: 1.5, y: 2.0, label: "spawn".to_owned() };
+/// assert_eq!(describe.call::<_, String>(position)?, "spawn at 1.5, 2.0");
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[macro_export]
+macro_rules! impl_to_lua_multi {
+    ($ty:ident<$lt:lifetime> { $($field:ident),* $(,)* }) => {
+        $crate::impl_to_lua_multi!(@impl $lt, $ty<$lt>, $($field)*);
+    };
+
+    ($ty:ident { $($field:ident),* $(,)* }) => {
+        $crate::impl_to_lua_multi!(@impl 'lua, $ty, $($field)*);
+    };
+
+    (@impl $lt:lifetime, $ty:ty, $($field:ident)*) => {
+        impl<$lt> $crate::ToLuaMulti<$lt> for $ty {
+            fn to_lua_multi(
+                self,
+                lua: &$lt $crate::Lua,
+            ) -> $crate::Result<$crate::MultiValue<$lt>> {
+                $crate::ToLuaMulti::to_lua_multi(($(self.$field,)*), lua)
+            }
+        }
+    };
+}
+
+/// Implements `FromLuaMulti` for a struct, converting multiple values to its fields in the given
+/// order.
+///
+/// Each field but the last must implement `FromLua`, and the last one `FromLuaMulti`, so that a
+/// `Variadic` or `MultiValue` field takes all of the remaining values. Missing values are
+/// converted from `nil`, and used as the arguments of a callback, a value which cannot be
+/// converted is reported as an [`Error::BadArgument`] with its position. A struct can list up to
+/// 16 fields, the same as the tuples implementing `FromLuaMulti`. If the struct has a lifetime
+/// parameter, it is used as the lifetime of the Lua state.
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate rlua;
+/// # use rlua::{Lua, Result, Variadic};
+/// struct SpawnArgs {
+///     kind: String,
+///     x: f64,
+///     y: f64,
+///     tags: Variadic<String>,
+/// }
+///
+/// impl_from_lua_multi!(SpawnArgs { kind, x, y, tags });
+///
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let spawn = lua.create_function(|_, args: SpawnArgs| {
+///     Ok(format!("{} at {}, {} [{}]", args.kind, args.x, args.y, args.tags.join(", ")))
+/// });
+/// lua.globals().set("spawn", spawn)?;
+///
+/// assert_eq!(
+///     lua.eval::<String>(r#"spawn("orc", 1, 2, "hostile", "slow")"#, None)?,
+///     "orc at 1, 2 [hostile, slow]"
+/// );
+/// assert!(lua.eval::<String>(r#"spawn("orc", "left")"#, None).is_err());
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Error::BadArgument`]: enum.Error.html#variant.BadArgument
+#[macro_export]
+macro_rules! impl_from_lua_multi {
+    ($ty:ident<$lt:lifetime> { $($field:ident),* $(,)* }) => {
+        $crate::impl_from_lua_multi!(@impl $lt, $ty<$lt>, $($field)*);
+    };
+
+    ($ty:ident { $($field:ident),* $(,)* }) => {
+        $crate::impl_from_lua_multi!(@impl 'lua, $ty, $($field)*);
+    };
+
+    (@impl $lt:lifetime, $ty:ty, $($field:ident)*) => {
+        impl<$lt> $crate::FromLuaMulti<$lt> for $ty {
+            fn from_lua_multi(
+                values: $crate::MultiValue<$lt>,
+                lua: &$lt $crate::Lua,
+            ) -> $crate::Result<Self> {
+                let ($($field,)*) = $crate::FromLuaMulti::from_lua_multi(values, lua)?;
+                Ok(Self { $($field: $field,)* })
+            }
+
+            fn from_lua_args(
+                values: $crate::MultiValue<$lt>,
+                first_arg: usize,
+                lua: &$lt $crate::Lua,
+            ) -> $crate::Result<Self> {
+                let ($($field,)*) = $crate::FromLuaMulti::from_lua_args(values, first_arg, lua)?;
+                Ok(Self { $($field: $field,)* })
+            }
+        }
+    };
+}
@@ -0,0 +1,647 @@
+use error::{Error, Result};
+use lua::{Function, Lua, MultiValue, Value};
+use string::String;
+use types::Integer;
+
+// Pattern matching of the string library, ported from `lstrlib.c` so that the number of steps a
+// single call may take can be limited with `ResourceLimits::max_pattern_steps`.
+
+const L_ESC: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+const MAX_CAPTURES: usize = 32;
+// Maximum recursion depth of `do_match`, like `MAXCCALLS` in `lstrlib.c`.
+const MAX_MATCH_DEPTH: usize = 200;
+
+#[derive(Copy, Clone, PartialEq)]
+enum CaptureLen {
+    Unfinished,
+    Position,
+    Len(usize),
+}
+
+struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    capture: [(usize, CaptureLen); MAX_CAPTURES],
+    depth: usize,
+    // Number of steps left before the limit is reached, or `None` if there is no limit.
+    steps: Option<usize>,
+}
+
+fn pattern_error(message: &str) -> Error {
+    Error::RuntimeError(message.to_owned())
+}
+
+impl<'a> MatchState<'a> {
+    fn new(src: &'a [u8], pat: &'a [u8], steps: Option<usize>) -> MatchState<'a> {
+        MatchState {
+            src,
+            pat,
+            level: 0,
+            capture: [(0, CaptureLen::Unfinished); MAX_CAPTURES],
+            depth: MAX_MATCH_DEPTH,
+            steps,
+        }
+    }
+
+    // Prepares the state for a new match attempt, keeping the remaining steps.
+    fn reset(&mut self) {
+        self.level = 0;
+        self.depth = MAX_MATCH_DEPTH;
+    }
+
+    fn step(&mut self) -> Result<()> {
+        match self.steps {
+            Some(0) => Err(pattern_error("pattern matching step limit exceeded")),
+            Some(ref mut steps) => {
+                *steps -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn pat_at(&self, p: usize) -> u8 {
+        self.pat.get(p).cloned().unwrap_or(0)
+    }
+
+    fn class_end(&self, mut p: usize) -> Result<usize> {
+        let c = self.pat[p];
+        p += 1;
+        if c == L_ESC {
+            if p >= self.pat.len() {
+                return Err(pattern_error("malformed pattern (ends with '%')"));
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pat_at(p) == b'^' {
+                p += 1;
+            }
+            // Look for a `]`, skipping escapes such as `%]`.
+            loop {
+                if p >= self.pat.len() {
+                    return Err(pattern_error("malformed pattern (missing ']')"));
+                }
+                let c = self.pat[p];
+                p += 1;
+                if c == L_ESC && p < self.pat.len() {
+                    p += 1;
+                }
+                if self.pat_at(p) == b']' {
+                    break;
+                }
+            }
+            return Ok(p + 1);
+        }
+        Ok(p)
+    }
+
+    // Matches `c` against the set starting with `[` at `p` and ending with `]` at `ec`.
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut sig = true;
+        if self.pat[p + 1] == b'^' {
+            sig = false;
+            p += 1;
+        }
+        loop {
+            p += 1;
+            if p >= ec {
+                return !sig;
+            }
+            if self.pat[p] == L_ESC {
+                p += 1;
+                if match_class(c, self.pat[p]) {
+                    return sig;
+                }
+            } else if self.pat[p + 1] == b'-' && p + 2 < ec {
+                p += 2;
+                if self.pat[p - 2] <= c && c <= self.pat[p] {
+                    return sig;
+                }
+            } else if self.pat[p] == c {
+                return sig;
+            }
+        }
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        if s >= self.src.len() {
+            return false;
+        }
+        let c = self.src[s];
+        match self.pat[p] {
+            b'.' => true,
+            L_ESC => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn match_balance(&mut self, mut s: usize, p: usize) -> Result<Option<usize>> {
+        if p + 1 >= self.pat.len() {
+            return Err(pattern_error(
+                "malformed pattern (missing arguments to '%b')",
+            ));
+        }
+        if s >= self.src.len() || self.src[s] != self.pat[p] {
+            return Ok(None);
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        let mut depth = 1;
+        s += 1;
+        while s < self.src.len() {
+            self.step()?;
+            let c = self.src[s];
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(s + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+            s += 1;
+        }
+        Ok(None)
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            self.step()?;
+            i += 1;
+        }
+        // Try with the maximum number of repetitions, then with fewer.
+        loop {
+            if let Some(res) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(res));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>> {
+        loop {
+            if let Some(res) = self.do_match(s, ep + 1)? {
+                return Ok(Some(res));
+            } else if self.single_match(s, p, ep) {
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: CaptureLen) -> Result<Option<usize>> {
+        if self.level >= MAX_CAPTURES {
+            return Err(pattern_error("too many captures"));
+        }
+        self.capture[self.level] = (s, what);
+        self.level += 1;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.level -= 1;
+        }
+        Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>> {
+        let l = self.capture_to_close()?;
+        self.capture[l].1 = CaptureLen::Len(s - self.capture[l].0);
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.capture[l].1 = CaptureLen::Unfinished;
+        }
+        Ok(res)
+    }
+
+    fn capture_to_close(&self) -> Result<usize> {
+        (0..self.level)
+            .rev()
+            .find(|&level| self.capture[level].1 == CaptureLen::Unfinished)
+            .ok_or_else(|| pattern_error("invalid pattern capture"))
+    }
+
+    fn match_capture(&mut self, s: usize, l: u8) -> Result<Option<usize>> {
+        let l = self.check_capture(l)?;
+        let (start, len) = match self.capture[l] {
+            (start, CaptureLen::Len(len)) => (start, len),
+            _ => return Ok(None),
+        };
+        if self.src.len() - s >= len && self.src[start..start + len] == self.src[s..s + len] {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn check_capture(&self, l: u8) -> Result<usize> {
+        let l = (l as usize).wrapping_sub(b'1' as usize);
+        if l >= self.level || self.capture[l].1 == CaptureLen::Unfinished {
+            return Err(Error::RuntimeError(format!(
+                "invalid capture index %{}",
+                l.wrapping_add(1) as isize
+            )));
+        }
+        Ok(l)
+    }
+
+    // Matches the pattern starting at `p` against the subject starting at `s`, and returns the
+    // end of the match.
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>> {
+        if self.depth == 0 {
+            return Err(pattern_error("pattern too complex"));
+        }
+        self.depth -= 1;
+        let res = loop {
+            self.step()?;
+            if p == self.pat.len() {
+                break Some(s);
+            }
+            match self.pat[p] {
+                b'(' => {
+                    break if self.pat_at(p + 1) == b')' {
+                        self.start_capture(s, p + 2, CaptureLen::Position)?
+                    } else {
+                        self.start_capture(s, p + 1, CaptureLen::Unfinished)?
+                    };
+                }
+                b')' => break self.end_capture(s, p + 1)?,
+                b'$' if p + 1 == self.pat.len() => {
+                    break if s == self.src.len() { Some(s) } else { None };
+                }
+                L_ESC if self.pat_at(p + 1) == b'b' => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                        continue;
+                    }
+                    None => break None,
+                },
+                L_ESC if self.pat_at(p + 1) == b'f' => {
+                    p += 2;
+                    if self.pat_at(p) != b'[' {
+                        return Err(pattern_error("missing '[' after '%f' in pattern"));
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).cloned().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, ep - 1)
+                        && self.match_bracket_class(current, p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    break None;
+                }
+                L_ESC if self.pat_at(p + 1).is_ascii_digit() => {
+                    match self.match_capture(s, self.pat[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        }
+                        None => break None,
+                    }
+                }
+                _ => {
+                    let ep = self.class_end(p)?;
+                    let suffix = self.pat_at(ep);
+                    if !self.single_match(s, p, ep) {
+                        if suffix == b'*' || suffix == b'?' || suffix == b'-' {
+                            // Accept an empty match.
+                            p = ep + 1;
+                            continue;
+                        }
+                        break None;
+                    }
+                    match suffix {
+                        b'?' => match self.do_match(s + 1, ep + 1)? {
+                            Some(res) => break Some(res),
+                            None => {
+                                p = ep + 1;
+                                continue;
+                            }
+                        },
+                        b'+' => break self.max_expand(s + 1, p, ep)?,
+                        b'*' => break self.max_expand(s, p, ep)?,
+                        b'-' => break self.min_expand(s, p, ep)?,
+                        _ => {
+                            s += 1;
+                            p = ep;
+                            continue;
+                        }
+                    }
+                }
+            }
+        };
+        self.depth += 1;
+        Ok(res)
+    }
+
+    fn push_capture<'lua>(
+        &self,
+        lua: &'lua Lua,
+        i: usize,
+        s: usize,
+        e: usize,
+    ) -> Result<Value<'lua>> {
+        if i >= self.level {
+            if i == 0 {
+                Ok(Value::String(lua.create_byte_string(&self.src[s..e])))
+            } else {
+                Err(Error::RuntimeError(format!(
+                    "invalid capture index %{}",
+                    i + 1
+                )))
+            }
+        } else {
+            match self.capture[i] {
+                (_, CaptureLen::Unfinished) => Err(pattern_error("unfinished capture")),
+                (start, CaptureLen::Position) => Ok(Value::Integer(start as Integer + 1)),
+                (start, CaptureLen::Len(len)) => Ok(Value::String(
+                    lua.create_byte_string(&self.src[start..start + len]),
+                )),
+            }
+        }
+    }
+
+    // Returns the captures of a match, or the whole match from `s` to `e` if there are none and
+    // `whole` is given.
+    fn push_captures<'lua>(
+        &self,
+        lua: &'lua Lua,
+        whole: Option<(usize, usize)>,
+        values: &mut MultiValue<'lua>,
+    ) -> Result<()> {
+        let (s, e) = whole.unwrap_or((0, 0));
+        let levels = if self.level == 0 && whole.is_some() {
+            1
+        } else {
+            self.level
+        };
+        for i in 0..levels {
+            values.push_back(self.push_capture(lua, i, s, e)?);
+        }
+        Ok(())
+    }
+}
+
+fn match_class(c: u8, class: u8) -> bool {
+    let res = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        // Unlike `is_ascii_whitespace`, `isspace` includes the vertical tab.
+        b's' => c == b' ' || (b'\t'..=b'\r').contains(&c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        b'z' => c == 0,
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !res
+    } else {
+        res
+    }
+}
+
+// Converts a relative string position, where negative positions count from the end, to an
+// absolute one.
+fn relative_position(pos: Integer, len: usize) -> Integer {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as Integer + pos + 1
+    }
+}
+
+fn is_truthy(value: &Value) -> bool {
+    !matches!(*value, Value::Nil | Value::Boolean(false))
+}
+
+// Implements `string.find` and `string.match`.
+fn find_aux<'lua>(lua: &'lua Lua, args: MultiValue<'lua>, find: bool) -> Result<MultiValue<'lua>> {
+    let (s, p, init, plain) =
+        lua.unpack_multi::<(String, String, Option<Integer>, Value)>(args)?;
+    let (src, pat) = (s.as_bytes(), p.as_bytes());
+    let init = relative_position(init.unwrap_or(1), src.len()).max(1) as usize;
+    let mut results = MultiValue::new();
+    if init > src.len() + 1 {
+        results.push_back(Value::Nil);
+        return Ok(results);
+    }
+
+    if find && (is_truthy(&plain) || !pat.iter().any(|c| SPECIALS.contains(c))) {
+        let found = if pat.is_empty() {
+            Some(0)
+        } else {
+            src[init - 1..]
+                .windows(pat.len())
+                .position(|window| window == pat)
+        };
+        match found {
+            Some(offset) => {
+                let start = init - 1 + offset;
+                results.push_back(Value::Integer(start as Integer + 1));
+                results.push_back(Value::Integer((start + pat.len()) as Integer));
+            }
+            None => results.push_back(Value::Nil),
+        }
+        return Ok(results);
+    }
+
+    let (anchor, pat) = match pat.first() {
+        Some(&b'^') => (true, &pat[1..]),
+        _ => (false, pat),
+    };
+    let mut ms = MatchState::new(src, pat, lua.pattern_step_limit());
+    let mut s1 = init - 1;
+    loop {
+        ms.reset();
+        if let Some(end) = ms.do_match(s1, 0)? {
+            if find {
+                results.push_back(Value::Integer(s1 as Integer + 1));
+                results.push_back(Value::Integer(end as Integer));
+                ms.push_captures(lua, None, &mut results)?;
+            } else {
+                ms.push_captures(lua, Some((s1, end)), &mut results)?;
+            }
+            return Ok(results);
+        }
+        if s1 >= src.len() || anchor {
+            break;
+        }
+        s1 += 1;
+    }
+    results.push_back(Value::Nil);
+    Ok(results)
+}
+
+// Appends the replacement of the match from `s` to `e` to `out`, like `add_value` in `lstrlib.c`.
+fn add_value<'lua>(
+    lua: &'lua Lua,
+    ms: &MatchState,
+    out: &mut Vec<u8>,
+    s: usize,
+    e: usize,
+    repl: &Value<'lua>,
+) -> Result<()> {
+    let value = match *repl {
+        Value::String(_) | Value::Integer(_) | Value::Number(_) => {
+            let repl = lua.coerce_string(repl.clone())?;
+            let repl = repl.as_bytes();
+            let mut i = 0;
+            while i < repl.len() {
+                let c = repl[i];
+                i += 1;
+                if c != L_ESC {
+                    out.push(c);
+                    continue;
+                }
+                let d = repl.get(i).cloned().unwrap_or(0);
+                i += 1;
+                if d == L_ESC {
+                    out.push(L_ESC);
+                } else if d == b'0' {
+                    out.extend_from_slice(&ms.src[s..e]);
+                } else if d.is_ascii_digit() {
+                    let capture = ms.push_capture(lua, (d - b'1') as usize, s, e)?;
+                    out.extend_from_slice(lua.coerce_string(capture)?.as_bytes());
+                } else {
+                    return Err(pattern_error(
+                        "invalid use of '%' in replacement string",
+                    ));
+                }
+            }
+            return Ok(());
+        }
+        Value::Function(ref function) => {
+            let mut captures = MultiValue::new();
+            ms.push_captures(lua, Some((s, e)), &mut captures)?;
+            function.call::<_, Value>(captures)?
+        }
+        Value::Table(ref table) => table.get::<_, Value>(ms.push_capture(lua, 0, s, e)?)?,
+        _ => unreachable!(),
+    };
+    match value {
+        Value::Nil | Value::Boolean(false) => out.extend_from_slice(&ms.src[s..e]),
+        Value::String(_) | Value::Integer(_) | Value::Number(_) => {
+            out.extend_from_slice(lua.coerce_string(value)?.as_bytes())
+        }
+        value => {
+            return Err(Error::RuntimeError(format!(
+                "invalid replacement value (a {})",
+                value.type_name()
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn gsub<'lua>(lua: &'lua Lua, args: MultiValue<'lua>) -> Result<(String<'lua>, Integer)> {
+    let (s, p, repl, max_n) = lua.unpack_multi::<(String, String, Value, Option<Integer>)>(args)?;
+    match repl {
+        Value::String(_)
+        | Value::Integer(_)
+        | Value::Number(_)
+        | Value::Table(_)
+        | Value::Function(_) => {}
+        _ => {
+            return Err(pattern_error(
+                "bad argument #3 to 'gsub' (string/function/table expected)",
+            ))
+        }
+    }
+    let src = s.as_bytes();
+    let (anchor, pat) = match p.as_bytes().first() {
+        Some(&b'^') => (true, &p.as_bytes()[1..]),
+        _ => (false, p.as_bytes()),
+    };
+    let max_n = max_n.unwrap_or(src.len() as Integer + 1);
+
+    let mut ms = MatchState::new(src, pat, lua.pattern_step_limit());
+    let mut out = Vec::new();
+    let mut position = 0;
+    let mut last_match = None;
+    let mut n = 0;
+    while n < max_n {
+        ms.reset();
+        match ms.do_match(position, 0)? {
+            Some(end) if Some(end) != last_match => {
+                n += 1;
+                add_value(lua, &ms, &mut out, position, end, &repl)?;
+                position = end;
+                last_match = Some(end);
+            }
+            _ if position < src.len() => {
+                out.push(src[position]);
+                position += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&src[position..]);
+    Ok((lua.create_byte_string(&out), n))
+}
+
+fn gmatch<'lua>(lua: &'lua Lua, (s, p): (String<'lua>, String<'lua>)) -> Result<Function<'lua>> {
+    let src = s.as_bytes().to_vec();
+    let pat = p.as_bytes().to_vec();
+    let mut position = 0;
+    let mut last_match = None;
+    let iterator = lua.create_function(move |lua, ()| {
+        let mut ms = MatchState::new(&src, &pat, lua.pattern_step_limit());
+        let mut results = MultiValue::new();
+        let mut start = position;
+        while start <= src.len() {
+            ms.reset();
+            match ms.do_match(start, 0)? {
+                Some(end) if Some(end) != last_match => {
+                    position = end;
+                    last_match = Some(end);
+                    ms.push_captures(lua, Some((start, end)), &mut results)?;
+                    return Ok(results);
+                }
+                _ => start += 1,
+            }
+        }
+        Ok(results)
+    });
+    Ok(lua.raise_string_errors(iterator))
+}
+
+// Creates replacements for the pattern matching functions of the string library, which fail once
+// a call takes more steps than `ResourceLimits::max_pattern_steps`.
+pub(crate) fn create_pattern_functions(lua: &Lua) -> Vec<(&'static str, Function<'_>)> {
+    let functions = vec![
+        (
+            "find",
+            lua.create_function(|lua, args| find_aux(lua, args, true)),
+        ),
+        (
+            "match",
+            lua.create_function(|lua, args| find_aux(lua, args, false)),
+        ),
+        ("gmatch", lua.create_function(gmatch)),
+        ("gsub", lua.create_function(gsub)),
+    ];
+    functions
+        .into_iter()
+        .map(|(name, function)| (name, lua.raise_string_errors(function)))
+        .collect()
+}
@@ -0,0 +1,784 @@
+#[cfg(feature = "builtin-lua")]
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "builtin-lua")]
+use std::ffi::CStr;
+use std::mem;
+#[cfg(feature = "builtin-lua")]
+use std::os::raw::c_int;
+use std::os::raw::c_void;
+use std::result::Result as StdResult;
+use std::string::String as StdString;
+
+use ffi;
+use error::{Error, Result};
+use lua::{Function, Lua, Thread, Value};
+use table::Table;
+use util::{check_stack, stack_guard};
+
+// Every persisted value starts with this header, followed by the persisted value.
+const MAGIC: &[u8] = b"\x1bRluaPersist\x01";
+
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INTEGER: u8 = 3;
+const NUMBER: u8 = 4;
+const STRING: u8 = 5;
+// A table, function or thread which was persisted before, by the order in which objects were
+// first persisted.
+const REFERENCE: u8 = 6;
+// A value of the permanents table, by its name.
+const PERMANENT: u8 = 7;
+// Fields as pairs of keys and values, ended by a nil key, followed by the metatable.
+const TABLE: u8 = 8;
+// Bytecode, followed by the number of upvalues and the upvalues.
+const FUNCTION: u8 = 9;
+// Whether the coroutine has a function, followed by the function.
+const THREAD: u8 = 10;
+// A suspended coroutine: the number of values on its stack and the size its stack needs, the
+// values, and the number of calls followed by the calls.
+#[cfg(feature = "builtin-lua")]
+const SUSPENDED_THREAD: u8 = 11;
+
+// Deepest nesting of values which can be persisted and unpersisted, counting tables, functions and
+// coroutines along with the fields, upvalues and functions they hold. This is the limit Lua puts
+// on nested C calls, deeper values would overflow the stack.
+const MAX_DEPTH: usize = 200;
+
+// An upvalue followed by its value, or an upvalue shared with an upvalue of a function persisted
+// before, followed by the object number of that function and the index of the upvalue.
+const UPVALUE: u8 = 0;
+const SHARED_UPVALUE: u8 = 1;
+// An upvalue which is a local variable of a suspended coroutine, followed by the coroutine and the
+// position of the variable in its stack.
+const OPEN_UPVALUE: u8 = 2;
+
+// Largest stack Lua gives a coroutine, `LUAI_MAXSTACK`.
+#[cfg(feature = "builtin-lua")]
+const MAX_STACK: u64 = 1_000_000;
+
+pub(crate) fn persist<'lua>(value: Value<'lua>, permanents: &Table<'lua>) -> Result<Vec<u8>> {
+    let mut persister = Persister {
+        permanents: HashMap::new(),
+        objects: HashMap::new(),
+        pinned: Vec::new(),
+        upvalues: HashMap::new(),
+        out: MAGIC.to_vec(),
+        depth: 0,
+    };
+    for pair in permanents.clone().pairs::<StdString, Value>() {
+        let (name, value) = pair?;
+        if let Some(key) = object_key(&value) {
+            persister.permanents.insert(key, name);
+        }
+    }
+    persister.value(value)?;
+    Ok(persister.out)
+}
+
+pub(crate) unsafe fn unpersist<'lua>(
+    lua: &'lua Lua,
+    data: &[u8],
+    permanents: &Table<'lua>,
+) -> Result<Value<'lua>> {
+    if !data.starts_with(MAGIC) {
+        return Err(Error::DeserializeError(
+            "data was not written by Lua::persist".to_owned(),
+        ));
+    }
+    let mut unpersister = Unpersister {
+        lua,
+        permanents,
+        objects: Vec::new(),
+        suspended: HashSet::new(),
+        data,
+        pos: MAGIC.len(),
+        depth: 0,
+    };
+    let value = unpersister.value()?;
+    if unpersister.pos != data.len() {
+        return Err(Error::DeserializeError(
+            "unexpected data after the persisted value".to_owned(),
+        ));
+    }
+    Ok(value)
+}
+
+// Identifies a value which is not copied by value: the name of its type and its address.
+fn object_key(value: &Value) -> Option<(&'static str, *const c_void)> {
+    match *value {
+        Value::Nil | Value::Boolean(_) | Value::Integer(_) | Value::Number(_) => None,
+        Value::String(_) => None,
+        _ => Some((value.type_name(), value.to_pointer())),
+    }
+}
+
+struct Persister<'lua> {
+    permanents: HashMap<(&'static str, *const c_void), StdString>,
+    // Object numbers of the tables, functions and threads persisted so far, which are kept alive
+    // in `pinned` so that their addresses are not reused.
+    objects: HashMap<*const c_void, u64>,
+    pinned: Vec<Value<'lua>>,
+    // Upvalues of the functions persisted so far, with the object number of the first function
+    // they were found in and their index in that function.
+    upvalues: HashMap<*mut c_void, (u64, u32)>,
+    out: Vec<u8>,
+    // Number of values being persisted which hold the current one.
+    depth: usize,
+}
+
+impl<'lua> Persister<'lua> {
+    fn value(&mut self, value: Value<'lua>) -> Result<()> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::SerializeError(format!(
+                "cannot persist values nested more than {} levels deep",
+                MAX_DEPTH
+            )));
+        }
+        self.depth += 1;
+        let result = self.nested_value(value);
+        self.depth -= 1;
+        result
+    }
+
+    fn nested_value(&mut self, value: Value<'lua>) -> Result<()> {
+        let key = object_key(&value);
+        if let Some(name) = key.and_then(|key| self.permanents.get(&key)).cloned() {
+            self.out.push(PERMANENT);
+            self.bytes(name.as_bytes());
+            return Ok(());
+        }
+        if let Some(&number) = key.and_then(|key| self.objects.get(&key.1)) {
+            self.out.push(REFERENCE);
+            self.u64(number);
+            return Ok(());
+        }
+
+        match value {
+            Value::Nil => self.out.push(NIL),
+            Value::Boolean(false) => self.out.push(FALSE),
+            Value::Boolean(true) => self.out.push(TRUE),
+            Value::Integer(i) => {
+                self.out.push(INTEGER);
+                self.u64(i as u64);
+            }
+            Value::Number(n) => {
+                self.out.push(NUMBER);
+                self.u64(n.to_bits());
+            }
+            Value::String(s) => {
+                self.out.push(STRING);
+                self.bytes(s.as_bytes());
+            }
+            Value::Table(t) => {
+                self.out.push(TABLE);
+                self.object(Value::Table(t.clone()));
+                for pair in t.clone().pairs::<Value, Value>() {
+                    let (key, value) = pair?;
+                    self.value(key)?;
+                    self.value(value)?;
+                }
+                self.out.push(NIL);
+                match t.get_metatable() {
+                    Some(metatable) => self.value(Value::Table(metatable))?,
+                    None => self.out.push(NIL),
+                }
+            }
+            Value::Function(f) => {
+                if is_c_function(&f) {
+                    return Err(unpersistable("a C or Rust function", &Value::Function(f)));
+                }
+                self.out.push(FUNCTION);
+                let number = self.object(Value::Function(f.clone()));
+                self.bytes(&f.dump(false)?);
+                let upvalues = get_upvalues(&f);
+                self.u64(upvalues.len() as u64);
+                for (i, (id, value)) in upvalues.into_iter().enumerate() {
+                    if let Some((thread, level)) = self.open_upvalue(&f, i as u32 + 1) {
+                        self.out.push(OPEN_UPVALUE);
+                        self.value(Value::Thread(thread))?;
+                        self.u64(level);
+                    } else if let Some(&(function, index)) = self.upvalues.get(&id) {
+                        self.out.push(SHARED_UPVALUE);
+                        self.u64(function);
+                        self.u64(index as u64);
+                    } else {
+                        self.upvalues.insert(id, (number, i as u32 + 1));
+                        self.out.push(UPVALUE);
+                        self.value(value)?;
+                    }
+                }
+            }
+            Value::Thread(t) => match coroutine_state(&t) {
+                Ok(Coroutine::Unstarted(function)) => {
+                    self.out.push(THREAD);
+                    self.object(Value::Thread(t.clone()));
+                    self.out.push(TRUE);
+                    self.value(Value::Function(function))?;
+                }
+                Ok(Coroutine::Dead) => {
+                    self.out.push(THREAD);
+                    self.object(Value::Thread(t.clone()));
+                    self.out.push(FALSE);
+                }
+                #[cfg(feature = "builtin-lua")]
+                Ok(Coroutine::Suspended(frames)) => self.suspended_thread(t, frames)?,
+                Err(state) => return Err(unpersistable(state, &Value::Thread(t))),
+            },
+            value => return Err(unpersistable(value.type_name(), &value)),
+        }
+        Ok(())
+    }
+
+    // Kept out of `nested_value`, which recurses for every level of nesting and so should not
+    // need much of the stack.
+    #[cfg(feature = "builtin-lua")]
+    fn suspended_thread(&mut self, t: Thread<'lua>, frames: Vec<ffi::rlua_Frame>) -> Result<()> {
+        self.out.push(SUSPENDED_THREAD);
+        self.object(Value::Thread(t.clone()));
+        let top = stack_top(&t);
+        let size = frames.iter().fold(top, |size, frame| cmp::max(size, frame.top));
+        self.u64(top as u64);
+        self.u64(size as u64);
+        // The first position holds no value.
+        for i in 1..top {
+            self.value(stack_value(&t, i))?;
+        }
+        self.u64(frames.len() as u64);
+        for frame in frames {
+            self.u64(frame.func as u64);
+            self.u64(frame.top as u64);
+            self.u64(frame.base as u64);
+            self.u64(frame.pc as u64);
+            self.u64(frame.extra as u64);
+            self.u64(frame.nresults as i64 as u64);
+            self.u64(frame.callstatus as u64);
+        }
+        Ok(())
+    }
+
+    // Returns the suspended coroutine and the position in its stack of upvalue `n` of `f`, if it is
+    // a local variable of a coroutine which is persisted along with it.
+    #[cfg(feature = "builtin-lua")]
+    fn open_upvalue(&self, f: &Function<'lua>, n: u32) -> Option<(Thread<'lua>, u64)> {
+        let lua = f.0.lua;
+        let (thread, level) = unsafe {
+            stack_guard(lua.state, 0, || {
+                check_stack(lua.state, 2);
+                lua.push_ref(lua.state, &f.0);
+                let level = ffi::rlua_openupvalue(lua.state, n as c_int);
+                if level < 0 {
+                    ffi::lua_pop(lua.state, 1);
+                    return None;
+                }
+                let thread = Thread(lua.pop_ref(lua.state));
+                ffi::lua_pop(lua.state, 1);
+                Some((thread, level as u64))
+            })
+        }?;
+        let key = object_key(&Value::Thread(thread.clone()))?;
+        match coroutine_state(&thread) {
+            Ok(Coroutine::Suspended(_)) if !self.permanents.contains_key(&key) => {
+                Some((thread, level))
+            }
+            _ => None,
+        }
+    }
+
+    #[cfg(not(feature = "builtin-lua"))]
+    fn open_upvalue(&self, _: &Function<'lua>, _: u32) -> Option<(Thread<'lua>, u64)> {
+        None
+    }
+
+    // Gives the next object number to a table, function or thread.
+    fn object(&mut self, value: Value<'lua>) -> u64 {
+        let number = self.objects.len() as u64;
+        self.objects.insert(value.to_pointer(), number);
+        self.pinned.push(value);
+        number
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.out.extend_from_slice(bytes);
+    }
+}
+
+fn unpersistable(what: &str, value: &Value) -> Error {
+    Error::SerializeError(format!(
+        "cannot persist {} ({:p}) which is not in the permanents table",
+        what,
+        value.to_pointer()
+    ))
+}
+
+struct Unpersister<'a, 'lua: 'a> {
+    lua: &'lua Lua,
+    permanents: &'a Table<'lua>,
+    // Tables, functions and threads by object number.
+    objects: Vec<Value<'lua>>,
+    // Addresses of the suspended coroutines created so far, whose local variables functions may
+    // have as upvalues.
+    suspended: HashSet<*const c_void>,
+    data: &'a [u8],
+    pos: usize,
+    // Number of values being unpersisted which hold the current one.
+    depth: usize,
+}
+
+impl<'a, 'lua> Unpersister<'a, 'lua> {
+    unsafe fn value(&mut self) -> Result<Value<'lua>> {
+        if self.depth == MAX_DEPTH {
+            return Err(corrupt("values are nested too deeply"));
+        }
+        self.depth += 1;
+        let result = self.nested_value();
+        self.depth -= 1;
+        result
+    }
+
+    unsafe fn nested_value(&mut self) -> Result<Value<'lua>> {
+        let lua = self.lua;
+        Ok(match self.u8()? {
+            NIL => Value::Nil,
+            FALSE => Value::Boolean(false),
+            TRUE => Value::Boolean(true),
+            INTEGER => Value::Integer(self.u64()? as i64),
+            NUMBER => Value::Number(f64::from_bits(self.u64()?)),
+            STRING => Value::String(lua.create_byte_string(self.bytes()?)),
+            REFERENCE => {
+                let number = self.u64()?;
+                self.objects
+                    .get(number as usize)
+                    .cloned()
+                    .ok_or_else(|| corrupt("reference to an unknown object"))?
+            }
+            PERMANENT => {
+                let name = lua.create_byte_string(self.bytes()?);
+                match self.permanents.raw_get::<_, Value>(name.clone())? {
+                    Value::Nil => {
+                        return Err(Error::DeserializeError(format!(
+                            "permanent '{}' is not in the permanents table",
+                            StdString::from_utf8_lossy(name.as_bytes())
+                        )))
+                    }
+                    value => value,
+                }
+            }
+            TABLE => {
+                let table = lua.create_table();
+                self.objects.push(Value::Table(table.clone()));
+                loop {
+                    let key = self.value()?;
+                    if let Value::Nil = key {
+                        break;
+                    }
+                    let value = self.value()?;
+                    table.raw_set(key, value)?;
+                }
+                match self.value()? {
+                    Value::Nil => {}
+                    Value::Table(metatable) => table.set_metatable(Some(metatable)),
+                    _ => return Err(corrupt("metatable which is not a table")),
+                }
+                Value::Table(table)
+            }
+            FUNCTION => {
+                let function = lua.load_buffer(self.bytes()?, None)?;
+                self.objects.push(Value::Function(function.clone()));
+                let count = self.u64()?;
+                for i in 1..count + 1 {
+                    match self.u8()? {
+                        UPVALUE => {
+                            let value = self.value()?;
+                            set_upvalue(&function, i as u32, value)?;
+                        }
+                        OPEN_UPVALUE => {
+                            let thread = match self.value()? {
+                                Value::Thread(thread) => thread,
+                                _ => return Err(corrupt("upvalue of a value which is not a thread")),
+                            };
+                            if !self.suspended.contains(&thread.to_pointer()) {
+                                return Err(corrupt("upvalue of a coroutine which is not suspended"));
+                            }
+                            let level = self.u64()?;
+                            join_open_upvalue(&function, i as u32, &thread, level)?;
+                        }
+                        SHARED_UPVALUE => {
+                            let number = self.u64()?;
+                            let shared = match self.objects.get(number as usize) {
+                                Some(Value::Function(shared)) => shared.clone(),
+                                _ => return Err(corrupt("upvalue of an unknown function")),
+                            };
+                            let index = self.u64()? as u32;
+                            join_upvalues(&function, i as u32, &shared, index)?;
+                        }
+                        _ => return Err(corrupt("invalid upvalue")),
+                    }
+                }
+                Value::Function(function)
+            }
+            THREAD => {
+                let thread = new_coroutine(lua);
+                self.objects.push(Value::Thread(thread.clone()));
+                if self.u8()? == TRUE {
+                    match self.value()? {
+                        Value::Function(function) => set_coroutine_function(&thread, &function),
+                        _ => return Err(corrupt("coroutine without a function")),
+                    }
+                }
+                Value::Thread(thread)
+            }
+            #[cfg(feature = "builtin-lua")]
+            SUSPENDED_THREAD => Value::Thread(self.suspended_thread()?),
+            _ => return Err(corrupt("invalid value")),
+        })
+    }
+
+    #[cfg(feature = "builtin-lua")]
+    unsafe fn suspended_thread(&mut self) -> Result<Thread<'lua>> {
+        let thread = new_coroutine(self.lua);
+        self.objects.push(Value::Thread(thread.clone()));
+        let top = self.u64()?;
+        let size = self.u64()?;
+        if top < 1 || size < top || size > MAX_STACK {
+            return Err(corrupt("invalid size of the stack of a coroutine"));
+        }
+        reserve_stack(&thread, top as c_int, size as c_int)?;
+        self.suspended.insert(thread.to_pointer());
+        for i in 1..top {
+            let value = self.value()?;
+            set_stack_value(&thread, i as c_int, value);
+        }
+        let count = self.u64()?;
+        if count > size {
+            return Err(corrupt("invalid number of calls of a coroutine"));
+        }
+        let mut frames = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            frames.push(ffi::rlua_Frame {
+                func: self.u64()? as i64,
+                top: self.u64()? as i64,
+                base: self.u64()? as i64,
+                pc: self.u64()? as i64,
+                extra: self.u64()? as i64,
+                nresults: self.u64()? as i64 as c_int,
+                callstatus: self.u64()? as c_int,
+            });
+        }
+        set_frames(&thread, &frames)?;
+        Ok(thread)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        let byte = *self.data.get(self.pos).ok_or_else(|| corrupt("unexpected end"))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let bytes = self.take(mem::size_of::<u64>())?;
+        let mut n = [0; 8];
+        n.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(n))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u64()?;
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(corrupt("unexpected end"));
+        }
+        self.take(len as usize)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(corrupt("unexpected end"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+fn corrupt(what: &str) -> Error {
+    Error::DeserializeError(format!("corrupt persisted data: {}", what))
+}
+
+fn is_c_function(f: &Function) -> bool {
+    let lua = f.0.lua;
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 1);
+            lua.push_ref(lua.state, &f.0);
+            let c_function = ffi::lua_iscfunction(lua.state, -1) != 0;
+            ffi::lua_pop(lua.state, 1);
+            c_function
+        })
+    }
+}
+
+// Returns the upvalues of a Lua function, with the ids Lua gives them to find out which upvalues
+// are shared between functions.
+fn get_upvalues<'lua>(f: &Function<'lua>) -> Vec<(*mut c_void, Value<'lua>)> {
+    let lua = f.0.lua;
+    let mut upvalues = Vec::new();
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 3);
+            lua.push_ref(lua.state, &f.0);
+            let mut n = 1;
+            while !ffi::lua_getupvalue(lua.state, -1, n).is_null() {
+                let id = ffi::lua_upvalueid(lua.state, -2, n);
+                upvalues.push((id, lua.pop_value(lua.state)));
+                n += 1;
+            }
+            ffi::lua_pop(lua.state, 1);
+        })
+    }
+    upvalues
+}
+
+fn set_upvalue<'lua>(f: &Function<'lua>, n: u32, value: Value<'lua>) -> Result<()> {
+    let lua = f.0.lua;
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 2);
+            lua.push_ref(lua.state, &f.0);
+            lua.push_value(lua.state, value);
+            let set = !ffi::lua_setupvalue(lua.state, -2, n as _).is_null();
+            ffi::lua_pop(lua.state, if set { 1 } else { 2 });
+            if set {
+                Ok(())
+            } else {
+                Err(corrupt("function has fewer upvalues"))
+            }
+        })
+    }
+}
+
+// Makes upvalue `n` of `f` refer to upvalue `m` of `g`.
+fn join_upvalues(f: &Function, n: u32, g: &Function, m: u32) -> Result<()> {
+    let lua = f.0.lua;
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 3);
+            lua.push_ref(lua.state, &f.0);
+            lua.push_ref(lua.state, &g.0);
+            // `lua_upvaluejoin` does not check the indices.
+            let valid = !ffi::lua_getupvalue(lua.state, -2, n as _).is_null()
+                && !ffi::lua_getupvalue(lua.state, -2, m as _).is_null();
+            if valid {
+                ffi::lua_pop(lua.state, 2);
+                ffi::lua_upvaluejoin(lua.state, -2, n as _, -1, m as _);
+                ffi::lua_pop(lua.state, 2);
+                Ok(())
+            } else {
+                ffi::lua_settop(lua.state, -3);
+                Err(corrupt("function has fewer upvalues"))
+            }
+        })
+    }
+}
+
+// What can be persisted of a coroutine.
+enum Coroutine<'lua> {
+    // A coroutine which has not started yet, with its function.
+    Unstarted(Function<'lua>),
+    Dead,
+    // A coroutine suspended in Lua functions, with its calls.
+    #[cfg(feature = "builtin-lua")]
+    Suspended(Vec<ffi::rlua_Frame>),
+}
+
+// Returns what can be persisted of a coroutine, or a description of the coroutine if it cannot be
+// persisted.
+fn coroutine_state<'lua>(thread: &Thread<'lua>) -> StdResult<Coroutine<'lua>, &'static str> {
+    let lua = thread.0.lua;
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 2);
+            lua.push_ref(lua.state, &thread.0);
+            let co = ffi::lua_tothread(lua.state, -1);
+            let mut ar: ffi::lua_Debug = mem::zeroed();
+            let result = if co == lua.main_state {
+                Err("the main thread")
+            } else if ffi::lua_status(co) == ffi::LUA_YIELD {
+                suspended_frames(co)
+            } else if ffi::lua_status(co) != ffi::LUA_OK {
+                Ok(Coroutine::Dead)
+            } else if ffi::lua_getstack(co, 0, &mut ar) != 0 {
+                Err("a running coroutine")
+            } else if ffi::lua_gettop(co) == 0 {
+                Ok(Coroutine::Dead)
+            } else if ffi::lua_gettop(co) == 1 && ffi::lua_type(co, 1) == ffi::LUA_TFUNCTION {
+                ffi::lua_pushvalue(co, 1);
+                ffi::lua_xmove(co, lua.state, 1);
+                Ok(Coroutine::Unstarted(Function(lua.pop_ref(lua.state))))
+            } else {
+                Err("a coroutine which has arguments")
+            };
+            ffi::lua_pop(lua.state, 1);
+            result
+        })
+    }
+}
+
+// Returns the calls of a suspended coroutine, which can only be resumed elsewhere if they are calls
+// of Lua functions, apart from the C function which yielded.
+#[cfg(feature = "builtin-lua")]
+unsafe fn suspended_frames<'lua>(co: *mut ffi::lua_State) -> StdResult<Coroutine<'lua>, &'static str> {
+    let count = ffi::rlua_getframes(co, [].as_mut_ptr(), 0);
+    if count < 0 {
+        return Err("a coroutine suspended inside a C function");
+    }
+    let mut frames = vec![ffi::rlua_Frame::default(); count as usize];
+    ffi::rlua_getframes(co, frames.as_mut_ptr(), count);
+    Ok(Coroutine::Suspended(frames))
+}
+
+#[cfg(not(feature = "builtin-lua"))]
+unsafe fn suspended_frames<'lua>(_: *mut ffi::lua_State) -> StdResult<Coroutine<'lua>, &'static str> {
+    Err("a suspended coroutine")
+}
+
+// Returns the number of values on the stack of a coroutine, counting the position below its first
+// function which holds no value.
+#[cfg(feature = "builtin-lua")]
+fn stack_top(thread: &Thread) -> i64 {
+    with_coroutine(thread, |co| unsafe { ffi::rlua_stacktop(co) })
+}
+
+// Returns the value at position `i` of the stack of a coroutine, including the positions which
+// belong to calls below the innermost one.
+#[cfg(feature = "builtin-lua")]
+fn stack_value<'lua>(thread: &Thread<'lua>, i: i64) -> Value<'lua> {
+    let lua = thread.0.lua;
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 2);
+            lua.push_ref(lua.state, &thread.0);
+            let co = ffi::lua_tothread(lua.state, -1);
+            ffi::rlua_pushslot(lua.state, co, i);
+            let value = lua.pop_value(lua.state);
+            ffi::lua_pop(lua.state, 1);
+            value
+        })
+    }
+}
+
+// Calls `f` with the state of a coroutine.
+#[cfg(feature = "builtin-lua")]
+fn with_coroutine<R, F: FnOnce(*mut ffi::lua_State) -> R>(thread: &Thread, f: F) -> R {
+    let lua = thread.0.lua;
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 1);
+            lua.push_ref(lua.state, &thread.0);
+            let co = ffi::lua_tothread(lua.state, -1);
+            let result = f(co);
+            ffi::lua_pop(lua.state, 1);
+            result
+        })
+    }
+}
+
+// Gives a coroutine which has not started `top` positions on its stack, which hold nil, and room
+// for `size` positions.
+#[cfg(feature = "builtin-lua")]
+fn reserve_stack(thread: &Thread, top: c_int, size: c_int) -> Result<()> {
+    with_coroutine(thread, |co| unsafe {
+        if ffi::lua_checkstack(co, size) == 0 {
+            return Err(corrupt("stack of a coroutine is too large"));
+        }
+        ffi::lua_settop(co, top - 1);
+        Ok(())
+    })
+}
+
+// Sets the value at position `i` of the stack of a coroutine which has not started.
+#[cfg(feature = "builtin-lua")]
+fn set_stack_value<'lua>(thread: &Thread<'lua>, i: c_int, value: Value<'lua>) {
+    let lua = thread.0.lua;
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 2);
+            lua.push_ref(lua.state, &thread.0);
+            let co = ffi::lua_tothread(lua.state, -1);
+            lua.push_value(lua.state, value);
+            ffi::lua_xmove(lua.state, co, 1);
+            ffi::lua_replace(co, i);
+            ffi::lua_pop(lua.state, 1);
+        })
+    }
+}
+
+// Suspends a coroutine whose stack has been restored in the calls it was persisted in.
+#[cfg(feature = "builtin-lua")]
+fn set_frames(thread: &Thread, frames: &[ffi::rlua_Frame]) -> Result<()> {
+    with_coroutine(thread, |co| unsafe {
+        let error = ffi::rlua_setframes(co, frames.as_ptr(), frames.len() as c_int);
+        if error.is_null() {
+            Ok(())
+        } else {
+            Err(corrupt(&CStr::from_ptr(error).to_string_lossy()))
+        }
+    })
+}
+
+// Makes upvalue `n` of `f` the local variable at position `level` of the stack of a suspended
+// coroutine.
+#[cfg(feature = "builtin-lua")]
+fn join_open_upvalue(f: &Function, n: u32, thread: &Thread, level: u64) -> Result<()> {
+    let lua = f.0.lua;
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 2);
+            lua.push_ref(lua.state, &thread.0);
+            let co = ffi::lua_tothread(lua.state, -1);
+            lua.push_ref(lua.state, &f.0);
+            let error = ffi::rlua_joinopenupvalue(lua.state, n as c_int, co, level as i64);
+            ffi::lua_pop(lua.state, 2);
+            if error.is_null() {
+                Ok(())
+            } else {
+                Err(corrupt(&CStr::from_ptr(error).to_string_lossy()))
+            }
+        })
+    }
+}
+
+#[cfg(not(feature = "builtin-lua"))]
+fn join_open_upvalue(_: &Function, _: u32, _: &Thread, _: u64) -> Result<()> {
+    Err(corrupt("upvalue of a suspended coroutine"))
+}
+
+// Creates a coroutine without a function, which is dead until it is given one.
+fn new_coroutine(lua: &Lua) -> Thread<'_> {
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 1);
+            ffi::lua_newthread(lua.state);
+            Thread(lua.pop_ref(lua.state))
+        })
+    }
+}
+
+fn set_coroutine_function(thread: &Thread, function: &Function) {
+    let lua = thread.0.lua;
+    unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 2);
+            lua.push_ref(lua.state, &thread.0);
+            let co = ffi::lua_tothread(lua.state, -1);
+            lua.push_ref(lua.state, &function.0);
+            ffi::lua_xmove(lua.state, co, 1);
+            ffi::lua_pop(lua.state, 1);
+        })
+    }
+}
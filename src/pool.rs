@@ -0,0 +1,160 @@
+use std::cmp;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use error::{Error, Result};
+use lua::{FromLua, Function, Lua, ToLua};
+
+type Job = Box<dyn FnOnce(&Lua) + Send>;
+
+/// A fixed number of Lua states, each owned by a worker thread, which run batches of work in
+/// parallel.
+///
+/// A `Lua` state cannot be moved between threads, so each state is created by its worker thread
+/// and lives until the pool is dropped. The globals scripts set in a state are kept between
+/// batches, but batches cannot choose which state their work runs in.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{LuaPool, Result};
+/// # fn try_main() -> Result<()> {
+/// let pool = LuaPool::new(4);
+/// let squares = pool.par_map::<i64, i64>("function(x) return x * x end", (1..=100).collect());
+/// assert_eq!(squares.len(), 100);
+/// assert_eq!(squares[9].clone()?, 100);
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct LuaPool {
+    workers: Vec<(Sender<Job>, JoinHandle<()>)>,
+}
+
+impl LuaPool {
+    /// Creates a pool of `size` states, or a single state if `size` is 0, and starts their
+    /// threads.
+    pub fn new(size: usize) -> LuaPool {
+        let workers = (0..cmp::max(size, 1))
+            .map(|_| {
+                let (jobs, receiver) = mpsc::channel::<Job>();
+                let thread = thread::Builder::new()
+                    .name("rlua-pool".to_owned())
+                    .spawn(move || {
+                        let lua = Lua::new();
+                        for job in receiver {
+                            job(&lua);
+                        }
+                    })
+                    .expect("could not start a thread of the pool");
+                (jobs, thread)
+            })
+            .collect();
+        LuaPool { workers }
+    }
+
+    /// Returns the number of states in the pool.
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Sets the prelude of every state of the pool with [`Lua::set_prelude`], so that the
+    /// functions run by [`par_map`] can use the helpers it defines.
+    ///
+    /// Waits until the prelude has run in every state, and returns the first error it raised.
+    ///
+    /// [`Lua::set_prelude`]: struct.Lua.html#method.set_prelude
+    /// [`par_map`]: #method.par_map
+    pub fn set_prelude(&self, source: &str) -> Result<()> {
+        let (results_sender, results_receiver) = mpsc::channel();
+        for (jobs, _) in &self.workers {
+            let results = results_sender.clone();
+            let source = source.to_owned();
+            let _ = jobs.send(Box::new(move |lua: &Lua| {
+                let _ = results.send(lua.set_prelude(&source));
+            }));
+        }
+        drop(results_sender);
+        let results = results_receiver.into_iter().collect::<Vec<_>>();
+        results.into_iter().collect()
+    }
+
+    /// Calls a Lua function on each item, distributing the items across the states of the pool,
+    /// and returns the results in the order of the items.
+    ///
+    /// `function_source` is evaluated like [`Lua::eval`] once in each state, and must evaluate to
+    /// the function, such as `function(x) return x * 2 end`, or be a chunk returning it, which
+    /// can define the helpers the function uses. The items are converted to Lua in the state
+    /// calling the function on them, and the results converted back from Lua, so both must be
+    /// owned types which can be sent to other threads.
+    ///
+    /// Each item fails separately: an error loading the function, or an error raised by the
+    /// function, is returned as the result of the items it affected. An item whose worker
+    /// thread panicked has an [`Error::RuntimeError`] as its result.
+    ///
+    /// [`Lua::eval`]: struct.Lua.html#method.eval
+    /// [`Error::RuntimeError`]: enum.Error.html#variant.RuntimeError
+    pub fn par_map<T, R>(&self, function_source: &str, items: Vec<T>) -> Vec<Result<R>>
+    where
+        T: 'static + Send + for<'lua> ToLua<'lua>,
+        R: 'static + Send + for<'lua> FromLua<'lua>,
+    {
+        let count = items.len();
+        let items = Arc::new(Mutex::new(items.into_iter().enumerate()));
+        let (results_sender, results_receiver) = mpsc::channel();
+        for (jobs, _) in &self.workers {
+            let items = items.clone();
+            let results = results_sender.clone();
+            let source = function_source.to_owned();
+            let job: Job = Box::new(move |lua: &Lua| {
+                let function = lua.eval::<Function>(&source, None);
+                loop {
+                    let next = items.lock().unwrap_or_else(|e| e.into_inner()).next();
+                    let (index, item) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    let result = match function {
+                        Ok(ref function) => function.call::<_, R>(item),
+                        Err(ref err) => Err(err.clone()),
+                    };
+                    if results.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+            // A worker which panicked no longer receives jobs, and the others take its share.
+            let _ = jobs.send(job);
+        }
+        drop(results_sender);
+
+        let mut results = (0..count).map(|_| None).collect::<Vec<_>>();
+        for (index, result) in results_receiver {
+            results[index] = Some(result);
+        }
+        results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    Err(Error::RuntimeError(
+                        "the thread of the pool running the item panicked".to_owned(),
+                    ))
+                })
+            })
+            .collect()
+    }
+}
+
+impl Drop for LuaPool {
+    fn drop(&mut self) {
+        for (jobs, thread) in self.workers.drain(..) {
+            drop(jobs);
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use error::{Error, Result};
+use lua::Lua;
+
+/// Options for [`precompile_script`].
+///
+/// [`precompile_script`]: fn.precompile_script.html
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrecompileOptions {
+    /// Whether the script is compiled to bytecode, rather than included as source.
+    ///
+    /// Bytecode loads faster, but depends on the platform, so the script should only be compiled
+    /// to bytecode when the build script runs on the same kind of platform as the crate.
+    /// Defaults to `false`.
+    pub bytecode: bool,
+    /// Whether debug information, such as line numbers, is left out of the bytecode.
+    ///
+    /// Defaults to `false`.
+    pub strip: bool,
+}
+
+/// Checks the syntax of a script from a build script, and prepares it to be included with
+/// [`include_lua!`].
+///
+/// `path` is relative to the directory of the crate being built. A syntax error in the script is
+/// returned as an [`Error::SyntaxError`], so that unwrapping the result fails the build. The
+/// script, or its bytecode, is written to the `rlua` directory of `OUT_DIR`, and Cargo is told to
+/// run the build script again when the script changes.
+///
+/// # Examples
+///
+/// In `build.rs`, with `rlua` as a build dependency:
+///
+/// ```no_run
+/// extern crate rlua;
+///
+/// use rlua::PrecompileOptions;
+///
+/// fn main() {
+///     let options = PrecompileOptions {
+///         bytecode: true,
+///         ..PrecompileOptions::default()
+///     };
+///     rlua::precompile_script("scripts/init.lua", options).unwrap();
+/// }
+/// ```
+///
+/// [`include_lua!`]: macro.include_lua.html
+/// [`Error::SyntaxError`]: enum.Error.html#variant.SyntaxError
+pub fn precompile_script<P: AsRef<Path>>(path: P, options: PrecompileOptions) -> Result<()> {
+    let var = |name: &str| {
+        env::var_os(name).map(PathBuf::from).ok_or_else(|| {
+            Error::RuntimeError(format!(
+                "{} is not set, precompile_script must be called from a build script",
+                name
+            ))
+        })
+    };
+    let source_dir = var("CARGO_MANIFEST_DIR")?;
+    let out_dir = var("OUT_DIR")?;
+
+    let path = path.as_ref();
+    println!("cargo:rerun-if-changed={}", source_dir.join(path).display());
+    precompile_script_to(path, &source_dir, &out_dir, options)
+}
+
+// Compiles `source_dir/path` to `out_dir/rlua/path`.
+pub(crate) fn precompile_script_to(
+    path: &Path,
+    source_dir: &Path,
+    out_dir: &Path,
+    options: PrecompileOptions,
+) -> Result<()> {
+    let source = fs::read_to_string(source_dir.join(path)).map_err(Error::external)?;
+
+    let lua = Lua::new();
+    let function = lua.load(&source, Some(&chunk_name(path)))?;
+    let chunk = if options.bytecode {
+        function.dump(options.strip)?
+    } else {
+        source.into_bytes()
+    };
+
+    let output = out_dir.join("rlua").join(path);
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).map_err(Error::external)?;
+    }
+    fs::write(output, chunk).map_err(Error::external)
+}
+
+// Returns the name scripts are loaded with, so that messages refer to `path:line`.
+pub(crate) fn chunk_name(path: &Path) -> String {
+    format!("@{}", path.display())
+}
+
+/// A script prepared by [`precompile_script`], as included by [`include_lua!`].
+///
+/// The script can be loaded with [`Lua::load_included`].
+///
+/// [`precompile_script`]: fn.precompile_script.html
+/// [`include_lua!`]: macro.include_lua.html
+/// [`Lua::load_included`]: struct.Lua.html#method.load_included
+#[derive(Copy, Clone, Debug)]
+pub struct IncludedChunk {
+    pub(crate) name: &'static str,
+    pub(crate) chunk: &'static [u8],
+}
+
+impl IncludedChunk {
+    /// Returns the path of the script, relative to the directory of the crate.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns whether the script was compiled to bytecode.
+    pub fn is_bytecode(&self) -> bool {
+        self.chunk.first() == Some(&0x1b)
+    }
+
+    // Used by `include_lua!`. Unsafe because Lua does not verify bytecode, so `chunk` must have
+    // been written by `precompile_script`.
+    #[doc(hidden)]
+    pub unsafe fn from_build_output(name: &'static str, chunk: &'static [u8]) -> IncludedChunk {
+        IncludedChunk {
+            name,
+            chunk,
+        }
+    }
+}
+
+/// Includes a script prepared by [`precompile_script`] in the build script of the crate, as an
+/// [`IncludedChunk`].
+///
+/// # Examples
+///
+/// ```ignore
+/// #[macro_use]
+/// extern crate rlua;
+///
+/// use rlua::{Lua, Result};
+///
+/// fn main() -> Result<()> {
+///     let lua = Lua::new();
+///     lua.load_included(include_lua!("scripts/init.lua"))?.call::<_, ()>(())
+/// }
+/// ```
+///
+/// [`precompile_script`]: fn.precompile_script.html
+/// [`IncludedChunk`]: struct.IncludedChunk.html
+#[macro_export]
+macro_rules! include_lua {
+    ($path:expr) => {
+        unsafe {
+            $crate::IncludedChunk::from_build_output(
+                $path,
+                include_bytes!(concat!(env!("OUT_DIR"), "/rlua/", $path)),
+            )
+        }
+    };
+}
@@ -1,10 +1,42 @@
 //! Re-exports most types with an extra `Lua*` prefix to prevent name clashes.
 
-pub use {AnyUserData as LuaAnyUserData, Error as LuaError, ExternalError as LuaExternalError,
-         ExternalResult as LuaExternalResult, FromLua, FromLuaMulti, Function as LuaFunction,
-         Integer as LuaInteger, LightUserData as LuaLightUserData, Lua,
-         MetaMethod as LuaMetaMethod, MultiValue as LuaMultiValue, Nil as LuaNil,
-         Number as LuaNumber, Result as LuaResult, String as LuaString, Table as LuaTable,
-         TablePairs as LuaTablePairs, TableSequence as LuaTableSequence, Thread as LuaThread,
-         ThreadStatus as LuaThreadStatus, ToLua, ToLuaMulti, UserData as LuaUserData,
-         UserDataMethods as LuaUserDataMethods, Value as LuaValue};
+pub use {AccessKind as LuaAccessKind, AllocationSite as LuaAllocationSite,
+         AnyUserData as LuaAnyUserData, BorrowedBytes as LuaBorrowedBytes,
+         BorrowedStr as LuaBorrowedStr, Budget as LuaBudget, Buffer as LuaBuffer,
+         ChannelReceiver as LuaChannelReceiver, ChannelSender as LuaChannelSender,
+         Context as LuaContext, Coverage as LuaCoverage, DebugFrame as LuaDebugFrame,
+         Debugger as LuaDebugger, DebugPause as LuaDebugPause, DirectoryVfs as LuaDirectoryVfs,
+         DurationFormat as LuaDurationFormat, Either as LuaEither, Error as LuaError,
+         ErrorContext as LuaErrorContext, ErrorValue as LuaErrorValue, Events as LuaEvents,
+         Execution as LuaExecution, ExecutionStep as LuaExecutionStep,
+         ExternalError as LuaExternalError, ExternalResult as LuaExternalResult, Frame as LuaFrame,
+         FromLua, FromLuaMulti, Function as LuaFunction, GcEvent as LuaGcEvent,
+         GlobalAccess as LuaGlobalAccess, GlobalsDiff as LuaGlobalsDiff,
+         GlobalsSnapshot as LuaGlobalsSnapshot, IncludedChunk as LuaIncludedChunk,
+         Integer as LuaInteger, IterValues as LuaIterValues, Kwargs as LuaKwargs,
+         LightUserData as LuaLightUserData, Lua, LuaConvention, LuaPool,
+         MemoryProfile as LuaMemoryProfile, MemoryVfs as LuaMemoryVfs,
+         MetaFieldValue as LuaMetaFieldValue, MetaMethod as LuaMetaMethod,
+         MultiValue as LuaMultiValue, Nil as LuaNil, Number as LuaNumber, OpenMode as LuaOpenMode,
+         Opt as LuaOpt, PauseReason as LuaPauseReason, PrecompileOptions as LuaPrecompileOptions,
+         ReadOnlyVfs as LuaReadOnlyVfs, RefStats as LuaRefStats, RegistryKey as LuaRegistryKey,
+         RequireResolver as LuaRequireResolver, RequireTrace as LuaRequireTrace,
+         ResourceLimits as LuaResourceLimits, ResourceReport as LuaResourceReport,
+         Result as LuaResult, SequenceItem as LuaSequenceItem, Snapshot as LuaSnapshot,
+         SnapshotValue as LuaSnapshotValue, SourceMap as LuaSourceMap, StackGuard as LuaStackGuard,
+         String as LuaString, Table as LuaTable, TableBackend as LuaTableBackend,
+         TableIPairs as LuaTableIPairs, TablePairs as LuaTablePairs,
+         TableSequence as LuaTableSequence, Thread as LuaThread, ThreadStatus as LuaThreadStatus,
+         ToLua, ToLuaMulti, TypedFunction as LuaTypedFunction,
+         TypedLightUserData as LuaTypedLightUserData, UserData as LuaUserData,
+         UserDataMetatable as LuaUserDataMetatable, UserDataMethods as LuaUserDataMethods,
+         UserDataRef as LuaUserDataRef, UserDataRefMut as LuaUserDataRefMut, Value as LuaValue,
+         Vfs as LuaVfs, VfsFile as LuaVfsFile, Watchdog as LuaWatchdog, WeakLuaRef as LuaWeakRef,
+         WrappedFunction as LuaWrappedFunction, Yielder as LuaYielder};
+
+#[cfg(feature = "serde")]
+pub use SerializeOptions as LuaSerializeOptions;
+#[cfg(feature = "http")]
+pub use HttpOptions as LuaHttpOptions;
+#[cfg(feature = "async")]
+pub use {AsyncCall as LuaAsyncCall, Awaitable as LuaAwaitable};
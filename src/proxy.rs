@@ -0,0 +1,175 @@
+use std::cell::RefCell;
+use std::os::raw::c_int;
+use std::rc::Rc;
+
+use error::{Error, Result};
+use ffi;
+use lua::{FromLua, Function, Lua, MultiValue, ToLua, Value};
+use table::Table;
+use util::push_string;
+
+/// The contents of a table created with [`Lua::create_proxy_table`], kept in Rust.
+///
+/// Scripts index, assign to, take the length of and iterate over the table as usual, and each
+/// operation calls the backend, so that large or computed collections can be used by scripts
+/// without being copied into Lua.
+///
+/// [`Lua::create_proxy_table`]: struct.Lua.html#method.create_proxy_table
+#[allow(clippy::len_without_is_empty)]
+pub trait TableBackend {
+    type Key: for<'lua> FromLua<'lua> + for<'lua> ToLua<'lua>;
+    type Value: for<'lua> FromLua<'lua> + for<'lua> ToLua<'lua>;
+
+    /// Returns the value of `key`, or `None` if the table has no value for it.
+    fn get(&self, key: Self::Key) -> Result<Option<Self::Value>>;
+
+    /// Assigns a value to `key`, or removes it if `value` is `None`. Backends which cannot store
+    /// the value may return an error, which is raised in the script.
+    fn set(&mut self, key: Self::Key, value: Option<Self::Value>) -> Result<()>;
+
+    /// Returns the length of the table, as returned by the `#` operator.
+    fn len(&self) -> usize;
+
+    /// Returns the entries of the table, in the order `pairs` traverses them.
+    ///
+    /// The iterator lives as long as the traversal, so it cannot borrow the backend. Backends
+    /// sharing their data with the host through an `Rc` or `Arc` can give it a clone.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Self::Key, Self::Value)>>;
+}
+
+// Creates the table returned by `Lua::create_proxy_table`: an empty table whose metatable has
+// functions calling `backend`, and cannot be replaced by scripts.
+pub(crate) fn create_proxy_table<B>(lua: &Lua, backend: B) -> Result<Table<'_>>
+where
+    B: 'static + TableBackend,
+{
+    let backend = Rc::new(RefCell::new(backend));
+    let metatable = lua.create_table();
+
+    let index_backend = backend.clone();
+    metatable.raw_set(
+        "__index",
+        lua.create_function(move |lua, (_, key): (Table, Value)| {
+            // Like in other tables, keys of another type have no value.
+            let key = match B::Key::from_lua(key, lua) {
+                Ok(key) => key,
+                Err(_) => return Ok(Value::Nil),
+            };
+            let value = index_backend.borrow().get(key)?;
+            value.to_lua(lua)
+        }),
+    )?;
+
+    let newindex_backend = backend.clone();
+    metatable.raw_set(
+        "__newindex",
+        lua.create_function(move |lua, (_, key, value): (Table, Value, Value)| {
+            let key = B::Key::from_lua(key, lua)?;
+            let value = match value {
+                Value::Nil => None,
+                value => Some(B::Value::from_lua(value, lua)?),
+            };
+            newindex_backend.borrow_mut().set(key, value)
+        }),
+    )?;
+
+    let len_backend = backend.clone();
+    metatable.raw_set(
+        "__len",
+        lua.create_function(move |_, _: Table| Ok(len_backend.borrow().len())),
+    )?;
+
+    metatable.raw_set(
+        "__pairs",
+        lua.create_function(move |lua, proxy: Table| {
+            let mut entries = backend.borrow().iter();
+            let next = lua.create_function(move |lua, ()| match entries.next() {
+                Some((key, value)) => Ok((key.to_lua(lua)?, value.to_lua(lua)?)),
+                None => Ok((Value::Nil, Value::Nil)),
+            });
+            Ok((next, proxy))
+        }),
+    )?;
+    metatable.raw_set("__metatable", false)?;
+
+    let proxy = lua.create_table();
+    proxy.set_metatable(Some(metatable));
+    Ok(proxy)
+}
+
+// Creates the table returned by `Lua::create_lazy_table`: an empty table whose metamethods call
+// `loader` on first use, and then turn the table into a copy of the table it returns.
+pub(crate) fn create_lazy_table<F>(lua: &Lua, loader: F) -> Result<Table<'_>>
+where
+    F: 'static + FnOnce(&Lua) -> Result<Table>,
+{
+    let loader = Rc::new(RefCell::new(Some(loader)));
+    let metatable = lua.create_table();
+    for &event in &["__index", "__newindex", "__len", "__pairs"] {
+        let loader = loader.clone();
+        metatable.raw_set(
+            event,
+            lua.create_function(move |lua, (table, mut args): (Table, MultiValue)| {
+                let loader = loader.borrow_mut().take().ok_or_else(|| {
+                    Error::RuntimeError(
+                        "lazy table accessed while loading, or after its loader failed".to_owned(),
+                    )
+                })?;
+                let contents = loader(lua)?;
+                for pair in contents.clone().pairs::<Value, Value>() {
+                    let (key, value) = pair?;
+                    table.raw_set(key, value)?;
+                }
+                table.set_metatable(contents.get_metatable());
+
+                // Repeat the operation which loaded the table on its contents.
+                let key = args.pop_front().unwrap_or(Value::Nil);
+                match event {
+                    "__index" => Ok(MultiValue::from_vec(vec![table.get(key)?])),
+                    "__newindex" => {
+                        table.set(key, args.pop_front().unwrap_or(Value::Nil))?;
+                        Ok(MultiValue::new())
+                    }
+                    "__len" => Ok(MultiValue::from_vec(vec![Value::Integer(table.len()?)])),
+                    _ => {
+                        let pairs = match table.get_metatable() {
+                            Some(metatable) => metatable.raw_get::<_, Option<Function>>("__pairs")?,
+                            None => None,
+                        };
+                        match pairs {
+                            Some(pairs) => pairs.call(table),
+                            None => {
+                                let next = unsafe { Function::from_c_function(lua, raw_next) };
+                                Ok(MultiValue::from_vec(vec![
+                                    Value::Function(next),
+                                    Value::Table(table),
+                                    Value::Nil,
+                                ]))
+                            }
+                        }
+                    }
+                }
+            }),
+        )?;
+    }
+
+    let table = lua.create_table();
+    table.set_metatable(Some(metatable));
+    Ok(table)
+}
+
+// The `next` function of the base library, which the `__pairs` metamethod of a lazy table returns
+// once the table has been loaded.
+unsafe extern "C" fn raw_next(state: *mut ffi::lua_State) -> c_int {
+    if ffi::lua_type(state, 1) != ffi::LUA_TTABLE {
+        push_string(state, "bad argument #1 to 'next' (table expected)");
+        ffi::lua_error(state);
+    }
+    ffi::lua_settop(state, 2);
+    if ffi::lua_next(state, 1) != 0 {
+        2
+    } else {
+        ffi::lua_pushnil(state);
+        1
+    }
+}
@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use rand_core::{RngCore, SeedableRng};
+
+use error::{Error, Result};
+use lua::{Lua, Value};
+use table::Table;
+use types::{Integer, Number};
+
+// Replaces `math.random` and `math.randomseed` with functions using `rng`, for
+// `Lua::install_rng`.
+pub(crate) fn install_rng<R>(lua: &Lua, rng: R) -> Result<()>
+where
+    R: 'static + RngCore + SeedableRng,
+{
+    let rng = Rc::new(RefCell::new(rng));
+    let math = lua.globals().get::<_, Table>("math")?;
+
+    let random_rng = rng.clone();
+    math.set(
+        "random",
+        lua.create_function(move |lua, (m, n): (Option<Integer>, Option<Integer>)| {
+            let mut rng = random_rng.borrow_mut();
+            let (low, high, arg_index) = match (m, n) {
+                (None, _) => return Ok(Value::Number(random_float(&mut *rng))),
+                (Some(m), None) => (1, m, 1),
+                (Some(m), Some(n)) => (m, n, 2),
+            };
+            if low > high {
+                let err = Error::BadArgument {
+                    function_name: None,
+                    arg_index,
+                    cause: Arc::new(Error::RuntimeError("interval is empty".to_owned())),
+                };
+                return Err(lua.name_bad_argument(err, false));
+            }
+            Ok(Value::Integer(random_integer(&mut *rng, low, high)))
+        }),
+    )?;
+
+    math.set(
+        "randomseed",
+        lua.create_function(move |lua, seed: Value| {
+            let seed = match seed {
+                Value::Integer(i) => i as u64,
+                seed => {
+                    let n = lua.coerce_number(seed)?;
+                    if n.fract() == 0.0 && n.abs() < Integer::MAX as Number {
+                        n as Integer as u64
+                    } else {
+                        n.to_bits()
+                    }
+                }
+            };
+            *rng.borrow_mut() = R::seed_from_u64(seed);
+            Ok(())
+        }),
+    )?;
+
+    Ok(())
+}
+
+// Returns a float in `[0, 1)` with 53 random bits.
+fn random_float<R: RngCore>(rng: &mut R) -> Number {
+    (rng.next_u64() >> 11) as Number * (1.0 / (1u64 << 53) as Number)
+}
+
+// Returns an integer in `[low, high]`, without the bias of taking a remainder.
+fn random_integer<R: RngCore>(rng: &mut R, low: Integer, high: Integer) -> Integer {
+    let range = (high as u64).wrapping_sub(low as u64);
+    if range == u64::MAX {
+        return rng.next_u64() as Integer;
+    }
+
+    let span = range + 1;
+    // The largest multiple of `span` that fits in a `u64`, values above it are rejected.
+    let zone = u64::MAX - (u64::MAX - span + 1) % span;
+    loop {
+        let value = rng.next_u64();
+        if value <= zone {
+            return (low as u64).wrapping_add(value % span) as Integer;
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::string::String as StdString;
+
+use regex::bytes::{Captures, Regex};
+
+use error::{Error, Result};
+use lua::{FromLua, Lua, MultiValue, Nil, Value};
+use string::String;
+use table::Table;
+use types::Integer;
+use userdata::{MetaMethod, UserData, UserDataMethods};
+
+// Maximum number of patterns compiled by the functions of the module which are kept for reuse.
+const PATTERN_CACHE_SIZE: usize = 64;
+
+// A compiled regular expression, as returned by `re.compile`.
+struct LuaRegex(Regex);
+
+impl UserData for LuaRegex {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("is_match", |_, re, (s, init): (String, Option<Integer>)| {
+            let s = s.as_bytes();
+            Ok(re.0.is_match_at(s, start_index(init, s.len())))
+        });
+        methods.add_method("find", |lua, re, args| find(lua, &re.0, args));
+        methods.add_method("match", |lua, re, args| match_(lua, &re.0, args));
+        methods.add_method("captures", |lua, re, args| captures(lua, &re.0, args));
+        methods.add_method("replace", |lua, re, args| replace(lua, &re.0, args));
+        methods.add_method("split", |lua, re, args| split(lua, &re.0, args));
+        methods.add_meta_method(MetaMethod::ToString, |_, re, ()| {
+            Ok(re.0.as_str().to_owned())
+        });
+    }
+}
+
+// Creates the table of functions installed by `Lua::load_regex_module`.
+pub(crate) fn create_regex_module(lua: &Lua) -> Result<Table<'_>> {
+    let cache = Rc::new(RefCell::new(HashMap::new()));
+    let module = lua.create_table();
+
+    module.raw_set(
+        "compile",
+        lua.create_function(|lua, pattern: String| {
+            Ok(lua.create_userdata(LuaRegex(compile(pattern.to_str()?)?)))
+        }),
+    )?;
+
+    macro_rules! module_function {
+        ($name:expr, $function:expr) => {{
+            let cache = cache.clone();
+            module.raw_set(
+                $name,
+                lua.create_function(move |lua, mut args: MultiValue| {
+                    let pattern = String::from_lua(args.pop_front().unwrap_or(Nil), lua)?;
+                    let re = cached_regex(&cache, pattern.to_str()?)?;
+                    $function(lua, &re, lua.unpack_multi(args)?)
+                }),
+            )?;
+        }};
+    }
+
+    module_function!("is_match", |_, re: &Regex, (s, init): (String, Option<Integer>)| {
+        let s = s.as_bytes();
+        Ok(re.is_match_at(s, start_index(init, s.len())))
+    });
+    module_function!("find", find);
+    module_function!("match", match_);
+    module_function!("captures", captures);
+    module_function!("replace", replace);
+    module_function!("split", split);
+
+    Ok(module)
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern).map_err(|err| Error::RuntimeError(err.to_string()))
+}
+
+// Compiles a pattern passed to one of the functions of the module, reusing the result of earlier
+// calls with the same pattern.
+fn cached_regex(cache: &RefCell<HashMap<StdString, Regex>>, pattern: &str) -> Result<Regex> {
+    if let Some(re) = cache.borrow().get(pattern) {
+        return Ok(re.clone());
+    }
+
+    let re = compile(pattern)?;
+    let mut cache = cache.borrow_mut();
+    if cache.len() >= PATTERN_CACHE_SIZE {
+        cache.clear();
+    }
+    cache.insert(pattern.to_owned(), re.clone());
+    Ok(re)
+}
+
+// Converts an optional 1-based position, which counts from the end of the string if it is
+// negative, into a byte offset.
+fn start_index(init: Option<Integer>, len: usize) -> usize {
+    match init {
+        None | Some(0) => 0,
+        Some(i) if i > 0 => (i as usize - 1).min(len),
+        Some(i) => len.saturating_sub(i.unsigned_abs() as usize),
+    }
+}
+
+// Returns the 1-based start and end positions of the first match, like `string.find`.
+fn find<'lua>(
+    _: &'lua Lua,
+    re: &Regex,
+    (s, init): (String<'lua>, Option<Integer>),
+) -> Result<MultiValue<'lua>> {
+    let s = s.as_bytes();
+    Ok(match re.find_at(s, start_index(init, s.len())) {
+        Some(m) => MultiValue::from_vec(vec![
+            Value::Integer(m.start() as Integer + 1),
+            Value::Integer(m.end() as Integer),
+        ]),
+        None => MultiValue::from_vec(vec![Nil]),
+    })
+}
+
+// Returns the captures of the first match, or the whole match if the pattern has no capture
+// groups, like `string.match`.
+fn match_<'lua>(
+    lua: &'lua Lua,
+    re: &Regex,
+    (s, init): (String<'lua>, Option<Integer>),
+) -> Result<MultiValue<'lua>> {
+    let s = s.as_bytes();
+    Ok(match re.captures_at(s, start_index(init, s.len())) {
+        Some(caps) => capture_values(lua, &caps),
+        None => MultiValue::from_vec(vec![Nil]),
+    })
+}
+
+// Returns a table holding the whole match at index 0, the capture groups from index 1, and the
+// named capture groups under their names. Groups which did not participate in the match are
+// `false`.
+fn captures<'lua>(
+    lua: &'lua Lua,
+    re: &Regex,
+    (s, init): (String<'lua>, Option<Integer>),
+) -> Result<Option<Table<'lua>>> {
+    let s = s.as_bytes();
+    let caps = match re.captures_at(s, start_index(init, s.len())) {
+        Some(caps) => caps,
+        None => return Ok(None),
+    };
+
+    let table = lua.create_table_with_capacity(caps.len() - 1, 1);
+    for (i, group) in caps.iter().enumerate() {
+        table.raw_set(i, group_value(lua, group.map(|m| m.as_bytes())))?;
+    }
+    for name in re.capture_names().flatten() {
+        table.raw_set(name, group_value(lua, caps.name(name).map(|m| m.as_bytes())))?;
+    }
+    Ok(Some(table))
+}
+
+// Replaces matches like `string.gsub`, returning the new string and the number of replacements.
+// The replacement is either a string, in which `$1` and `${name}` refer to capture groups, or a
+// function called with the values `match` would return, whose result replaces the match unless
+// it is `false` or `nil`.
+fn replace<'lua>(
+    lua: &'lua Lua,
+    re: &Regex,
+    (s, replacement, limit): (String<'lua>, Value<'lua>, Option<usize>),
+) -> Result<(String<'lua>, Integer)> {
+    let s = s.as_bytes();
+    let mut result = Vec::with_capacity(s.len());
+    let mut last = 0;
+    let mut count = 0;
+
+    for caps in re.captures_iter(s).take(limit.unwrap_or(usize::MAX)) {
+        let m = caps.get(0).unwrap();
+        result.extend_from_slice(&s[last..m.start()]);
+        match replacement {
+            Value::String(ref template) => caps.expand(template.as_bytes(), &mut result),
+            Value::Integer(_) | Value::Number(_) => {
+                caps.expand(lua.coerce_string(replacement.clone())?.as_bytes(), &mut result)
+            }
+            Value::Function(ref function) => {
+                match function.call::<_, Value>(capture_values(lua, &caps))? {
+                    Value::Nil | Value::Boolean(false) => result.extend_from_slice(m.as_bytes()),
+                    value => result.extend_from_slice(lua.coerce_string(value)?.as_bytes()),
+                }
+            }
+            ref value => {
+                return Err(Error::RuntimeError(format!(
+                    "replacement must be a string or a function, got {}",
+                    value.type_name()
+                )))
+            }
+        }
+        last = m.end();
+        count += 1;
+    }
+    result.extend_from_slice(&s[last..]);
+
+    Ok((lua.create_byte_string(&result), count))
+}
+
+// Splits a string at the matches of the pattern, returning a sequence of the pieces.
+fn split<'lua>(
+    lua: &'lua Lua,
+    re: &Regex,
+    (s, limit): (String<'lua>, Option<usize>),
+) -> Result<Table<'lua>> {
+    let s = s.as_bytes();
+    let pieces = match limit {
+        Some(limit) => re.splitn(s, limit).collect::<Vec<_>>(),
+        None => re.split(s).collect(),
+    };
+    lua.create_sequence_from(pieces.into_iter().map(|piece| lua.create_byte_string(piece)))
+}
+
+fn capture_values<'lua>(lua: &'lua Lua, caps: &Captures) -> MultiValue<'lua> {
+    if caps.len() == 1 {
+        return MultiValue::from_vec(vec![group_value(lua, caps.get(0).map(|m| m.as_bytes()))]);
+    }
+    caps.iter()
+        .skip(1)
+        .map(|group| group_value(lua, group.map(|m| m.as_bytes())))
+        .collect()
+}
+
+fn group_value<'lua>(lua: &'lua Lua, group: Option<&[u8]>) -> Value<'lua> {
+    match group {
+        Some(bytes) => Value::String(lua.create_byte_string(bytes)),
+        None => Value::Boolean(false),
+    }
+}
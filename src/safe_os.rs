@@ -0,0 +1,49 @@
+use std::collections::HashSet;
+use std::env;
+
+use error::Result;
+use ffi;
+use lua::{Function, Lua};
+use table::Table;
+use util::{check_stack, stack_guard};
+
+// The functions of the standard `os` library which are kept by `Lua::use_safe_os`. They only read
+// the clocks of the system and format dates.
+const SAFE_OS_FUNCTIONS: &[&str] = &["time", "clock", "date"];
+
+// Replaces the `os` library with a table holding only its functions reading the time, and a
+// `getenv` which only reads the environment variables in `allowed_env`, for `Lua::use_safe_os`.
+pub(crate) fn install_safe_os(lua: &Lua, allowed_env: &[&str]) -> Result<()> {
+    // Opens a separate copy of the standard library, so that it is available even if scripts or
+    // the host have removed or replaced the `os` global.
+    let open_os = unsafe {
+        stack_guard(lua.state, 0, || {
+            check_stack(lua.state, 1);
+            ffi::lua_pushcfunction(lua.state, ffi::luaopen_os);
+            Function(lua.pop_ref(lua.state))
+        })
+    };
+    let standard_os = open_os.call::<_, Table>(())?;
+
+    let os = lua.create_table();
+    for &name in SAFE_OS_FUNCTIONS {
+        os.raw_set(name, standard_os.raw_get::<_, Function>(name)?)?;
+    }
+
+    let allowed_env = allowed_env
+        .iter()
+        .map(|&name| name.to_owned())
+        .collect::<HashSet<_>>();
+    os.raw_set(
+        "getenv",
+        lua.create_function(move |_, name: String| {
+            if allowed_env.contains(&name) {
+                Ok(env::var(name).ok())
+            } else {
+                Ok(None)
+            }
+        }),
+    )?;
+
+    lua.install_module("os", os)
+}
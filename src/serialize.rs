@@ -0,0 +1,814 @@
+use std::cell::{Ref, RefCell};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::os::raw::c_void;
+use std::str;
+use std::error::Error as StdError;
+
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, SerializeStruct, SerializeTuple};
+use serde::ser::Serializer as SerdeSerializer;
+use erased_serde;
+
+use ffi;
+use error::{Error, ErrorValue, Result};
+use util::{check_stack, error_kind, stack_guard};
+use types::Integer;
+use string::String;
+use table::Table;
+use userdata::AnyUserData;
+use lua::{Frame, Lua, Value};
+use globals::{GlobalsDiff, GlobalsSnapshot, SnapshotValue};
+
+// Borrows the userdata at the given pointer as a serializable value.
+pub(crate) type SerializeUserData =
+    unsafe fn(*mut c_void) -> Result<Ref<'static, dyn erased_serde::Serialize>>;
+
+pub(crate) unsafe fn serialize_userdata<T: Serialize + 'static>(
+    ud: *mut c_void,
+) -> Result<Ref<'static, dyn erased_serde::Serialize>> {
+    let cell = &*(ud as *const Option<RefCell<T>>);
+    let cell = cell.as_ref().ok_or(Error::UserDataTypeMismatch)?;
+    let value = cell.try_borrow().map_err(|_| Error::UserDataBorrowError)?;
+    Ok(Ref::map(value, |value| value as &dyn erased_serde::Serialize))
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::SerializeError(msg.to_string())
+    }
+}
+
+impl<'lua> Serialize for AnyUserData<'lua> {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        let value = unsafe {
+            let lua = self.0.lua;
+            stack_guard(lua.state, 0, move || {
+                check_stack(lua.state, 2);
+
+                lua.push_ref(lua.state, &self.0);
+                if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                    ffi::lua_pop(lua.state, 1);
+                    return None;
+                }
+                let metatable = ffi::lua_topointer(lua.state, -1);
+                let ud = ffi::lua_touserdata(lua.state, -2);
+                ffi::lua_pop(lua.state, 2);
+
+                lua.userdata_serializer(metatable)
+                    .map(|serialize_userdata| serialize_userdata(ud))
+            })
+        };
+
+        match value {
+            Some(Ok(value)) => erased_serde::serialize(&*value, serializer),
+            Some(Err(err)) => Err(ser::Error::custom(err)),
+            None => Err(ser::Error::custom("cannot serialize userdata")),
+        }
+    }
+}
+
+impl<'lua> Serialize for String<'lua> {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        match self.to_str() {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => serializer.serialize_bytes(self.as_bytes()),
+        }
+    }
+}
+
+// Deepest nesting of tables serialized or deserialized with serde, the same as the limit for
+// JSON. Deeper tables would overflow the stack.
+const MAX_SERDE_DEPTH: usize = 128;
+
+thread_local! {
+    // Tables which are currently being serialized or deserialized, to detect recursive tables.
+    static VISITED_TABLES: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+// Removes a table from `VISITED_TABLES` once it has been serialized or deserialized.
+pub(crate) struct VisitGuard(usize);
+
+impl VisitGuard {
+    // Fails with the reason if the table is already being visited or tables are nested too deeply.
+    pub(crate) fn enter(ptr: *const c_void) -> ::std::result::Result<VisitGuard, &'static str> {
+        let ptr = ptr as usize;
+        VISITED_TABLES.with(|visited| {
+            let mut visited = visited.borrow_mut();
+            if visited.contains(&ptr) {
+                Err("recursive table")
+            } else if visited.len() == MAX_SERDE_DEPTH {
+                Err("tables nested too deeply")
+            } else {
+                visited.insert(ptr);
+                Ok(VisitGuard(ptr))
+            }
+        })
+    }
+}
+
+impl Drop for VisitGuard {
+    fn drop(&mut self) {
+        VISITED_TABLES.with(|visited| visited.borrow_mut().remove(&self.0));
+    }
+}
+
+impl<'lua> Serialize for Table<'lua> {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        let _guard = VisitGuard::enter(self.to_pointer())
+            .map_err(|reason| ser::Error::custom(format!("cannot serialize {}", reason)))?;
+
+        let len = self.raw_len();
+        let pairs = self.clone()
+            .pairs::<Value, Value>()
+            .collect::<Result<Vec<_>>>()
+            .map_err(ser::Error::custom)?;
+
+        // Tables with only the keys `1..n` or the array metatable are serialized as sequences, any
+        // other table as a map.
+        if (len > 0 && pairs.len() as Integer == len) || self.0.lua.is_array_table(self) {
+            let mut seq = serializer.serialize_seq(Some(pairs.len()))?;
+            for v in self.clone().sequence_values::<Value>() {
+                seq.serialize_element(&v.map_err(ser::Error::custom)?)?;
+            }
+            seq.end()
+        } else {
+            let mut map = serializer.serialize_map(Some(pairs.len()))?;
+            for (k, v) in pairs {
+                map.serialize_entry(&k, &v)?;
+            }
+            map.end()
+        }
+    }
+}
+
+/// Serializes Lua values, as done by [`Lua::to_value`] in the other direction.
+///
+/// Tables with only the keys `1..n` or the [`Lua::array_metatable`] are serialized as sequences,
+/// other tables as maps, and `nil` and [`Lua::null`] as a unit. Userdata can only be serialized
+/// if it was created with [`Lua::create_ser_userdata`]. Functions, threads, other light userdata,
+/// errors, recursive tables and tables nested more than 128 levels deep cannot be serialized.
+///
+/// Requires the `serde` feature.
+///
+/// [`Lua::to_value`]: struct.Lua.html#method.to_value
+/// [`Lua::array_metatable`]: struct.Lua.html#method.array_metatable
+/// [`Lua::null`]: struct.Lua.html#method.null
+/// [`Lua::create_ser_userdata`]: struct.Lua.html#method.create_ser_userdata
+impl<'lua> Serialize for Value<'lua> {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        match *self {
+            Value::Nil => serializer.serialize_unit(),
+            Value::LightUserData(ud) if ud.0.is_null() => serializer.serialize_unit(),
+            Value::Boolean(b) => serializer.serialize_bool(b),
+            Value::Integer(i) => serializer.serialize_i64(i),
+            Value::Number(n) => serializer.serialize_f64(n),
+            Value::String(ref s) => s.serialize(serializer),
+            Value::Table(ref t) => t.serialize(serializer),
+            Value::UserData(ref ud) => ud.serialize(serializer),
+            ref value => Err(ser::Error::custom(format!(
+                "cannot serialize {}",
+                value.type_name()
+            ))),
+        }
+    }
+}
+
+/// Serializes an error as a map, for example to include it in structured logs.
+///
+/// The map contains the `kind` and `message` of the error, the fields of its variant (named as
+/// they are in Lua, see [`Error`]), the `frames` of its traceback, if any, and its `cause`, which
+/// is serialized the same way. The sources of external errors are serialized as causes with only
+/// a `message`.
+///
+/// Requires the `serde` feature.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # extern crate serde_json;
+/// # use rlua::{Lua, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+///
+/// let err = lua.exec::<()>("error('oops')", Some("script.lua")).unwrap_err();
+/// let json = serde_json::to_value(&err).unwrap();
+/// assert_eq!(json["kind"], "runtime");
+/// assert!(json["message"].as_str().unwrap().contains("oops"));
+/// assert!(json["frames"].as_array().unwrap().iter().any(|frame| frame["line"] == 1));
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Error`]: enum.Error.html
+impl Serialize for Error {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("kind", error_kind(self))?;
+        map.serialize_entry("message", &self.to_string())?;
+
+        match *self {
+            Error::SyntaxError {
+                incomplete_input, ..
+            } => map.serialize_entry("incomplete_input", &incomplete_input)?,
+            Error::RuntimeErrorValue { ref value, .. } => map.serialize_entry("value", value)?,
+            Error::ToLuaConversionError { from, to, .. }
+            | Error::FromLuaConversionError { from, to, .. } => {
+                map.serialize_entry("from", from)?;
+                map.serialize_entry("to", to)?;
+            }
+            Error::CallbackError { ref traceback, .. } => {
+                map.serialize_entry("traceback", traceback)?
+            }
+            Error::BadArgument {
+                ref function_name,
+                arg_index,
+                ..
+            } => {
+                map.serialize_entry("function_name", function_name)?;
+                map.serialize_entry("arg_index", &arg_index)?;
+            }
+            Error::WithContext { ref context, .. } => map.serialize_entry("context", context)?,
+            _ => {}
+        }
+
+        match *self {
+            // The traceback belongs to the cause, which is serialized below.
+            Error::WithContext { .. } => {}
+            _ => if let Some(frames) = self.frames() {
+                map.serialize_entry("frames", &frames)?;
+            },
+        }
+
+        match *self {
+            Error::CallbackError { ref cause, .. }
+            | Error::BadArgument { ref cause, .. }
+            | Error::WithContext { ref cause, .. } => map.serialize_entry("cause", &**cause)?,
+            Error::ExternalError(ref err) => if let Some(source) = err.source() {
+                map.serialize_entry("cause", &ErrorSource(source))?;
+            },
+            _ => {}
+        }
+
+        map.end()
+    }
+}
+
+// The source of an external error, serialized like an `Error` with only a message.
+struct ErrorSource<'a>(&'a (dyn StdError + 'static));
+
+impl<'a> Serialize for ErrorSource<'a> {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("message", &self.0.to_string())?;
+        if let Some(source) = self.0.source() {
+            map.serialize_entry("cause", &ErrorSource(source))?;
+        }
+        map.end()
+    }
+}
+
+/// Serializes a copy of an error value like the original Lua value would be serialized.
+///
+/// Strings which are not valid UTF-8 are serialized as bytes, and values which could not be copied
+/// as unit.
+impl Serialize for ErrorValue {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        match *self {
+            ErrorValue::Nil | ErrorValue::Other(_) => serializer.serialize_unit(),
+            ErrorValue::Boolean(b) => serializer.serialize_bool(b),
+            ErrorValue::Integer(i) => serializer.serialize_i64(i),
+            ErrorValue::Number(n) => serializer.serialize_f64(n),
+            ErrorValue::String(ref bytes) => match str::from_utf8(bytes) {
+                Ok(s) => serializer.serialize_str(s),
+                Err(_) => serializer.serialize_bytes(bytes),
+            },
+            ErrorValue::Table(ref entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl Serialize for Frame {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        let mut frame = serializer.serialize_struct("Frame", 3)?;
+        frame.serialize_field("source", &self.source)?;
+        frame.serialize_field("line", &self.line)?;
+        frame.serialize_field("name", &self.name)?;
+        frame.end()
+    }
+}
+
+/// Serializes the global variables as a map from their names to their values.
+///
+/// Strings which are not valid UTF-8 are serialized as bytes, and values which were not copied as
+/// unit.
+impl Serialize for GlobalsSnapshot {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        self.globals.serialize(serializer)
+    }
+}
+
+impl Serialize for SnapshotValue {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        match *self {
+            SnapshotValue::Truncated(_) | SnapshotValue::Other(..) => serializer.serialize_unit(),
+            SnapshotValue::Boolean(b) => serializer.serialize_bool(b),
+            SnapshotValue::Integer(i) => serializer.serialize_i64(i),
+            SnapshotValue::Number(n) => serializer.serialize_f64(n),
+            SnapshotValue::String(ref bytes) => match str::from_utf8(bytes) {
+                Ok(s) => serializer.serialize_str(s),
+                Err(_) => serializer.serialize_bytes(bytes),
+            },
+            SnapshotValue::Table(ref fields) => fields.serialize(serializer),
+        }
+    }
+}
+
+impl Serialize for GlobalsDiff {
+    fn serialize<S: SerdeSerializer>(
+        &self,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        let mut diff = serializer.serialize_struct("GlobalsDiff", 3)?;
+        diff.serialize_field("added", &self.added)?;
+        diff.serialize_field("removed", &self.removed)?;
+        diff.serialize_field("changed", &self.changed)?;
+        diff.end()
+    }
+}
+
+/// Options for converting Rust values to Lua values with [`Lua::to_value_with`].
+///
+/// Requires the `serde` feature.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, Result, SerializeOptions};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let options = SerializeOptions {
+///     serialize_none_as_null: true,
+///     ..SerializeOptions::default()
+/// };
+///
+/// let value = lua.to_value_with(&vec![Some(1), None, Some(3)], options)?;
+/// lua.globals().set("list", value)?;
+/// assert_eq!(lua.eval::<i64>("#list", None)?, 3);
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Lua::to_value_with`]: struct.Lua.html#method.to_value_with
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SerializeOptions {
+    /// Whether `None` is converted to [`Lua::null`] rather than `nil`.
+    ///
+    /// This keeps `None` elements from leaving holes in sequences, and `None` fields from being
+    /// absent from tables. Defaults to `false`.
+    ///
+    /// [`Lua::null`]: struct.Lua.html#method.null
+    pub serialize_none_as_null: bool,
+    /// Whether units and unit structs are converted to [`Lua::null`] rather than `nil`.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [`Lua::null`]: struct.Lua.html#method.null
+    pub serialize_unit_as_null: bool,
+    /// Whether sequences and tuples are given the [`Lua::array_metatable`], so that they are
+    /// converted back to sequences even when they are empty.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [`Lua::array_metatable`]: struct.Lua.html#method.array_metatable
+    pub set_array_metatable: bool,
+    /// Whether `serde_json` numbers serialized with its `arbitrary_precision` feature are
+    /// converted to Lua numbers, rather than to tables holding the number as a string.
+    ///
+    /// Defaults to `true`.
+    pub detect_serde_json_arbitrary_precision: bool,
+}
+
+impl Default for SerializeOptions {
+    fn default() -> SerializeOptions {
+        SerializeOptions {
+            serialize_none_as_null: false,
+            serialize_unit_as_null: false,
+            set_array_metatable: false,
+            detect_serde_json_arbitrary_precision: true,
+        }
+    }
+}
+
+// The struct name and field `serde_json` uses for numbers with its `arbitrary_precision` feature.
+const SERDE_JSON_NUMBER_TOKEN: &str = "$serde_json::private::Number";
+
+// Serializes Rust values into Lua values.
+#[derive(Copy, Clone)]
+pub(crate) struct Serializer<'lua> {
+    pub(crate) lua: &'lua Lua,
+    pub(crate) options: SerializeOptions,
+}
+
+impl<'lua> Serializer<'lua> {
+    // Creates a table holding a single entry, used for enum variants.
+    fn variant_table(&self, variant: &'static str, value: Value<'lua>) -> Result<Value<'lua>> {
+        let table = self.lua.create_table();
+        table.raw_set(variant, value)?;
+        Ok(Value::Table(table))
+    }
+}
+
+impl<'lua> SerdeSerializer for Serializer<'lua> {
+    type Ok = Value<'lua>;
+    type Error = Error;
+
+    type SerializeSeq = SerializeSequence<'lua>;
+    type SerializeTuple = SerializeSequence<'lua>;
+    type SerializeTupleStruct = SerializeSequence<'lua>;
+    type SerializeTupleVariant = SerializeVariant<'lua, SerializeSequence<'lua>>;
+    type SerializeMap = SerializeTable<'lua>;
+    type SerializeStruct = SerializeTable<'lua>;
+    type SerializeStructVariant = SerializeVariant<'lua, SerializeTable<'lua>>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value<'lua>> {
+        Ok(Value::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value<'lua>> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value<'lua>> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value<'lua>> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value<'lua>> {
+        Ok(Value::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value<'lua>> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value<'lua>> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value<'lua>> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value<'lua>> {
+        if v <= Integer::MAX as u64 {
+            Ok(Value::Integer(v as Integer))
+        } else {
+            Ok(Value::Number(v as f64))
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value<'lua>> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value<'lua>> {
+        Ok(Value::Number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value<'lua>> {
+        let mut buf = [0; 4];
+        self.serialize_str(v.encode_utf8(&mut buf))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value<'lua>> {
+        Ok(Value::String(self.lua.create_string(v)))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value<'lua>> {
+        Ok(Value::String(self.lua.create_byte_string(v)))
+    }
+
+    fn serialize_none(self) -> Result<Value<'lua>> {
+        if self.options.serialize_none_as_null {
+            Ok(self.lua.null())
+        } else {
+            Ok(Value::Nil)
+        }
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Value<'lua>> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value<'lua>> {
+        if self.options.serialize_unit_as_null {
+            Ok(self.lua.null())
+        } else {
+            Ok(Value::Nil)
+        }
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value<'lua>> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value<'lua>> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value<'lua>> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value<'lua>> {
+        let value = value.serialize(self)?;
+        self.variant_table(variant, value)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SerializeSequence<'lua>> {
+        let table = self.lua.create_table();
+        if self.options.set_array_metatable {
+            table.set_metatable(Some(self.lua.array_metatable()));
+        }
+        Ok(SerializeSequence {
+            serializer: self,
+            table,
+            len: 0,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeSequence<'lua>> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeSequence<'lua>> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<'lua, SerializeSequence<'lua>>> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+            serializer: self,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeTable<'lua>> {
+        Ok(SerializeTable {
+            serializer: self,
+            table: self.lua.create_table(),
+            key: None,
+            number: None,
+        })
+    }
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<SerializeTable<'lua>> {
+        let mut table = self.serialize_map(Some(len))?;
+        if self.options.detect_serde_json_arbitrary_precision && name == SERDE_JSON_NUMBER_TOKEN {
+            table.number = Some(Value::Nil);
+        }
+        Ok(table)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<'lua, SerializeTable<'lua>>> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+            serializer: self,
+        })
+    }
+}
+
+pub(crate) struct SerializeSequence<'lua> {
+    serializer: Serializer<'lua>,
+    table: Table<'lua>,
+    len: Integer,
+}
+
+impl<'lua> SerializeSeq for SerializeSequence<'lua> {
+    type Ok = Value<'lua>;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        let value = value.serialize(self.serializer)?;
+        self.len += 1;
+        self.table.raw_set(self.len, value)
+    }
+
+    fn end(self) -> Result<Value<'lua>> {
+        Ok(Value::Table(self.table))
+    }
+}
+
+impl<'lua> SerializeTuple for SerializeSequence<'lua> {
+    type Ok = Value<'lua>;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value<'lua>> {
+        SerializeSeq::end(self)
+    }
+}
+
+impl<'lua> ser::SerializeTupleStruct for SerializeSequence<'lua> {
+    type Ok = Value<'lua>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value<'lua>> {
+        SerializeSeq::end(self)
+    }
+}
+
+pub(crate) struct SerializeTable<'lua> {
+    serializer: Serializer<'lua>,
+    table: Table<'lua>,
+    key: Option<Value<'lua>>,
+    // Set when serializing a `serde_json` arbitrary precision number, holds the parsed number.
+    number: Option<Value<'lua>>,
+}
+
+impl<'lua> SerializeMap for SerializeTable<'lua> {
+    type Ok = Value<'lua>;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(key.serialize(self.serializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        let key = self.key
+            .take()
+            .ok_or_else(|| Error::SerializeError("map value without a key".to_owned()))?;
+        let value = value.serialize(self.serializer)?;
+        self.table.raw_set(key, value)
+    }
+
+    fn end(self) -> Result<Value<'lua>> {
+        Ok(Value::Table(self.table))
+    }
+}
+
+impl<'lua> ser::SerializeStruct for SerializeTable<'lua> {
+    type Ok = Value<'lua>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        if self.number.is_some() && key == SERDE_JSON_NUMBER_TOKEN {
+            let number = match value.serialize(self.serializer)? {
+                Value::String(s) => parse_number(s.to_str()?),
+                _ => None,
+            };
+            self.number = Some(number.ok_or_else(|| {
+                Error::SerializeError("invalid serde_json arbitrary precision number".to_owned())
+            })?);
+            Ok(())
+        } else {
+            SerializeMap::serialize_entry(self, key, value)
+        }
+    }
+
+    fn end(self) -> Result<Value<'lua>> {
+        match self.number {
+            Some(Value::Nil) | None => SerializeMap::end(self),
+            Some(number) => Ok(number),
+        }
+    }
+}
+
+// Serializes the contents of an enum variant with `inner`, and wraps the result in a table with
+// the variant name as the only key.
+pub(crate) struct SerializeVariant<'lua, S> {
+    serializer: Serializer<'lua>,
+    variant: &'static str,
+    inner: S,
+}
+
+impl<'lua> ser::SerializeTupleVariant for SerializeVariant<'lua, SerializeSequence<'lua>> {
+    type Ok = Value<'lua>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        SerializeSeq::serialize_element(&mut self.inner, value)
+    }
+
+    fn end(self) -> Result<Value<'lua>> {
+        let value = SerializeSeq::end(self.inner)?;
+        self.serializer.variant_table(self.variant, value)
+    }
+}
+
+impl<'lua> ser::SerializeStructVariant for SerializeVariant<'lua, SerializeTable<'lua>> {
+    type Ok = Value<'lua>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        SerializeMap::serialize_entry(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Value<'lua>> {
+        let value = SerializeMap::end(self.inner)?;
+        self.serializer.variant_table(self.variant, value)
+    }
+}
+
+// Parses a number as Lua would, as an integer if possible and otherwise as a float.
+fn parse_number<'lua>(s: &str) -> Option<Value<'lua>> {
+    s.parse::<Integer>()
+        .map(Value::Integer)
+        .or_else(|_| s.parse::<f64>().map(Value::Number))
+        .ok()
+}
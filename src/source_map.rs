@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::string::String as StdString;
+
+use error::Error;
+
+/// Maps the lines of a generated chunk of Lua code to the files and lines it was generated from.
+///
+/// Attach a map to a chunk with [`Lua::load_with_source_map`] to have error messages and
+/// tracebacks refer to the original source instead of the generated code.
+///
+/// Each mapped line starts a region which extends until the next mapped line: the lines following
+/// a mapped line are mapped to the lines following the original line. Lines before the first
+/// mapped line are not mapped.
+///
+/// [`Lua::load_with_source_map`]: struct.Lua.html#method.load_with_source_map
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SourceMap {
+    lines: BTreeMap<usize, (StdString, usize)>,
+}
+
+impl SourceMap {
+    /// Creates an empty source map.
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Maps line `generated_line` of the generated chunk to line `line` of `source`.
+    ///
+    /// Lines are numbered from 1, like in Lua messages.
+    pub fn insert(&mut self, generated_line: usize, source: &str, line: usize) {
+        self.lines.insert(generated_line, (source.to_owned(), line));
+    }
+
+    /// Returns the source and line which line `generated_line` of the generated chunk was
+    /// generated from, or `None` if it is not mapped.
+    pub fn get(&self, generated_line: usize) -> Option<(&str, usize)> {
+        self.lines
+            .range(..=generated_line)
+            .next_back()
+            .map(|(&start, &(ref source, line))| (source.as_str(), line + generated_line - start))
+    }
+
+    // Rewrites the locations `chunk:line` in `text`, where `chunk` is the chunk name as it appears
+    // in Lua messages, to the locations they were generated from.
+    fn rewrite(&self, chunk: &str, text: &str) -> Option<StdString> {
+        let mut rewritten = StdString::new();
+        let mut rest = text;
+        let mut changed = false;
+        while let Some(i) = rest.find(chunk) {
+            let after = &rest[i + chunk.len()..];
+            let digits = match after.strip_prefix(':') {
+                Some(line) => line.bytes().take_while(u8::is_ascii_digit).count(),
+                None => 0,
+            };
+            let location = if digits > 0 {
+                after[1..1 + digits].parse().ok().and_then(|line| self.get(line))
+            } else {
+                None
+            };
+            match location {
+                Some((source, line)) => {
+                    rewritten.push_str(&rest[..i]);
+                    rewritten.push_str(&format!("{}:{}", source, line));
+                    rest = &after[1 + digits..];
+                    changed = true;
+                }
+                _ => {
+                    rewritten.push_str(&rest[..i + chunk.len()]);
+                    rest = after;
+                }
+            }
+        }
+        if changed {
+            rewritten.push_str(rest);
+            Some(rewritten)
+        } else {
+            None
+        }
+    }
+}
+
+// Rewrites the locations in the message and traceback of `err` with the source maps of the
+// chunks, keyed by their names as they appear in Lua messages.
+pub(crate) fn map_error(maps: &[(StdString, SourceMap)], err: Error) -> Error {
+    let rewrite = |text: StdString| {
+        maps.iter()
+            .fold(text, |text, map| map.1.rewrite(&map.0, &text).unwrap_or(text))
+    };
+    match err {
+        Error::RuntimeError(message) => Error::RuntimeError(rewrite(message)),
+        Error::RuntimeErrorValue { message, value } => Error::RuntimeErrorValue {
+            message: rewrite(message),
+            value,
+        },
+        Error::SyntaxError {
+            message,
+            incomplete_input,
+        } => Error::SyntaxError {
+            message: rewrite(message),
+            incomplete_input,
+        },
+        Error::CallbackError { traceback, cause } => Error::CallbackError {
+            traceback: rewrite(traceback),
+            cause,
+        },
+        err => err,
+    }
+}
@@ -1,9 +1,11 @@
-use std::{slice, str};
+use std::{fmt, slice, str};
+use std::ops::Deref;
 
 use ffi;
 use error::{Error, Result};
 use util::{check_stack, stack_guard};
 use types::LuaRef;
+use lua::{FromLua, Lua, ToLua, Value};
 
 /// Handle to an internal Lua string.
 ///
@@ -109,6 +111,148 @@ where
     }
 }
 
+/// A string argument borrowed from Lua for as long as the value is alive.
+///
+/// Converting a Lua string to a Rust `String` copies it, which is wasteful for callbacks that only
+/// need to look at their string arguments. `BorrowedStr` keeps a reference to the Lua string
+/// instead, and dereferences to a `&str` pointing directly into the string's memory. The string is
+/// checked to be valid UTF-8 once, when it is converted. Numbers are coerced to strings, like they
+/// are for `String`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{BorrowedStr, Lua, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+///
+/// let count_words = lua.create_function(|_, line: BorrowedStr| {
+///     Ok(line.split_whitespace().count())
+/// });
+/// lua.globals().set("count_words", count_words)?;
+/// assert_eq!(lua.eval::<usize>(r#"count_words("a b  c")"#, None)?, 3);
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct BorrowedStr<'lua> {
+    string: String<'lua>,
+    data: *const str,
+}
+
+impl<'lua> BorrowedStr<'lua> {
+    /// Returns the Lua string this borrows from.
+    pub fn as_lua_string(&self) -> &String<'lua> {
+        &self.string
+    }
+}
+
+impl<'lua> Deref for BorrowedStr<'lua> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        // Lua strings are immutable and never moved, so the data stays valid for as long as
+        // `self.string` holds a reference to the string.
+        unsafe { &*self.data }
+    }
+}
+
+impl<'lua> AsRef<str> for BorrowedStr<'lua> {
+    fn as_ref(&self) -> &str {
+        self
+    }
+}
+
+impl<'lua> fmt::Debug for BorrowedStr<'lua> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'lua> fmt::Display for BorrowedStr<'lua> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'lua> ToLua<'lua> for BorrowedStr<'lua> {
+    fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::String(self.string))
+    }
+}
+
+impl<'lua> FromLua<'lua> for BorrowedStr<'lua> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        let string = String::from_lua(value, lua)?;
+        let data = string.to_str()? as *const str;
+        Ok(BorrowedStr {
+            string,
+            data,
+        })
+    }
+}
+
+/// A byte string argument borrowed from Lua for as long as the value is alive.
+///
+/// This is like [`BorrowedStr`], but dereferences to `&[u8]` and accepts strings which are not
+/// valid UTF-8.
+///
+/// [`BorrowedStr`]: struct.BorrowedStr.html
+#[derive(Clone)]
+pub struct BorrowedBytes<'lua> {
+    string: String<'lua>,
+    data: *const [u8],
+}
+
+impl<'lua> BorrowedBytes<'lua> {
+    /// Returns the Lua string this borrows from.
+    pub fn as_lua_string(&self) -> &String<'lua> {
+        &self.string
+    }
+}
+
+impl<'lua> Deref for BorrowedBytes<'lua> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // See `BorrowedStr::deref`.
+        unsafe { &*self.data }
+    }
+}
+
+impl<'lua> AsRef<[u8]> for BorrowedBytes<'lua> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<'lua> fmt::Debug for BorrowedBytes<'lua> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'lua> ToLua<'lua> for BorrowedBytes<'lua> {
+    fn to_lua(self, _: &'lua Lua) -> Result<Value<'lua>> {
+        Ok(Value::String(self.string))
+    }
+}
+
+impl<'lua> FromLua<'lua> for BorrowedBytes<'lua> {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> Result<Self> {
+        let string = String::from_lua(value, lua)?;
+        let data = string.as_bytes() as *const [u8];
+        Ok(BorrowedBytes {
+            string,
+            data,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -170,6 +314,27 @@ mod tests {
 
         assert_eq!(empty.to_str().unwrap(), "");
         assert_eq!(empty.as_bytes_with_nul(), &[0]);
-        assert_eq!(empty.as_bytes(), &[]);
+        assert_eq!(empty.as_bytes(), &[] as &[u8]);
+    }
+
+    #[test]
+    fn borrowed_strings() {
+        let lua = Lua::new();
+        let globals = lua.globals();
+
+        let upper = lua.create_function(|_, s: BorrowedStr| Ok(s.to_uppercase()));
+        globals.set("upper", upper).unwrap();
+        assert_eq!(lua.eval::<::std::string::String>("upper('abc')", None).unwrap(), "ABC");
+        assert_eq!(lua.eval::<::std::string::String>("upper(12)", None).unwrap(), "12");
+        assert!(lua.eval::<()>("upper('\\xff')", None).is_err());
+        assert!(lua.eval::<()>("upper({})", None).is_err());
+
+        let len = lua.create_function(|_, b: BorrowedBytes| Ok(b.len()));
+        globals.set("len", len).unwrap();
+        assert_eq!(lua.eval::<usize>("len('a\\xff\\0')", None).unwrap(), 3);
+
+        let same = lua.create_function(|_, s: BorrowedStr| Ok(s));
+        globals.set("same", same).unwrap();
+        assert!(lua.eval::<bool>("local s = 'abc' return same(s) == s", None).unwrap());
     }
 }
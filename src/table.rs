@@ -1,10 +1,17 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::{c_int, c_void};
 
 use ffi;
-use error::Result;
+use error::{Error, Result};
 use util::*;
-use types::{Integer, LuaRef};
-use lua::{FromLua, ToLua};
+use types::{Integer, LuaRef, RefIndex, WeakLuaRef};
+use lua::{FromLua, FromLuaMulti, Function, Nil, ToLua, ToLuaMulti, Value};
+
+// The largest number of values `TableSequence` reads with a single call into Lua.
+const MAX_SEQUENCE_BATCH_SIZE: usize = 256;
 
 /// Handle to an internal Lua table.
 #[derive(Clone, Debug)]
@@ -51,15 +58,13 @@ impl<'lua> Table<'lua> {
     pub fn set<K: ToLua<'lua>, V: ToLua<'lua>>(&self, key: K, value: V) -> Result<()> {
         let lua = self.0.lua;
         unsafe {
-            stack_err_guard(lua.state, 0, || {
-                check_stack(lua.state, 7);
-                lua.push_ref(lua.state, &self.0);
-                lua.push_value(lua.state, key.to_lua(lua)?);
-                lua.push_value(lua.state, value.to_lua(lua)?);
-                psettable(lua.state, -3)?;
-                ffi::lua_pop(lua.state, 1);
-                Ok(())
-            })
+            let _sg = StackGuard::with_reserved(lua.state, 7);
+            lua.push_ref(lua.state, &self.0);
+            lua.push_value(lua.state, key.to_lua(lua)?);
+            lua.push_value(lua.state, value.to_lua(lua)?);
+            psettable(lua.state, -3)?;
+            ffi::lua_pop(lua.state, 1);
+            Ok(())
         }
     }
 
@@ -93,32 +98,132 @@ impl<'lua> Table<'lua> {
     /// [`raw_get`]: #method.raw_get
     pub fn get<K: ToLua<'lua>, V: FromLua<'lua>>(&self, key: K) -> Result<V> {
         let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::with_reserved(lua.state, 5);
+            lua.push_ref(lua.state, &self.0);
+            lua.push_value(lua.state, key.to_lua(lua)?);
+            pgettable(lua.state, -2)?;
+            let res = lua.pop_value(lua.state);
+            ffi::lua_pop(lua.state, 1);
+            V::from_lua(res, lua)
+        }
+    }
+
+    /// Gets the values associated to several keys from the table at once.
+    ///
+    /// This is equivalent to calling [`get`] for each of the `keys`, but looks up all of them with
+    /// a single call into Lua, which is considerably faster when reading many fields. Like
+    /// [`get`], this might invoke the `__index` metamethod.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config = lua.eval::<Table>(r#"{ name = "server", port = 8080 }"#, None)?;
+    ///
+    /// let (name, port, debug) = config
+    ///     .get_multi::<_, (String, u16, Option<bool>)>(("name", "port", "debug"))?;
+    /// assert_eq!((name.as_str(), port, debug), ("server", 8080, None));
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`get`]: #method.get
+    pub fn get_multi<K: ToLuaMulti<'lua>, V: FromLuaMulti<'lua>>(&self, keys: K) -> Result<V> {
+        let lua = self.0.lua;
+        let mut values = keys.to_lua_multi(lua)?;
+        let nkeys = values.len() as c_int;
         unsafe {
             stack_err_guard(lua.state, 0, || {
-                check_stack(lua.state, 5);
+                check_stack(lua.state, nkeys + 4);
                 lua.push_ref(lua.state, &self.0);
-                lua.push_value(lua.state, key.to_lua(lua)?);
-                pgettable(lua.state, -2)?;
-                let res = lua.pop_value(lua.state);
+                for key in values.drain(..) {
+                    lua.push_value(lua.state, key);
+                }
+                pgettable_multi(lua.state, -(nkeys + 1), nkeys)?;
+                for _ in 0..nkeys {
+                    values.push_front(lua.pop_value(lua.state));
+                }
                 ffi::lua_pop(lua.state, 1);
-                V::from_lua(res, lua)
-            })
+                Ok(())
+            })?;
         }
+        V::from_lua_multi(values, lua)
     }
 
-    /// Checks whether the table contains a non-nil value for `key`.
-    pub fn contains_key<K: ToLua<'lua>>(&self, key: K) -> Result<bool> {
+    /// Sets several key-value pairs in the table at once.
+    ///
+    /// This is equivalent to calling [`set`] for each of the `keys` and the value at the same
+    /// position in `values`, but sets all of them with a single call into Lua. Like Lua's multiple
+    /// assignment, keys without a corresponding value are set to `nil`, and extra values are
+    /// ignored. Like [`set`], this might invoke the `__newindex` metamethod.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let config = lua.create_table();
+    ///
+    /// config.set_multi(("name", "port"), ("server", 8080))?;
+    /// assert_eq!(config.get::<_, u16>("port")?, 8080);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`set`]: #method.set
+    pub fn set_multi<K, V>(&self, keys: K, values: V) -> Result<()>
+    where
+        K: ToLuaMulti<'lua>,
+        V: ToLuaMulti<'lua>,
+    {
         let lua = self.0.lua;
+        let mut keys = keys.to_lua_multi(lua)?;
+        let mut values = values.to_lua_multi(lua)?;
+        let nkeys = keys.len() as c_int;
         unsafe {
             stack_err_guard(lua.state, 0, || {
-                check_stack(lua.state, 5);
+                check_stack(lua.state, 2 * nkeys + 5);
                 lua.push_ref(lua.state, &self.0);
-                lua.push_value(lua.state, key.to_lua(lua)?);
-                pgettable(lua.state, -2)?;
-                let has = ffi::lua_isnil(lua.state, -1) == 0;
-                ffi::lua_pop(lua.state, 2);
-                Ok(has)
-            })
+                for key in keys.drain(..) {
+                    lua.push_value(lua.state, key);
+                }
+                let mut values = values.drain(..);
+                for _ in 0..nkeys {
+                    lua.push_value(lua.state, values.next().unwrap_or(Nil));
+                }
+                psettable_multi(lua.state, -(2 * nkeys + 1), nkeys)?;
+                ffi::lua_pop(lua.state, 1);
+                Ok(())
+            })?;
+        }
+        lua.cache_multi_value(keys);
+        lua.cache_multi_value(values);
+        Ok(())
+    }
+
+    /// Checks whether the table contains a non-nil value for `key`.
+    pub fn contains_key<K: ToLua<'lua>>(&self, key: K) -> Result<bool> {
+        let lua = self.0.lua;
+        unsafe {
+            let _sg = StackGuard::with_reserved(lua.state, 5);
+            lua.push_ref(lua.state, &self.0);
+            lua.push_value(lua.state, key.to_lua(lua)?);
+            pgettable(lua.state, -2)?;
+            let has = ffi::lua_isnil(lua.state, -1) == 0;
+            ffi::lua_pop(lua.state, 2);
+            Ok(has)
         }
     }
 
@@ -126,15 +231,13 @@ impl<'lua> Table<'lua> {
     pub fn raw_set<K: ToLua<'lua>, V: ToLua<'lua>>(&self, key: K, value: V) -> Result<()> {
         let lua = self.0.lua;
         unsafe {
-            stack_err_guard(lua.state, 0, || {
-                check_stack(lua.state, 3);
-                lua.push_ref(lua.state, &self.0);
-                lua.push_value(lua.state, key.to_lua(lua)?);
-                lua.push_value(lua.state, value.to_lua(lua)?);
-                ffi::lua_rawset(lua.state, -3);
-                ffi::lua_pop(lua.state, 1);
-                Ok(())
-            })
+            let _sg = StackGuard::with_reserved(lua.state, 3);
+            lua.push_ref(lua.state, &self.0);
+            lua.push_value(lua.state, key.to_lua(lua)?);
+            lua.push_value(lua.state, value.to_lua(lua)?);
+            ffi::lua_rawset(lua.state, -3);
+            ffi::lua_pop(lua.state, 1);
+            Ok(())
         }
     }
 
@@ -142,15 +245,13 @@ impl<'lua> Table<'lua> {
     pub fn raw_get<K: ToLua<'lua>, V: FromLua<'lua>>(&self, key: K) -> Result<V> {
         let lua = self.0.lua;
         unsafe {
-            stack_err_guard(lua.state, 0, || {
-                check_stack(lua.state, 2);
-                lua.push_ref(lua.state, &self.0);
-                lua.push_value(lua.state, key.to_lua(lua)?);
-                ffi::lua_rawget(lua.state, -2);
-                let res = V::from_lua(lua.pop_value(lua.state), lua)?;
-                ffi::lua_pop(lua.state, 1);
-                Ok(res)
-            })
+            let _sg = StackGuard::with_reserved(lua.state, 2);
+            lua.push_ref(lua.state, &self.0);
+            lua.push_value(lua.state, key.to_lua(lua)?);
+            ffi::lua_rawget(lua.state, -2);
+            let res = V::from_lua(lua.pop_value(lua.state), lua)?;
+            ffi::lua_pop(lua.state, 1);
+            Ok(res)
         }
     }
 
@@ -159,16 +260,15 @@ impl<'lua> Table<'lua> {
     /// This might invoke the `__len` metamethod. Use the [`raw_len`] method if that is not desired.
     ///
     /// [`raw_len`]: #method.raw_len
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> Result<Integer> {
         let lua = self.0.lua;
         unsafe {
-            stack_err_guard(lua.state, 0, || {
-                check_stack(lua.state, 3);
-                lua.push_ref(lua.state, &self.0);
-                let len = plen(lua.state, -1)?;
-                ffi::lua_pop(lua.state, 1);
-                Ok(len)
-            })
+            let _sg = StackGuard::with_reserved(lua.state, 3);
+            lua.push_ref(lua.state, &self.0);
+            let len = plen(lua.state, -1)?;
+            ffi::lua_pop(lua.state, 1);
+            Ok(len)
         }
     }
 
@@ -186,6 +286,88 @@ impl<'lua> Table<'lua> {
         }
     }
 
+    /// Reads the sequence part of the table into a vector of floats.
+    ///
+    /// This reads the values `t[1]` to `t[n]`, where `n` is the result of [`raw_len`], without
+    /// invoking metamethods. The values are copied directly instead of being converted from a
+    /// [`Value`] one by one, which is much faster than collecting [`sequence_values`] for large
+    /// arrays. Integers are converted to floats, and any value which is not a number results in a
+    /// conversion error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let samples: Table = lua.eval("{0.5, -0.25, 1}", None)?;
+    /// assert_eq!(samples.as_f64_vec()?, vec![0.5, -0.25, 1.0]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`raw_len`]: #method.raw_len
+    /// [`sequence_values`]: #method.sequence_values
+    /// [`Value`]: enum.Value.html
+    pub fn as_f64_vec(&self) -> Result<Vec<f64>> {
+        self.read_numbers("f64", read_f64)
+    }
+
+    /// Reads the sequence part of the table into a vector of 32-bit integers.
+    ///
+    /// This works like [`as_f64_vec`]. Floats without a fractional part are accepted, and values
+    /// which are not numbers or are out of range of `i32` result in a conversion error.
+    ///
+    /// [`as_f64_vec`]: #method.as_f64_vec
+    pub fn as_i32_vec(&self) -> Result<Vec<i32>> {
+        self.read_numbers("i32", read_i32)
+    }
+
+    /// Sets `t[1]` to `t[n]` to the floats in `values`, where `n` is the length of the slice.
+    ///
+    /// Like [`as_f64_vec`], this does not invoke metamethods and avoids the per-element overhead
+    /// of [`raw_set`]. Values stored after index `n` are left untouched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let vertices = lua.create_table();
+    /// vertices.set_from_f64_slice(&[0.0, 1.0, 0.5, 2.0]);
+    ///
+    /// lua.globals().set("vertices", vertices)?;
+    /// assert_eq!(lua.eval::<f64>("vertices[2] + vertices[4]", None)?, 3.0);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`as_f64_vec`]: #method.as_f64_vec
+    /// [`raw_set`]: #method.raw_set
+    pub fn set_from_f64_slice(&self, values: &[f64]) {
+        self.write_numbers(values, |state, n| unsafe { ffi::lua_pushnumber(state, n) })
+    }
+
+    /// Sets `t[1]` to `t[n]` to the integers in `values`, where `n` is the length of the slice.
+    ///
+    /// This works like [`set_from_f64_slice`], storing the values as Lua integers.
+    ///
+    /// [`set_from_f64_slice`]: #method.set_from_f64_slice
+    pub fn set_from_i32_slice(&self, values: &[i32]) {
+        self.write_numbers(values, |state, i| unsafe {
+            ffi::lua_pushinteger(state, i as Integer)
+        })
+    }
+
     /// Returns a reference to the metatable of this table, or `None` if no metatable is set.
     ///
     /// Unlike the `getmetatable` Lua function, this method ignores the `__metatable` field.
@@ -268,7 +450,7 @@ impl<'lua> Table<'lua> {
     pub fn pairs<K: FromLua<'lua>, V: FromLua<'lua>>(self) -> TablePairs<'lua, K, V> {
         let next_key = Some(LuaRef {
             lua: self.0.lua,
-            registry_id: ffi::LUA_REFNIL,
+            index: RefIndex::Nil,
         });
 
         TablePairs {
@@ -317,12 +499,194 @@ impl<'lua> Table<'lua> {
     /// [`Result`]: type.Result.html
     /// [Lua manual]: http://www.lua.org/manual/5.3/manual.html#pdf-next
     pub fn sequence_values<V: FromLua<'lua>>(self) -> TableSequence<'lua, V> {
+        self.sequence_values_batched(1)
+    }
+
+    /// Consume this table and return an iterator over the values in its sequence part, which are
+    /// fetched `batch_size` at a time.
+    ///
+    /// This works like [`sequence_values`], but reads up to `batch_size` values with a single call
+    /// into Lua, and converts them as the iterator advances. This is considerably faster for
+    /// large sequences, and is used when converting tables to `Vec` and `VecDeque`. The batch size
+    /// is clamped to the range from 1 to 256.
+    ///
+    /// As values are read ahead of the iteration, changes made to the table during the iteration
+    /// may not be observed by the iterator. If an `__index` metamethod raises an error, the error
+    /// is returned in place of all remaining values of the batch it occurred in.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let squares: Table = lua.eval(r#"
+    ///     local t = {}
+    ///     for i = 1, 1000 do t[i] = i * i end
+    ///     return t
+    /// "#, None)?;
+    ///
+    /// let sum = squares
+    ///     .sequence_values_batched::<i64>(64)
+    ///     .collect::<Result<Vec<_>>>()?
+    ///     .iter()
+    ///     .sum::<i64>();
+    /// assert_eq!(sum, 333_833_500);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`sequence_values`]: #method.sequence_values
+    pub fn sequence_values_batched<V: FromLua<'lua>>(
+        self,
+        batch_size: usize,
+    ) -> TableSequence<'lua, V> {
         TableSequence {
             table: self.0,
             index: Some(1),
+            batch_size: batch_size.clamp(1, MAX_SEQUENCE_BATCH_SIZE) as c_int,
+            buffer: VecDeque::new(),
             _phantom: PhantomData,
         }
     }
+
+    /// Consume this table and return an iterator over its sequence part which follows the
+    /// semantics of Lua's `ipairs` function, yielding each index along with its value.
+    ///
+    /// Unlike [`sequence_values`], this also honors the `__ipairs` metamethod, which this build
+    /// of Lua still supports: if the metatable of the table has one, it is called with the table
+    /// and the iteration uses the function, state and initial index it returns, until the
+    /// function returns `nil`. Otherwise, the values `t[1]`, `t[2]`, and so on are read until a
+    /// `nil` value is encountered, invoking the `__index` metamethod according to the usual
+    /// rules. This gives the same results as a `for i, v in ipairs(t)` loop, whether the table is
+    /// a plain table or a proxy whose elements are provided by metamethods.
+    ///
+    /// An error raised by a metamethod is returned by the iterator, which then ends.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, Result, Table};
+    /// # fn try_main() -> Result<()> {
+    /// let lua = Lua::new();
+    /// let evens: Table = lua.eval(r#"
+    ///     setmetatable({}, { __ipairs = function(t)
+    ///         return function(_, i)
+    ///             if i < 3 then return i + 1, (i + 1) * 2 end
+    ///         end, t, 0
+    ///     end })
+    /// "#, None)?;
+    ///
+    /// let pairs = evens.ipairs::<i64>().collect::<Result<Vec<_>>>()?;
+    /// assert_eq!(pairs, vec![(1, 2), (2, 4), (3, 6)]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`sequence_values`]: #method.sequence_values
+    pub fn ipairs<V: FromLua<'lua>>(self) -> TableIPairs<'lua, V> {
+        TableIPairs {
+            state: IPairsState::Start(self),
+        }
+    }
+
+    /// Returns a pointer identifying the table.
+    ///
+    /// The pointer is the same for every handle to the same Lua table, so it can be used as a
+    /// key to recognize the table without holding a reference to it. It cannot be dereferenced,
+    /// and may be reused for a different value once the table has been garbage collected.
+    pub fn to_pointer(&self) -> *const c_void {
+        self.0.to_pointer()
+    }
+
+    /// Creates a weak reference to the table, which does not keep it from being garbage collected.
+    pub fn downgrade(&self) -> WeakLuaRef<'lua, Table<'lua>> {
+        WeakLuaRef::new(&self.0)
+    }
+
+    // Reads the values `t[1]` to `t[#t]` without invoking metamethods, converting each of them
+    // with `read` while it is on top of the stack.
+    fn read_numbers<T>(
+        &self,
+        to: &'static str,
+        read: unsafe fn(*mut ffi::lua_State) -> Option<T>,
+    ) -> Result<Vec<T>> {
+        let lua = self.0.lua;
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                check_stack(lua.state, 2);
+                lua.push_ref(lua.state, &self.0);
+                let len = ffi::lua_rawlen(lua.state, -1);
+                let mut values = Vec::with_capacity(len);
+                for i in 1..len + 1 {
+                    ffi::lua_rawgeti(lua.state, -1, i as Integer);
+                    match read(lua.state) {
+                        Some(value) => values.push(value),
+                        None => {
+                            return Err(Error::FromLuaConversionError {
+                                from: lua.pop_value(lua.state).type_name(),
+                                to,
+                                message: Some(format!("element {} cannot be converted", i)),
+                            })
+                        }
+                    }
+                    ffi::lua_pop(lua.state, 1);
+                }
+                ffi::lua_pop(lua.state, 1);
+                Ok(values)
+            })
+        }
+    }
+
+    // Sets `t[1]` to `t[n]` to `values` without invoking metamethods, pushing each of them with
+    // `push`.
+    fn write_numbers<T: Copy>(&self, values: &[T], push: fn(*mut ffi::lua_State, T)) {
+        let lua = self.0.lua;
+        unsafe {
+            stack_guard(lua.state, 0, || {
+                check_stack(lua.state, 2);
+                lua.push_ref(lua.state, &self.0);
+                for (i, &value) in values.iter().enumerate() {
+                    push(lua.state, value);
+                    ffi::lua_rawseti(lua.state, -2, i as Integer + 1);
+                }
+                ffi::lua_pop(lua.state, 1);
+            })
+        }
+    }
+}
+
+// Reads the number on top of the stack as a float.
+unsafe fn read_f64(state: *mut ffi::lua_State) -> Option<f64> {
+    if ffi::lua_type(state, -1) == ffi::LUA_TNUMBER {
+        Some(ffi::lua_tonumber(state, -1))
+    } else {
+        None
+    }
+}
+
+// Reads the number on top of the stack as an `i32`, if it is an integral value in range.
+unsafe fn read_i32(state: *mut ffi::lua_State) -> Option<i32> {
+    if ffi::lua_isinteger(state, -1) != 0 {
+        i32::try_from(ffi::lua_tointeger(state, -1)).ok()
+    } else if ffi::lua_type(state, -1) == ffi::LUA_TNUMBER {
+        let n = ffi::lua_tonumber(state, -1);
+        if n.fract() == 0.0 && n >= i32::MIN as f64 && n <= i32::MAX as f64 {
+            Some(n as i32)
+        } else {
+            None
+        }
+    } else {
+        None
+    }
 }
 
 /// An iterator over the pairs of a Lua table.
@@ -390,9 +754,54 @@ where
 pub struct TableSequence<'lua, V> {
     table: LuaRef<'lua>,
     index: Option<Integer>,
+    batch_size: c_int,
+    buffer: VecDeque<Value<'lua>>,
     _phantom: PhantomData<V>,
 }
 
+impl<'lua, V> TableSequence<'lua, V> {
+    // Reads the next batch of values into the buffer, and ends the iteration once a nil value has
+    // been found or an error occurred.
+    fn fetch(&mut self, index: Integer) -> Result<()> {
+        let lua = self.table.lua;
+
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                check_stack(lua.state, self.batch_size + 5);
+
+                lua.push_ref(lua.state, &self.table);
+                // Without a metatable, no Lua code can run and raw accesses do not need to be
+                // protected.
+                let count = if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                    let mut count = 0;
+                    while count < self.batch_size {
+                        let i = index + count as Integer;
+                        if ffi::lua_rawgeti(lua.state, -1 - count, i) == ffi::LUA_TNIL {
+                            ffi::lua_pop(lua.state, 1);
+                            break;
+                        }
+                        count += 1;
+                    }
+                    count
+                } else {
+                    ffi::lua_pop(lua.state, 1);
+                    pgeti_batch(lua.state, -1, index, self.batch_size)?
+                };
+
+                for _ in 0..count {
+                    self.buffer.push_front(lua.pop_value(lua.state));
+                }
+                ffi::lua_pop(lua.state, 1);
+
+                if count == self.batch_size {
+                    self.index = Some(index + count as Integer);
+                }
+                Ok(())
+            })
+        }
+    }
+}
+
 impl<'lua, V> Iterator for TableSequence<'lua, V>
 where
     V: FromLua<'lua>,
@@ -400,31 +809,104 @@ where
     type Item = Result<V>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(index) = self.index.take() {
-            let lua = self.table.lua;
+        if self.buffer.is_empty() {
+            if let Some(index) = self.index.take() {
+                if let Err(err) = self.fetch(index) {
+                    return Some(Err(err));
+                }
+            }
+        }
 
-            unsafe {
-                stack_guard(lua.state, 0, || {
-                    check_stack(lua.state, 4);
+        let lua = self.table.lua;
+        self.buffer
+            .pop_front()
+            .map(|value| V::from_lua(value, lua))
+    }
+}
 
-                    lua.push_ref(lua.state, &self.table);
-                    match pgeti(lua.state, -1, index) {
-                        Ok(ffi::LUA_TNIL) => {
-                            ffi::lua_pop(lua.state, 2);
-                            None
-                        }
-                        Ok(_) => {
-                            let value = lua.pop_value(lua.state);
-                            ffi::lua_pop(lua.state, 1);
-                            self.index = Some(index + 1);
-                            Some(V::from_lua(value, lua))
-                        }
-                        Err(err) => Some(Err(err)),
+/// An iterator over the sequence part of a Lua table, following the semantics of `ipairs`.
+///
+/// This struct is created by the [`Table::ipairs`] method.
+///
+/// [`Table::ipairs`]: struct.Table.html#method.ipairs
+pub struct TableIPairs<'lua, V> {
+    state: IPairsState<'lua, V>,
+}
+
+enum IPairsState<'lua, V> {
+    // The iteration has not started, and the table has not been checked for `__ipairs` yet.
+    Start(Table<'lua>),
+    // The table has no `__ipairs` metamethod, and the values are read from its sequence part.
+    Sequence(Integer, TableSequence<'lua, V>),
+    // The iteration uses the function, state and control value returned by `__ipairs`.
+    Metamethod(Function<'lua>, Value<'lua>, Value<'lua>),
+    Done,
+}
+
+impl<'lua, V> TableIPairs<'lua, V>
+where
+    V: FromLua<'lua>,
+{
+    // Calls the `__ipairs` metamethod of the table, if it has one, and returns the state of the
+    // iteration.
+    fn start(table: Table<'lua>) -> Result<IPairsState<'lua, V>> {
+        let metamethod = match table.get_metatable() {
+            Some(metatable) => metatable.raw_get::<_, Value>("__ipairs")?,
+            None => Nil,
+        };
+        Ok(match metamethod {
+            Nil => IPairsState::Sequence(0, table.sequence_values()),
+            metamethod => {
+                let lua = table.0.lua;
+                let metamethod = Function::from_lua(metamethod, lua)?;
+                let (function, state, control) = metamethod.call(table)?;
+                IPairsState::Metamethod(function, state, control)
+            }
+        })
+    }
+}
+
+impl<'lua, V> Iterator for TableIPairs<'lua, V>
+where
+    V: FromLua<'lua>,
+{
+    type Item = Result<(Integer, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let IPairsState::Start(_) = self.state {
+            let table = match mem::replace(&mut self.state, IPairsState::Done) {
+                IPairsState::Start(table) => table,
+                _ => unreachable!(),
+            };
+            match TableIPairs::start(table) {
+                Ok(state) => self.state = state,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        match self.state {
+            IPairsState::Sequence(ref mut index, ref mut sequence) => {
+                *index += 1;
+                let index = *index;
+                sequence.next().map(|value| value.map(|value| (index, value)))
+            }
+            IPairsState::Metamethod(ref function, ref state, ref mut control) => {
+                let result = function.call::<_, (Value, Value)>((state.clone(), control.clone()));
+                let lua = function.0.lua;
+                match result {
+                    Ok((Nil, _)) => None,
+                    Ok((key, value)) => {
+                        *control = key.clone();
+                        Some(Integer::from_lua(key, lua)
+                            .and_then(|index| Ok((index, V::from_lua(value, lua)?))))
                     }
-                })
+                    Err(err) => {
+                        self.state = IPairsState::Done;
+                        Some(Err(err))
+                    }
+                }
             }
-        } else {
-            None
+            IPairsState::Start(_) | IPairsState::Done => None,
         }
     }
 }
@@ -434,6 +916,7 @@ mod tests {
     use super::Table;
     use error::Result;
     use lua::{Lua, Nil, Value};
+    use multi::Variadic;
 
     #[test]
     fn test_set_get() {
@@ -505,7 +988,7 @@ mod tests {
                 .sequence_values()
                 .collect::<Result<Vec<i64>>>()
                 .unwrap(),
-            vec![]
+            Vec::<i64>::new()
         );
 
         // sequence_values should only iterate until the first border
@@ -634,4 +1117,189 @@ mod tests {
         assert!(bad_table.raw_get::<_, i32>(1).is_ok());
         assert_eq!(bad_table.raw_len(), 1);
     }
+
+    #[test]
+    fn test_create_presized() {
+        let lua = Lua::new();
+
+        let table = lua.create_table_with_capacity(10, 10);
+        table.set(1, "one").unwrap();
+        table.set("key", "value").unwrap();
+        assert_eq!(table.raw_len(), 1);
+
+        let sequence = lua.create_sequence_from((1..1001).filter(|i| i % 2 == 0)).unwrap();
+        assert_eq!(sequence.raw_len(), 500);
+        assert_eq!(sequence.get::<_, i64>(500).unwrap(), 1000);
+
+        let map = lua
+            .create_table_from((0..100).map(|i| (format!("key{}", i), i)))
+            .unwrap();
+        assert_eq!(map.clone().pairs::<String, i64>().count(), 100);
+        assert_eq!(map.get::<_, i64>("key42").unwrap(), 42);
+    }
+
+    #[test]
+    fn test_sequence_values_batched() {
+        let lua = Lua::new();
+
+        let plain: Table = lua.eval(
+            r#"
+                local t = {}
+                for i = 1, 100 do t[i] = i end
+                t[102] = 102
+                return t
+            "#,
+            None,
+        ).unwrap();
+        for &batch_size in &[0, 1, 7, 32, 100, 1000] {
+            let values = plain
+                .clone()
+                .sequence_values_batched(batch_size)
+                .collect::<Result<Vec<i64>>>()
+                .unwrap();
+            assert_eq!(values, (1..101).collect::<Vec<_>>());
+        }
+
+        // Values provided by `__index` are read in protected mode.
+        let proxy: Table = lua.eval(
+            r#"
+                setmetatable({}, { __index = function(_, i)
+                    if i > 50 then error("too far") end
+                    return i * 2
+                end })
+            "#,
+            None,
+        ).unwrap();
+        let mut values = proxy.sequence_values_batched::<i64>(16);
+        assert_eq!(values.by_ref().take(48).collect::<Result<Vec<_>>>().unwrap().len(), 48);
+        assert!(values.next().unwrap().is_err());
+        assert!(values.next().is_none());
+
+        // Conversion errors are reported for each value.
+        let mixed: Table = lua.eval("{1, 'x', 3}", None).unwrap();
+        let results = mixed.sequence_values_batched::<i64>(8).collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert!(results[1].is_err());
+        assert_eq!(lua.eval::<Vec<i64>>("{1, 2, 3}", None).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_ipairs() {
+        let lua = Lua::new();
+
+        let plain: Table = lua.eval("{1, 2, 3, nil, 5}", None).unwrap();
+        assert_eq!(
+            plain.ipairs().collect::<Result<Vec<(i64, i64)>>>().unwrap(),
+            vec![(1, 1), (2, 2), (3, 3)]
+        );
+
+        let indexed: Table = lua.eval(
+            r#"
+                setmetatable({10}, { __index = function(_, i)
+                    if i <= 3 then return i * 10 end
+                end })
+            "#,
+            None,
+        ).unwrap();
+        assert_eq!(
+            indexed.ipairs().collect::<Result<Vec<(i64, i64)>>>().unwrap(),
+            vec![(1, 10), (2, 20), (3, 30)]
+        );
+
+        // The results match an `ipairs` loop in Lua, including with `__ipairs`.
+        lua.exec::<()>(
+            r#"
+                proxy = setmetatable({}, { __ipairs = function(t)
+                    return function(_, i)
+                        if i < 4 then return i + 1, "v" .. (i + 1) end
+                    end, t, 1
+                end })
+                visited = {}
+                for i, v in ipairs(proxy) do visited[#visited + 1] = i .. "=" .. v end
+            "#,
+            None,
+        ).unwrap();
+        let proxy: Table = lua.globals().get("proxy").unwrap();
+        let visited = proxy
+            .ipairs::<String>()
+            .map(|pair| pair.map(|(i, v)| format!("{}={}", i, v)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(visited, lua.globals().get::<_, Vec<String>>("visited").unwrap());
+        assert_eq!(visited, vec!["2=v2", "3=v3", "4=v4"]);
+
+        let failing: Table = lua.eval(
+            "setmetatable({}, { __ipairs = function() error('no iteration') end })",
+            None,
+        ).unwrap();
+        let mut pairs = failing.ipairs::<Value>();
+        assert!(pairs.next().unwrap().is_err());
+        assert!(pairs.next().is_none());
+    }
+
+    #[test]
+    fn test_numeric_arrays() {
+        let lua = Lua::new();
+
+        let table = lua.create_table();
+        let values = (0..10_000).map(|i| i as f64 * 0.5).collect::<Vec<_>>();
+        table.set_from_f64_slice(&values);
+        assert_eq!(table.raw_len(), 10_000);
+        assert_eq!(table.as_f64_vec().unwrap(), values);
+        assert!(table.as_i32_vec().is_err());
+
+        table.set_from_i32_slice(&[-1, 2, 3]);
+        assert_eq!(table.get::<_, f64>(4).unwrap(), 1.5);
+        assert!(table.as_i32_vec().unwrap_err().to_string().contains("element 4"));
+
+        let mixed: Table = lua.eval("{1, 2.0, 3.5, 'x'}", None).unwrap();
+        assert!(mixed.as_f64_vec().is_err());
+        let ints: Table = lua.eval("{1, 2.0, -3, math.maxinteger}", None).unwrap();
+        assert!(ints.as_i32_vec().is_err());
+        let ints: Table = lua.eval("{1, 2.0, -3}", None).unwrap();
+        assert_eq!(ints.as_i32_vec().unwrap(), vec![1, 2, -3]);
+        assert_eq!(ints.as_f64_vec().unwrap(), vec![1.0, 2.0, -3.0]);
+        assert_eq!(lua.create_table().as_f64_vec().unwrap(), Vec::<f64>::new());
+    }
+
+    #[test]
+    fn test_get_set_multi() {
+        let lua = Lua::new();
+        let table = lua.create_table();
+
+        table.set_multi(("a", "b", 3), (1, "two", true)).unwrap();
+        assert_eq!(
+            table.get_multi::<_, (i64, String, bool)>(("a", "b", 3)).unwrap(),
+            (1, "two".to_owned(), true)
+        );
+
+        table.set_multi(("a", "b"), 10).unwrap();
+        assert_eq!(table.get::<_, i64>("a").unwrap(), 10);
+        assert!(table.get::<_, Value>("b").unwrap().is_nil());
+        table.set_multi("c", (1, 2)).unwrap();
+        assert_eq!(table.get::<_, i64>("c").unwrap(), 1);
+        table.set_multi((), ()).unwrap();
+        assert_eq!(table.get_multi::<_, ()>(()).unwrap(), ());
+
+        let keys = (0..100).map(|i| i.to_string()).collect::<Variadic<_>>();
+        table.set_multi(keys.clone(), (0..100).collect::<Variadic<_>>()).unwrap();
+        let values = table.get_multi::<_, Variadic<i64>>(keys).unwrap();
+        assert_eq!(values.iter().cloned().collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+
+        let proxy: Table = lua.eval(
+            r#"
+                setmetatable({}, {
+                    __index = function(_, k) return k .. "!" end,
+                    __newindex = function(_, k) if k == "bad" then error("bad key") end end,
+                })
+            "#,
+            None,
+        ).unwrap();
+        assert_eq!(
+            proxy.get_multi::<_, (String, String)>(("x", "y")).unwrap(),
+            ("x!".to_owned(), "y!".to_owned())
+        );
+        assert!(proxy.set_multi(("good", "bad"), (1, 2)).is_err());
+        assert!(table.get_multi::<_, (i64, i64)>(("a", "b")).is_err());
+    }
 }
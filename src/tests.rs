@@ -2,7 +2,38 @@ use std::fmt;
 use std::error;
 use std::panic::catch_unwind;
 
-use {Error, ExternalError, Function, Lua, Result, Table, Thread, ThreadStatus, Value, Variadic};
+use {Error, ExternalError, Function, IncludedChunk, Lua, PrecompileOptions, Result, Table, Thread,
+     ThreadStatus, TypedFunction, Value, Variadic};
+
+#[test]
+fn test_new_lazy() {
+    let lua = Lua::new_lazy();
+    let globals = lua.globals();
+
+    assert!(globals.raw_get::<_, Value>("math").unwrap().is_nil());
+    assert_eq!(lua.eval::<String>("('abc'):upper()", None).unwrap(), "ABC");
+    assert_eq!(lua.eval::<i64>("math.max(1, 5, 3)", None).unwrap(), 5);
+    assert!(globals.raw_get::<_, Table>("math").is_ok());
+
+    // Libraries loaded with `require` are shared with their global variable.
+    lua.exec::<()>(
+        r#"
+            local os_module = require("os")
+            assert(type(os_module.time()) == "number")
+            assert(os == os_module)
+            assert(require("table") == table)
+            assert(require("string") == string)
+            assert(package.loaded.io == nil)
+            assert(io == require("io"))
+            assert(undefined_global == nil)
+        "#,
+        None,
+    ).unwrap();
+
+    let lua = Lua::new();
+    assert!(lua.globals().raw_get::<_, Table>("io").is_ok());
+    lua.exec::<()>("assert(require('io') == io)", None).unwrap();
+}
 
 #[test]
 fn test_load() {
@@ -67,6 +98,7 @@ fn test_exec() {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_eval() {
     let lua = Lua::new();
     assert_eq!(lua.eval::<i32>("1 + 1", None).unwrap(), 2);
@@ -101,6 +133,48 @@ fn test_function() {
     assert_eq!(concat.call::<_, String>(("foo", "bar")).unwrap(), "foobar");
 }
 
+#[test]
+fn test_call_without_traceback() {
+    use std::panic::AssertUnwindSafe;
+    use Nil;
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+    globals
+        .set(
+            "fail",
+            lua.create_function(|_, ()| Err::<(), _>(Error::RuntimeError("boom".to_owned()))),
+        )
+        .unwrap();
+
+    let add: Function = lua.eval("function(a, b) return a + b end", None).unwrap();
+    assert_eq!(add.call_without_traceback::<_, i64>((1, 2)).unwrap(), 3);
+
+    match add.call_without_traceback::<_, i64>((1, Nil)) {
+        Err(Error::RuntimeError(msg)) => {
+            assert!(msg.contains("attempt to perform arithmetic"));
+            assert!(!msg.contains("stack traceback"));
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let fail: Function = globals.get("fail").unwrap();
+    match fail.call_without_traceback::<_, ()>(()) {
+        Err(Error::RuntimeError(msg)) => assert_eq!(msg, "boom"),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let call_fail: Function = lua.eval("function() fail() end", None).unwrap();
+    match call_fail.call_without_traceback::<_, ()>(()) {
+        Err(Error::RuntimeError(msg)) => assert_eq!(msg, "boom"),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let panics = lua.create_function(|_, ()| -> Result<()> { panic!("oops") });
+    let result = catch_unwind(AssertUnwindSafe(|| panics.call_without_traceback::<_, ()>(())));
+    assert!(result.is_err());
+}
+
 #[test]
 fn test_bind() {
     let lua = Lua::new();
@@ -128,6 +202,350 @@ fn test_bind() {
     );
 }
 
+#[test]
+fn test_function_dump() {
+    use std::io::{self, Write};
+
+    let lua = Lua::new();
+    let load = lua.globals().get::<_, Function>("load").unwrap();
+    let function = lua.eval::<Function>(
+        r#"
+            function(n)
+                local parts = {}
+                for i = 1, n do parts[i] = i * i end
+                return table.concat(parts, ",")
+            end
+        "#,
+        None,
+    ).unwrap();
+
+    let bytes = function.dump(false).unwrap();
+    assert_eq!(&bytes[..4], b"\x1bLua");
+    let mut streamed = Vec::new();
+    function.dump_to(&mut streamed, false).unwrap();
+    assert_eq!(streamed, bytes);
+
+    let stripped = function.dump(true).unwrap();
+    assert!(stripped.len() < bytes.len());
+    for bytes in &[bytes, stripped] {
+        let loaded = load
+            .call::<_, Function>(lua.create_byte_string(bytes))
+            .unwrap();
+        assert_eq!(loaded.call::<_, String>(4).unwrap(), "1,4,9,16");
+    }
+
+    let print = lua.globals().get::<_, Function>("print").unwrap();
+    assert!(print.dump(false).is_err());
+
+    struct Failing(usize);
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 < buf.len() {
+                return Err(io::Error::other("disk full"));
+            }
+            self.0 -= buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    match function.dump_to(&mut Failing(16), false) {
+        Err(Error::ExternalError(ref err)) => assert_eq!(err.to_string(), "disk full"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(lua.eval::<i64>("1 + 1", None).unwrap(), 2);
+}
+
+#[test]
+fn test_bytecode_listing() {
+    let lua = Lua::new();
+    let chunk = lua.load(
+        r#"local scale = 2.5
+local function f(t, ...)
+    for i = 1, #t do
+        t[i] = t[i] * scale .. "\n"
+    end
+    return { ..., 1, 2, 3 }
+end
+return f
+"#,
+        Some("=listing"),
+    ).unwrap();
+    let listing = chunk.bytecode_listing().unwrap();
+
+    let lines = listing.lines().collect::<Vec<_>>();
+    assert_eq!(lines[1], "main <listing:0,0> (4 instructions)");
+    assert_eq!(
+        lines[2],
+        "0+ params, 2 slots, 1 upvalue, 2 locals, 1 constant, 1 function"
+    );
+    assert_eq!(lines[3], "\t1\t[1]\tLOADK    \t0 -1\t; 2.5");
+    assert_eq!(lines[4], "\t2\t[7]\tCLOSURE  \t1 0\t; function <2,7>");
+    assert!(listing.contains("\nfunction <listing:2,7> ("));
+    assert!(listing.contains("1+ param, 7 slots, 1 upvalue, 5 locals, 4 constants, 0 functions"));
+    assert!(listing.contains("\tGETUPVAL \t6 0\t; scale\n"));
+    assert!(listing.contains("\tLOADK    \t6 -2\t; \"\\n\"\n"));
+    assert!(listing.contains("\tFORPREP  \t"));
+    assert!(listing.contains("\tSETLIST  \t1 4 1\t; 1\n"));
+    let forloop = lines.iter().find(|line| line.contains("FORLOOP")).unwrap();
+    let forprep = lines.iter().find(|line| line.contains("FORPREP")).unwrap();
+    let target = |line: &str| line.rsplit("; to ").next().unwrap().parse::<usize>().unwrap();
+    let pc = |line: &str| line.split('\t').nth(1).unwrap().parse::<usize>().unwrap();
+    assert_eq!(target(forprep), pc(forloop));
+    assert_eq!(target(forloop), pc(forprep) + 1);
+
+    let print = lua.globals().get::<_, Function>("print").unwrap();
+    assert!(print.bytecode_listing().is_err());
+}
+
+#[test]
+fn test_precompile_script() {
+    use std::{env, fs, process};
+    use std::path::Path;
+    use precompile::precompile_script_to;
+
+    let dir = env::temp_dir().join(format!("rlua_precompile_{}", process::id()));
+    let scripts = dir.join("src");
+    fs::create_dir_all(scripts.join("scripts")).unwrap();
+    fs::write(
+        scripts.join("scripts/init.lua"),
+        "local greeting = ...\nassert(greeting, 'no greeting')\nreturn greeting .. ' from init'",
+    ).unwrap();
+    fs::write(scripts.join("scripts/broken.lua"), "local x = = 1").unwrap();
+
+    let lua = Lua::new();
+    for &(bytecode, strip) in &[(false, false), (true, false), (true, true)] {
+        let out = dir.join(format!("out_{}_{}", bytecode, strip));
+        let options = PrecompileOptions {
+            bytecode,
+            strip,
+        };
+        precompile_script_to(Path::new("scripts/init.lua"), &scripts, &out, options).unwrap();
+
+        let bytes = fs::read(out.join("rlua/scripts/init.lua")).unwrap();
+        let bytes = Box::leak(bytes.into_boxed_slice());
+        let chunk = unsafe { IncludedChunk::from_build_output("scripts/init.lua", bytes) };
+        assert_eq!(chunk.is_bytecode(), bytecode);
+        assert_eq!(chunk.name(), "scripts/init.lua");
+
+        let function = lua.load_included(chunk).unwrap();
+        assert_eq!(function.call::<_, String>("hello").unwrap(), "hello from init");
+        let message = function.call::<_, ()>(()).unwrap_err().to_string();
+        if strip {
+            assert!(message.contains("no greeting"), "{}", message);
+        } else {
+            assert!(message.contains("scripts/init.lua:2: no greeting"), "{}", message);
+        }
+    }
+
+    let out = dir.join("out_broken");
+    let options = PrecompileOptions::default();
+    match precompile_script_to(Path::new("scripts/broken.lua"), &scripts, &out, options) {
+        Err(Error::SyntaxError { ref message, .. }) => {
+            assert!(message.starts_with("scripts/broken.lua:1:"), "{}", message)
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(!out.join("rlua/scripts/broken.lua").exists());
+    assert!(precompile_script_to(Path::new("missing.lua"), &scripts, &out, options).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_raw_state() {
+    use std::os::raw::c_int;
+    use std::panic::AssertUnwindSafe;
+    use ffi;
+
+    unsafe extern "C" fn sum(state: *mut ffi::lua_State) -> c_int {
+        let mut total = 0;
+        for i in 1..=ffi::lua_gettop(state) {
+            if ffi::lua_type(state, i) != ffi::LUA_TNUMBER {
+                ffi::lua_pushlstring(state, "expected numbers".as_ptr() as *const _, 16);
+                ffi::lua_error(state);
+            }
+            total += ffi::lua_tointeger(state, i);
+        }
+        ffi::lua_pushinteger(state, total);
+        1
+    }
+
+    let lua = Lua::new();
+    let sum = unsafe { Function::from_c_function(&lua, sum) };
+    assert_eq!(sum.call::<_, i64>((1, 2, 3)).unwrap(), 6);
+    lua.globals().set("sum", sum.clone()).unwrap();
+    assert_eq!(
+        lua.eval::<String>("select(2, pcall(sum, 1, {}))", None).unwrap(),
+        "expected numbers"
+    );
+    match sum.call::<_, i64>("x") {
+        Err(Error::RuntimeError(ref message)) => assert!(message.starts_with("expected numbers")),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let top = lua.with_raw_state(|state| unsafe {
+        ffi::lua_pushinteger(state, 1);
+        let top = ffi::lua_gettop(state);
+        ffi::lua_pop(state, 1);
+        top
+    });
+    assert!(top > 0);
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        lua.with_raw_state(|state| unsafe { ffi::lua_pushinteger(state, 1) })
+    }));
+    assert!(result.is_err());
+    // The value left on the stack was removed.
+    assert_eq!(lua.with_raw_state(|state| unsafe { ffi::lua_gettop(state) }), top - 1);
+    assert_eq!(sum.call::<_, i64>((4, 5)).unwrap(), 9);
+}
+
+#[test]
+fn test_init_from_ptr() {
+    use std::cell::Cell;
+    use std::os::raw::{c_char, c_void};
+    use std::ptr;
+    use ffi;
+    use libc;
+
+    unsafe extern "C" fn counting_allocator(
+        ud: *mut c_void,
+        ptr: *mut c_void,
+        osize: usize,
+        nsize: usize,
+    ) -> *mut c_void {
+        let allocated = &*(ud as *const Cell<usize>);
+        let old_size = if ptr.is_null() { 0 } else { osize };
+        allocated.set(allocated.get() + nsize - old_size);
+        if nsize == 0 {
+            libc::free(ptr);
+            ptr::null_mut()
+        } else {
+            libc::realloc(ptr, nsize)
+        }
+    }
+
+    let allocated = Cell::new(0usize);
+    let ud = &allocated as *const Cell<usize> as *mut c_void;
+    unsafe {
+        let state = ffi::lua_newstate(counting_allocator, ud);
+        ffi::luaL_requiref(state, cstr!("_G"), ffi::luaopen_base, 1);
+        ffi::lua_pop(state, 1);
+        ffi::lua_rawgeti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+        ffi::lua_pushinteger(state, 41);
+        ffi::lua_setfield(state, -2, cstr!("host_value"));
+        ffi::lua_pop(state, 1);
+        // A coroutine created before the state is attached.
+        ffi::luaL_requiref(state, cstr!("coroutine"), ffi::luaopen_coroutine, 1);
+        ffi::lua_pop(state, 1);
+        let chunk = "host_co = coroutine.wrap(function(n) return double(n) end)";
+        assert_eq!(
+            ffi::luaL_loadbufferx(
+                state,
+                chunk.as_ptr() as *const c_char,
+                chunk.len(),
+                cstr!("chunk"),
+                ptr::null(),
+            ),
+            ffi::LUA_OK
+        );
+        assert_eq!(ffi::lua_pcall(state, 0, 0, 0), ffi::LUA_OK);
+
+        let lua = Lua::init_from_ptr(state);
+        assert_eq!(lua.eval::<i64>("host_value + 1", None).unwrap(), 42);
+        lua.globals()
+            .set("double", lua.create_function(|_, n: i64| Ok(n * 2)))
+            .unwrap();
+        assert_eq!(lua.eval::<i64>("double(21)", None).unwrap(), 42);
+        #[cfg(feature = "builtin-lua")]
+        assert_eq!(lua.eval::<i64>("host_co(4)", None).unwrap(), 8);
+        assert!(lua.eval::<bool>("pcall(error, 'x')", None).is_ok());
+
+        let state = lua.into_raw();
+        let mut host_ud = ptr::null_mut();
+        ffi::lua_getallocf(state, &mut host_ud);
+        assert_eq!(host_ud, ud);
+        assert!(allocated.get() > 0);
+
+        // Attaching again reuses the registry entries installed the first time.
+        let lua = Lua::init_from_ptr(state);
+        assert_eq!(lua.eval::<i64>("double(host_value)", None).unwrap(), 82);
+        drop(lua);
+    }
+    assert_eq!(allocated.get(), 0);
+}
+
+#[test]
+fn test_into_raw_removes_hooks() {
+    use std::os::raw::c_char;
+    use ffi;
+    use Budget;
+
+    let lua = Lua::new();
+    lua.start_coverage();
+    lua.set_budget(Budget::instructions(1_000_000));
+    lua.exec::<()>(
+        r#"
+            function sum(n)
+                local total = 0
+                for i = 1, n do
+                    total = total + i
+                end
+                return total
+            end
+            co = coroutine.create(function(n)
+                n = coroutine.yield(sum(n))
+                return sum(n)
+            end)
+            coroutine.resume(co, 10)
+        "#,
+        None,
+    ).unwrap();
+
+    let state = lua.into_raw();
+    unsafe {
+        ffi::lua_rawgeti(state, ffi::LUA_REGISTRYINDEX, ffi::LUA_RIDX_GLOBALS);
+        ffi::lua_getfield(state, -1, cstr!("sum"));
+        ffi::lua_pushinteger(state, 100);
+        assert_eq!(ffi::lua_pcall(state, 1, 1, 0), ffi::LUA_OK);
+        assert_eq!(ffi::lua_tointeger(state, -1), 5050);
+        ffi::lua_pop(state, 1);
+
+        // The coroutine inherited the hook before the state was detached.
+        ffi::lua_getfield(state, -1, cstr!("co"));
+        let co = ffi::lua_tothread(state, -1);
+        ffi::lua_pushinteger(co, 100);
+        assert_eq!(ffi::lua_resume(co, state, 1), ffi::LUA_OK);
+        assert_eq!(ffi::lua_tointeger(co, -1), 5050);
+        ffi::lua_pop(state, 2);
+        ffi::lua_close(state);
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_load_c_module_errors() {
+    let lua = Lua::new();
+    unsafe {
+        match lua.load_c_module("/nonexistent/module.so", "luaopen_module") {
+            Err(Error::RuntimeError(ref message)) => {
+                assert!(message.starts_with("cannot load C module '/nonexistent/module.so'"))
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        match lua.load_c_module("libc.so.6", "luaopen_missing") {
+            Err(Error::RuntimeError(ref message)) => {
+                assert!(message.starts_with("cannot find 'luaopen_missing' in 'libc.so.6'"))
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(lua.load_c_module("libc.so.6", "malloc").is_err());
+    }
+    assert!(lua.eval::<Value>("package.loaded.missing", None).unwrap().is_nil());
+}
+
 #[test]
 fn test_rust_function() {
     let lua = Lua::new();
@@ -151,6 +569,172 @@ fn test_rust_function() {
     assert_eq!(lua_function.call::<_, String>(()).unwrap(), "hello");
 }
 
+#[test]
+fn test_function_ptr() {
+    fn factorial(lua: &Lua, n: i64) -> Result<i64> {
+        if n <= 1 {
+            Ok(1)
+        } else {
+            let factorial = lua.globals().get::<_, Function>("factorial")?;
+            Ok(n * factorial.call::<_, i64>(n - 1)?)
+        }
+    }
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+    globals.set("factorial", lua.create_function_ptr(factorial)).unwrap();
+    globals
+        .set("concat", lua.create_function_ptr(|_, (a, b): (String, String)| Ok(a + &b)))
+        .unwrap();
+
+    // Function pointers have no state to borrow, so they may be called recursively.
+    assert_eq!(lua.eval::<i64>("factorial(10)", None).unwrap(), 3628800);
+    assert_eq!(lua.eval::<String>("concat('a', 1)", None).unwrap(), "a1");
+    match lua.exec::<()>("concat('a', {})", None) {
+        Err(Error::CallbackError { ref cause, .. }) => assert!(cause
+            .to_string()
+            .starts_with("bad argument #2 to 'concat'")),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let thread = lua.create_thread(lua.create_function_ptr(|lua, n: i64| {
+        lua.yield_with(n + 1)?;
+        Ok(())
+    }));
+    assert_eq!(thread.resume::<_, i64>(1).unwrap(), 2);
+}
+
+#[test]
+fn test_wrapped_function() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let api = lua.create_table();
+    api.set("add", Function::wrap(|_, (a, b): (i64, i64)| Ok(a + b)))
+        .unwrap();
+    api.set(
+        "greet",
+        Function::wrap(|_, name: String| Ok(format!("hello, {}", name))),
+    ).unwrap();
+    globals.set("api", api).unwrap();
+
+    assert_eq!(lua.eval::<i64>("api.add(3, 4)", None).unwrap(), 7);
+    assert_eq!(
+        lua.eval::<String>("api.greet('lua')", None).unwrap(),
+        "hello, lua"
+    );
+
+    let apply: Function = lua.eval("function(f, x) return f(x) end", None)
+        .unwrap();
+    assert_eq!(
+        apply
+            .call::<_, i64>((Function::wrap(|_, x: i64| Ok(x * 2)), 21))
+            .unwrap(),
+        42
+    );
+}
+
+#[test]
+fn test_typed_function() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+    lua.exec::<()>(
+        r#"
+            function add(a, b)
+                return a + b
+            end
+        "#,
+        None,
+    ).unwrap();
+
+    let add: TypedFunction<(i64, i64), i64> = globals.get("add").unwrap();
+    assert_eq!(add.call((1, 2)).unwrap(), 3);
+    assert_eq!(add.call((40, 2)).unwrap(), 42);
+
+    globals.set("add2", add.clone()).unwrap();
+    assert_eq!(lua.eval::<i64>("add2(5, 5)", None).unwrap(), 10);
+
+    assert!(
+        globals
+            .get::<_, TypedFunction<(), ()>>("nonexistent")
+            .is_err()
+    );
+}
+
+#[test]
+fn test_call_with_handler() {
+    let lua = Lua::new();
+
+    let fail: Function = lua.eval(r#"function(msg) error(msg, 0) end"#, None)
+        .unwrap();
+    let handler: Function = lua.eval(
+        r#"
+            function(err)
+                return "handled: " .. err
+            end
+        "#,
+        None,
+    ).unwrap();
+
+    match fail.call_with_handler::<_, ()>(handler.clone(), "oops") {
+        Err(Error::RuntimeError(msg)) => assert_eq!(msg, "handled: oops"),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let ok: Function = lua.eval("function(a, b) return a + b end", None).unwrap();
+    assert_eq!(
+        ok.call_with_handler::<_, i64>(handler, (1, 2)).unwrap(),
+        3
+    );
+
+    // Rust handlers see the original error value and may replace it.
+    let rust_handler = lua.create_function(|_, err: Error| {
+        Ok(Error::RuntimeError(format!("rust handled: {}", err)))
+    });
+    let rust_fail = lua.create_function(|_, ()| -> Result<()> {
+        Err(Error::RuntimeError("inner".to_owned()))
+    });
+    match rust_fail.call_with_handler::<_, ()>(rust_handler, ()) {
+        Err(Error::RuntimeError(msg)) => {
+            assert_eq!(msg, "rust handled: runtime error: inner")
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_pcall_raw_error() {
+    let lua = Lua::new();
+
+    let fail: Function = lua.eval(
+        r#"
+            function(code)
+                error({ code = code, msg = "failed" })
+            end
+        "#,
+        None,
+    ).unwrap();
+
+    match fail.pcall::<_, ()>(42).unwrap() {
+        Err(Value::Table(err)) => {
+            assert_eq!(err.get::<_, i64>("code").unwrap(), 42);
+            assert_eq!(err.get::<_, String>("msg").unwrap(), "failed");
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let ok: Function = lua.eval("function(a) return a * 2 end", None).unwrap();
+    assert_eq!(ok.pcall::<_, i64>(21).unwrap().unwrap(), 42);
+
+    let rust_fail = lua.create_function(|_, ()| -> Result<()> {
+        Err(Error::RuntimeError("rust failure".to_owned()))
+    });
+    match rust_fail.pcall::<_, ()>(()).unwrap() {
+        Err(Value::Error(Error::RuntimeError(msg))) => assert_eq!(msg, "rust failure"),
+        r => panic!("unexpected result {:?}", r),
+    };
+}
+
 #[test]
 fn test_lua_multi() {
     let lua = Lua::new();
@@ -213,7 +797,7 @@ fn test_error() {
             "test error"
         }
 
-        fn cause(&self) -> Option<&error::Error> {
+        fn cause(&self) -> Option<&dyn error::Error> {
             None
         }
     }
@@ -380,6 +964,79 @@ fn test_error() {
     };
 }
 
+#[test]
+fn test_panic_mode() {
+    use std::panic::AssertUnwindSafe;
+    use {PanicMode, UserData, UserDataMethods};
+
+    fn callback_panic(result: Result<()>) -> String {
+        match result {
+            Err(Error::CallbackError { cause, .. }) => match *cause {
+                Error::CallbackPanic(ref message) => message.clone(),
+                ref err => panic!("unexpected cause {:?}", err),
+            },
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+    let default = lua.create_function(|_, ()| -> Result<()> { panic!("default panic") });
+    let resume = lua.create_function_with_panic_mode(PanicMode::Resume, |_, ()| -> Result<()> {
+        panic!("resumed panic")
+    });
+    let catch = lua.create_function_with_panic_mode(PanicMode::Catch, |_, ()| -> Result<()> {
+        panic!("caught panic")
+    });
+    globals.set("default", default).unwrap();
+    globals.set("resume", resume).unwrap();
+    globals.set("catch", catch).unwrap();
+
+    let run = |script: &str| catch_unwind(AssertUnwindSafe(|| lua.exec::<()>(script, None)));
+    let in_coroutine = |name: &str| format!("assert(coroutine.resume(coroutine.create({})))", name);
+
+    assert!(run("pcall(default)").is_err());
+    assert!(run("pcall(resume)").is_err());
+    assert!(run(&in_coroutine("default")).is_err());
+    assert!(run("coroutine.wrap(resume)()").is_err());
+    assert_eq!(
+        lua.eval::<String>("select(2, pcall(catch)).message", None).unwrap(),
+        "callback panicked: caught panic"
+    );
+    assert_eq!(callback_panic(run(&in_coroutine("catch")).unwrap()), "caught panic");
+
+    lua.set_panic_mode(PanicMode::Catch);
+    assert_eq!(
+        lua.eval::<String>("select(2, pcall(default)).kind", None).unwrap(),
+        "callback_panic"
+    );
+    assert_eq!(callback_panic(run("default()").unwrap()), "default panic");
+    assert_eq!(callback_panic(run(&in_coroutine("default")).unwrap()), "default panic");
+    assert!(run("pcall(resume)").is_err());
+    assert!(run(&in_coroutine("resume")).is_err());
+
+    // Panics in threads resumed from Rust follow the same rules.
+    let thread = lua.create_thread(globals.get::<_, Function>("default").unwrap());
+    assert_eq!(callback_panic(thread.resume::<_, ()>(())), "default panic");
+    assert_eq!(thread.status(), ThreadStatus::Error);
+    let thread = lua.create_thread(globals.get::<_, Function>("resume").unwrap());
+    assert!(catch_unwind(AssertUnwindSafe(|| thread.resume::<_, ()>(()))).is_err());
+
+    // Userdata methods use the mode set for the `Lua`.
+    struct Faulty;
+
+    impl UserData for Faulty {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method("fail", |_, _, ()| -> Result<()> { panic!("method panic") });
+        }
+    }
+
+    globals.set("faulty", Faulty).unwrap();
+    assert_eq!(callback_panic(run("faulty:fail()").unwrap()), "method panic");
+    lua.set_panic_mode(PanicMode::Resume);
+    assert!(run("pcall(faulty.fail, faulty)").is_err());
+}
+
 #[test]
 fn test_thread() {
     let lua = Lua::new();
@@ -408,7 +1065,26 @@ fn test_thread() {
     assert_eq!(thread.resume::<_, i64>(3).unwrap(), 6);
     assert_eq!(thread.status(), ThreadStatus::Resumable);
     assert_eq!(thread.resume::<_, i64>(4).unwrap(), 10);
-    assert_eq!(thread.status(), ThreadStatus::Unresumable);
+    assert_eq!(thread.status(), ThreadStatus::Finished);
+
+    let running = lua.create_thread(
+        lua.eval::<Function>(
+            r#"
+                function(check)
+                    check(coroutine.running())
+                end
+            "#,
+            None,
+        ).unwrap(),
+    );
+    running
+        .resume::<_, ()>(lua.create_function(|_, thread: Thread| {
+            assert_eq!(thread.status(), ThreadStatus::Running);
+            assert!(!thread.is_resumable());
+            Ok(())
+        }))
+        .unwrap();
+    assert!(!running.is_resumable());
 
     let accumulate = lua.create_thread(
         lua.eval::<Function>(
@@ -467,176 +1143,4806 @@ fn test_thread() {
 }
 
 #[test]
-fn test_result_conversions() {
+fn test_thread_reset() {
     let lua = Lua::new();
-    let globals = lua.globals();
+    let func: Function = lua.eval(
+        r#"
+            function(arg)
+                local next = coroutine.yield(arg)
+                return next + 1
+            end
+        "#,
+        None,
+    ).unwrap();
 
-    let err = lua.create_function(|_, ()| {
-        Ok(Err::<String, _>(
-            "only through failure can we succeed".to_lua_err(),
-        ))
-    });
-    let ok = lua.create_function(|_, ()| Ok(Ok::<_, Error>("!".to_owned())));
+    let thread = lua.create_thread(func.clone());
+    for i in 0..3 {
+        assert_eq!(thread.resume::<_, i64>(i).unwrap(), i);
+        assert_eq!(thread.resume::<_, i64>(i).unwrap(), i + 1);
+        assert_eq!(thread.status(), ThreadStatus::Finished);
+        thread.reset(func.clone()).unwrap();
+        assert_eq!(thread.status(), ThreadStatus::Resumable);
+    }
+
+    let erroring: Function = lua.eval("function() error('oops') end", None)
+        .unwrap();
+    let errored = lua.create_thread(erroring);
+    assert!(errored.resume::<_, ()>(()).is_err());
+
+    #[cfg(feature = "builtin-lua")]
+    {
+        // Suspended and errored threads are abandoned, their captured locals keep their values.
+        let counting: Function = lua.eval(
+            r#"
+                function()
+                    local count = 0
+                    get_count = function() return count end
+                    while true do
+                        count = count + 1
+                        coroutine.yield(count)
+                    end
+                end
+            "#,
+            None,
+        ).unwrap();
+        assert_eq!(thread.resume::<_, i64>(0).unwrap(), 0);
+        thread.reset(counting.clone()).unwrap();
+        assert_eq!(thread.status(), ThreadStatus::Resumable);
+        assert_eq!(thread.resume::<_, i64>(()).unwrap(), 1);
+        assert_eq!(thread.resume::<_, i64>(()).unwrap(), 2);
+        let get_count: Function = lua.globals().get("get_count").unwrap();
+        thread.reset(func.clone()).unwrap();
+        assert_eq!(thread.resume::<_, i64>(5).unwrap(), 5);
+        assert_eq!(thread.resume::<_, i64>(5).unwrap(), 6);
+        assert_eq!(get_count.call::<_, i64>(()).unwrap(), 2);
+        lua.exec::<()>("collectgarbage()", None).unwrap();
+        assert_eq!(get_count.call::<_, i64>(()).unwrap(), 2);
+
+        errored.reset(counting).unwrap();
+        assert_eq!(errored.status(), ThreadStatus::Resumable);
+        assert_eq!(errored.resume::<_, i64>(()).unwrap(), 1);
+    }
+
+    #[cfg(not(feature = "builtin-lua"))]
+    {
+        assert_eq!(thread.resume::<_, i64>(0).unwrap(), 0);
+        // Cannot reset while suspended in coroutine.yield
+        assert!(thread.reset(func.clone()).is_err());
+        assert!(errored.reset(func.clone()).is_err());
+    }
+
+    // A running thread cannot reset itself
+    let reset_self = lua.create_function(|lua, ()| {
+        let thread: Thread = lua.globals().get("thread")?;
+        let func: Function = lua.globals().get("func")?;
+        Ok(thread.reset(func).is_err())
+    });
+    let thread = lua.create_thread(reset_self.clone());
+    lua.globals().set("thread", thread.clone()).unwrap();
+    lua.globals().set("func", reset_self).unwrap();
+    assert!(thread.resume::<_, bool>(()).unwrap());
+}
+
+#[test]
+fn test_thread_pool() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+    lua.set_thread_pool_size(1);
+
+    lua.exec::<()>(
+        r#"
+            function remember()
+                previous = coroutine.running()
+            end
+
+            function compare()
+                return previous == coroutine.running()
+            end
+        "#,
+        None,
+    ).unwrap();
+
+    let remember: Function = globals.get("remember").unwrap();
+    let compare: Function = globals.get("compare").unwrap();
+
+    let thread = lua.create_thread(remember.clone());
+    thread.resume::<_, ()>(()).unwrap();
+    assert!(unsafe { lua.recycle_thread(thread) });
+
+    // The finished thread is handed out again
+    let thread = lua.create_thread(compare.clone());
+    assert!(thread.resume::<_, bool>(()).unwrap());
+
+    // Dropping a thread does not recycle it, as other handles may still refer to it
+    drop(thread);
+    let thread = lua.create_thread(compare.clone());
+    assert!(!thread.resume::<_, bool>(()).unwrap());
+    let other = thread.clone();
+    drop(thread);
+    let _fresh = lua.create_thread(remember.clone());
+    assert_eq!(other.status(), ThreadStatus::Finished);
+
+    // Unfinished threads are not recycled
+    let yielding: Function = lua.eval(
+        r#"
+            function()
+                remember()
+                coroutine.yield()
+            end
+        "#,
+        None,
+    ).unwrap();
+    let unfinished = lua.create_thread(yielding);
+    unfinished.resume::<_, ()>(()).unwrap();
+    assert!(!unsafe { lua.recycle_thread(unfinished) });
+    let thread = lua.create_thread(compare.clone());
+    assert!(!thread.resume::<_, bool>(()).unwrap());
+
+    // Nothing is recycled once the pool is disabled
+    let thread = lua.create_thread(remember);
+    thread.resume::<_, ()>(()).unwrap();
+    lua.set_thread_pool_size(0);
+    assert!(!unsafe { lua.recycle_thread(thread) });
+    let thread = lua.create_thread(compare);
+    assert!(!thread.resume::<_, bool>(()).unwrap());
+}
+
+#[test]
+fn test_stack_guard() {
+    use ffi;
+    use StackGuard;
+
+    let lua = Lua::new();
+    unsafe {
+        let top = ffi::lua_gettop(lua.state);
+        {
+            let guard = StackGuard::with_reserved(lua.state, 100);
+            assert_eq!(guard.top(), top);
+            for i in 0..100 {
+                ffi::lua_pushinteger(lua.state, i);
+            }
+        }
+        assert_eq!(ffi::lua_gettop(lua.state), top);
+    }
+
+    // Failing operations leave the stack as they found it.
+    let table: Table = lua.eval("setmetatable({}, { __index = error })", None).unwrap();
+    for _ in 0..100 {
+        assert!(table.get::<_, i64>("key").is_err());
+        assert!(lua.globals().get::<_, Table>("print").is_err());
+    }
+    unsafe {
+        assert_eq!(ffi::lua_gettop(lua.state), 0);
+    }
+}
+
+#[test]
+fn test_channels() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let (sender, receiver) = lua.create_channel::<i64>(1);
+    globals.set("sender", sender).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(sender:try_send(1))
+            assert(not sender:try_send(2))
+        "#,
+        None,
+    ).unwrap();
+    assert_eq!(receiver.try_recv().unwrap(), 1);
+    assert!(lua.exec::<()>("sender:send('not a number')", None).is_err());
+    drop(receiver);
+    assert!(!lua.eval::<bool>("sender:send(3)", None).unwrap());
+
+    let (sender, receiver) = lua.create_reverse_channel::<String>(2);
+    globals.set("receiver", receiver).unwrap();
+    assert!(lua.eval::<Value>("receiver:try_recv()", None).unwrap().is_nil());
+    sender.send("hello".to_owned()).unwrap();
+    assert_eq!(lua.eval::<String>("receiver:recv()", None).unwrap(), "hello");
+    drop(sender);
+    assert!(lua.eval::<Value>("receiver:recv()", None).unwrap().is_nil());
+}
+
+#[test]
+fn test_many_refs() {
+    let lua = Lua::new();
+    let table = lua.create_table();
+    table.set("answer", 42).unwrap();
+
+    // Enough handles to fill the stack of the reference thread, so that the last ones are stored
+    // in the registry instead.
+    let tables = (0..1_000_100).map(|_| table.clone()).collect::<Vec<_>>();
+    assert_eq!(tables.last().unwrap().get::<_, i64>("answer").unwrap(), 42);
+    assert_eq!(tables[0].to_pointer(), tables.last().unwrap().to_pointer());
+
+    // Dropped handles free their slots for new ones.
+    drop(tables);
+    let strings = (0..100)
+        .map(|i| lua.create_string(&i.to_string()))
+        .collect::<Vec<_>>();
+    for (i, s) in strings.iter().enumerate() {
+        assert_eq!(s.to_str().unwrap(), i.to_string());
+    }
+    assert_eq!(table.get::<_, i64>("answer").unwrap(), 42);
+}
+
+#[test]
+fn test_callback_yield() {
+    let lua = Lua::new();
+
+    lua.globals()
+        .set(
+            "host_yield",
+            lua.create_function(|lua, n: i64| {
+                lua.yield_with((n, n + 1))?;
+                Ok(0)
+            }),
+        )
+        .unwrap();
+
+    let thread = lua.create_thread(
+        lua.eval::<Function>(
+            r#"
+                function(n)
+                    return host_yield(n) + 100
+                end
+            "#,
+            None,
+        ).unwrap(),
+    );
+
+    assert_eq!(thread.resume::<_, (i64, i64)>(1).unwrap(), (1, 2));
+    assert_eq!(thread.status(), ThreadStatus::Resumable);
+    assert_eq!(thread.resume::<_, i64>(5).unwrap(), 105);
+    assert_eq!(thread.status(), ThreadStatus::Finished);
+
+    let host_yield: Function = lua.globals().get("host_yield").unwrap();
+    assert!(host_yield.call::<_, i64>(1).is_err());
+    assert!(lua.yield_with(()).is_err());
+}
+
+#[test]
+fn test_thread_from_closure() {
+    let lua = Lua::new();
+
+    let mut total = 0;
+    let thread = lua.create_thread_from(move |yielder, n: i64| {
+        total += n;
+        if n != 0 {
+            yielder.yield_values(total)?;
+        }
+        Ok(total * 10)
+    });
+
+    assert_eq!(thread.resume::<_, i64>(1).unwrap(), 1);
+    assert_eq!(thread.resume::<_, i64>(2).unwrap(), 3);
+    assert_eq!(thread.status(), ThreadStatus::Resumable);
+    assert_eq!(thread.resume::<_, i64>(0).unwrap(), 30);
+    assert_eq!(thread.status(), ThreadStatus::Finished);
+
+    lua.globals()
+        .set(
+            "gen",
+            lua.create_thread_from(|yielder, ()| {
+                yielder.yield_values("value")?;
+                Ok(())
+            }),
+        )
+        .unwrap();
+    assert_eq!(
+        lua.eval::<String>("select(2, coroutine.resume(gen))", None)
+            .unwrap(),
+        "value"
+    );
+}
+
+#[test]
+fn test_thread_traceback() {
+    let lua = Lua::new();
+
+    let thread = lua.create_thread(
+        lua.eval::<Function>(
+            r#"
+                local function park()
+                    coroutine.yield()
+                end
+                return function()
+                    park()
+                end
+            "#,
+            Some("watched"),
+        ).unwrap(),
+    );
+    assert!(thread.traceback().is_empty());
+
+    thread.resume::<_, ()>(()).unwrap();
+    let frames = thread.traceback();
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[0].source, "[C]");
+    assert_eq!(frames[1].source, "[string \"watched\"]");
+    assert_eq!(frames[1].line, Some(3));
+    assert_eq!(frames[1].name, Some("park".to_owned()));
+    assert_eq!(frames[2].line, Some(6));
+}
+
+#[test]
+fn test_error_frames() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let fail = lua.create_function(|_, ()| Err::<(), _>("failed".to_lua_err()));
+    globals.set("rust_fail", fail).unwrap();
+
+    let f = lua.load(
+        r#"
+            local function inner(f)
+                f()
+            end
+            function outer(f)
+                inner(f)
+            end
+            return function(f)
+                outer(f)
+            end
+        "#,
+        Some("a: in b"),
+    ).unwrap()
+        .call::<_, Function>(())
+        .unwrap();
+
+    let err = f.call::<_, ()>(lua.eval::<Function>("function() error('oops') end", None)
+        .unwrap())
+        .unwrap_err();
+    let frames = err.frames().unwrap();
+    let names = frames.iter().map(|f| f.name.clone()).collect::<Vec<_>>();
+    let inner = names
+        .iter()
+        .position(|n| *n == Some("inner".to_owned()))
+        .unwrap();
+    assert_eq!(names[inner + 1], Some("outer".to_owned()));
+    assert_eq!(frames[inner].source, "[string \"a: in b\"]");
+    assert_eq!(frames[inner].line, Some(3));
+    assert_eq!(frames[inner + 1].line, Some(6));
+    assert_eq!(frames[0].source, "[C]");
+    assert_eq!(frames[0].line, None);
+
+    let err = f.call::<_, ()>(globals.get::<_, Function>("rust_fail").unwrap())
+        .unwrap_err();
+    match err {
+        Error::CallbackError { .. } => {}
+        ref err => panic!("unexpected error {:?}", err),
+    }
+    let frames = err.frames().unwrap();
+    assert_eq!(frames[1].source, "[C]");
+    assert_eq!(frames[1].name, Some("rust_fail".to_owned()));
+    assert_eq!(frames[2].name, Some("inner".to_owned()));
+
+    assert!(Error::RuntimeError("no traceback".to_owned()).frames().is_none());
+    assert!(Error::UserDataTypeMismatch.frames().is_none());
+}
+
+#[test]
+fn test_error_source_chain() {
+    use std::error::Error as StdError;
+
+    #[derive(Debug)]
+    struct Inner;
+
+    impl fmt::Display for Inner {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            write!(fmt, "inner error")
+        }
+    }
+
+    impl error::Error for Inner {}
+
+    #[derive(Debug)]
+    struct Outer(Inner);
+
+    impl fmt::Display for Outer {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            write!(fmt, "outer error")
+        }
+    }
+
+    impl error::Error for Outer {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let fail = lua.create_function(|_, ()| Err::<(), _>(Error::external(Outer(Inner))));
+    globals.set("fail", fail).unwrap();
+    let call_lua = lua.create_function(|lua, ()| {
+        lua.globals().get::<_, Function>("call_fail")?.call::<_, ()>(())
+    });
+    globals.set("call_lua", call_lua).unwrap();
+    lua.exec::<()>("function call_fail() fail() end", None)
+        .unwrap();
+
+    let err = lua.eval::<()>("call_lua()", None).unwrap_err();
+
+    let mut chain = Vec::new();
+    let mut source: Option<&dyn StdError> = Some(&err);
+    while let Some(err) = source {
+        chain.push(err.to_string());
+        source = err.source();
+    }
+    assert_eq!(chain.len(), 4);
+    assert!(chain[0].starts_with("callback error"));
+    assert!(chain[1].starts_with("callback error"));
+    assert_eq!(chain[2], "outer error");
+    assert_eq!(chain[3], "inner error");
+
+    match err.source().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(Error::CallbackError { cause, .. }) => match **cause {
+            Error::ExternalError(ref err) => assert!(err.downcast_ref::<Outer>().is_some()),
+            ref err => panic!("unexpected cause {:?}", err),
+        },
+        err => panic!("unexpected source {:?}", err),
+    }
+}
+
+#[test]
+fn test_error_downcast() {
+    use ExternalResult;
+
+    #[derive(Debug)]
+    struct Custom;
+
+    impl fmt::Display for Custom {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            write!(fmt, "custom error")
+        }
+    }
+
+    impl error::Error for Custom {}
+
+    let lua = Lua::new();
+    let external = lua.create_function(|_, ()| Err::<(), _>(Error::external(Custom)));
+    let wrapped = lua.create_function(|_, ()| {
+        Err::<(), _>(Box::new(Custom) as Box<dyn error::Error + Send + Sync>).to_lua_err()
+    });
+
+    for f in &[external, wrapped] {
+        let err = f.call::<_, ()>(()).unwrap_err();
+        assert!(err.downcast_ref::<Custom>().is_some());
+        assert!(err.downcast_ref::<fmt::Error>().is_none());
+        assert!(err.is::<Custom>());
+        assert!(!err.is::<fmt::Error>());
+    }
+
+    let rethrow = lua.eval::<Function>(
+        r#"
+            function(f)
+                local ok, err = pcall(f)
+                assert(not ok)
+                error(err)
+            end
+        "#,
+        None,
+    ).unwrap();
+    let err = rethrow
+        .call::<_, ()>(lua.create_function(|_, ()| Err::<(), _>(Error::external(Custom))))
+        .unwrap_err();
+    assert!(err.is::<Custom>());
+}
+
+#[test]
+fn test_error_fields_in_lua() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let external = lua.create_function(|_, ()| Err::<(), _>(Error::external(fmt::Error)));
+    globals.set("external", external).unwrap();
+    let convert = lua.create_function(|_, _: u32| Ok(()));
+    globals.set("convert", convert).unwrap();
+    let context = lua.create_function(|_, ()| {
+        Err::<(), _>(Error::RuntimeError("inner".to_owned()).context("outer"))
+    });
+    globals.set("context", context).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            local ok, err = pcall(external)
+            assert(not ok)
+            assert(err.kind == "external")
+            assert(err.message == tostring(err))
+            assert(err.from == nil)
+            assert(err[1] == nil)
+
+            ok, err = pcall(convert, {})
+            assert(err.kind == "bad_argument")
+            assert(err.arg_index == 1 and err.function_name == nil)
+            assert(err.cause.kind == "from_lua_conversion")
+            assert(err.cause.from == "table" and err.cause.to == "integer")
+
+            ok, err = pcall(context)
+            assert(err.kind == "context")
+            assert(err.context == "outer")
+            assert(err.message == "outer: runtime error: inner")
+            assert(err.cause.kind == "runtime")
+            assert(err.cause.message == "runtime error: inner")
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn test_bad_argument() {
+    use {UserData, UserDataMethods};
+
+    struct Entity;
+
+    impl UserData for Entity {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method("move_to", |_, _, (_x, _y): (f64, f64)| Ok(()));
+        }
+    }
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let spawn = lua.create_function(|_, (_name, _x, _rest): (String, i64, Variadic<i64>)| Ok(()));
+    globals.set("spawn_entity", spawn).unwrap();
+    globals.set("entity", Entity).unwrap();
+
+    let message = |script: &str| match lua.exec::<()>(script, None).unwrap_err() {
+        Error::CallbackError { cause, .. } => match *cause {
+            Error::BadArgument { .. } => cause.to_string(),
+            ref err => panic!("unexpected cause {:?}", err),
+        },
+        err => panic!("unexpected error {:?}", err),
+    };
+
+    assert_eq!(
+        message("spawn_entity('orc', 'here')"),
+        "bad argument #2 to 'spawn_entity' (error converting Lua string to integer)"
+    );
+    assert!(message("spawn_entity('orc', 1, 2, 3, 'x')").starts_with("bad argument #5 to"));
+    assert!(message("spawn_entity({}, 1)").starts_with("bad argument #1 to 'spawn_entity'"));
+    assert!(message("entity:move_to(1, 'b')").starts_with("bad argument #2 to 'move_to'"));
+    assert!(message("entity.move_to(entity, 1, 'b')").starts_with("bad argument #3 to 'move_to'"));
+}
+
+#[test]
+fn test_error_context() {
+    use std::error::Error as StdError;
+    use ErrorContext;
+
+    let lua = Lua::new();
+    let config = lua.eval::<Table>(r#"{ timeout = "soon" }"#, None)
+        .unwrap();
+
+    let err = config
+        .get::<_, u32>("timeout")
+        .context("while reading field 'timeout'")
+        .with_context(|| format!("while loading config {}", "a.lua"))
+        .unwrap_err();
+    assert!(err.to_string().starts_with(
+        "while loading config a.lua: while reading field 'timeout': error converting"
+    ));
+    match err {
+        Error::WithContext { ref cause, .. } => match **cause {
+            Error::WithContext { ref cause, .. } => match **cause {
+                Error::FromLuaConversionError { .. } => {}
+                ref err => panic!("unexpected cause {:?}", err),
+            },
+            ref err => panic!("unexpected cause {:?}", err),
+        },
+        ref err => panic!("unexpected error {:?}", err),
+    }
+    assert!(err.source().is_none());
+
+    let f = lua.create_function(|_, ()| Err::<(), _>(Error::external(fmt::Error)));
+    let err = f.call::<_, ()>(()).context("calling f").unwrap_err();
+    assert!(err.downcast_ref::<fmt::Error>().is_some());
+    assert!(err.frames().is_some());
+
+    assert!(Ok::<_, Error>(1).context("unused").is_ok());
+}
+
+#[test]
+fn test_error_values() {
+    use ErrorValue;
+
+    let lua = Lua::new();
+
+    match lua.exec::<()>(r#"error({ code = 404, path = { "a", 2.5 } })"#, None) {
+        Err(ref err @ Error::RuntimeErrorValue { .. }) => {
+            assert!(err.to_string()
+                .starts_with("runtime error: (error object is a table value)"));
+            assert!(err.frames().is_some());
+            match *err {
+                Error::RuntimeErrorValue { ref value, .. } => {
+                    assert_eq!(value.get("code"), Some(&ErrorValue::Integer(404)));
+                    let mut path = match value.get("path") {
+                        Some(ErrorValue::Table(entries)) => entries.clone(),
+                        v => panic!("unexpected path {:?}", v),
+                    };
+                    path.sort_by_key(|entry| format!("{:?}", entry.0));
+                    assert_eq!(
+                        path,
+                        vec![
+                            (ErrorValue::Integer(1), ErrorValue::String(b"a".to_vec())),
+                            (ErrorValue::Integer(2), ErrorValue::Number(2.5)),
+                        ]
+                    );
+                }
+                _ => unreachable!(),
+            }
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    match lua.exec::<()>(r#"error("\255bad", 0)"#, None) {
+        Err(Error::RuntimeErrorValue { message, value }) => {
+            assert!(message.starts_with("\u{fffd}bad\nstack traceback:"));
+            assert_eq!(value, ErrorValue::String(b"\xffbad".to_vec()));
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    match lua.exec::<()>("local t = { f = print } t.t = t error(t)", None) {
+        Err(Error::RuntimeErrorValue { value, .. }) => {
+            assert_eq!(value.get("t"), Some(&ErrorValue::Other("table")));
+            assert_eq!(value.get("f"), Some(&ErrorValue::Other("function")));
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    match lua.exec::<()>("error('plain')", None) {
+        Err(Error::RuntimeError(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let thread = lua.create_thread(lua.load("error(false)", None).unwrap());
+    match thread.resume::<_, ()>(()) {
+        Err(Error::RuntimeErrorValue { value, .. }) => {
+            assert_eq!(value, ErrorValue::Boolean(false))
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let thrower = lua.load("error({ code = 500 })", None).unwrap();
+    match thrower.pcall::<_, ()>(()).unwrap() {
+        Err(value) => match lua.unpack::<Error>(value).unwrap() {
+            Error::RuntimeErrorValue { value, .. } => {
+                assert_eq!(value.get("code"), Some(&ErrorValue::Integer(500)))
+            }
+            err => panic!("unexpected error {:?}", err),
+        },
+        Ok(()) => panic!("thrower did not fail"),
+    }
+
+    // Errors raised again from Rust callbacks keep the value accessible to Lua.
+    lua.globals().set("thrower", thrower).unwrap();
+    let rethrow = lua.create_function(|lua, ()| {
+        lua.globals().get::<_, Function>("thrower")?.call::<_, ()>(())
+    });
+    lua.globals().set("rethrow", rethrow).unwrap();
+    let code = lua.eval::<i64>(
+        r#"
+            local ok, err = pcall(rethrow)
+            assert(err.kind == "runtime")
+            return err.value.code
+        "#,
+        None,
+    ).unwrap();
+    assert_eq!(code, 500);
+}
+
+#[cfg(feature = "anyhow")]
+#[test]
+fn test_anyhow_conversion() {
+    use anyhow;
+
+    #[derive(Debug)]
+    struct Custom;
+
+    impl fmt::Display for Custom {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            write!(fmt, "custom error")
+        }
+    }
+
+    impl error::Error for Custom {}
+
+    fn fails() -> anyhow::Result<()> {
+        Err(Custom)?
+    }
+
+    let lua = Lua::new();
+    let f = lua.create_function(|_, ()| Ok(fails()?));
+    let err = f.call::<_, ()>(()).unwrap_err();
+    assert!(err.downcast_ref::<Custom>().is_some());
+    assert!(err.to_string().starts_with("callback error"));
+
+    // Errors from rlua are passed through unchanged.
+    let err = Error::from(anyhow::Error::from(Error::UserDataTypeMismatch));
+    match err {
+        Error::UserDataTypeMismatch => {}
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+#[cfg(feature = "eyre")]
+#[test]
+fn test_eyre_conversion() {
+    use eyre;
+
+    let lua = Lua::new();
+    let f = lua.create_function(|_, ()| -> Result<()> { Err(eyre::eyre!("eyre error"))? });
+    match f.call::<_, ()>(()).unwrap_err() {
+        Error::CallbackError { ref cause, .. } => assert_eq!(cause.to_string(), "eyre error"),
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+#[test]
+fn test_result_conversions() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let err = lua.create_function(|_, ()| {
+        Ok(Err::<String, _>(
+            "only through failure can we succeed".to_lua_err(),
+        ))
+    });
+    let ok = lua.create_function(|_, ()| Ok(Ok::<_, Error>("!".to_owned())));
+
+    globals.set("err", err).unwrap();
+    globals.set("ok", ok).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            local r, e = err()
+            assert(r == nil)
+            assert(tostring(e) == "only through failure can we succeed")
+
+            local r, e = ok()
+            assert(r == "!")
+            assert(e == nil)
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn test_lua_convention() {
+    use std::io;
+    use LuaConvention;
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let open = lua.create_function(|_, path: String| {
+        Ok(LuaConvention(if path == "exists" {
+            Ok((true, 42))
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotFound, "no such file"))
+        }))
+    });
+    globals.set("open", open).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            local ok, size = open("exists")
+            assert(ok == true and size == 42)
+
+            local r, e, extra = open("missing")
+            assert(r == nil)
+            assert(e == "no such file")
+            assert(extra == nil)
+            assert(select('#', open("missing")) == 2)
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn test_num_conversion() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("n", "1.0").unwrap();
+    assert_eq!(globals.get::<_, i64>("n").unwrap(), 1);
+    assert_eq!(globals.get::<_, f64>("n").unwrap(), 1.0);
+    assert_eq!(globals.get::<_, String>("n").unwrap(), "1.0");
+
+    globals.set("n", "1.5").unwrap();
+    assert!(globals.get::<_, i64>("n").is_err());
+    assert_eq!(globals.get::<_, f64>("n").unwrap(), 1.5);
+    assert_eq!(globals.get::<_, String>("n").unwrap(), "1.5");
+
+    globals.set("n", 1.5).unwrap();
+    assert!(globals.get::<_, i64>("n").is_err());
+    assert_eq!(globals.get::<_, f64>("n").unwrap(), 1.5);
+    assert_eq!(globals.get::<_, String>("n").unwrap(), "1.5");
+
+    lua.exec::<()>("a = math.huge", None).unwrap();
+    assert!(globals.get::<_, i64>("n").is_err());
+}
+
+#[test]
+fn test_collection_conversions() {
+    use std::collections::{BTreeSet, HashSet, VecDeque};
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let set: HashSet<i64> = [1, 2, 3].iter().cloned().collect();
+    globals.set("set", set.clone()).unwrap();
+    let queue: VecDeque<i64> = [4, 5].iter().cloned().collect();
+    globals.set("queue", queue.clone()).unwrap();
+    globals.set("slice", vec![6, 7].into_boxed_slice()).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(set[1] == true and set[3] == true and set[4] == nil)
+            assert(#queue == 2 and queue[1] == 4)
+            assert(#slice == 2 and slice[2] == 7)
+            names = { b = true, a = true, c = false }
+        "#,
+        None,
+    ).unwrap();
+
+    assert_eq!(globals.get::<_, HashSet<i64>>("set").unwrap(), set);
+    assert_eq!(globals.get::<_, VecDeque<i64>>("queue").unwrap(), queue);
+    assert_eq!(&*globals.get::<_, Box<[i64]>>("slice").unwrap(), &[6, 7]);
+    assert_eq!(
+        globals
+            .get::<_, BTreeSet<String>>("names")
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        vec!["a", "b", "c"]
+    );
+    assert!(globals.get::<_, BTreeSet<i64>>("names").is_err());
+    assert!(globals.get::<_, HashSet<i64>>("none").is_err());
+}
+
+#[test]
+fn test_str_conversions() {
+    use std::borrow::Cow;
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("borrowed", Cow::Borrowed("a")).unwrap();
+    globals.set("owned", Cow::Owned::<str>("b".to_owned())).unwrap();
+    globals.set("boxed", "c".to_owned().into_boxed_str()).unwrap();
+    assert_eq!(lua.eval::<String>("borrowed .. owned .. boxed", None).unwrap(), "abc");
+
+    assert_eq!(globals.get::<_, Cow<str>>("owned").unwrap(), "b");
+    assert_eq!(&*globals.get::<_, Box<str>>("boxed").unwrap(), "c");
+    assert!(globals.get::<_, Box<str>>("missing").is_err());
+}
+
+#[test]
+fn test_char_conversion() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("c", 'é').unwrap();
+    assert_eq!(lua.eval::<String>("c", None).unwrap(), "é");
+    assert_eq!(globals.get::<_, char>("c").unwrap(), 'é');
+    assert_eq!(lua.eval::<char>("7", None).unwrap(), '7');
+
+    match lua.eval::<char>("'ab'", None) {
+        Err(Error::FromLuaConversionError {
+            message: Some(message),
+            ..
+        }) => assert_eq!(message, "expected a string of one character, got 2 characters"),
+        r => panic!("wrong result for multi-character string: {:?}", r),
+    }
+    assert!(lua.eval::<char>("''", None).is_err());
+    assert!(lua.eval::<char>("{}", None).is_err());
+}
+
+#[test]
+fn test_path_conversions() {
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("path", Path::new("/tmp/file.txt")).unwrap();
+    assert_eq!(
+        globals.get::<_, PathBuf>("path").unwrap(),
+        PathBuf::from("/tmp/file.txt")
+    );
+    assert_eq!(lua.eval::<String>("path", None).unwrap(), "/tmp/file.txt");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        let non_utf8 = OsString::from_vec(b"file\xff".to_vec());
+        globals.set("name", non_utf8.clone()).unwrap();
+        assert!(lua.eval::<bool>(r#"name == "file\xff""#, None).unwrap());
+        let name = globals.get::<_, OsString>("name").unwrap();
+        assert_eq!(name.as_bytes(), b"file\xff");
+        assert_eq!(globals.get::<_, PathBuf>("name").unwrap(), PathBuf::from(non_utf8));
+    }
+}
+
+#[test]
+fn test_time_conversions() {
+    use std::time::{Duration, UNIX_EPOCH};
+    use DurationFormat;
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("duration", Duration::from_millis(2500)).unwrap();
+    globals.set("time", UNIX_EPOCH + Duration::from_secs(60)).unwrap();
+    globals.set("before", UNIX_EPOCH - Duration::from_secs(30)).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(duration == 2.5)
+            assert(time == 60 and before == -30)
+        "#,
+        None,
+    ).unwrap();
+
+    assert_eq!(
+        globals.get::<_, Duration>("duration").unwrap(),
+        Duration::from_millis(2500)
+    );
+    assert_eq!(
+        globals.get::<_, ::std::time::SystemTime>("before").unwrap(),
+        UNIX_EPOCH - Duration::from_secs(30)
+    );
+    assert!(lua.eval::<Duration>("-1", None).is_err());
+    assert!(lua.eval::<Duration>("0 / 0", None).is_err());
+
+    lua.set_duration_format(DurationFormat::Milliseconds);
+    globals.set("duration", Duration::from_millis(2500)).unwrap();
+    assert_eq!(lua.eval::<Value>("duration", None).unwrap().type_name(), "integer");
+    assert_eq!(lua.eval::<i64>("duration", None).unwrap(), 2500);
+    assert_eq!(
+        lua.eval::<Duration>("10", None).unwrap(),
+        Duration::from_millis(10)
+    );
+}
+
+#[test]
+fn test_array_conversions() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("color", [0.5, 0.25, 1.0]).unwrap();
+    globals.set("matrix", [[1, 2], [3, 4]]).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(#color == 3 and color[2] == 0.25)
+            assert(matrix[2][1] == 3)
+        "#,
+        None,
+    ).unwrap();
+
+    assert_eq!(globals.get::<_, [f64; 3]>("color").unwrap(), [0.5, 0.25, 1.0]);
+    assert_eq!(globals.get::<_, [[i64; 2]; 2]>("matrix").unwrap(), [[1, 2], [3, 4]]);
+    assert!(globals.get::<_, [i64; 2]>("missing").is_err());
+
+    match globals.get::<_, [f64; 2]>("color") {
+        Err(Error::FromLuaConversionError {
+            message: Some(message),
+            ..
+        }) => assert_eq!(message, "expected table of length 2, got 3"),
+        r => panic!("wrong result for mismatched array length: {:?}", r),
+    }
+}
+
+#[test]
+fn test_checked_int_conversion() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("big", u64::MAX - 2047).unwrap();
+    assert_eq!(lua.eval::<String>("math.type(big)", None).unwrap(), "float");
+    assert_eq!(globals.get::<_, u64>("big").unwrap(), u64::MAX - 2047);
+    assert!(globals.get::<_, i64>("big").is_err());
+    assert!(globals.set("big", u64::MAX).is_err());
+
+    globals.set("wide", -(1i128 << 100)).unwrap();
+    assert_eq!(globals.get::<_, i128>("wide").unwrap(), -(1i128 << 100));
+    globals.set("small", 5u128).unwrap();
+    assert_eq!(lua.eval::<String>("math.type(small)", None).unwrap(), "integer");
+    assert_eq!(globals.get::<_, u128>("small").unwrap(), 5);
+    assert!(globals.set("wide", i128::MAX).is_err());
+
+    assert_eq!(lua.eval::<u8>("255", None).unwrap(), 255);
+    assert_eq!(lua.eval::<i8>("-128.0", None).unwrap(), -128);
+    match lua.eval::<u8>("256", None) {
+        Err(Error::FromLuaConversionError {
+            message: Some(message),
+            ..
+        }) => assert_eq!(message, "number 256 is out of range"),
+        r => panic!("wrong result for out of range integer: {:?}", r),
+    }
+    assert!(lua.eval::<u32>("-1", None).is_err());
+    assert!(lua.eval::<u64>("1e20", None).is_err());
+}
+
+#[test]
+fn test_strict_numbers() {
+    let lua = Lua::new();
+
+    assert_eq!(lua.eval::<i32>("'42'", None).unwrap(), 42);
+    assert_eq!(lua.eval::<f32>("1e300", None).unwrap(), f32::INFINITY);
+    match lua.eval::<i32>("3.7", None) {
+        Err(Error::FromLuaConversionError {
+            message: Some(message),
+            ..
+        }) => assert_eq!(message, "number 3.7 has a fractional part"),
+        r => panic!("wrong result for fractional number: {:?}", r),
+    }
+
+    lua.set_strict_numbers(true);
+    assert_eq!(lua.eval::<i32>("42.0", None).unwrap(), 42);
+    assert_eq!(lua.eval::<f32>("0.5", None).unwrap(), 0.5);
+    assert_eq!(lua.eval::<f64>("1 << 53", None).unwrap(), 9007199254740992.0);
+    match lua.eval::<i32>("'42'", None) {
+        Err(Error::FromLuaConversionError {
+            message: Some(message),
+            ..
+        }) => assert_eq!(
+            message,
+            "string \"42\" is not a number, and strict numeric conversions are enabled"
+        ),
+        r => panic!("wrong result for string in strict mode: {:?}", r),
+    }
+    assert!(lua.eval::<f64>("'1.5'", None).is_err());
+    assert!(lua.eval::<f32>("1e300", None).is_err());
+    match lua.eval::<f32>("0.1", None) {
+        Err(Error::FromLuaConversionError {
+            message: Some(message),
+            ..
+        }) => assert_eq!(message, "number 0.1 cannot be represented exactly"),
+        r => panic!("wrong result for imprecise number: {:?}", r),
+    }
+    assert_eq!(lua.eval::<f64>("0.1", None).unwrap(), 0.1);
+    assert!(lua.eval::<f32>("0 / 0", None).unwrap().is_nan());
+    assert!(lua.eval::<f64>("(1 << 53) + 1", None).is_err());
+    assert_eq!(lua.eval::<f64>("1 / 0", None).unwrap(), f64::INFINITY);
+}
+
+#[test]
+fn test_proxy_table() {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+    use TableBackend;
+
+    struct Settings(Rc<RefCell<BTreeMap<String, i64>>>);
+
+    impl TableBackend for Settings {
+        type Key = String;
+        type Value = i64;
+
+        fn get(&self, key: String) -> Result<Option<i64>> {
+            Ok(self.0.borrow().get(&key).cloned())
+        }
+
+        fn set(&mut self, key: String, value: Option<i64>) -> Result<()> {
+            if key == "locked" {
+                return Err(Error::RuntimeError("setting is locked".to_owned()));
+            }
+            match value {
+                Some(value) => self.0.borrow_mut().insert(key, value),
+                None => self.0.borrow_mut().remove(&key),
+            };
+            Ok(())
+        }
+
+        fn len(&self) -> usize {
+            self.0.borrow().len()
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = (String, i64)>> {
+            let settings = self.0.clone();
+            let keys = settings.borrow().keys().cloned().collect::<Vec<_>>();
+            Box::new(keys.into_iter().filter_map(move |key| {
+                let value = settings.borrow().get(&key).cloned();
+                value.map(|value| (key, value))
+            }))
+        }
+    }
+
+    let lua = Lua::new();
+    let settings = Rc::new(RefCell::new(BTreeMap::new()));
+    settings.borrow_mut().insert("volume".to_owned(), 7);
+    settings.borrow_mut().insert("locked".to_owned(), 1);
+    let proxy = lua.create_proxy_table(Settings(settings.clone())).unwrap();
+    lua.globals().set("settings", proxy).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            assert(type(settings) == "table")
+            assert(settings.volume == 7 and settings.missing == nil and settings[1] == nil)
+            settings.brightness = 3
+            settings.volume = nil
+            assert(#settings == 2)
+            local seen = {}
+            for key, value in pairs(settings) do seen[#seen + 1] = key .. "=" .. value end
+            assert(table.concat(seen, ",") == "brightness=3,locked=1")
+            assert(rawget(settings, "brightness") == nil)
+            assert(getmetatable(settings) == false)
+            assert(not pcall(setmetatable, settings, {}))
+        "#,
+        None,
+    ).unwrap();
+    assert_eq!(settings.borrow().get("brightness"), Some(&3));
+    assert_eq!(settings.borrow().get("volume"), None);
+
+    assert!(lua.exec::<()>("settings.locked = 0", None).is_err());
+    assert!(lua.exec::<()>("settings[{}] = 0", None).is_err());
+    assert!(lua.exec::<()>("settings.volume = 'loud'", None).is_err());
+    assert_eq!(settings.borrow().get("locked"), Some(&1));
+}
+
+#[test]
+fn test_lazy_table() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let lua = Lua::new();
+    let loads = Rc::new(Cell::new(0));
+    let globals = lua.globals();
+    for &name in &["a", "b", "c", "d"] {
+        let loads = loads.clone();
+        let table = lua.create_lazy_table(move |lua| {
+            loads.set(loads.get() + 1);
+            let table = lua.create_sequence_from(vec![10, 20, 30])?;
+            table.set("name", "lazy")?;
+            Ok(table)
+        }).unwrap();
+        globals.set(name, table).unwrap();
+    }
+
+    lua.exec::<()>(
+        r#"
+            assert(rawget(a, 1) == nil and next(a) == nil)
+            assert(a[2] == 20 and rawget(a, 1) == 10 and getmetatable(a) == nil)
+            b.extra = true
+            assert(b.extra and b.name == "lazy")
+            assert(#c == 3)
+            local sum = 0
+            for k, v in pairs(d) do
+                if type(v) == "number" then sum = sum + v end
+            end
+            assert(sum == 60)
+        "#,
+        None,
+    ).unwrap();
+    assert_eq!(loads.get(), 4);
+
+    let failing = lua.create_lazy_table(|_| Err(Error::RuntimeError("no data".to_owned())))
+        .unwrap();
+    globals.set("failing", failing).unwrap();
+    match lua.exec::<()>("return failing.x", None) {
+        Err(Error::CallbackError { ref cause, .. }) => {
+            assert_eq!(cause.to_string(), "runtime error: no data")
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(lua.exec::<()>("return failing.x", None).is_err());
+}
+
+#[test]
+fn test_freeze_deep() {
+    let lua = Lua::new();
+    let config = lua.eval::<Value>(
+        r#"
+            local defaults = { retries = 3 }
+            local config = setmetatable({
+                name = "server",
+                ports = { 80, 443 },
+                limits = { memory = { max = 64 } },
+            }, {
+                __index = defaults,
+                __tostring = function() return "config" end,
+            })
+            config.self = config
+            config[{ key = true }] = "table key"
+            return config
+        "#,
+        None,
+    ).unwrap();
+    config.freeze_deep();
+    lua.globals().set("config", config).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            assert(config.name == "server" and config.self == config)
+            assert(config.limits.memory.max == 64 and config.retries == 3)
+            assert(tostring(config) == "config" and getmetatable(config) == false)
+            assert(#config.ports == 2 and rawget(config, "name") == nil)
+            local sum = 0
+            for _, port in ipairs(config.ports) do sum = sum + port end
+            assert(sum == 523)
+            local count = 0
+            for k in pairs(config) do
+                if type(k) == "table" then assert(k.key) end
+                count = count + 1
+            end
+            assert(count == 5)
+            assert(rawset({}, "x", 1).x == 1)
+        "#,
+        None,
+    ).unwrap();
+
+    for code in &[
+        "config.name = 'client'",
+        "config.limits.memory.max = 1024",
+        "config.new = true",
+        "rawset(config.ports, 1, 8080)",
+        "table.insert(config.ports, 8080)",
+        "setmetatable(config, nil)",
+        "for k in pairs(config) do if type(k) == 'table' then k.key = false end end",
+        "getmetatable(config.self).__index.retries = 0",
+    ] {
+        assert!(lua.exec::<()>(code, None).is_err(), "{} succeeded", code);
+    }
+    match lua.exec::<()>("config.ports[1] = 8080", Some("plugin")) {
+        Err(Error::RuntimeError(msg)) => {
+            assert!(msg.starts_with("[string \"plugin\"]:1: attempt to modify a frozen table"))
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    lua.exec::<()>("assert(config.ports[1] == 80 and config.retries == 3)", None)
+        .unwrap();
+}
+
+#[test]
+fn test_weak_refs() {
+    use {Nil, UserData};
+
+    struct Cached;
+    impl UserData for Cached {}
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+    let table = lua.create_table();
+    table.set("name", "cached").unwrap();
+    let function = lua.create_function(|_, ()| Ok(42));
+    let thread = lua.create_thread(function.clone());
+    let userdata = lua.create_userdata(Cached);
+
+    let weak_table = table.downgrade();
+    let weak_function = function.downgrade();
+    let weak_thread = thread.downgrade();
+    let weak_userdata = userdata.downgrade();
+    let cloned = weak_table.clone();
+    globals.set("kept", function.clone()).unwrap();
+    drop((table, function, thread, userdata));
+
+    assert_eq!(
+        weak_table.upgrade().unwrap().get::<_, String>("name").unwrap(),
+        "cached"
+    );
+    assert!(weak_userdata.upgrade().is_some());
+    lua.exec::<()>("collectgarbage()", None).unwrap();
+
+    assert!(weak_table.upgrade().is_none());
+    assert!(cloned.upgrade().is_none());
+    assert!(weak_thread.upgrade().is_none());
+    assert!(weak_userdata.upgrade().is_none());
+    assert!(weak_table.clone().upgrade().is_none());
+    assert_eq!(weak_function.upgrade().unwrap().call::<_, i64>(()).unwrap(), 42);
+
+    globals.set("kept", Nil).unwrap();
+    lua.exec::<()>("collectgarbage()", None).unwrap();
+    assert!(weak_function.upgrade().is_none());
+
+    // Keys are not reused once the value of a weak reference has been collected.
+    let replacement = lua.create_table();
+    let weak_replacement = replacement.downgrade();
+    assert!(weak_table.upgrade().is_none());
+    assert!(weak_replacement.upgrade().is_some());
+}
+
+#[test]
+fn test_persist() {
+    let lua = Lua::new();
+    lua.exec::<()>(
+        r#"
+            local shared = 0
+            local function add(n) shared = shared + n return shared end
+            local function get() return shared end
+            local vector = { __add = function(a, b) return a.x + b.x end }
+            vector.__index = vector
+            game = {
+                add = add,
+                get = get,
+                position = setmetatable({ x = 3 }, vector),
+                script = coroutine.create(function(a) coroutine.yield(a) return a * 2 end),
+                finished = coroutine.create(function() end),
+                print = print,
+                [1.5] = "float key",
+            }
+            game.self = game
+            coroutine.resume(game.finished)
+            add(10)
+        "#,
+        None,
+    ).unwrap();
+
+    let permanents = lua.create_table();
+    permanents.set("_G", lua.globals()).unwrap();
+    permanents.set("print", lua.globals().get::<_, Function>("print").unwrap())
+        .unwrap();
+    let game = lua.globals().get::<_, Table>("game").unwrap();
+    let saved = lua.persist(game, permanents.clone()).unwrap();
+
+    let other = Lua::new();
+    let other_permanents = other.create_table();
+    other_permanents.set("_G", other.globals()).unwrap();
+    other_permanents.set("print", other.globals().get::<_, Function>("print").unwrap())
+        .unwrap();
+    let restored = unsafe { other.unpersist::<Table>(&saved, other_permanents.clone()) }.unwrap();
+    other.globals().set("game", restored).unwrap();
+    other.exec::<()>(
+        r#"
+            assert(game.self == game and game[1.5] == "float key" and game.print == print)
+            assert(game.get() == 10 and game.add(5) == 15 and game.get() == 15)
+            assert(game.position + game.position == 6)
+            assert(coroutine.status(game.finished) == "dead")
+            assert(select(2, coroutine.resume(game.script, 4)) == 4)
+            assert(select(2, coroutine.resume(game.script)) == 8)
+        "#,
+        None,
+    ).unwrap();
+
+    lua.exec::<()>("coroutine.resume(game.script, 1)", None).unwrap();
+    match lua.persist(lua.globals().get::<_, Value>("game").unwrap(), permanents.clone()) {
+        Err(Error::SerializeError(msg)) => assert!(msg.contains("a C or Rust function")),
+        r => panic!("unexpected result {:?}", r),
+    }
+    lua.exec::<()>("game.script = nil game.print = nil", None).unwrap();
+    let callback = lua.create_function(|_, ()| Ok(()));
+    match lua.persist(callback, permanents.clone()) {
+        Err(Error::SerializeError(msg)) => assert!(msg.contains("a C or Rust function")),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let missing = other.create_table();
+    match unsafe { other.unpersist::<Table>(&saved, missing) } {
+        Err(Error::DeserializeError(msg)) => assert!(msg.contains("permanent '")),
+        r => panic!("unexpected result {:?}", r),
+    }
+    match unsafe { other.unpersist::<Table>(&saved[..saved.len() - 1], other_permanents) } {
+        Err(Error::DeserializeError(msg)) => assert!(msg.contains("corrupt")),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // Values nested too deeply to be persisted or unpersisted on the stack return errors.
+    let nested = "local t = {} for i = 1, NESTING do t = {t} end return t";
+    let value = lua.eval::<Value>(&nested.replace("NESTING", "150"), None).unwrap();
+    let saved = lua.persist(value, permanents.clone()).unwrap();
+    assert!(unsafe { lua.unpersist::<Table>(&saved, permanents.clone()) }.is_ok());
+    let value = lua.eval::<Value>(&nested.replace("NESTING", "200000"), None).unwrap();
+    match lua.persist(value, permanents.clone()) {
+        Err(Error::SerializeError(msg)) => assert!(msg.contains("nested")),
+        r => panic!("unexpected result {:?}", r),
+    }
+    let mut crafted = b"\x1bRluaPersist\x01".to_vec();
+    for _ in 0..200000 {
+        crafted.extend_from_slice(&[8, 1, 8]);
+    }
+    match unsafe { lua.unpersist::<Value>(&crafted, permanents) } {
+        Err(Error::DeserializeError(msg)) => assert!(msg.contains("nested")),
+        r => panic!("unexpected result {:?}", r),
+    }
+;
+}
+
+#[cfg(feature = "builtin-lua")]
+#[test]
+fn test_persist_suspended_coroutine() {
+    let lua = Lua::new();
+    lua.exec::<()>(
+        r#"
+            local total = 0
+            script = coroutine.create(function(step)
+                local count = 0
+                report = function() return count, total end
+                for i = 1, 5 do
+                    count = count + step
+                    total = total + i
+                    step = coroutine.yield(count) or step
+                end
+                return "done", count
+            end)
+            coroutine.resume(script, 2)
+            coroutine.resume(script)
+        "#,
+        None,
+    ).unwrap();
+
+    let permanents = lua.create_table();
+    permanents.set("_G", lua.globals()).unwrap();
+    permanents.set("yield", lua.eval::<Function>("coroutine.yield", None).unwrap())
+        .unwrap();
+    let state = lua.create_table();
+    state.set("script", lua.globals().get::<_, Thread>("script").unwrap()).unwrap();
+    state.set("report", lua.globals().get::<_, Function>("report").unwrap()).unwrap();
+    let saved = lua.persist(state, permanents).unwrap();
+
+    let other = Lua::new();
+    let other_permanents = other.create_table();
+    other_permanents.set("_G", other.globals()).unwrap();
+    other_permanents.set("yield", other.eval::<Function>("coroutine.yield", None).unwrap())
+        .unwrap();
+    let restored = unsafe { other.unpersist::<Table>(&saved, other_permanents) }.unwrap();
+    other.globals().set("state", restored).unwrap();
+    other.exec::<()>(
+        r#"
+            local script, report = state.script, state.report
+            assert(coroutine.status(script) == "suspended")
+            local count, total = report()
+            assert(count == 4 and total == 3)
+            assert(select(2, coroutine.resume(script, 10)) == 14)
+            count, total = report()
+            assert(count == 14 and total == 6)
+            coroutine.resume(script)
+            coroutine.resume(script)
+            local ok, done, final = coroutine.resume(script)
+            assert(ok and done == "done" and final == 34)
+            assert(coroutine.status(script) == "dead")
+        "#,
+        None,
+    ).unwrap();
+
+    // A Rust callback which yields to be called again cannot be resumed elsewhere.
+    let callback = lua.create_thread_from(|yielder, ()| yielder.yield_values(()));
+    callback.resume::<_, ()>(()).unwrap();
+    let permanents = lua.create_table();
+    match lua.persist(callback, permanents) {
+        Err(Error::SerializeError(msg)) => assert!(msg.contains("suspended inside a C function")),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_buffer() {
+    use Buffer;
+
+    let lua = Lua::new();
+    let buffer = lua.create_userdata(Buffer::from(b"hello".to_vec()));
+    lua.globals().set("buffer", buffer.clone()).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(buffer:len() == 5 and #buffer == 5)
+            assert(buffer:get(1) == 104 and buffer:get(0) == nil and buffer:get(6) == nil)
+            buffer:set(1, 72)
+            assert(buffer:tostring() == "Hello" and buffer:tostring(-3) == "llo")
+            local slice = buffer:slice(2, -2)
+            assert(slice:tostring() == "ell" and buffer:slice(4, 2):len() == 0)
+            slice:set(1, 69)
+            assert(buffer:get(2) == 101)
+            assert(not pcall(buffer.set, buffer, 6, 0))
+            assert(not pcall(buffer.set, buffer, 1, 256))
+        "#,
+        None,
+    ).unwrap();
+
+    assert_eq!(buffer.borrow::<Buffer>().unwrap().as_slice(), b"Hello");
+    buffer.borrow_mut::<Buffer>().unwrap().as_mut_vec().extend_from_slice(b"!");
+    assert_eq!(lua.eval::<String>("buffer:tostring()", None).unwrap(), "Hello!");
+    let copy = lua.globals().get::<_, Buffer>("buffer").unwrap();
+    assert_eq!(copy.into_vec(), b"Hello!");
+}
+
+#[test]
+fn test_typed_light_userdata() {
+    use std::os::raw::c_void;
+    use {LightUserData, TypedLightUserData};
+
+    struct Texture(u32);
+    struct Mesh;
+
+    let lua = Lua::new();
+    let mut texture = Texture(7);
+    let mut mesh = Mesh;
+    let texture_ptr = &mut texture as *mut Texture;
+    let globals = lua.globals();
+    globals.set("texture", TypedLightUserData::new(texture_ptr)).unwrap();
+    globals.set("mesh", TypedLightUserData::new(&mut mesh as *mut Mesh)).unwrap();
+    globals.set("raw", LightUserData(8 as *mut c_void)).unwrap();
+
+    let handle = globals.get::<_, TypedLightUserData<Texture>>("texture").unwrap();
+    assert_eq!(handle.as_ptr(), texture_ptr);
+    assert_eq!(unsafe { (*handle.as_ptr()).0 }, 7);
+    assert!(globals.get::<_, TypedLightUserData<Mesh>>("mesh").is_ok());
+    assert_eq!(globals.get::<_, LightUserData>("mesh").unwrap().0, &mut mesh as *mut Mesh as _);
+
+    for name in &["mesh", "raw", "print"] {
+        match globals.get::<_, TypedLightUserData<Texture>>(*name) {
+            Err(Error::FromLuaConversionError { .. }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    lua.forget_light_userdata(texture_ptr);
+    assert!(globals.get::<_, TypedLightUserData<Texture>>("texture").is_err());
+}
+
+#[test]
+fn test_iter_values() {
+    use IterValues;
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+    let squares = lua.create_function(|_, n: i64| Ok(IterValues((1..n + 1).map(|i| i * i))));
+    globals.set("squares", squares).unwrap();
+    let words = lua.create_function(|_, s: String| {
+        Ok(IterValues(s.split_whitespace().map(str::to_owned).collect::<Vec<_>>()))
+    });
+    globals.set("words", words).unwrap();
+    let failing = lua.create_function(|_, ()| Ok(IterValues(vec![1, u64::MAX])));
+    globals.set("failing", failing).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            assert(select('#', squares(0)) == 0)
+            assert(select('#', squares(5000)) == 5000)
+            assert(select(5000, squares(5000)) == 25000000)
+            local a, b, c = words("one two three")
+            assert(a == "one" and b == "two" and c == "three")
+            assert(not pcall(failing))
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn test_impl_lua_trait() {
+    #[derive(Debug)]
+    struct PluginError(String);
+
+    impl From<Error> for PluginError {
+        fn from(err: Error) -> PluginError {
+            PluginError(err.to_string())
+        }
+    }
+
+    trait Shape {
+        fn area(&self) -> Result<f64>;
+        fn scale(&self, x: f64, y: f64) -> Result<()>;
+        fn describe(&self, prefix: String) -> ::std::result::Result<String, PluginError>;
+    }
+
+    impl_lua_trait! {
+        struct LuaShape: Shape {
+            fn area(&self) -> Result<f64>;
+            fn scale(&self, x: f64, y: f64) -> Result<()>;
+            fn describe(&self, prefix: String) -> ::std::result::Result<String, PluginError>;
+        }
+    }
+
+    let lua = Lua::new();
+    let rectangle: Table = lua.eval(
+        r#"
+            local rectangle = { width = 2, height = 3 }
+            function rectangle:area()
+                return self.width * self.height
+            end
+            function rectangle.scale(self, x, y)
+                self.width = self.width * x
+                self.height = self.height * y
+            end
+            return rectangle
+        "#,
+        None,
+    ).unwrap();
+
+    let shape = LuaShape::boxed(rectangle);
+    assert_eq!(shape.area().unwrap(), 6.0);
+    shape.scale(2.0, 0.5).unwrap();
+    assert_eq!(shape.area().unwrap(), 6.0);
+    match shape.describe("a".to_owned()) {
+        Err(PluginError(ref message)) => assert!(message.contains("no method 'describe'")),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let total_area = lua.create_function(|_, shapes: Vec<LuaShape>| {
+        let mut total = 0.0;
+        for shape in shapes {
+            total += shape.area()?;
+        }
+        Ok(total)
+    });
+    lua.globals().set("total_area", total_area).unwrap();
+    let total: f64 = lua.eval(
+        r#"
+            local function square(side)
+                return { area = function(self) return side * side end }
+            end
+            return total_area({ square(1), square(2) })
+        "#,
+        None,
+    ).unwrap();
+    assert_eq!(total, 5.0);
+}
+
+#[test]
+fn test_events() {
+    use Events;
+
+    let lua = Lua::new();
+    let events = Events::new();
+    events.define::<(String, i64)>("player_died");
+    events.define::<()>("tick");
+    lua.globals().set("events", events.clone()).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            deaths = {}
+            ticks = 0
+            events:on("player_died", function(name, score)
+                table.insert(deaths, name .. ":" .. score)
+            end)
+            events:on("player_died", function()
+                error("broken listener")
+            end)
+            events:on("player_died", function(name)
+                table.insert(deaths, "second " .. name)
+            end)
+            function on_tick()
+                ticks = ticks + 1
+            end
+            events:on("tick", on_tick)
+            assert(not pcall(events.on, events, "unknown", print))
+        "#,
+        None,
+    ).unwrap();
+    assert_eq!(events.listener_count(&lua, "player_died").unwrap(), 3);
+
+    let errors = events.emit(&lua, "player_died", ("alice".to_owned(), 3i64)).unwrap();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].to_string().contains("broken listener"));
+    let deaths = lua.globals().get::<_, Vec<String>>("deaths").unwrap();
+    assert_eq!(deaths, vec!["alice:3", "second alice"]);
+
+    assert!(events.emit(&lua, "tick", ()).unwrap().is_empty());
+    assert!(events.emit(&lua, "tick", 1).is_err());
+    assert!(events.emit(&lua, "unknown", ()).is_err());
+    assert_eq!(lua.globals().get::<_, i64>("ticks").unwrap(), 1);
+
+    lua.exec::<()>(
+        r#"
+            assert(events:off("tick", on_tick))
+            assert(not events:off("tick", on_tick))
+        "#,
+        None,
+    ).unwrap();
+    assert!(events.emit(&lua, "tick", ()).unwrap().is_empty());
+    assert_eq!(lua.globals().get::<_, i64>("ticks").unwrap(), 1);
+
+    let other = Lua::new();
+    assert_eq!(events.listener_count(&other, "player_died").unwrap(), 0);
+    assert!(!Events::new().is_defined("tick"));
+}
+
+#[test]
+fn test_exec_incremental() {
+    use {Budget, ExecutionStep};
+
+    let lua = Lua::new();
+    let calls = lua.create_function(|lua, ()| {
+        lua.exec::<()>("for i = 1, 10000 do end", None)
+    });
+    lua.globals().set("rust_loop", calls).unwrap();
+
+    let execution = lua.exec_incremental(
+        r#"
+            local co = coroutine.wrap(function()
+                for i = 1, 10000 do end
+                coroutine.yield(1)
+                return 2
+            end)
+            local a = co()
+            rust_loop()
+            local b = co()
+            local n = 0
+            for i = 1, 10000 do n = n + 1 end
+            return a + b + n
+        "#,
+        None,
+    ).unwrap();
+    let mut steps = 0;
+    let result = loop {
+        steps += 1;
+        match execution.step::<i64>(100).unwrap() {
+            ExecutionStep::Pending => assert!(!execution.is_finished()),
+            ExecutionStep::Done(result) => break result,
+        }
+    };
+    assert_eq!(result, 10003);
+    assert!(steps > 100);
+    assert!(execution.is_finished());
+    assert!(execution.step::<()>(100).is_err());
+
+    let execution = lua.exec_incremental("coroutine.yield(1) return 2", None).unwrap();
+    assert_eq!(execution.step::<i64>(1000).unwrap(), ExecutionStep::Pending);
+    assert_eq!(execution.step::<i64>(1000).unwrap(), ExecutionStep::Done(2));
+
+    let execution = lua.exec_incremental("for i = 1, 1000 do end error('late')", None)
+        .unwrap();
+    assert_eq!(execution.step::<()>(10).unwrap(), ExecutionStep::Pending);
+    match execution.step::<()>(100_000) {
+        Err(Error::RuntimeError(ref message)) => assert!(message.contains("late")),
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(execution.is_finished());
+
+    let budget = Budget::instructions(5000);
+    lua.set_budget(budget.clone());
+    let execution = lua.exec_incremental("while true do end", None).unwrap();
+    assert_eq!(execution.step::<()>(1000).unwrap(), ExecutionStep::Pending);
+    assert!(budget.remaining() < 5000);
+    assert!(execution.step::<()>(100_000).is_err());
+    lua.remove_budget();
+
+    assert!(lua.exec_incremental("(", None).is_err());
+    lua.exec::<()>("for i = 1, 100000 do end", None).unwrap();
+}
+
+#[test]
+fn test_pool_par_map() {
+    use LuaPool;
+
+    let pool = LuaPool::new(3);
+    assert_eq!(pool.size(), 3);
+
+    let items = (0..1000).map(|i| format!("item {}", i)).collect::<Vec<_>>();
+    let results = pool.par_map::<_, String>(
+        r#"
+            local function shout(s)
+                return s:upper()
+            end
+            return function(s)
+                if s == "item 500" then error("no 500") end
+                return shout(s)
+            end
+        "#,
+        items,
+    );
+    assert_eq!(results.len(), 1000);
+    for (i, result) in results.iter().enumerate() {
+        match *result {
+            Ok(ref s) => assert_eq!(*s, format!("ITEM {}", i)),
+            Err(ref err) => {
+                assert_eq!(i, 500);
+                assert!(err.to_string().contains("no 500"));
+            }
+        }
+    }
+
+    let results = pool.par_map::<i64, i64>("function(x", vec![1, 2]);
+    assert!(results.iter().all(|r| matches!(*r, Err(Error::SyntaxError { .. }))));
+    assert!(pool.par_map::<i64, i64>("function(x) return x end", vec![]).is_empty());
+    assert_eq!(LuaPool::new(0).size(), 1);
+}
+
+#[test]
+fn test_prelude() {
+    use LuaPool;
+
+    let lua = Lua::new();
+    let snapshot = lua.snapshot();
+    lua.set_prelude("function double(x) return x * 2 end").unwrap();
+    assert_eq!(lua.eval::<i64>("double(2)", None).unwrap(), 4);
+    assert!(lua.set_prelude("function broken(").is_err());
+    assert!(lua.set_prelude("error('failed')").is_err());
+
+    lua.exec::<()>("double = nil", None).unwrap();
+    lua.restore(&snapshot).unwrap();
+    let thread = lua.create_thread(lua.eval("function() return double(3) end", None).unwrap());
+    assert_eq!(thread.resume::<_, i64>(()).unwrap(), 6);
+
+    lua.remove_prelude();
+    lua.restore(&snapshot).unwrap();
+    assert!(lua.eval::<Option<Function>>("double", None).unwrap().is_none());
+
+    let pool = LuaPool::new(2);
+    pool.set_prelude("function triple(x) return x * 3 end").unwrap();
+    let results = pool.par_map::<i64, i64>("triple", vec![1, 2, 3]);
+    assert_eq!(
+        results.into_iter().collect::<Result<Vec<_>>>().unwrap(),
+        vec![3, 6, 9]
+    );
+    assert!(pool.set_prelude("error('failed')").is_err());
+}
+
+#[test]
+fn test_error_formatter() {
+    let lua = Lua::new();
+    let fail = lua.create_function(|_, ()| -> Result<()> {
+        Err(Error::RuntimeError("secret path /srv/data".to_owned()))
+    });
+    lua.globals().set("fail", fail).unwrap();
+    let inner = lua.create_function(|lua, ()| lua.exec::<()>("fail()", None));
+    lua.globals().set("inner", inner).unwrap();
+
+    lua.set_error_formatter(|err| match *err {
+        Error::RuntimeError(_) => "runtime error".to_owned(),
+        Error::CallbackError { ref cause, .. } => format!("callback failed: {}", cause),
+        _ => "internal error".to_owned(),
+    });
+    lua.exec::<()>(
+        r#"
+            local ok, err = pcall(fail)
+            assert(not ok)
+            assert(tostring(err) == "runtime error")
+            assert(err.message == "runtime error")
+            assert(err.kind == "runtime")
+
+            local ok, err = pcall(inner)
+            assert(tostring(err) == "callback failed: runtime error: secret path /srv/data")
+            assert(tostring(err.cause) == "runtime error")
+        "#,
+        None,
+    ).unwrap();
+
+    match lua.exec::<()>("fail()", None) {
+        Err(Error::CallbackError { ref cause, .. }) => {
+            assert_eq!(cause.to_string(), "runtime error: secret path /srv/data")
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    lua.remove_error_formatter();
+    lua.exec::<()>(
+        r#"
+            local ok, err = pcall(fail)
+            assert(tostring(err) == "runtime error: secret path /srv/data")
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn test_multi_value_macros() {
+    use {Function, Table, Variadic};
+
+    struct Query<'lua> {
+        table: Table<'lua>,
+        key: String,
+        defaults: Variadic<i64>,
+    }
+
+    impl_from_lua_multi!(Query<'lua> { table, key, defaults });
+
+    struct Pair {
+        first: i64,
+        second: Option<String>,
+    }
+
+    impl_to_lua_multi!(Pair { first, second });
+    impl_from_lua_multi!(Pair { first, second });
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+    let lookup = lua.create_function(|_, query: Query| {
+        let value = query.table.get::<_, Option<i64>>(query.key.as_str())?;
+        Ok(value.or_else(|| query.defaults.first().cloned()))
+    });
+    globals.set("lookup", lookup).unwrap();
+    let pair = lua.create_function(|_, n: i64| {
+        Ok(Pair {
+            first: n,
+            second: Some(n.to_string()),
+        })
+    });
+    globals.set("pair", pair).unwrap();
+    type Sixteen = (
+        i64, i64, i64, i64, i64, i64, i64, i64, i64, i64, i64, i64, i64, i64, i64, i64,
+    );
+    let sum = lua.create_function(|_, (a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p): Sixteen| {
+        Ok(a + b + c + d + e + f + g + h + i + j + k + l + m + n + o + p)
+    });
+    globals.set("sum", sum).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            assert(lookup({ a = 1 }, "a") == 1)
+            assert(lookup({}, "a", 7, 8) == 7)
+            assert(lookup({}, "a") == nil)
+            local first, second = pair(3)
+            assert(first == 3 and second == "3")
+            assert(sum(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16) == 136)
+        "#,
+        None,
+    ).unwrap();
+
+    match lua.exec::<()>("lookup({}, 'a', 'x')", None).unwrap_err() {
+        Error::CallbackError { cause, .. } => match *cause {
+            Error::BadArgument { arg_index: 3, .. } => {}
+            ref err => panic!("unexpected cause {:?}", err),
+        },
+        err => panic!("unexpected error {:?}", err),
+    }
+
+    let swap: Function = lua.eval("function(a, b) return tonumber(b), tostring(a) end", None)
+        .unwrap();
+    let swapped = swap.call::<_, Pair>(Pair {
+        first: 1,
+        second: Some("2".to_owned()),
+    }).unwrap();
+    assert_eq!(swapped.first, 2);
+    assert_eq!(swapped.second, Some("1".to_owned()));
+}
+
+#[test]
+fn test_safe_os() {
+    use Nil;
+
+    let lua = Lua::new();
+    lua.globals().set("os", Nil).unwrap();
+    lua.use_safe_os(&["CARGO_PKG_NAME"]).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(os.getenv("CARGO_PKG_NAME") == "rlua")
+            assert(os.getenv("CARGO_MANIFEST_DIR") == nil)
+            assert(os.time({ year = 2000, month = 1, day = 1, hour = 12 }) > 0)
+            assert(os.clock() >= 0)
+            assert(os.date("!%H:%M", 3600) == "01:00")
+            for _, name in ipairs({ "execute", "exit", "remove", "rename", "setlocale" }) do
+                assert(os[name] == nil)
+            end
+            assert(require("os") == os)
+        "#,
+        None,
+    ).unwrap();
+
+    // Scripts of lazy states do not get the standard library back.
+    let lua = Lua::new_lazy();
+    lua.use_safe_os(&[]).unwrap();
+    lua.exec::<()>("assert(os.execute == nil and require('os') == os)", None).unwrap();
+}
+
+#[test]
+fn test_file_handle() {
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+    use MemoryVfs;
+
+    // A connection which answers with `input`, and records what the script sends.
+    struct Connection {
+        input: io::Cursor<Vec<u8>>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let lua = Lua::new();
+    lua.set_vfs(MemoryVfs::new()).unwrap();
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let connection = lua.create_file_handle(Connection {
+        input: io::Cursor::new(b"HELLO\n2\nfirst\nsecond\n".to_vec()),
+        output: output.clone(),
+    });
+    lua.globals().set("connection", connection).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            assert(io.type(connection) == "file")
+            local greeting, count = connection:read("l", "n")
+            assert(greeting == "HELLO" and count == 2)
+            assert(connection:write("ACK ", count, "\n") == connection)
+            assert(connection:read("l") == "" and connection:read("l") == "first")
+            local ok, err = connection:seek("set", 0)
+            assert(ok == nil and err == "illegal seek")
+
+            io.input(connection)
+            assert(io.read("L") == "second\n" and io.read("l") == nil)
+            connection:close()
+            assert(io.type(connection) == "closed file")
+            assert(io.open("/etc/passwd") == nil)
+        "#,
+        None,
+    ).unwrap();
+    assert_eq!(&*output.borrow(), b"ACK 2\n");
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_awaitable() {
+    use std::cell::Cell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use {Awaitable, Function};
+
+    // Ready after being polled `polls` times, counting every poll in `polled`.
+    struct Countdown {
+        polls: u32,
+        output: Option<Result<String>>,
+        polled: Rc<Cell<u32>>,
+    }
+
+    impl Future for Countdown {
+        type Output = Result<String>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<String>> {
+            self.polled.set(self.polled.get() + 1);
+            if self.polls == 0 {
+                Poll::Ready(self.output.take().unwrap())
+            } else {
+                self.polls -= 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    // Polls the future until it is ready, and returns its output with the number of polls.
+    fn block_on<F: Future>(future: F) -> (F::Output, u32) {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        let mut polls = 1;
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return (output, polls);
+            }
+            polls += 1;
+        }
+    }
+
+    let lua = Lua::new();
+    let polled = Rc::new(Cell::new(0));
+    let fetch_polled = polled.clone();
+    let fetch = lua.create_function(move |_, (key, polls): (String, u32)| {
+        let output = if key == "missing" {
+            Err(Error::RuntimeError("no such key".to_owned()))
+        } else {
+            Ok(key.to_uppercase())
+        };
+        Ok(Awaitable(Countdown {
+            polls,
+            output: Some(output),
+            polled: fetch_polled.clone(),
+        }))
+    });
+    lua.globals().set("fetch", fetch).unwrap();
+
+    let script: Function = lua.eval(
+        r#"
+            function(prefix)
+                local a = fetch("a", 2)
+                coroutine.yield()
+                return prefix .. a .. fetch("b", 1)
+            end
+        "#,
+        None,
+    ).unwrap();
+    let (output, polls) = block_on(script.call_async::<_, String>(">"));
+    assert_eq!(output.unwrap(), ">AB");
+    assert_eq!(polled.get(), 5);
+    assert_eq!(polls, 5);
+
+    // Errors of futures which are not caught end the call.
+    let failing: Function = lua.eval("function() return fetch('missing', 0) end", None).unwrap();
+    match block_on(failing.call_async::<_, String>(())).0 {
+        Err(Error::CallbackError { ref cause, .. }) => match **cause {
+            Error::RuntimeError(ref msg) => assert_eq!(msg, "no such key"),
+            ref err => panic!("unexpected cause {:?}", err),
+        },
+        ref err => panic!("unexpected result {:?}", err),
+    }
+
+    // Futures cannot be awaited outside of `call_async`, in nested coroutines or in `pcall`.
+    assert!(lua.exec::<()>("fetch('a', 0)", None).is_err());
+    let protected: Function = lua.eval("function() return pcall(fetch, 'a', 0) end", None).unwrap();
+    let (output, _) = block_on(protected.call_async::<_, (bool, Value)>(()));
+    assert!(!output.unwrap().0);
+    let nested: Function = lua.eval(
+        "function() return coroutine.wrap(function() return fetch('a', 0) end)() end",
+        None,
+    ).unwrap();
+    assert!(block_on(nested.call_async::<_, String>(())).0.is_err());
+}
+
+#[test]
+fn test_ref_stats() {
+    use {Function, UserData, UserDataMethods};
+
+    let lua = Lua::new();
+    lua.exec::<()>("collectgarbage()", None).unwrap();
+    let before = lua.ref_stats();
+
+    let tables = (0..300).map(|_| lua.create_table()).collect::<Vec<_>>();
+    let stats = lua.ref_stats();
+    assert_eq!(stats.live_refs, before.live_refs + 300);
+    drop(tables);
+    assert_eq!(lua.ref_stats().live_refs, before.live_refs);
+
+    let callbacks = (0..3)
+        .map(|_| lua.create_function(|_, ()| Ok(())))
+        .collect::<Vec<Function>>();
+    assert_eq!(lua.ref_stats().live_callbacks, before.live_callbacks + 3);
+    drop(callbacks);
+    lua.exec::<()>("collectgarbage()", None).unwrap();
+    assert_eq!(lua.ref_stats().live_callbacks, before.live_callbacks);
+
+    // The metatable of a new userdata type is kept in the registry, along with its methods.
+    struct Registered;
+    impl UserData for Registered {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method("a", |_, _, ()| Ok(()));
+            methods.add_method("b", |_, _, ()| Ok(()));
+        }
+    }
+    lua.create_userdata(Registered);
+    assert_eq!(lua.ref_stats().registry_len, before.registry_len + 1);
+    lua.exec::<()>("collectgarbage()", None).unwrap();
+    assert_eq!(lua.ref_stats().live_callbacks, before.live_callbacks + 2);
+
+    #[cfg(debug_assertions)]
+    {
+        lua.set_ref_tracking(true);
+        let leaked = lua.create_table();
+        let origins = lua.tracked_refs();
+        assert_eq!(origins.len(), 1);
+        assert!(origins[0].contains("test_ref_stats"));
+        drop(leaked);
+        assert!(lua.tracked_refs().is_empty());
+        lua.set_ref_tracking(false);
+    }
+}
+
+#[test]
+fn test_sequence_from_slice() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+    globals
+        .set("numbers", lua.create_sequence_from_slice(&[0.5, 1.5, 2.5]))
+        .unwrap();
+    globals
+        .set("integers", lua.create_sequence_from_slice(&[1i64, 2, 3, 4]))
+        .unwrap();
+    globals
+        .set("names", lua.create_sequence_from_slice(&["a", "b\0c"]))
+        .unwrap();
+    globals
+        .set("empty", lua.create_sequence_from_slice::<i64>(&[]))
+        .unwrap();
+
+    assert!(
+        lua.eval::<bool>(
+            r#"
+                #numbers == 3 and numbers[2] == 1.5 and math.type(numbers[1]) == "float" and
+                #integers == 4 and integers[4] == 4 and math.type(integers[1]) == "integer" and
+                #names == 2 and names[2] == "b\0c" and next(empty) == nil
+            "#,
+            None
+        ).unwrap()
+    );
+}
+
+#[test]
+fn test_current_thread() {
+    use {Thread, ThreadStatus};
+
+    let lua = Lua::new();
+    assert!(lua.is_main_thread());
+    assert!(!lua.is_yieldable());
+    assert_eq!(lua.main_thread().to_pointer(), lua.current_thread().to_pointer());
+    assert_eq!(lua.main_thread().status(), ThreadStatus::Running);
+    match lua.main_thread().resume::<_, ()>(()) {
+        Err(Error::CoroutineInactive) => {}
+        r => panic!("main thread should not be resumable, got {:?}", r),
+    }
+
+    let inspect = lua.create_function(|lua, ()| {
+        let current = lua.current_thread();
+        Ok((
+            lua.is_main_thread(),
+            lua.is_yieldable(),
+            current.status() == ThreadStatus::Running,
+            current,
+        ))
+    });
+    lua.globals().set("inspect", inspect.clone()).unwrap();
+
+    let (main, yieldable, running, current): (bool, bool, bool, Thread) =
+        inspect.call(()).unwrap();
+    assert!(main && !yieldable && running);
+    assert_eq!(current.to_pointer(), lua.main_thread().to_pointer());
+
+    let thread: Thread = lua
+        .eval(
+            r#"
+                coroutine.create(function()
+                    local main, yieldable, running, current = inspect()
+                    assert(not main and yieldable and running)
+                    assert(current == coroutine.running())
+                    coroutine.yield(current)
+                end)
+            "#,
+            None,
+        )
+        .unwrap();
+    let current: Thread = thread.resume(()).unwrap();
+    assert_eq!(current.to_pointer(), thread.to_pointer());
+    assert_eq!(current.status(), ThreadStatus::Resumable);
+}
+
+#[test]
+fn test_context_registry_values() {
+    use {Function, Nil, RegistryKey, Table};
+
+    struct Host {
+        lua: Lua,
+        handlers: Vec<RegistryKey>,
+    }
+
+    let mut host = Host {
+        lua: Lua::new(),
+        handlers: Vec::new(),
+    };
+    let key = host.lua.context(|ctx| {
+        let handler: Function = ctx.eval("function(x) return x + 1 end", None).unwrap();
+        ctx.create_registry_value(handler).unwrap()
+    });
+    host.handlers.push(key);
+
+    let result = host.lua.context(|ctx| {
+        let handler = ctx.registry_value::<Function>(&host.handlers[0]).unwrap();
+        handler.call::<_, i64>(41).unwrap()
+    });
+    assert_eq!(result, 42);
+
+    // Keys are valid for the `Lua` passed to callbacks.
+    let key = host.handlers.pop().unwrap();
+    host.lua
+        .globals()
+        .set(
+            "check",
+            host.lua.create_function(move |lua, ()| {
+                Ok(lua.registry_value::<Function>(&key)?.call::<_, i64>(1)? == 2)
+            }),
+        )
+        .unwrap();
+    assert!(host.lua.eval::<bool>("check()", None).unwrap());
+
+    let other = Lua::new();
+    let key = host.lua.create_registry_value("value").unwrap();
+    match other.registry_value::<String>(&key) {
+        Err(Error::MismatchedRegistryKey) => {}
+        r => panic!("expected MismatchedRegistryKey, got {:?}", r),
+    }
+    match other.remove_registry_value(key) {
+        Err(Error::MismatchedRegistryKey) => {}
+        r => panic!("expected MismatchedRegistryKey, got {:?}", r),
+    }
+
+    // Removed and dropped keys no longer keep their values alive.
+    let lua = Lua::new();
+    let removed = lua.create_registry_value(lua.create_table()).unwrap();
+    let dropped = lua.create_registry_value(lua.create_table()).unwrap();
+    let weak_removed = lua.registry_value::<Table>(&removed).unwrap().downgrade();
+    let weak_dropped = lua.registry_value::<Table>(&dropped).unwrap().downgrade();
+    lua.remove_registry_value(removed).unwrap();
+    drop(dropped);
+    lua.create_registry_value(Nil).unwrap();
+    lua.exec::<()>("collectgarbage()", None).unwrap();
+    assert!(weak_removed.upgrade().is_none());
+    assert!(weak_dropped.upgrade().is_none());
+}
+
+#[test]
+fn test_lua52_compat() {
+    let compat = cfg!(feature = "lua52-compat");
+    for lua in &[Lua::new(), Lua::new_lazy()] {
+        assert_eq!(lua.lua52_compat(), compat);
+        assert_eq!(
+            lua.eval::<bool>("bit32 ~= nil and math.pow(2, 10) == 1024", None)
+                .unwrap_or(false),
+            compat
+        );
+    }
+}
+
+#[test]
+fn test_exec_with_args() {
+    use Variadic;
+
+    let lua = Lua::new();
+    let (count, first, last) = lua
+        .exec_with_args::<_, (i64, String, Option<i64>)>(
+            "local args = {...} return select('#', ...), args[1], args[3]",
+            None,
+            ("config.toml", "release", 3),
+        )
+        .unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(first, "config.toml");
+    assert_eq!(last, Some(3));
+
+    let count = lua
+        .exec_with_args::<_, i64>("return select('#', ...)", None, ())
+        .unwrap();
+    assert_eq!(count, 0);
+
+    let args = vec![1, 2, 3].into_iter().collect::<Variadic<i64>>();
+    let sum = lua
+        .exec_with_args::<_, i64>(
+            "local sum = 0 for _, x in ipairs({...}) do sum = sum + x end return sum",
+            None,
+            args,
+        )
+        .unwrap();
+    assert_eq!(sum, 6);
+
+    let chunk = lua.load("local a, b = ... return a * b", None).unwrap();
+    assert_eq!(chunk.call::<_, i64>((6, 7)).unwrap(), 42);
+    assert_eq!(chunk.call::<_, i64>((2, 3)).unwrap(), 6);
+}
+
+#[test]
+fn test_strict_globals() {
+    let lua = Lua::new_lazy();
+    let globals = lua.globals();
+    lua.set_strict_globals(true);
+    lua.declare_global("optional");
+    globals.set("from_rust", 1).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            declared = 1
+            function f()
+                declared = declared + from_rust
+                optional = "set"
+            end
+            f()
+            assert(declared == 2 and optional == "set")
+            declared = nil
+            assert(declared == nil)
+            assert(math.max(1, 2) == 2)
+            assert(coroutine.wrap(function() return rawget(_G, "missing") end)() == nil)
+        "#,
+        Some("strict"),
+    ).unwrap();
+    assert_eq!(globals.get::<_, Option<i64>>("missing").unwrap(), None);
+
+    match lua.exec::<()>("local x = mispelled", Some("strict")) {
+        Err(Error::RuntimeError(ref message)) => assert!(
+            message.starts_with("[string \"strict\"]:1: variable 'mispelled' is not declared")
+        ),
+        r => panic!("unexpected result {:?}", r),
+    }
+    match lua.exec::<()>("(function() total = 1 end)()", Some("strict")) {
+        Err(Error::RuntimeError(ref message)) => assert!(
+            message.starts_with("[string \"strict\"]:1: assignment to undeclared variable 'total'")
+        ),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // Enabling strict mode again does not install the metamethods twice.
+    lua.set_strict_globals(true);
+    assert!(lua.exec::<()>("local x = mispelled", None).is_err());
+
+    lua.set_strict_globals(false);
+    lua.exec::<()>(
+        r#"
+            assert(mispelled == nil)
+            ;(function() total = 1 end)()
+            assert(total == 1)
+            assert(table.concat({1, 2}) == "12")
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn test_audit_globals() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use {AccessKind, GlobalAccess};
+
+    let lua = Lua::new_lazy();
+    let accesses = Rc::new(RefCell::new(Vec::new()));
+    let log = accesses.clone();
+    lua.audit_globals(move |access| log.borrow_mut().push(access));
+    lua.set_strict_globals(true);
+    lua.globals().set("from_rust", 1).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            count = from_rust
+            local upper = string.upper
+            _G.count = count + 1
+            local names = {}
+            for name in pairs(_G) do names[name] = true end
+            assert(names.count and names.string and rawget(_G, "count") == nil)
+            assert(getmetatable(_G) == false)
+            assert(not pcall(setmetatable, _G, {}))
+        "#,
+        Some("plugin"),
+    ).unwrap();
+
+    let access = |name: &str, kind, line| GlobalAccess {
+        name: name.to_owned(),
+        kind,
+        source: "[string \"plugin\"]".to_owned(),
+        line: Some(line),
+    };
+    assert_eq!(
+        &accesses.borrow()[..6],
+        &[
+            access("from_rust", AccessKind::Read, 2),
+            access("count", AccessKind::Write, 2),
+            access("string", AccessKind::Read, 3),
+            access("_G", AccessKind::Read, 4),
+            access("count", AccessKind::Read, 4),
+            access("count", AccessKind::Write, 4),
+        ][..]
+    );
+    assert_eq!(lua.globals().get::<_, i64>("count").unwrap(), 2);
+
+    // Strict mode still applies to accesses through the proxy.
+    match lua.exec::<()>("local x = mispelled", Some("plugin")) {
+        Err(Error::RuntimeError(ref message)) => assert!(
+            message.starts_with("[string \"plugin\"]:1: variable 'mispelled' is not declared")
+        ),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    lua.remove_global_audit();
+    accesses.borrow_mut().clear();
+    lua.exec::<()>("count = count + 1", None).unwrap();
+    assert!(accesses.borrow().is_empty());
+    assert_eq!(lua.globals().get::<_, i64>("count").unwrap(), 3);
+}
+
+#[test]
+fn test_trace_require() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use {MemoryVfs, RequireResolver};
+
+    let lua = Lua::new_lazy();
+    let files = MemoryVfs::new();
+    files.insert("app/main.lua", b"local util = require('app.util')\nreturn util".to_vec());
+    files.insert("app/util.lua", b"return { answer = 42 }".to_vec());
+    let traces = Rc::new(RefCell::new(Vec::new()));
+    let log = traces.clone();
+    lua.trace_require(move |trace| log.borrow_mut().push(trace));
+    lua.set_vfs(files).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            package.preload.config = function(name) return { name = name } end
+            assert(require("app.main").answer == 42)
+            assert(require("config").name == "config")
+            assert(require("app.util") == require("app.main"))
+            assert(not pcall(require, "app.missing"))
+        "#,
+        Some("plugin"),
+    ).unwrap();
+
+    let traces = traces.borrow();
+    let summary = traces
+        .iter()
+        .map(|trace| {
+            (
+                &trace.module[..],
+                trace.resolver.clone(),
+                trace.path.as_ref().map(|path| &path[..]),
+                &trace.source[..],
+                trace.line,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        &summary[..5],
+        &[
+            (
+                "app.main",
+                RequireResolver::Searcher(2),
+                Some("./app/main.lua"),
+                "[string \"plugin\"]",
+                Some(3),
+            ),
+            (
+                "app.util",
+                RequireResolver::Searcher(2),
+                Some("./app/util.lua"),
+                "./app/main.lua",
+                Some(1),
+            ),
+            ("config", RequireResolver::Preload, None, "[string \"plugin\"]", Some(4)),
+            ("app.util", RequireResolver::Loaded, None, "[string \"plugin\"]", Some(5)),
+            ("app.main", RequireResolver::Loaded, None, "[string \"plugin\"]", Some(5)),
+        ][..]
+    );
+    assert_eq!(traces[5].module, "app.missing");
+    assert_eq!(traces[5].source, "[C]");
+    match traces[5].resolver {
+        RequireResolver::NotFound(ref reasons) => {
+            assert_eq!(reasons, "\n\tno field package.preload['app.missing']\
+                                 \n\tno file './app/missing.lua'\
+                                 \n\tno file './app/missing/init.lua'")
+        }
+        ref resolver => panic!("unexpected resolver {:?}", resolver),
+    }
+
+    lua.remove_require_trace();
+    lua.exec::<()>("require('app.util')", None).unwrap();
+    assert_eq!(traces.len(), 6);
+}
+
+#[test]
+fn test_resource_limits() {
+    use ResourceLimits;
+
+    let lua = Lua::new_lazy();
+    lua.set_resource_limits(ResourceLimits {
+        max_string_length: Some(100),
+        max_memory: Some(4 * 1024 * 1024),
+        max_pattern_steps: None,
+        max_table_entries: None,
+    });
+
+    lua.exec::<()>(
+        r#"
+            assert(#("x"):rep(100) == 100)
+            assert(#string.rep("ab", 33, ",") == 98)
+            assert(not pcall(string.rep, "ab", 34, ", "))
+            local ok, err = pcall(string.rep, "x", math.maxinteger)
+            assert(not ok and err:find("resulting string is longer than the limit of the state"))
+
+            local list = {}
+            for i = 1, 50 do list[i] = "x" end
+            assert(#table.concat(list) == 50)
+            assert(#table.concat(list, "a") == 99)
+            assert(not pcall(table.concat, list, "ab"))
+            assert(#table.concat(list, "ab", 1, 10) == 28)
+            assert(not pcall(table.concat, {1, {}, 3}))
+
+            local ok, err = pcall(function()
+                local t = {}
+                for i = 1, 1e7 do t[i] = i end
+            end)
+            assert(not ok and err == "not enough memory")
+
+            local ok, err = pcall(function()
+                local t = {}
+                for i = 1, 1e7 do t[i] = {} end
+            end)
+            assert(not ok and err == "not enough memory")
+
+            -- The values left in the registers of a function which exceeded the limit are not
+            -- counted against its caller, whose unused registers they occupy.
+            local ok, err = pcall(function()
+                local a, b, c, d, e, f, g, h
+                local t = {}
+                for i = 1, 1e7 do t[i] = i end
+            end)
+            assert(not ok and err == "not enough memory")
+            local t = {}
+            for i = 1, 1e5 do t[i] = i end
+            t = nil
+            local a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p, q, r, s, u, v, w, x, y, z
+
+            -- Strings are refused as soon as they would exceed the limit.
+            local ok, err = pcall(function()
+                local s = "x"
+                for i = 1, 30 do s = s .. s end
+            end)
+            assert(not ok and err == "not enough memory")
+        "#,
+        None,
+    ).unwrap();
+    assert!(lua.used_memory() < 4 * 1024 * 1024);
+
+    match lua.exec::<()>("local t = {} for i = 1, 1e7 do t[i] = i end", None) {
+        Err(Error::MemoryError(msg)) => assert_eq!(msg, "not enough memory"),
+        r => panic!("memory limit was not exceeded: {:?}", r),
+    }
+
+    // Coroutines created before the limit was set are limited as well, and a single allocation
+    // cannot go past the limit.
+    lua.set_resource_limits(ResourceLimits::default());
+    lua.exec::<()>(
+        r#"
+            co = coroutine.create(function()
+                local t = {}
+                for i = 1, 1e7 do t[i] = i end
+            end)
+        "#,
+        None,
+    ).unwrap();
+    lua.set_resource_limits(ResourceLimits {
+        max_memory: Some(4 * 1024 * 1024),
+        ..ResourceLimits::default()
+    });
+    lua.exec::<()>(
+        r#"
+            local ok, err = coroutine.resume(co)
+            assert(not ok and err == "not enough memory")
+            local ok, err = pcall(string.rep, "x", 2^26)
+            assert(not ok and err:find("not enough memory"))
+        "#,
+        None,
+    ).unwrap();
+    assert!(lua.used_memory() < 4 * 1024 * 1024);
+
+    lua.set_resource_limits(ResourceLimits::default());
+    lua.exec::<()>(
+        r#"
+            assert(#("x"):rep(1000) == 1000)
+            local t = {}
+            for i = 1, 1e6 do t[i] = i end
+        "#,
+        None,
+    ).unwrap();
+    assert!(lua.used_memory() > 4 * 1024 * 1024);
+}
+
+#[cfg(feature = "builtin-lua")]
+#[test]
+fn test_table_entry_limit() {
+    use ResourceLimits;
+
+    let lua = Lua::new();
+    lua.set_resource_limits(ResourceLimits {
+        max_table_entries: Some(10_000),
+        ..ResourceLimits::default()
+    });
+
+    lua.exec::<()>(
+        r#"
+            local t = {}
+            for i = 1, 1000 do t[i] = i end
+            for i = 1, 1000 do t["key" .. i] = i end
+
+            local ok, err = pcall(function()
+                local t = {}
+                for i = 1, 1e6 do t[i] = i end
+            end)
+            assert(not ok and err:find("table entry limit exceeded"))
+        "#,
+        None,
+    ).unwrap();
+
+    // Every execution starts with no entries counted, including those made by Rust callbacks.
+    let fill = lua.create_function(|lua, n: i64| {
+        lua.eval::<Table>(&format!("local t = {{}} for i = 1, {} do t[i] = i end return t", n), None)
+    });
+    lua.globals().set("fill", fill).unwrap();
+    lua.exec::<()>("fill(6000)", None).unwrap();
+    let err = lua.exec::<()>("fill(6000) fill(6000)", None).unwrap_err();
+    assert!(format!("{:?}", err).contains("table entry limit exceeded"));
+    lua.exec::<()>("for i = 1, 1e5 do local t = {} end", None).unwrap();
+    assert!(lua.exec::<()>("for i = 1, 1e5 do local t = {1, 2} end", None).is_err());
+
+    lua.create_table_with_capacity(100_000, 0);
+    lua.exec::<()>("local t = {1, 2, 3}", None).unwrap();
+
+    // The limit only applies to the state it was set on.
+    let other = Lua::new();
+    other.exec::<()>("local t = {} for i = 1, 1e6 do t[i] = i end", None).unwrap();
+
+    lua.set_resource_limits(ResourceLimits::default());
+    lua.exec::<()>("local t = {} for i = 1, 1e6 do t[i] = i end", None).unwrap();
+}
+
+#[test]
+fn test_budget() {
+    use Budget;
+
+    let lua = Lua::new();
+    let budget = Budget::instructions(1_000_000);
+    lua.set_budget(budget.clone());
+
+    let count = |lua: &Lua| lua.exec::<()>("local n = 0 for i = 1, 1000 do n = n + i end", None);
+    count(&lua).unwrap();
+    let remaining = budget.remaining();
+    assert!(remaining < 1_000_000 && remaining > 990_000);
+    count(&lua).unwrap();
+    assert!(budget.remaining() < remaining);
+
+    match lua.exec::<()>(
+        r#"
+            while true do
+                pcall(function() while true do end end)
+            end
+        "#,
+        None,
+    ) {
+        Err(Error::RuntimeError(msg)) => assert!(msg.contains("instruction budget exhausted")),
+        r => panic!("budget was not exhausted: {:?}", r),
+    }
+    assert_eq!(budget.remaining(), 0);
+    assert!(count(&lua).is_err());
+
+    let thread = lua.create_thread(lua.load("while true do end", None).unwrap());
+    assert!(thread.resume::<_, ()>(()).is_err());
+
+    budget.refill(100_000);
+    count(&lua).unwrap();
+    assert!(budget.remaining() > 0);
+
+    lua.remove_budget();
+    budget.set_remaining(0);
+    count(&lua).unwrap();
+    assert_eq!(budget.remaining(), 0);
+}
+
+#[test]
+fn test_watchdog() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use Watchdog;
+
+    let watchdog = Watchdog::new();
+    let slow = Lua::new();
+    let fast = Lua::new();
+    slow.set_watchdog(watchdog.clone());
+    fast.set_watchdog(watchdog.clone());
+    drop(watchdog);
+
+    let count = |lua: &Lua| lua.exec::<()>("local n = 0 for i = 1, 1000 do n = n + i end", None);
+    fast.set_deadline(Instant::now() + Duration::from_secs(60));
+    slow.set_deadline(Instant::now() + Duration::from_millis(50));
+    let start = Instant::now();
+    match slow.exec::<()>(
+        r#"
+            while true do
+                pcall(function() while true do end end)
+            end
+        "#,
+        None,
+    ) {
+        Err(Error::RuntimeError(msg)) => assert!(msg.contains("deadline exceeded")),
+        r => panic!("state was not interrupted: {:?}", r),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(count(&slow).is_err());
+    count(&fast).unwrap();
+
+    slow.set_deadline(Instant::now() + Duration::from_secs(60));
+    count(&slow).unwrap();
+    slow.set_deadline(Instant::now());
+    thread::sleep(Duration::from_millis(50));
+    assert!(count(&slow).is_err());
+    slow.clear_deadline();
+    count(&slow).unwrap();
+
+    // The deadline is kept when the state is attached to another watchdog.
+    slow.set_deadline(Instant::now() + Duration::from_millis(20));
+    slow.set_watchdog(Watchdog::new());
+    assert!(slow.exec::<()>("while true do end", None).is_err());
+    slow.remove_watchdog();
+    count(&slow).unwrap();
+}
+
+#[test]
+fn test_vfs() {
+    use {MemoryVfs, ReadOnlyVfs};
+
+    let lua = Lua::new_lazy();
+    let files = MemoryVfs::new();
+    files.insert("lib/util.lua", b"#!/usr/bin/lua\nreturn { name = ..., twice = 2 }".to_vec());
+    files.insert("lib/pkg/init.lua", b"return 'pkg'".to_vec());
+    files.insert("data/numbers.txt", b"1 2.5 0x10\nline two\nlast".to_vec());
+    lua.set_vfs(files.clone()).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            local util = require("lib.util")
+            assert(util.name == "lib.util" and util.twice == 2)
+            assert(require("lib.pkg") == "pkg")
+            local ok, err = pcall(require, "missing")
+            assert(not ok and err:find("no file './missing.lua'", 1, true))
+
+            local f = assert(io.open("/data/./numbers.txt"))
+            assert(io.type(f) == "file" and io.type(io.stdout) == "file")
+            local a, b, c = f:read("n", "n", "n")
+            assert(a == 1 and math.type(a) == "integer" and b == 2.5 and c == 16)
+            assert(f:read("l") == "" and f:read("L") == "line two\n")
+            assert(f:read("a") == "last" and f:read("a") == "" and f:read("l") == nil)
+            assert(f:seek("set", 2) == 2 and f:read(3) == "2.5")
+            f:close()
+            assert(io.type(f) == "closed file" and not pcall(f.read, f))
+
+            local lines = {}
+            for line in io.lines("data/numbers.txt") do lines[#lines + 1] = line end
+            assert(#lines == 3 and lines[3] == "last")
+
+            local out = assert(io.open("out/log.txt", "w"))
+            assert(out:write("a", 1, "\n") == out)
+            out:close()
+            out = assert(io.open("out/log.txt", "a+"))
+            out:write("b")
+            assert(out:seek("set") == 0 and out:read("a") == "a1\nb")
+            out:close()
+
+            io.output("out/default.txt")
+            io.write("default")
+            io.close()
+            io.input("out/default.txt")
+            assert(io.read("a") == "default")
+
+            local tmp = io.tmpfile()
+            tmp:write("temporary")
+            tmp:seek("set")
+            assert(tmp:read("a") == "temporary")
+
+            assert(io.open("../../etc/passwd") == nil)
+            assert(io.open("missing.txt") == nil)
+            assert(not pcall(io.open, "x", "rw"))
+            assert(not pcall(io.popen, "ls"))
+
+            local names = io.list("out")
+            assert(#names == 2 and names[1] == "default.txt" and names[2] == "log.txt")
+            local root = io.list()
+            assert(#root == 3 and root[1] == "data")
+            assert(os.rename("out/log.txt", "out/moved.txt"))
+            assert(os.remove("out/default.txt"))
+            assert(os.remove("out/default.txt") == nil)
+
+            local chunk = assert(loadfile("lib/util.lua", "t", { x = 1 }))
+            assert(chunk().twice == 2)
+            assert(select(2, loadfile("lib/util.lua", "b")))
+            assert(dofile("lib/pkg/init.lua") == "pkg")
+
+            local bytecode = assert(io.open("code.luac", "w"))
+            bytecode:write(string.dump(function() return 1 end))
+            bytecode:close()
+            assert(loadfile("code.luac") == nil)
+        "#,
+        None,
+    ).unwrap();
+
+    assert_eq!(files.get("out/moved.txt"), Some(b"a1\nb".to_vec()));
+    assert_eq!(files.get("out/default.txt"), None);
+
+    let lua = Lua::new();
+    lua.set_vfs(ReadOnlyVfs(files.clone())).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(io.open("out/moved.txt"):read("a") == "a1\nb")
+            assert(io.open("out/new.txt", "w") == nil)
+            assert(os.remove("out/moved.txt") == nil)
+        "#,
+        None,
+    ).unwrap();
+    assert_eq!(files.get("out/new.txt"), None);
+}
+
+#[test]
+fn test_directory_vfs() {
+    use std::{env, fs, process};
+    use DirectoryVfs;
+
+    let dir = env::temp_dir().join(format!("rlua_vfs_{}", process::id()));
+    fs::create_dir_all(dir.join("root/lib")).unwrap();
+    fs::write(dir.join("root/lib/mod.lua"), "return 42").unwrap();
+    fs::write(dir.join("secret.txt"), "secret").unwrap();
+
+    let lua = Lua::new();
+    lua.set_vfs(DirectoryVfs::new(dir.join("root"))).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(require("lib.mod") == 42)
+            assert(io.open("../secret.txt") == nil)
+            assert(io.open("/lib/../../secret.txt") == nil)
+            local f = assert(io.open("lib/out.txt", "w"))
+            f:write("written")
+            f:close()
+            local names = io.list("lib")
+            assert(#names == 2 and names[1] == "mod.lua" and names[2] == "out.txt")
+            assert(io.open("lib") == nil)
+        "#,
+        None,
+    ).unwrap();
+    assert_eq!(fs::read_to_string(dir.join("root/lib/out.txt")).unwrap(), "written");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_snapshot() {
+    let lua = Lua::new();
+    lua.exec::<()>(
+        r#"
+            counter = { n = 0, history = {} }
+            counter.self = counter
+            local state = counter
+            function bump() state.n = state.n + 1 end
+            shared = setmetatable({}, { __index = { default = 1 } })
+            package.loaded.mod = { value = "original" }
+        "#,
+        None,
+    ).unwrap();
+    let counter = lua.globals().get::<_, Table>("counter").unwrap();
+    let snapshot = lua.snapshot();
+
+    for _ in 0..2 {
+        lua.exec::<()>(
+            r#"
+                bump()
+                counter.history[1] = "bumped"
+                counter.self = nil
+                getmetatable(shared).__index.default = 2
+                setmetatable(shared, nil)
+                package.loaded.mod.value = "changed"
+                package.loaded.other = true
+                leaked = {}
+                string.upper = nil
+            "#,
+            None,
+        ).unwrap();
+        lua.restore(&snapshot).unwrap();
+
+        lua.exec::<()>(
+            r#"
+                assert(counter.n == 0 and #counter.history == 0 and counter.self == counter)
+                assert(shared.default == 1)
+                assert(require("mod").value == "original" and package.loaded.other == nil)
+                assert(leaked == nil and string.upper("a") == "A")
+                bump()
+                assert(counter.n == 1)
+            "#,
+            None,
+        ).unwrap();
+        assert_eq!(counter.get::<_, i64>("n").unwrap(), 1);
+        lua.restore(&snapshot).unwrap();
+    }
+}
+
+#[test]
+fn test_globals_snapshot() {
+    use SnapshotValue;
+
+    let lua = Lua::new();
+    lua.exec::<()>(
+        r#"
+            config = { name = "app", debug = false, ports = { 80, 443 }, ["a b"] = 1 }
+            config.self = config
+            handler = function() end
+            ratio = 0 / 0
+            deep = { { { { "bottom" } } } }
+        "#,
+        None,
+    ).unwrap();
+
+    let before = lua.globals_snapshot().unwrap();
+    assert_eq!(before.get("config.name"), Some(&SnapshotValue::String(b"app".to_vec())));
+    assert_eq!(before.get("config.ports[2]"), Some(&SnapshotValue::Integer(443)));
+    assert_eq!(before.get("config[\"a b\"]"), Some(&SnapshotValue::Integer(1)));
+    match before.get("config.self") {
+        Some(&SnapshotValue::Truncated(_)) => {}
+        v => panic!("unexpected value {:?}", v),
+    }
+    match before.get("handler") {
+        Some(&SnapshotValue::Other("function", _)) => {}
+        v => panic!("unexpected value {:?}", v),
+    }
+    assert_eq!(before.get("config.missing"), None);
+    assert_eq!(before.get("config.name.length"), None);
+    assert!(before.diff(&lua.globals_snapshot().unwrap()).is_empty());
+
+    let shallow = lua.globals_snapshot_with_depth(1).unwrap();
+    match shallow.get("deep[1]") {
+        Some(&SnapshotValue::Truncated(_)) => {}
+        v => panic!("unexpected value {:?}", v),
+    }
+    assert!(shallow.get("config.name").is_some());
+
+    lua.exec::<()>(
+        r#"
+            config.debug = true
+            config.ports[3] = 8080
+            config["a b"] = nil
+            handler = function() end
+            counter = 1
+            string.custom = tostring
+            deep = nil
+        "#,
+        None,
+    ).unwrap();
+    let diff = before.diff(&lua.globals_snapshot().unwrap());
+    // Tables reachable through several paths are compared at each of them.
+    assert_eq!(
+        diff.added,
+        vec!["config.ports[3]", "counter", "package.loaded.string.custom", "string.custom"]
+    );
+    assert_eq!(diff.removed, vec!["config[\"a b\"]", "deep"]);
+    assert_eq!(diff.changed, vec!["config.debug", "handler"]);
+}
+
+#[test]
+fn test_memory_profile() {
+    use MemoryProfile;
+
+    let lua = Lua::new();
+    assert_eq!(lua.memory_profile(), MemoryProfile::default());
+
+    lua.start_memory_profile();
+    lua.exec::<()>(
+        r#"
+            local function make(n)
+                return ("y"):rep(n) .. n
+            end
+            local small = {}
+            local large = {}
+            for i = 1, 500 do
+                large[i] = ("x"):rep(200) .. i
+            end
+            for i = 1, 10 do
+                small[i] = make(50)
+            end
+        "#,
+        Some("profiled"),
+    ).unwrap();
+
+    let profile = lua.memory_profile();
+    let top = &profile.sites[0];
+    assert_eq!(top.chunk, r#"[string "profiled"]"#);
+    assert_eq!(top.line, 8);
+    assert!(top.bytes >= 500 * 200);
+    assert!(top.allocations >= 500);
+    // Memory allocated by a called Lua function is charged to its own line.
+    assert!(profile.sites.iter().any(|site| site.line == 3));
+    assert!(
+        profile
+            .sites
+            .windows(2)
+            .all(|sites| sites[0].bytes >= sites[1].bytes)
+    );
+
+    // Host allocations are not attributed to a line.
+    lua.create_string(&"z".repeat(10_000));
+    let profile = lua.stop_memory_profile();
+    assert!(profile.unattributed_bytes >= 10_000);
+
+    lua.exec::<()>("local t = {} for i = 1, 100 do t[i] = {} end", None).unwrap();
+    assert_eq!(lua.memory_profile(), MemoryProfile::default());
+    assert_eq!(lua.stop_memory_profile(), MemoryProfile::default());
+}
+
+#[test]
+fn test_coverage() {
+    let lua = Lua::new();
+    assert!(lua.coverage().chunks().is_empty());
+    lua.start_coverage();
+
+    lua.exec::<()>(
+        r#"
+            local function classify(n)
+                if n > 0 then
+                    return "positive"
+                elseif n < 0 then
+                    return "negative"
+                end
+                return "zero"
+            end
+            for i = 1, 3 do
+                classify(i)
+            end
+            classify(0)
+            local unused = function()
+                return 1
+            end
+        "#,
+        Some("plugin.lua"),
+    ).unwrap();
+    lua.exec::<()>("local x = 1", Some("=other & <chunk>")).unwrap();
+
+    let coverage = lua.coverage();
+    assert_eq!(coverage.hits("plugin.lua", 3), Some(4));
+    assert_eq!(coverage.hits("plugin.lua", 4), Some(3));
+    assert_eq!(coverage.hits("plugin.lua", 6), Some(0));
+    assert_eq!(coverage.hits("plugin.lua", 8), Some(1));
+    assert_eq!(coverage.hits("plugin.lua", 11), Some(3));
+    // Lines of functions which never ran are listed from the bytecode of the chunk.
+    assert_eq!(coverage.hits("plugin.lua", 15), Some(0));
+    assert_eq!(coverage.hits("plugin.lua", 1), None);
+    assert_eq!(coverage.hits("other & <chunk>", 1), Some(1));
+
+    let lcov = coverage.to_lcov();
+    assert!(lcov.starts_with("TN:\nSF:other & <chunk>\nDA:1,1\nLF:1\nLH:1\nend_of_record\n"));
+    assert!(lcov.contains("SF:plugin.lua\nDA:3,4\nDA:4,3\nDA:5,1\nDA:6,0\n"));
+    let lines = coverage.chunks()["plugin.lua"].len();
+    let hit = coverage.chunks()["plugin.lua"].values().filter(|&&n| n > 0).count();
+    assert!(lcov.ends_with(&format!("LF:{}\nLH:{}\nend_of_record\n", lines, hit)));
+
+    let xml = coverage.to_cobertura();
+    assert!(xml.starts_with("<?xml version=\"1.0\" ?>\n"));
+    assert!(xml.contains(
+        "<class name=\"other &amp; &lt;chunk&gt;\" filename=\"other &amp; &lt;chunk&gt;\" \
+         line-rate=\"1\""
+    ));
+    assert!(xml.contains("<line number=\"6\" hits=\"0\"/>"));
+    assert!(xml.contains(&format!("lines-covered=\"{}\" lines-valid=\"{}\"", hit + 1, lines + 1)));
+    assert!(xml.ends_with("</coverage>\n"));
+
+    let stopped = lua.stop_coverage();
+    assert_eq!(stopped, coverage);
+    lua.exec::<()>("local y = 2", Some("=later")).unwrap();
+    assert!(lua.stop_coverage().chunks().is_empty());
+}
+
+#[test]
+fn test_gc_events() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use GcEvent;
+
+    let lua = Lua::new();
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let recorded = events.clone();
+    lua.on_gc(move |event| recorded.borrow_mut().push(event));
+
+    lua.exec::<()>(
+        r#"
+            for i = 1, 200000 do
+                local t = { i, tostring(i) }
+            end
+        "#,
+        None,
+    ).unwrap();
+    {
+        let events = events.borrow();
+        let ends = events
+            .iter()
+            .filter(|event| matches!(**event, GcEvent::End { .. }))
+            .count();
+        assert!(ends >= 2);
+        // Automatic cycles are reported as pairs of events, and the last cycle may not have
+        // finished yet.
+        let finished = events.len() / 2 * 2;
+        if finished < events.len() {
+            assert!(matches!(events[finished], GcEvent::Start { .. }));
+        }
+        for pair in events[..finished].chunks(2) {
+            match (pair[0], pair[1]) {
+                (
+                    GcEvent::Start { .. },
+                    GcEvent::End {
+                        reclaimed_bytes,
+                        duration,
+                        ..
+                    },
+                ) => {
+                    assert!(reclaimed_bytes > 0);
+                    assert!(duration.is_some());
+                }
+                pair => panic!("unexpected events {:?}", pair),
+            }
+        }
+    }
+
+    // Full collections finish the cycle that is running, and one more.
+    events.borrow_mut().clear();
+    lua.exec::<()>("local t = {} for i = 1, 1000 do t[i] = {} end t = nil", None)
+        .unwrap();
+    lua.exec::<()>("collectgarbage() collectgarbage()", None).unwrap();
+    match events.borrow().last() {
+        Some(&GcEvent::End {
+            used_memory,
+            duration: None,
+            ..
+        }) => assert!(used_memory > 0),
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    lua.remove_gc_callback();
+    events.borrow_mut().clear();
+    lua.exec::<()>("collectgarbage() collectgarbage()", None).unwrap();
+    assert!(events.borrow().is_empty());
+
+    // Replacing the callback does not report cycles twice.
+    let count = Rc::new(RefCell::new(0));
+    let counted = count.clone();
+    lua.on_gc(|_| {});
+    lua.on_gc(move |event| {
+        if let GcEvent::End { .. } = event {
+            *counted.borrow_mut() += 1;
+        }
+    });
+    lua.exec::<()>("collectgarbage()", None).unwrap();
+    assert_eq!(*count.borrow(), 1);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialize_globals_snapshot() {
+    let lua = Lua::new();
+    let before = lua.globals_snapshot().unwrap();
+    lua.exec::<()>("settings = { volume = 0.5, tags = { 'a', 'b' }, [1] = true }", None)
+        .unwrap();
+    let after = lua.globals_snapshot().unwrap();
+
+    let json = serde_json::to_value(&after).unwrap();
+    assert_eq!(
+        json["settings"],
+        serde_json::json!({ "volume": 0.5, "tags": { "[1]": "a", "[2]": "b" }, "[1]": true })
+    );
+    assert_eq!(json["print"], serde_json::Value::Null);
+    assert_eq!(
+        serde_json::to_value(before.diff(&after)).unwrap(),
+        serde_json::json!({ "added": ["settings"], "removed": [], "changed": [] })
+    );
+}
+
+#[test]
+fn test_debugger() {
+    use std::thread;
+    use std::time::Duration;
+    use {Debugger, PauseReason};
+
+    let lua = Lua::new();
+    let debugger = Debugger::new();
+    debugger.set_breakpoint("calc", 7);
+    debugger.set_breakpoint("other", 7);
+    lua.set_debugger(debugger.clone());
+
+    let control = {
+        let debugger = debugger.clone();
+        thread::spawn(move || {
+            let timeout = Duration::from_secs(10);
+            let mut pauses = Vec::new();
+            pauses.push(debugger.wait_for_pause(timeout));
+            debugger.step_into();
+            pauses.push(debugger.wait_for_pause(timeout));
+            debugger.step_over();
+            pauses.push(debugger.wait_for_pause(timeout));
+            debugger.step_out();
+            pauses.push(debugger.wait_for_pause(timeout));
+            let removed = debugger.remove_breakpoint("calc", 7);
+            debugger.resume();
+            (pauses, removed)
+        })
+    };
+
+    let source = r#"local function add(a, b)
+    local sum = a + b
+    return sum
+end
+local total, t = 0, { 1, x = "y" }
+for i = 1, 3 do
+    total = add(total, i)
+end
+return total"#;
+    let total = lua.load(source, Some("calc"))
+        .unwrap()
+        .call::<_, i64>(())
+        .unwrap();
+    assert_eq!(total, 6);
+
+    let (pauses, removed) = control.join().unwrap();
+    assert!(removed);
+    let pauses: Vec<_> = pauses.into_iter().map(Option::unwrap).collect();
+
+    assert_eq!(pauses[0].reason, PauseReason::Breakpoint);
+    assert_eq!(pauses[0].frames.len(), 1);
+    let frame = &pauses[0].frames[0];
+    assert_eq!(frame.frame.source, "[string \"calc\"]");
+    assert_eq!(frame.frame.line, Some(7));
+    let locals: Vec<_> = frame
+        .locals
+        .iter()
+        .filter(|local| local.0 != "add")
+        .map(|local| (local.0.as_str(), local.1.as_str()))
+        .collect();
+    assert_eq!(locals, vec![("total", "0"), ("t", "{1, x = \"y\"}"), ("i", "1")]);
+
+    assert_eq!(pauses[1].reason, PauseReason::Step);
+    assert_eq!(pauses[1].frames.len(), 2);
+    assert_eq!(pauses[1].frames[0].frame.name, Some("add".to_owned()));
+    assert_eq!(pauses[1].frames[0].frame.line, Some(2));
+    let locals = &pauses[1].frames[0].locals;
+    assert_eq!(locals, &vec![("a".to_owned(), "0".to_owned()), ("b".to_owned(), "1".to_owned())]);
+
+    assert_eq!(pauses[2].frames[0].frame.line, Some(3));
+    assert_eq!(pauses[2].frames.len(), 2);
+
+    assert_eq!(pauses[3].reason, PauseReason::Step);
+    assert_eq!(pauses[3].frames.len(), 1);
+
+    // A pause requested before a script runs stops it on its first line.
+    debugger.pause();
+    let control = {
+        let debugger = debugger.clone();
+        thread::spawn(move || {
+            let pause = debugger.wait_for_pause(Duration::from_secs(10));
+            debugger.resume();
+            pause
+        })
+    };
+    lua.exec::<()>("local x = 1\nlocal y = 2", Some("other")).unwrap();
+    let pause = control.join().unwrap().unwrap();
+    assert_eq!(pause.reason, PauseReason::Pause);
+    assert_eq!(pause.frames[0].frame.line, Some(1));
+
+    assert!(debugger.paused().is_none());
+    assert!(debugger.wait_for_pause(Duration::from_millis(1)).is_none());
+    assert!(!debugger.remove_breakpoint("calc", 7));
+    lua.remove_debugger();
+    lua.exec::<()>(source, Some("other")).unwrap();
+}
+
+#[test]
+fn test_source_map() {
+    use SourceMap;
+
+    let mut map = SourceMap::new();
+    map.insert(3, "view.tmpl", 10);
+    map.insert(6, "footer.tmpl", 1);
+    assert_eq!(map.get(2), None);
+    assert_eq!(map.get(3), Some(("view.tmpl", 10)));
+    assert_eq!(map.get(5), Some(("view.tmpl", 12)));
+    assert_eq!(map.get(7), Some(("footer.tmpl", 2)));
+
+    let lua = Lua::new();
+    let generated = r#"
+        local function render(items)
+            local out = {}
+            for i = 1, #items do out[i] = items[i].name end
+            local footer = function()
+                error("no footer")
+            end
+            footer()
+            return out
+        end
+        return render
+    "#;
+    let render: Function = lua.load_with_source_map(generated, "view", map)
+        .unwrap()
+        .call(())
+        .unwrap();
+
+    match render.call::<_, ()>(lua.create_sequence_from(vec![1]).unwrap()) {
+        Err(Error::RuntimeError(message)) => {
+            assert!(message.starts_with("view.tmpl:11: attempt to index"));
+            assert!(message.contains("\tview.tmpl:11: in function <[string \"view\"]:2>"));
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    let err = render.call::<_, ()>(lua.create_table()).unwrap_err();
+    let lines = err.frames()
+        .unwrap()
+        .into_iter()
+        .filter(|frame| frame.source == "footer.tmpl")
+        .map(|frame| frame.line)
+        .collect::<Vec<_>>();
+    assert_eq!(lines, vec![Some(1), Some(3)]);
+
+    // Errors raised by callbacks keep their cause, and unmapped lines are left as they are.
+    let fail = lua.create_function(|_, ()| Err::<(), _>(Error::RuntimeError("fail".to_owned())));
+    lua.globals().set("fail", fail).unwrap();
+    let mut map = SourceMap::new();
+    map.insert(2, "script.src", 7);
+    let chunk = lua.load_with_source_map("local x = 1\nfail()", "chunk", map).unwrap();
+    match chunk.call::<_, ()>(()) {
+        Err(Error::CallbackError { traceback, .. }) => {
+            assert!(traceback.contains("script.src:7: in main chunk"));
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let mut map = SourceMap::new();
+    map.insert(2, "broken.src", 40);
+    match lua.load_with_source_map("local x = 1\nlocal = 2", "broken", map) {
+        Err(Error::SyntaxError { message, .. }) => assert!(message.starts_with("broken.src:40:")),
+        r => panic!("unexpected result {:?}", r),
+    }
+    let err = lua.load("local x = 1\nerror('x')", Some("other")).unwrap().call::<_, ()>(());
+    assert!(err.unwrap_err().to_string().contains("[string \"other\"]:2:"));
+}
+
+#[test]
+fn test_resource_report() {
+    use Budget;
+
+    let lua = Lua::new();
+
+    let (result, report) = lua.exec_with_report::<()>("for i = 1, 100000 do end", None);
+    result.unwrap();
+    assert!(report.instructions >= 99_000 && report.instructions <= 400_000);
+    assert_eq!(report.peak_stack_depth, 1);
+
+    let (result, report) = lua.exec_with_report::<()>("local s = ('x'):rep(1000000)", None);
+    result.unwrap();
+    assert!(report.lua_allocated_bytes >= 1_000_000);
+    assert!(report.lua_allocated_bytes < 10_000_000);
+
+    let recurse: Function = lua.eval(
+        r#"
+            function recurse(n)
+                if n > 0 then recurse(n - 1) end
+            end
+            return recurse
+        "#,
+        None,
+    ).unwrap();
+    let (result, report) = recurse.call_with_report::<_, ()>(99);
+    result.unwrap();
+    assert_eq!(report.peak_stack_depth, 100);
+
+    // Calls left by caught errors and tail calls do not add to the depth, and coroutines are
+    // measured on their own.
+    let (result, report) = lua.exec_with_report::<()>(
+        r#"
+            local function fail(n)
+                if n == 0 then error("fail") end
+                fail(n - 1)
+            end
+            for i = 1, 100 do pcall(fail, 10) end
+            local function loop(n)
+                if n > 0 then return loop(n - 1) end
+            end
+            loop(1000)
+            local co = coroutine.wrap(function()
+                for i = 1, 3 do coroutine.yield(i) end
+            end)
+            co() co() co()
+        "#,
+        None,
+    );
+    result.unwrap();
+    assert_eq!(report.peak_stack_depth, 14);
+
+    let (result, report) = lua.exec_with_report::<()>("error('failed')", None);
+    assert!(result.is_err());
+    assert!(report.peak_stack_depth >= 2);
+
+    // Nested measurements are included in the outer one.
+    let inner = lua.create_function(|lua, ()| {
+        let (result, report) = lua.exec_with_report::<()>("recurse(9)", None);
+        result?;
+        assert_eq!(report.peak_stack_depth, 11);
+        Ok(())
+    });
+    lua.globals().set("inner", inner).unwrap();
+    let (result, report) = lua.exec_with_report::<()>("inner()", None);
+    result.unwrap();
+    assert_eq!(report.peak_stack_depth, 13);
+
+    // Measuring does not affect the hooks of limits.
+    let budget = Budget::instructions(100_000);
+    lua.set_budget(budget.clone());
+    let (result, report) = lua.exec_with_report::<()>("while true do end", None);
+    assert!(result.is_err());
+    assert!(report.instructions >= 100_000);
+    lua.remove_budget();
+    assert!(lua.exec::<()>("for i = 1, 100000 do end", None).is_ok());
+}
+
+#[test]
+fn test_pattern_step_limit() {
+    use ResourceLimits;
+
+    let lua = Lua::new();
+    lua.exec::<()>(
+        "original = { find = string.find, match = string.match, gmatch = string.gmatch, \
+         gsub = string.gsub }",
+        None,
+    ).unwrap();
+    lua.set_resource_limits(ResourceLimits {
+        max_pattern_steps: Some(100_000),
+        ..ResourceLimits::default()
+    });
+
+    lua.exec::<()>(
+        r#"
+            local function same(name, ...)
+                local expected = table.pack(pcall(original[name], ...))
+                local actual = table.pack(pcall(string[name], ...))
+                assert(expected[1] == actual[1], name .. ": " .. tostring(actual[2]))
+                assert(type(expected[2]) == type(actual[2]), name .. ": different result types")
+                if expected[1] then
+                    assert(expected.n == actual.n, name .. ": different number of results")
+                    for i = 2, expected.n do
+                        assert(expected[i] == actual[i], name .. ": " .. tostring(actual[i]))
+                    end
+                end
+            end
+            local function collect(gmatch, s, p)
+                local results = {}
+                for a, b in gmatch(s, p) do
+                    results[#results + 1] = tostring(a) .. tostring(b)
+                end
+                return table.concat(results, "|")
+            end
+            local function all(s, p)
+                local eok, expected = pcall(collect, original.gmatch, s, p)
+                local aok, actual = pcall(collect, string.gmatch, s, p)
+                assert(eok == aok and (not eok or expected == actual), "gmatch: " .. p)
+            end
+
+            local subjects = { "", "hello world", "  key = value  ", "(a(b)c)d",
+                               "THE (quick) fox", "a,b,,c", "x = 10, y = 0x1F, z = -3.5e2",
+                               "aaa\0bbb", "[[x]]" }
+            local patterns = { "", "o", "l+", "l*", "l-", "l?o", "^h", "d$", "^$", "%a+", "%A+",
+                               "(%w+)%s*=%s*(%w+)", "%b()", "%f[%w]%w+", "()ll()", "(h)(e)(l)",
+                               "[%a_][%w_]*", "[^,]*", "[a-c]+", "[%]]", "(a)%1", "%d+%.?%d*",
+                               "%s", "%S+", "%p", "%x+", "%u%l", ".-b", "[]", "[^", "%", "(()",
+                               "%1", "(a", "a)", "%bx", "%f", "%z", "[%w-]+", "x%-" }
+            for _, s in ipairs(subjects) do
+                for _, p in ipairs(patterns) do
+                    same("find", s, p)
+                    same("find", s, p, 3)
+                    same("find", s, p, -4, true)
+                    same("match", s, p)
+                    same("match", s, p, 100)
+                    same("gsub", s, p, "<%0>")
+                    same("gsub", s, p, "%%", 2)
+                    same("gsub", s, p, "%1")
+                    same("gsub", s, p, { hello = "bye", a = false })
+                    same("gsub", s, p, function(c) return c and #tostring(c) end)
+                    same("gsub", s, p, "%x")
+                    all(s, p)
+                end
+            end
+            same("gsub", "abc", "%w", "%2")
+            same("gsub", "abc", "%w", {})
+            same("gsub", "abc", "%w", true)
+            same("find", 12345, 3)
+
+            local ok, err = pcall(string.find, ("a"):rep(10000), ".-.-.-b")
+            assert(not ok and type(err) == "string")
+            assert(err:find("pattern matching step limit exceeded"))
+            ok, err = pcall(function() return (("a"):rep(10000)):find(".-.-.-b") end)
+            assert(not ok and err:find("pattern matching step limit exceeded"))
+            ok, err = pcall(package.loaded.string.match, ("a"):rep(10000), ".-.-.-b")
+            assert(not ok and err:find("pattern matching step limit exceeded"))
+            ok, err = pcall(string.gmatch(("a"):rep(10000), ".-.-.-b"))
+            assert(not ok and err:find("pattern matching step limit exceeded"))
+            ok, err = pcall(string.find, "abc", "[a")
+            assert(not ok and type(err) == "string" and err:find("malformed pattern"))
+            assert(not pcall(string.gsub, ("a"):rep(10000), "a*a*a*b", ""))
+            assert(string.find(("a"):rep(10000), "a+$") == 1)
+        "#,
+        None,
+    ).unwrap();
+}
+
+#[test]
+fn test_non_zero_conversion() {
+    use std::num::{NonZeroI32, NonZeroU64};
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    globals.set("n", NonZeroU64::new(7).unwrap()).unwrap();
+    assert_eq!(globals.get::<_, u64>("n").unwrap(), 7);
+    assert_eq!(
+        globals.get::<_, NonZeroI32>("n").unwrap(),
+        NonZeroI32::new(7).unwrap()
+    );
+
+    match lua.eval::<NonZeroU64>("0", None) {
+        Err(Error::FromLuaConversionError {
+            to: "NonZeroU64",
+            message: Some(message),
+            ..
+        }) => assert_eq!(message, "expected a non-zero integer, got 0"),
+        r => panic!("wrong result for zero: {:?}", r),
+    }
+    assert!(lua.eval::<NonZeroU64>("-1", None).is_err());
+}
+
+#[test]
+fn test_either_conversion() {
+    use Either;
+
+    let lua = Lua::new();
+
+    assert_eq!(
+        lua.eval::<Either<i64, String>>("1", None).unwrap(),
+        Either::Left(1)
+    );
+    assert_eq!(
+        lua.eval::<Either<i64, String>>("'a'", None).unwrap(),
+        Either::Right("a".to_owned())
+    );
+    match lua.eval::<Either<i64, Table>>("true", None) {
+        Err(Error::FromLuaConversionError {
+            from: "boolean",
+            message: Some(message),
+            ..
+        }) => {
+            assert_eq!(
+                message,
+                "both conversions failed: error converting Lua boolean to integer; \
+                 error converting Lua boolean to table"
+            );
+        }
+        r => panic!("wrong result for failed Either conversion: {:?}", r),
+    }
+
+    lua.globals()
+        .set("e", Either::Right::<i64, _>("b"))
+        .unwrap();
+    assert_eq!(lua.eval::<String>("e", None).unwrap(), "b");
+}
+
+#[test]
+fn test_missing_arguments() {
+    use Opt;
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let f = lua.create_function(|_, (a, b): (Opt<Option<i64>>, Opt<i64>)| {
+        Ok((format!("{:?}", a.0), format!("{:?}", b.0)))
+    });
+    globals.set("f", f).unwrap();
+
+    assert_eq!(
+        lua.eval::<(String, String)>("f()", None).unwrap(),
+        ("None".to_owned(), "None".to_owned())
+    );
+    assert_eq!(
+        lua.eval::<(String, String)>("f(nil)", None).unwrap(),
+        ("Some(None)".to_owned(), "None".to_owned())
+    );
+    assert_eq!(
+        lua.eval::<(String, String)>("f(1, 2)", None).unwrap(),
+        ("Some(Some(1))".to_owned(), "Some(2)".to_owned())
+    );
+    assert!(lua.eval::<(String, String)>("f(1, nil)", None).is_err());
+
+    let g = lua.create_function(|_, a: Opt<String>| Ok(a.0.is_none()));
+    globals.set("g", g).unwrap();
+    assert!(lua.eval::<bool>("g()", None).unwrap());
+    assert!(!lua.eval::<bool>("g('a')", None).unwrap());
+}
+
+#[test]
+fn test_variadic() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let join = lua.create_function(|_, (sep, parts): (String, Variadic<String>)| {
+        Ok(parts.join(&sep))
+    });
+    globals.set("join", join).unwrap();
+    assert_eq!(
+        lua.eval::<String>(r#"join(", ", "a", "b", "c")"#, None).unwrap(),
+        "a, b, c"
+    );
+    assert_eq!(lua.eval::<String>(r#"join(", ")"#, None).unwrap(), "");
+
+    let double = lua.create_function(|_, mut vals: Variadic<i64>| {
+        for v in &mut vals {
+            *v *= 2;
+        }
+        Ok((vals.len(), vals))
+    });
+    globals.set("double", double).unwrap();
+    let (len, vals) = lua.eval::<(usize, Variadic<i64>)>("double(1, 2, 3)", None)
+        .unwrap();
+    assert_eq!(len, 3);
+    assert_eq!(Vec::from(vals), vec![2, 4, 6]);
+
+    let sum = lua.create_function(|_, vals: Variadic<i64>| {
+        Ok((&vals).into_iter().sum::<i64>())
+    });
+    let vals: Variadic<i64> = vec![1, 2, 3].into();
+    assert_eq!(sum.call::<_, i64>(vals).unwrap(), 6);
+}
+
+#[test]
+fn test_multi_value() {
+    use MultiValue;
+
+    let lua = Lua::new();
+
+    let mut multi = MultiValue::with_capacity(2);
+    multi.push_back(Value::Integer(1));
+    multi.push_back(Value::Boolean(true));
+    match (multi.get(0), multi.get(1), multi.get(2)) {
+        (Some(&Value::Integer(1)), Some(&Value::Boolean(true)), None) => {}
+        v => panic!("unexpected values {:?}", v),
+    }
+
+    let values = multi.into_vec();
+    assert_eq!(values.len(), 2);
+    let multi = MultiValue::from_vec(values);
+    assert_eq!(lua.unpack_multi::<(i64, bool)>(multi).unwrap(), (1, true));
+
+    // Buffers are reused between calls, which must not leak values from one call into the next.
+    let count = lua.create_function(|_, args: MultiValue| Ok(args.len()));
+    for i in 0..20 {
+        let args = (0..i).collect::<Variadic<_>>();
+        assert_eq!(count.call::<_, usize>(args).unwrap(), i);
+    }
+}
+
+#[test]
+fn test_value_accessors() {
+    let lua = Lua::new();
+    let values = lua.eval::<Variadic<Value>>(
+        r#"return nil, true, 3, 1.5, "abc", "\xff", {}, print, coroutine.create(print)"#,
+        None,
+    ).unwrap();
+
+    assert!(values[0].is_nil());
+    assert!(!values[1].is_nil());
+    assert_eq!(values[1].as_boolean(), Some(true));
+    assert_eq!(values[0].as_boolean(), None);
+    assert_eq!(values[2].as_integer(), Some(3));
+    assert_eq!(values[3].as_integer(), None);
+    assert_eq!(values[2].as_number(), Some(3.0));
+    assert_eq!(values[3].as_number(), Some(1.5));
+    assert_eq!(values[4].as_str(), Some("abc"));
+    assert_eq!(values[5].as_str(), None);
+    assert_eq!(values[5].as_string().unwrap().as_bytes(), b"\xff");
+    assert_eq!(values[2].as_str(), None);
+    assert!(values[6].as_table().is_some());
+    assert!(values[7].as_function().is_some());
+    assert!(values[8].as_thread().is_some());
+    assert!(values[6].as_userdata().is_none());
+    assert!(values[7].as_table().is_none());
+}
+
+#[test]
+fn test_value_equals() {
+    let lua = Lua::new();
+    let values = lua.eval::<Variadic<Value>>(
+        r#"
+            local mt = {
+                __eq = function(a, b)
+                    if a.id == "bad" then error("cannot compare") end
+                    return a.id == b.id
+                end
+            }
+            local t = {}
+            return 1, 1.0, 1.5, "a", "a", t, t, {}, setmetatable({ id = 1 }, mt),
+                setmetatable({ id = 1 }, mt), setmetatable({ id = "bad" }, mt)
+        "#,
+        None,
+    ).unwrap();
+
+    assert!(values[0].equals(&values[1]).unwrap());
+    assert!(!values[0].equals(&values[2]).unwrap());
+    assert!(values[3].equals(&values[4]).unwrap());
+    assert!(!values[3].equals(&values[0]).unwrap());
+    assert!(values[5].equals(&values[6]).unwrap());
+    assert!(!values[5].equals(&values[7]).unwrap());
+    assert!(values[8].equals(&values[9]).unwrap());
+    assert!(values[10].equals(&values[8]).is_err());
+    assert!(Value::Nil.equals(&Value::Nil).unwrap());
+    assert!(Value::Integer(3).equals(&Value::Number(3.0)).unwrap());
+    assert!(!Value::Integer(3).equals(&Value::Boolean(true)).unwrap());
+}
+
+#[test]
+fn test_value_to_string_lua() {
+    let lua = Lua::new();
+    let values = lua.eval::<Variadic<Value>>(
+        r#"
+            return nil, true, 10, 1.5, 1e100, "a\0b",
+                setmetatable({}, { __tostring = function() return "custom" end }),
+                setmetatable({}, { __tostring = function() error("oops") end })
+        "#,
+        None,
+    ).unwrap();
+    let strings = values
+        .iter()
+        .take(7)
+        .map(|v| v.to_string_lua(&lua).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        strings,
+        vec!["nil", "true", "10", "1.5", "1e+100", "a\0b", "custom"]
+    );
+    assert!(values[7].to_string_lua(&lua).is_err());
+
+    let table = lua.create_table();
+    assert!(
+        Value::Table(table)
+            .to_string_lua(&lua)
+            .unwrap()
+            .starts_with("table: ")
+    );
+}
+
+#[test]
+fn test_value_debug_string() {
+    let lua = Lua::new();
+    let value = lua.eval::<Value>(
+        r#"
+            local t = { 1, "two", [10] = false, ["key with spaces"] = 1.5, x = { y = {} } }
+            t.self = t
+            return t
+        "#,
+        None,
+    ).unwrap();
+
+    assert_eq!(
+        value.to_debug_string(3),
+        r#"{1, "two", ["key with spaces"] = 1.5, [10] = false, self = <cycle>, x = {y = {}}}"#
+    );
+    assert_eq!(
+        value.to_debug_string(1),
+        r#"{1, "two", ["key with spaces"] = 1.5, [10] = false, self = <cycle>, x = {...}}"#
+    );
+    assert_eq!(value.to_debug_string(0), "{...}");
+    assert_eq!(
+        format!("{:?}", value),
+        format!("Table({})", value.to_debug_string(3))
+    );
+
+    let string = lua.eval::<Value>(r#""a\"b""#, None).unwrap();
+    assert_eq!(format!("{:?}", string), r#"String("a\"b")"#);
+    assert_eq!(format!("{:?}", Value::Number(2.0)), "Number(2.0)");
+    assert!(
+        lua.eval::<Value>("print", None)
+            .unwrap()
+            .to_debug_string(1)
+            .starts_with("function: 0x")
+    );
+}
+
+#[test]
+fn coroutine_from_closure() {
+    let lua = Lua::new();
+    let thrd_main = lua.create_function(|_, ()| Ok(()));
+    lua.globals().set("main", thrd_main).unwrap();
+    let thrd: Thread = lua.eval("coroutine.create(main)", None).unwrap();
+    thrd.resume::<_, ()>(()).unwrap();
+}
+
+#[test]
+#[should_panic]
+fn coroutine_panic() {
+    let lua = Lua::new();
+    let thrd_main = lua.create_function(|lua, ()| {
+        // whoops, 'main' has a wrong type
+        let _coro: u32 = lua.globals().get("main").unwrap();
+        Ok(())
+    });
+    lua.globals().set("main", thrd_main.clone()).unwrap();
+    let thrd: Thread = lua.create_thread(thrd_main);
+    thrd.resume::<_, ()>(()).unwrap();
+}
+
+#[test]
+#[allow(clippy::bool_assert_comparison)]
+fn test_pcall_xpcall() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    // make sure that we handle not enough arguments
+    assert!(lua.exec::<()>("pcall()", None).is_err());
+    assert!(lua.exec::<()>("xpcall()", None).is_err());
+    assert!(lua.exec::<()>("xpcall(function() end)", None).is_err());
+
+    // Make sure that the return values from are correct on success
+    assert_eq!(lua.eval::<(bool, String)>("pcall(function(p) return p end, 'foo')", None).unwrap(), (true, "foo".to_owned()));
+    assert_eq!(lua.eval::<(bool, String)>("xpcall(function(p) return p end, print, 'foo')", None).unwrap(), (true, "foo".to_owned()));
+
+    // Make sure that the return values are correct on errors, and that error handling works
+
+    lua.exec::<()>(
+        r#"
+            pcall_error = nil
+            pcall_status, pcall_error = pcall(error, "testerror")
+
+            xpcall_error = nil
+            xpcall_status, _ = xpcall(error, function(err) xpcall_error = err end, "testerror")
+        "#,
+        None,
+    ).unwrap();
+
+    assert_eq!(globals.get::<_, bool>("pcall_status").unwrap(), false);
+    assert_eq!(
+        globals.get::<_, String>("pcall_error").unwrap(),
+        "testerror"
+    );
+
+    assert_eq!(globals.get::<_, bool>("xpcall_statusr").unwrap(), false);
+    assert_eq!(
+        globals.get::<_, String>("xpcall_error").unwrap(),
+        "testerror"
+    );
+
+    // Make sure that weird xpcall error recursion at least doesn't cause unsafety or panics.
+    lua.exec::<()>(
+        r#"
+            function xpcall_recursion()
+                xpcall(error, function(err) error(err) end, "testerror")
+            end
+        "#,
+        None,
+    ).unwrap();
+    let _ = globals
+        .get::<_, Function>("xpcall_recursion")
+        .unwrap()
+        .call::<_, ()>(());
+}
+
+#[test]
+#[should_panic]
+fn test_recursive_callback_panic() {
+    let lua = Lua::new();
+
+    let mut v = Some(Box::new(123));
+    let f = lua.create_function::<_, (), _>(move |lua, mutate: bool| {
+        if mutate {
+            v = None;
+        } else {
+            // Produce a mutable reference
+            let r = v.as_mut().unwrap();
+            // Whoops, this will recurse into the function and produce another mutable reference!
+            lua.globals()
+                .get::<_, Function>("f")
+                .unwrap()
+                .call::<_, ()>(true)
+                .unwrap();
+            println!("Should not get here, mutable aliasing has occurred!");
+            println!("value at {:p}", r as *mut _);
+            println!("value is {}", r);
+        }
+
+        Ok(())
+    });
+    lua.globals().set("f", f).unwrap();
+    lua.globals()
+        .get::<_, Function>("f")
+        .unwrap()
+        .call::<_, ()>(false)
+        .unwrap();
+}
+
+#[test]
+fn test_set_metatable_nil() {
+    let lua = Lua::new();
+    lua.exec::<()>(
+    r#"
+        a = {}
+        setmetatable(a, nil)
+    "#, None).unwrap();
+}
+
+#[test]
+fn test_to_pointer() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+    lua.exec::<()>(
+        r#"
+            t = {}
+            same = t
+            other = {}
+            f = function() end
+            co = coroutine.create(f)
+        "#,
+        None,
+    ).unwrap();
+
+    let t = globals.get::<_, Table>("t").unwrap();
+    let same = globals.get::<_, Table>("same").unwrap();
+    let other = globals.get::<_, Table>("other").unwrap();
+    assert_eq!(t.to_pointer(), same.to_pointer());
+    assert_ne!(t.to_pointer(), other.to_pointer());
+
+    let f = globals.get::<_, Function>("f").unwrap();
+    let co = globals.get::<_, Thread>("co").unwrap();
+    assert!(!f.to_pointer().is_null());
+    assert!(!co.to_pointer().is_null());
+    assert_eq!(globals.get::<_, Value>("f").unwrap().to_pointer(), f.to_pointer());
+    assert!(Value::Integer(1).to_pointer().is_null());
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn test_log_module() {
+    use std::sync::Mutex;
+    use log::{self, Level, LevelFilter, Log, Metadata, Record};
+
+    struct TestLogger(Mutex<Vec<(Level, String, String)>>);
+
+    impl Log for TestLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push((
+                record.level(),
+                record.target().to_owned(),
+                record.args().to_string(),
+            ));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger(Mutex::new(Vec::new()));
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Debug);
+
+    let lua = Lua::new();
+    lua.load_log_module("log").unwrap();
+    lua.exec::<()>(
+        r#"
+            log.info("%d items", 3)
+            require("log").error({})
+            log.debug()
+            log.trace("filtered")
+        "#,
+        Some("script.lua"),
+    ).unwrap();
+    assert!(lua.exec::<()>("log.warn('%d', 'x')", None).is_err());
+
+    let records = LOGGER.0.lock().unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(
+        records[0],
+        (Level::Info, "script.lua:2".to_owned(), "3 items".to_owned())
+    );
+    assert_eq!(records[1].0, Level::Error);
+    assert_eq!(records[1].1, "script.lua:3");
+    assert!(records[1].2.starts_with("table: "));
+    assert_eq!(records[2], (Level::Debug, "script.lua:4".to_owned(), String::new()));
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_regex_module() {
+    let lua = Lua::new();
+    lua.load_regex_module("re").unwrap();
+    lua.exec::<()>(
+        r#"
+            local word = re.compile([[\w+]])
+            assert(tostring(word) == [[\w+]])
+            assert(word:is_match("  hi"))
+            assert(not word:is_match("hi  ", 3))
+
+            local s, e = word:find("  hello world")
+            assert(s == 3 and e == 7)
+            s, e = word:find("  hello world", -5)
+            assert(s == 9 and e == 13)
+            assert(word:find("  ") == nil)
+
+            assert(word:match("  hello") == "hello")
+            local key, value = re.match([[(\w+)=(\w+)?]], "a=")
+            assert(key == "a" and value == false)
+            assert(re.captures("x", "y") == nil)
+
+            local upper, count = re.replace("[aeiou]", "banana", function(v)
+                if v == "a" then return v:upper() end
+            end)
+            assert(upper == "bAnAnA" and count == 3)
+            assert(select(2, re.replace("a", "aaa", "b", 2)) == 2)
+            assert(re.replace("(a)(b)", "abab", "$2$1") == "baba")
+
+            local parts = re.split([[\s*;\s*]], "a ; b;c", 2)
+            assert(#parts == 2 and parts[1] == "a" and parts[2] == "b;c")
+        "#,
+        None,
+    ).unwrap();
+
+    assert!(lua.exec::<()>("re.compile('(')", None).is_err());
+    assert!(lua.exec::<()>("re.replace('a', 'a', {})", None).is_err());
+    assert!(lua.exec::<()>("assert(require('re') == re)", None).is_ok());
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn test_json_module() {
+    let lua = Lua::new();
+    lua.load_json_module("json").unwrap();
+    lua.exec::<()>(
+        r#"
+            local value = json.decode('{"a": [1, 2.5, null, "x"], "b": {}, "c": true}')
+            assert(value.a[1] == 1 and math.type(value.a[1]) == "integer")
+            assert(value.a[2] == 2.5 and value.a[3] == json.null and value.a[4] == "x")
+            assert(value.c == true and next(value.b) == nil)
+            assert(json.encode(value) == '{"a":[1,2.5,null,"x"],"b":{},"c":true}')
+            assert(json.encode(json.decode("[]")) == "[]")
+            assert(json.encode({1, 2}, {pretty = true}) == "[\n  1,\n  2\n]")
+
+            local nulls = json.decode('{"a": null}', {null = "none"})
+            assert(nulls.a == "none")
+            assert(json.encode(nil) == "null")
+        "#,
+        None,
+    ).unwrap();
+
+    assert!(lua.exec::<()>("json.decode('{')", None).is_err());
+    assert!(lua.exec::<()>("json.encode(print)", None).is_err());
+    assert!(lua.exec::<()>("local t = {} t.t = t json.encode(t)", None).is_err());
+    lua.exec::<()>(
+        r#"
+            local t = {}
+            for i = 1, 1e6 do t = {t} end
+            local ok, err = pcall(json.encode, t)
+            assert(not ok and tostring(err):find("nested too deeply"))
+        "#,
+        None,
+    ).unwrap();
+    assert!(lua.exec::<()>("assert(require('json') == json)", None).is_ok());
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn test_msgpack() {
+    let lua = Lua::new();
+
+    let value = lua.eval::<Value>(
+        r#"{ 1, 2.5, "three", { nested = true, [10] = "\xff" }, {} }"#,
+        None,
+    ).unwrap();
+    let bytes = value.to_msgpack().unwrap();
+    // A fixarray of five elements, starting with a positive fixint.
+    assert_eq!(&bytes[..2], &[0x95, 0x01]);
+
+    let decoded = lua.value_from_msgpack(&bytes).unwrap();
+    let check = lua.eval::<Function>(
+        r#"
+            function(t)
+                assert(#t == 5 and math.type(t[1]) == "integer" and t[2] == 2.5)
+                assert(t[3] == "three" and t[4].nested == true and t[4][10] == "\xff")
+                assert(next(t[5]) == nil)
+            end
+        "#,
+        None,
+    ).unwrap();
+    check.call::<_, ()>(decoded).unwrap();
 
-    globals.set("err", err).unwrap();
-    globals.set("ok", ok).unwrap();
+    assert_eq!(Value::Nil.to_msgpack().unwrap(), vec![0xc0]);
+    assert!(lua.value_from_msgpack(&[0xc0, 0xc0]).is_err());
+    assert!(lua.value_from_msgpack(&[0x81, 0xc0, 0x01]).is_err());
+    assert!(lua.value_from_msgpack(&[0x92, 0x01]).is_err());
+
+    let recursive = lua.eval::<Value>("local t = {} t.t = t return t", None).unwrap();
+    assert!(recursive.to_msgpack().is_err());
+    let nested = "local t = {} for i = 1, NESTING do t = {t} end return t";
+    let deep = lua.eval::<Value>(&nested.replace("NESTING", "127"), None).unwrap();
+    assert!(deep.to_msgpack().is_ok());
+    let deep = lua.eval::<Value>(&nested.replace("NESTING", "1e6"), None).unwrap();
+    match deep.to_msgpack() {
+        Err(Error::FromLuaConversionError { .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
+    let function = lua.eval::<Value>("print", None).unwrap();
+    assert!(function.to_msgpack().is_err());
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn test_table_from_toml() {
+    let lua = Lua::new();
+    let config = lua.table_from_toml(
+        r#"
+            name = "service"
+            started = 1979-05-27T07:32:00Z
+
+            [limits]
+            ratio = 0.5
+            sizes = [1, 2, 3]
 
+            [[plugins]]
+            path = "a.lua"
+        "#,
+    ).unwrap();
+    lua.globals().set("config", config).unwrap();
     lua.exec::<()>(
         r#"
-            local r, e = err()
-            assert(r == nil)
-            assert(tostring(e) == "only through failure can we succeed")
+            assert(config.name == "service" and config.started == "1979-05-27T07:32:00Z")
+            assert(config.limits.ratio == 0.5 and #config.limits.sizes == 3)
+            assert(math.type(config.limits.sizes[1]) == "integer")
+            assert(config.plugins[1].path == "a.lua")
+        "#,
+        None,
+    ).unwrap();
 
-            local r, e = ok()
-            assert(r == "!")
-            assert(e == nil)
+    assert!(lua.table_from_toml("name = ").is_err());
+}
+
+#[cfg(feature = "yaml")]
+#[test]
+fn test_table_from_yaml() {
+    let lua = Lua::new();
+    let config = lua.table_from_yaml(
+        r#"
+            name: service
+            limits:
+              ratio: 0.5
+              sizes: [1, 2, 3]
+            optional: ~
+            1: one
+            tagged: !wrapped 5
+        "#,
+    ).unwrap();
+    lua.globals().set("config", config).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(config.name == "service" and config[1] == "one" and config.optional == nil)
+            assert(config.limits.ratio == 0.5 and #config.limits.sizes == 3)
+            assert(config.tagged == 5)
         "#,
         None,
     ).unwrap();
+
+    assert_eq!(lua.table_from_yaml("").unwrap().len().unwrap(), 0);
+    assert!(lua.table_from_yaml("- 1").is_err());
+    assert!(lua.table_from_yaml("~: 1").is_err());
+    assert!(lua.table_from_yaml("a: [").is_err());
 }
 
+#[cfg(feature = "chrono")]
 #[test]
-fn test_num_conversion() {
+fn test_chrono_conversions() {
+    use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
     let lua = Lua::new();
     let globals = lua.globals();
 
-    globals.set("n", "1.0").unwrap();
-    assert_eq!(globals.get::<_, i64>("n").unwrap(), 1);
-    assert_eq!(globals.get::<_, f64>("n").unwrap(), 1.0);
-    assert_eq!(globals.get::<_, String>("n").unwrap(), "1.0");
+    let time = Utc.with_ymd_and_hms(2018, 3, 21, 12, 30, 0).unwrap();
+    globals.set("time", time).unwrap();
+    assert_eq!(lua.eval::<i64>("time", None).unwrap(), 1521635400);
+    let later = lua.eval::<DateTime<Utc>>("time + 0.5", None).unwrap();
+    assert_eq!(later.timestamp_subsec_millis(), 500);
+    assert_eq!(
+        lua.eval::<DateTime<Utc>>("{ year = 2018, month = 3, day = 21, hour = 12, min = 30 }", None)
+            .unwrap(),
+        time
+    );
+    let offset = lua.eval::<DateTime<FixedOffset>>("'2018-03-21T14:30:00+02:00'", None).unwrap();
+    assert_eq!(offset, time);
+    assert_eq!(offset.offset().local_minus_utc(), 7200);
 
-    globals.set("n", "1.5").unwrap();
-    assert!(globals.get::<_, i64>("n").is_err());
-    assert_eq!(globals.get::<_, f64>("n").unwrap(), 1.5);
-    assert_eq!(globals.get::<_, String>("n").unwrap(), "1.5");
+    globals.set("date", NaiveDate::from_ymd_opt(2018, 3, 21).unwrap()).unwrap();
+    assert!(lua.eval::<bool>("date.year == 2018 and date.wday == 4 and date.yday == 80", None)
+        .unwrap());
+    assert_eq!(
+        lua.eval::<NaiveDate>("'2018-03-21'", None).unwrap(),
+        NaiveDate::from_ymd_opt(2018, 3, 21).unwrap()
+    );
 
-    globals.set("n", 1.5).unwrap();
-    assert!(globals.get::<_, i64>("n").is_err());
-    assert_eq!(globals.get::<_, f64>("n").unwrap(), 1.5);
-    assert_eq!(globals.get::<_, String>("n").unwrap(), "1.5");
+    globals.set("naive", time.naive_utc()).unwrap();
+    assert_eq!(lua.eval::<NaiveDateTime>("naive", None).unwrap(), time.naive_utc());
+    assert_eq!(
+        lua.eval::<NaiveDateTime>("'2018-03-21T12:30:00'", None).unwrap(),
+        time.naive_utc()
+    );
 
-    lua.exec::<()>("a = math.huge", None).unwrap();
-    assert!(globals.get::<_, i64>("n").is_err());
+    assert!(lua.eval::<NaiveDate>("{ year = 2018, month = 2, day = 30 }", None).is_err());
+    assert!(lua.eval::<DateTime<Utc>>("1e300", None).is_err());
+    assert!(lua.eval::<DateTime<Utc>>("'yesterday'", None).is_err());
 }
 
+#[cfg(feature = "chrono")]
 #[test]
-fn coroutine_from_closure() {
+fn test_datetime_module() {
     let lua = Lua::new();
-    let thrd_main = lua.create_function(|_, ()| Ok(()));
-    lua.globals().set("main", thrd_main).unwrap();
-    let thrd: Thread = lua.eval("coroutine.create(main)", None).unwrap();
-    thrd.resume::<_, ()>(()).unwrap();
+    lua.load_datetime_module("datetime").unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(math.abs(datetime.now() - os.time()) < 5)
+
+            local time = datetime.parse("2018-03-21T22:30:00.25Z")
+            assert(time == 1521671400.25)
+            assert(datetime.format(time, "%d.%m.%Y %H:%M:%S%.3f") == "21.03.2018 22:30:00.250")
+            assert(datetime.format(time, "%H:%M %:z", "-0330") == "19:00 -03:30")
+            assert(datetime.parse("21/03/2018 22:30", "%d/%m/%Y %H:%M") == 1521671400)
+
+            local date = datetime.date(time, "+02:00")
+            assert(date.year == 2018 and date.month == 3 and date.day == 22 and date.hour == 0)
+            assert(date.sec == 0.25 and date.wday == 5 and date.offset == 7200)
+            assert(datetime.time(date, "+02:00") == time)
+            assert(datetime.time({ year = 1970, month = 1, day = 1 }) == 0)
+
+            assert(not pcall(datetime.format, time, "%Q"))
+            assert(not pcall(datetime.format, time, "%Y", "Mars/Olympus"))
+            assert(not pcall(datetime.parse, "tomorrow"))
+            assert(not pcall(datetime.time, { year = 2018, month = 13, day = 1 }))
+        "#,
+        None,
+    ).unwrap();
 }
 
 #[test]
-#[should_panic]
-fn coroutine_panic() {
-    let lua = Lua::new();
-    let thrd_main = lua.create_function(|lua, ()| {
-        // whoops, 'main' has a wrong type
-        let _coro: u32 = lua.globals().get("main").unwrap();
-        Ok(())
-    });
-    lua.globals().set("main", thrd_main.clone()).unwrap();
-    let thrd: Thread = lua.create_thread(thrd_main);
-    thrd.resume::<_, ()>(()).unwrap();
+fn test_set_clock() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let lua = Lua::new_lazy();
+    let now = Rc::new(Cell::new(UNIX_EPOCH - Duration::from_millis(1500)));
+    let time = now.clone();
+    lua.set_clock(move || time.get(), || Duration::from_millis(250)).unwrap();
+
+    lua.exec::<()>(
+        r#"
+            assert(os.time() == -2)
+            assert(os.clock() == 0.25)
+        "#,
+        None,
+    ).unwrap();
+
+    now.set(UNIX_EPOCH + Duration::from_secs(86400 * 365));
+    lua.exec::<()>(
+        r#"
+            assert(math.type(os.time()) == "integer" and os.time() == 86400 * 365)
+            assert(os.date("!%Y-%m-%d %H:%M:%S") == "1971-01-01 00:00:00")
+            assert(os.date("!*t").year == 1971)
+            assert(os.date(nil, 0) == os.date("%c", 0))
+            assert(os.date("!%Y", 86400 * 366 * 3) == "1973")
+            local date = os.date("*t")
+            assert(os.time(date) == os.time())
+        "#,
+        None,
+    ).unwrap();
 }
 
+#[cfg(feature = "rand")]
 #[test]
-fn test_pcall_xpcall() {
+fn test_install_rng() {
+    use rand_chacha::ChaCha8Rng;
+    use rand_chacha::rand_core::SeedableRng;
+
     let lua = Lua::new();
-    let globals = lua.globals();
+    lua.install_rng(ChaCha8Rng::seed_from_u64(7)).unwrap();
+    lua.exec::<()>(
+        r#"
+            for _ = 1, 1000 do
+                local f = math.random()
+                assert(math.type(f) == "float" and f >= 0 and f < 1)
+                local d = math.random(6)
+                assert(math.type(d) == "integer" and d >= 1 and d <= 6)
+                local r = math.random(-3, 3)
+                assert(r >= -3 and r <= 3)
+            end
+            assert(math.random(5, 5) == 5)
+            assert(math.type(math.random(math.mininteger, math.maxinteger)) == "integer")
 
-    // make sure that we handle not enough arguments
-    assert!(lua.exec::<()>("pcall()", None).is_err());
-    assert!(lua.exec::<()>("xpcall()", None).is_err());
-    assert!(lua.exec::<()>("xpcall(function() end)", None).is_err());
+            math.randomseed(1234)
+            local a = { math.random(1000), math.random(), math.random(-10, 10) }
+            math.randomseed(1234.0)
+            local b = { math.random(1000), math.random(), math.random(-10, 10) }
+            assert(a[1] == b[1] and a[2] == b[2] and a[3] == b[3])
+        "#,
+        None,
+    ).unwrap();
 
-    // Make sure that the return values from are correct on success
-    assert_eq!(lua.eval::<(bool, String)>("pcall(function(p) return p end, 'foo')", None).unwrap(), (true, "foo".to_owned()));
-    assert_eq!(lua.eval::<(bool, String)>("xpcall(function(p) return p end, print, 'foo')", None).unwrap(), (true, "foo".to_owned()));
+    // The same seed gives the same numbers in another state.
+    let other = Lua::new();
+    other.install_rng(ChaCha8Rng::seed_from_u64(0)).unwrap();
+    let script = "math.randomseed(99) return math.random(1 << 40)";
+    assert_eq!(
+        lua.eval::<i64>(script, None).unwrap(),
+        other.eval::<i64>(script, None).unwrap()
+    );
 
-    // Make sure that the return values are correct on errors, and that error handling works
+    match lua.exec::<()>("math.random(2, 1)", None) {
+        Err(Error::CallbackError { ref cause, .. }) => {
+            assert_eq!(
+                cause.to_string(),
+                "bad argument #2 to 'random' (runtime error: interval is empty)"
+            )
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn test_http_module() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use HttpOptions;
+
+    // Answers requests with the method and body of the request.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        for _ in 0..3 {
+            let mut stream = listener.accept().unwrap().0;
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            let head_end = loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break i + 4;
+                }
+            };
+            let head = ::std::string::String::from_utf8_lossy(&request[..head_end]).into_owned();
+            let length = head
+                .lines()
+                .filter_map(|line| {
+                    let line = line.to_ascii_lowercase();
+                    let length = line.strip_prefix("content-length:")?;
+                    length.trim().parse::<usize>().ok()
+                })
+                .next()
+                .unwrap_or(0);
+            while request.len() < head_end + length {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let method = head.split(' ').next().unwrap().to_owned();
+            let body = &request[head_end..];
+            write!(
+                stream,
+                "HTTP/1.1 201 Created\r\nContent-Length: {}\r\nX-Method: {}\r\n\
+                 Connection: close\r\n\r\n",
+                body.len() + 5,
+                method
+            ).unwrap();
+            stream.write_all(b"echo:").unwrap();
+            stream.write_all(body).unwrap();
+        }
+    });
+
+    let lua = Lua::new();
+    let options = HttpOptions {
+        allowed_hosts: vec!["127.0.0.1".to_owned(), "*.example.com".to_owned()],
+        max_body_size: 10,
+        ..HttpOptions::default()
+    };
+    lua.load_http_module("http", options).unwrap();
+    lua.globals().set("base", format!("http://127.0.0.1:{}", port)).unwrap();
 
     lua.exec::<()>(
         r#"
-            pcall_error = nil
-            pcall_status, pcall_error = pcall(error, "testerror")
+            local status, headers, body = http.get(base .. "/path", { ["X-Test"] = "1" })
+            assert(status == 201)
+            assert(headers["x-method"] == "GET")
+            assert(body == "echo:")
 
-            xpcall_error = nil
-            xpcall_status, _ = xpcall(error, function(err) xpcall_error = err end, "testerror")
+            status, headers, body = http.post(base, "data")
+            assert(status == 201 and headers["x-method"] == "POST")
+            assert(body == "echo:data")
+
+            local r, e = http.post(base, "too long")
+            assert(r == nil and e == "response body is larger than 10 bytes")
+
+            assert(not pcall(http.get, "http://localhost/"))
+            assert(not pcall(http.get, "http://example.com/"))
+            assert(not pcall(http.get, "ftp://127.0.0.1/"))
+            assert(not pcall(http.get, "not a url"))
+            assert(not pcall(http.get, base, { Host = "internal.example.com" }))
+            assert(not pcall(http.post, base, "", { HOST = "internal.example.com" }))
+            assert(not pcall(http.get, base, { ["host "] = "internal.example.com" }))
         "#,
         None,
     ).unwrap();
+    server.join().unwrap();
 
-    assert_eq!(globals.get::<_, bool>("pcall_status").unwrap(), false);
+    // The server has stopped listening, so the connection is refused.
+    let (status, message) = lua
+        .eval::<(Value, String)>("http.get(base)", None)
+        .unwrap();
+    assert!(status.is_nil());
+    assert!(!message.is_empty());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_values() {
+    use std::collections::HashMap;
+    use serde_json;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { w: i64, h: i64 },
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Scene {
+        name: ::std::string::String,
+        shapes: Vec<Shape>,
+        origin: (i64, i64),
+        tag: Option<::std::string::String>,
+        weights: HashMap<::std::string::String, u32>,
+    }
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let mut weights = HashMap::new();
+    weights.insert("a".to_owned(), 1);
+    let scene = Scene {
+        name: "test".to_owned(),
+        shapes: vec![Shape::Empty, Shape::Circle(0.5), Shape::Rect { w: 2, h: 3 }],
+        origin: (4, 5),
+        tag: None,
+        weights,
+    };
+
+    globals.set("scene", lua.to_value(&scene).unwrap()).unwrap();
+    lua.exec::<()>(
+        r#"
+            assert(scene.name == "test")
+            assert(scene.shapes[1] == "Empty")
+            assert(scene.shapes[2].Circle == 0.5)
+            assert(scene.shapes[3].Rect.w == 2)
+            assert(scene.origin[2] == 5)
+            assert(scene.tag == nil)
+            assert(scene.weights.a == 1)
+            scene.weights.a = 6 / 2
+        "#,
+        None,
+    ).unwrap();
+
+    let value = globals.get::<_, Value>("scene").unwrap();
+    let scene: Scene = lua.from_value(value).unwrap();
+    assert_eq!(scene.weights["a"], 3);
+    assert_eq!(scene.shapes[2], Shape::Rect { w: 2, h: 3 });
+
+    let value = lua.eval::<Value>(r#"{ 1, "two", { three = true } }"#, None).unwrap();
     assert_eq!(
-        globals.get::<_, String>("pcall_error").unwrap(),
-        "testerror"
+        serde_json::to_string(&value).unwrap(),
+        r#"[1,"two",{"three":true}]"#
     );
 
-    assert_eq!(globals.get::<_, bool>("xpcall_statusr").unwrap(), false);
+    let recursive = lua.eval::<Value>("local t = {} t.t = t return t", None).unwrap();
+    assert!(serde_json::to_string(&recursive).is_err());
+    match lua.from_value::<serde_json::Value>(recursive) {
+        Err(Error::DeserializeError(_)) => {}
+        r => panic!("expected DeserializeError, got {:?}", r),
+    }
+    let recursive = lua.eval::<Value>("local t = {} t[1] = t return t", None).unwrap();
+    assert!(lua.from_value::<Vec<serde_json::Value>>(recursive).is_err());
+
+    let nested = "local t = {} for i = 1, NESTING do t = {t} end return t";
+    let value = lua.eval::<Value>(&nested.replace("NESTING", "127"), None).unwrap();
     assert_eq!(
-        globals.get::<_, String>("xpcall_error").unwrap(),
-        "testerror"
+        serde_json::to_string(&value).unwrap(),
+        format!("{}{{}}{}", "[".repeat(127), "]".repeat(127))
     );
+    assert!(lua.from_value::<serde_json::Value>(value).is_ok());
+    let value = lua.eval::<Value>(&nested.replace("NESTING", "200000"), None).unwrap();
+    assert!(serde_json::to_string(&value).is_err());
+    match lua.from_value::<serde_json::Value>(value) {
+        Err(Error::DeserializeError(_)) => {}
+        r => panic!("expected DeserializeError, got {:?}", r),
+    }
 
-    // Make sure that weird xpcall error recursion at least doesn't cause unsafety or panics.
+    let function = lua.eval::<Value>("function() end", None).unwrap();
+    assert!(serde_json::to_string(&function).is_err());
+    assert!(lua.from_value::<Vec<i64>>(Value::Boolean(true)).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serialize_options() {
+    use std::collections::HashMap;
+    use serde::ser::{SerializeStruct, Serializer};
+    use serde::Serialize;
+    use serde_json;
+    use SerializeOptions;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Record {
+        values: Vec<Option<i64>>,
+        empty: Vec<i64>,
+        unit: (),
+    }
+
+    // Serializes like a `serde_json` number with the `arbitrary_precision` feature.
+    struct ArbitraryNumber(&'static str);
+
+    impl Serialize for ArbitraryNumber {
+        fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut s = serializer.serialize_struct("$serde_json::private::Number", 1)?;
+            s.serialize_field("$serde_json::private::Number", self.0)?;
+            s.end()
+        }
+    }
+
+    let lua = Lua::new();
+    let globals = lua.globals();
+
+    let record = Record {
+        values: vec![Some(1), None, Some(3)],
+        empty: Vec::new(),
+        unit: (),
+    };
+    let options = SerializeOptions {
+        serialize_none_as_null: true,
+        serialize_unit_as_null: true,
+        set_array_metatable: true,
+        ..SerializeOptions::default()
+    };
+
+    globals.set("default", lua.to_value(&record).unwrap()).unwrap();
+    globals.set("record", lua.to_value_with(&record, options).unwrap()).unwrap();
+    globals.set("null", lua.null()).unwrap();
     lua.exec::<()>(
         r#"
-            function xpcall_recursion()
-                xpcall(error, function(err) error(err) end, "testerror")
-            end
+            assert(default.unit == nil and getmetatable(default.empty) == nil)
+            assert(#record.values == 3 and record.values[2] == null)
+            assert(record.unit == null)
+            assert(getmetatable(record.empty) ~= nil)
         "#,
         None,
     ).unwrap();
-    let _ = globals
-        .get::<_, Function>("xpcall_recursion")
-        .unwrap()
-        .call::<_, ()>(());
+
+    let value = globals.get::<_, Value>("record").unwrap();
+    assert_eq!(lua.from_value::<Record>(value.clone()).unwrap(), record);
+    let json = serde_json::to_string(&value).unwrap();
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+        serde_json::from_str::<serde_json::Value>(r#"{"empty":[],"unit":null,"values":[1,null,3]}"#)
+            .unwrap()
+    );
+    let map = lua.from_value::<HashMap<::std::string::String, serde_json::Value>>(value)
+        .unwrap();
+    assert_eq!(map["empty"], serde_json::Value::Array(Vec::new()));
+    assert_eq!(map["unit"], serde_json::Value::Null);
+
+    let big = lua.to_value(&ArbitraryNumber("12345678901234")).unwrap();
+    assert_eq!(lua.from_value::<i64>(big).unwrap(), 12345678901234);
+    let float = lua.to_value(&ArbitraryNumber("0.25")).unwrap();
+    assert_eq!(lua.from_value::<f64>(float).unwrap(), 0.25);
+    let options = SerializeOptions {
+        detect_serde_json_arbitrary_precision: false,
+        ..SerializeOptions::default()
+    };
+    let table = lua.to_value_with(&ArbitraryNumber("1"), options).unwrap();
+    assert_eq!(lua.unpack::<Table>(table).unwrap().raw_len(), 0);
 }
 
+#[cfg(feature = "serde")]
 #[test]
-#[should_panic]
-fn test_recursive_callback_panic() {
+fn test_serialize_error() {
+    use serde_json;
+    use ErrorContext;
+
     let lua = Lua::new();
 
-    let mut v = Some(Box::new(123));
-    let f = lua.create_function::<_, (), _>(move |lua, mutate: bool| {
-        if mutate {
-            v = None;
-        } else {
-            // Produce a mutable reference
-            let r = v.as_mut().unwrap();
-            // Whoops, this will recurse into the function and produce another mutable reference!
-            lua.globals()
-                .get::<_, Function>("f")
-                .unwrap()
-                .call::<_, ()>(true)
-                .unwrap();
-            println!("Should not get here, mutable aliasing has occurred!");
-            println!("value at {:p}", r as *mut _);
-            println!("value is {}", r);
+    let parse = lua.create_function(|_, n: u32| Ok(n));
+    lua.globals().set("parse", parse).unwrap();
+    let err = lua.exec::<()>("parse('abc')", Some("script.lua"))
+        .context("running script")
+        .unwrap_err();
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["kind"], "context");
+    assert_eq!(json["context"], "running script");
+    assert!(json.get("frames").is_none());
+
+    let callback = &json["cause"];
+    assert_eq!(callback["kind"], "callback");
+    assert!(callback["traceback"].as_str().unwrap().contains("stack traceback:"));
+    assert!(callback["frames"].as_array().unwrap().iter().any(|frame| {
+        frame["source"] == r#"[string "script.lua"]"# && frame["line"] == 1
+    }));
+    assert_eq!(
+        callback["cause"],
+        serde_json::json!({
+            "kind": "bad_argument",
+            "message": "bad argument #1 to 'parse' (error converting Lua string to integer)",
+            "function_name": "parse",
+            "arg_index": 1,
+            "cause": {
+                "kind": "from_lua_conversion",
+                "message": "error converting Lua string to integer",
+                "from": "string",
+                "to": "integer",
+            },
+        })
+    );
+
+    let err = lua.exec::<()>("error({ code = 404, tags = { 'a' } }, 0)", None)
+        .unwrap_err();
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["value"], serde_json::json!({ "code": 404, "tags": { "1": "a" } }));
+
+    #[derive(Debug)]
+    struct Outer(fmt::Error);
+
+    impl fmt::Display for Outer {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            write!(fmt, "outer error")
         }
+    }
 
-        Ok(())
-    });
-    lua.globals().set("f", f).unwrap();
-    lua.globals()
-        .get::<_, Function>("f")
-        .unwrap()
-        .call::<_, ()>(false)
-        .unwrap();
+    impl error::Error for Outer {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    let err = Error::external(Outer(fmt::Error));
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["kind"], "external");
+    assert_eq!(json["message"], "outer error");
+    assert_eq!(
+        json["cause"],
+        serde_json::json!({ "message": "an error occurred when formatting an argument" })
+    );
 }
 
+#[cfg(feature = "serde_json")]
 #[test]
-fn test_set_metatable_nil() {
+fn test_serde_json_values() {
+    use serde_json;
+
     let lua = Lua::new();
+    let globals = lua.globals();
+
+    let json: serde_json::Value = serde_json::from_str(
+        r#"{"name": "test", "count": 3, "ratio": 0.5, "tags": ["a", "b"], "none": null}"#,
+    ).unwrap();
+    globals.set("json", json).unwrap();
     lua.exec::<()>(
-    r#"
-        a = {}
-        setmetatable(a, nil)
-    "#, None).unwrap();
+        r#"
+            assert(json.name == "test")
+            assert(math.type(json.count) == "integer")
+            assert(math.type(json.ratio) == "float")
+            assert(#json.tags == 2 and json.tags[2] == "b")
+            assert(json.none == nil)
+        "#,
+        None,
+    ).unwrap();
+
+    let json = lua.eval::<serde_json::Value>(
+        r#"{ list = { 1, 2.5, "three" }, empty = {}, [1] = true }"#,
+        None,
+    ).unwrap();
+    assert_eq!(
+        json,
+        serde_json::from_str::<serde_json::Value>(
+            r#"{"list": [1, 2.5, "three"], "empty": {}, "1": true}"#
+        ).unwrap()
+    );
+
+    assert!(
+        lua.eval::<serde_json::Value>("local t = {} t[1] = t return t", None)
+            .is_err()
+    );
+    assert!(lua.eval::<serde_json::Value>("{ f = print }", None).is_err());
+    assert!(lua.eval::<serde_json::Value>("0 / 0", None).is_err());
+
+    let nested = "local t = {} for i = 1, NESTING do t = {t} end return t";
+    let json = lua.eval::<serde_json::Value>(&nested.replace("NESTING", "127"), None)
+        .unwrap();
+    assert_eq!(json.to_string(), format!("{}{{}}{}", "[".repeat(127), "]".repeat(127)));
+    match lua.eval::<serde_json::Value>(&nested.replace("NESTING", "1e6"), None) {
+        Err(Error::FromLuaConversionError { .. }) => {}
+        r => panic!("expected FromLuaConversionError, got {:?}", r),
+    }
 }
 
 // TODO: Need to use compiletest-rs or similar to make sure these don't compile.
@@ -1,9 +1,16 @@
+use std::cell::RefCell;
 use std::fmt;
+use std::marker::PhantomData;
 use std::os::raw::{c_int, c_void};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 use ffi;
-use error::Result;
-use lua::{Lua, MultiValue};
+use error::{Error, Result};
+use util::{check_stack, stack_guard};
+use lua::{FromLua, Lua, MultiValue, Value};
 
 /// Type of Lua integer numbers.
 pub type Integer = ffi::lua_Integer;
@@ -14,18 +21,550 @@ pub type Number = ffi::lua_Number;
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct LightUserData(pub *mut c_void);
 
+/// A light userdata which remembers the Rust type it points to.
+///
+/// It is converted to a plain light userdata, and the Lua state records that its address points
+/// to a `T`. Converting a light userdata back to a `TypedLightUserData<T>` checks that it was last
+/// passed to Lua as a `TypedLightUserData<T>`, and returns a [`FromLuaConversionError`]
+/// otherwise. This catches scripts passing a handle of one type to a function expecting another,
+/// or a handle the host has forgotten with [`Lua::forget_light_userdata`]. Like any light
+/// userdata, the pointer is not kept alive or dereferenced by rlua.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, Result, TypedLightUserData};
+/// # fn try_main() -> Result<()> {
+/// struct Window;
+/// struct Sound;
+///
+/// let lua = Lua::new();
+/// let mut window = Window;
+/// let mut sound = Sound;
+/// lua.globals().set("window", TypedLightUserData::new(&mut window as *mut Window))?;
+/// lua.globals().set("sound", TypedLightUserData::new(&mut sound as *mut Sound))?;
+///
+/// assert!(lua.globals().get::<_, TypedLightUserData<Window>>("window").is_ok());
+/// assert!(lua.globals().get::<_, TypedLightUserData<Window>>("sound").is_err());
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`FromLuaConversionError`]: enum.Error.html#variant.FromLuaConversionError
+/// [`Lua::forget_light_userdata`]: struct.Lua.html#method.forget_light_userdata
+pub struct TypedLightUserData<T: 'static>(pub *mut T);
+
+impl<T: 'static> TypedLightUserData<T> {
+    /// Wraps a pointer to a `T`.
+    pub fn new(ptr: *mut T) -> TypedLightUserData<T> {
+        TypedLightUserData(ptr)
+    }
+
+    /// Returns the pointer.
+    pub fn as_ptr(&self) -> *mut T {
+        self.0
+    }
+}
+
+impl<T: 'static> fmt::Debug for TypedLightUserData<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TypedLightUserData({:p})", self.0)
+    }
+}
+
+impl<T: 'static> Clone for TypedLightUserData<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: 'static> Copy for TypedLightUserData<T> {}
+
+impl<T: 'static> PartialEq for TypedLightUserData<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T: 'static> Eq for TypedLightUserData<T> {}
+
+/// How `Duration` values are represented in Lua.
+///
+/// See [`Lua::set_duration_format`].
+///
+/// [`Lua::set_duration_format`]: struct.Lua.html#method.set_duration_format
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DurationFormat {
+    /// Fractional seconds as a float. This is the default.
+    Seconds,
+    /// Whole milliseconds as an integer.
+    Milliseconds,
+}
+
+/// What happens when a Rust callback called from Lua panics.
+///
+/// See [`Lua::set_panic_mode`] and [`Lua::create_function_with_panic_mode`].
+///
+/// [`Lua::set_panic_mode`]: struct.Lua.html#method.set_panic_mode
+/// [`Lua::create_function_with_panic_mode`]: struct.Lua.html#method.create_function_with_panic_mode
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PanicMode {
+    /// The panic unwinds through the Lua code and is resumed once it reaches the Rust code which
+    /// called into Lua. Lua code cannot catch the panic. This is the default.
+    Resume,
+    /// The panic is caught and raised as a Lua error holding an [`Error::CallbackPanic`], which
+    /// Lua code can catch with `pcall` like any other error.
+    ///
+    /// [`Error::CallbackPanic`]: enum.Error.html#variant.CallbackPanic
+    Catch,
+}
+
+/// Limits on the resources scripts can use.
+///
+/// See [`Lua::set_resource_limits`].
+///
+/// [`Lua::set_resource_limits`]: struct.Lua.html#method.set_resource_limits
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ResourceLimits {
+    /// Length in bytes of the longest string which `string.rep` and `table.concat` may create.
+    ///
+    /// The length is checked before the string is created, so that a single call cannot exhaust
+    /// the memory of the host. Defaults to no limit.
+    pub max_string_length: Option<usize>,
+    /// Number of bytes the Lua state may use, as counted by its allocator.
+    ///
+    /// While Lua code runs, on any thread, allocations which would exceed this fail once a full
+    /// garbage collection has not freed enough memory. This raises a memory error, which scripts
+    /// see as `"not enough memory"` and can catch with `pcall`, and which is returned to the host
+    /// as an [`Error::MemoryError`]. Memory allocated by Rust callbacks and finalizers is counted,
+    /// but never fails. Defaults to no limit.
+    ///
+    /// [`Error::MemoryError`]: enum.Error.html#variant.MemoryError
+    pub max_memory: Option<usize>,
+    /// Number of steps a single call of `string.find`, `string.match`, `string.gsub` or of an
+    /// iterator returned by `string.gmatch` may take to match a pattern.
+    ///
+    /// Patterns which backtrack a lot, such as `.-.-.-x`, can take a very long time on long
+    /// strings. A step is roughly one attempt to match an element of the pattern at a position of
+    /// the subject. The limited functions replace those of the `string` global, of
+    /// `package.loaded.string` and of the metatable of strings, so method calls such as
+    /// `s:find(p)` are limited too. References to the original functions taken before the limit
+    /// was set are not. Defaults to no limit.
+    pub max_pattern_steps: Option<usize>,
+    /// Number of entries tables may grow by during a single execution, that is a call into Lua
+    /// made by the host such as `Lua::exec`, `Function::call` or `Thread::resume`, along with the
+    /// calls made by the Rust callbacks it runs.
+    ///
+    /// Tables are counted as they grow, by the room they are given for entries, which Lua rounds
+    /// up to a power of two in their hash part. Growing a table past this raises an error from the
+    /// code growing it, which scripts can catch with `pcall`. Tables grown by Rust callbacks are
+    /// counted, but never fail. Only enforced with the bundled Lua, the `builtin-lua` feature.
+    /// Defaults to no limit.
+    pub max_table_entries: Option<usize>,
+}
+
+/// The resources used by a single call into Lua.
+///
+/// Returned by [`Function::call_with_report`] and [`Lua::exec_with_report`], so that hosts can
+/// account for individual script invocations.
+///
+/// [`Function::call_with_report`]: struct.Function.html#method.call_with_report
+/// [`Lua::exec_with_report`]: struct.Lua.html#method.exec_with_report
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ResourceReport {
+    /// Number of Lua instructions executed.
+    ///
+    /// Instructions are counted in steps of up to 1000, so calls which execute fewer instructions
+    /// than that may report none.
+    pub instructions: u64,
+    /// Number of bytes allocated by the Lua state, not counting memory which was freed.
+    pub lua_allocated_bytes: u64,
+    /// Largest number of nested function calls entered on any thread, including the called
+    /// function itself.
+    pub peak_stack_depth: usize,
+    /// Time spent in the call.
+    pub wall_time: Duration,
+}
+
+/// Counts of the references keeping Lua values alive, returned by [`Lua::ref_stats`].
+///
+/// [`Lua::ref_stats`]: struct.Lua.html#method.ref_stats
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RefStats {
+    /// Number of references to Lua values held by handles such as [`Table`] and [`Function`],
+    /// including those rlua holds internally.
+    ///
+    /// [`Table`]: struct.Table.html
+    /// [`Function`]: struct.Function.html
+    pub live_refs: usize,
+    /// Number of functions created for Rust callbacks, including the methods of userdata types,
+    /// which have not been garbage collected yet. The methods of a userdata type are collected
+    /// together, once none of them is reachable.
+    pub live_callbacks: usize,
+    /// Number of entries in the Lua registry.
+    pub registry_len: usize,
+}
+
+/// The memory allocated by each line of Lua code, recorded after [`Lua::start_memory_profile`].
+///
+/// [`Lua::start_memory_profile`]: struct.Lua.html#method.start_memory_profile
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryProfile {
+    /// The lines which allocated memory, those which allocated the most bytes first.
+    pub sites: Vec<AllocationSite>,
+    /// Number of bytes allocated while no Lua code was running, such as by the host between calls
+    /// into Lua.
+    pub unattributed_bytes: u64,
+}
+
+/// A line of Lua code which allocated memory, as part of a [`MemoryProfile`].
+///
+/// [`MemoryProfile`]: struct.MemoryProfile.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationSite {
+    /// The name of the chunk, as it appears in Lua messages.
+    pub chunk: String,
+    pub line: u32,
+    /// Number of bytes allocated, not counting memory which was freed.
+    pub bytes: u64,
+    /// Number of allocations.
+    pub allocations: u64,
+}
+
+/// A garbage collection event, passed to the callback set with [`Lua::on_gc`].
+///
+/// [`Lua::on_gc`]: struct.Lua.html#method.on_gc
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GcEvent {
+    /// The collector started a cycle, because the memory used by the state grew past the
+    /// threshold set at the end of the previous cycle.
+    Start {
+        /// Number of bytes used by the state.
+        used_memory: usize,
+    },
+    /// The collector finished marking the objects which are still reachable, and started to run
+    /// the finalizers of the others. Their memory is freed in the steps following this event.
+    End {
+        /// Number of bytes freed since the previous cycle ended, most of which were reclaimed
+        /// by the steps following it.
+        reclaimed_bytes: usize,
+        /// Number of bytes used by the state.
+        used_memory: usize,
+        /// Time since the cycle started, or `None` if its start was not reported, such as for a
+        /// full collection requested with `collectgarbage()`.
+        duration: Option<Duration>,
+    },
+}
+
+/// An access of a script to a global variable, passed to the callback set with
+/// [`Lua::audit_globals`].
+///
+/// [`Lua::audit_globals`]: struct.Lua.html#method.audit_globals
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalAccess {
+    /// The name of the global variable.
+    pub name: String,
+    pub kind: AccessKind,
+    /// A printable version of the source of the function which accessed the variable, such as a
+    /// chunk name.
+    pub source: String,
+    /// The line which accessed the variable, if known.
+    pub line: Option<u32>,
+}
+
+/// Whether a [`GlobalAccess`] read or assigned the variable.
+///
+/// [`GlobalAccess`]: struct.GlobalAccess.html
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A call to `require`, passed to the callback set with [`Lua::trace_require`].
+///
+/// [`Lua::trace_require`]: struct.Lua.html#method.trace_require
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequireTrace {
+    /// The name of the module.
+    pub module: String,
+    pub resolver: RequireResolver,
+    /// The path of the file the module is loaded from, or any other string the searcher which
+    /// found the module passes to its loader.
+    pub path: Option<String>,
+    /// A printable version of the source of the function which called `require`, such as a chunk
+    /// name, or `[C]` if it was called from C or Rust.
+    pub source: String,
+    /// The line which called `require`, if known.
+    pub line: Option<u32>,
+}
+
+/// How `require` resolved a module, as part of a [`RequireTrace`].
+///
+/// [`RequireTrace`]: struct.RequireTrace.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequireResolver {
+    /// The module was already loaded, and was taken from `package.loaded`.
+    Loaded,
+    /// The module is loaded by the function stored for it in `package.preload`.
+    Preload,
+    /// The module was found by the searcher at the given index of `package.searchers`, such as
+    /// the searcher of Lua files at index 2.
+    Searcher(usize),
+    /// No searcher found the module. Holds the reasons given by the searchers, as they appear in
+    /// the error raised by `require`.
+    NotFound(String),
+}
+
+/// A number of instructions Lua code may execute, shared between a Lua state and the host.
+///
+/// A budget attached with [`Lua::set_budget`] is consumed by all code running in the state, across
+/// any number of calls, and scripts are stopped with an error once it is exhausted. Clones of a
+/// budget refer to the same count, so the host can keep one to check and refill it, also from
+/// other threads.
+///
+/// Instructions are counted in steps of up to 1000, so scripts may run slightly more instructions
+/// than the budget allows.
+///
+/// [`Lua::set_budget`]: struct.Lua.html#method.set_budget
+#[derive(Debug, Clone)]
+pub struct Budget(Arc<AtomicU64>);
+
+impl Budget {
+    /// Creates a budget of `count` instructions.
+    pub fn instructions(count: u64) -> Budget {
+        Budget(Arc::new(AtomicU64::new(count)))
+    }
+
+    /// Returns the number of instructions left.
+    pub fn remaining(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Adds `count` instructions to the budget, so that scripts can run again once it has been
+    /// exhausted.
+    pub fn refill(&self, count: u64) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+            Some(remaining.saturating_add(count))
+        });
+    }
+
+    /// Sets the number of instructions left, discarding the rest of the budget.
+    pub fn set_remaining(&self, count: u64) {
+        self.0.store(count, Ordering::Relaxed);
+    }
+
+    // Subtracts `count` instructions, and returns the number left.
+    pub(crate) fn consume(&self, count: u64) -> u64 {
+        let previous = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+            Some(remaining.saturating_sub(count))
+        });
+        previous.unwrap_or(0).saturating_sub(count)
+    }
+}
+
 pub(crate) type Callback<'lua> = Box<
-    FnMut(&'lua Lua, MultiValue<'lua>) -> Result<MultiValue<'lua>> + 'lua,
+    dyn FnMut(&'lua Lua, MultiValue<'lua>) -> Result<MultiValue<'lua>> + 'lua,
+>;
+
+pub(crate) type MethodCallback<'lua> = Box<
+    dyn FnMut(&'lua Lua, MethodSelf<'lua>, MultiValue<'lua>) -> Result<MultiValue<'lua>> + 'lua,
 >;
 
+// The methods of a userdata type, which are called through one dispatcher by their index. A method
+// is moved out of its slot while it runs.
+pub(crate) type MethodTable<'lua> = Vec<Option<MethodCallback<'lua>>>;
+
+// Renders errors for scripts, see `Lua::set_error_formatter`.
+pub(crate) type ErrorFormatter = Box<dyn Fn(&Error) -> String>;
+
+// The `self` argument of a userdata method.
+pub(crate) enum MethodSelf<'lua> {
+    // A userdata which has been checked to have the metatable of the type the method was
+    // registered for.
+    Checked(*mut c_void),
+    // Any other value, or `None` if the method was called without arguments.
+    Unchecked(Option<Value<'lua>>),
+}
+
+// A reference to a Lua value, which keeps the value from being garbage collected.
 pub(crate) struct LuaRef<'lua> {
     pub lua: &'lua Lua,
-    pub registry_id: c_int,
+    pub index: RefIndex,
+}
+
+// Where the value of a `LuaRef` is stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum RefIndex {
+    // Always refers to nil, without storing anything.
+    Nil,
+    // A slot on the stack of the reference thread, see `Lua::pop_ref`.
+    Stack(c_int),
+    // A reference in the registry, used once the stack of the reference thread is full.
+    Registry(c_int),
+}
+
+impl<'lua> LuaRef<'lua> {
+    pub fn to_pointer(&self) -> *const c_void {
+        unsafe {
+            stack_guard(self.lua.state, 0, || {
+                check_stack(self.lua.state, 1);
+                self.lua.push_ref(self.lua.state, self);
+                let ptr = ffi::lua_topointer(self.lua.state, -1);
+                ffi::lua_pop(self.lua.state, 1);
+                ptr
+            })
+        }
+    }
+}
+
+/// A reference to a Lua table, function, userdata or thread which does not keep it from being
+/// garbage collected.
+///
+/// Unlike the other handles to Lua values, which keep their value alive until they are dropped, a
+/// weak reference only gives access to its value while something else keeps it alive, so that
+/// caches of Lua values kept by Rust do not prevent them from being collected. Weak references
+/// are created by the `downgrade` method of [`Table`], [`Function`], [`AnyUserData`] and
+/// [`Thread`].
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, Result};
+/// # fn try_main() -> Result<()> {
+/// let lua = Lua::new();
+/// let table = lua.create_table();
+/// let weak = table.downgrade();
+/// assert!(weak.upgrade().is_some());
+///
+/// drop(table);
+/// lua.exec::<()>("collectgarbage()", None)?;
+/// assert!(weak.upgrade().is_none());
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Table`]: struct.Table.html
+/// [`Function`]: struct.Function.html
+/// [`AnyUserData`]: struct.AnyUserData.html
+/// [`Thread`]: struct.Thread.html
+pub struct WeakLuaRef<'lua, T> {
+    lua: &'lua Lua,
+    key: Integer,
+    _type: PhantomData<T>,
+}
+
+impl<'lua, T: FromLua<'lua>> WeakLuaRef<'lua, T> {
+    pub(crate) fn new(lref: &LuaRef<'lua>) -> WeakLuaRef<'lua, T> {
+        let lua = lref.lua;
+        let key = unsafe {
+            stack_guard(lua.state, 0, || {
+                check_stack(lua.state, 5);
+                lua.push_ref(lua.state, lref);
+                lua.create_weak_ref(lua.state)
+            })
+        };
+        WeakLuaRef {
+            lua,
+            key,
+            _type: PhantomData,
+        }
+    }
+
+    /// Returns a handle to the value, or `None` if it has been garbage collected.
+    pub fn upgrade(&self) -> Option<T> {
+        let value = unsafe {
+            stack_guard(self.lua.state, 0, || {
+                check_stack(self.lua.state, 4);
+                self.lua.push_weak_ref(self.lua.state, self.key);
+                self.lua.pop_value(self.lua.state)
+            })
+        };
+        match value {
+            Value::Nil => None,
+            value => T::from_lua(value, self.lua).ok(),
+        }
+    }
+}
+
+impl<'lua, T> fmt::Debug for WeakLuaRef<'lua, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WeakLuaRef({})", self.key)
+    }
+}
+
+impl<'lua, T> Clone for WeakLuaRef<'lua, T> {
+    fn clone(&self) -> Self {
+        let key = unsafe {
+            stack_guard(self.lua.state, 0, || {
+                check_stack(self.lua.state, 5);
+                self.lua.push_weak_ref(self.lua.state, self.key);
+                self.lua.create_weak_ref(self.lua.state)
+            })
+        };
+        WeakLuaRef {
+            lua: self.lua,
+            key,
+            _type: PhantomData,
+        }
+    }
+}
+
+impl<'lua, T> Drop for WeakLuaRef<'lua, T> {
+    fn drop(&mut self) {
+        unsafe {
+            stack_guard(self.lua.state, 0, || {
+                check_stack(self.lua.state, 4);
+                self.lua.drop_weak_ref(self.key);
+            })
+        }
+    }
+}
+
+/// A key to a value stored in the Lua registry, which keeps the value alive.
+///
+/// Unlike the handles to Lua values, a key does not borrow the [`Lua`] state, so it can be stored
+/// next to the state, such as in the same struct, and turned back into a handle with
+/// [`Lua::registry_value`] when the state is used again.
+///
+/// The value is removed from the registry by [`Lua::remove_registry_value`], or the next time a
+/// value is stored in the registry after the key is dropped. A key can only be used with the
+/// state which created it.
+///
+/// [`Lua`]: struct.Lua.html
+/// [`Lua::registry_value`]: struct.Lua.html#method.registry_value
+/// [`Lua::remove_registry_value`]: struct.Lua.html#method.remove_registry_value
+pub struct RegistryKey {
+    pub(crate) registry_id: c_int,
+    // The ids of the dropped keys of the state, which are removed from its registry later.
+    pub(crate) unref_list: Rc<RefCell<Vec<c_int>>>,
+}
+
+impl fmt::Debug for RegistryKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RegistryKey({})", self.registry_id)
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        if self.registry_id != ffi::LUA_NOREF {
+            self.unref_list.borrow_mut().push(self.registry_id);
+        }
+    }
 }
 
 impl<'lua> fmt::Debug for LuaRef<'lua> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "LuaRef({})", self.registry_id)
+        write!(f, "LuaRef({:?})", self.index)
     }
 }
 
@@ -41,7 +580,7 @@ impl<'lua> Clone for LuaRef<'lua> {
 impl<'lua> Drop for LuaRef<'lua> {
     fn drop(&mut self) {
         unsafe {
-            ffi::luaL_unref(self.lua.state, ffi::LUA_REGISTRYINDEX, self.registry_id);
+            self.lua.drop_ref(self.index);
         }
     }
 }
@@ -1,16 +1,24 @@
+use std::{fmt, mem};
+use std::any::{Any, TypeId};
 use std::cell::{Ref, RefCell, RefMut};
 use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
 use std::collections::HashMap;
+use std::os::raw::{c_int, c_void};
+use std::rc::Rc;
 use std::string::String as StdString;
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
 
 use ffi;
 use error::*;
 use util::*;
-use types::{Callback, LuaRef};
-use lua::{FromLua, FromLuaMulti, Lua, ToLuaMulti};
+use types::{Callback, Integer, LuaRef, MethodCallback, MethodSelf, Number, WeakLuaRef};
+use table::Table;
+use lua::{FromLua, FromLuaMulti, Lua, MultiValue, ToLua, ToLuaMulti, Value};
 
 /// Kinds of metamethods that can be overridden.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum MetaMethod {
     /// The `+` operator.
     Add,
@@ -58,18 +66,274 @@ pub enum MetaMethod {
     Call,
     /// tostring(ud) will call this if it exists
     ToString,
+    /// The `pairs` function, used by `for k, v in pairs(ud)` loops.
+    ///
+    /// Should return an iterator function, and optionally a state and an initial control value,
+    /// the same way `pairs` does.
+    Pairs,
+    /// The `ipairs` function, used by `for i, v in ipairs(ud)` loops.
+    ///
+    /// Lua 5.3 only honors this metamethod when built with `LUA_COMPAT_IPAIRS`, which the bundled
+    /// Lua is. Without it, `ipairs` instead indexes the userdata with consecutive integers.
+    IPairs,
+    /// A metatable entry with an arbitrary name, such as `__name` or a library specific key.
+    ///
+    /// This should not be used for any of the metamethods listed above.
+    Custom(StdString),
+}
+
+impl MetaMethod {
+    pub(crate) fn name(&self) -> &str {
+        match *self {
+            MetaMethod::Add => "__add",
+            MetaMethod::Sub => "__sub",
+            MetaMethod::Mul => "__mul",
+            MetaMethod::Div => "__div",
+            MetaMethod::Mod => "__mod",
+            MetaMethod::Pow => "__pow",
+            MetaMethod::Unm => "__unm",
+            MetaMethod::IDiv => "__idiv",
+            MetaMethod::BAnd => "__band",
+            MetaMethod::BOr => "__bor",
+            MetaMethod::BXor => "__bxor",
+            MetaMethod::BNot => "__bnot",
+            MetaMethod::Shl => "__shl",
+            MetaMethod::Shr => "__shr",
+            MetaMethod::Concat => "__concat",
+            MetaMethod::Len => "__len",
+            MetaMethod::Eq => "__eq",
+            MetaMethod::Lt => "__lt",
+            MetaMethod::Le => "__le",
+            MetaMethod::Index => "__index",
+            MetaMethod::NewIndex => "__newindex",
+            MetaMethod::Call => "__call",
+            MetaMethod::ToString => "__tostring",
+            MetaMethod::Pairs => "__pairs",
+            MetaMethod::IPairs => "__ipairs",
+            MetaMethod::Custom(ref name) => name,
+        }
+    }
+}
+
+/// A plain value stored in the metatable of a userdata type with
+/// [`UserDataMethods::add_meta_field`].
+///
+/// [`UserDataMethods::add_meta_field`]: struct.UserDataMethods.html#method.add_meta_field
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetaFieldValue {
+    Boolean(bool),
+    Integer(Integer),
+    Number(Number),
+    String(StdString),
+}
+
+impl From<bool> for MetaFieldValue {
+    fn from(b: bool) -> MetaFieldValue {
+        MetaFieldValue::Boolean(b)
+    }
+}
+
+impl From<Integer> for MetaFieldValue {
+    fn from(i: Integer) -> MetaFieldValue {
+        MetaFieldValue::Integer(i)
+    }
+}
+
+impl From<Number> for MetaFieldValue {
+    fn from(n: Number) -> MetaFieldValue {
+        MetaFieldValue::Number(n)
+    }
+}
+
+impl<'a> From<&'a str> for MetaFieldValue {
+    fn from(s: &'a str) -> MetaFieldValue {
+        MetaFieldValue::String(s.to_owned())
+    }
+}
+
+impl From<StdString> for MetaFieldValue {
+    fn from(s: StdString) -> MetaFieldValue {
+        MetaFieldValue::String(s)
+    }
 }
 
 /// Method registry for [`UserData`] implementors.
 ///
 /// [`UserData`]: trait.UserData.html
 pub struct UserDataMethods<'lua, T> {
-    pub(crate) methods: HashMap<StdString, Callback<'lua>>,
-    pub(crate) meta_methods: HashMap<MetaMethod, Callback<'lua>>,
+    pub(crate) methods: HashMap<StdString, UserDataCallback<'lua>>,
+    pub(crate) meta_methods: HashMap<MetaMethod, UserDataCallback<'lua>>,
+    pub(crate) meta_fields: HashMap<MetaMethod, MetaFieldValue>,
+    pub(crate) destructor: Option<Callback<'lua>>,
+    pub(crate) bases: Vec<UserDataBase>,
+    pub(crate) access: UserDataAccess<T>,
     pub(crate) _type: PhantomData<T>,
 }
 
-impl<'lua, T: UserData> UserDataMethods<'lua, T> {
+pub(crate) enum UserDataCallback<'lua> {
+    Function(Callback<'lua>),
+    Method(MethodCallback<'lua>),
+}
+
+// Gives methods access to the `T` held by a userdata, which is usually stored directly in the
+// userdata, but may also be behind a shared pointer.
+pub(crate) struct UserDataAccess<T> {
+    with_ref: WithRef<T>,
+    with_mut: WithMut<T>,
+}
+
+type WithRef<T> =
+    for<'lua> fn(&'lua Lua, MethodSelf<'lua>, &mut dyn FnMut(&T) -> Result<MultiValue<'lua>>)
+        -> Result<MultiValue<'lua>>;
+type WithMut<T> =
+    for<'lua> fn(&'lua Lua, MethodSelf<'lua>, &mut dyn FnMut(&mut T) -> Result<MultiValue<'lua>>)
+        -> Result<MultiValue<'lua>>;
+
+impl<T> Clone for UserDataAccess<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserDataAccess<T> {}
+
+impl<T: 'static> UserDataAccess<T> {
+    fn direct() -> UserDataAccess<T> {
+        fn with_ref<'lua, T: 'static>(
+            lua: &'lua Lua,
+            this: MethodSelf<'lua>,
+            f: &mut dyn FnMut(&T) -> Result<MultiValue<'lua>>,
+        ) -> Result<MultiValue<'lua>> {
+            with_self_ref(lua, this, |value: &T| f(value))
+        }
+
+        fn with_mut<'lua, T: 'static>(
+            lua: &'lua Lua,
+            this: MethodSelf<'lua>,
+            f: &mut dyn FnMut(&mut T) -> Result<MultiValue<'lua>>,
+        ) -> Result<MultiValue<'lua>> {
+            with_self_mut(lua, this, |value: &mut T| f(value))
+        }
+
+        UserDataAccess {
+            with_ref: with_ref::<T>,
+            with_mut: with_mut::<T>,
+        }
+    }
+}
+
+// Returns the cell of a userdata holding a `T`.
+unsafe fn userdata_cell<'a, T>(ud: *mut c_void) -> Result<&'a RefCell<T>> {
+    (*(ud as *const Option<RefCell<T>>))
+        .as_ref()
+        .ok_or(Error::UserDataTypeMismatch)
+}
+
+// Converts the `self` argument of a method which was not checked to be of the right type.
+fn unchecked_self<'lua>(lua: &'lua Lua, value: Option<Value<'lua>>) -> Result<AnyUserData<'lua>> {
+    match value {
+        Some(value) => AnyUserData::from_lua(value, lua),
+        None => Err(Error::FromLuaConversionError {
+            from: "missing argument",
+            to: "userdata",
+            message: None,
+        }),
+    }
+}
+
+// Calls `f` with a reference to the `T` held by the `self` userdata of a method.
+fn with_self_ref<'lua, T, F>(
+    lua: &'lua Lua,
+    this: MethodSelf<'lua>,
+    f: F,
+) -> Result<MultiValue<'lua>>
+where
+    T: 'static,
+    F: FnOnce(&T) -> Result<MultiValue<'lua>>,
+{
+    match this {
+        MethodSelf::Checked(ud) => {
+            let cell = unsafe { userdata_cell::<T>(ud)? };
+            let value = cell.try_borrow().map_err(|_| Error::UserDataBorrowError)?;
+            f(&value)
+        }
+        MethodSelf::Unchecked(value) => {
+            let userdata = unchecked_self(lua, value)?;
+            let value = userdata.borrow::<T>()?;
+            f(&value)
+        }
+    }
+}
+
+// Calls `f` with a mutable reference to the `T` held by the `self` userdata of a method.
+fn with_self_mut<'lua, T, F>(
+    lua: &'lua Lua,
+    this: MethodSelf<'lua>,
+    f: F,
+) -> Result<MultiValue<'lua>>
+where
+    T: 'static,
+    F: FnOnce(&mut T) -> Result<MultiValue<'lua>>,
+{
+    match this {
+        MethodSelf::Checked(ud) => {
+            let cell = unsafe { userdata_cell::<T>(ud)? };
+            let mut value = cell.try_borrow_mut()
+                .map_err(|_| Error::UserDataBorrowMutError)?;
+            f(&mut value)
+        }
+        MethodSelf::Unchecked(value) => {
+            let userdata = unchecked_self(lua, value)?;
+            let mut value = userdata.borrow_mut::<T>()?;
+            f(&mut value)
+        }
+    }
+}
+
+// A base type declared with `UserDataMethods::inherit`.
+pub(crate) struct UserDataBase {
+    pub(crate) type_id: TypeId,
+    // Returns the registry id of the metatable of the base type.
+    pub(crate) metatable: unsafe fn(&Lua) -> c_int,
+    // A `Coercion` to the base type.
+    pub(crate) coercion: Box<dyn Any>,
+}
+
+// Borrows a base type `B` out of a pointer to the userdata of a derived type.
+pub(crate) struct Coercion<B: 'static> {
+    borrow: Box<dyn Fn(*mut c_void) -> Result<Ref<'static, B>>>,
+    borrow_mut: Box<dyn Fn(*mut c_void) -> Result<RefMut<'static, B>>>,
+}
+
+impl<'lua, T: 'static> UserDataMethods<'lua, T> {
+    pub(crate) fn new() -> UserDataMethods<'lua, T> {
+        UserDataMethods {
+            methods: HashMap::new(),
+            meta_methods: HashMap::new(),
+            meta_fields: HashMap::new(),
+            destructor: None,
+            bases: Vec::new(),
+            access: UserDataAccess::direct(),
+            _type: PhantomData,
+        }
+    }
+
+    // Creates methods for `T` which will be registered for another type, accessing the `T` inside
+    // of it with `access`.
+    fn forward(access: UserDataAccess<T>) -> UserDataMethods<'lua, T> {
+        let mut methods = UserDataMethods::new();
+        methods.access = access;
+        methods
+    }
+
+    // Moves the methods of `T` into the methods of `U`, which must have been created with
+    // `forward`. Base types and the destructor of `T` are dropped, as they do not apply to `U`.
+    fn forward_into<U: 'static>(self, methods: &mut UserDataMethods<'lua, U>) {
+        methods.methods.extend(self.methods);
+        methods.meta_methods.extend(self.meta_methods);
+        methods.meta_fields.extend(self.meta_fields);
+    }
+
     /// Add a method which accepts a `&T` as the first parameter.
     ///
     /// Regular methods are implemented by overriding the `__index` metamethod and returning the
@@ -84,7 +348,7 @@ impl<'lua, T: UserData> UserDataMethods<'lua, T> {
         M: 'static + for<'a> FnMut(&'lua Lua, &'a T, A) -> Result<R>,
     {
         self.methods
-            .insert(name.to_owned(), Self::box_method(method));
+            .insert(name.to_owned(), self.box_method(method));
     }
 
     /// Add a regular method which accepts a `&mut T` as the first parameter.
@@ -99,16 +363,20 @@ impl<'lua, T: UserData> UserDataMethods<'lua, T> {
         M: 'static + for<'a> FnMut(&'lua Lua, &'a mut T, A) -> Result<R>,
     {
         self.methods
-            .insert(name.to_owned(), Self::box_method_mut(method));
+            .insert(name.to_owned(), self.box_method_mut(method));
     }
 
-    /// Add a regular method as a function which accepts generic arguments, the first argument will
-    /// always be a `UserData` of type T.
+    /// Add a regular method as a function which accepts generic arguments. When called with the
+    /// `userdata:method()` syntax, the first argument will be a `UserData` of type T.
     ///
-    /// Prefer to use [`add_method`] or [`add_method_mut`] as they are easier to use.
+    /// Functions can also be called without an instance through the methods table returned by
+    /// [`Lua::userdata_methods`], which makes them suitable for constructors and other associated
+    /// functions. For regular methods, prefer to use [`add_method`] or [`add_method_mut`] as they
+    /// are easier to use.
     ///
     /// [`add_method`]: #method.add_method
     /// [`add_method_mut`]: #method.add_method_mut
+    /// [`Lua::userdata_methods`]: struct.Lua.html#method.userdata_methods
     pub fn add_function<A, R, F>(&mut self, name: &str, function: F)
     where
         A: FromLuaMulti<'lua>,
@@ -116,7 +384,7 @@ impl<'lua, T: UserData> UserDataMethods<'lua, T> {
         F: 'static + FnMut(&'lua Lua, A) -> Result<R>,
     {
         self.methods
-            .insert(name.to_owned(), Self::box_function(function));
+            .insert(name.to_owned(), UserDataCallback::Function(Self::box_function(function)));
     }
 
     /// Add a metamethod which accepts a `&T` as the first parameter.
@@ -133,7 +401,7 @@ impl<'lua, T: UserData> UserDataMethods<'lua, T> {
         R: ToLuaMulti<'lua>,
         M: 'static + for<'a> FnMut(&'lua Lua, &'a T, A) -> Result<R>,
     {
-        self.meta_methods.insert(meta, Self::box_method(method));
+        self.meta_methods.insert(meta, self.box_method(method));
     }
 
     /// Add a metamethod as a function which accepts a `&mut T` as the first parameter.
@@ -150,21 +418,224 @@ impl<'lua, T: UserData> UserDataMethods<'lua, T> {
         R: ToLuaMulti<'lua>,
         M: 'static + for<'a> FnMut(&'lua Lua, &'a mut T, A) -> Result<R>,
     {
-        self.meta_methods.insert(meta, Self::box_method_mut(method));
+        self.meta_methods.insert(meta, self.box_method_mut(method));
     }
 
     /// Add a metamethod which accepts generic arguments.
     ///
     /// Metamethods for binary operators can be triggered if either the left or right argument to
     /// the binary operator has a metatable, so the first argument here is not necessarily a
-    /// userdata of type `T`.
+    /// userdata of type `T`. Taking the arguments as a `MultiValue` allows handling operands of
+    /// mixed types in either order.
     pub fn add_meta_function<A, R, F>(&mut self, meta: MetaMethod, function: F)
     where
         A: FromLuaMulti<'lua>,
         R: ToLuaMulti<'lua>,
         F: 'static + FnMut(&'lua Lua, A) -> Result<R>,
     {
-        self.meta_methods.insert(meta, Self::box_function(function));
+        self.meta_methods.insert(meta, UserDataCallback::Function(Self::box_function(function)));
+    }
+
+    /// Implements the `__eq` metamethod with the `PartialEq` implementation of `T`.
+    ///
+    /// Comparing a userdata of type `T` with a userdata of any other type results in `false`.
+    ///
+    /// When forwarded by `Arc<Mutex<T>>`, both operands are locked during the comparison, so
+    /// comparing two userdata sharing the same mutex fails with a `UserDataBorrowMutError`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, UserData, UserDataMethods, Result};
+    /// # fn try_main() -> Result<()> {
+    /// #[derive(PartialEq)]
+    /// struct Color(u8, u8, u8);
+    ///
+    /// impl UserData for Color {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.add_derived_eq();
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// lua.globals().set("a", Color(255, 0, 0))?;
+    /// lua.globals().set("b", Color(255, 0, 0))?;
+    /// assert!(lua.eval::<bool>("a == b", None)?);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    pub fn add_derived_eq(&mut self)
+    where
+        T: PartialEq,
+    {
+        let access = self.access;
+        let eq = Box::new(move |lua, this, mut args: MultiValue<'lua>| {
+            let mut other = args.pop_front();
+            let res = (access.with_ref)(lua, this, &mut |a| {
+                (access.with_ref)(lua, MethodSelf::Unchecked(other.take()), &mut |b| {
+                    (a == b).to_lua_multi(lua)
+                })
+            });
+            match res {
+                Err(Error::UserDataTypeMismatch) => false.to_lua_multi(lua),
+                res => res,
+            }
+        });
+        self.meta_methods
+            .insert(MetaMethod::Eq, UserDataCallback::Method(eq));
+    }
+
+    /// Add a plain value to the metatable, rather than a function.
+    ///
+    /// Meta fields are set after all metamethods, replacing any metamethod of the same kind. This
+    /// is mostly useful together with [`MetaMethod::Custom`], for libraries which look up
+    /// nonstandard metatable entries. The `__gc` and `__metatable` fields cannot be overridden.
+    ///
+    /// [`MetaMethod::Custom`]: enum.MetaMethod.html#variant.Custom
+    pub fn add_meta_field<V>(&mut self, meta: MetaMethod, value: V)
+    where
+        V: Into<MetaFieldValue>,
+    {
+        self.meta_fields.insert(meta, value.into());
+    }
+
+    /// Add a function which is called with the value of a userdata when Lua collects it.
+    ///
+    /// The destructor is run from the `__gc` metamethod and receives the value by ownership, so it
+    /// is dropped when the destructor returns. It is not called for values that have been moved
+    /// out of their userdata with [`AnyUserData::take`]. Destructors are not forwarded to shared
+    /// wrappers such as `Rc<RefCell<T>>`.
+    ///
+    /// Errors returned by the destructor and panics inside of it are ignored, as Lua aborts the
+    /// process when an error is raised while it collects garbage.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, UserData, UserDataMethods, Result};
+    /// # use std::sync::{Arc, Mutex};
+    /// # fn try_main() -> Result<()> {
+    /// struct Handle(Arc<Mutex<Vec<u32>>>, u32);
+    ///
+    /// impl UserData for Handle {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.add_destructor(|_, handle| {
+    ///             handle.0.lock().unwrap().push(handle.1);
+    ///             Ok(())
+    ///         });
+    ///     }
+    /// }
+    ///
+    /// let closed = Arc::new(Mutex::new(Vec::new()));
+    /// let lua = Lua::new();
+    /// lua.globals().set("handle", Handle(closed.clone(), 1))?;
+    /// lua.exec::<()>("handle = nil; collectgarbage()", None)?;
+    /// assert_eq!(*closed.lock().unwrap(), vec![1]);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`AnyUserData::take`]: struct.AnyUserData.html#method.take
+    pub fn add_destructor<F>(&mut self, mut destructor: F)
+    where
+        F: 'static + FnMut(&'lua Lua, T) -> Result<()>,
+    {
+        self.destructor = Some(Box::new(move |lua, mut args| {
+            if let Some(Value::UserData(userdata)) = args.pop_front() {
+                if let Ok(value) = userdata.take::<T>() {
+                    // Nothing may be raised from `__gc`, so errors and panics are dropped here.
+                    let destructor = &mut destructor;
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| destructor(lua, value)));
+                }
+            }
+            Ok(lua.new_multi_value())
+        }));
+    }
+
+    /// Declares `B` as a base type of `T`.
+    ///
+    /// Methods that are not found among the regular methods of `T` are looked up in the regular
+    /// methods of `B` (and in turn its base types). The given accessors are used to reach the `B`
+    /// inside of a `T`, so that methods of `B`, [`AnyUserData::is::<B>`] and
+    /// [`AnyUserData::borrow::<B>`] also work on userdata of type `T`.
+    ///
+    /// Base types are checked in the order they are declared. Metamethods are not inherited, and
+    /// borrowing only reaches the base types that are declared directly on `T`, so to call methods
+    /// of a more distant ancestor, that ancestor should be declared as a base as well.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate rlua;
+    /// # use rlua::{Lua, UserData, UserDataMethods, Result};
+    /// # fn try_main() -> Result<()> {
+    /// struct Entity {
+    ///     id: u32,
+    /// }
+    ///
+    /// impl UserData for Entity {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.add_method("id", |_, entity, ()| Ok(entity.id));
+    ///     }
+    /// }
+    ///
+    /// struct Player {
+    ///     entity: Entity,
+    ///     name: String,
+    /// }
+    ///
+    /// impl UserData for Player {
+    ///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+    ///         methods.inherit(|p: &Player| &p.entity, |p: &mut Player| &mut p.entity);
+    ///         methods.add_method("name", |_, player, ()| Ok(player.name.clone()));
+    ///     }
+    /// }
+    ///
+    /// let lua = Lua::new();
+    /// let player = Player { entity: Entity { id: 7 }, name: "alice".to_owned() };
+    /// lua.globals().set("player", player)?;
+    /// assert_eq!(lua.eval::<u32>("player:id()", None)?, 7);
+    /// # Ok(())
+    /// # }
+    /// # fn main() {
+    /// #     try_main().unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`AnyUserData::is::<B>`]: struct.AnyUserData.html#method.is
+    /// [`AnyUserData::borrow::<B>`]: struct.AnyUserData.html#method.borrow
+    pub fn inherit<B: UserData>(&mut self, access: fn(&T) -> &B, access_mut: fn(&mut T) -> &mut B) {
+        unsafe fn base_metatable<B: UserData>(lua: &Lua) -> c_int {
+            lua.userdata_metatable::<B>()
+        }
+
+        let coercion = Coercion::<B> {
+            borrow: Box::new(move |ud| {
+                let cell = unsafe { userdata_cell::<T>(ud)? };
+                let value = cell.try_borrow().map_err(|_| Error::UserDataBorrowError)?;
+                Ok(Ref::map(value, access))
+            }),
+            borrow_mut: Box::new(move |ud| {
+                let cell = unsafe { userdata_cell::<T>(ud)? };
+                let value = cell.try_borrow_mut()
+                    .map_err(|_| Error::UserDataBorrowMutError)?;
+                Ok(RefMut::map(value, access_mut))
+            }),
+        };
+
+        self.bases.push(UserDataBase {
+            type_id: TypeId::of::<B>(),
+            metatable: base_metatable::<B>,
+            coercion: Box::new(coercion),
+        });
     }
 
     fn box_function<A, R, F>(mut function: F) -> Callback<'lua>
@@ -174,46 +645,46 @@ impl<'lua, T: UserData> UserDataMethods<'lua, T> {
         F: 'static + FnMut(&'lua Lua, A) -> Result<R>,
     {
         Box::new(move |lua, args| {
-            function(lua, A::from_lua_multi(args, lua)?)?.to_lua_multi(lua)
+            let args = A::from_lua_args(args, 1, lua)
+                .map_err(|err| lua.name_bad_argument(err, false))?;
+            function(lua, args)?.to_lua_multi(lua)
         })
     }
 
-    fn box_method<A, R, M>(mut method: M) -> Callback<'lua>
+    fn box_method<A, R, M>(&self, mut method: M) -> UserDataCallback<'lua>
     where
         A: FromLuaMulti<'lua>,
         R: ToLuaMulti<'lua>,
         M: 'static + for<'a> FnMut(&'lua Lua, &'a T, A) -> Result<R>,
     {
-        Box::new(move |lua, mut args| if let Some(front) = args.pop_front() {
-            let userdata = AnyUserData::from_lua(front, lua)?;
-            let userdata = userdata.borrow::<T>()?;
-            method(lua, &userdata, A::from_lua_multi(args, lua)?)?.to_lua_multi(lua)
-        } else {
-            Err(Error::FromLuaConversionError {
-                from: "missing argument",
-                to: "userdata",
-                message: None,
+        let access = self.access;
+        UserDataCallback::Method(Box::new(move |lua, this, args| {
+            let args = A::from_lua_args(args, 2, lua)
+                .map_err(|err| lua.name_bad_argument(err, true))?;
+            let mut args = Some(args);
+            (access.with_ref)(lua, this, &mut |value| {
+                let args = args.take().expect("userdata accessed more than once");
+                method(lua, value, args)?.to_lua_multi(lua)
             })
-        })
+        }))
     }
 
-    fn box_method_mut<A, R, M>(mut method: M) -> Callback<'lua>
+    fn box_method_mut<A, R, M>(&self, mut method: M) -> UserDataCallback<'lua>
     where
         A: FromLuaMulti<'lua>,
         R: ToLuaMulti<'lua>,
         M: 'static + for<'a> FnMut(&'lua Lua, &'a mut T, A) -> Result<R>,
     {
-        Box::new(move |lua, mut args| if let Some(front) = args.pop_front() {
-            let userdata = AnyUserData::from_lua(front, lua)?;
-            let mut userdata = userdata.borrow_mut::<T>()?;
-            method(lua, &mut userdata, A::from_lua_multi(args, lua)?)?.to_lua_multi(lua)
-        } else {
-            Err(Error::FromLuaConversionError {
-                from: "missing argument",
-                to: "userdata",
-                message: None,
+        let access = self.access;
+        UserDataCallback::Method(Box::new(move |lua, this, args| {
+            let args = A::from_lua_args(args, 2, lua)
+                .map_err(|err| lua.name_bad_argument(err, true))?;
+            let mut args = Some(args);
+            (access.with_mut)(lua, this, &mut |value| {
+                let args = args.take().expect("userdata accessed more than once");
+                method(lua, value, args)?.to_lua_multi(lua)
             })
-        })
+        }))
     }
 }
 
@@ -228,6 +699,7 @@ impl<'lua, T: UserData> UserDataMethods<'lua, T> {
 /// # extern crate rlua;
 /// # use rlua::{Lua, UserData, Result};
 /// # fn try_main() -> Result<()> {
+/// # #[allow(dead_code)]
 /// struct MyUserData(i32);
 ///
 /// impl UserData for MyUserData {}
@@ -296,6 +768,204 @@ pub trait UserData: 'static + Sized {
     fn add_methods(_methods: &mut UserDataMethods<Self>) {}
 }
 
+/// Shared userdata, forwarding the methods of `T`.
+///
+/// Methods of `T` borrow the `RefCell` for the duration of the call, so other references to the
+/// value can be kept outside of Lua. Base types declared with [`UserDataMethods::inherit`] are not
+/// forwarded.
+///
+/// [`UserDataMethods::inherit`]: struct.UserDataMethods.html#method.inherit
+impl<T: UserData> UserData for Rc<RefCell<T>> {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        fn with_ref<'lua, T: 'static>(
+            lua: &'lua Lua,
+            this: MethodSelf<'lua>,
+            f: &mut dyn FnMut(&T) -> Result<MultiValue<'lua>>,
+        ) -> Result<MultiValue<'lua>> {
+            with_self_ref(lua, this, |cell: &Rc<RefCell<T>>| {
+                let value = cell.try_borrow().map_err(|_| Error::UserDataBorrowError)?;
+                f(&value)
+            })
+        }
+
+        fn with_mut<'lua, T: 'static>(
+            lua: &'lua Lua,
+            this: MethodSelf<'lua>,
+            f: &mut dyn FnMut(&mut T) -> Result<MultiValue<'lua>>,
+        ) -> Result<MultiValue<'lua>> {
+            with_self_ref(lua, this, |cell: &Rc<RefCell<T>>| {
+                let mut value = cell.try_borrow_mut()
+                    .map_err(|_| Error::UserDataBorrowMutError)?;
+                f(&mut value)
+            })
+        }
+
+        let mut forwarded = UserDataMethods::forward(UserDataAccess {
+            with_ref: with_ref::<T>,
+            with_mut: with_mut::<T>,
+        });
+        T::add_methods(&mut forwarded);
+        forwarded.forward_into(methods);
+    }
+}
+
+thread_local! {
+    // The locks of shared userdata held by methods running on this thread, and whether each is
+    // held for writing, to detect a method calling back into its own value.
+    static HELD_LOCKS: RefCell<Vec<(usize, bool)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Removes a lock from `HELD_LOCKS` once the method holding it returns.
+struct HeldLock(usize);
+
+impl HeldLock {
+    fn enter<L>(lock: &L, write: bool) -> HeldLock {
+        let ptr = lock as *const L as usize;
+        HELD_LOCKS.with(|held| held.borrow_mut().push((ptr, write)));
+        HeldLock(ptr)
+    }
+
+    // Returns whether a method further up the stack of this thread holds the lock, and if so,
+    // whether it holds it for writing.
+    fn held<L>(lock: &L) -> Option<bool> {
+        let ptr = lock as *const L as usize;
+        HELD_LOCKS.with(|held| {
+            held.borrow()
+                .iter()
+                .filter(|&&(p, _)| p == ptr)
+                .fold(None, |found, &(_, write)| Some(found == Some(true) || write))
+        })
+    }
+}
+
+impl Drop for HeldLock {
+    fn drop(&mut self) {
+        let ptr = self.0;
+        HELD_LOCKS.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(i) = held.iter().rposition(|&(p, _)| p == ptr) {
+                held.remove(i);
+            }
+        });
+    }
+}
+
+/// Shared userdata, forwarding the methods of `T`.
+///
+/// Methods of `T` lock the mutex for the duration of the call, waiting for other threads to
+/// release it. If a method of the same value further up the stack of the current thread holds the
+/// lock, the method fails with a [`UserDataBorrowMutError`] rather than deadlocking. A poisoned
+/// mutex results in a [`RuntimeError`]. Base types declared with [`UserDataMethods::inherit`] are
+/// not forwarded.
+///
+/// Locking the mutex outside of a method, and then calling a method of the value on the same
+/// thread, still deadlocks.
+///
+/// [`UserDataBorrowMutError`]: enum.Error.html#variant.UserDataBorrowMutError
+/// [`RuntimeError`]: enum.Error.html#variant.RuntimeError
+/// [`UserDataMethods::inherit`]: struct.UserDataMethods.html#method.inherit
+impl<T: UserData> UserData for Arc<Mutex<T>> {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        fn with_mut<'lua, T: 'static>(
+            lua: &'lua Lua,
+            this: MethodSelf<'lua>,
+            f: &mut dyn FnMut(&mut T) -> Result<MultiValue<'lua>>,
+        ) -> Result<MultiValue<'lua>> {
+            with_self_ref(lua, this, |mutex: &Arc<Mutex<T>>| {
+                if HeldLock::held(&**mutex).is_some() {
+                    return Err(Error::UserDataBorrowMutError);
+                }
+                let mut value = mutex.lock().map_err(|_| {
+                    Error::RuntimeError("userdata mutex is poisoned".to_owned())
+                })?;
+                let _held = HeldLock::enter(&**mutex, true);
+                f(&mut value)
+            })
+        }
+
+        fn with_ref<'lua, T: 'static>(
+            lua: &'lua Lua,
+            this: MethodSelf<'lua>,
+            f: &mut dyn FnMut(&T) -> Result<MultiValue<'lua>>,
+        ) -> Result<MultiValue<'lua>> {
+            with_mut::<T>(lua, this, &mut |value| f(value))
+        }
+
+        let mut forwarded = UserDataMethods::forward(UserDataAccess {
+            with_ref: with_ref::<T>,
+            with_mut: with_mut::<T>,
+        });
+        T::add_methods(&mut forwarded);
+        forwarded.forward_into(methods);
+    }
+}
+
+/// Shared userdata, forwarding the methods of `T`.
+///
+/// Methods of `T` acquire a read lock for methods taking `&T`, and a write lock for methods taking
+/// `&mut T`, waiting for other threads to release the lock. If a method of the same value further
+/// up the stack of the current thread holds the lock, a method which would deadlock fails with a
+/// [`UserDataBorrowError`] or [`UserDataBorrowMutError`] instead. A poisoned lock results in a
+/// [`RuntimeError`]. Base types declared with [`UserDataMethods::inherit`] are not forwarded.
+///
+/// Acquiring the lock outside of a method, and then calling a method of the value which needs a
+/// conflicting lock on the same thread, still deadlocks.
+///
+/// [`UserDataBorrowError`]: enum.Error.html#variant.UserDataBorrowError
+/// [`UserDataBorrowMutError`]: enum.Error.html#variant.UserDataBorrowMutError
+/// [`RuntimeError`]: enum.Error.html#variant.RuntimeError
+/// [`UserDataMethods::inherit`]: struct.UserDataMethods.html#method.inherit
+impl<T: UserData> UserData for Arc<RwLock<T>> {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        fn poisoned() -> Error {
+            Error::RuntimeError("userdata lock is poisoned".to_owned())
+        }
+
+        fn with_ref<'lua, T: 'static>(
+            lua: &'lua Lua,
+            this: MethodSelf<'lua>,
+            f: &mut dyn FnMut(&T) -> Result<MultiValue<'lua>>,
+        ) -> Result<MultiValue<'lua>> {
+            with_self_ref(lua, this, |lock: &Arc<RwLock<T>>| {
+                let value = match HeldLock::held(&**lock) {
+                    None => lock.read().map_err(|_| poisoned())?,
+                    Some(true) => return Err(Error::UserDataBorrowError),
+                    // Waiting for a second read lock could deadlock if another thread is waiting
+                    // to write.
+                    Some(false) => lock.try_read().map_err(|err| match err {
+                        TryLockError::WouldBlock => Error::UserDataBorrowError,
+                        TryLockError::Poisoned(_) => poisoned(),
+                    })?,
+                };
+                let _held = HeldLock::enter(&**lock, false);
+                f(&value)
+            })
+        }
+
+        fn with_mut<'lua, T: 'static>(
+            lua: &'lua Lua,
+            this: MethodSelf<'lua>,
+            f: &mut dyn FnMut(&mut T) -> Result<MultiValue<'lua>>,
+        ) -> Result<MultiValue<'lua>> {
+            with_self_ref(lua, this, |lock: &Arc<RwLock<T>>| {
+                if HeldLock::held(&**lock).is_some() {
+                    return Err(Error::UserDataBorrowMutError);
+                }
+                let mut value = lock.write().map_err(|_| poisoned())?;
+                let _held = HeldLock::enter(&**lock, true);
+                f(&mut value)
+            })
+        }
+
+        let mut forwarded = UserDataMethods::forward(UserDataAccess {
+            with_ref: with_ref::<T>,
+            with_mut: with_mut::<T>,
+        });
+        T::add_methods(&mut forwarded);
+        forwarded.forward_into(methods);
+    }
+}
+
 /// Handle to an internal Lua userdata for any type that implements [`UserData`].
 ///
 /// Similar to `std::any::Any`, this provides an interface for dynamic type checking via the [`is`]
@@ -316,9 +986,18 @@ pub trait UserData: 'static + Sized {
 pub struct AnyUserData<'lua>(pub(crate) LuaRef<'lua>);
 
 impl<'lua> AnyUserData<'lua> {
-    /// Checks whether the type of this userdata is `T`.
-    pub fn is<T: UserData>(&self) -> bool {
+    /// Checks whether the type of this userdata is `T`, or a type which declared `T` as a base
+    /// type with [`UserDataMethods::inherit`], in which case [`borrow::<T>`] reaches the `T` inside
+    /// of it.
+    ///
+    /// This only compares the metatable of the userdata and never borrows the value, so it also
+    /// works while the userdata is mutably borrowed.
+    ///
+    /// [`UserDataMethods::inherit`]: struct.UserDataMethods.html#method.inherit
+    /// [`borrow::<T>`]: #method.borrow
+    pub fn is<T: 'static>(&self) -> bool {
         self.inspect(|_: &RefCell<T>| ()).is_some()
+            || self.coerce(|_: &Coercion<T>, _| ()).is_some()
     }
 
     /// Borrow this userdata immutably if it is of type `T`.
@@ -327,10 +1006,12 @@ impl<'lua> AnyUserData<'lua> {
     ///
     /// Returns a `UserDataBorrowError` if the userdata is already mutably borrowed. Returns a
     /// `UserDataTypeMismatch` if the userdata is not of type `T`.
-    pub fn borrow<T: UserData>(&self) -> Result<Ref<T>> {
-        self.inspect(|cell| {
-            Ok(cell.try_borrow().map_err(|_| Error::UserDataBorrowError)?)
-        }).ok_or(Error::UserDataTypeMismatch)?
+    pub fn borrow<T: 'static>(&self) -> Result<Ref<'_, T>> {
+        match self.inspect(|cell| cell.try_borrow().map_err(|_| Error::UserDataBorrowError)) {
+            Some(res) => res,
+            None => self.coerce(|coercion: &Coercion<T>, ud| (coercion.borrow)(ud))
+                .ok_or(Error::UserDataTypeMismatch)?,
+        }
     }
 
     /// Borrow this userdata mutably if it is of type `T`.
@@ -339,64 +1020,484 @@ impl<'lua> AnyUserData<'lua> {
     ///
     /// Returns a `UserDataBorrowMutError` if the userdata is already borrowed. Returns a
     /// `UserDataTypeMismatch` if the userdata is not of type `T`.
-    pub fn borrow_mut<T: UserData>(&self) -> Result<RefMut<T>> {
-        self.inspect(|cell| {
-            Ok(cell.try_borrow_mut()
-                .map_err(|_| Error::UserDataBorrowMutError)?)
-        }).ok_or(Error::UserDataTypeMismatch)?
+    pub fn borrow_mut<T: 'static>(&self) -> Result<RefMut<'_, T>> {
+        match self.inspect(|cell| {
+            cell.try_borrow_mut()
+                .map_err(|_| Error::UserDataBorrowMutError)
+        }) {
+            Some(res) => res,
+            None => self.coerce(|coercion: &Coercion<T>, ud| (coercion.borrow_mut)(ud))
+                .ok_or(Error::UserDataTypeMismatch)?,
+        }
     }
 
-    fn inspect<'a, T, R, F>(&'a self, func: F) -> Option<R>
-    where
-        T: UserData,
-        F: FnOnce(&'a RefCell<T>) -> R,
-    {
+    /// Sets the Lua value associated to this userdata.
+    ///
+    /// The user value is kept alive as long as the userdata is, so it can be used to attach Lua
+    /// state, such as a table of callbacks, to a single userdata instance. This is the same as
+    /// calling [`set_nth_user_value`] with `n = 1`.
+    ///
+    /// [`set_nth_user_value`]: #method.set_nth_user_value
+    pub fn set_user_value<V: ToLua<'lua>>(&self, value: V) -> Result<()> {
+        self.set_nth_user_value(1, value)
+    }
+
+    /// Returns the Lua value associated to this userdata, set by [`set_user_value`].
+    ///
+    /// If no value has been set, returns `nil`.
+    ///
+    /// [`set_user_value`]: #method.set_user_value
+    pub fn get_user_value<V: FromLua<'lua>>(&self) -> Result<V> {
+        self.get_nth_user_value(1)
+    }
+
+    /// Sets the `n`th Lua value associated to this userdata, starting from 1.
+    ///
+    /// Lua 5.3 only supports a single user value per userdata, so the values are kept in an
+    /// internal table stored as that user value.
+    pub fn set_nth_user_value<V: ToLua<'lua>>(&self, n: usize, value: V) -> Result<()> {
+        let lua = self.0.lua;
+        let n = check_user_value_index(n)?;
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                check_stack(lua.state, 4);
+                lua.push_ref(lua.state, &self.0);
+                ffi::lua_getuservalue(lua.state, -1);
+                if ffi::lua_istable(lua.state, -1) == 0 {
+                    ffi::lua_pop(lua.state, 1);
+                    ffi::lua_newtable(lua.state);
+                    ffi::lua_pushvalue(lua.state, -1);
+                    ffi::lua_setuservalue(lua.state, -3);
+                }
+                lua.push_value(lua.state, value.to_lua(lua)?);
+                ffi::lua_rawseti(lua.state, -2, n);
+                ffi::lua_pop(lua.state, 2);
+                Ok(())
+            })
+        }
+    }
+
+    /// Returns the `n`th Lua value associated to this userdata, set by [`set_nth_user_value`].
+    ///
+    /// If no value has been set, returns `nil`.
+    ///
+    /// [`set_nth_user_value`]: #method.set_nth_user_value
+    pub fn get_nth_user_value<V: FromLua<'lua>>(&self, n: usize) -> Result<V> {
+        let lua = self.0.lua;
+        let n = check_user_value_index(n)?;
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                check_stack(lua.state, 3);
+                lua.push_ref(lua.state, &self.0);
+                ffi::lua_getuservalue(lua.state, -1);
+                let res = if ffi::lua_istable(lua.state, -1) != 0 {
+                    ffi::lua_rawgeti(lua.state, -1, n);
+                    let res = lua.pop_value(lua.state);
+                    ffi::lua_pop(lua.state, 2);
+                    res
+                } else {
+                    ffi::lua_pop(lua.state, 2);
+                    Value::Nil
+                };
+                V::from_lua(res, lua)
+            })
+        }
+    }
+
+    /// Returns the name of the type of this userdata.
+    ///
+    /// This is the `__name` field of its metatable, which defaults to the Rust type name of the
+    /// value, as given by `std::any::type_name`. Returns `None` if the userdata has no metatable,
+    /// or if the field is not a string.
+    pub fn type_name(&self) -> Option<StdString> {
+        let lua = self.0.lua;
+        unsafe {
+            stack_guard(lua.state, 0, || {
+                check_stack(lua.state, 3);
+                lua.push_ref(lua.state, &self.0);
+                if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                    ffi::lua_pop(lua.state, 1);
+                    return None;
+                }
+                push_string(lua.state, "__name");
+                ffi::lua_rawget(lua.state, -2);
+                let name = match lua.pop_value(lua.state) {
+                    Value::String(name) => name.to_str().ok().map(|name| name.to_owned()),
+                    _ => None,
+                };
+                ffi::lua_pop(lua.state, 2);
+                name
+            })
+        }
+    }
+
+    /// Returns the metatable of this userdata.
+    ///
+    /// The metatable is shared by all userdata of the same type, so changes made through it affect
+    /// every instance of that type.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RuntimeError` if the userdata has no metatable, which is the case after its
+    /// value has been moved out with [`take`].
+    ///
+    /// [`take`]: #method.take
+    pub fn get_metatable(&self) -> Result<UserDataMetatable<'lua>> {
+        let lua = self.0.lua;
+        unsafe {
+            stack_err_guard(lua.state, 0, || {
+                check_stack(lua.state, 2);
+                lua.push_ref(lua.state, &self.0);
+                if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                    ffi::lua_pop(lua.state, 1);
+                    return Err(Error::RuntimeError("userdata has no metatable".to_owned()));
+                }
+                let metatable = Table(lua.pop_ref(lua.state));
+                ffi::lua_pop(lua.state, 1);
+                Ok(UserDataMetatable(metatable))
+            })
+        }
+    }
+
+    /// Moves the value out of this userdata if it is of type `T`.
+    ///
+    /// The userdata is left without a value and metatable, so any later access to it from Lua
+    /// raises an error, and later Rust accesses return a `UserDataTypeMismatch` error. The value
+    /// is not dropped when Lua collects the userdata.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `UserDataBorrowMutError` if the userdata is currently borrowed. Returns a
+    /// `UserDataTypeMismatch` if the userdata is not of type `T`.
+    pub fn take<T: 'static>(&self) -> Result<T> {
         unsafe {
             let lua = self.0.lua;
-            stack_guard(lua.state, 0, move || {
+            let metatable_id = lua.registered_userdata_metatable::<T>()
+                .ok_or(Error::UserDataTypeMismatch)?;
+            stack_err_guard(lua.state, 0, move || {
                 check_stack(lua.state, 3);
 
                 lua.push_ref(lua.state, &self.0);
 
-                lua_assert!(
-                    lua.state,
-                    ffi::lua_getmetatable(lua.state, -1) != 0,
-                    "AnyUserData missing metatable"
-                );
+                if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                    return Err(Error::UserDataTypeMismatch);
+                }
 
                 ffi::lua_rawgeti(
                     lua.state,
                     ffi::LUA_REGISTRYINDEX,
-                    lua.userdata_metatable::<T>() as ffi::lua_Integer,
+                    metatable_id as ffi::lua_Integer,
                 );
 
                 if ffi::lua_rawequal(lua.state, -1, -2) == 0 {
-                    ffi::lua_pop(lua.state, 3);
-                    None
-                } else {
-                    let res = func(&*get_userdata::<RefCell<T>>(lua.state, -3));
-                    ffi::lua_pop(lua.state, 3);
-                    Some(res)
+                    return Err(Error::UserDataTypeMismatch);
+                }
+                ffi::lua_pop(lua.state, 2);
+
+                let ud = ffi::lua_touserdata(lua.state, -1) as *mut Option<RefCell<T>>;
+                lua_assert!(lua.state, !ud.is_null());
+                match *ud {
+                    Some(ref cell) if cell.try_borrow_mut().is_ok() => {}
+                    Some(_) => return Err(Error::UserDataBorrowMutError),
+                    None => return Err(Error::UserDataTypeMismatch),
                 }
+                let value = (*ud).take().unwrap().into_inner();
+
+                ffi::lua_pushnil(lua.state);
+                ffi::lua_setmetatable(lua.state, -2);
+                ffi::lua_pop(lua.state, 1);
+
+                Ok(value)
             })
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{MetaMethod, UserData, UserDataMethods};
-    use error::ExternalError;
-    use string::String;
-    use lua::{Function, Lua};
-
-    #[test]
-    fn test_user_data() {
-        struct UserData1(i64);
-        struct UserData2(Box<i64>);
+    // Looks for a coercion to the base type `T` declared by the type of this userdata.
+    fn coerce<'a, T, R, F>(&'a self, func: F) -> Option<R>
+    where
+        T: 'static,
+        F: FnOnce(&'a Coercion<T>, *mut c_void) -> R,
+    {
+        unsafe {
+            let lua = self.0.lua;
+            stack_guard(lua.state, 0, move || {
+                check_stack(lua.state, 2);
 
-        impl UserData for UserData1 {};
-        impl UserData for UserData2 {};
+                lua.push_ref(lua.state, &self.0);
+                if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                    ffi::lua_pop(lua.state, 1);
+                    return None;
+                }
+                let metatable = ffi::lua_topointer(lua.state, -1);
+                let ud = ffi::lua_touserdata(lua.state, -2);
+                ffi::lua_pop(lua.state, 2);
+
+                let coercion = lua.userdata_coercion(metatable, TypeId::of::<T>())?;
+                Some(func(coercion.downcast_ref::<Coercion<T>>()?, ud))
+            })
+        }
+    }
+
+    fn inspect<'a, T, R, F>(&'a self, func: F) -> Option<R>
+    where
+        T: 'static,
+        F: FnOnce(&'a RefCell<T>) -> R,
+    {
+        unsafe {
+            let lua = self.0.lua;
+            // No userdata of type `T` can exist before its metatable has been created.
+            let metatable_id = lua.registered_userdata_metatable::<T>()?;
+            stack_guard(lua.state, 0, move || {
+                check_stack(lua.state, 3);
+
+                lua.push_ref(lua.state, &self.0);
+
+                // The metatable is removed when the value is taken out of the userdata.
+                if ffi::lua_getmetatable(lua.state, -1) == 0 {
+                    ffi::lua_pop(lua.state, 1);
+                    return None;
+                }
+
+                ffi::lua_rawgeti(
+                    lua.state,
+                    ffi::LUA_REGISTRYINDEX,
+                    metatable_id as ffi::lua_Integer,
+                );
+
+                if ffi::lua_rawequal(lua.state, -1, -2) == 0 {
+                    ffi::lua_pop(lua.state, 3);
+                    None
+                } else {
+                    let res = func(&*get_userdata::<RefCell<T>>(lua.state, -3));
+                    ffi::lua_pop(lua.state, 3);
+                    Some(res)
+                }
+            })
+        }
+    }
+
+    /// Returns a pointer identifying the userdata.
+    ///
+    /// The pointer is the same for every handle to the same Lua userdata, so it can be used as a
+    /// key to recognize the userdata without holding a reference to it. It cannot be dereferenced,
+    /// and may be reused for a different value once the userdata has been garbage collected.
+    pub fn to_pointer(&self) -> *const c_void {
+        self.0.to_pointer()
+    }
+
+    /// Creates a weak reference to the userdata, which does not keep it from being garbage
+    /// collected.
+    pub fn downgrade(&self) -> WeakLuaRef<'lua, AnyUserData<'lua>> {
+        WeakLuaRef::new(&self.0)
+    }
+}
+
+/// A borrowed userdata value of type `T`, usable as a callback argument.
+///
+/// Converting a userdata to a `UserDataRef` borrows its value, which stays borrowed until the
+/// `UserDataRef` is dropped. This allows functions and methods to take other userdata as
+/// arguments, without going through [`AnyUserData::borrow`].
+///
+/// The conversion fails with a `FromLuaConversionError` if the value is not a userdata, with a
+/// `UserDataTypeMismatch` if it is not of type `T`, and with a `UserDataBorrowError` if the value
+/// is mutably borrowed.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use rlua::{Lua, UserData, UserDataMethods, UserDataRefMut, Result};
+/// # fn try_main() -> Result<()> {
+/// struct Player {
+///     attack: i64,
+/// }
+///
+/// struct Enemy {
+///     hp: i64,
+/// }
+///
+/// impl UserData for Player {
+///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+///         methods.add_method("attack", |_, player, mut target: UserDataRefMut<Enemy>| {
+///             target.hp -= player.attack;
+///             Ok(target.hp)
+///         });
+///     }
+/// }
+///
+/// impl UserData for Enemy {}
+///
+/// let lua = Lua::new();
+/// lua.globals().set("player", Player { attack: 3 })?;
+/// lua.globals().set("enemy", Enemy { hp: 10 })?;
+/// assert_eq!(lua.eval::<i64>("player:attack(enemy)", None)?, 7);
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`AnyUserData::borrow`]: struct.AnyUserData.html#method.borrow
+pub struct UserDataRef<'lua, T: 'static> {
+    // Borrows from the userdata kept alive by `userdata`, so it must be dropped first.
+    value: Ref<'lua, T>,
+    userdata: AnyUserData<'lua>,
+}
+
+impl<'lua, T: 'static> UserDataRef<'lua, T> {
+    /// Returns the userdata holding the borrowed value.
+    pub fn userdata(&self) -> &AnyUserData<'lua> {
+        &self.userdata
+    }
+}
+
+impl<'lua, T: 'static> Deref for UserDataRef<'lua, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'lua, T: 'static + fmt::Debug> fmt::Debug for UserDataRef<'lua, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.value, f)
+    }
+}
+
+impl<'lua, T: 'static> FromLua<'lua> for UserDataRef<'lua, T> {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<UserDataRef<'lua, T>> {
+        let userdata = userdata_argument(value)?;
+        // The userdata memory is kept alive by the reference held in the `UserDataRef`, and Lua
+        // never moves userdata.
+        let value = unsafe { mem::transmute::<Ref<T>, Ref<'lua, T>>(userdata.borrow::<T>()?) };
+        Ok(UserDataRef { value, userdata })
+    }
+}
+
+/// A mutably borrowed userdata value of type `T`, usable as a callback argument.
+///
+/// See [`UserDataRef`] for details. The conversion fails with a `UserDataBorrowMutError` if the
+/// value is already borrowed, which includes passing the same userdata twice.
+///
+/// [`UserDataRef`]: struct.UserDataRef.html
+pub struct UserDataRefMut<'lua, T: 'static> {
+    // Borrows from the userdata kept alive by `userdata`, so it must be dropped first.
+    value: RefMut<'lua, T>,
+    userdata: AnyUserData<'lua>,
+}
+
+impl<'lua, T: 'static> UserDataRefMut<'lua, T> {
+    /// Returns the userdata holding the borrowed value.
+    pub fn userdata(&self) -> &AnyUserData<'lua> {
+        &self.userdata
+    }
+}
+
+impl<'lua, T: 'static> Deref for UserDataRefMut<'lua, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'lua, T: 'static> DerefMut for UserDataRefMut<'lua, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<'lua, T: 'static + fmt::Debug> fmt::Debug for UserDataRefMut<'lua, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.value, f)
+    }
+}
+
+impl<'lua, T: 'static> FromLua<'lua> for UserDataRefMut<'lua, T> {
+    fn from_lua(value: Value<'lua>, _: &'lua Lua) -> Result<UserDataRefMut<'lua, T>> {
+        let userdata = userdata_argument(value)?;
+        // The userdata memory is kept alive by the reference held in the `UserDataRefMut`, and Lua
+        // never moves userdata.
+        let value =
+            unsafe { mem::transmute::<RefMut<T>, RefMut<'lua, T>>(userdata.borrow_mut::<T>()?) };
+        Ok(UserDataRefMut { value, userdata })
+    }
+}
+
+fn userdata_argument<'lua>(value: Value<'lua>) -> Result<AnyUserData<'lua>> {
+    match value {
+        Value::UserData(userdata) => Ok(userdata),
+        _ => Err(Error::FromLuaConversionError {
+            from: value.type_name(),
+            to: "userdata",
+            message: None,
+        }),
+    }
+}
+
+/// Handle to the metatable of a userdata, returned by [`AnyUserData::get_metatable`].
+///
+/// Entries are accessed raw, without invoking any metamethods of the metatable itself. The `__gc`
+/// and `__metatable` entries are managed by rlua and cannot be changed.
+///
+/// [`AnyUserData::get_metatable`]: struct.AnyUserData.html#method.get_metatable
+#[derive(Clone, Debug)]
+pub struct UserDataMetatable<'lua>(pub(crate) Table<'lua>);
+
+impl<'lua> UserDataMetatable<'lua> {
+    /// Gets the value of a metamethod or meta field, or `nil` if it is not set.
+    pub fn get<V: FromLua<'lua>>(&self, key: MetaMethod) -> Result<V> {
+        self.0.raw_get(key.name())
+    }
+
+    /// Sets the value of a metamethod or meta field, a value of `nil` removes it.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `RuntimeError` if `key` is `__gc` or `__metatable`.
+    pub fn set<V: ToLua<'lua>>(&self, key: MetaMethod, value: V) -> Result<()> {
+        let name = key.name();
+        if name == "__gc" || name == "__metatable" {
+            return Err(Error::RuntimeError(format!(
+                "cannot change the {} field of a userdata metatable",
+                name
+            )));
+        }
+        self.0.raw_set(name, value)
+    }
+
+    /// Checks whether a metamethod or meta field is set.
+    pub fn contains(&self, key: MetaMethod) -> Result<bool> {
+        // Metatables created by rlua never have a metatable themselves.
+        self.0.contains_key(key.name())
+    }
+}
+
+fn check_user_value_index(n: usize) -> Result<ffi::lua_Integer> {
+    if n == 0 || n > ffi::lua_Integer::MAX as usize {
+        Err(Error::RuntimeError(format!("user value index {} out of range", n)))
+    } else {
+        Ok(n as ffi::lua_Integer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MetaMethod, UserData, UserDataMethods};
+    use error::{Error, ExternalError};
+    use string::String;
+    use table::Table;
+    use lua::{Function, Lua, MultiValue, Nil, Value};
+
+    #[test]
+    fn test_user_data() {
+        struct UserData1(i64);
+        struct UserData2(Box<i64>);
+
+        impl UserData for UserData1 {}
+        impl UserData for UserData2 {}
 
         let lua = Lua::new();
 
@@ -410,45 +1511,429 @@ mod tests {
 
         assert_eq!(userdata1.borrow::<UserData1>().unwrap().0, 1);
         assert_eq!(*userdata2.borrow::<UserData2>().unwrap().0, 2);
+
+        let _borrow = userdata1.borrow_mut::<UserData1>().unwrap();
+        assert!(userdata1.is::<UserData1>());
+        assert!(!userdata1.is::<UserData2>());
+    }
+
+    #[test]
+    fn test_borrow_errors() {
+        struct UserData1(i64);
+        struct UserData2;
+
+        impl UserData for UserData1 {}
+        impl UserData for UserData2 {}
+
+        let lua = Lua::new();
+        let userdata = lua.create_userdata(UserData1(1));
+
+        match userdata.borrow::<UserData2>() {
+            Err(Error::UserDataTypeMismatch) => {}
+            _ => panic!("expected UserDataTypeMismatch"),
+        }
+        match userdata.borrow_mut::<UserData2>() {
+            Err(Error::UserDataTypeMismatch) => {}
+            _ => panic!("expected UserDataTypeMismatch"),
+        }
+
+        {
+            let _borrow = userdata.borrow::<UserData1>().unwrap();
+            assert_eq!(userdata.borrow::<UserData1>().unwrap().0, 1);
+            match userdata.borrow_mut::<UserData1>() {
+                Err(Error::UserDataBorrowMutError) => {}
+                _ => panic!("expected UserDataBorrowMutError"),
+            }
+        }
+
+        {
+            let mut borrow = userdata.borrow_mut::<UserData1>().unwrap();
+            borrow.0 = 2;
+            match userdata.borrow::<UserData1>() {
+                Err(Error::UserDataBorrowError) => {}
+                _ => panic!("expected UserDataBorrowError"),
+            }
+        }
+        assert_eq!(userdata.borrow::<UserData1>().unwrap().0, 2);
+    }
+
+    #[test]
+    fn test_take() {
+        use std::rc::Rc;
+
+        struct Resource(Rc<()>);
+
+        impl UserData for Resource {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("count", |_, r, ()| Ok(Rc::strong_count(&r.0)));
+            }
+        }
+
+        let lua = Lua::new();
+        let rc = Rc::new(());
+        let userdata = lua.create_userdata(Resource(rc.clone()));
+        lua.globals().set("resource", userdata.clone()).unwrap();
+        assert_eq!(lua.eval::<usize>("resource:count()", None).unwrap(), 2);
+
+        {
+            let _borrow = userdata.borrow::<Resource>().unwrap();
+            match userdata.take::<Resource>() {
+                Err(Error::UserDataBorrowMutError) => {}
+                _ => panic!("expected UserDataBorrowMutError"),
+            }
+        }
+
+        let resource = userdata.take::<Resource>().unwrap();
+        assert_eq!(Rc::strong_count(&rc), 2);
+        assert!(!userdata.is::<Resource>());
+        match userdata.take::<Resource>() {
+            Err(Error::UserDataTypeMismatch) => {}
+            _ => panic!("expected UserDataTypeMismatch"),
+        }
+        assert!(lua.eval::<usize>("resource:count()", None).is_err());
+
+        drop(resource);
+        lua.globals().set("resource", Nil).unwrap();
+        drop(userdata);
+        lua.exec::<()>("collectgarbage()", None).unwrap();
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn test_user_value() {
+        struct MyUserData;
+
+        impl UserData for MyUserData {}
+
+        let lua = Lua::new();
+        let userdata = lua.create_userdata(MyUserData);
+        match userdata.get_user_value::<Value>().unwrap() {
+            Nil => {}
+            _ => panic!("expected nil user value"),
+        }
+
+        let callbacks = lua.create_table();
+        callbacks.set("answer", 42).unwrap();
+        userdata.set_user_value(callbacks).unwrap();
+        lua.exec::<()>("collectgarbage()", None).unwrap();
+
+        let callbacks = userdata.get_user_value::<Table>().unwrap();
+        assert_eq!(callbacks.get::<_, i64>("answer").unwrap(), 42);
+
+        userdata.set_user_value("replaced").unwrap();
+        assert_eq!(
+            userdata.get_user_value::<String>().unwrap().to_str().unwrap(),
+            "replaced"
+        );
+
+        userdata.set_nth_user_value(3, 3).unwrap();
+        assert_eq!(userdata.get_nth_user_value::<i64>(3).unwrap(), 3);
+        assert_eq!(userdata.get_nth_user_value::<Option<i64>>(2).unwrap(), None);
+        assert_eq!(
+            userdata.get_nth_user_value::<String>(1).unwrap().to_str().unwrap(),
+            "replaced"
+        );
+        assert!(userdata.set_nth_user_value(0, 0).is_err());
+        assert!(userdata.get_nth_user_value::<Value>(0).is_err());
+    }
+
+    #[test]
+    fn test_metatable() {
+        struct MyUserData(i64);
+
+        impl UserData for MyUserData {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_meta_method(MetaMethod::Len, |_, data, ()| Ok(data.0));
+            }
+        }
+
+        let lua = Lua::new();
+        let userdata = lua.create_userdata(MyUserData(7));
+        lua.globals().set("userdata", userdata.clone()).unwrap();
+
+        let metatable = userdata.get_metatable().unwrap();
+        assert!(metatable.contains(MetaMethod::Len).unwrap());
+        assert!(!metatable.contains(MetaMethod::Concat).unwrap());
+        metatable
+            .get::<Function>(MetaMethod::Len)
+            .unwrap()
+            .call::<_, i64>(userdata.clone())
+            .unwrap();
+
+        metatable
+            .set(MetaMethod::Custom("__name".to_owned()), "MyUserData")
+            .unwrap();
+        metatable
+            .set(
+                MetaMethod::Concat,
+                lua.create_function(|_, (_, s): (Value, String)| {
+                    Ok(format!("ud{}", s.to_str()?))
+                }),
+            )
+            .unwrap();
+        assert_eq!(
+            lua.eval::<String>("userdata .. '!'", None)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "ud!"
+        );
+        assert_eq!(lua.eval::<i64>("#userdata", None).unwrap(), 7);
+        assert!(
+            metatable
+                .set(MetaMethod::Custom("__gc".to_owned()), Nil)
+                .is_err()
+        );
+
+        userdata.take::<MyUserData>().unwrap();
+        assert!(userdata.get_metatable().is_err());
+    }
+
+    #[test]
+    fn test_registered_type() {
+        // Stands in for a type from another crate, which cannot implement `UserData`.
+        struct Foreign(i64);
+
+        let lua = Lua::new();
+        assert!(lua.create_any_userdata(Foreign(1)).is_err());
+
+        lua.register_userdata_type::<Foreign, _>(|methods| {
+            methods.add_method("get", |_, foreign, ()| Ok(foreign.0));
+            methods.add_method_mut("set", |_, foreign, value| {
+                foreign.0 = value;
+                Ok(())
+            });
+        }).unwrap();
+        assert!(lua.register_userdata_type::<Foreign, _>(|_| {}).is_err());
+
+        let userdata = lua.create_any_userdata(Foreign(1)).unwrap();
+        lua.globals().set("foreign", userdata.clone()).unwrap();
+        lua.exec::<()>("foreign:set(foreign:get() + 1)", None).unwrap();
+
+        assert!(userdata.is::<Foreign>());
+        assert_eq!(userdata.borrow::<Foreign>().unwrap().0, 2);
+        assert_eq!(userdata.take::<Foreign>().unwrap().0, 2);
+    }
+
+    #[test]
+    fn test_register_userdata() {
+        struct Counter(i64);
+
+        impl UserData for Counter {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("get", |_, counter, ()| Ok(counter.0));
+            }
+        }
+
+        let lua = Lua::new();
+        lua.register_userdata::<Counter>();
+        lua.register_userdata::<Counter>();
+        assert!(lua.register_userdata_type::<Counter, _>(|_| {}).is_err());
+
+        let globals = lua.globals();
+        globals.set("a", Counter(1)).unwrap();
+        globals.set("b", Counter(2)).unwrap();
+        assert!(lua.eval::<bool>("getmetatable(a) == getmetatable(b)", None).unwrap());
+        assert_eq!(lua.eval::<i64>("a:get() + b:get()", None).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_inheritance() {
+        struct Entity {
+            id: i64,
+        }
+
+        struct Character {
+            entity: Entity,
+            hp: i64,
+        }
+
+        struct Player {
+            character: Character,
+        }
+
+        impl UserData for Entity {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("id", |_, entity, ()| Ok(entity.id));
+                methods.add_method("kind", |_, _, ()| Ok("entity"));
+            }
+        }
+
+        impl UserData for Character {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.inherit(|c: &Character| &c.entity, |c: &mut Character| &mut c.entity);
+                methods.add_method_mut("damage", |_, character, amount: i64| {
+                    character.hp -= amount;
+                    Ok(character.hp)
+                });
+                methods.add_method("kind", |_, _, ()| Ok("character"));
+            }
+        }
+
+        impl UserData for Player {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.inherit(|p: &Player| &p.character, |p: &mut Player| &mut p.character);
+                methods.inherit(
+                    |p: &Player| &p.character.entity,
+                    |p: &mut Player| &mut p.character.entity,
+                );
+            }
+        }
+
+        let lua = Lua::new();
+        let player = lua.create_userdata(Player {
+            character: Character {
+                entity: Entity { id: 3 },
+                hp: 10,
+            },
+        });
+        lua.globals().set("player", player.clone()).unwrap();
+
+        assert_eq!(lua.eval::<i64>("player:id()", None).unwrap(), 3);
+        assert_eq!(lua.eval::<i64>("player:damage(4)", None).unwrap(), 6);
+        assert_eq!(
+            lua.eval::<String>("player:kind()", None)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "character"
+        );
+        assert!(lua.eval::<()>("player:missing()", None).is_err());
+
+        assert!(player.is::<Player>());
+        assert!(player.is::<Character>());
+        assert!(player.is::<Entity>());
+        assert_eq!(player.borrow::<Character>().unwrap().hp, 6);
+        player.borrow_mut::<Entity>().unwrap().id = 4;
+        assert_eq!(lua.eval::<i64>("player:id()", None).unwrap(), 4);
+        {
+            let _borrow = player.borrow_mut::<Player>().unwrap();
+            assert!(player.is::<Character>());
+        }
+
+        let entity = lua.create_userdata(Entity { id: 1 });
+        assert!(!entity.is::<Character>());
+        assert!(entity.borrow::<Character>().is_err());
+        let character = lua.create_userdata(Character {
+            entity: Entity { id: 2 },
+            hp: 1,
+        });
+        assert!(character.is::<Entity>());
+        assert!(!character.is::<Player>());
+    }
+
+    #[test]
+    fn test_methods() {
+        struct MyUserData(i64);
+
+        impl UserData for MyUserData {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("get_value", |_, data, ()| Ok(data.0));
+                methods.add_method_mut("set_value", |_, data, args| {
+                    data.0 = args;
+                    Ok(())
+                });
+            }
+        }
+
+        let lua = Lua::new();
+        let globals = lua.globals();
+        let userdata = lua.create_userdata(MyUserData(42));
+        globals.set("userdata", userdata.clone()).unwrap();
+        lua.exec::<()>(
+            r#"
+            function get_it()
+                return userdata:get_value()
+            end
+
+            function set_it(i)
+                return userdata:set_value(i)
+            end
+        "#,
+            None,
+        ).unwrap();
+        let get = globals.get::<_, Function>("get_it").unwrap();
+        let set = globals.get::<_, Function>("set_it").unwrap();
+        assert_eq!(get.call::<_, i64>(()).unwrap(), 42);
+        userdata.borrow_mut::<MyUserData>().unwrap().0 = 64;
+        assert_eq!(get.call::<_, i64>(()).unwrap(), 64);
+        set.call::<_, ()>(100).unwrap();
+        assert_eq!(get.call::<_, i64>(()).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_method_borrows() {
+        struct MyUserData(i64);
+
+        impl UserData for MyUserData {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("get", |_, data, ()| Ok(data.0));
+                methods.add_method_mut("increment", |_, data, ()| {
+                    data.0 += 1;
+                    Ok(())
+                });
+                methods.add_method("apply", |_, _, f: Function| f.call::<_, i64>(()));
+            }
+        }
+
+        let lua = Lua::new();
+        let userdata = lua.create_userdata(MyUserData(1));
+        lua.globals().set("userdata", userdata.clone()).unwrap();
+
+        {
+            // Read-only methods only need a shared borrow, mutating ones need exclusive access.
+            let _borrow = userdata.borrow::<MyUserData>().unwrap();
+            assert_eq!(lua.eval::<i64>("userdata:get()", None).unwrap(), 1);
+            assert!(lua.exec::<()>("userdata:increment()", None).is_err());
+        }
+
+        lua.exec::<()>("userdata:increment()", None).unwrap();
+        assert_eq!(userdata.borrow::<MyUserData>().unwrap().0, 2);
+
+        // Other methods of the type may be called while a method runs.
+        let nested = "return userdata:apply(function() return userdata:get() end)";
+        assert_eq!(lua.eval::<i64>(nested, None).unwrap(), 2);
     }
 
     #[test]
-    fn test_methods() {
-        struct MyUserData(i64);
+    fn test_methods_table() {
+        struct Point(i64);
+        struct Empty;
 
-        impl UserData for MyUserData {
+        impl UserData for Point {
             fn add_methods(methods: &mut UserDataMethods<Self>) {
-                methods.add_method("get_value", |_, data, ()| Ok(data.0));
-                methods.add_method_mut("set_value", |_, data, args| {
-                    data.0 = args;
-                    Ok(())
+                methods.add_function("new", |_, x| Ok(Point(x)));
+                methods.add_method("x", |_, p, ()| Ok(p.0));
+                methods.add_meta_method(MetaMethod::Index, |_, p, key: String| {
+                    Ok(format!("{}{}", key.to_str()?, p.0))
                 });
             }
         }
 
+        impl UserData for Empty {}
+
         let lua = Lua::new();
-        let globals = lua.globals();
-        let userdata = lua.create_userdata(MyUserData(42));
-        globals.set("userdata", userdata.clone()).unwrap();
+        let methods = lua.userdata_methods::<Point>().unwrap();
+        lua.globals().set("Point", methods).unwrap();
         lua.exec::<()>(
             r#"
-            function get_it()
-                return userdata:get_value()
-            end
-
-            function set_it(i)
-                return userdata:set_value(i)
-            end
-        "#,
+                function Point.double(p)
+                    return p:x() * 2
+                end
+            "#,
             None,
         ).unwrap();
-        let get = globals.get::<_, Function>("get_it").unwrap();
-        let set = globals.get::<_, Function>("set_it").unwrap();
-        assert_eq!(get.call::<_, i64>(()).unwrap(), 42);
-        userdata.borrow_mut::<MyUserData>().unwrap().0 = 64;
-        assert_eq!(get.call::<_, i64>(()).unwrap(), 64);
-        set.call::<_, ()>(100).unwrap();
-        assert_eq!(get.call::<_, i64>(()).unwrap(), 100);
+
+        assert_eq!(lua.eval::<i64>("Point.new(3):x()", None).unwrap(), 3);
+        assert_eq!(lua.eval::<i64>("Point.new(4):double()", None).unwrap(), 8);
+        assert_eq!(
+            lua.eval::<String>("Point.new(5).other", None)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "other5"
+        );
+        assert!(lua.userdata_methods::<Empty>().is_none());
     }
 
     #[test]
@@ -498,6 +1983,532 @@ mod tests {
         assert!(lua.eval::<()>("userdata2.nonexist_field", None).is_err());
     }
 
+    #[test]
+    fn test_proxy() {
+        struct Player(::std::string::String);
+        struct Empty;
+
+        impl UserData for Player {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_function("new", |_, name| Ok(Player(name)));
+                methods.add_method("name", |_, p, ()| Ok(p.0.clone()));
+            }
+        }
+
+        impl UserData for Empty {}
+
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals.set("Player", lua.create_proxy::<Player>()).unwrap();
+        globals.set("Empty", lua.create_proxy::<Empty>()).unwrap();
+        lua.exec::<()>(
+            r#"
+                local p = Player.new("bob")
+                assert(p:name() == "bob")
+                Player.name = "shadowed"
+                assert(p:name() == "bob")
+                assert(next(Empty) == nil)
+            "#,
+            None,
+        ).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        use serde_json;
+
+        #[derive(Serialize)]
+        struct Point {
+            x: i64,
+            y: i64,
+        }
+
+        impl UserData for Point {}
+
+        struct Opaque;
+
+        impl UserData for Opaque {}
+
+        let lua = Lua::new();
+        let point = lua.create_ser_userdata(Point { x: 1, y: 2 });
+        assert_eq!(
+            serde_json::to_string(&point).unwrap(),
+            r#"{"x":1,"y":2}"#
+        );
+
+        let other = lua.create_userdata(Point { x: 3, y: 4 });
+        assert_eq!(
+            serde_json::to_string(&other).unwrap(),
+            r#"{"x":3,"y":4}"#
+        );
+
+        let opaque = lua.create_userdata(Opaque);
+        assert!(serde_json::to_string(&opaque).is_err());
+    }
+
+    #[test]
+    fn test_shared_userdata() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use std::sync::{mpsc, Arc, Mutex, RwLock};
+        use std::thread;
+        use std::time::Duration;
+
+        struct Counter(i64);
+
+        impl UserData for Counter {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("get", |_, counter, ()| Ok(counter.0));
+                methods.add_method_mut("incr", |_, counter, n: i64| {
+                    counter.0 += n;
+                    Ok(())
+                });
+                methods.add_method_mut("apply", |_, _, f: Function| f.call::<_, ()>(()));
+                methods.add_meta_method(MetaMethod::ToString, |_, counter, ()| {
+                    Ok(format!("counter {}", counter.0))
+                });
+            }
+        }
+
+        let lua = Lua::new();
+        let globals = lua.globals();
+
+        let rc = Rc::new(RefCell::new(Counter(0)));
+        let mutex = Arc::new(Mutex::new(Counter(10)));
+        let rwlock = Arc::new(RwLock::new(Counter(20)));
+        globals.set("rc", rc.clone()).unwrap();
+        globals.set("mutex", mutex.clone()).unwrap();
+        globals.set("rwlock", rwlock.clone()).unwrap();
+
+        lua.exec::<()>(
+            r#"
+                for _, counter in ipairs({rc, mutex, rwlock}) do
+                    counter:incr(2)
+                    counter:incr(counter:get())
+                end
+                assert(tostring(rc) == "counter 4")
+            "#,
+            None,
+        ).unwrap();
+
+        assert_eq!(rc.borrow().0, 4);
+        assert_eq!(mutex.lock().unwrap().0, 24);
+        assert_eq!(rwlock.read().unwrap().0, 44);
+
+        let borrowed = rc.borrow_mut();
+        match lua.eval::<i64>("rc:get()", None) {
+            Err(Error::CallbackError { ref cause, .. }) => match *cause.as_ref() {
+                Error::UserDataBorrowError => {}
+                ref other => panic!("wrong error type {:?}", other),
+            },
+            Err(other) => panic!("wrong error type {:?}", other),
+            Ok(_) => panic!("incorrectly returned Ok"),
+        }
+        drop(borrowed);
+
+        // Calling a method of a value while one of its methods runs fails instead of waiting for
+        // the lock, which would never be released.
+        for &(name, expected) in &[
+            ("rc", "UserDataBorrowError"),
+            ("mutex", "UserDataBorrowMutError"),
+            ("rwlock", "UserDataBorrowError"),
+        ] {
+            let source = format!("{0}:apply(function() {0}:get() end)", name);
+            let err = lua.exec::<()>(&source, None).unwrap_err();
+            assert!(format!("{:?}", err).contains(expected), "{:?}", err);
+        }
+
+        // Locks held by other threads are waited for.
+        for &name in &["mutex", "rwlock"] {
+            let (locked, wait) = mpsc::channel();
+            let (release, waiting) = mpsc::channel::<()>();
+            let holder = {
+                let mutex = mutex.clone();
+                let rwlock = rwlock.clone();
+                thread::spawn(move || {
+                    let _mutex = mutex.lock().unwrap();
+                    let _rwlock = rwlock.write().unwrap();
+                    locked.send(()).unwrap();
+                    waiting.recv().unwrap();
+                    thread::sleep(Duration::from_millis(50));
+                })
+            };
+            wait.recv().unwrap();
+            release.send(()).unwrap();
+            lua.exec::<()>(&format!("{}:incr(1)", name), None).unwrap();
+            holder.join().unwrap();
+        }
+        assert_eq!(mutex.lock().unwrap().0, 25);
+        assert_eq!(rwlock.read().unwrap().0, 45);
+
+        // A read lock held by another thread does not block reading.
+        let read = rwlock.read().unwrap();
+        let reader = {
+            let rwlock = rwlock.clone();
+            thread::spawn(move || {
+                let lua = Lua::new();
+                lua.globals().set("rwlock", rwlock).unwrap();
+                lua.eval::<i64>("rwlock:get()", None).unwrap()
+            })
+        };
+        assert_eq!(reader.join().unwrap(), 45);
+        drop(read);
+    }
+
+    #[test]
+    fn test_type_name() {
+        struct Named;
+        struct Custom;
+
+        impl UserData for Named {}
+
+        impl UserData for Custom {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_meta_field(MetaMethod::Custom("__name".to_owned()), "custom");
+            }
+        }
+
+        let lua = Lua::new();
+        let named = lua.create_userdata(Named);
+        let custom = lua.create_userdata(Custom);
+        let name = named.type_name().unwrap();
+        assert!(name.ends_with("Named"));
+        assert_eq!(custom.type_name().unwrap(), "custom");
+
+        let globals = lua.globals();
+        globals.set("named", named.clone()).unwrap();
+        let tostring = lua.eval::<String>("tostring(named)", None).unwrap();
+        assert!(tostring.to_str().unwrap().starts_with(&name));
+        match lua.exec::<()>("local x = named.field", None) {
+            Err(Error::RuntimeError(msg)) => assert!(msg.contains(&name)),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        named.take::<Named>().unwrap();
+        assert!(named.type_name().is_none());
+    }
+
+    #[test]
+    fn test_destructor() {
+        use std::rc::Rc;
+        use std::cell::Cell;
+
+        struct Tracked(Rc<Cell<i64>>, i64);
+
+        impl UserData for Tracked {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_destructor(|_, tracked| {
+                    tracked.0.set(tracked.0.get() + tracked.1);
+                    Ok(())
+                });
+            }
+        }
+
+        let total = Rc::new(Cell::new(0));
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals.set("a", Tracked(total.clone(), 1)).unwrap();
+        globals.set("b", Tracked(total.clone(), 10)).unwrap();
+        let c = lua.create_userdata(Tracked(total.clone(), 100));
+
+        lua.exec::<()>("a = nil; collectgarbage()", None).unwrap();
+        assert_eq!(total.get(), 1);
+
+        // Values moved out are not passed to the destructor.
+        let taken = c.take::<Tracked>().unwrap();
+        drop(c);
+        lua.exec::<()>("collectgarbage()", None).unwrap();
+        assert_eq!(total.get(), 1);
+        assert_eq!(taken.1, 100);
+
+        drop(globals);
+        drop(lua);
+        assert_eq!(total.get(), 11);
+    }
+
+    #[test]
+    fn test_destructor_errors() {
+        use std::rc::Rc;
+        use std::cell::Cell;
+
+        struct Failing(Rc<Cell<i64>>, bool);
+
+        impl UserData for Failing {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_destructor(|_, failing| {
+                    failing.0.set(failing.0.get() + 1);
+                    if failing.1 {
+                        panic!("destructor panic");
+                    }
+                    Err(Error::RuntimeError("destructor error".to_owned()))
+                });
+            }
+        }
+
+        let count = Rc::new(Cell::new(0));
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals.set("a", Failing(count.clone(), false)).unwrap();
+        globals.set("b", Failing(count.clone(), true)).unwrap();
+        globals.set("c", Failing(count.clone(), false)).unwrap();
+
+        // Neither the error nor the panic leaves `__gc`, which would abort the process.
+        lua.exec::<()>("a = nil; b = nil; collectgarbage()", None).unwrap();
+        assert_eq!(count.get(), 2);
+
+        drop(globals);
+        drop(lua);
+        assert_eq!(count.get(), 3);
+    }
+
+    #[test]
+    fn test_method_self() {
+        struct A(i64);
+        struct B;
+
+        impl UserData for A {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("get", |_, a, ()| Ok(a.0));
+            }
+        }
+
+        impl UserData for B {}
+
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals.set("a", A(5)).unwrap();
+        globals.set("b", B).unwrap();
+
+        lua.exec::<()>(
+            r#"
+                local get = a.get
+                assert(get(a) == 5)
+                assert(not pcall(get))
+                assert(not pcall(get, b))
+                assert(not pcall(get, {}))
+                assert(not pcall(get, 1))
+            "#,
+            None,
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_derived_eq() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        #[derive(PartialEq)]
+        struct Point(i64, i64);
+        struct Other;
+
+        impl UserData for Point {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_derived_eq();
+            }
+        }
+
+        impl UserData for Other {}
+
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals.set("a", Point(1, 2)).unwrap();
+        globals.set("b", Point(1, 2)).unwrap();
+        globals.set("c", Point(3, 4)).unwrap();
+        globals.set("other", Other).unwrap();
+        globals.set("shared_a", Rc::new(RefCell::new(Point(5, 6)))).unwrap();
+        globals.set("shared_b", Rc::new(RefCell::new(Point(5, 6)))).unwrap();
+
+        lua.exec::<()>(
+            r#"
+                assert(a == b)
+                assert(a ~= c)
+                assert(a ~= other)
+                assert(other ~= a)
+                assert(shared_a == shared_b)
+                assert(a ~= shared_a)
+            "#,
+            None,
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_userdata_ref() {
+        use super::{UserDataRef, UserDataRefMut};
+
+        struct Player(i64);
+        struct Enemy(i64);
+
+        impl UserData for Player {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("attack", |_, player, mut enemy: UserDataRefMut<Enemy>| {
+                    enemy.0 -= player.0;
+                    Ok(enemy.0)
+                });
+                methods.add_function("sum", |_, (a, b): (UserDataRef<Enemy>, UserDataRef<Enemy>)| {
+                    Ok(a.0 + b.0)
+                });
+                methods.add_function(
+                    "swap",
+                    |_, (mut a, mut b): (UserDataRefMut<Enemy>, UserDataRefMut<Enemy>)| {
+                        ::std::mem::swap(&mut a.0, &mut b.0);
+                        Ok(())
+                    },
+                );
+            }
+        }
+
+        impl UserData for Enemy {}
+
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals.set("player", Player(3)).unwrap();
+        globals.set("enemy", Enemy(10)).unwrap();
+        globals.set("other", Enemy(1)).unwrap();
+
+        assert_eq!(lua.eval::<i64>("player:attack(enemy)", None).unwrap(), 7);
+        assert_eq!(lua.eval::<i64>("player.sum(enemy, enemy)", None).unwrap(), 14);
+        lua.exec::<()>("player.swap(enemy, other)", None).unwrap();
+        assert_eq!(lua.eval::<i64>("player.sum(other, other)", None).unwrap(), 14);
+        assert!(lua.exec::<()>("player.swap(enemy, enemy)", None).is_err());
+        assert!(lua.exec::<()>("player:attack(player)", None).is_err());
+        assert!(lua.exec::<()>("player:attack(1)", None).is_err());
+
+        let enemy = globals.get::<_, UserDataRef<Enemy>>("enemy").unwrap();
+        assert_eq!(enemy.0, 1);
+        assert!(enemy.userdata().borrow_mut::<Enemy>().is_err());
+    }
+
+    #[test]
+    fn test_meta_function_operands() {
+        #[derive(Copy, Clone)]
+        struct Meters(f64);
+
+        impl UserData for Meters {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                // The userdata may be on either side of the operator.
+                methods.add_meta_function(MetaMethod::Mul, |lua, args: MultiValue| {
+                    let mut factor = 1.0;
+                    let mut meters = None;
+                    for arg in args {
+                        match arg {
+                            Value::UserData(ud) => meters = Some(ud.borrow::<Meters>()?.0),
+                            value => factor *= lua.coerce_number(value)?,
+                        }
+                    }
+                    Ok(Meters(meters.unwrap() * factor))
+                });
+            }
+        }
+
+        let lua = Lua::new();
+        lua.globals().set("m", Meters(1.5)).unwrap();
+        assert_eq!(lua.eval::<Meters>("m * 2", None).unwrap().0, 3.0);
+        assert_eq!(lua.eval::<Meters>("4 * m", None).unwrap().0, 6.0);
+    }
+
+    #[test]
+    fn test_pairs_metamethod() {
+        struct Registry(Vec<(&'static str, i64)>);
+
+        impl UserData for Registry {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_meta_method(MetaMethod::Pairs, |lua, registry, ()| {
+                    let mut entries = registry.0.clone().into_iter();
+                    Ok(lua.create_function(move |_, ()| match entries.next() {
+                        Some((k, v)) => Ok((Some(k), Some(v))),
+                        None => Ok((None, None)),
+                    }))
+                });
+            }
+        }
+
+        let lua = Lua::new();
+        lua.globals()
+            .set("registry", Registry(vec![("a", 1), ("b", 2)]))
+            .unwrap();
+        assert_eq!(
+            lua.eval::<String>(
+                r#"
+                    local s = ""
+                    for k, v in pairs(registry) do
+                        s = s .. k .. v
+                    end
+                    return s
+                "#,
+                None,
+            ).unwrap()
+                .to_str()
+                .unwrap(),
+            "a1b2"
+        );
+    }
+
+    #[test]
+    fn test_ipairs_metamethod() {
+        struct List(Vec<i64>);
+
+        impl UserData for List {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_meta_method(MetaMethod::IPairs, |lua, list, ()| {
+                    let items = list.0.clone();
+                    let next = lua.create_function(move |_, (_, i): (Value, usize)| {
+                        match items.get(i) {
+                            Some(&v) => Ok((Some(i + 1), Some(v))),
+                            None => Ok((None, None)),
+                        }
+                    });
+                    Ok((next, Value::Nil, 0))
+                });
+            }
+        }
+
+        let lua = Lua::new();
+        lua.globals().set("list", List(vec![3, 4, 5])).unwrap();
+        assert_eq!(
+            lua.eval::<i64>(
+                r#"
+                    local sum = 0
+                    for i, v in ipairs(list) do
+                        sum = sum + i * v
+                    end
+                    return sum
+                "#,
+                None,
+            ).unwrap(),
+            26
+        );
+    }
+
+    #[test]
+    fn test_meta_fields() {
+        struct Tagged;
+
+        impl UserData for Tagged {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_meta_field(MetaMethod::Custom("__name".to_owned()), "Tagged");
+                methods.add_meta_field(MetaMethod::Custom("__version".to_owned()), 3);
+                methods.add_meta_field(MetaMethod::Custom("__weight".to_owned()), 0.5);
+                methods.add_meta_field(MetaMethod::Custom("__sealed".to_owned()), true);
+            }
+        }
+
+        let lua = Lua::new();
+        let ud = lua.create_userdata(Tagged);
+        lua.globals().set("tagged", ud.clone()).unwrap();
+        assert!(
+            lua.eval::<String>("tostring(tagged)", None)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .starts_with("Tagged: ")
+        );
+        let metatable = ud.get_metatable().unwrap();
+        assert_eq!(metatable.get::<i64>(MetaMethod::Custom("__version".to_owned())).unwrap(), 3);
+        assert_eq!(metatable.get::<f64>(MetaMethod::Custom("__weight".to_owned())).unwrap(), 0.5);
+        assert!(metatable.get::<bool>(MetaMethod::Custom("__sealed".to_owned())).unwrap());
+    }
+
     #[test]
     #[should_panic]
     fn test_expired_userdata() {
@@ -539,10 +2550,11 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn detroys_userdata() {
-        use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+        use std::sync::atomic::{AtomicBool, Ordering};
 
-        static DROPPED: AtomicBool = ATOMIC_BOOL_INIT;
+        static DROPPED: AtomicBool = AtomicBool::new(false);
 
         struct MyUserdata;
 
@@ -564,4 +2576,47 @@ mod tests {
         drop(lua); // should destroy all objects
         assert_eq!(DROPPED.load(Ordering::SeqCst), true);
     }
+
+    #[test]
+    fn test_method_self_survives_gc() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct Probe {
+            value: i64,
+            dropped: Rc<Cell<bool>>,
+        }
+
+        impl Drop for Probe {
+            fn drop(&mut self) {
+                self.dropped.set(true);
+            }
+        }
+
+        impl UserData for Probe {
+            fn add_methods(methods: &mut UserDataMethods<Self>) {
+                methods.add_method("collect", |lua, probe, ()| {
+                    lua.exec::<()>("probe = nil collectgarbage() collectgarbage()", None)?;
+                    assert!(!probe.dropped.get());
+                    Ok(probe.value)
+                });
+            }
+        }
+
+        let lua = Lua::new();
+        let dropped = Rc::new(Cell::new(false));
+        lua.globals()
+            .set(
+                "probe",
+                Probe {
+                    value: 42,
+                    dropped: dropped.clone(),
+                },
+            )
+            .unwrap();
+        assert_eq!(lua.eval::<i64>("probe:collect()", None).unwrap(), 42);
+        assert!(!dropped.get());
+        lua.exec::<()>("collectgarbage()", None).unwrap();
+        assert!(dropped.get());
+    }
 }
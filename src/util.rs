@@ -1,14 +1,19 @@
 use std::mem;
 use std::ptr;
+use std::slice;
+use std::str;
+use std::string::String as StdString;
 use std::process;
 use std::sync::Arc;
+use std::thread;
 use std::ffi::CStr;
 use std::any::Any;
 use std::os::raw::{c_char, c_int, c_void};
 use std::panic::{catch_unwind, resume_unwind, UnwindSafe};
 
 use ffi;
-use error::{Error, Result};
+use error::{Error, ErrorValue, Result};
+use lua::{format_error, map_error_locations, LimitScope};
 
 macro_rules! cstr {
   ($s:expr) => (
@@ -147,6 +152,67 @@ where
     res
 }
 
+/// Reserves space on a Lua stack for the duration of a scope.
+///
+/// A guard makes sure, with a single call to `lua_checkstack`, that a sequence of operations can
+/// push a number of values without checking for stack space before each push. When the guard is
+/// dropped, values left above the height of the stack at its creation are removed, which cleans up
+/// after operations that returned early with an error.
+///
+/// This is mainly useful in combination with the raw bindings in the [`ffi`] module.
+///
+/// # Panics
+///
+/// Dropping the guard panics if values below the height of the stack at its creation have been
+/// popped, as this is a logic error in the guarded code.
+///
+/// [`ffi`]: ffi/index.html
+pub struct StackGuard {
+    state: *mut ffi::lua_State,
+    top: c_int,
+}
+
+impl StackGuard {
+    /// Reserves space for `n` additional values on the stack of `state`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack cannot grow by `n` values.
+    ///
+    /// # Safety
+    ///
+    /// `state` must be a valid Lua state, which must outlive the guard.
+    pub unsafe fn with_reserved(state: *mut ffi::lua_State, n: c_int) -> StackGuard {
+        check_stack(state, n);
+        StackGuard {
+            state,
+            top: ffi::lua_gettop(state),
+        }
+    }
+
+    /// Returns the height of the stack at the creation of the guard.
+    pub fn top(&self) -> c_int {
+        self.top
+    }
+}
+
+impl Drop for StackGuard {
+    fn drop(&mut self) {
+        unsafe {
+            let top = ffi::lua_gettop(self.state);
+            if top > self.top {
+                ffi::lua_settop(self.state, self.top);
+            } else if top < self.top && !thread::panicking() {
+                lua_panic!(
+                    self.state,
+                    "internal stack error: {} too many values popped",
+                    self.top - top
+                );
+            }
+        }
+    }
+}
+
 // Protected version of lua_gettable, uses 3 stack spaces, does not call checkstack.
 pub unsafe fn pgettable(state: *mut ffi::lua_State, index: c_int) -> Result<c_int> {
     unsafe extern "C" fn gettable(state: *mut ffi::lua_State) -> c_int {
@@ -185,6 +251,58 @@ pub unsafe fn psettable(state: *mut ffi::lua_State, index: c_int) -> Result<()>
     Ok(())
 }
 
+// Protected version of lua_gettable for several keys, replaces the `nkeys` keys on the top of the
+// stack with their values. Uses 3 stack spaces, does not call checkstack.
+pub unsafe fn pgettable_multi(
+    state: *mut ffi::lua_State,
+    index: c_int,
+    nkeys: c_int,
+) -> Result<()> {
+    unsafe extern "C" fn gettable_multi(state: *mut ffi::lua_State) -> c_int {
+        let top = ffi::lua_gettop(state);
+        for i in 2..top + 1 {
+            ffi::lua_pushvalue(state, i);
+            ffi::lua_gettable(state, 1);
+            ffi::lua_replace(state, i);
+        }
+        top - 1
+    }
+
+    let table_index = ffi::lua_absindex(state, index);
+
+    ffi::lua_pushcfunction(state, gettable_multi);
+    ffi::lua_pushvalue(state, table_index);
+    ffi::lua_rotate(state, -(nkeys + 2), 2);
+
+    handle_error(state, pcall_with_traceback(state, nkeys + 1, nkeys))
+}
+
+// Protected version of lua_settable for several keys, pops `nkeys` keys followed by as many values
+// from the top of the stack. Uses 4 stack spaces, does not call checkstack.
+pub unsafe fn psettable_multi(
+    state: *mut ffi::lua_State,
+    index: c_int,
+    nkeys: c_int,
+) -> Result<()> {
+    unsafe extern "C" fn settable_multi(state: *mut ffi::lua_State) -> c_int {
+        let nkeys = (ffi::lua_gettop(state) - 1) / 2;
+        for i in 2..nkeys + 2 {
+            ffi::lua_pushvalue(state, i);
+            ffi::lua_pushvalue(state, i + nkeys);
+            ffi::lua_settable(state, 1);
+        }
+        0
+    }
+
+    let table_index = ffi::lua_absindex(state, index);
+
+    ffi::lua_pushcfunction(state, settable_multi);
+    ffi::lua_pushvalue(state, table_index);
+    ffi::lua_rotate(state, -(2 * nkeys + 2), 2);
+
+    handle_error(state, pcall_with_traceback(state, 2 * nkeys + 1, 0))
+}
+
 // Protected version of luaL_len, uses 2 stack spaces, does not call checkstack.
 pub unsafe fn plen(state: *mut ffi::lua_State, index: c_int) -> Result<ffi::lua_Integer> {
     unsafe extern "C" fn len(state: *mut ffi::lua_State) -> c_int {
@@ -203,26 +321,70 @@ pub unsafe fn plen(state: *mut ffi::lua_State, index: c_int) -> Result<ffi::lua_
     Ok(len)
 }
 
-// Protected version of lua_geti, uses 3 stack spaces, does not call checkstack.
-pub unsafe fn pgeti(
+// Protected version of lua_geti for consecutive indices, pushes `t[i]`, `t[i + 1]`, ... until
+// `count` values have been pushed or a nil value is found, which is not pushed. Returns the number
+// of values pushed. Uses `count + 4` stack spaces, does not call checkstack.
+pub unsafe fn pgeti_batch(
     state: *mut ffi::lua_State,
     index: c_int,
     i: ffi::lua_Integer,
+    count: c_int,
 ) -> Result<c_int> {
-    unsafe extern "C" fn geti(state: *mut ffi::lua_State) -> c_int {
-        let i = ffi::lua_tointeger(state, -1);
-        ffi::lua_geti(state, -2, i);
-        1
+    unsafe extern "C" fn geti_batch(state: *mut ffi::lua_State) -> c_int {
+        let i = ffi::lua_tointeger(state, 2);
+        let count = ffi::lua_tointeger(state, 3) as c_int;
+        ffi::luaL_checkstack(state, count, ptr::null());
+        for n in 0..count {
+            if ffi::lua_geti(state, 1, i + n as ffi::lua_Integer) == ffi::LUA_TNIL {
+                ffi::lua_pop(state, 1);
+                return n;
+            }
+        }
+        count
     }
 
     let table_index = ffi::lua_absindex(state, index);
+    let top = ffi::lua_gettop(state);
 
-    ffi::lua_pushcfunction(state, geti);
+    ffi::lua_pushcfunction(state, geti_batch);
     ffi::lua_pushvalue(state, table_index);
     ffi::lua_pushinteger(state, i);
+    ffi::lua_pushinteger(state, count as ffi::lua_Integer);
+
+    handle_error(state, pcall_with_traceback(state, 3, ffi::LUA_MULTRET))?;
+    Ok(ffi::lua_gettop(state) - top)
+}
+
+// Protected version of lua_compare with LUA_OPEQ, compares the two values on top of the stack and
+// pops them. Uses 3 stack spaces, does not call checkstack.
+pub unsafe fn pequal(state: *mut ffi::lua_State) -> Result<bool> {
+    unsafe extern "C" fn equal(state: *mut ffi::lua_State) -> c_int {
+        let eq = ffi::lua_compare(state, -2, -1, ffi::LUA_OPEQ);
+        ffi::lua_pushboolean(state, eq);
+        1
+    }
+
+    ffi::lua_pushcfunction(state, equal);
+    ffi::lua_insert(state, -3);
 
     handle_error(state, pcall_with_traceback(state, 2, 1))?;
-    Ok(ffi::lua_type(state, -1))
+    let eq = ffi::lua_toboolean(state, -1) != 0;
+    ffi::lua_pop(state, 1);
+    Ok(eq)
+}
+
+// Protected version of luaL_tolstring, replaces the value on top of the stack with its string
+// representation. Uses 2 stack spaces, does not call checkstack.
+pub unsafe fn ptostring(state: *mut ffi::lua_State) -> Result<()> {
+    unsafe extern "C" fn tostring(state: *mut ffi::lua_State) -> c_int {
+        ffi::luaL_tolstring(state, -1, ptr::null_mut());
+        1
+    }
+
+    ffi::lua_pushcfunction(state, tostring);
+    ffi::lua_insert(state, -2);
+
+    handle_error(state, pcall_with_traceback(state, 1, 1))
 }
 
 // Protected version of lua_next, uses 3 stack spaces, does not call checkstack.
@@ -257,7 +419,13 @@ pub unsafe fn pnext(state: *mut ffi::lua_State, index: c_int) -> Result<c_int> {
 // stack and continues the panic.  If the error on the top of the stack is
 // actually a WrappedError, just returns it.  Otherwise, interprets the error as
 // the appropriate lua error.
+//
+// The locations in the error are rewritten with the source maps of the state.
 pub unsafe fn handle_error(state: *mut ffi::lua_State, err: c_int) -> Result<()> {
+    pop_error(state, err).map_err(|err| map_error_locations(state, err))
+}
+
+unsafe fn pop_error(state: *mut ffi::lua_State, err: c_int) -> Result<()> {
     if err == ffi::LUA_OK || err == ffi::LUA_YIELD {
         Ok(())
     } else if let Some(err) = pop_wrapped_error(state) {
@@ -271,10 +439,16 @@ pub unsafe fn handle_error(state: *mut ffi::lua_State, err: c_int) -> Result<()>
             lua_panic!(state, "internal error: panic was resumed twice")
         }
     } else {
+        if err == ffi::LUA_ERRRUN && !is_plain_error(state, -1) {
+            let err = runtime_error_value(state, -1, false);
+            ffi::lua_pop(state, 1);
+            return Err(err);
+        }
+
         let err_string = if let Some(s) = ffi::lua_tolstring(state, -1, ptr::null_mut()).as_ref() {
             CStr::from_ptr(s)
                 .to_str()
-                .unwrap_or_else(|_| "<unprintable error>")
+                .unwrap_or("<unprintable error>")
                 .to_owned()
         } else {
             "<unprintable error>".to_owned()
@@ -298,12 +472,8 @@ pub unsafe fn handle_error(state: *mut ffi::lua_State, err: c_int) -> Result<()>
                 // between that and "ordinary" runtime errors, we handle them the same way.
                 Error::RuntimeError(err_string)
             }
-            ffi::LUA_ERRMEM => {
-                // This should be impossible, as we set the lua allocator to one that aborts
-                // instead of failing.
-                eprintln!("Lua memory error, aborting!");
-                process::abort()
-            }
+            // The allocator aborts instead of failing, unless the memory limit is exceeded.
+            ffi::LUA_ERRMEM => Error::MemoryError(err_string),
             ffi::LUA_ERRGCMM => {
                 // This should be impossible, since we wrap setmetatable to protect __gc
                 // metamethods, but if we do end up here then the same logic as setmetatable
@@ -333,6 +503,7 @@ pub unsafe fn get_userdata<T>(state: *mut ffi::lua_State, index: c_int) -> *mut
 }
 
 pub unsafe extern "C" fn userdata_destructor<T>(state: *mut ffi::lua_State) -> c_int {
+    let limits = LimitScope::new(state, false);
     match catch_unwind(|| {
         *(ffi::lua_touserdata(state, 1) as *mut Option<T>) = None;
         0
@@ -340,6 +511,7 @@ pub unsafe extern "C" fn userdata_destructor<T>(state: *mut ffi::lua_State) -> c
         Ok(r) => r,
         Err(p) => {
             push_wrapped_panic(state, p);
+            drop(limits);
             ffi::lua_error(state)
         }
     }
@@ -353,14 +525,45 @@ pub unsafe fn callback_error<R, F>(state: *mut ffi::lua_State, f: F) -> R
 where
     F: FnOnce() -> Result<R> + UnwindSafe,
 {
+    callback_error_catching(state, || false, f)
+}
+
+// Like callback_error, except that if `catch_panic` returns true after the given function panics,
+// the panic is raised as a `CallbackPanic` error instead, which lua is allowed to catch.
+//
+// The memory and table entry limits of the state are lifted while the given function runs, so that
+// exceeding them cannot raise an error through its frames.
+pub unsafe fn callback_error_catching<R, C, F>(
+    state: *mut ffi::lua_State,
+    catch_panic: C,
+    f: F,
+) -> R
+where
+    C: FnOnce() -> bool,
+    F: FnOnce() -> Result<R> + UnwindSafe,
+{
+    let limits = LimitScope::new(state, false);
     match catch_unwind(f) {
         Ok(Ok(r)) => r,
         Ok(Err(err)) => {
             push_wrapped_error(state, err);
+            drop(limits);
             ffi::lua_error(state)
         }
         Err(p) => {
-            push_wrapped_panic(state, p);
+            if catch_panic() {
+                let message = if let Some(s) = p.downcast_ref::<&str>() {
+                    (*s).to_owned()
+                } else if let Some(s) = p.downcast_ref::<StdString>() {
+                    s.clone()
+                } else {
+                    "<non-string panic payload>".to_owned()
+                };
+                push_wrapped_error(state, Error::CallbackPanic(message));
+            } else {
+                push_wrapped_panic(state, p);
+            }
+            drop(limits);
             ffi::lua_error(state)
         }
     }
@@ -375,6 +578,7 @@ pub unsafe fn pcall_with_traceback(
     nresults: c_int,
 ) -> c_int {
     unsafe extern "C" fn message_handler(state: *mut ffi::lua_State) -> c_int {
+        let _limits = LimitScope::new(state, false);
         if let Some(error) = pop_wrapped_error(state) {
             ffi::luaL_traceback(state, state, ptr::null(), 0);
             let traceback = CStr::from_ptr(ffi::lua_tolstring(state, -1, ptr::null_mut()))
@@ -389,13 +593,12 @@ pub unsafe fn pcall_with_traceback(
             );
             ffi::lua_remove(state, -2);
         } else if !is_wrapped_panic(state, 1) {
-            let s = ffi::lua_tolstring(state, 1, ptr::null_mut());
-            let s = if s.is_null() {
-                cstr!("<unprintable Rust panic>")
+            if is_plain_error(state, 1) {
+                let s = ffi::lua_tolstring(state, 1, ptr::null_mut());
+                ffi::luaL_traceback(state, state, s, 0);
             } else {
-                s
-            };
-            ffi::luaL_traceback(state, state, s, 0);
+                push_wrapped_error(state, runtime_error_value(state, 1, true));
+            }
             ffi::lua_remove(state, -2);
         }
         1
@@ -404,23 +607,37 @@ pub unsafe fn pcall_with_traceback(
     let msgh_position = ffi::lua_gettop(state) - nargs;
     ffi::lua_pushcfunction(state, message_handler);
     ffi::lua_insert(state, msgh_position);
-    let ret = ffi::lua_pcall(state, nargs, nresults, msgh_position);
+    let ret = limited_pcall(state, nargs, nresults, msgh_position);
     ffi::lua_remove(state, msgh_position);
     ret
 }
 
+// ffi::lua_pcall with the memory and table entry limits of the state enforced, see `LimitScope`.
+pub unsafe fn limited_pcall(
+    state: *mut ffi::lua_State,
+    nargs: c_int,
+    nresults: c_int,
+    msgh: c_int,
+) -> c_int {
+    let _limits = LimitScope::new(state, true);
+    ffi::lua_pcall(state, nargs, nresults, msgh)
+}
+
 pub unsafe fn resume_with_traceback(
     state: *mut ffi::lua_State,
     from: *mut ffi::lua_State,
     nargs: c_int,
 ) -> c_int {
-    let res = ffi::lua_resume(state, from, nargs);
+    let res = {
+        let _limits = LimitScope::new(state, true);
+        ffi::lua_resume(state, from, nargs)
+    };
     if res != ffi::LUA_OK && res != ffi::LUA_YIELD {
         if let Some(error) = pop_wrapped_error(state) {
             ffi::luaL_traceback(state, state, ptr::null(), 0);
             let traceback = CStr::from_ptr(ffi::lua_tolstring(state, -1, ptr::null_mut()))
                 .to_str()
-                .unwrap_or_else(|_| "<could not capture traceback>")
+                .unwrap_or("<could not capture traceback>")
                 .to_owned();
             push_wrapped_error(
                 state,
@@ -431,11 +648,11 @@ pub unsafe fn resume_with_traceback(
             );
             ffi::lua_remove(state, -2);
         } else if !is_wrapped_panic(state, 1) {
-            let s = ffi::lua_tolstring(state, 1, ptr::null_mut());
-            if !s.is_null() {
+            if is_plain_error(state, 1) {
+                let s = ffi::lua_tolstring(state, 1, ptr::null_mut());
                 ffi::luaL_traceback(state, state, s, 0);
             } else {
-                ffi::luaL_traceback(state, state, cstr!("<unprintable lua error>"), 0);
+                push_wrapped_error(state, runtime_error_value(state, 1, true));
             }
             ffi::lua_remove(state, -2);
         }
@@ -443,16 +660,160 @@ pub unsafe fn resume_with_traceback(
     res
 }
 
+// Returns true if the error value at the given index is a number or a UTF-8 string, which is
+// reported as a `RuntimeError` without losing information.
+pub unsafe fn is_plain_error(state: *mut ffi::lua_State, index: c_int) -> bool {
+    match ffi::lua_type(state, index) {
+        ffi::LUA_TNUMBER => true,
+        ffi::LUA_TSTRING => {
+            let mut size = 0;
+            let data = ffi::lua_tolstring(state, index, &mut size);
+            str::from_utf8(slice::from_raw_parts(data as *const u8, size)).is_ok()
+        }
+        _ => false,
+    }
+}
+
+// Creates a `RuntimeErrorValue` holding a copy of the error value at the given index, with a
+// traceback of the given state in its message if `traceback` is true. Uses 3 stack spaces, does
+// not call checkstack.
+pub unsafe fn runtime_error_value(
+    state: *mut ffi::lua_State,
+    index: c_int,
+    traceback: bool,
+) -> Error {
+    let value = copy_error_value(state, ffi::lua_absindex(state, index), &mut Vec::new());
+    let mut message = match value {
+        ErrorValue::String(ref bytes) => StdString::from_utf8_lossy(bytes).into_owned(),
+        ErrorValue::Nil => "(error object is a nil value)".to_owned(),
+        ErrorValue::Boolean(_) => "(error object is a boolean value)".to_owned(),
+        ErrorValue::Table(_) => "(error object is a table value)".to_owned(),
+        ErrorValue::Other(type_name) => format!("(error object is a {} value)", type_name),
+        ErrorValue::Integer(_) | ErrorValue::Number(_) => unreachable!(),
+    };
+    if traceback {
+        ffi::luaL_traceback(state, state, cstr!(""), 0);
+        message.push_str(&CStr::from_ptr(ffi::lua_tolstring(state, -1, ptr::null_mut()))
+            .to_string_lossy());
+        ffi::lua_pop(state, 1);
+    }
+
+    Error::RuntimeErrorValue {
+        message,
+        value,
+    }
+}
+
+// Maximum nesting depth of tables copied by `copy_error_value`.
+const ERROR_VALUE_MAX_DEPTH: usize = 16;
+
+// Copies the value at the given (absolute) index without invoking any metamethods. `tables` holds
+// the tables currently being copied, in order to detect cycles. Uses 2 stack spaces per level of
+// nesting, and stops copying tables if there is no more stack space available.
+unsafe fn copy_error_value(
+    state: *mut ffi::lua_State,
+    index: c_int,
+    tables: &mut Vec<*const c_void>,
+) -> ErrorValue {
+    match ffi::lua_type(state, index) {
+        ffi::LUA_TNIL => ErrorValue::Nil,
+        ffi::LUA_TBOOLEAN => ErrorValue::Boolean(ffi::lua_toboolean(state, index) != 0),
+        ffi::LUA_TNUMBER => if ffi::lua_isinteger(state, index) != 0 {
+            ErrorValue::Integer(ffi::lua_tointeger(state, index))
+        } else {
+            ErrorValue::Number(ffi::lua_tonumber(state, index))
+        },
+        ffi::LUA_TSTRING => {
+            let mut size = 0;
+            let data = ffi::lua_tolstring(state, index, &mut size);
+            ErrorValue::String(slice::from_raw_parts(data as *const u8, size).to_vec())
+        }
+        ffi::LUA_TTABLE => {
+            let table = ffi::lua_topointer(state, index);
+            if tables.len() >= ERROR_VALUE_MAX_DEPTH || tables.contains(&table)
+                || ffi::lua_checkstack(state, 2) == 0
+            {
+                return ErrorValue::Other("table");
+            }
+
+            tables.push(table);
+            let mut entries = Vec::new();
+            ffi::lua_pushnil(state);
+            while ffi::lua_next(state, index) != 0 {
+                let top = ffi::lua_gettop(state);
+                let key = copy_error_value(state, top - 1, tables);
+                let value = copy_error_value(state, top, tables);
+                entries.push((key, value));
+                ffi::lua_pop(state, 1);
+            }
+            tables.pop();
+            ErrorValue::Table(entries)
+        }
+        ffi::LUA_TFUNCTION => ErrorValue::Other("function"),
+        ffi::LUA_TTHREAD => ErrorValue::Other("thread"),
+        ffi::LUA_TLIGHTUSERDATA => ErrorValue::Other("lightuserdata"),
+        _ => ErrorValue::Other("userdata"),
+    }
+}
+
+// Pushes a copy of the given error value onto the stack. Values which could not be copied are
+// pushed as nil.
+unsafe fn push_error_value(state: *mut ffi::lua_State, value: &ErrorValue) {
+    ffi::luaL_checkstack(state, 3, ptr::null());
+
+    match *value {
+        ErrorValue::Boolean(b) => ffi::lua_pushboolean(state, b as c_int),
+        ErrorValue::Integer(i) => ffi::lua_pushinteger(state, i),
+        ErrorValue::Number(n) => ffi::lua_pushnumber(state, n),
+        ErrorValue::String(ref bytes) => {
+            ffi::lua_pushlstring(state, bytes.as_ptr() as *const c_char, bytes.len());
+        }
+        ErrorValue::Table(ref entries) => {
+            ffi::lua_createtable(state, 0, entries.len() as c_int);
+            for (key, value) in entries {
+                push_error_value(state, key);
+                push_error_value(state, value);
+                if ffi::lua_isnil(state, -2) != 0 {
+                    ffi::lua_pop(state, 2);
+                } else {
+                    ffi::lua_rawset(state, -3);
+                }
+            }
+        }
+        ErrorValue::Nil | ErrorValue::Other(_) => ffi::lua_pushnil(state),
+    }
+}
+
+// Called by `pcall` and `xpcall` when they catch a memory error. The functions the error unwound
+// leave their values in the stack above the top, where the registers of the calling Lua function
+// still reach them until they are overwritten, keeping them from being collected when the memory
+// is needed again. Lua functions have at most 255 registers.
+unsafe fn clear_stale_registers(state: *mut ffi::lua_State) {
+    let top = ffi::lua_gettop(state);
+    if ffi::lua_checkstack(state, 256) != 0 {
+        for _ in 0..256 {
+            ffi::lua_pushnil(state);
+        }
+        ffi::lua_settop(state, top);
+    }
+}
+
 // A variant of pcall that does not allow lua to catch panic errors from callback_error
 pub unsafe extern "C" fn safe_pcall(state: *mut ffi::lua_State) -> c_int {
     let top = ffi::lua_gettop(state);
     if top == 0 {
         push_string(state, "not enough arguments to pcall");
         ffi::lua_error(state);
-    } else if ffi::lua_pcall(state, top - 1, ffi::LUA_MULTRET, 0) != ffi::LUA_OK {
+    }
+
+    let res = ffi::lua_pcall(state, top - 1, ffi::LUA_MULTRET, 0);
+    if res != ffi::LUA_OK {
         if is_wrapped_panic(state, -1) {
             ffi::lua_error(state);
         }
+        if res == ffi::LUA_ERRMEM {
+            clear_stale_registers(state);
+        }
         ffi::lua_pushboolean(state, 0);
         ffi::lua_insert(state, -2);
         2
@@ -463,6 +824,20 @@ pub unsafe extern "C" fn safe_pcall(state: *mut ffi::lua_State) -> c_int {
     }
 }
 
+// A variant of coroutine.resume that does not allow lua to catch panic errors from
+// callback_error, the original coroutine.resume is expected as the first upvalue.
+pub unsafe extern "C" fn safe_resume(state: *mut ffi::lua_State) -> c_int {
+    ffi::luaL_checkstack(state, 1, ptr::null());
+    ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
+    ffi::lua_insert(state, 1);
+    ffi::lua_call(state, ffi::lua_gettop(state) - 1, ffi::LUA_MULTRET);
+    if ffi::lua_toboolean(state, 1) == 0 && is_wrapped_panic(state, 2) {
+        ffi::lua_settop(state, 2);
+        ffi::lua_error(state);
+    }
+    ffi::lua_gettop(state)
+}
+
 // A variant of xpcall that does not allow lua to catch panic errors from callback_error
 pub unsafe extern "C" fn safe_xpcall(state: *mut ffi::lua_State) -> c_int {
     unsafe extern "C" fn xpcall_msgh(state: *mut ffi::lua_State) -> c_int {
@@ -492,6 +867,9 @@ pub unsafe extern "C" fn safe_xpcall(state: *mut ffi::lua_State) -> c_int {
         if is_wrapped_panic(state, -1) {
             ffi::lua_error(state);
         }
+        if res == ffi::LUA_ERRMEM {
+            clear_stale_registers(state);
+        }
         ffi::lua_pushboolean(state, 0);
         ffi::lua_insert(state, -2);
         2
@@ -509,11 +887,24 @@ pub unsafe extern "C" fn safe_setmetatable(state: *mut ffi::lua_State) -> c_int
         ffi::lua_error(state);
     }
 
+    // Like the original, refuse to replace a metatable protected by a `__metatable` field.
+    if ffi::lua_istable(state, 1) == 1 && ffi::lua_getmetatable(state, 1) != 0 {
+        push_string(state, "__metatable");
+        let protected = ffi::lua_rawget(state, -2) != ffi::LUA_TNIL;
+        ffi::lua_pop(state, 2);
+        if protected {
+            push_string(state, "cannot change a protected metatable");
+            ffi::lua_error(state);
+        }
+    }
+
     // Wrapping the __gc method in setmetatable ONLY works because Lua 5.3 only honors the __gc
     // method when it exists upon calling setmetatable, and ignores it if it is set later.
     push_string(state, "__gc");
     if ffi::lua_istable(state, -2) == 1 && ffi::lua_rawget(state, -2) == ffi::LUA_TFUNCTION {
         unsafe extern "C" fn safe_gc(state: *mut ffi::lua_State) -> c_int {
+            // Finalizers are not limited, since exceeding a limit would abort.
+            let _limits = LimitScope::new(state, false);
             ffi::lua_pushvalue(state, ffi::lua_upvalueindex(1));
             ffi::lua_insert(state, 1);
             if ffi::lua_pcall(state, 1, 0, 0) != ffi::LUA_OK {
@@ -548,14 +939,14 @@ pub unsafe fn main_state(state: *mut ffi::lua_State) -> *mut ffi::lua_State {
 }
 
 pub struct WrappedError(pub Error);
-pub struct WrappedPanic(pub Option<Box<Any + Send>>);
+pub struct WrappedPanic(pub Option<Box<dyn Any + Send>>);
 
 // Pushes a WrappedError::Error to the top of the stack
 pub unsafe fn push_wrapped_error(state: *mut ffi::lua_State, err: Error) {
     unsafe extern "C" fn error_tostring(state: *mut ffi::lua_State) -> c_int {
         callback_error(state, || if is_wrapped_error(state, -1) {
             let error = get_userdata::<WrappedError>(state, -1);
-            push_string(state, &(*error).0.to_string());
+            push_string(state, &format_error(state, &(*error).0));
             ffi::lua_remove(state, -2);
 
             Ok(1)
@@ -564,6 +955,62 @@ pub unsafe fn push_wrapped_error(state: *mut ffi::lua_State, err: Error) {
         })
     }
 
+    // Gives scripts access to the error's message, its kind and the fields of its variant, so that
+    // they can handle errors raised by Rust functions without parsing the message.
+    unsafe extern "C" fn error_index(state: *mut ffi::lua_State) -> c_int {
+        callback_error(state, || {
+            if !is_wrapped_error(state, 1) {
+                panic!("internal error: userdata mismatch in Error metamethod");
+            }
+            let error = &(*get_userdata::<WrappedError>(state, 1)).0;
+
+            ffi::luaL_checkstack(state, 2, ptr::null());
+            let key = if ffi::lua_type(state, 2) == ffi::LUA_TSTRING {
+                let mut size = 0;
+                let data = ffi::lua_tolstring(state, 2, &mut size);
+                slice::from_raw_parts(data as *const u8, size)
+            } else {
+                &[]
+            };
+
+            match (key, error) {
+                (b"message", _) => push_string(state, &format_error(state, error)),
+                (b"kind", _) => push_string(state, error_kind(error)),
+                (b"value", Error::RuntimeErrorValue { value, .. }) => {
+                    push_error_value(state, value)
+                }
+                (b"incomplete_input", &Error::SyntaxError { incomplete_input, .. }) => {
+                    ffi::lua_pushboolean(state, incomplete_input as c_int)
+                }
+                (b"from", &Error::ToLuaConversionError { from, .. })
+                | (b"from", &Error::FromLuaConversionError { from, .. }) => {
+                    push_string(state, from)
+                }
+                (b"to", &Error::ToLuaConversionError { to, .. })
+                | (b"to", &Error::FromLuaConversionError { to, .. }) => push_string(state, to),
+                (b"traceback", Error::CallbackError { traceback, .. }) => {
+                    push_string(state, traceback)
+                }
+                (b"function_name", Error::BadArgument { function_name: Some(name), .. }) => {
+                    push_string(state, name)
+                }
+                (b"arg_index", &Error::BadArgument { arg_index, .. }) => {
+                    ffi::lua_pushinteger(state, arg_index as ffi::lua_Integer)
+                }
+                (b"context", Error::WithContext { context, .. }) => {
+                    push_string(state, context)
+                }
+                (b"cause", Error::CallbackError { cause, .. })
+                | (b"cause", Error::BadArgument { cause, .. })
+                | (b"cause", Error::WithContext { cause, .. }) => {
+                    push_wrapped_error(state, (**cause).clone())
+                }
+                _ => ffi::lua_pushnil(state),
+            }
+            Ok(1)
+        })
+    }
+
     ffi::luaL_checkstack(state, 2, ptr::null());
 
     push_userdata(state, WrappedError(err));
@@ -589,6 +1036,10 @@ pub unsafe fn push_wrapped_error(state: *mut ffi::lua_State, err: Error) {
         ffi::lua_pushcfunction(state, error_tostring);
         ffi::lua_settable(state, -3);
 
+        push_string(state, "__index");
+        ffi::lua_pushcfunction(state, error_index);
+        ffi::lua_settable(state, -3);
+
         push_string(state, "__metatable");
         ffi::lua_pushboolean(state, 0);
         ffi::lua_settable(state, -3);
@@ -600,7 +1051,7 @@ pub unsafe fn push_wrapped_error(state: *mut ffi::lua_State, err: Error) {
 }
 
 // Pushes a WrappedError::Panic to the top of the stack
-pub unsafe fn push_wrapped_panic(state: *mut ffi::lua_State, panic: Box<Any + Send>) {
+pub unsafe fn push_wrapped_panic(state: *mut ffi::lua_State, panic: Box<dyn Any + Send>) {
     ffi::luaL_checkstack(state, 2, ptr::null());
 
     push_userdata(state, WrappedPanic(Some(panic)));
@@ -645,6 +1096,29 @@ pub unsafe fn pop_wrapped_error(state: *mut ffi::lua_State) -> Option<Error> {
     }
 }
 
+// Returns the name of the kind of an error, as seen by scripts.
+pub fn error_kind(error: &Error) -> &'static str {
+    match *error {
+        Error::SyntaxError { .. } => "syntax",
+        Error::RuntimeError(_) | Error::RuntimeErrorValue { .. } => "runtime",
+        Error::ToLuaConversionError { .. } => "to_lua_conversion",
+        Error::FromLuaConversionError { .. } => "from_lua_conversion",
+        Error::SerializeError(_) => "serialize",
+        Error::DeserializeError(_) => "deserialize",
+        Error::CoroutineInactive => "coroutine_inactive",
+        Error::UserDataTypeMismatch => "userdata_type_mismatch",
+        Error::UserDataBorrowError => "userdata_borrow",
+        Error::UserDataBorrowMutError => "userdata_borrow_mut",
+        Error::MismatchedRegistryKey => "mismatched_registry_key",
+        Error::CallbackError { .. } => "callback",
+        Error::CallbackPanic(_) => "callback_panic",
+        Error::MemoryError(_) => "memory",
+        Error::ExternalError(_) => "external",
+        Error::BadArgument { .. } => "bad_argument",
+        Error::WithContext { .. } => "context",
+    }
+}
+
 // Checks if the value at the given index is a WrappedError
 pub unsafe fn is_wrapped_error(state: *mut ffi::lua_State, index: c_int) -> bool {
     assert_ne!(
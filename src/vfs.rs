@@ -0,0 +1,1320 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::result::Result as StdResult;
+use std::string::String as StdString;
+use std::sync::Arc;
+
+use error::{Error, Result};
+use lua::{Function, Lua, MultiValue, Value};
+use multi::{LuaConvention, Variadic};
+use string::String;
+use table::Table;
+use types::Integer;
+use userdata::{AnyUserData, MetaMethod, UserData, UserDataMethods};
+
+/// A file opened through a [`Vfs`].
+///
+/// [`Vfs`]: trait.Vfs.html
+pub trait VfsFile: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> VfsFile for T {}
+
+// A stream given to scripts by the host with `Lua::create_file_handle`.
+trait HandleStream: Read + Write {}
+
+impl<T: Read + Write> HandleStream for T {}
+
+/// How a file is opened through a [`Vfs`], following the modes of `io.open`.
+///
+/// Mode `"r"` opens a file for reading. Mode `"w"` opens it for writing, creating it if it does
+/// not exist and emptying it otherwise, and mode `"a"` opens it for appending, creating it if it
+/// does not exist. A `+` in the mode allows both reading and writing.
+///
+/// [`Vfs`]: trait.Vfs.html
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct OpenMode {
+    /// Whether the file can be read.
+    pub read: bool,
+    /// Whether the file can be written.
+    pub write: bool,
+    /// Whether everything written goes to the end of the file. The file is also writable.
+    ///
+    /// rlua moves to the end of the file before every write, so implementations of [`Vfs`] do not
+    /// need to handle this themselves.
+    ///
+    /// [`Vfs`]: trait.Vfs.html
+    pub append: bool,
+    /// Whether the file is emptied when it is opened.
+    pub truncate: bool,
+    /// Whether the file is created if it does not exist.
+    pub create: bool,
+}
+
+impl OpenMode {
+    // Parses the mode argument of `io.open`.
+    fn parse(mode: &str) -> Option<OpenMode> {
+        let mode = mode.trim_end_matches('b');
+        let (kind, update) = match mode.strip_suffix('+') {
+            Some(kind) => (kind, true),
+            None => (mode, false),
+        };
+        match kind {
+            "r" => Some(OpenMode {
+                read: true,
+                write: update,
+                ..OpenMode::default()
+            }),
+            "w" => Some(OpenMode {
+                read: update,
+                write: true,
+                truncate: true,
+                create: true,
+                ..OpenMode::default()
+            }),
+            "a" => Some(OpenMode {
+                read: update,
+                write: true,
+                append: true,
+                create: true,
+                ..OpenMode::default()
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A file system seen by scripts, installed with [`Lua::set_vfs`].
+///
+/// Paths given by scripts are resolved before they are passed to a `Vfs`: they are relative to
+/// the root of the file system, use `/` as separator, and contain no `.`, `..` or empty
+/// components, so that the root itself is the empty path. Scripts cannot name a path outside of
+/// the root, whether they use absolute or relative paths.
+///
+/// [`Lua::set_vfs`]: struct.Lua.html#method.set_vfs
+pub trait Vfs {
+    /// Opens the file at `path`.
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Returns the names of the entries of the directory at `path`.
+    fn list(&self, path: &str) -> io::Result<Vec<StdString>>;
+
+    /// Returns the contents of the file at `path`.
+    ///
+    /// By default, the file is opened for reading and read to the end.
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mode = OpenMode {
+            read: true,
+            ..OpenMode::default()
+        };
+        let mut data = Vec::new();
+        self.open(path, mode)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Replaces the contents of the file at `path`, creating it if it does not exist.
+    ///
+    /// By default, the file is opened for writing and `data` is written to it.
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let mode = OpenMode {
+            write: true,
+            truncate: true,
+            create: true,
+            ..OpenMode::default()
+        };
+        let mut file = self.open(path, mode)?;
+        file.write_all(data)?;
+        file.flush()
+    }
+
+    /// Removes the file at `path`.
+    ///
+    /// By default, files cannot be removed.
+    fn remove(&self, path: &str) -> io::Result<()> {
+        let _ = path;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "files cannot be removed",
+        ))
+    }
+
+    /// Moves the file at `from` to `to`, replacing any file at `to`.
+    ///
+    /// By default, files cannot be moved.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let _ = (from, to);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "files cannot be renamed",
+        ))
+    }
+}
+
+/// A file system held in memory, which the host can fill and inspect.
+///
+/// Clones of a `MemoryVfs` share the same files. Directories are not stored, a directory exists
+/// as long as there are files in it. Data written by scripts is stored when the file is flushed
+/// or closed.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryVfs(Rc<RefCell<BTreeMap<StdString, Vec<u8>>>>);
+
+impl MemoryVfs {
+    /// Creates an empty file system.
+    pub fn new() -> MemoryVfs {
+        MemoryVfs::default()
+    }
+
+    /// Creates or replaces the file at `path`, given in the form passed to [`Vfs`] methods, such
+    /// as `lib/module.lua`.
+    ///
+    /// [`Vfs`]: trait.Vfs.html
+    pub fn insert(&self, path: &str, data: Vec<u8>) {
+        self.0.borrow_mut().insert(path.to_owned(), data);
+    }
+
+    /// Returns the contents of the file at `path`, given in the form passed to [`Vfs`] methods.
+    ///
+    /// [`Vfs`]: trait.Vfs.html
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.0.borrow().get(path).cloned()
+    }
+
+    // Returns whether there are files in the directory at `path`.
+    fn is_directory(&self, path: &str) -> bool {
+        let prefix = directory_prefix(path);
+        let files = self.0.borrow();
+        let is_directory = files
+            .range(prefix.clone()..)
+            .next()
+            .is_some_and(|(name, _)| name.starts_with(&prefix));
+        is_directory
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>> {
+        if path.is_empty() || self.is_directory(path) {
+            return Err(io::Error::other("is a directory"));
+        }
+        let data = match self.get(path) {
+            Some(_) if mode.truncate => Vec::new(),
+            Some(data) => data,
+            None if mode.create => {
+                self.insert(path, Vec::new());
+                Vec::new()
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no such file or directory",
+                ))
+            }
+        };
+        Ok(Box::new(MemoryFile {
+            files: self.clone(),
+            path: path.to_owned(),
+            data: Cursor::new(data),
+            write: mode.write || mode.append,
+        }))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<StdString>> {
+        let prefix = directory_prefix(path);
+        let files = self.0.borrow();
+        let mut names: Vec<StdString> = Vec::new();
+        for name in files.range(prefix.clone()..).map(|(name, _)| name) {
+            let entry = match name.strip_prefix(&prefix) {
+                Some(rest) => rest.split('/').next().unwrap_or(rest),
+                None => break,
+            };
+            if names.last().map(|last| last.as_str()) != Some(entry) {
+                names.push(entry.to_owned());
+            }
+        }
+        if names.is_empty() && !path.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no such file or directory",
+            ));
+        }
+        Ok(names)
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.get(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file or directory"))
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.insert(path, data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        match self.0.borrow_mut().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no such file or directory",
+            )),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.0.borrow_mut();
+        match files.remove(from) {
+            Some(data) => {
+                files.insert(to.to_owned(), data);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no such file or directory",
+            )),
+        }
+    }
+}
+
+// A file of a `MemoryVfs`, which stores its data back when it is flushed or dropped.
+struct MemoryFile {
+    files: MemoryVfs,
+    path: StdString,
+    data: Cursor<Vec<u8>>,
+    write: bool,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.write {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not open for writing",
+            ));
+        }
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.write {
+            self.files.insert(&self.path, self.data.get_ref().clone());
+        }
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// Returns the prefix of the paths of the files in the directory at `path`.
+fn directory_prefix(path: &str) -> StdString {
+    if path.is_empty() {
+        StdString::new()
+    } else {
+        format!("{}/", path)
+    }
+}
+
+/// A file system backed by a directory of the host.
+///
+/// Scripts cannot name files outside of the directory, but symbolic links inside of it are
+/// followed, so they should not point to files which scripts must not access.
+#[derive(Clone, Debug)]
+pub struct DirectoryVfs {
+    root: PathBuf,
+}
+
+impl DirectoryVfs {
+    /// Creates a file system rooted at the directory `root`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> DirectoryVfs {
+        DirectoryVfs { root: root.into() }
+    }
+}
+
+impl Vfs for DirectoryVfs {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>> {
+        let file = fs::OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write)
+            .append(mode.append)
+            .truncate(mode.truncate)
+            .create(mode.create)
+            .open(self.root.join(path))?;
+        if file.metadata()?.is_dir() {
+            return Err(io::Error::other("is a directory"));
+        }
+        Ok(Box::new(file))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<StdString>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(self.root.join(path))? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(self.root.join(path))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.root.join(from), self.root.join(to))
+    }
+}
+
+/// Wraps a [`Vfs`] so that scripts can only read from it.
+///
+/// [`Vfs`]: trait.Vfs.html
+#[derive(Clone, Debug)]
+pub struct ReadOnlyVfs<V>(pub V);
+
+impl<V: Vfs> Vfs for ReadOnlyVfs<V> {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>> {
+        if mode.write || mode.append || mode.truncate || mode.create {
+            return Err(read_only_error());
+        }
+        self.0.open(path, mode)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<StdString>> {
+        self.0.list(path)
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.0.read(path)
+    }
+
+    fn write(&self, _: &str, _: &[u8]) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn remove(&self, _: &str) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn rename(&self, _: &str, _: &str) -> io::Result<()> {
+        Err(read_only_error())
+    }
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "file system is read-only")
+}
+
+// Resolves the `.` and `..` components of a path given by a script, relative to the root of the
+// file system. Returns `None` if the path leads outside of the root.
+fn resolve_path(path: &str) -> Option<StdString> {
+    let mut components = Vec::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop()?;
+            }
+            component => components.push(component),
+        }
+    }
+    Some(components.join("/"))
+}
+
+// Resolves a path given by a script, failing with a message in the style of the `io` library.
+fn checked_path(path: &str) -> StdResult<StdString, StdString> {
+    resolve_path(path).ok_or_else(|| format!("{}: path is outside of the file system", path))
+}
+
+// Size of the reads made to fill the buffer of a file.
+const BUFFER_SIZE: usize = 4096;
+
+// Longest numeral read by the `n` format.
+const MAX_NUMERAL_LENGTH: usize = 200;
+
+// The stream behind a file of scripts.
+enum Stream {
+    File(Box<dyn VfsFile>),
+    // Cannot seek, so data read ahead is kept until it is returned.
+    Handle(Box<dyn HandleStream>),
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+// A file of scripts, shared by the userdata values referring to it.
+struct FileState {
+    // `None` once the file has been closed.
+    stream: Option<Stream>,
+    mode: OpenMode,
+    // Data read ahead from the stream, and the position of the next byte to return from it.
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl FileState {
+    fn check_open(&self) -> Result<()> {
+        match self.stream {
+            Some(_) => Ok(()),
+            None => Err(Error::RuntimeError(
+                "attempt to use a closed file".to_owned(),
+            )),
+        }
+    }
+
+    // Reads more data into the buffer if it has all been returned. Returns `false` at the end of
+    // the stream.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.position < self.buffer.len() {
+            return Ok(true);
+        }
+        if !self.mode.read {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not open for reading",
+            ));
+        }
+        self.buffer.resize(BUFFER_SIZE, 0);
+        self.position = 0;
+        let read = loop {
+            let result = match self.stream {
+                Some(Stream::File(ref mut file)) => file.read(&mut self.buffer),
+                Some(Stream::Handle(ref mut handle)) => handle.read(&mut self.buffer),
+                Some(Stream::Stdin) => io::stdin().read(&mut self.buffer),
+                _ => Ok(0),
+            };
+            match result {
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    self.buffer.clear();
+                    return Err(err);
+                }
+                Ok(read) => break read,
+            }
+        };
+        self.buffer.truncate(read);
+        Ok(read > 0)
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(if self.fill()? {
+            Some(self.buffer[self.position])
+        } else {
+            None
+        })
+    }
+
+    // Moves the stream back to the position of the next byte to return, before it is written to or
+    // moved.
+    fn discard_buffer(&mut self) -> io::Result<()> {
+        if let Some(Stream::Handle(_)) = self.stream {
+            return Ok(());
+        }
+        let unread = self.buffer.len() - self.position;
+        if unread > 0 {
+            if let Some(Stream::File(ref mut file)) = self.stream {
+                file.seek(SeekFrom::Current(-(unread as i64)))?;
+            }
+        }
+        self.buffer.clear();
+        self.position = 0;
+        Ok(())
+    }
+
+    fn read_line(&mut self, keep_newline: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let mut found_newline = false;
+        while self.fill()? {
+            let available = &self.buffer[self.position..];
+            match available.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    line.extend_from_slice(&available[..end]);
+                    self.position += end + 1;
+                    found_newline = true;
+                    break;
+                }
+                None => {
+                    line.extend_from_slice(available);
+                    self.position = self.buffer.len();
+                }
+            }
+        }
+        if found_newline && keep_newline {
+            line.push(b'\n');
+        }
+        Ok(if found_newline || !line.is_empty() {
+            Some(line)
+        } else {
+            None
+        })
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while self.fill()? {
+            data.extend_from_slice(&self.buffer[self.position..]);
+            self.position = self.buffer.len();
+        }
+        Ok(data)
+    }
+
+    fn read_count(&mut self, count: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut data = Vec::new();
+        while data.len() < count && self.fill()? {
+            let available = &self.buffer[self.position..];
+            let n = available.len().min(count - data.len());
+            data.extend_from_slice(&available[..n]);
+            self.position += n;
+        }
+        Ok(if !data.is_empty() || (count == 0 && self.fill()?) {
+            Some(data)
+        } else {
+            None
+        })
+    }
+
+    // Reads the characters which can be part of a numeral, after skipping whitespace.
+    fn read_numeral(&mut self) -> io::Result<Vec<u8>> {
+        while let Some(b) = self.peek()? {
+            if !b.is_ascii_whitespace() {
+                break;
+            }
+            self.position += 1;
+        }
+        let mut numeral = Vec::new();
+        while let Some(b) = self.peek()? {
+            let allowed = b.is_ascii_hexdigit() || b"+-.xXpP".contains(&b);
+            if !allowed || numeral.len() == MAX_NUMERAL_LENGTH {
+                break;
+            }
+            numeral.push(b);
+            self.position += 1;
+        }
+        Ok(numeral)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if !self.mode.write {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not open for writing",
+            ));
+        }
+        self.discard_buffer()?;
+        match self.stream {
+            Some(Stream::File(ref mut file)) => {
+                if self.mode.append {
+                    file.seek(SeekFrom::End(0))?;
+                }
+                file.write_all(data)
+            }
+            Some(Stream::Handle(ref mut handle)) => handle.write_all(data),
+            Some(Stream::Stdout) => io::stdout().write_all(data),
+            Some(Stream::Stderr) => io::stderr().write_all(data),
+            _ => Ok(()),
+        }
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.discard_buffer()?;
+        match self.stream {
+            Some(Stream::File(ref mut file)) => file.seek(pos),
+            _ => Err(io::Error::other("illegal seek")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stream {
+            Some(Stream::File(ref mut file)) => file.flush(),
+            Some(Stream::Handle(ref mut handle)) => handle.flush(),
+            Some(Stream::Stdout) => io::stdout().flush(),
+            _ => Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        match self.stream {
+            Some(Stream::File(_)) | Some(Stream::Handle(_)) => {
+                let result = self.discard_buffer().and_then(|()| self.flush());
+                self.stream = None;
+                result
+            }
+            _ => Err(io::Error::other("cannot close standard file")),
+        }
+    }
+}
+
+// A format of `file:read`.
+#[derive(Copy, Clone)]
+enum Format {
+    Number,
+    Line,
+    LineWithNewline,
+    All,
+    Count(usize),
+}
+
+fn parse_formats<'lua>(lua: &'lua Lua, formats: Variadic<Value<'lua>>) -> Result<Vec<Format>> {
+    let mut parsed = Vec::with_capacity(formats.len());
+    for format in formats {
+        parsed.push(match format {
+            Value::Integer(n) => Format::Count(n.max(0) as usize),
+            Value::Number(n) => Format::Count(n.max(0.0) as usize),
+            format => {
+                let format = lua.coerce_string(format)?;
+                let format = format.as_bytes();
+                let format = format.strip_prefix(b"*").unwrap_or(format);
+                match format.first() {
+                    Some(&b'n') => Format::Number,
+                    Some(&b'l') => Format::Line,
+                    Some(&b'L') => Format::LineWithNewline,
+                    Some(&b'a') => Format::All,
+                    _ => return Err(Error::RuntimeError("invalid format".to_owned())),
+                }
+            }
+        });
+    }
+    if parsed.is_empty() {
+        parsed.push(Format::Line);
+    }
+    Ok(parsed)
+}
+
+// Reads values from a file according to `formats`, like `file:read`. Reading stops at the first
+// format which fails, and `nil` is returned for it.
+fn read_formats<'lua>(
+    lua: &'lua Lua,
+    file: &LuaFile,
+    formats: &[Format],
+) -> Result<LuaConvention<Variadic<Value<'lua>>, StdString>> {
+    let mut state = file.0.borrow_mut();
+    state.check_open()?;
+    let mut values = Variadic::new();
+    for &format in formats {
+        let result = match format {
+            Format::Number => state.read_numeral().map(Some),
+            Format::Line => state.read_line(false),
+            Format::LineWithNewline => state.read_line(true),
+            Format::All => state.read_all().map(Some),
+            Format::Count(count) => state.read_count(count),
+        };
+        let value = match result {
+            Ok(Some(data)) => {
+                let data = lua.create_byte_string(&data);
+                match format {
+                    Format::Number => lua
+                        .globals()
+                        .get::<_, Function>("tonumber")?
+                        .call::<_, Value>(data)?,
+                    _ => Value::String(data),
+                }
+            }
+            Ok(None) => Value::Nil,
+            Err(err) => return Ok(LuaConvention(Err(err.to_string()))),
+        };
+        let failed = value.is_nil();
+        values.push(value);
+        if failed {
+            break;
+        }
+    }
+    Ok(LuaConvention(Ok(values)))
+}
+
+// Writes strings and numbers to a file, like `file:write`.
+fn write_values<'lua>(
+    lua: &'lua Lua,
+    file: &LuaFile,
+    values: Variadic<Value<'lua>>,
+) -> Result<StdResult<(), StdString>> {
+    let mut state = file.0.borrow_mut();
+    state.check_open()?;
+    for value in values {
+        let value = match value {
+            Value::String(_) | Value::Integer(_) | Value::Number(_) => lua.coerce_string(value)?,
+            value => {
+                return Err(Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "string",
+                    message: None,
+                })
+            }
+        };
+        if let Err(err) = state.write(value.as_bytes()) {
+            return Ok(Err(err.to_string()));
+        }
+    }
+    Ok(Ok(()))
+}
+
+// Creates the iterator returned by `file:lines` and `io.lines`, which closes the file at its end
+// if `close` is set.
+fn lines_iterator<'lua>(
+    lua: &'lua Lua,
+    file: LuaFile,
+    formats: Vec<Format>,
+    close: bool,
+) -> Function<'lua> {
+    lua.create_function(move |lua, ()| {
+        if file.0.borrow().stream.is_none() {
+            return Err(Error::RuntimeError("file is already closed".to_owned()));
+        }
+        let values = match read_formats(lua, &file, &formats)?.0 {
+            Ok(values) => values,
+            Err(err) => return Err(Error::RuntimeError(err)),
+        };
+        if close && values.first().is_none_or(|value| value.is_nil()) {
+            let _ = file.0.borrow_mut().close();
+        }
+        Ok(values)
+    })
+}
+
+// A file of scripts. Every userdata value refers to the same file as the one it was created from.
+#[derive(Clone)]
+struct LuaFile(Rc<RefCell<FileState>>);
+
+impl LuaFile {
+    fn new(stream: Stream, mode: OpenMode) -> LuaFile {
+        LuaFile(Rc::new(RefCell::new(FileState {
+            stream: Some(stream),
+            mode,
+            buffer: Vec::new(),
+            position: 0,
+        })))
+    }
+}
+
+impl UserData for LuaFile {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("read", |lua, file, formats: Variadic<Value>| {
+            let formats = parse_formats(lua, formats)?;
+            read_formats(lua, file, &formats)
+        });
+
+        methods.add_function(
+            "write",
+            |lua, (userdata, values): (AnyUserData, Variadic<Value>)| {
+                let file = userdata.borrow::<LuaFile>()?.clone();
+                Ok(LuaConvention(
+                    write_values(lua, &file, values)?.map(|()| userdata),
+                ))
+            },
+        );
+
+        methods.add_method("lines", |lua, file, formats: Variadic<Value>| {
+            file.0.borrow().check_open()?;
+            let formats = parse_formats(lua, formats)?;
+            Ok(lines_iterator(lua, file.clone(), formats, false))
+        });
+
+        methods.add_method(
+            "seek",
+            |_, file, (whence, offset): (Option<StdString>, Option<Integer>)| {
+                let mut state = file.0.borrow_mut();
+                state.check_open()?;
+                let offset = offset.unwrap_or(0);
+                let pos = match whence.as_ref().map_or("cur", |whence| whence.as_str()) {
+                    "set" => SeekFrom::Start(offset.max(0) as u64),
+                    "cur" => SeekFrom::Current(offset),
+                    "end" => SeekFrom::End(offset),
+                    whence => {
+                        return Err(Error::RuntimeError(format!("invalid option '{}'", whence)))
+                    }
+                };
+                Ok(LuaConvention(
+                    state
+                        .seek(pos)
+                        .map(|pos| pos as Integer)
+                        .map_err(|err| err.to_string()),
+                ))
+            },
+        );
+
+        methods.add_method("flush", |_, file, ()| {
+            let mut state = file.0.borrow_mut();
+            state.check_open()?;
+            Ok(LuaConvention(
+                state.flush().map(|()| true).map_err(|err| err.to_string()),
+            ))
+        });
+
+        methods.add_method("close", |_, file, ()| close_file(file));
+
+        methods.add_method("setvbuf", |_, file, _: MultiValue| {
+            file.0.borrow().check_open()?;
+            Ok(true)
+        });
+
+        methods.add_meta_method(MetaMethod::ToString, |_, file, ()| {
+            Ok(match file.0.borrow().stream {
+                Some(_) => format!("file ({:p})", Rc::as_ptr(&file.0)),
+                None => "file (closed)".to_owned(),
+            })
+        });
+
+        methods.add_meta_function(MetaMethod::Eq, |_, (a, b): (AnyUserData, AnyUserData)| {
+            Ok(match (a.borrow::<LuaFile>(), b.borrow::<LuaFile>()) {
+                (Ok(a), Ok(b)) => Rc::ptr_eq(&a.0, &b.0),
+                _ => false,
+            })
+        });
+    }
+}
+
+fn close_file(file: &LuaFile) -> Result<LuaConvention<bool, StdString>> {
+    let mut state = file.0.borrow_mut();
+    state.check_open()?;
+    Ok(LuaConvention(
+        state.close().map(|()| true).map_err(|err| err.to_string()),
+    ))
+}
+
+// Opens a file through `vfs` for `io.open`, returning a message in the style of the `io` library
+// on failure.
+fn open_file(vfs: &dyn Vfs, path: &str, mode: OpenMode) -> StdResult<LuaFile, StdString> {
+    let resolved = checked_path(path)?;
+    match vfs.open(&resolved, mode) {
+        Ok(file) => Ok(LuaFile::new(Stream::File(file), mode)),
+        Err(err) => Err(format!("{}: {}", path, err)),
+    }
+}
+
+// Creates a file of scripts reading from and writing to `handle`, for `Lua::create_file_handle`.
+pub(crate) fn create_file_handle<H>(lua: &Lua, handle: H) -> AnyUserData<'_>
+where
+    H: 'static + Read + Write,
+{
+    let mode = OpenMode {
+        read: true,
+        write: true,
+        ..OpenMode::default()
+    };
+    lua.create_userdata(LuaFile::new(Stream::Handle(Box::new(handle)), mode))
+}
+
+// Returns the file of a userdata argument, or a `BadArgument` error.
+fn file_argument(lua: &Lua, userdata: &AnyUserData, arg_index: usize) -> Result<LuaFile> {
+    match userdata.borrow::<LuaFile>() {
+        Ok(file) => Ok(file.clone()),
+        Err(_) => {
+            let err = Error::BadArgument {
+                function_name: None,
+                arg_index,
+                cause: Arc::new(Error::RuntimeError("file expected".to_owned())),
+            };
+            Err(lua.name_bad_argument(err, false))
+        }
+    }
+}
+
+// The default input and output files of the `io` library.
+struct DefaultFiles {
+    input: LuaFile,
+    output: LuaFile,
+}
+
+// Replaces the functions of the standard library which access files with functions using `vfs`,
+// for `Lua::set_vfs`.
+pub(crate) fn install_vfs(lua: &Lua, vfs: Rc<dyn Vfs>) -> Result<()> {
+    let globals = lua.globals();
+    let read_mode = OpenMode {
+        read: true,
+        ..OpenMode::default()
+    };
+    let write_mode = OpenMode::parse("w").unwrap();
+
+    if let Some(io) = globals.get::<_, Option<Table>>("io")? {
+        let stdin = LuaFile::new(Stream::Stdin, read_mode);
+        let stdout = LuaFile::new(Stream::Stdout, write_mode);
+        let stderr = LuaFile::new(Stream::Stderr, write_mode);
+        io.set("stdin", lua.create_userdata(stdin.clone()))?;
+        io.set("stdout", lua.create_userdata(stdout.clone()))?;
+        io.set("stderr", lua.create_userdata(stderr))?;
+        let defaults = Rc::new(RefCell::new(DefaultFiles {
+            input: stdin,
+            output: stdout,
+        }));
+
+        let open_vfs = vfs.clone();
+        io.set(
+            "open",
+            lua.create_function(move |lua, (path, mode): (StdString, Option<StdString>)| {
+                let mode = match OpenMode::parse(mode.as_ref().map_or("r", |mode| mode.as_str())) {
+                    Some(mode) => mode,
+                    None => {
+                        let err = Error::BadArgument {
+                            function_name: None,
+                            arg_index: 2,
+                            cause: Arc::new(Error::RuntimeError("invalid mode".to_owned())),
+                        };
+                        return Err(lua.name_bad_argument(err, false));
+                    }
+                };
+                Ok(LuaConvention(
+                    open_file(&*open_vfs, &path, mode).map(|file| lua.create_userdata(file)),
+                ))
+            }),
+        )?;
+
+        let lines_vfs = vfs.clone();
+        let lines_defaults = defaults.clone();
+        io.set(
+            "lines",
+            lua.create_function(
+                move |lua, (path, formats): (Option<StdString>, Variadic<Value>)| {
+                    let formats = parse_formats(lua, formats)?;
+                    Ok(match path {
+                        Some(path) => {
+                            let file = open_file(&*lines_vfs, &path, read_mode)
+                                .map_err(Error::RuntimeError)?;
+                            lines_iterator(lua, file, formats, true)
+                        }
+                        None => {
+                            let input = lines_defaults.borrow().input.clone();
+                            input.0.borrow().check_open()?;
+                            lines_iterator(lua, input, formats, false)
+                        }
+                    })
+                },
+            ),
+        )?;
+
+        let input_vfs = vfs.clone();
+        let input_defaults = defaults.clone();
+        io.set(
+            "input",
+            lua.create_function(move |lua, file: Value| {
+                let mut defaults = input_defaults.borrow_mut();
+                match file {
+                    Value::Nil => {}
+                    Value::UserData(userdata) => defaults.input = file_argument(lua, &userdata, 1)?,
+                    path => {
+                        let path = lua.coerce_string(path)?;
+                        defaults.input = open_file(&*input_vfs, path.to_str()?, read_mode)
+                            .map_err(Error::RuntimeError)?;
+                    }
+                }
+                Ok(lua.create_userdata(defaults.input.clone()))
+            }),
+        )?;
+
+        let output_vfs = vfs.clone();
+        let output_defaults = defaults.clone();
+        io.set(
+            "output",
+            lua.create_function(move |lua, file: Value| {
+                let mut defaults = output_defaults.borrow_mut();
+                match file {
+                    Value::Nil => {}
+                    Value::UserData(userdata) => {
+                        defaults.output = file_argument(lua, &userdata, 1)?
+                    }
+                    path => {
+                        let path = lua.coerce_string(path)?;
+                        defaults.output = open_file(&*output_vfs, path.to_str()?, write_mode)
+                            .map_err(Error::RuntimeError)?;
+                    }
+                }
+                Ok(lua.create_userdata(defaults.output.clone()))
+            }),
+        )?;
+
+        let read_defaults = defaults.clone();
+        io.set(
+            "read",
+            lua.create_function(move |lua, formats: Variadic<Value>| {
+                let formats = parse_formats(lua, formats)?;
+                let input = read_defaults.borrow().input.clone();
+                read_formats(lua, &input, &formats)
+            }),
+        )?;
+
+        let write_defaults = defaults.clone();
+        io.set(
+            "write",
+            lua.create_function(move |lua, values: Variadic<Value>| {
+                let output = write_defaults.borrow().output.clone();
+                let result = write_values(lua, &output, values)?;
+                Ok(LuaConvention(result.map(|()| lua.create_userdata(output))))
+            }),
+        )?;
+
+        io.set(
+            "close",
+            lua.create_function(move |lua, file: Option<AnyUserData>| {
+                let file = match file {
+                    Some(userdata) => file_argument(lua, &userdata, 1)?,
+                    None => defaults.borrow().output.clone(),
+                };
+                close_file(&file)
+            }),
+        )?;
+
+        io.set(
+            "type",
+            lua.create_function(|_, value: Value| {
+                Ok(match value {
+                    Value::UserData(userdata) => match userdata.borrow::<LuaFile>() {
+                        Ok(file) if file.0.borrow().stream.is_some() => Some("file"),
+                        Ok(_) => Some("closed file"),
+                        Err(_) => None,
+                    },
+                    _ => None,
+                })
+            }),
+        )?;
+
+        io.set(
+            "tmpfile",
+            lua.create_function(|lua, ()| {
+                let mode = OpenMode::parse("w+").unwrap();
+                let file = LuaFile::new(Stream::File(Box::new(Cursor::new(Vec::new()))), mode);
+                Ok(lua.create_userdata(file))
+            }),
+        )?;
+
+        io.set(
+            "popen",
+            lua.create_function(|_, _: MultiValue| -> Result<()> {
+                Err(Error::RuntimeError("'popen' not supported".to_owned()))
+            }),
+        )?;
+
+        let list_vfs = vfs.clone();
+        io.set(
+            "list",
+            lua.create_function(move |_, path: Option<StdString>| {
+                let path = path.unwrap_or_default();
+                Ok(LuaConvention(checked_path(&path).and_then(|resolved| {
+                    list_vfs
+                        .list(&resolved)
+                        .map_err(|err| format!("{}: {}", path, err))
+                })))
+            }),
+        )?;
+    }
+
+    if let Some(os) = globals.get::<_, Option<Table>>("os")? {
+        let remove_vfs = vfs.clone();
+        os.set(
+            "remove",
+            lua.create_function(move |_, path: StdString| {
+                Ok(LuaConvention(checked_path(&path).and_then(|resolved| {
+                    remove_vfs
+                        .remove(&resolved)
+                        .map(|()| true)
+                        .map_err(|err| format!("{}: {}", path, err))
+                })))
+            }),
+        )?;
+
+        let rename_vfs = vfs.clone();
+        os.set(
+            "rename",
+            lua.create_function(move |_, (from, to): (StdString, StdString)| {
+                let result = checked_path(&from).and_then(|resolved_from| {
+                    let resolved_to = checked_path(&to)?;
+                    rename_vfs
+                        .rename(&resolved_from, &resolved_to)
+                        .map(|()| true)
+                        .map_err(|err| format!("{}: {}", from, err))
+                });
+                Ok(LuaConvention(result))
+            }),
+        )?;
+
+        os.set(
+            "tmpname",
+            lua.create_function(|_, ()| -> Result<()> {
+                Err(Error::RuntimeError("'tmpname' not supported".to_owned()))
+            }),
+        )?;
+    }
+
+    let loadfile_vfs = vfs.clone();
+    globals.set(
+        "loadfile",
+        lua.create_function(move |lua, args: MultiValue| {
+            let mut args = args.into_iter();
+            let path = match args.next() {
+                None | Some(Value::Nil) => None,
+                Some(path) => Some(lua.coerce_string(path)?.to_str()?.to_owned()),
+            };
+            let mode = match args.next() {
+                None | Some(Value::Nil) => None,
+                Some(mode) => Some(lua.coerce_string(mode)?.to_str()?.to_owned()),
+            };
+            let env = args.next();
+            if mode.is_some_and(|mode| !mode.contains('t')) {
+                return Ok(LuaConvention(
+                    Err("attempt to load a text chunk".to_owned()),
+                ));
+            }
+            Ok(LuaConvention(load_file(
+                lua,
+                &*loadfile_vfs,
+                path.as_deref(),
+                env,
+            )?))
+        }),
+    )?;
+
+    let dofile_vfs = vfs.clone();
+    globals.set(
+        "dofile",
+        lua.create_function(move |lua, path: Option<StdString>| {
+            let path = path.as_deref();
+            match load_file(lua, &*dofile_vfs, path, None)? {
+                Ok(chunk) => chunk.call::<_, MultiValue>(()),
+                Err(err) => Err(Error::RuntimeError(err)),
+            }
+        }),
+    )?;
+
+    if let Some(package) = globals.get::<_, Option<Table>>("package")? {
+        package.set("path", "./?.lua;./?/init.lua")?;
+        package.set("cpath", "")?;
+
+        let searchpath_vfs = vfs.clone();
+        package.set(
+            "searchpath",
+            lua.create_function(
+                move |_, (name, path, sep, rep): (String, String, Option<String>, Option<String>)| {
+                    let name = name.to_str()?;
+                    let sep = sep.as_ref().map_or(Ok("."), |sep| sep.to_str())?;
+                    let rep = rep.as_ref().map_or(Ok("/"), |rep| rep.to_str())?;
+                    Ok(LuaConvention(search_path(
+                        &*searchpath_vfs,
+                        name,
+                        path.to_str()?,
+                        sep,
+                        rep,
+                    )))
+                },
+            ),
+        )?;
+
+        let searchers = package.get::<_, Table>("searchers")?;
+        let preload_searcher = searchers.get::<_, Value>(1)?;
+        let searchers = lua.create_table();
+        searchers.set(1, preload_searcher)?;
+        searchers.set(
+            2,
+            lua.create_function(move |lua, name: StdString| {
+                let path = lua
+                    .globals()
+                    .get::<_, Table>("package")?
+                    .get::<_, StdString>("path")?;
+                let file = match search_path(&*vfs, &name, &path, ".", "/") {
+                    Ok(file) => file,
+                    Err(message) => return Ok((Value::String(lua.create_string(&message)), None)),
+                };
+                match load_file(lua, &*vfs, Some(&file), None)? {
+                    Ok(chunk) => Ok((Value::Function(chunk), Some(file))),
+                    Err(err) => Err(Error::RuntimeError(format!(
+                        "error loading module '{}' from file '{}':\n\t{}",
+                        name, file, err
+                    ))),
+                }
+            }),
+        )?;
+        package.set("searchers", searchers)?;
+    }
+
+    Ok(())
+}
+
+// Loads a file of Lua code through `vfs`, or from the standard input if `path` is `None`, like
+// `loadfile`.
+fn load_file<'lua>(
+    lua: &'lua Lua,
+    vfs: &dyn Vfs,
+    path: Option<&str>,
+    env: Option<Value<'lua>>,
+) -> Result<StdResult<Function<'lua>, StdString>> {
+    let (mut source, name) = match path {
+        Some(path) => {
+            let result = checked_path(path).and_then(|resolved| {
+                vfs.read(&resolved)
+                    .map_err(|err| format!("cannot open {}: {}", path, err))
+            });
+            match result {
+                Ok(source) => (source, format!("@{}", path)),
+                Err(err) => return Ok(Err(err)),
+            }
+        }
+        None => {
+            let mut source = Vec::new();
+            if let Err(err) = io::stdin().read_to_end(&mut source) {
+                return Ok(Err(format!("cannot read stdin: {}", err)));
+            }
+            (source, "=stdin".to_owned())
+        }
+    };
+
+    // Like `luaL_loadfile`, skip a first line starting with `#`, keeping its newline so that line
+    // numbers are unchanged.
+    if source.first() == Some(&b'#') {
+        let end = source
+            .iter()
+            .position(|&b| b == b'\n')
+            .unwrap_or(source.len());
+        source.drain(..end);
+    }
+
+    Ok(lua.load_source(&source, &name, env).map_err(error_message))
+}
+
+// Finds the first file in `path` which can be opened for `name`, like `package.searchpath`.
+fn search_path(
+    vfs: &dyn Vfs,
+    name: &str,
+    path: &str,
+    sep: &str,
+    rep: &str,
+) -> StdResult<StdString, StdString> {
+    let name = if sep.is_empty() {
+        name.to_owned()
+    } else {
+        name.replace(sep, rep)
+    };
+    let mode = OpenMode {
+        read: true,
+        ..OpenMode::default()
+    };
+    let mut message = StdString::new();
+    for template in path.split(';').filter(|template| !template.is_empty()) {
+        let file = template.replace('?', &name);
+        if let Some(resolved) = resolve_path(&file) {
+            if vfs.open(&resolved, mode).is_ok() {
+                return Ok(file);
+            }
+        }
+        message.push_str(&format!("\n\tno file '{}'", file));
+    }
+    Err(message)
+}
+
+// Returns the message of an error raised while loading a chunk, as Lua would show it.
+fn error_message(err: Error) -> StdString {
+    match err {
+        Error::SyntaxError { message, .. } => message,
+        err => err.to_string(),
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+
+/// Enforces wall-clock deadlines on any number of Lua states from a single background thread.
+///
+/// Attach a watchdog to each state with [`Lua::set_watchdog`], and give a state a deadline with
+/// [`Lua::set_deadline`] before calling into it. Once the deadline of a state has passed, the
+/// watchdog interrupts the state: the scripts it runs are stopped with an error until the state
+/// is given a new deadline or its deadline is cleared. Clones of a watchdog share its thread,
+/// which stops once the watchdog and all states attached to it have been dropped.
+///
+/// Scripts are checked for interruption every 1000 instructions, so a script may keep running
+/// for a short while after its deadline, and Rust functions called from scripts are not
+/// interrupted.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rlua;
+/// # use std::time::{Duration, Instant};
+/// # use rlua::{Lua, Result, Watchdog};
+/// # fn try_main() -> Result<()> {
+/// let watchdog = Watchdog::new();
+/// let tenants = (0..10).map(|_| Lua::new()).collect::<Vec<_>>();
+/// for lua in &tenants {
+///     lua.set_watchdog(watchdog.clone());
+/// }
+///
+/// let lua = &tenants[0];
+/// lua.set_deadline(Instant::now() + Duration::from_millis(50));
+/// assert!(lua.exec::<()>("while true do end", None).is_err());
+///
+/// lua.set_deadline(Instant::now() + Duration::from_secs(10));
+/// lua.exec::<()>("for i = 1, 100 do end", None)?;
+/// # Ok(())
+/// # }
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Lua::set_watchdog`]: struct.Lua.html#method.set_watchdog
+/// [`Lua::set_deadline`]: struct.Lua.html#method.set_deadline
+#[derive(Clone, Debug)]
+pub struct Watchdog(Arc<Handle>);
+
+// Stops the thread of the watchdog once the last clone of it is dropped. The thread itself only
+// holds the shared state, so that it does not keep itself running.
+#[derive(Debug)]
+struct Handle(Arc<Shared>);
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Deadlines of the states attached to the watchdog, and the flags the watchdog sets to
+    // interrupt them, keyed by an id given to each state.
+    deadlines: HashMap<u64, (Option<Instant>, Arc<AtomicBool>)>,
+    next_id: u64,
+    stopped: bool,
+}
+
+// A state attached to a watchdog, which is detached when dropped.
+pub(crate) struct WatchedState {
+    watchdog: Watchdog,
+    id: u64,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    /// Creates a watchdog and starts its thread.
+    pub fn new() -> Watchdog {
+        let shared = Arc::new(Shared::default());
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name("rlua-watchdog".to_owned())
+            .spawn(move || run(&thread_shared))
+            .expect("could not start the watchdog thread");
+        Watchdog(Arc::new(Handle(shared)))
+    }
+
+    // Attaches a state with the given deadline.
+    pub(crate) fn watch(&self, deadline: Option<Instant>) -> WatchedState {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.deadlines.insert(id, (deadline, interrupted.clone()));
+        self.notify();
+        WatchedState {
+            watchdog: self.clone(),
+            id,
+            interrupted,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        (self.0).0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn notify(&self) {
+        (self.0).0.changed.notify_all();
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Watchdog {
+        Watchdog::new()
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner()).stopped = true;
+        self.0.changed.notify_all();
+    }
+}
+
+impl WatchedState {
+    // Replaces the deadline of the state, which is no longer interrupted.
+    pub(crate) fn set_deadline(&self, deadline: Option<Instant>) {
+        let mut state = self.watchdog.lock();
+        self.interrupted.store(false, Ordering::Relaxed);
+        if let Some(entry) = state.deadlines.get_mut(&self.id) {
+            entry.0 = deadline;
+        }
+        self.watchdog.notify();
+    }
+
+    pub(crate) fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for WatchedState {
+    fn drop(&mut self) {
+        self.watchdog.lock().deadlines.remove(&self.id);
+        self.watchdog.notify();
+    }
+}
+
+// Sleeps until the earliest deadline and interrupts the states whose deadline has passed, until
+// the watchdog is dropped.
+fn run(shared: &Shared) {
+    let mut state = shared.state.lock().unwrap_or_else(|e| e.into_inner());
+    while !state.stopped {
+        let now = Instant::now();
+        let mut next = None;
+        for entry in state.deadlines.values_mut() {
+            match entry.0 {
+                Some(deadline) if deadline <= now => {
+                    entry.1.store(true, Ordering::Relaxed);
+                    entry.0 = None;
+                }
+                Some(deadline) => {
+                    next = Some(next.map_or(deadline, |next| deadline.min(next)));
+                }
+                None => {}
+            }
+        }
+        state = match next {
+            Some(next) => {
+                shared
+                    .changed
+                    .wait_timeout(state, next - now)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0
+            }
+            None => shared.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+        };
+    }
+}